@@ -0,0 +1,902 @@
+//! A bridge to and from Cranelift's IR, so that graphs can be optimized in
+//! between a frontend generating CLIF and Cranelift's code generation.
+//!
+//! A function becomes a lambda in the root region, named after it, taking
+//! its parameters followed by a state and producing its return values
+//! followed by the state. Which op stands for which instruction is up to the
+//! ops, through `ClifOp`, and those taking a state are threaded on that of
+//! the function in the order they run in.
+//!
+//! Blocks have to end in `jump`, `brif` or `return`. Branches must have an
+//! immediate post-dominator they all meet again at, or otherwise all
+//! return, and loops must only be left from the block branching back to
+//! their header, as for the LLVM frontend.
+
+use crate::{
+    rvsdg::{
+        CfgError, Inst, Jump, NodeCtxt, NodeCtxtConfig, NodeId, PortKind, Sig, Terminator,
+        ValOrigin, Var,
+    },
+    ssa::SsaBuilder,
+    structure::{FlowBlock, Structure, StructureError},
+};
+use cranelift_codegen::ir::{
+    self, types, BlockArg, BlockCall, Function, InstBuilder, JumpTableData, Opcode, Signature,
+    Type, UserFuncName, Value,
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use std::{collections::HashMap, fmt, hash::Hash};
+
+/// How ops stand for the instructions of Cranelift's IR.
+pub trait ClifOp: Sig + Sized {
+    /// The op standing for `inst` of `func`, if there's one. Its value
+    /// inputs are the arguments of the instruction and its value outputs
+    /// the results.
+    fn from_clif(func: &Function, inst: ir::Inst) -> Option<Self>;
+
+    /// Builds the instructions computing the op from `args` in the current
+    /// block of `builder`, returning its value outputs. Its states are
+    /// implied by the order the instructions come in.
+    fn to_clif(&self, builder: &mut FunctionBuilder, args: &[Value]) -> Vec<Value>;
+
+    /// An op producing any value of type `ty`, which the values defined in
+    /// loops start from.
+    fn undef(ty: Type) -> Self;
+
+    /// An op taking a value of type `ty`, producing 1 if it's zero and 0
+    /// otherwise.
+    fn is_zero(ty: Type) -> Self;
+
+    /// An op taking a value of type `ty`, producing 1 if it isn't zero and
+    /// 0 otherwise.
+    fn is_nonzero(ty: Type) -> Self;
+}
+
+/// Why a function couldn't be converted to or from Cranelift's IR.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClifError {
+    /// No op stands for the instruction, or it ends a block with something
+    /// other than `jump`, `brif` or `return`.
+    Unsupported(ir::Inst),
+    /// The value is used where it isn't defined.
+    Undefined(Value),
+    /// The loop at the block has several entries.
+    IrreducibleLoop(ir::Block),
+    /// The loop at the block is branched back to from several blocks.
+    SeveralLatches(ir::Block),
+    /// The loop at the block is left from other blocks than the one
+    /// branching back to it.
+    LoopLeftMidway(ir::Block),
+    /// The body of the lambda couldn't be turned into a CFG.
+    Cfg(CfgError),
+    /// The lambda doesn't have the parameters and results of the signature.
+    Signature(NodeId),
+    /// The lambda has context variables or applies functions.
+    Call(NodeId),
+}
+
+impl fmt::Display for ClifError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClifError::Unsupported(inst) => write!(f, "{} isn't supported", inst),
+            ClifError::Undefined(value) => write!(f, "{} isn't defined where it's used", value),
+            ClifError::IrreducibleLoop(header) => {
+                write!(f, "the loop at {} is irreducible", header)
+            }
+            ClifError::SeveralLatches(header) => write!(
+                f,
+                "the loop at {} is branched back to from several blocks",
+                header
+            ),
+            ClifError::LoopLeftMidway(header) => write!(
+                f,
+                "the loop at {} has to be left from the block branching back to it",
+                header
+            ),
+            ClifError::Cfg(error) => write!(f, "{}", error),
+            ClifError::Signature(lambda) => {
+                write!(f, "{:?} doesn't match its signature", lambda)
+            }
+            ClifError::Call(lambda) => write!(f, "{:?} calls other functions", lambda),
+        }
+    }
+}
+
+impl<S: ClifOp + Eq + Hash + Clone> NodeCtxt<S> {
+    /// Reads a function of Cranelift's IR into a graph, whose root region
+    /// holds nothing but its lambda.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the function has no blocks.
+    pub fn from_clif(func: &Function) -> Result<NodeCtxt<S>, ClifError> {
+        let blocks: Vec<ir::Block> = func.layout.blocks().collect();
+        let indices: HashMap<ir::Block, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(index, &block)| (block, index))
+            .collect();
+        let structure = function_structure(func, &blocks, &indices)?;
+
+        // Values defined in loops start out undefined, and those not used
+        // after them are removed as regions are finished.
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_region_cleanup: true,
+            ..NodeCtxtConfig::default()
+        });
+        let entry = blocks[0];
+        let mut kinds = vec![PortKind::Val; func.dfg.block_params(entry).len()];
+        kinds.push(PortKind::St);
+        let lambda = ncx.lambda_builder(ncx.root_region(), &kinds);
+        let mut importer = Importer {
+            ncx: &ncx,
+            ssa: SsaBuilder::new(&ncx, lambda.body()),
+            func,
+            blocks: &blocks,
+            indices: &indices,
+            structure: &structure,
+            loops: vec![],
+        };
+        for (index, &param) in func.dfg.block_params(entry).iter().enumerate() {
+            importer
+                .ssa
+                .write_val(Slot::Param(param), lambda.val_param(index));
+        }
+        importer.ssa.write_state(Slot::State, lambda.st_param(0));
+        importer.import_from(0, None)?;
+
+        let results: Vec<_> = (0..func.signature.returns.len())
+            .map(|index| importer.ssa.read_val(&Slot::Ret(index)).unwrap())
+            .collect();
+        let state = importer.ssa.read_state(&Slot::State).unwrap();
+        lambda
+            .finish(&results, &[state])
+            .set_name(func.name.to_string());
+        Ok(ncx)
+    }
+}
+
+/// Finds how the blocks of `func`, in the order of its layout, nest into
+/// branches and loops.
+fn function_structure(
+    func: &Function,
+    blocks: &[ir::Block],
+    indices: &HashMap<ir::Block, usize>,
+) -> Result<Structure<Value>, ClifError> {
+    let mut flow_blocks: Vec<FlowBlock<Value>> = blocks
+        .iter()
+        .map(|&block| {
+            let insts = || func.layout.block_insts(block);
+            let results = insts().flat_map(|inst| func.dfg.inst_results(inst));
+            let args = insts().flat_map(|inst| func.dfg.inst_args(inst));
+            FlowBlock {
+                succs: vec![],
+                defs: func
+                    .dfg
+                    .block_params(block)
+                    .iter()
+                    .chain(results)
+                    .copied()
+                    .collect(),
+                uses: args.map(|&arg| func.dfg.resolve_aliases(arg)).collect(),
+            }
+        })
+        .collect();
+    for (index, &block) in blocks.iter().enumerate() {
+        let term = terminator(func, block)?;
+        for dest in destinations(func, term) {
+            let succ = indices[&dest.block(&func.dfg.value_lists)];
+            flow_blocks[index].succs.push(succ);
+            let args = block_args(func, term, dest)?;
+            flow_blocks[succ].uses.extend(args);
+        }
+    }
+
+    Structure::new(&flow_blocks).map_err(|error| match error {
+        StructureError::Irreducible { header, .. } => ClifError::IrreducibleLoop(blocks[header]),
+        StructureError::SeveralLatches { header, .. } => ClifError::SeveralLatches(blocks[header]),
+        StructureError::LeftMidway { header, .. } => ClifError::LoopLeftMidway(blocks[header]),
+    })
+}
+
+/// The instruction ending `block`, if it's one the graph can stand for.
+fn terminator(func: &Function, block: ir::Block) -> Result<ir::Inst, ClifError> {
+    let inst = func.layout.last_inst(block).unwrap();
+    match func.dfg.insts[inst].opcode() {
+        Opcode::Jump | Opcode::Brif | Opcode::Return => Ok(inst),
+        _ => Err(ClifError::Unsupported(inst)),
+    }
+}
+
+fn destinations(func: &Function, term: ir::Inst) -> &[BlockCall] {
+    func.dfg.insts[term].branch_destination(&func.dfg.jump_tables, &func.dfg.exception_tables)
+}
+
+/// The values `term` passes to the parameters of the block it branches to
+/// through `dest`.
+fn block_args(func: &Function, term: ir::Inst, dest: &BlockCall) -> Result<Vec<Value>, ClifError> {
+    dest.args(&func.dfg.value_lists)
+        .map(|arg| match arg {
+            BlockArg::Value(value) => Ok(func.dfg.resolve_aliases(value)),
+            _ => Err(ClifError::Unsupported(term)),
+        })
+        .collect()
+}
+
+/// What the SSA builder keeps track of while a function is imported.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Slot {
+    Value(Value),
+    /// The value a block parameter takes in the block being branched to,
+    /// written on the way there.
+    Param(Value),
+    Ret(usize),
+    State,
+}
+
+struct Importer<'a, 'g, S> {
+    ncx: &'g NodeCtxt<S>,
+    ssa: SsaBuilder<'g, S, Slot>,
+    func: &'a Function,
+    blocks: &'a [ir::Block],
+    indices: &'a HashMap<ir::Block, usize>,
+    structure: &'a Structure<Value>,
+    /// The headers of the loops being imported, innermost last.
+    loops: Vec<usize>,
+}
+
+impl<'a, 'g, S: ClifOp + Eq + Hash + Clone> Importer<'a, 'g, S> {
+    /// Imports the blocks from `block` on, up to `stop` or the returns of
+    /// the function, or the latch of the innermost loop being imported.
+    fn import_from(&mut self, mut block: usize, stop: Option<usize>) -> Result<(), ClifError> {
+        let func = self.func;
+        loop {
+            if Some(block) == stop {
+                return Ok(());
+            }
+            if self.structure.loops.contains_key(&block) && self.loops.last() != Some(&block) {
+                block = self.import_loop(block)?;
+                continue;
+            }
+            let term = self.import_insts(block)?;
+            if let Some(header) = self.loops.last() {
+                if self.structure.loops[header].latch == block {
+                    return Ok(());
+                }
+            }
+
+            let dests = destinations(func, term);
+            match func.dfg.insts[term].opcode() {
+                Opcode::Return => {
+                    for (index, &arg) in func.dfg.inst_args(term).iter().enumerate() {
+                        let result = self.value(arg)?;
+                        self.ssa.write_val(Slot::Ret(index), result);
+                    }
+                    return Ok(());
+                }
+                Opcode::Jump => block = self.pass_args(term, &dests[0])?,
+                _ => {
+                    let join = self.structure.ipdoms[block];
+                    let cond = func.dfg.inst_args(term)[0];
+                    let predicate = self.test(cond, true)?;
+                    self.ssa.begin_gamma(predicate, 2);
+                    for (index, dest) in [dests[1], dests[0]].iter().enumerate() {
+                        if index > 0 {
+                            self.ssa.next_branch();
+                        }
+                        let target = self.pass_args(term, dest)?;
+                        self.import_from(target, join)?;
+                    }
+                    self.ssa.end_gamma();
+                    match join {
+                        Some(join) => block = join,
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Imports the loop at `header` as a theta, returning the block it exits
+    /// to.
+    fn import_loop(&mut self, header: usize) -> Result<usize, ClifError> {
+        let func = self.func;
+        let structure = self.structure;
+        let lp = &structure.loops[&header];
+
+        // Values defined in the loop only get out of it as loop variables,
+        // which need a value to start from.
+        for &value in &lp.live_out {
+            let region = self.ssa.region();
+            let undef = S::undef(func.dfg.value_type(value));
+            let undef = self.ncx.mk_node_in(region, undef).val_out(0);
+            self.ssa.write_val(Slot::Value(value), undef);
+        }
+        self.ssa.begin_theta();
+        self.loops.push(header);
+        self.import_from(header, None)?;
+        self.loops.pop();
+
+        // The latch branches both back and out, so it ends in a `brif`.
+        let term = func.layout.last_inst(self.blocks[lp.latch]).unwrap();
+        let dests = destinations(func, term);
+        let repeats = dests[0].block(&func.dfg.value_lists) == self.blocks[header];
+        let (back, out) = if repeats {
+            (dests[0], dests[1])
+        } else {
+            (dests[1], dests[0])
+        };
+        self.pass_args(term, &back)?;
+        let predicate = self.test(func.dfg.inst_args(term)[0], repeats)?;
+        self.ssa.end_theta(predicate);
+        self.pass_args(term, &out)?;
+        Ok(lp.exit)
+    }
+
+    /// Imports the instructions of `block` up to the one ending it, which is
+    /// returned.
+    fn import_insts(&mut self, block: usize) -> Result<ir::Inst, ClifError> {
+        let func = self.func;
+        let block = self.blocks[block];
+        for &param in func.dfg.block_params(block) {
+            let value = match self.ssa.read_val(&Slot::Param(param)) {
+                Some(value) => value,
+                None => return Err(ClifError::Undefined(param)),
+            };
+            self.ssa.write_val(Slot::Value(param), value);
+        }
+
+        let term = terminator(func, block)?;
+        for inst in func.layout.block_insts(block).filter(|&inst| inst != term) {
+            let op = match S::from_clif(func, inst) {
+                Some(op) => op,
+                None => return Err(ClifError::Unsupported(inst)),
+            };
+            let sig = op.sig();
+            let region = self.ssa.region();
+            let mut builder = self.ncx.node_builder_in(region, op);
+            for &arg in func.dfg.inst_args(inst) {
+                builder = builder.operand(self.value(arg)?);
+            }
+            if sig.st_ins > 0 {
+                builder = builder.state(self.ssa.read_state(&Slot::State).unwrap());
+            }
+            let node = builder.finish();
+            if sig.st_outs > 0 {
+                self.ssa.write_state(Slot::State, node.st_out(0));
+            }
+            for (index, &result) in func.dfg.inst_results(inst).iter().enumerate() {
+                self.ssa.write_val(Slot::Value(result), node.val_out(index));
+            }
+        }
+        Ok(term)
+    }
+
+    /// Writes the values the parameters of the block `dest` branches to
+    /// take, returning that block. They're all read before any is written,
+    /// as parameters take their values at once.
+    fn pass_args(&mut self, term: ir::Inst, dest: &BlockCall) -> Result<usize, ClifError> {
+        let func = self.func;
+        let target = dest.block(&func.dfg.value_lists);
+        let mut values = vec![];
+        for arg in block_args(func, term, dest)? {
+            values.push(self.value(arg)?);
+        }
+        for (&param, value) in func.dfg.block_params(target).iter().zip(values) {
+            self.ssa.write_val(Slot::Param(param), value);
+        }
+        Ok(self.indices[&target])
+    }
+
+    /// Whether `cond` is nonzero, or zero if `nonzero` is false, as 1 or 0.
+    fn test(&mut self, cond: Value, nonzero: bool) -> Result<ValOrigin<'g, S>, ClifError> {
+        let ty = self.func.dfg.value_type(cond);
+        let op = if nonzero {
+            S::is_nonzero(ty)
+        } else {
+            S::is_zero(ty)
+        };
+        let cond = self.value(cond)?;
+        let region = self.ssa.region();
+        let node = self.ncx.node_builder_in(region, op).operand(cond).finish();
+        Ok(node.val_out(0))
+    }
+
+    fn value(&mut self, value: Value) -> Result<ValOrigin<'g, S>, ClifError> {
+        let value = self.func.dfg.resolve_aliases(value);
+        match self.ssa.read_val(&Slot::Value(value)) {
+            Some(origin) => Ok(origin),
+            None => Err(ClifError::Undefined(value)),
+        }
+    }
+}
+
+impl<S: ClifOp + Clone> NodeCtxt<S> {
+    /// Writes `lambda` as a function of Cranelift's IR, with the given name
+    /// and signature.
+    ///
+    /// The body of the lambda is turned into a CFG, with the nodes of each
+    /// block in the order `NodeCtxt::schedule` puts them in. Switches
+    /// between two blocks become `brif`s, taking the first block when the
+    /// predicate is zero, and others become `br_table`s.
+    pub fn to_clif(
+        &self,
+        lambda: NodeId,
+        name: UserFuncName,
+        signature: Signature,
+    ) -> Result<Function, ClifError> {
+        let sig = self.lambda_sig(lambda);
+        if sig.val_ins != signature.params.len() || sig.val_outs != signature.returns.len() {
+            return Err(ClifError::Signature(lambda));
+        }
+        if !self.ctx_var_lambdas(lambda).is_empty() {
+            return Err(ClifError::Call(lambda));
+        }
+        let body = self.inner_regions(lambda)[0];
+        let cfg = self.to_scheduled_cfg(body).map_err(ClifError::Cfg)?;
+
+        let mut func = Function::with_name_signature(name, signature);
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+        let mut emitter = Emitter {
+            blocks: cfg.blocks.iter().map(|_| builder.create_block()).collect(),
+            has_params: vec![false; cfg.blocks.len()],
+            values: HashMap::new(),
+        };
+        builder.append_block_params_for_function_params(emitter.blocks[0]);
+        emitter.has_params[0] = true;
+
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            let clif_block = emitter.blocks[index];
+            builder.switch_to_block(clif_block);
+            let params = builder.block_params(clif_block).to_vec();
+            emitter
+                .values
+                .extend(block.params.iter().copied().zip(params));
+
+            for inst in &block.insts {
+                match inst {
+                    Inst::Op { op, args, results } => {
+                        let args = emitter.values(args);
+                        let values = op.to_clif(&mut builder, &args);
+                        assert_eq!(results.len(), values.len());
+                        emitter.values.extend(results.iter().copied().zip(values));
+                    }
+                    Inst::Call { .. } => return Err(ClifError::Call(lambda)),
+                }
+            }
+
+            match &block.terminator {
+                Terminator::Jump(jump) => {
+                    let args = emitter.args(&mut builder, jump);
+                    builder.ins().jump(emitter.blocks[jump.block.0], &args);
+                }
+                Terminator::Switch { on, jumps } if jumps.len() == 2 => {
+                    let on = emitter.values[on];
+                    let if_zero = emitter.args(&mut builder, &jumps[0]);
+                    let if_nonzero = emitter.args(&mut builder, &jumps[1]);
+                    builder.ins().brif(
+                        on,
+                        emitter.blocks[jumps[1].block.0],
+                        &if_nonzero,
+                        emitter.blocks[jumps[0].block.0],
+                        &if_zero,
+                    );
+                }
+                Terminator::Switch { on, jumps } => {
+                    let mut on = emitter.values[on];
+                    let bits = builder.func.dfg.value_type(on).bits();
+                    if bits < 32 {
+                        on = builder.ins().uextend(types::I32, on);
+                    } else if bits > 32 {
+                        on = builder.ins().ireduce(types::I32, on);
+                    }
+                    // Predicates out of range take the last jump.
+                    let mut table = vec![];
+                    for jump in jumps {
+                        let args = emitter.args(&mut builder, jump);
+                        let block = emitter.blocks[jump.block.0];
+                        table.push(builder.func.dfg.block_call(block, &args));
+                    }
+                    let default = *table.last().unwrap();
+                    let table = builder.create_jump_table(JumpTableData::new(default, &table));
+                    builder.ins().br_table(on, table);
+                }
+                Terminator::Return(results) => {
+                    let results = emitter.values(results);
+                    builder.ins().return_(&results);
+                }
+            }
+        }
+        builder.seal_all_blocks();
+        builder.finalize();
+        Ok(func)
+    }
+}
+
+struct Emitter {
+    /// The block each block of the CFG is written as.
+    blocks: Vec<ir::Block>,
+    /// Whether the parameters of each block have been added, which is done
+    /// on the first jump there, once the types of the arguments are known.
+    has_params: Vec<bool>,
+    values: HashMap<Var, Value>,
+}
+
+impl Emitter {
+    fn values(&self, vars: &[Var]) -> Vec<Value> {
+        vars.iter().map(|var| self.values[var]).collect()
+    }
+
+    fn args(&mut self, builder: &mut FunctionBuilder, jump: &Jump) -> Vec<BlockArg> {
+        let args = self.values(&jump.args);
+        let target = jump.block.0;
+        if !self.has_params[target] {
+            for &arg in &args {
+                let ty = builder.func.dfg.value_type(arg);
+                builder.append_block_param(self.blocks[target], ty);
+            }
+            self.has_params[target] = true;
+        }
+        args.into_iter().map(BlockArg::Value).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClifError, ClifOp};
+    use crate::rvsdg::{NodeCtxt, NodeKind, Sig, SigS};
+    use cranelift_codegen::{
+        ir::{
+            condcodes::IntCC, types, AbiParam, Function, InstBuilder, InstructionData, MemFlags,
+            Opcode, Signature, Type, UserFuncName, Value,
+        },
+        isa::CallConv,
+        settings, verify_function,
+    };
+    use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    enum Op {
+        Iconst(Type, i64),
+        Iadd,
+        Isub,
+        Icmp(IntCC),
+        IcmpImm(IntCC, i64),
+        Load(Type),
+        Store,
+    }
+
+    impl Sig for Op {
+        fn sig(&self) -> SigS {
+            match self {
+                Op::Iconst(..) => SigS {
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+                Op::Iadd | Op::Isub | Op::Icmp(..) => SigS {
+                    val_ins: 2,
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+                Op::IcmpImm(..) => SigS {
+                    val_ins: 1,
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+                Op::Load(..) => SigS {
+                    val_ins: 1,
+                    val_outs: 1,
+                    st_ins: 1,
+                    st_outs: 1,
+                },
+                Op::Store => SigS {
+                    val_ins: 2,
+                    st_ins: 1,
+                    st_outs: 1,
+                    ..SigS::default()
+                },
+            }
+        }
+    }
+
+    impl ClifOp for Op {
+        fn from_clif(func: &Function, inst: cranelift_codegen::ir::Inst) -> Option<Op> {
+            let result_ty = || func.dfg.value_type(func.dfg.first_result(inst));
+            Some(match func.dfg.insts[inst] {
+                InstructionData::UnaryImm {
+                    opcode: Opcode::Iconst,
+                    imm,
+                } => Op::Iconst(result_ty(), imm.bits()),
+                InstructionData::Binary {
+                    opcode: Opcode::Iadd,
+                    ..
+                } => Op::Iadd,
+                InstructionData::Binary {
+                    opcode: Opcode::Isub,
+                    ..
+                } => Op::Isub,
+                InstructionData::IntCompare { cond, .. } => Op::Icmp(cond),
+                InstructionData::IntCompareImm { cond, imm, .. } => Op::IcmpImm(cond, imm.bits()),
+                InstructionData::Load {
+                    opcode: Opcode::Load,
+                    ..
+                } => Op::Load(result_ty()),
+                InstructionData::Store {
+                    opcode: Opcode::Store,
+                    ..
+                } => Op::Store,
+                _ => return None,
+            })
+        }
+
+        fn to_clif(&self, builder: &mut FunctionBuilder, args: &[Value]) -> Vec<Value> {
+            let ins = builder.ins();
+            match *self {
+                Op::Iconst(ty, imm) => vec![ins.iconst(ty, imm)],
+                Op::Iadd => vec![ins.iadd(args[0], args[1])],
+                Op::Isub => vec![ins.isub(args[0], args[1])],
+                Op::Icmp(cond) => vec![ins.icmp(cond, args[0], args[1])],
+                Op::IcmpImm(cond, imm) => vec![ins.icmp_imm(cond, args[0], imm)],
+                Op::Load(ty) => vec![ins.load(ty, MemFlags::new(), args[0], 0)],
+                Op::Store => {
+                    ins.store(MemFlags::new(), args[0], args[1], 0);
+                    vec![]
+                }
+            }
+        }
+
+        fn undef(ty: Type) -> Op {
+            Op::Iconst(ty, 0)
+        }
+
+        fn is_zero(_: Type) -> Op {
+            Op::IcmpImm(IntCC::Equal, 0)
+        }
+
+        fn is_nonzero(_: Type) -> Op {
+            Op::IcmpImm(IntCC::NotEqual, 0)
+        }
+    }
+
+    fn signature(params: usize, returns: usize) -> Signature {
+        let mut sig = Signature::new(CallConv::SystemV);
+        sig.params = vec![AbiParam::new(types::I64); params];
+        sig.returns = vec![AbiParam::new(types::I64); returns];
+        sig
+    }
+
+    /// Builds a function taking and returning `i64`s with `f`.
+    fn build<F>(name: &str, params: usize, returns: usize, f: F) -> Function
+    where
+        F: FnOnce(&mut FunctionBuilder),
+    {
+        let sig = signature(params, returns);
+        let mut func = Function::with_name_signature(UserFuncName::testcase(name), sig);
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+        f(&mut builder);
+        builder.seal_all_blocks();
+        builder.finalize();
+        verify(&func);
+        func
+    }
+
+    fn verify(func: &Function) {
+        let flags = settings::Flags::new(settings::builder());
+        if let Err(errors) = verify_function(func, &flags) {
+            panic!("{}\n{}", errors, func.display());
+        }
+    }
+
+    /// Runs a function on integers, with memory as a map from addresses to
+    /// the values stored there.
+    fn run(func: &Function, args: &[i64], memory: &mut HashMap<i64, i64>) -> Vec<i64> {
+        let dfg = &func.dfg;
+        let mut values: HashMap<Value, i64> = HashMap::new();
+        let mut block = func.layout.entry_block().unwrap();
+        let mut block_args = args.to_vec();
+        loop {
+            for (&param, &arg) in dfg.block_params(block).iter().zip(&block_args) {
+                values.insert(param, arg);
+            }
+            for inst in func.layout.block_insts(block) {
+                let args: Vec<i64> = dfg
+                    .inst_args(inst)
+                    .iter()
+                    .map(|&arg| values[&dfg.resolve_aliases(arg)])
+                    .collect();
+                let jump = |dest: &cranelift_codegen::ir::BlockCall| {
+                    let args = dest.args(&dfg.value_lists).map(|arg| match arg {
+                        cranelift_codegen::ir::BlockArg::Value(value) => {
+                            values[&dfg.resolve_aliases(value)]
+                        }
+                        _ => unreachable!(),
+                    });
+                    (dest.block(&dfg.value_lists), args.collect::<Vec<_>>())
+                };
+                let dests =
+                    dfg.insts[inst].branch_destination(&dfg.jump_tables, &dfg.exception_tables);
+                let result = match dfg.insts[inst] {
+                    InstructionData::UnaryImm { imm, .. } => imm.bits(),
+                    InstructionData::Binary { opcode, .. } => match opcode {
+                        Opcode::Iadd => args[0].wrapping_add(args[1]),
+                        _ => args[0].wrapping_sub(args[1]),
+                    },
+                    InstructionData::IntCompare { cond, .. } => compare(cond, args[0], args[1]),
+                    InstructionData::IntCompareImm { cond, imm, .. } => {
+                        compare(cond, args[0], imm.bits())
+                    }
+                    InstructionData::Unary { .. } => args[0],
+                    InstructionData::Load { .. } => *memory.get(&args[0]).unwrap_or(&0),
+                    InstructionData::Store { .. } => {
+                        memory.insert(args[1], args[0]);
+                        continue;
+                    }
+                    InstructionData::Jump { .. } => {
+                        let (target, args) = jump(&dests[0]);
+                        block = target;
+                        block_args = args;
+                        break;
+                    }
+                    InstructionData::Brif { .. } => {
+                        let (target, args) = jump(&dests[if args[0] != 0 { 0 } else { 1 }]);
+                        block = target;
+                        block_args = args;
+                        break;
+                    }
+                    InstructionData::BranchTable { .. } => {
+                        let index = args[0] as usize + 1;
+                        let (target, args) = jump(dests.get(index).unwrap_or(&dests[0]));
+                        block = target;
+                        block_args = args;
+                        break;
+                    }
+                    _ => return args,
+                };
+                values.insert(dfg.first_result(inst), result);
+            }
+        }
+    }
+
+    fn compare(cond: IntCC, a: i64, b: i64) -> i64 {
+        let result = match cond {
+            IntCC::Equal => a == b,
+            IntCC::NotEqual => a != b,
+            IntCC::SignedLessThan => a < b,
+            _ => unimplemented!(),
+        };
+        result as i64
+    }
+
+    fn round_trip(func: &Function) -> Function {
+        let ncx = NodeCtxt::<Op>::from_clif(func).unwrap();
+        let lambda = ncx.region_ref(ncx.root_region()).nodes().next().unwrap();
+        assert_eq!(Some(func.name.to_string()), lambda.name());
+        let out = ncx
+            .to_clif(lambda.id(), func.name.clone(), func.signature.clone())
+            .unwrap();
+        verify(&out);
+        out
+    }
+
+    #[test]
+    fn branches_become_gammas() {
+        // Returns the larger of two values, storing the smaller at 0.
+        let func = build("max", 2, 1, |builder| {
+            let entry = builder.create_block();
+            let smaller = builder.create_block();
+            let join = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            let result = builder.append_block_param(join, types::I64);
+            builder.switch_to_block(entry);
+            let a = builder.block_params(entry)[0];
+            let b = builder.block_params(entry)[1];
+            let zero = builder.ins().iconst(types::I64, 0);
+            let less = builder.ins().icmp(IntCC::SignedLessThan, a, b);
+            builder.ins().brif(less, smaller, &[], join, &[a.into()]);
+            builder.switch_to_block(smaller);
+            builder.ins().store(MemFlags::new(), a, zero, 0);
+            builder.ins().jump(join, &[b.into()]);
+            builder.switch_to_block(join);
+            builder.ins().return_(&[result]);
+        });
+
+        let ncx = NodeCtxt::<Op>::from_clif(&func).unwrap();
+        let lambda = ncx.region_ref(ncx.root_region()).nodes().next().unwrap();
+        let body = ncx.inner_regions(lambda.id())[0];
+        let result = ncx.region_ref(body).val_res(0).origin().producer();
+        assert!(matches!(*result.kind(), NodeKind::Gamma { .. }));
+
+        let out = round_trip(&func);
+        for &(a, b) in &[(1, 2), (5, -3), (4, 4)] {
+            let mut memory = HashMap::new();
+            let mut out_memory = HashMap::new();
+            assert_eq!(
+                run(&func, &[a, b], &mut memory),
+                run(&out, &[a, b], &mut out_memory)
+            );
+            assert_eq!(memory, out_memory);
+        }
+    }
+
+    #[test]
+    fn loops_become_thetas() {
+        // Sums the values from 0 up to its parameter, keeping the last one.
+        let func = build("sum", 1, 2, |builder| {
+            let entry = builder.create_block();
+            let header = builder.create_block();
+            let exit = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            let i = builder.append_block_param(header, types::I64);
+            let sum = builder.append_block_param(header, types::I64);
+            let last = builder.append_block_param(exit, types::I64);
+            builder.switch_to_block(entry);
+            let n = builder.block_params(entry)[0];
+            let zero = builder.ins().iconst(types::I64, 0);
+            builder.ins().jump(header, &[zero.into(), zero.into()]);
+            builder.switch_to_block(header);
+            let next_sum = builder.ins().iadd(sum, i);
+            let one = builder.ins().iconst(types::I64, 1);
+            let next = builder.ins().iadd(i, one);
+            let more = builder.ins().icmp(IntCC::SignedLessThan, next, n);
+            builder.ins().brif(
+                more,
+                header,
+                &[next.into(), next_sum.into()],
+                exit,
+                &[i.into()],
+            );
+            builder.switch_to_block(exit);
+            builder.ins().return_(&[next_sum, last]);
+        });
+
+        let ncx = NodeCtxt::<Op>::from_clif(&func).unwrap();
+        let lambda = ncx.region_ref(ncx.root_region()).nodes().next().unwrap();
+        let body = ncx.inner_regions(lambda.id())[0];
+        let result = ncx.region_ref(body).val_res(0).origin().producer();
+        assert!(matches!(*result.kind(), NodeKind::Theta { .. }));
+
+        let out = round_trip(&func);
+        let again = round_trip(&out);
+        for &n in &[0, 1, 5] {
+            let expected = vec![(0..n.max(1)).sum(), n.max(1) - 1];
+            assert_eq!(expected, run(&func, &[n], &mut HashMap::new()));
+            assert_eq!(expected, run(&out, &[n], &mut HashMap::new()));
+            assert_eq!(expected, run(&again, &[n], &mut HashMap::new()));
+        }
+    }
+
+    #[test]
+    fn unsupported_code() {
+        let func = build("trap", 1, 1, |builder| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let y = builder.ins().imul(x, x);
+            builder.ins().return_(&[y]);
+        });
+        let imul = func.layout.first_inst(func.layout.entry_block().unwrap());
+        assert_eq!(
+            Err(ClifError::Unsupported(imul.unwrap())),
+            NodeCtxt::<Op>::from_clif(&func).map(|_| ())
+        );
+
+        let ncx = NodeCtxt::<Op>::from_clif(&build("id", 1, 1, |builder| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            builder.ins().return_(&[x]);
+        }))
+        .unwrap();
+        let lambda = ncx.region_ref(ncx.root_region()).nodes().next().unwrap();
+        assert_eq!(
+            Err(ClifError::Signature(lambda.id())),
+            ncx.to_clif(lambda.id(), UserFuncName::default(), signature(2, 1))
+                .map(|_| ())
+        );
+    }
+}
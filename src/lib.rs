@@ -1,4 +1,55 @@
-#![feature(hash_raw_entry)]
+#![feature(associated_type_defaults, hash_raw_entry)]
 
 mod rvsdg;
 mod lower;
+mod ssa;
+mod workload;
+#[cfg(any(feature = "llvm", feature = "cranelift"))]
+mod structure;
+#[cfg(feature = "cranelift")]
+mod clif;
+#[cfg(feature = "llvm")]
+mod llvm;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use crate::rvsdg::{
+    declared_output_types, AliasAnalysis, ArityError, AvailableOrigin, Block, BlockId, BuildError,
+    CallGraph, Cfg, CfgError, Changed, ConnectError, ConstBranch, CostModel, DataflowAnalysis,
+    DataflowSolution, DecodeError, Direction, Distinction, Dominators, DotOptions, EGraph,
+    EffectSummary, ExternalDep, Fold, FrozenGraph, GammaBuilder, GraphStats, IdMap, InlineSite,
+    Input, InsertionPoint, Inst, InternCounts, InternTableStats, InternedTerm, InterningPolicy,
+    JoinStates, Jump, LambdaBuilder, Liveness, MemoryAccess, MemoryOp, Node, NodeBuilder, NodeCtxt,
+    NodeCtxtConfig, NodeId, NodeKind, NodeMap, Observable, OpProperties, Origin, OriginId, Output,
+    ParseError, Pass, PassManager, PassStats, Pattern, PlacementModel, PortKind, PortType,
+    PressurePriority, RankDir, Region, RegionHeights, RegionId, RegionMap, RegionSummary,
+    Remapping, Replacement, Rewriter, SchedulePriority, Sig, SigS, Span, SplitState, StOrigin,
+    StUser, Switch, SwitchBuilder, Terminator, ThetaBuilder, TypeError, TypeRule, UserId,
+    ValOrigin, ValUser, ValueNumbering, Var, Violation,
+};
+
+pub use crate::ssa::SsaBuilder;
+
+pub use crate::workload::{
+    deep_expression_chain, deeply_nested_gammas, heavy_state_chain, wide_fan_out, WorkOp,
+};
+
+#[cfg(feature = "serde")]
+pub use crate::rvsdg::LoadError;
+
+#[cfg(feature = "petgraph")]
+pub use crate::rvsdg::RegionGraph;
+
+#[cfg(feature = "testing")]
+pub use crate::rvsdg::{check_pass_outputs, Divergence, Rng};
+
+#[cfg(feature = "cranelift")]
+pub use crate::clif::{ClifError, ClifOp};
+
+#[cfg(feature = "llvm")]
+pub use crate::llvm::{
+    BinOp, CastOp, EmitError, IcmpPred, LlvmFunction, LlvmOp, LlvmType, LlvmValue, LowerToLlvm,
+};
+
+#[cfg(feature = "wasm")]
+pub use crate::wasm::{WasmEmitError, WasmError, WasmOp, WasmType};
@@ -0,0 +1,1354 @@
+//! A frontend reading functions written in LLVM's textual IR.
+//!
+//! Each function becomes a lambda in the root region, named after it, taking
+//! its parameters followed by a state and producing its return value, if it
+//! has one, followed by the state. Memory accesses, allocations and calls
+//! are threaded on that state in the order they run in. Calls are kept as
+//! ops naming their callee rather than applies, so that functions may call
+//! each other in any order.
+//!
+//! Only a subset of the IR is understood: integer and pointer values, the
+//! integer arithmetic, comparison and conversion instructions, `select`,
+//! `phi`, `alloca`, `load`, `store`, direct calls, `br` and `ret`. Wrapping
+//! and exactness flags are dropped. Branches must have an immediate
+//! post-dominator they all meet again at, or otherwise all return, and loops
+//! must only be left from the block branching back to their header, as
+//! loops rotated by LLVM are.
+//!
+//! Graphs are written back out by `NodeCtxt::to_llvm_ir`, for any op that
+//! implements `LowerToLlvm`.
+
+mod emit;
+
+pub use self::emit::{EmitError, LlvmFunction, LlvmValue, LowerToLlvm};
+
+use crate::{
+    rvsdg::{LambdaBuilder, NodeCtxt, OpProperties, ParseError, PortKind, Sig, SigS, ValOrigin},
+    ssa::SsaBuilder,
+    structure::{FlowBlock, Structure, StructureError},
+};
+use std::collections::HashMap;
+
+/// The type of an LLVM value.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LlvmType {
+    /// An integer of the given number of bits.
+    Int(u32),
+    Ptr,
+    Void,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    UDiv,
+    SDiv,
+    URem,
+    SRem,
+    Shl,
+    LShr,
+    AShr,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum IcmpPred {
+    Eq,
+    Ne,
+    Ugt,
+    Uge,
+    Ult,
+    Ule,
+    Sgt,
+    Sge,
+    Slt,
+    Sle,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum CastOp {
+    Trunc,
+    ZExt,
+    SExt,
+    PtrToInt,
+    IntToPtr,
+    BitCast,
+}
+
+/// The ops of graphs read by `NodeCtxt::parse_llvm_ir`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum LlvmOp {
+    /// An integer of `bits` bits, whose bits above those are clear.
+    Int {
+        bits: u32,
+        value: u64,
+    },
+    Null,
+    /// An `undef` or `poison` value of the given type.
+    Undef(LlvmType),
+    Bin(BinOp),
+    Icmp(IcmpPred),
+    /// Takes the condition followed by the values to pick from.
+    Select,
+    Cast(CastOp, LlvmType),
+    /// Allocates room for a value of the given type on the stack.
+    Alloca(LlvmType),
+    Load(LlvmType),
+    /// Takes the value to store followed by the pointer.
+    Store,
+    Call {
+        callee: String,
+        args: usize,
+        ret: LlvmType,
+    },
+}
+
+impl Sig for LlvmOp {
+    fn sig(&self) -> SigS {
+        match self {
+            LlvmOp::Int { .. } | LlvmOp::Null | LlvmOp::Undef(..) => SigS {
+                val_outs: 1,
+                ..SigS::default()
+            },
+            LlvmOp::Bin(..) | LlvmOp::Icmp(..) => SigS {
+                val_ins: 2,
+                val_outs: 1,
+                ..SigS::default()
+            },
+            LlvmOp::Select => SigS {
+                val_ins: 3,
+                val_outs: 1,
+                ..SigS::default()
+            },
+            LlvmOp::Cast(..) => SigS {
+                val_ins: 1,
+                val_outs: 1,
+                ..SigS::default()
+            },
+            LlvmOp::Alloca(..) => SigS {
+                st_ins: 1,
+                val_outs: 1,
+                st_outs: 1,
+                ..SigS::default()
+            },
+            LlvmOp::Load(..) => SigS {
+                val_ins: 1,
+                st_ins: 1,
+                val_outs: 1,
+                st_outs: 1,
+            },
+            LlvmOp::Store => SigS {
+                val_ins: 2,
+                st_ins: 1,
+                st_outs: 1,
+                ..SigS::default()
+            },
+            LlvmOp::Call { args, ret, .. } => SigS {
+                val_ins: *args,
+                st_ins: 1,
+                val_outs: if *ret == LlvmType::Void { 0 } else { 1 },
+                st_outs: 1,
+            },
+        }
+    }
+
+    fn properties(&self) -> &dyn OpProperties {
+        self
+    }
+}
+
+/// Integer arithmetic wraps around, so it regroups freely. Comparisons for
+/// equality commute, but they give booleans rather than their operands'
+/// type, so they can't be regrouped.
+impl OpProperties for LlvmOp {
+    fn is_commutative(&self) -> bool {
+        matches!(
+            self,
+            LlvmOp::Bin(BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor)
+                | LlvmOp::Icmp(IcmpPred::Eq | IcmpPred::Ne)
+        )
+    }
+
+    fn is_associative(&self) -> bool {
+        matches!(
+            self,
+            LlvmOp::Bin(BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor)
+        )
+    }
+}
+
+impl NodeCtxt<LlvmOp> {
+    /// Reads the functions defined in `input`, written in LLVM's textual
+    /// IR. Declarations, globals, attributes and metadata are skipped.
+    ///
+    /// The graph is verified before it's returned.
+    pub fn parse_llvm_ir(input: &str) -> Result<NodeCtxt<LlvmOp>, ParseError> {
+        let functions = parse_module(input)?;
+        let ncx = NodeCtxt::new();
+        for function in &functions {
+            let cfg = function_cfg(function)?;
+            import_function(&ncx, function, &cfg)?;
+        }
+
+        let last_line = input.lines().count().max(1);
+        ncx.verify().map_err(|violations| ParseError {
+            line: last_line,
+            message: format!("malformed graph: {:?}", violations),
+        })?;
+        Ok(ncx)
+    }
+}
+
+/// An operand of an instruction, along with its type.
+#[derive(Clone, PartialEq, Debug)]
+struct Value {
+    operand: Operand,
+    ty: LlvmType,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Operand {
+    /// A value defined in the function, without its `%`.
+    Local(String),
+    Int(i64),
+    Null,
+    Undef,
+}
+
+enum Inst {
+    Op {
+        result: Option<String>,
+        op: LlvmOp,
+        operands: Vec<Value>,
+    },
+    Phi {
+        result: String,
+        incoming: Vec<(Value, Label)>,
+    },
+}
+
+/// A block a branch or phi refers to, by its name until every block of the
+/// function is known, and by its index after.
+#[derive(Clone, PartialEq, Debug)]
+enum Label {
+    Name(String),
+    Block(usize),
+}
+
+impl Label {
+    fn block(&self) -> usize {
+        match self {
+            Label::Block(block) => *block,
+            Label::Name(..) => unreachable!(),
+        }
+    }
+}
+
+enum Term {
+    Ret(Option<Value>),
+    Br(Label),
+    CondBr(Value, Label, Label),
+}
+
+struct Block {
+    name: String,
+    insts: Vec<(usize, Inst)>,
+    term: Option<(usize, Term)>,
+}
+
+struct Function {
+    name: String,
+    line: usize,
+    params: Vec<String>,
+    ret: LlvmType,
+    blocks: Vec<Block>,
+    /// The types of the parameters of the function and the values its
+    /// instructions define.
+    types: HashMap<String, LlvmType>,
+}
+
+impl Block {
+    fn term(&self) -> &(usize, Term) {
+        self.term.as_ref().unwrap()
+    }
+
+    fn succs(&self) -> Vec<usize> {
+        match &self.term().1 {
+            Term::Ret(..) => vec![],
+            Term::Br(target) => vec![target.block()],
+            Term::CondBr(_, if_true, if_false) => vec![if_true.block(), if_false.block()],
+        }
+    }
+
+    /// The values defined in the function the block uses.
+    fn uses(&self) -> impl Iterator<Item = &str> {
+        let insts = self.insts.iter().flat_map(|(_, inst)| match inst {
+            Inst::Op { operands, .. } => operands.iter().collect::<Vec<_>>(),
+            Inst::Phi { incoming, .. } => incoming.iter().map(|(value, _)| value).collect(),
+        });
+        let term = match &self.term().1 {
+            Term::Ret(value) => value.iter().collect(),
+            Term::Br(..) => vec![],
+            Term::CondBr(value, ..) => vec![value],
+        };
+        insts.chain(term).filter_map(|value| match &value.operand {
+            Operand::Local(name) => Some(&name[..]),
+            _ => None,
+        })
+    }
+
+    fn defs(&self) -> impl Iterator<Item = &str> {
+        self.insts.iter().filter_map(|(_, inst)| match inst {
+            Inst::Op { result, .. } => result.as_deref(),
+            Inst::Phi { result, .. } => Some(&result[..]),
+        })
+    }
+}
+
+fn parse_module(input: &str) -> Result<Vec<Function>, ParseError> {
+    let mut functions = vec![];
+    let mut function: Option<Function> = None;
+    for (line_index, text) in input.lines().enumerate() {
+        let line = line_index + 1;
+        let tokens = tokenize(text);
+        if tokens.is_empty() {
+            continue;
+        }
+        if function.is_some() && tokens[0] == "}" {
+            functions.push(resolve_labels(function.take().unwrap())?);
+            continue;
+        }
+        let function_ref = match &mut function {
+            Some(function) => function,
+            None => {
+                if tokens[0] == "define" {
+                    function = Some(Line::new(&tokens, line).parse_define()?);
+                }
+                continue;
+            }
+        };
+        if tokens.len() == 1 && tokens[0].ends_with(':') {
+            let name = tokens[0].trim_end_matches(':').to_owned();
+            function_ref.blocks.push(Block {
+                name,
+                insts: vec![],
+                term: None,
+            });
+            continue;
+        }
+
+        // The entry block may be left unnamed, as nothing branches to it.
+        if function_ref.blocks.is_empty() {
+            function_ref.blocks.push(Block {
+                name: String::new(),
+                insts: vec![],
+                term: None,
+            });
+        }
+        let block = function_ref.blocks.last_mut().unwrap();
+        if block.term.is_some() {
+            let message = format!("block `%{}` goes on after its terminator", block.name);
+            return Err(ParseError { line, message });
+        }
+        match Line::new(&tokens, line).parse_inst()? {
+            Parsed::Inst(inst) => {
+                let defined = match &inst {
+                    Inst::Op {
+                        result: Some(result),
+                        op,
+                        operands,
+                    } => {
+                        let args: Vec<_> = operands.iter().map(|operand| operand.ty).collect();
+                        Some((result, op.llvm_result_types(&args)[0]))
+                    }
+                    Inst::Phi { result, incoming } => Some((result, incoming[0].0.ty)),
+                    Inst::Op { result: None, .. } => None,
+                };
+                if let Some((result, ty)) = defined {
+                    function_ref.types.insert(result.clone(), ty);
+                }
+                block.insts.push((line, inst));
+            }
+            Parsed::Term(term) => block.term = Some((line, term)),
+        }
+    }
+    if let Some(function) = function {
+        let message = format!("function `@{}` isn't closed", function.name);
+        return Err(ParseError {
+            line: function.line,
+            message,
+        });
+    }
+    Ok(functions)
+}
+
+/// Resolves the labels of the branches and phis of a function to the
+/// indices of its blocks.
+fn resolve_labels(mut function: Function) -> Result<Function, ParseError> {
+    let indices: HashMap<String, usize> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| (block.name.clone(), index))
+        .collect();
+    let resolve = |label: &mut Label, line| match label {
+        Label::Name(name) => match indices.get(name) {
+            Some(&index) => {
+                *label = Label::Block(index);
+                Ok(())
+            }
+            None => Err(ParseError {
+                line,
+                message: format!("there's no block `%{}`", name),
+            }),
+        },
+        Label::Block(..) => Ok(()),
+    };
+
+    if function.blocks.is_empty() {
+        let message = format!("function `@{}` has no blocks", function.name);
+        return Err(ParseError {
+            line: function.line,
+            message,
+        });
+    }
+    for block in &mut function.blocks {
+        for (line, inst) in &mut block.insts {
+            if let Inst::Phi { incoming, .. } = inst {
+                for (_, label) in incoming {
+                    resolve(label, *line)?;
+                }
+            }
+        }
+        match &mut block.term {
+            Some((line, Term::Br(target))) => resolve(target, *line)?,
+            Some((line, Term::CondBr(_, if_true, if_false))) => {
+                resolve(if_true, *line)?;
+                resolve(if_false, *line)?;
+            }
+            Some((_, Term::Ret(..))) => {}
+            None => {
+                let message = format!("block `%{}` has no terminator", block.name);
+                return Err(ParseError {
+                    line: function.line,
+                    message,
+                });
+            }
+        }
+    }
+    Ok(function)
+}
+
+/// Splits a line into words and punctuation, dropping comments.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            _ if c.is_whitespace() => {}
+            '(' | ')' | '[' | ']' | '{' | '}' | ',' | '=' => tokens.push(c.to_string()),
+            '"' => {
+                let mut word = c.to_string();
+                for c in chars.by_ref() {
+                    word.push(c);
+                    if c == '"' {
+                        break;
+                    }
+                }
+                tokens.push(word);
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()[]{},=;".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(word);
+            }
+        }
+    }
+
+    // Alignments and metadata attachments come last, and don't matter here.
+    let trailing = tokens
+        .windows(2)
+        .position(|pair| pair[0] == "," && (pair[1] == "align" || pair[1].starts_with('!')));
+    if let Some(index) = trailing {
+        tokens.truncate(index);
+    }
+    tokens
+}
+
+enum Parsed {
+    Inst(Inst),
+    Term(Term),
+}
+
+/// The tokens of a line, being parsed.
+struct Line<'t> {
+    tokens: &'t [String],
+    pos: usize,
+    line: usize,
+}
+
+impl<'t> Line<'t> {
+    fn new(tokens: &'t [String], line: usize) -> Line<'t> {
+        Line {
+            tokens,
+            pos: 0,
+            line,
+        }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<&'t str> {
+        self.tokens.get(self.pos).map(|token| &token[..])
+    }
+
+    fn next(&mut self) -> Result<&'t str, ParseError> {
+        match self.peek() {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            None => Err(self.error("unexpected end of line".to_owned())),
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ParseError> {
+        match self.next()? {
+            found if found == token => Ok(()),
+            found => Err(self.error(format!("expected `{}`, found `{}`", token, found))),
+        }
+    }
+
+    fn finish(&self) -> Result<(), ParseError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(self.error(format!("unexpected `{}`", token))),
+        }
+    }
+
+    fn ty(&mut self) -> Result<LlvmType, ParseError> {
+        let token = self.next()?;
+        if token == "ptr" || token.ends_with('*') {
+            return Ok(LlvmType::Ptr);
+        }
+        if token == "void" {
+            return Ok(LlvmType::Void);
+        }
+        match token.strip_prefix('i').map(str::parse) {
+            Some(Ok(bits)) if bits > 0 && bits <= 64 => Ok(LlvmType::Int(bits)),
+            _ => Err(self.error(format!("unsupported type `{}`", token))),
+        }
+    }
+
+    /// A value defined in the function, or a block, without its `%`.
+    fn local(&mut self) -> Result<String, ParseError> {
+        let token = self.next()?;
+        match token.strip_prefix('%') {
+            Some(name) => Ok(name.to_owned()),
+            None => Err(self.error(format!("expected a local name, found `{}`", token))),
+        }
+    }
+
+    fn value(&mut self, ty: LlvmType) -> Result<Value, ParseError> {
+        let token = self.next()?;
+        let operand = match token {
+            "true" => Operand::Int(1),
+            "false" => Operand::Int(0),
+            "null" => Operand::Null,
+            "undef" | "poison" => Operand::Undef,
+            _ if token.starts_with('%') => Operand::Local(token[1..].to_owned()),
+            _ => match token.parse() {
+                Ok(value) => Operand::Int(value),
+                Err(_) => return Err(self.error(format!("unsupported operand `{}`", token))),
+            },
+        };
+        Ok(Value { operand, ty })
+    }
+
+    /// A type followed by a value of it.
+    fn typed_value(&mut self) -> Result<Value, ParseError> {
+        let ty = self.ty()?;
+        self.value(ty)
+    }
+
+    /// Parses the first line of a function definition, up to its `{`.
+    fn parse_define(&mut self) -> Result<Function, ParseError> {
+        let at = self.tokens.iter().position(|token| token.starts_with('@'));
+        let at = match at {
+            Some(at) if at > 0 => at,
+            _ => return Err(self.error("expected the name of the function".to_owned())),
+        };
+        self.pos = at - 1;
+        let ret = self.ty()?;
+        let name = self.next()?[1..].trim_matches('"').to_owned();
+        self.expect("(")?;
+
+        let mut params = vec![];
+        let mut types = HashMap::new();
+        while !self.eat(")") {
+            if !params.is_empty() {
+                self.expect(",")?;
+            }
+            let ty = self.ty()?;
+            if ty == LlvmType::Void {
+                return Err(self.error("parameters can't be void".to_owned()));
+            }
+            // Skips attributes, some of which take arguments.
+            let mut depth = 0;
+            let mut last = None;
+            while let Some(token) = self.peek() {
+                match token {
+                    "," | ")" if depth == 0 => break,
+                    "(" => depth += 1,
+                    ")" => depth -= 1,
+                    _ => {}
+                }
+                last = Some(self.next()?);
+            }
+            match last.and_then(|token| token.strip_prefix('%')) {
+                Some(param) => {
+                    types.insert(param.to_owned(), ty);
+                    params.push(param.to_owned());
+                }
+                None => return Err(self.error("parameters must be named".to_owned())),
+            }
+        }
+        if self.tokens.last().map(|token| &token[..]) != Some("{") {
+            return Err(self.error("expected `{` at the end of the line".to_owned()));
+        }
+        Ok(Function {
+            name,
+            line: self.line,
+            params,
+            ret,
+            blocks: vec![],
+            types,
+        })
+    }
+
+    fn parse_inst(&mut self) -> Result<Parsed, ParseError> {
+        let result = if self.tokens.get(1).map(|token| &token[..]) == Some("=") {
+            let result = self.local()?;
+            self.expect("=")?;
+            Some(result)
+        } else {
+            None
+        };
+        let mut opcode = self.next()?;
+        if let "tail" | "musttail" | "notail" = opcode {
+            opcode = self.next()?;
+        }
+
+        let (op, operands) = match opcode {
+            "add" | "sub" | "mul" | "udiv" | "sdiv" | "urem" | "srem" | "shl" | "lshr" | "ashr"
+            | "and" | "or" | "xor" => {
+                while let Some("nsw" | "nuw" | "exact" | "disjoint") = self.peek() {
+                    self.pos += 1;
+                }
+                let ty = self.ty()?;
+                let lhs = self.value(ty)?;
+                self.expect(",")?;
+                let rhs = self.value(ty)?;
+                (LlvmOp::Bin(bin_op(opcode)), vec![lhs, rhs])
+            }
+            "icmp" => {
+                let pred = match self.next()? {
+                    "eq" => IcmpPred::Eq,
+                    "ne" => IcmpPred::Ne,
+                    "ugt" => IcmpPred::Ugt,
+                    "uge" => IcmpPred::Uge,
+                    "ult" => IcmpPred::Ult,
+                    "ule" => IcmpPred::Ule,
+                    "sgt" => IcmpPred::Sgt,
+                    "sge" => IcmpPred::Sge,
+                    "slt" => IcmpPred::Slt,
+                    "sle" => IcmpPred::Sle,
+                    pred => return Err(self.error(format!("unknown predicate `{}`", pred))),
+                };
+                let ty = self.ty()?;
+                let lhs = self.value(ty)?;
+                self.expect(",")?;
+                let rhs = self.value(ty)?;
+                (LlvmOp::Icmp(pred), vec![lhs, rhs])
+            }
+            "select" => {
+                let cond = self.typed_value()?;
+                self.expect(",")?;
+                let if_true = self.typed_value()?;
+                self.expect(",")?;
+                let if_false = self.typed_value()?;
+                (LlvmOp::Select, vec![cond, if_true, if_false])
+            }
+            "trunc" | "zext" | "sext" | "ptrtoint" | "inttoptr" | "bitcast" => {
+                let cast_op = match opcode {
+                    "trunc" => CastOp::Trunc,
+                    "zext" => CastOp::ZExt,
+                    "sext" => CastOp::SExt,
+                    "ptrtoint" => CastOp::PtrToInt,
+                    "inttoptr" => CastOp::IntToPtr,
+                    _ => CastOp::BitCast,
+                };
+                let value = self.typed_value()?;
+                self.expect("to")?;
+                (LlvmOp::Cast(cast_op, self.ty()?), vec![value])
+            }
+            "alloca" => {
+                let ty = self.ty()?;
+                if self.peek().is_some() {
+                    return Err(self.error("array allocations aren't supported".to_owned()));
+                }
+                (LlvmOp::Alloca(ty), vec![])
+            }
+            "load" => {
+                self.volatile()?;
+                let ty = self.ty()?;
+                self.expect(",")?;
+                (LlvmOp::Load(ty), vec![self.typed_value()?])
+            }
+            "store" => {
+                self.volatile()?;
+                let value = self.typed_value()?;
+                self.expect(",")?;
+                (LlvmOp::Store, vec![value, self.typed_value()?])
+            }
+            "call" => self.parse_call()?,
+            "phi" => {
+                let ty = self.ty()?;
+                let mut incoming = vec![];
+                loop {
+                    self.expect("[")?;
+                    let value = self.value(ty)?;
+                    self.expect(",")?;
+                    let label = Label::Name(self.local()?);
+                    self.expect("]")?;
+                    incoming.push((value, label));
+                    if !self.eat(",") {
+                        break;
+                    }
+                }
+                self.finish()?;
+                return match result {
+                    Some(result) => Ok(Parsed::Inst(Inst::Phi { result, incoming })),
+                    None => Err(self.error("phis must be named".to_owned())),
+                };
+            }
+            "br" | "ret" => {
+                let term = self.parse_term(opcode)?;
+                self.finish()?;
+                return Ok(Parsed::Term(term));
+            }
+            _ => return Err(self.error(format!("unsupported instruction `{}`", opcode))),
+        };
+        self.finish()?;
+
+        if result.is_some() && op.sig().val_outs == 0 {
+            return Err(self.error(format!("`{}` doesn't produce a value", opcode)));
+        }
+        Ok(Parsed::Inst(Inst::Op {
+            result,
+            op,
+            operands,
+        }))
+    }
+
+    fn volatile(&mut self) -> Result<(), ParseError> {
+        if self.eat("volatile") {
+            return Err(self.error("volatile accesses aren't supported".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Parses a direct call, after its `call`.
+    fn parse_call(&mut self) -> Result<(LlvmOp, Vec<Value>), ParseError> {
+        let callee = self.tokens[self.pos..]
+            .iter()
+            .position(|token| token.starts_with('@'));
+        let callee = match callee {
+            Some(offset) if offset > 0 => self.pos + offset,
+            _ => return Err(self.error("only direct calls are supported".to_owned())),
+        };
+        // Attributes of the result come before its type.
+        self.pos = callee - 1;
+        let ret = self.ty()?;
+        let callee = self.next()?[1..].trim_matches('"').to_owned();
+        self.expect("(")?;
+
+        let mut args = vec![];
+        while !self.eat(")") {
+            if !args.is_empty() {
+                self.expect(",")?;
+            }
+            let ty = self.ty()?;
+            // Skips attributes of the argument, up to its value.
+            while let Some(token) = self.tokens.get(self.pos + 1) {
+                if token == "," || token == ")" {
+                    break;
+                }
+                self.pos += 1;
+            }
+            args.push(self.value(ty)?);
+        }
+        // Attribute groups of the call.
+        while matches!(self.peek(), Some(token) if token.starts_with('#')) {
+            self.pos += 1;
+        }
+        let op = LlvmOp::Call {
+            callee,
+            args: args.len(),
+            ret,
+        };
+        Ok((op, args))
+    }
+
+    fn parse_term(&mut self, opcode: &str) -> Result<Term, ParseError> {
+        if opcode == "ret" {
+            if self.eat("void") {
+                return Ok(Term::Ret(None));
+            }
+            return Ok(Term::Ret(Some(self.typed_value()?)));
+        }
+        if self.eat("label") {
+            return Ok(Term::Br(Label::Name(self.local()?)));
+        }
+        let cond = self.typed_value()?;
+        self.expect(",")?;
+        self.expect("label")?;
+        let if_true = Label::Name(self.local()?);
+        self.expect(",")?;
+        self.expect("label")?;
+        let if_false = Label::Name(self.local()?);
+        Ok(Term::CondBr(cond, if_true, if_false))
+    }
+}
+
+fn bin_op(opcode: &str) -> BinOp {
+    match opcode {
+        "add" => BinOp::Add,
+        "sub" => BinOp::Sub,
+        "mul" => BinOp::Mul,
+        "udiv" => BinOp::UDiv,
+        "sdiv" => BinOp::SDiv,
+        "urem" => BinOp::URem,
+        "srem" => BinOp::SRem,
+        "shl" => BinOp::Shl,
+        "lshr" => BinOp::LShr,
+        "ashr" => BinOp::AShr,
+        "and" => BinOp::And,
+        "or" => BinOp::Or,
+        _ => BinOp::Xor,
+    }
+}
+
+/// How the blocks of a function nest into branches and loops, with the
+/// values of the function named.
+type FunctionCfg = Structure<String>;
+
+fn function_cfg(function: &Function) -> Result<FunctionCfg, ParseError> {
+    let blocks = &function.blocks;
+    let flow_blocks: Vec<FlowBlock<String>> = blocks
+        .iter()
+        .map(|block| FlowBlock {
+            succs: block.succs(),
+            defs: block.defs().map(str::to_owned).collect(),
+            uses: block.uses().map(str::to_owned).collect(),
+        })
+        .collect();
+    Structure::new(&flow_blocks).map_err(|error| {
+        let (block, message) = match error {
+            StructureError::Irreducible { header, block } => (
+                block,
+                format!("the loop at `%{}` is irreducible", blocks[header].name),
+            ),
+            StructureError::SeveralLatches { header, block } => (
+                block,
+                format!(
+                    "the loop at `%{}` is branched back to from several blocks",
+                    blocks[header].name
+                ),
+            ),
+            StructureError::LeftMidway { header, latch } => (
+                latch,
+                format!(
+                    "the loop at `%{}` has to be left from the block branching back to it",
+                    blocks[header].name
+                ),
+            ),
+        };
+        ParseError {
+            line: blocks[block].term().0,
+            message,
+        }
+    })
+}
+
+/// What the SSA builder keeps track of while a function is imported.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Slot {
+    Value(String),
+    /// The value a phi takes in the block being branched to, written on
+    /// the way there.
+    Phi(String),
+    Ret,
+    State,
+}
+
+fn import_function(
+    ncx: &NodeCtxt<LlvmOp>,
+    function: &Function,
+    cfg: &FunctionCfg,
+) -> Result<(), ParseError> {
+    let mut kinds = vec![PortKind::Val; function.params.len()];
+    kinds.push(PortKind::St);
+    let lambda = ncx.lambda_builder(ncx.root_region(), &kinds);
+    let mut importer = Importer {
+        ncx,
+        ssa: SsaBuilder::new(ncx, lambda.body()),
+        function,
+        cfg,
+        loops: vec![],
+    };
+    for (index, param) in function.params.iter().enumerate() {
+        let slot = Slot::Value(param.clone());
+        importer.ssa.write_val(slot, lambda.val_param(index));
+    }
+    importer.ssa.write_state(Slot::State, lambda.st_param(0));
+    importer.import_from(0, None)?;
+    finish_function(importer, lambda)
+}
+
+fn finish_function<'g>(
+    mut importer: Importer<'_, 'g>,
+    lambda: LambdaBuilder<'g, LlvmOp>,
+) -> Result<(), ParseError> {
+    let function = importer.function;
+    let results = match function.ret {
+        LlvmType::Void => vec![],
+        _ => match importer.ssa.read_val(&Slot::Ret) {
+            Some(result) => vec![result],
+            None => {
+                let message = format!("function `@{}` doesn't return a value", function.name);
+                return Err(ParseError {
+                    line: function.line,
+                    message,
+                });
+            }
+        },
+    };
+    let state = importer.ssa.read_state(&Slot::State).unwrap();
+    let node = lambda.finish(&results, &[state]);
+    node.set_name(function.name.clone());
+    Ok(())
+}
+
+struct Importer<'a, 'g> {
+    ncx: &'g NodeCtxt<LlvmOp>,
+    ssa: SsaBuilder<'g, LlvmOp, Slot>,
+    function: &'a Function,
+    cfg: &'a FunctionCfg,
+    /// The headers of the loops being imported, innermost last.
+    loops: Vec<usize>,
+}
+
+impl<'a, 'g> Importer<'a, 'g> {
+    /// Imports the blocks from `block` on, up to `stop` or the returns of
+    /// the function, or the latch of the innermost loop being imported.
+    fn import_from(&mut self, mut block: usize, stop: Option<usize>) -> Result<(), ParseError> {
+        let function = self.function;
+        loop {
+            if Some(block) == stop {
+                return Ok(());
+            }
+            if self.cfg.loops.contains_key(&block) && self.loops.last() != Some(&block) {
+                block = self.import_loop(block)?;
+                continue;
+            }
+            self.import_insts(block)?;
+            if let Some(header) = self.loops.last() {
+                if self.cfg.loops[header].latch == block {
+                    return Ok(());
+                }
+            }
+
+            let (line, term) = function.blocks[block].term();
+            match term {
+                Term::Ret(value) => {
+                    if let Some(value) = value {
+                        let result = self.value(value, *line)?;
+                        self.ssa.write_val(Slot::Ret, result);
+                    }
+                    return Ok(());
+                }
+                Term::Br(target) => {
+                    self.copy_phis(block, target.block())?;
+                    block = target.block();
+                }
+                Term::CondBr(cond, if_true, if_false) => {
+                    let join = self.cfg.ipdoms[block];
+                    let predicate = self.value(cond, *line)?;
+                    self.ssa.begin_gamma(predicate, 2);
+                    for (index, target) in [if_false, if_true].iter().enumerate() {
+                        if index > 0 {
+                            self.ssa.next_branch();
+                        }
+                        self.copy_phis(block, target.block())?;
+                        self.import_from(target.block(), join)?;
+                    }
+                    self.ssa.end_gamma();
+                    match join {
+                        Some(join) => block = join,
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Imports the loop at `header` as a theta, returning the block it exits
+    /// to.
+    fn import_loop(&mut self, header: usize) -> Result<usize, ParseError> {
+        let function = self.function;
+        let cfg = self.cfg;
+        let lp = &cfg.loops[&header];
+
+        // Values defined in the loop only get out of it as loop variables,
+        // which need a value to start from.
+        for name in &lp.live_out {
+            let region = self.ssa.region();
+            let undef = LlvmOp::Undef(function.types[name]);
+            let undef = self.ncx.mk_node_in(region, undef).val_out(0);
+            self.ssa.write_val(Slot::Value(name.clone()), undef);
+        }
+        self.ssa.begin_theta();
+        self.loops.push(header);
+        self.import_from(header, None)?;
+        self.loops.pop();
+
+        let (line, cond, repeats) = match function.blocks[lp.latch].term() {
+            (line, Term::CondBr(cond, if_true, _)) => (*line, cond, if_true.block() == header),
+            _ => unreachable!(),
+        };
+        self.copy_phis(lp.latch, header)?;
+        let mut predicate = self.value(cond, line)?;
+        if !repeats {
+            let region = self.ssa.region();
+            let one = self
+                .ncx
+                .mk_node_in(region, LlvmOp::Int { bits: 1, value: 1 });
+            predicate = self
+                .ncx
+                .node_builder_in(region, LlvmOp::Bin(BinOp::Xor))
+                .operand(predicate)
+                .operand(one.val_out(0))
+                .finish()
+                .val_out(0);
+        }
+        self.ssa.end_theta(predicate);
+        self.copy_phis(lp.latch, lp.exit)?;
+        Ok(lp.exit)
+    }
+
+    fn import_insts(&mut self, block: usize) -> Result<(), ParseError> {
+        let function = self.function;
+        for (line, inst) in &function.blocks[block].insts {
+            let (result, op, operands) = match inst {
+                Inst::Phi { result, .. } => {
+                    let value = match self.ssa.read_val(&Slot::Phi(result.clone())) {
+                        Some(value) => value,
+                        None => {
+                            let message = format!("phi `%{}` has no value to take", result);
+                            return Err(ParseError {
+                                line: *line,
+                                message,
+                            });
+                        }
+                    };
+                    self.ssa.write_val(Slot::Value(result.clone()), value);
+                    continue;
+                }
+                Inst::Op {
+                    result,
+                    op,
+                    operands,
+                } => (result, op, operands),
+            };
+
+            let region = self.ssa.region();
+            let mut builder = self.ncx.node_builder_in(region, op.clone());
+            for operand in operands {
+                builder = builder.operand(self.value(operand, *line)?);
+            }
+            let sig = op.sig();
+            if sig.st_ins > 0 {
+                builder = builder.state(self.ssa.read_state(&Slot::State).unwrap());
+            }
+            let node = builder.finish();
+            if sig.st_outs > 0 {
+                self.ssa.write_state(Slot::State, node.st_out(0));
+            }
+            if let Some(result) = result {
+                self.ssa
+                    .write_val(Slot::Value(result.clone()), node.val_out(0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the values the phis of `to` take when branching there from
+    /// `from`. They're all read before any is written, as phis take their
+    /// values at once.
+    fn copy_phis(&mut self, from: usize, to: usize) -> Result<(), ParseError> {
+        let function = self.function;
+        let mut values = vec![];
+        for (line, inst) in &function.blocks[to].insts {
+            if let Inst::Phi { result, incoming } = inst {
+                let value = incoming.iter().find(|(_, label)| label.block() == from);
+                let value = match value {
+                    Some((value, _)) => self.value(value, *line)?,
+                    None => {
+                        let message = format!(
+                            "phi `%{}` has no value for `%{}`",
+                            result, function.blocks[from].name
+                        );
+                        return Err(ParseError {
+                            line: *line,
+                            message,
+                        });
+                    }
+                };
+                values.push((result, value));
+            }
+        }
+        for (result, value) in values {
+            self.ssa.write_val(Slot::Phi(result.clone()), value);
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value, line: usize) -> Result<ValOrigin<'g, LlvmOp>, ParseError> {
+        let op = match (&value.operand, value.ty) {
+            (Operand::Local(name), _) => {
+                return match self.ssa.read_val(&Slot::Value(name.clone())) {
+                    Some(origin) => Ok(origin),
+                    None => Err(ParseError {
+                        line,
+                        message: format!("`%{}` isn't defined where it's used", name),
+                    }),
+                };
+            }
+            (Operand::Int(value), LlvmType::Int(bits)) => {
+                let mask = u64::MAX >> (64 - bits);
+                LlvmOp::Int {
+                    bits,
+                    value: *value as u64 & mask,
+                }
+            }
+            (Operand::Null, LlvmType::Ptr) | (Operand::Int(0), LlvmType::Ptr) => LlvmOp::Null,
+            (Operand::Undef, ty) => LlvmOp::Undef(ty),
+            (operand, ty) => {
+                let message = format!("`{:?}` isn't a constant of type {:?}", operand, ty);
+                return Err(ParseError { line, message });
+            }
+        };
+        Ok(self.ncx.mk_node_in(self.ssa.region(), op).val_out(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BinOp, IcmpPred, LlvmOp, LlvmType};
+    use crate::rvsdg::{NodeCtxt, NodeKind, RegionId};
+
+    fn ops(ncx: &NodeCtxt<LlvmOp>, region_id: RegionId) -> Vec<NodeKind<LlvmOp>> {
+        ncx.region_ref(region_id)
+            .nodes()
+            .map(|node| node.kind().clone())
+            .collect()
+    }
+
+    #[test]
+    fn straight_line_code() {
+        let ncx = NodeCtxt::parse_llvm_ir(
+            r#"
+            ; ModuleID = 'sum.c'
+            target triple = "x86_64-pc-linux-gnu"
+
+            define dso_local i32 @sum(i32 noundef %a, ptr nocapture noundef %p) #0 {
+              %slot = alloca i32, align 4
+              store i32 %a, ptr %slot, align 4
+              %x = load i32, ptr %slot, align 4, !tbaa !3
+              %y = load i32, i32* %p, align 4
+              %sum = add nsw i32 %x, %y
+              %big = icmp sgt i32 %sum, -1
+              %r = select i1 %big, i32 %sum, i32 0
+              call void @print(i32 %r) #2
+              ret i32 %r
+            }
+
+            declare void @print(i32)
+            "#,
+        )
+        .unwrap();
+
+        let lambda = ncx.region_ref(ncx.root_region()).nodes().next().unwrap();
+        assert_eq!(Some("sum".to_owned()), lambda.name());
+        let body = ncx.inner_regions(lambda.id())[0];
+        let ops = ops(&ncx, body);
+        assert!(ops.contains(&NodeKind::Op(LlvmOp::Alloca(LlvmType::Int(32)))));
+        assert!(ops.contains(&NodeKind::Op(LlvmOp::Store)));
+        assert!(ops.contains(&NodeKind::Op(LlvmOp::Bin(BinOp::Add))));
+        assert!(ops.contains(&NodeKind::Op(LlvmOp::Icmp(IcmpPred::Sgt))));
+        assert!(ops.contains(&NodeKind::Op(LlvmOp::Int {
+            bits: 32,
+            value: u32::MAX as u64,
+        })));
+        assert!(ops.contains(&NodeKind::Op(LlvmOp::Call {
+            callee: "print".to_owned(),
+            args: 1,
+            ret: LlvmType::Void,
+        })));
+
+        // The loads, stores and calls run in the order they're written.
+        let region = ncx.region_ref(body);
+        let mut state = region.st_res(0).origin();
+        let mut chain = vec![];
+        while state != region.st_arg(0) {
+            let node = state.producer();
+            chain.push(node.kind().clone());
+            state = node.st_in(0).origin();
+        }
+        assert_eq!(5, chain.len());
+        assert_eq!(NodeKind::Op(LlvmOp::Alloca(LlvmType::Int(32))), chain[4]);
+    }
+
+    #[test]
+    fn branches_and_phis() {
+        let ncx = NodeCtxt::parse_llvm_ir(
+            r#"
+            define i32 @max(i32 %a, i32 %b) {
+            entry:
+              %lt = icmp slt i32 %a, %b
+              br i1 %lt, label %then, label %done
+
+            then:                                   ; preds = %entry
+              %twice = mul i32 %b, 2
+              br label %done
+
+            done:
+              %m = phi i32 [ %twice, %then ], [ %a, %entry ]
+              ret i32 %m
+            }
+
+            define i32 @sign(i32 %x) {
+              %neg = icmp slt i32 %x, 0
+              br i1 %neg, label %minus, label %plus
+            minus:
+              ret i32 -1
+            plus:
+              ret i32 1
+            }
+            "#,
+        )
+        .unwrap();
+
+        let lambdas: Vec<_> = ncx.region_ref(ncx.root_region()).nodes().collect();
+        for lambda in lambdas {
+            let body = ncx.inner_regions(lambda.id())[0];
+            let result = ncx.region_ref(body).val_res(0).origin().producer();
+            assert!(matches!(*result.kind(), NodeKind::Gamma { .. }));
+        }
+    }
+
+    #[test]
+    fn rotated_loops() {
+        let ncx = NodeCtxt::parse_llvm_ir(
+            r#"
+            define i32 @count(i32 %n) {
+            entry:
+              br label %loop
+
+            loop:
+              %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+              %next = add i32 %i, 1
+              %done = icmp eq i32 %next, %n
+              br i1 %done, label %exit, label %loop
+
+            exit:
+              %last = add i32 %i, %next
+              ret i32 %last
+            }
+            "#,
+        )
+        .unwrap();
+
+        let lambda = ncx.region_ref(ncx.root_region()).nodes().next().unwrap();
+        let body = ncx.inner_regions(lambda.id())[0];
+        let add = ncx.region_ref(body).val_res(0).origin().producer();
+        assert_eq!(NodeKind::Op(LlvmOp::Bin(BinOp::Add)), *add.kind());
+        // Both the phi and the value it takes next come out of the loop.
+        for port in 0..2 {
+            let theta = add.val_in(port).origin().producer();
+            assert!(matches!(*theta.kind(), NodeKind::Theta { .. }));
+        }
+    }
+
+    #[test]
+    fn unsupported_code() {
+        let error = |input: &str| match NodeCtxt::parse_llvm_ir(input) {
+            Ok(..) => panic!("parsed unsupported code"),
+            Err(error) => error,
+        };
+
+        let loop_exiting_at_its_header = r#"
+            define void @f(i32 %n) {
+            entry:
+              br label %header
+            header:
+              %i = phi i32 [ 0, %entry ], [ %next, %body ]
+              %more = icmp slt i32 %i, %n
+              br i1 %more, label %body, label %exit
+            body:
+              %next = add i32 %i, 1
+              br label %header
+            exit:
+              ret void
+            }
+            "#;
+        assert_eq!(11, error(loop_exiting_at_its_header).line);
+
+        let error = error(
+            r#"
+            define i32 @f(i32 %x) {
+              %y = fadd float 1.0, 2.0
+              ret i32 %x
+            }
+            "#,
+        );
+        assert_eq!(3, error.line);
+        assert_eq!("unsupported instruction `fadd`", error.message);
+    }
+}
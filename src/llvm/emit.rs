@@ -0,0 +1,724 @@
+use super::{BinOp, CastOp, IcmpPred, LlvmOp, LlvmType};
+use crate::rvsdg::{Cfg, CfgError, Inst, Jump, NodeCtxt, NodeId, Sig, Terminator, Var};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// A value of a function being written in LLVM IR.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LlvmValue {
+    pub ty: LlvmType,
+    /// The name of the value, along with its `%`.
+    pub name: String,
+}
+
+impl fmt::Display for LlvmValue {
+    /// Writes the value along with its type, as operands are written.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.ty, self.name)
+    }
+}
+
+/// How ops are written in LLVM IR by `NodeCtxt::to_llvm_ir`.
+pub trait LowerToLlvm {
+    /// The types of the value outputs of the op, given those of its value
+    /// inputs.
+    fn llvm_result_types(&self, args: &[LlvmType]) -> Vec<LlvmType>;
+
+    /// The instructions computing the value outputs of the op from `args`,
+    /// into `results`. Its states are implied by the order the instructions
+    /// of the function come in.
+    fn lower_to_llvm(&self, args: &[LlvmValue], results: &[LlvmValue]) -> Vec<String>;
+
+    /// The function the op calls, if it's a call, so that it can be
+    /// declared when the module doesn't define it.
+    fn llvm_callee(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// A lambda to be written as a function by `NodeCtxt::to_llvm_ir`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LlvmFunction {
+    pub lambda: NodeId,
+    pub name: String,
+    /// The types of the value parameters of the lambda.
+    pub params: Vec<LlvmType>,
+    /// The type of the only value result of the lambda, or `Void` if it has
+    /// none.
+    pub ret: LlvmType,
+}
+
+/// Why a lambda couldn't be written in LLVM IR.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmitError {
+    /// The body of the lambda couldn't be turned into a CFG.
+    Cfg(CfgError),
+    /// The lambda doesn't have the parameters and results given for it.
+    Signature(NodeId),
+    /// A context variable of the lambda doesn't carry one of the functions
+    /// being written.
+    ContextVar { lambda: NodeId, port: usize },
+    /// The lambda calls a function that isn't known to be one of the
+    /// functions being written.
+    IndirectCall(NodeId),
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmitError::Cfg(error) => write!(f, "{}", error),
+            EmitError::Signature(lambda) => {
+                write!(f, "{:?} doesn't match its signature", lambda)
+            }
+            EmitError::ContextVar { lambda, port } => write!(
+                f,
+                "context variable {} of {:?} isn't a function being written",
+                port, lambda
+            ),
+            EmitError::IndirectCall(lambda) => {
+                write!(f, "{:?} calls an unknown function", lambda)
+            }
+        }
+    }
+}
+
+impl<S: Sig + Clone + LowerToLlvm> NodeCtxt<S> {
+    /// Writes `functions` as a module in LLVM's textual IR, declaring the
+    /// functions their ops call that aren't among them.
+    ///
+    /// The body of each lambda is turned into a CFG, with the nodes of each
+    /// block in the order `NodeCtxt::schedule` puts them in. Parameters of
+    /// blocks become phis, and switches on an `i1` between two blocks
+    /// become conditional branches. Functions are called through context
+    /// variables carrying them.
+    pub fn to_llvm_ir(&self, functions: &[LlvmFunction]) -> Result<String, EmitError> {
+        let by_lambda: HashMap<NodeId, &LlvmFunction> = functions
+            .iter()
+            .map(|function| (function.lambda, function))
+            .collect();
+        let mut module = Module {
+            defined: functions
+                .iter()
+                .map(|function| &function.name[..])
+                .collect(),
+            declarations: vec![],
+            definitions: vec![],
+        };
+        for function in functions {
+            FunctionEmitter::new(self, function, &by_lambda)?.emit(&mut module);
+        }
+
+        let mut out = String::new();
+        for declaration in &module.declarations {
+            out.push_str(declaration);
+            out.push('\n');
+        }
+        for definition in &module.definitions {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(definition);
+        }
+        Ok(out)
+    }
+}
+
+struct Module<'a> {
+    /// The names of the functions being written.
+    defined: HashSet<&'a str>,
+    declarations: Vec<String>,
+    definitions: Vec<String>,
+}
+
+/// What's known of the function a variable holds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Callee {
+    /// Nothing yet, for parameters of blocks not yet jumped to.
+    Unknown,
+    Lambda(NodeId),
+    /// Any function or other value.
+    Varying,
+}
+
+struct FunctionEmitter<'a, S> {
+    function: &'a LlvmFunction,
+    by_lambda: &'a HashMap<NodeId, &'a LlvmFunction>,
+    cfg: Cfg<S>,
+    types: HashMap<Var, LlvmType>,
+    callees: HashMap<Var, Callee>,
+}
+
+impl<'a, S: Sig + Clone + LowerToLlvm> FunctionEmitter<'a, S> {
+    fn new(
+        ncx: &NodeCtxt<S>,
+        function: &'a LlvmFunction,
+        by_lambda: &'a HashMap<NodeId, &'a LlvmFunction>,
+    ) -> Result<FunctionEmitter<'a, S>, EmitError> {
+        let lambda = function.lambda;
+        let sig = ncx.lambda_sig(lambda);
+        let num_results = if function.ret == LlvmType::Void { 0 } else { 1 };
+        if sig.val_ins != function.params.len() || sig.val_outs != num_results {
+            return Err(EmitError::Signature(lambda));
+        }
+        let body = ncx.inner_regions(lambda)[0];
+        let cfg = ncx.to_scheduled_cfg(body).map_err(EmitError::Cfg)?;
+
+        let mut emitter = FunctionEmitter {
+            function,
+            by_lambda,
+            cfg,
+            types: HashMap::new(),
+            callees: HashMap::new(),
+        };
+        let entry_params = emitter.cfg.blocks[0].params.clone();
+        for (&param, &ty) in entry_params.iter().zip(&function.params) {
+            emitter.types.insert(param, ty);
+        }
+        let ctx_vars = entry_params[function.params.len()..].iter();
+        for (port, (&var, ctx_lambda)) in ctx_vars.zip(ncx.ctx_var_lambdas(lambda)).enumerate() {
+            match ctx_lambda.filter(|ctx_lambda| by_lambda.contains_key(ctx_lambda)) {
+                Some(ctx_lambda) => {
+                    emitter.types.insert(var, LlvmType::Ptr);
+                    emitter.callees.insert(var, Callee::Lambda(ctx_lambda));
+                }
+                None => return Err(EmitError::ContextVar { lambda, port }),
+            }
+        }
+        emitter.infer_types()?;
+        Ok(emitter)
+    }
+
+    /// Finds the types of the variables, and the functions those called
+    /// hold, passing them on to the parameters of the blocks jumped to
+    /// until nothing changes.
+    fn infer_types(&mut self) -> Result<(), EmitError> {
+        let block_params: HashSet<Var> = self.cfg.blocks[1..]
+            .iter()
+            .flat_map(|block| block.params.iter().copied())
+            .collect();
+        for &param in &block_params {
+            self.callees.insert(param, Callee::Unknown);
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in &self.cfg.blocks {
+                for inst in &block.insts {
+                    let (results, types) = match inst {
+                        Inst::Op { op, args, results } => {
+                            let args: Option<Vec<LlvmType>> = args
+                                .iter()
+                                .map(|arg| self.types.get(arg).copied())
+                                .collect();
+                            match args {
+                                Some(args) => (results, op.llvm_result_types(&args)),
+                                None => continue,
+                            }
+                        }
+                        Inst::Call {
+                            callee, results, ..
+                        } => match self.callees.get(callee) {
+                            Some(Callee::Lambda(lambda)) => {
+                                let ret = self.by_lambda[lambda].ret;
+                                (
+                                    results,
+                                    [ret]
+                                        .iter()
+                                        .copied()
+                                        .filter(|&ret| ret != LlvmType::Void)
+                                        .collect(),
+                                )
+                            }
+                            _ => continue,
+                        },
+                    };
+                    assert_eq!(results.len(), types.len());
+                    for (&result, ty) in results.iter().zip(types) {
+                        changed |= self.types.insert(result, ty).is_none();
+                    }
+                }
+
+                for jump in jumps(&block.terminator) {
+                    let params = &self.cfg.blocks[jump.block.0].params;
+                    for (&param, &arg) in params.iter().zip(&jump.args) {
+                        if let Some(&ty) = self.types.get(&arg) {
+                            changed |= self.types.insert(param, ty).is_none();
+                        }
+                        if arg == param {
+                            continue;
+                        }
+                        let callee = match self.callees.get(&arg) {
+                            Some(&callee) => callee,
+                            None => Callee::Varying,
+                        };
+                        let merged = match (self.callees[&param], callee) {
+                            (Callee::Unknown, callee) | (callee, Callee::Unknown) => callee,
+                            (old, new) if old == new => old,
+                            _ => Callee::Varying,
+                        };
+                        changed |= self.callees.insert(param, merged) != Some(merged);
+                    }
+                }
+            }
+        }
+
+        for block in &self.cfg.blocks {
+            for inst in &block.insts {
+                if let Inst::Call { callee, .. } = inst {
+                    if !matches!(self.callees.get(callee), Some(Callee::Lambda(..))) {
+                        return Err(EmitError::IndirectCall(self.function.lambda));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, var: Var) -> LlvmValue {
+        LlvmValue {
+            ty: self.types[&var],
+            name: format!("%v{}", var.0),
+        }
+    }
+
+    fn values(&self, vars: &[Var]) -> Vec<LlvmValue> {
+        vars.iter().map(|&var| self.value(var)).collect()
+    }
+
+    fn emit(&self, module: &mut Module) {
+        let function = self.function;
+        let num_params = function.params.len();
+        let params: Vec<String> = self
+            .values(&self.cfg.blocks[0].params[..num_params])
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let mut out = format!(
+            "define {} @{}({}) {{\n",
+            function.ret,
+            function.name,
+            params.join(", ")
+        );
+
+        let mut incoming: HashMap<usize, Vec<(usize, &Jump)>> = HashMap::new();
+        for (index, block) in self.cfg.blocks.iter().enumerate() {
+            for jump in jumps(&block.terminator) {
+                incoming
+                    .entry(jump.block.0)
+                    .or_default()
+                    .push((index, jump));
+            }
+        }
+
+        for (index, block) in self.cfg.blocks.iter().enumerate() {
+            out.push_str(&format!("bb{}:\n", index));
+            // The parameters of the first block are those of the function.
+            let params = if index == 0 { &[][..] } else { &block.params };
+            for (port, &param) in params.iter().enumerate() {
+                let param = self.value(param);
+                let values: Vec<String> = incoming[&index]
+                    .iter()
+                    .map(|(pred, jump)| format!("[ %v{}, %bb{} ]", jump.args[port].0, pred))
+                    .collect();
+                out.push_str(&format!(
+                    "  {} = phi {} {}\n",
+                    param.name,
+                    param.ty,
+                    values.join(", ")
+                ));
+            }
+
+            for inst in &block.insts {
+                let lines = match inst {
+                    Inst::Op { op, args, results } => {
+                        let args = self.values(args);
+                        let results = self.values(results);
+                        if let Some(callee) = op.llvm_callee() {
+                            self.declare(module, callee, &args, &results);
+                        }
+                        op.lower_to_llvm(&args, &results)
+                    }
+                    Inst::Call {
+                        callee,
+                        args,
+                        results,
+                    } => {
+                        let callee = match self.callees[callee] {
+                            Callee::Lambda(lambda) => self.by_lambda[&lambda],
+                            _ => unreachable!(),
+                        };
+                        let args: Vec<String> =
+                            self.values(args).iter().map(ToString::to_string).collect();
+                        let call =
+                            format!("call {} @{}({})", callee.ret, callee.name, args.join(", "));
+                        match results.first() {
+                            Some(&result) => vec![format!("%v{} = {}", result.0, call)],
+                            None => vec![call],
+                        }
+                    }
+                };
+                for line in lines {
+                    out.push_str(&format!("  {}\n", line));
+                }
+            }
+
+            let terminator = match &block.terminator {
+                Terminator::Jump(jump) => format!("br label %bb{}", jump.block.0),
+                Terminator::Switch { on, jumps } => {
+                    let on = self.value(*on);
+                    match &jumps[..] {
+                        [jump] => format!("br label %bb{}", jump.block.0),
+                        [if_false, if_true] if on.ty == LlvmType::Int(1) => format!(
+                            "br {}, label %bb{}, label %bb{}",
+                            on, if_true.block.0, if_false.block.0
+                        ),
+                        _ => {
+                            let (default, cases) = jumps.split_last().unwrap();
+                            let cases: Vec<String> = cases
+                                .iter()
+                                .enumerate()
+                                .map(|(case, jump)| {
+                                    format!("{} {}, label %bb{}", on.ty, case, jump.block.0)
+                                })
+                                .collect();
+                            format!(
+                                "switch {}, label %bb{} [ {} ]",
+                                on,
+                                default.block.0,
+                                cases.join(" ")
+                            )
+                        }
+                    }
+                }
+                Terminator::Return(results) => match results.first() {
+                    Some(&result) => format!("ret {}", self.value(result)),
+                    None => "ret void".to_owned(),
+                },
+            };
+            out.push_str(&format!("  {}\n", terminator));
+        }
+        out.push_str("}\n");
+        module.definitions.push(out);
+    }
+
+    /// Declares `callee` in the module unless it's defined or declared
+    /// there already.
+    fn declare(
+        &self,
+        module: &mut Module,
+        callee: &str,
+        args: &[LlvmValue],
+        results: &[LlvmValue],
+    ) {
+        let ret = results.first().map_or(LlvmType::Void, |result| result.ty);
+        let args: Vec<String> = args.iter().map(|arg| arg.ty.to_string()).collect();
+        let declaration = format!("declare {} @{}({})", ret, callee, args.join(", "));
+        if !module.defined.contains(callee) && !module.declarations.contains(&declaration) {
+            module.declarations.push(declaration);
+        }
+    }
+}
+
+fn jumps(terminator: &Terminator) -> &[Jump] {
+    match terminator {
+        Terminator::Jump(jump) => std::slice::from_ref(jump),
+        Terminator::Switch { jumps, .. } => jumps,
+        Terminator::Return(..) => &[],
+    }
+}
+
+impl fmt::Display for LlvmType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LlvmType::Int(bits) => write!(f, "i{}", bits),
+            LlvmType::Ptr => write!(f, "ptr"),
+            LlvmType::Void => write!(f, "void"),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::UDiv => "udiv",
+            BinOp::SDiv => "sdiv",
+            BinOp::URem => "urem",
+            BinOp::SRem => "srem",
+            BinOp::Shl => "shl",
+            BinOp::LShr => "lshr",
+            BinOp::AShr => "ashr",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Xor => "xor",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for IcmpPred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            IcmpPred::Eq => "eq",
+            IcmpPred::Ne => "ne",
+            IcmpPred::Ugt => "ugt",
+            IcmpPred::Uge => "uge",
+            IcmpPred::Ult => "ult",
+            IcmpPred::Ule => "ule",
+            IcmpPred::Sgt => "sgt",
+            IcmpPred::Sge => "sge",
+            IcmpPred::Slt => "slt",
+            IcmpPred::Sle => "sle",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for CastOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CastOp::Trunc => "trunc",
+            CastOp::ZExt => "zext",
+            CastOp::SExt => "sext",
+            CastOp::PtrToInt => "ptrtoint",
+            CastOp::IntToPtr => "inttoptr",
+            CastOp::BitCast => "bitcast",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Constants are written as bitcasts of themselves, as LLVM has no
+/// instruction that only defines a constant.
+impl LowerToLlvm for LlvmOp {
+    fn llvm_result_types(&self, args: &[LlvmType]) -> Vec<LlvmType> {
+        let ty = match self {
+            LlvmOp::Int { bits, .. } => LlvmType::Int(*bits),
+            LlvmOp::Null | LlvmOp::Alloca(..) => LlvmType::Ptr,
+            LlvmOp::Undef(ty) | LlvmOp::Load(ty) | LlvmOp::Cast(_, ty) => *ty,
+            LlvmOp::Bin(..) => args[0],
+            LlvmOp::Icmp(..) => LlvmType::Int(1),
+            LlvmOp::Select => args[1],
+            LlvmOp::Store => return vec![],
+            LlvmOp::Call { ret, .. } => *ret,
+        };
+        if ty == LlvmType::Void {
+            return vec![];
+        }
+        vec![ty]
+    }
+
+    fn lower_to_llvm(&self, args: &[LlvmValue], results: &[LlvmValue]) -> Vec<String> {
+        let result = results.first().map(|result| &result.name);
+        let inst = match self {
+            LlvmOp::Int { bits: 1, value } => {
+                let value = if *value == 0 { "false" } else { "true" };
+                format!("bitcast i1 {} to i1", value)
+            }
+            LlvmOp::Int { bits, value } => {
+                // Written as signed, so that it fits the type as LLVM reads it.
+                let shift = 64 - bits;
+                let value = ((*value << shift) as i64) >> shift;
+                format!("bitcast i{} {} to i{}", bits, value, bits)
+            }
+            LlvmOp::Null => "bitcast ptr null to ptr".to_owned(),
+            LlvmOp::Undef(ty) => format!("bitcast {} undef to {}", ty, ty),
+            LlvmOp::Bin(op) => format!("{} {}, {}", op, args[0], args[1].name),
+            LlvmOp::Icmp(pred) => format!("icmp {} {}, {}", pred, args[0], args[1].name),
+            LlvmOp::Select => format!("select {}, {}, {}", args[0], args[1], args[2]),
+            LlvmOp::Cast(op, ty) => format!("{} {} to {}", op, args[0], ty),
+            LlvmOp::Alloca(ty) => format!("alloca {}", ty),
+            LlvmOp::Load(ty) => format!("load {}, {}", ty, args[0]),
+            LlvmOp::Store => format!("store {}, {}", args[0], args[1]),
+            LlvmOp::Call { callee, ret, .. } => {
+                let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+                format!("call {} @{}({})", ret, callee, args.join(", "))
+            }
+        };
+        match result {
+            Some(result) => vec![format!("{} = {}", result, inst)],
+            None => vec![inst],
+        }
+    }
+
+    fn llvm_callee(&self) -> Option<&str> {
+        match self {
+            LlvmOp::Call { callee, .. } => Some(callee),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EmitError, LlvmFunction};
+    use crate::{
+        llvm::{BinOp, LlvmOp, LlvmType},
+        rvsdg::{NodeCtxt, NodeId, PortKind, SigS},
+    };
+
+    fn lambda_named(ncx: &NodeCtxt<LlvmOp>, name: &str) -> NodeId {
+        let root = ncx.region_ref(ncx.root_region());
+        let mut lambdas = root
+            .nodes()
+            .filter(|node| node.name().as_deref() == Some(name));
+        lambdas.next().unwrap().id()
+    }
+
+    #[test]
+    fn round_trip() {
+        let ncx = NodeCtxt::parse_llvm_ir(
+            r#"
+            define i32 @count(i32 %n, ptr %p) {
+            entry:
+              %init = load i32, ptr %p
+              br label %loop
+            loop:
+              %i = phi i32 [ %init, %entry ], [ %next, %loop ]
+              %next = add i32 %i, 1
+              call void @print(i32 %next)
+              %done = icmp sge i32 %next, %n
+              br i1 %done, label %exit, label %loop
+            exit:
+              %neg = icmp slt i32 %next, 0
+              %r = select i1 %neg, i32 -1, i32 %next
+              store i32 %r, ptr %p
+              ret i32 %r
+            }
+            "#,
+        )
+        .unwrap();
+        let count = LlvmFunction {
+            lambda: lambda_named(&ncx, "count"),
+            name: "count".to_owned(),
+            params: vec![LlvmType::Int(32), LlvmType::Ptr],
+            ret: LlvmType::Int(32),
+        };
+
+        assert_eq!(
+            ncx.to_llvm_ir(&[count]).unwrap(),
+            "declare void @print(i32)
+
+define i32 @count(i32 %v0, ptr %v1) {
+bb0:
+  %v2 = load i32, ptr %v1
+  %v3 = bitcast i32 undef to i32
+  br label %bb1
+bb1:
+  %v4 = phi i32 [ %v2, %bb0 ], [ %v8, %bb1 ]
+  %v5 = phi i32 [ %v0, %bb0 ], [ %v5, %bb1 ]
+  %v6 = phi i32 [ %v3, %bb0 ], [ %v8, %bb1 ]
+  %v7 = bitcast i32 1 to i32
+  %v8 = add i32 %v7, %v4
+  %v9 = icmp sge i32 %v8, %v5
+  %v10 = bitcast i1 true to i1
+  call void @print(i32 %v8)
+  %v11 = xor i1 %v9, %v10
+  br i1 %v11, label %bb1, label %bb2
+bb2:
+  %v12 = phi i32 [ %v8, %bb1 ]
+  %v13 = phi i32 [ %v5, %bb1 ]
+  %v14 = phi i32 [ %v8, %bb1 ]
+  %v15 = bitcast i32 0 to i32
+  %v16 = icmp slt i32 %v14, %v15
+  %v17 = bitcast i32 -1 to i32
+  %v18 = select i1 %v16, i32 %v17, i32 %v14
+  store i32 %v18, ptr %v1
+  ret i32 %v18
+}
+"
+        );
+    }
+
+    #[test]
+    fn calls_between_lambdas() {
+        let ncx = NodeCtxt::new();
+        let root = ncx.root_region();
+
+        let inc = ncx.lambda_builder(root, &[PortKind::Val]);
+        let n_one = ncx.mk_node_in(inc.body(), LlvmOp::Int { bits: 8, value: 1 });
+        let n_sum = ncx
+            .node_builder_in(inc.body(), LlvmOp::Bin(BinOp::Add))
+            .operand(inc.val_param(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        let inc = inc.finish(&[n_sum.val_out(0)], &[]);
+
+        // Increments its argument when the condition holds.
+        let caller = ncx.lambda_builder(root, &[PortKind::Val, PortKind::Val]);
+        let f = caller.ctx_var(inc.val_out(0));
+        let gamma = ncx.gamma_builder(caller.val_param(0), 2);
+        let fs = gamma.entry_var(f);
+        let xs = gamma.entry_var(caller.val_param(1));
+        let sig = SigS {
+            val_ins: 1,
+            val_outs: 1,
+            ..SigS::default()
+        };
+        let n_call = ncx
+            .apply_builder(fs[1].clone(), sig)
+            .operand(xs[1].clone())
+            .finish();
+        gamma.exit_var(&[xs[0].clone(), n_call.val_out(0)]);
+        let gamma = gamma.finish();
+        let caller = caller.finish(&[gamma.val_out(0)], &[]);
+
+        let functions = [
+            LlvmFunction {
+                lambda: inc.id(),
+                name: "inc".to_owned(),
+                params: vec![LlvmType::Int(8)],
+                ret: LlvmType::Int(8),
+            },
+            LlvmFunction {
+                lambda: caller.id(),
+                name: "inc_if".to_owned(),
+                params: vec![LlvmType::Int(1), LlvmType::Int(8)],
+                ret: LlvmType::Int(8),
+            },
+        ];
+        assert_eq!(
+            ncx.to_llvm_ir(&functions).unwrap(),
+            "define i8 @inc(i8 %v0) {
+bb0:
+  %v1 = bitcast i8 1 to i8
+  %v2 = add i8 %v1, %v0
+  ret i8 %v2
+}
+
+define i8 @inc_if(i1 %v0, i8 %v1) {
+bb0:
+  br i1 %v0, label %bb2, label %bb1
+bb1:
+  br label %bb3
+bb2:
+  %v3 = call i8 @inc(i8 %v1)
+  br label %bb3
+bb3:
+  %v4 = phi i8 [ %v1, %bb1 ], [ %v3, %bb2 ]
+  ret i8 %v4
+}
+"
+        );
+
+        // Functions must be written along with those calling them.
+        assert_eq!(
+            Err(EmitError::ContextVar {
+                lambda: caller.id(),
+                port: 0
+            }),
+            ncx.to_llvm_ir(&functions[1..])
+        );
+        let mut wrong_params = functions.clone();
+        wrong_params[0].params.clear();
+        assert_eq!(
+            Err(EmitError::Signature(inc.id())),
+            ncx.to_llvm_ir(&wrong_params)
+        );
+    }
+}
@@ -615,7 +615,7 @@ mod test {
 
     #[test]
     fn array_10x42_to_stores() {
-        use crate::rvsdg::NodeCtxtConfig;
+        use crate::rvsdg::{InterningPolicy, NodeCtxtConfig};
 
         let hir = NodeCtxt::new();
         let arr = hir.mk_node(Hir::Array(vec![42; 10]));
@@ -634,7 +634,8 @@ mod test {
 
         let mut hir_to_lir = HirToLir::new();
         let lir = NodeCtxt::with_config(NodeCtxtConfig {
-            opt_interning: false,
+            interning: InterningPolicy::Never,
+            ..NodeCtxtConfig::default()
         });
         let merge = hir_to_lir.lower(subscript.clone(), &lir);
         lir.print(&mut io::stdout().lock()).unwrap();
@@ -647,7 +648,8 @@ mod test {
 
         let mut hir_to_lir = HirToLir::new();
         let lir = NodeCtxt::with_config(NodeCtxtConfig {
-            opt_interning: true,
+            interning: InterningPolicy::Stateless,
+            ..NodeCtxtConfig::default()
         });
         let merge = hir_to_lir.lower(subscript, &lir);
         lir.print(&mut io::stdout().lock()).unwrap();
@@ -672,10 +674,13 @@ mod test {
 
     #[test]
     fn array_0to9_to_stores() {
-        use crate::rvsdg::NodeCtxtConfig;
+        use crate::rvsdg::{InterningPolicy, NodeCtxtConfig};
 
         {
-            let hir = NodeCtxt::with_config(NodeCtxtConfig { opt_interning: false });
+            let hir = NodeCtxt::with_config(NodeCtxtConfig {
+                interning: InterningPolicy::Never,
+                ..NodeCtxtConfig::default()
+            });
             let arr1 = hir.mk_node(Hir::Array((0..2).collect()));
             let arr2 = hir.mk_node(Hir::Array((0..2).collect()));
             let subscript1 = hir
@@ -730,7 +735,8 @@ mod test {
 
         let mut hir_to_lir = HirToLir::new();
         let lir = NodeCtxt::with_config(NodeCtxtConfig {
-            opt_interning: false,
+            interning: InterningPolicy::Never,
+            ..NodeCtxtConfig::default()
         });
         let merge = hir_to_lir.lower(add.clone(), &lir);
         lir.print(&mut io::stdout().lock()).unwrap();
@@ -743,7 +749,8 @@ mod test {
 
         let mut hir_to_lir = HirToLir::new();
         let lir = NodeCtxt::with_config(NodeCtxtConfig {
-            opt_interning: true,
+            interning: InterningPolicy::Stateless,
+            ..NodeCtxtConfig::default()
         });
         let merge = hir_to_lir.lower(add, &lir);
         lir.print(&mut io::stdout().lock()).unwrap();
@@ -770,7 +777,6 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
     fn bug_traverse() {
         struct Traverser;
 
@@ -842,6 +848,7 @@ mod test {
         let mut trav = Traverser;
         let ncx_out = NodeCtxt::new();
         let x = trav.lower(b1, &ncx_out);
+        assert_eq!(NodeKind::Op(D::A(20)), *x.kind());
     }
 
     #[test]
@@ -1,30 +1,214 @@
 use smallvec::SmallVec;
 use std::{
-    cell::{Cell, Ref, RefCell},
-    collections::{hash_map::RawEntryMut, HashMap},
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::{hash_map::RawEntryMut, HashMap, VecDeque},
+    convert::TryFrom,
     fmt::{self, Debug},
     hash::{BuildHasher, Hash, Hasher},
-    io::{self, Write},
+    iter,
+    mem::{self, Discriminant},
+    ops::Deref,
+    path::PathBuf,
     ptr,
+    rc::Rc,
+};
+
+mod alias;
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
+mod arena;
+mod available;
+mod binary;
+mod branch;
+mod callgraph;
+mod cfg;
+mod compact;
+mod dataflow;
+mod deps;
+#[cfg(any(test, feature = "testing"))]
+mod differential;
+mod dominators;
+mod dot;
+mod effects;
+mod egraph;
+mod extract;
+mod fold;
+mod freeze;
+mod frozen;
+mod graphml;
+mod gvn;
+mod height;
+mod import;
+mod infer;
+mod inline;
+mod interned;
+mod jlm;
+#[cfg(feature = "serde")]
+mod json;
+mod liveness;
+mod memory;
+mod mermaid;
+mod mlir;
+mod outline;
+mod pass;
+#[cfg(feature = "petgraph")]
+mod petgraph;
+mod placement;
+mod pool;
+mod purity;
+mod push;
+mod rewrite;
+mod route;
+mod sccp;
+mod schedule;
+mod snapshot;
+mod span;
+mod state;
+mod stats;
+mod switch;
+mod table;
+#[cfg(test)]
+pub(crate) mod testing;
+mod text;
+mod topo;
+mod types;
+mod verify;
+
+use self::{
+    arena::Arena,
+    pool::{PortPool, Ports},
+};
+
+pub use self::{
+    alias::{AliasAnalysis, JoinStates},
+    available::{AvailableOrigin, InsertionPoint},
+    branch::ConstBranch,
+    callgraph::CallGraph,
+    dataflow::{DataflowAnalysis, DataflowSolution, Direction},
+    deps::ExternalDep,
+    dominators::Dominators,
+    dot::RegionSummary,
+    effects::Observable,
+    egraph::{CostModel, EGraph},
+    fold::Fold,
+    gvn::{Distinction, ValueNumbering},
+    height::RegionHeights,
+    import::IdMap,
+    infer::{declared_output_types, TypeError, TypeRule},
+    inline::InlineSite,
+    interned::{InternTableStats, InternedTerm},
+    liveness::Liveness,
+    memory::{MemoryAccess, MemoryOp},
+    placement::PlacementModel,
+    purity::EffectSummary,
+    rewrite::{Pattern, Replacement, Rewriter},
+    schedule::{PressurePriority, SchedulePriority},
+    state::SplitState,
+    switch::{Switch, SwitchBuilder},
+    types::PortType,
+    verify::Violation,
+};
+
+#[cfg(feature = "testing")]
+pub use self::differential::{check_pass_outputs, Divergence, Rng};
+#[cfg(feature = "serde")]
+pub use self::json::LoadError;
+#[cfg(feature = "petgraph")]
+pub use self::petgraph::RegionGraph;
+pub use self::{
+    binary::DecodeError,
+    cfg::{Block, BlockId, Cfg, CfgError, Inst, Jump, Terminator, Var},
+    compact::Remapping,
+    dot::{DotOptions, RankDir},
+    frozen::FrozenGraph,
+    interned::InternCounts,
+    pass::{Changed, Pass, PassManager, PassStats},
+    span::Span,
+    stats::GraphStats,
+    table::{NodeMap, RegionMap},
+    text::ParseError,
 };
 
 /// An index for a NodeData in a NodeCtxt.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub(crate) struct NodeId(usize);
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    /// Panics if a graph can't have `index + 1` nodes.
+    pub(crate) fn new(index: usize) -> NodeId {
+        match u32::try_from(index) {
+            Ok(index) => NodeId(index),
+            Err(_) => panic!("node index {} doesn't fit in 32 bits", index),
+        }
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+}
 
 /// An index for a RegionData in a NodeCtxt.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub(crate) struct RegionId(usize);
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct RegionId(u32);
+
+impl RegionId {
+    /// Panics if a graph can't have `index + 1` regions.
+    pub(crate) fn new(index: usize) -> RegionId {
+        match u32::try_from(index) {
+            Ok(index) => RegionId(index),
+            Err(_) => panic!("region index {} doesn't fit in 32 bits", index),
+        }
+    }
 
-/// An index for a UserData of an input or result port.
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub(crate) enum UserId {
-    In { node: NodeId, index: usize },
-    Res { region: RegionId, index: usize },
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Ports are indexed with 16 bits, which keeps the ids of ports, and so
+/// edges, compact.
+fn compact_port_index(index: usize) -> u16 {
+    match u16::try_from(index) {
+        Ok(index) => index,
+        Err(_) => panic!("port index {} doesn't fit in 16 bits", index),
+    }
+}
+
+/// The id of an input of a node or a result of a region, the ports that
+/// use origins.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum UserId {
+    In { node: NodeId, index: u16 },
+    Res { region: RegionId, index: u16 },
 }
 
 impl UserId {
-    pub(crate) fn node_id(&self) -> Option<NodeId> {
+    /// Input `index` of `node`. Panics if nodes can't have that many ports.
+    pub fn input(node: NodeId, index: usize) -> UserId {
+        UserId::In {
+            node,
+            index: compact_port_index(index),
+        }
+    }
+
+    /// Result `index` of `region`. Panics if regions can't have that many
+    /// ports.
+    pub fn result(region: RegionId, index: usize) -> UserId {
+        UserId::Res {
+            region,
+            index: compact_port_index(index),
+        }
+    }
+
+    /// The index of the port among the inputs of its node or the results
+    /// of its region.
+    pub fn index(&self) -> usize {
+        match *self {
+            UserId::In { index, .. } | UserId::Res { index, .. } => usize::from(index),
+        }
+    }
+
+    pub fn node_id(&self) -> Option<NodeId> {
         match self {
             &UserId::In { node, .. } => Some(node),
             _ => None,
@@ -32,15 +216,41 @@ impl UserId {
     }
 }
 
-/// An index for an OriginData of an output or argument port.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub(crate) enum OriginId {
-    Out { node: NodeId, index: usize },
-    Arg { region: RegionId, index: usize },
+/// The id of an output of a node or an argument of a region, the ports
+/// that users are connected to.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum OriginId {
+    Out { node: NodeId, index: u16 },
+    Arg { region: RegionId, index: u16 },
 }
 
 impl OriginId {
-    pub(crate) fn node_id(&self) -> Option<NodeId> {
+    /// Output `index` of `node`. Panics if nodes can't have that many ports.
+    pub fn output(node: NodeId, index: usize) -> OriginId {
+        OriginId::Out {
+            node,
+            index: compact_port_index(index),
+        }
+    }
+
+    /// Argument `index` of `region`. Panics if regions can't have that many
+    /// ports.
+    pub fn argument(region: RegionId, index: usize) -> OriginId {
+        OriginId::Arg {
+            region,
+            index: compact_port_index(index),
+        }
+    }
+
+    /// The index of the port among the outputs of its node or the
+    /// arguments of its region.
+    pub fn index(&self) -> usize {
+        match *self {
+            OriginId::Out { index, .. } | OriginId::Arg { index, .. } => usize::from(index),
+        }
+    }
+
+    pub fn node_id(&self) -> Option<NodeId> {
         match self {
             &OriginId::Out { node, .. } => Some(node),
             _ => None,
@@ -48,9 +258,25 @@ impl OriginId {
     }
 }
 
+/// Whether a port carries a value or a state edge.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum PortKind {
+    #[default]
+    Val,
+    St,
+}
+
 /// A UserData contains information about an input or result port.
 #[derive(Clone, Default, Debug)]
 pub(crate) struct UserData {
+    kind: PortKind,
+    /// The type of value the port takes, if it's typed.
+    ty: Option<Rc<dyn PortType>>,
     origin: Cell<Option<OriginId>>,
     sink: Option<OriginId>,
     prev_user: Cell<Option<UserId>>,
@@ -60,8 +286,74 @@ pub(crate) struct UserData {
 /// An OriginData contains information about an output or argument port.
 #[derive(Clone, Default, Debug)]
 pub(crate) struct OriginData {
+    kind: PortKind,
+    /// The type of value the port carries, if it's typed.
+    ty: Option<Rc<dyn PortType>>,
     source: Option<UserId>,
     users: Cell<Option<UserIdList>>,
+    /// How many times the user list changed, which iterating over it checks
+    /// for.
+    generation: Cell<u32>,
+}
+
+impl OriginData {
+    /// Replaces the user list, which invalidates iterators over it.
+    fn set_users(&self, users: Option<UserIdList>) {
+        self.users.set(users);
+        self.generation.set(self.generation.get().wrapping_add(1));
+    }
+
+    fn num_users(&self) -> usize {
+        self.users.get().map_or(0, |users| users.len as usize)
+    }
+}
+
+/// Creates `val_ports` value ports followed by `st_ports` state ports.
+fn user_ports(val_ports: usize, st_ports: usize) -> impl Iterator<Item = UserData> {
+    port_kinds(val_ports, st_ports).map(|kind| UserData {
+        kind,
+        ..UserData::default()
+    })
+}
+
+/// Creates `val_ports` value ports followed by `st_ports` state ports.
+fn origin_ports(val_ports: usize, st_ports: usize) -> impl Iterator<Item = OriginData> {
+    port_kinds(val_ports, st_ports).map(|kind| OriginData {
+        kind,
+        ..OriginData::default()
+    })
+}
+
+fn port_kinds(val_ports: usize, st_ports: usize) -> impl Iterator<Item = PortKind> {
+    let val_kinds = iter::repeat_n(PortKind::Val, val_ports);
+    let st_kinds = iter::repeat_n(PortKind::St, st_ports);
+    val_kinds.chain(st_kinds)
+}
+
+/// Finds the position of the `port`-th port of the given kind.
+///
+/// Simple nodes lay out their value ports before their state ports, but
+/// structural nodes and regions grow their ports as variables are routed
+/// through them, so both kinds may be interleaved.
+fn port_index(kinds: impl Iterator<Item = PortKind>, kind: PortKind, port: usize) -> usize {
+    kinds
+        .enumerate()
+        .filter(|&(_, port_kind)| port_kind == kind)
+        .nth(port)
+        .map(|(index, _)| index)
+        .unwrap_or_else(|| panic!("no {:?} port {}", kind, port))
+}
+
+/// Escapes the characters with a meaning in labels of record-shaped nodes.
+fn escape_record_label(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        if let '{' | '}' | '|' | '<' | '>' | '"' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
 }
 
 /// A linked list of users connected to a common origin.
@@ -69,10 +361,16 @@ pub(crate) struct OriginData {
 pub(crate) struct UserIdList {
     first: UserId,
     last: UserId,
+    len: u32,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub(crate) enum NodeKind<S> {
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum NodeKind<S> {
     Op(S),
     Apply {
         arg_val_ins: usize,
@@ -86,6 +384,14 @@ pub(crate) enum NodeKind<S> {
         st_ins: usize,
         st_outs: usize,
     },
+    Theta {
+        val_loop_vars: usize,
+        st_loop_vars: usize,
+    },
+    Lambda {
+        params: usize,
+        ctx_vars: usize,
+    },
     Omega {
         imports: usize,
         exports: usize,
@@ -93,11 +399,30 @@ pub(crate) enum NodeKind<S> {
 }
 
 pub(crate) struct NodeData<S> {
-    ins: Vec<UserData>,
-    outs: Vec<OriginData>,
+    /// The ports live in the pools of the node context, so that nodes
+    /// don't allocate their own, and are read through `node_data`.
+    ins: Ports<UserData>,
+    outs: Ports<OriginData>,
     inner_regions: Cell<Option<InnerRegionList>>,
     outer_region: RegionId,
     kind: NodeKind<S>,
+    removed: bool,
+}
+
+/// A node's data along with its ports, borrowed from the pools of the
+/// node context.
+pub(crate) struct NodeDataRef<'a, S> {
+    data: Ref<'a, NodeData<S>>,
+    pub(crate) ins: Ref<'a, [UserData]>,
+    pub(crate) outs: Ref<'a, [OriginData]>,
+}
+
+impl<'a, S> Deref for NodeDataRef<'a, S> {
+    type Target = NodeData<S>;
+
+    fn deref(&self) -> &NodeData<S> {
+        &self.data
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -107,19 +432,42 @@ pub(crate) struct InnerRegionList {
 }
 
 pub(crate) struct RegionData {
+    node: Option<NodeId>,
     sequence_index: usize,
     res: Vec<UserData>,
     args: Vec<OriginData>,
     prev_region: Cell<Option<RegionId>>,
     next_region: Cell<Option<RegionId>>,
+    /// The nodes in the region that haven't been removed, in the order they
+    /// were made.
+    nodes: Vec<NodeId>,
+    removed: bool,
+    /// The structural hash of the region, if it's frozen.
+    frozen: Option<u64>,
+}
+
+impl RegionData {
+    fn new(node: Option<NodeId>, sequence_index: usize) -> RegionData {
+        RegionData {
+            node,
+            sequence_index,
+            res: vec![],
+            args: vec![],
+            prev_region: Cell::default(),
+            next_region: Cell::default(),
+            nodes: vec![],
+            removed: false,
+            frozen: None,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Default)]
-pub(crate) struct SigS {
-    pub(crate) val_ins: usize,
-    pub(crate) val_outs: usize,
-    pub(crate) st_ins: usize,
-    pub(crate) st_outs: usize,
+pub struct SigS {
+    pub val_ins: usize,
+    pub val_outs: usize,
+    pub st_ins: usize,
+    pub st_outs: usize,
 }
 
 // TODO: remove this and let region ports be imperatively created.
@@ -132,11 +480,11 @@ pub(crate) struct RegionSigS {
 }
 
 impl SigS {
-    pub(crate) fn num_input_ports(&self) -> usize {
+    pub fn num_input_ports(&self) -> usize {
         self.val_ins + self.st_ins
     }
 
-    pub(crate) fn num_output_ports(&self) -> usize {
+    pub fn num_output_ports(&self) -> usize {
         self.val_outs + self.st_outs
     }
 
@@ -155,18 +503,93 @@ impl RegionSigS {
     }
 }
 
-pub(crate) trait Sig {
+/// Algebraic properties of ops, which let equal terms written differently
+/// be recognized.
+pub trait OpProperties {
+    /// Whether the value operands of the op may be given in any order.
+    fn is_commutative(&self) -> bool {
+        false
+    }
+
+    /// Whether nested uses of the op may be regrouped, as in `(a + b) + c`
+    /// and `a + (b + c)`.
+    fn is_associative(&self) -> bool {
+        false
+    }
+}
+
+/// No properties at all, for ops that don't declare any.
+impl OpProperties for () {}
+
+pub trait Sig {
     fn sig(&self) -> SigS;
+
+    /// The algebraic properties of the op. Ops with any implement
+    /// `OpProperties` and return themselves.
+    fn properties(&self) -> &dyn OpProperties {
+        &()
+    }
+
+    /// Whether no other op with state ports may be moved or reordered
+    /// across this one, such as a call with unknown effects.
+    ///
+    /// Barriers must have state ports, and every other op with state ports
+    /// in their region must be ordered before or after them through state
+    /// edges, which `NodeCtxt::verify` checks.
+    fn is_scheduling_barrier(&self) -> bool {
+        false
+    }
+
+    /// Whether loops and recursion may be taken to always end, as in
+    /// languages where those that never end without side effects are
+    /// undefined. Otherwise, lambdas that loop or recurse are never taken to
+    /// be pure, as calling them may not return.
+    fn assumes_termination() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+
+    /// The type of the values on the op's ports, which edges must agree on
+    /// when `opt_type_check` is on. Ops are untyped by default.
+    type Type: Clone + PartialEq + Debug + 'static = ();
+
+    /// The type value input `port` takes, or `None` if it takes any.
+    fn val_in_type(&self, _port: usize) -> Option<Self::Type> {
+        None
+    }
+
+    /// The type of value output `port`, or `None` if it's untyped.
+    fn val_out_type(&self, _port: usize) -> Option<Self::Type> {
+        None
+    }
 }
 
 // TODO: implement this dynamically for structured nodes.
 impl<S: Sig> Sig for NodeData<S> {
+    type Type = S::Type;
+
     fn sig(&self) -> SigS {
         self.kind.sig()
     }
+
+    fn properties(&self) -> &dyn OpProperties {
+        self.kind.properties()
+    }
+
+    fn val_in_type(&self, port: usize) -> Option<S::Type> {
+        self.kind.val_in_type(port)
+    }
+
+    fn val_out_type(&self, port: usize) -> Option<S::Type> {
+        self.kind.val_out_type(port)
+    }
 }
 
 impl<S: Sig> Sig for NodeKind<S> {
+    type Type = S::Type;
+
     fn sig(&self) -> SigS {
         match self {
             NodeKind::Op(s) => s.sig(),
@@ -196,37 +619,153 @@ impl<S: Sig> Sig for NodeKind<S> {
                     ..SigS::default()
                 }
             }
+            &NodeKind::Theta {
+                val_loop_vars,
+                st_loop_vars,
+            } => SigS {
+                val_ins: val_loop_vars,
+                val_outs: val_loop_vars,
+                st_ins: st_loop_vars,
+                st_outs: st_loop_vars,
+            },
+            // Context variables come in, and the function itself comes out.
+            &NodeKind::Lambda { ctx_vars, .. } => SigS {
+                val_ins: ctx_vars,
+                val_outs: 1,
+                ..SigS::default()
+            },
             &NodeKind::Omega { .. } => SigS::default(),
         }
     }
+
+    fn properties(&self) -> &dyn OpProperties {
+        match self {
+            NodeKind::Op(s) => s.properties(),
+            _ => &(),
+        }
+    }
+
+    fn val_in_type(&self, port: usize) -> Option<S::Type> {
+        match self {
+            NodeKind::Op(s) => s.val_in_type(port),
+            _ => None,
+        }
+    }
+
+    fn val_out_type(&self, port: usize) -> Option<S::Type> {
+        match self {
+            NodeKind::Op(s) => s.val_out_type(port),
+            _ => None,
+        }
+    }
+}
+
+impl<S> NodeKind<S> {
+    /// Whether nodes of this kind own inner regions.
+    pub fn is_structural(&self) -> bool {
+        match self {
+            NodeKind::Gamma { .. }
+            | NodeKind::Theta { .. }
+            | NodeKind::Lambda { .. }
+            | NodeKind::Omega { .. } => true,
+            NodeKind::Op(..) | NodeKind::Apply { .. } => false,
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 struct NodeTerm<S> {
     region: RegionId,
     kind: NodeKind<S>,
     origins: SmallVec<[OriginId; 4]>,
 }
 
-pub(crate) struct NodeCtxt<S> {
-    nodes: RefCell<Vec<NodeData<S>>>,
-    regions: RefCell<Vec<RegionData>>,
+pub struct NodeCtxt<S> {
+    nodes: Arena<RefCell<NodeData<S>>>,
+    regions: Arena<RefCell<RegionData>>,
+    input_ports: PortPool<UserData>,
+    output_ports: PortPool<OriginData>,
     interned_nodes: RefCell<HashMap<NodeTerm<S>, NodeId>>,
-    config: NodeCtxtConfig,
+    /// How the nodes looked up in the intern table fared, by the kind of
+    /// their op, or `None` for applies, along with the first kind of each.
+    /// Kinds are kept rather than nodes, which may be removed.
+    intern_counts: RefCell<HashMap<Option<Discriminant<S>>, (NodeKind<S>, InternCounts)>>,
+    origin_names: RefCell<HashMap<OriginId, String>>,
+    spans: RefCell<NodeMap<Span>>,
+    node_names: RefCell<NodeMap<String>>,
+    journal: RefCell<VecDeque<snapshot::Mutation>>,
+    config: NodeCtxtConfig<S>,
+}
+
+/// Which nodes are looked up in the intern table as they're made, sharing
+/// an equal node made before rather than adding another. Structural and
+/// side-effectful nodes are never shared, whatever the policy.
+pub enum InterningPolicy<S> {
+    /// Every node that may be shared.
+    Stateless,
+    /// Only nodes without inputs, such as literals.
+    Leaves,
+    /// No nodes, which makes creating them cheaper.
+    Never,
+    /// The op nodes whose op the predicate holds for.
+    Ops(Rc<dyn Fn(&S) -> bool>),
+}
+
+impl<S> Clone for InterningPolicy<S> {
+    fn clone(&self) -> InterningPolicy<S> {
+        match self {
+            InterningPolicy::Stateless => InterningPolicy::Stateless,
+            InterningPolicy::Leaves => InterningPolicy::Leaves,
+            InterningPolicy::Never => InterningPolicy::Never,
+            InterningPolicy::Ops(holds) => InterningPolicy::Ops(Rc::clone(holds)),
+        }
+    }
 }
 
-pub(crate) struct NodeCtxtConfig {
-    pub(crate) opt_interning: bool,
+pub struct NodeCtxtConfig<S> {
+    /// Which nodes are shared with an equal node made before.
+    pub interning: InterningPolicy<S>,
+    /// Whether finishing a gamma or theta builder removes the nodes of its
+    /// regions that ended up unused.
+    pub opt_region_cleanup: bool,
+    /// Whether replacing the users of a named origin passes its debug name
+    /// on to the replacement.
+    pub opt_transfer_names: bool,
+    /// Whether connecting ports of different types panics, or makes
+    /// `NodeBuilder::try_finish` fail. Untyped ports connect to anything.
+    pub opt_type_check: bool,
+    /// Whether connecting an input to an output of its own node, or of a
+    /// node using its outputs, panics rather than leaving a cycle for
+    /// `verify` to find.
+    pub opt_cycle_check: bool,
+    /// Whether `verify` reports state outputs with more than one user, which
+    /// usually means an ordering between their users got lost.
+    pub opt_linear_state: bool,
+    /// Where `run_pass` writes the graph to when a pass panics. Changes to
+    /// the graph are only journaled when this is set.
+    pub crash_snapshot: Option<PathBuf>,
 }
 
-impl Default for NodeCtxtConfig {
-    fn default() -> NodeCtxtConfig {
+impl<S> Default for NodeCtxtConfig<S> {
+    fn default() -> NodeCtxtConfig<S> {
         NodeCtxtConfig {
-            opt_interning: true,
+            interning: InterningPolicy::Stateless,
+            opt_region_cleanup: false,
+            opt_transfer_names: true,
+            opt_type_check: false,
+            opt_cycle_check: false,
+            opt_linear_state: false,
+            crash_snapshot: None,
         }
     }
 }
 
+impl<S: Eq + Hash> Default for NodeCtxt<S> {
+    fn default() -> NodeCtxt<S> {
+        NodeCtxt::new()
+    }
+}
+
 impl<S> std::hash::Hash for NodeCtxt<S> {
     fn hash<H>(&self, state: &mut H)
     where
@@ -237,29 +776,39 @@ impl<S> std::hash::Hash for NodeCtxt<S> {
 }
 
 impl<S> NodeCtxt<S> {
-    pub(crate) fn num_nodes(&self) -> usize {
-        self.nodes.borrow().len()
+    pub fn num_nodes(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| !node.borrow().removed)
+            .count()
     }
 
-    pub(crate) fn num_edges(&self) -> usize {
-        self.nodes.borrow().iter().map(|node| node.ins.len()).sum()
+    pub fn num_edges(&self) -> usize {
+        self.nodes.iter().map(|node| node.borrow().ins.len()).sum()
     }
 }
 
 impl<S> NodeCtxt<S> {
-    pub(crate) fn new() -> NodeCtxt<S>
+    pub fn new() -> NodeCtxt<S>
     where
         S: Eq + Hash,
     {
         NodeCtxt {
-            nodes: RefCell::new(vec![]),
-            regions: RefCell::new(vec![]),
+            nodes: Arena::new(),
+            regions: iter::once(RefCell::new(RegionData::new(None, 0))).collect(),
+            input_ports: PortPool::new(),
+            output_ports: PortPool::new(),
             interned_nodes: RefCell::default(),
+            intern_counts: RefCell::default(),
+            origin_names: RefCell::default(),
+            spans: RefCell::default(),
+            node_names: RefCell::default(),
+            journal: RefCell::default(),
             config: Default::default(),
         }
     }
 
-    pub(crate) fn with_config(config: NodeCtxtConfig) -> NodeCtxt<S>
+    pub fn with_config(config: NodeCtxtConfig<S>) -> NodeCtxt<S>
     where
         S: Eq + Hash,
     {
@@ -269,205 +818,683 @@ impl<S> NodeCtxt<S> {
         }
     }
 
-    // FIXME: This doesn't do interning. How could we do it?
+    /// Creates a context with room for `nodes` nodes and `regions` regions
+    /// besides the root region, for when the size of the program is known
+    /// ahead of time.
+    pub fn with_capacity(nodes: usize, regions: usize) -> NodeCtxt<S>
+    where
+        S: Eq + Hash,
+    {
+        let ncx = NodeCtxt::new();
+        ncx.reserve(nodes, regions);
+        ncx
+    }
+
+    /// Makes room for at least `nodes` more nodes and `regions` more
+    /// regions, along with interning as many nodes.
+    pub fn reserve(&self, nodes: usize, regions: usize)
+    where
+        S: Eq + Hash,
+    {
+        self.nodes.reserve(nodes);
+        self.regions.reserve(regions);
+        self.interned_nodes.borrow_mut().reserve(nodes);
+    }
+
+    /// The top-level region, which isn't owned by any node.
+    pub fn root_region(&self) -> RegionId {
+        RegionId(0)
+    }
+
+    /// Creates a node with unconnected ports, bypassing interning until
+    /// `intern_node` is called on it.
     fn create_node(&self, node_kind: NodeKind<S>, outer_region_id: RegionId) -> Node<'_, S>
     where
         S: Sig,
     {
+        self.assert_not_frozen(outer_region_id);
+
         let node_id;
 
         {
-            let mut nodes = self.nodes.borrow_mut();
-            node_id = NodeId(nodes.len());
-            nodes.push(NodeData {
-                ins: vec![UserData::default(); node_kind.sig().num_input_ports()],
-                outs: vec![OriginData::default(); node_kind.sig().num_output_ports()],
+            let sig = node_kind.sig();
+            let ins = self.input_ports.alloc(user_ports(sig.val_ins, sig.st_ins));
+            let outs = self
+                .output_ports
+                .alloc(origin_ports(sig.val_outs, sig.st_outs));
+            node_id = NodeId::new(self.nodes.push(RefCell::new(NodeData {
+                ins,
+                outs,
                 inner_regions: Cell::default(),
                 outer_region: outer_region_id,
                 kind: node_kind,
-            });
+                removed: false,
+            })));
         }
+        self.type_op_ports(node_id);
+        self.region_data_mut(outer_region_id).nodes.push(node_id);
+        self.record(snapshot::Mutation::Created(node_id));
         self.node_ref(node_id)
     }
 
     fn connect_ports(&self, user_id: UserId, origin_id: OriginId) {
+        if let Some(err) = self.misplaced_connection(user_id, origin_id) {
+            panic!(
+                "{:?} can't be connected to {:?}: {}",
+                user_id, origin_id, err
+            );
+        }
+        self.link_user(user_id, origin_id);
+    }
+
+    /// Connects `user_id` to `origin_id` wherever they are, which leaves the
+    /// graph malformed if they're in different regions.
+    pub(crate) fn link_user(&self, user_id: UserId, origin_id: OriginId) {
+        self.assert_not_frozen(self.user_region(user_id));
+
         let user_data = self.user_data(user_id);
 
         assert_eq!(user_data.origin.get(), None);
         assert_eq!(user_data.prev_user.get(), None);
         assert_eq!(user_data.next_user.get(), None);
 
-        user_data.origin.set(Some(origin_id));
-
         let origin_data = self.origin_data(origin_id);
 
+        assert_eq!(user_data.kind, origin_data.kind);
+        self.check_connection(user_id, origin_id);
+        self.check_acyclic(user_id, origin_id);
+
+        user_data.origin.set(Some(origin_id));
+
         let new_user_list = match origin_data.users.get() {
-            Some(UserIdList { first, last }) => {
+            Some(UserIdList { first, last, len }) => {
                 self.user_data(last).next_user.set(Some(user_id));
                 user_data.prev_user.set(Some(last));
                 UserIdList {
                     first,
                     last: user_id,
+                    len: len + 1,
                 }
             }
             None => UserIdList {
                 first: user_id,
                 last: user_id,
+                len: 1,
             },
         };
 
-        origin_data.users.set(Some(new_user_list));
+        origin_data.set_users(Some(new_user_list));
+        self.record(snapshot::Mutation::Connected {
+            user: user_id,
+            origin: origin_id,
+        });
+    }
+
+    /// Connects `user_id` to `origin_id`, or reports why they can't be
+    /// connected rather than panicking.
+    pub(crate) fn try_connect_ports(
+        &self,
+        user_id: UserId,
+        origin_id: OriginId,
+    ) -> Result<(), ConnectError> {
+        if self.user_data(user_id).origin.get().is_some() {
+            return Err(ConnectError::AlreadyConnected);
+        }
+        if let Some(err) = self.misplaced_connection(user_id, origin_id) {
+            return Err(err);
+        }
+        if let Some(err) = self.mistyped_connection(user_id, origin_id) {
+            return Err(err);
+        }
+        self.connect_ports(user_id, origin_id);
+        Ok(())
+    }
+
+    /// The error of connecting `user_id` to `origin_id`, if the latter isn't
+    /// in the region of the former. Edges never leave a region: values go
+    /// into and out of inner regions through their arguments and results.
+    fn misplaced_connection(&self, user_id: UserId, origin_id: OriginId) -> Option<ConnectError> {
+        let user_region = self.user_region(user_id);
+        let origin_region = self.origin_region(origin_id);
+        if user_region == origin_region {
+            None
+        } else {
+            Some(ConnectError::RegionMismatch {
+                user_region,
+                origin_region,
+            })
+        }
+    }
+
+    /// Removes `user_id` from the user list of its origin, leaving it
+    /// unconnected.
+    fn unlink_user(&self, user_id: UserId) {
+        self.assert_not_frozen(self.user_region(user_id));
+
+        let user_data = self.user_data(user_id);
+        let origin_id = match user_data.origin.get() {
+            Some(origin_id) => origin_id,
+            None => return,
+        };
+
+        let prev_user = user_data.prev_user.get();
+        let next_user = user_data.next_user.get();
+
+        if let Some(prev_user) = prev_user {
+            self.user_data(prev_user).next_user.set(next_user);
+        }
+        if let Some(next_user) = next_user {
+            self.user_data(next_user).prev_user.set(prev_user);
+        }
+
+        let origin_data = self.origin_data(origin_id);
+        let UserIdList { first, last, len } = origin_data.users.get().unwrap();
+        let new_user_list = match (prev_user, next_user) {
+            (None, None) => None,
+            _ => Some(UserIdList {
+                first: if first == user_id {
+                    next_user.unwrap()
+                } else {
+                    first
+                },
+                last: if last == user_id {
+                    prev_user.unwrap()
+                } else {
+                    last
+                },
+                len: len - 1,
+            }),
+        };
+        origin_data.set_users(new_user_list);
+
+        user_data.origin.set(None);
+        user_data.prev_user.set(None);
+        user_data.next_user.set(None);
+        self.record(snapshot::Mutation::Disconnected {
+            user: user_id,
+            origin: origin_id,
+        });
     }
 
-    pub(crate) fn print(&self, out: &mut dyn Write) -> io::Result<()>
+    /// Disconnects `user_id` from its origin, properly unlinking it from
+    /// the origin's user list.
+    ///
+    /// A node with a disconnected input is no longer interned, since its term
+    /// changed.
+    pub fn disconnect(&self, user_id: UserId)
     where
-        S: Sig + Debug,
+        S: Eq + Hash + Clone,
     {
-        writeln!(out, "digraph rvsdg {{")?;
-        writeln!(out, "    node [shape=record]")?;
-        writeln!(out, "    edge [arrowhead=none]")?;
-        for idx in 0..self.nodes.borrow().len() {
-            let node = self.node_ref(NodeId(idx));
-            let sig = node.kind().sig();
-
-            match *node.kind() {
-                NodeKind::Op(ref operation) => {
-                    let dot_ins = (0..sig.num_input_ports())
-                        .map(|i| format!("<i{0}>{0}", i))
-                        .collect::<Vec<_>>()
-                        .join("|");
-                    let dot_outs = (0..sig.num_output_ports())
-                        .map(|i| format!("<o{0}>{0}", i))
-                        .collect::<Vec<_>>()
-                        .join("|");
-                    let mut label_op = String::with_capacity(16);
-                    for c in format!("{:?}", operation).chars() {
-                        if c == '{' || c == '}' {
-                            label_op.push('\\');
-                        }
-                        label_op.push(c);
-                    }
-                    let label_value = vec![dot_ins, label_op, dot_outs]
-                        .into_iter()
-                        .filter(|s| !s.is_empty())
-                        .collect::<Vec<_>>()
-                        .join("}|{");
-                    let label = format!("{{{{{}}}}}", label_value);
-                    writeln!(out, r#"    n{} [label="{}"]"#, node.id.0, label)?;
-                }
-                _ => unimplemented!(),
-            }
+        if let UserId::In { node, .. } = user_id {
+            self.forget_interned(node);
+        }
+        self.unlink_user(user_id);
+    }
 
-            for i in 0..sig.val_ins {
-                let origin = node.val_in(i).origin();
-                match origin.0.origin_id {
-                    OriginId::Out {
-                        node: origin_node_id,
-                        index,
-                    } => {
-                        let port_origin = index;
-                        let port_user = i;
-                        writeln!(
-                            out,
-                            "    n{}:o{} -> n{}:i{} [color=blue]",
-                            origin_node_id.0, port_origin, node.id.0, port_user
-                        )?;
-                    }
-                    _ => unimplemented!(),
-                }
-            }
+    /// Disconnects `user_id` from its current origin, if any, and connects it
+    /// to `origin_id` instead.
+    pub fn reconnect(&self, user_id: UserId, origin_id: OriginId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        self.disconnect(user_id);
+        self.connect_ports(user_id, origin_id);
+    }
 
-            for i in 0..sig.st_ins {
-                let origin = node.st_in(i).origin();
-                match origin.0.origin_id {
-                    OriginId::Out {
-                        node: origin_node_id,
-                        index,
-                    } => {
-                        let port_origin = index;
-                        let port_user = sig.val_ins + i;
-                        writeln!(
-                            out,
-                            "    n{}:o{} -> n{}:i{} [style=dashed, color=red]",
-                            origin_node_id.0, port_origin, node.id.0, port_user
-                        )?;
-                    }
-                    _ => unimplemented!(),
-                }
-            }
+    /// Reconnects every user of `origin_id` to `new_origin_id`, leaving the
+    /// former without users.
+    pub fn replace_all_users(&self, origin_id: OriginId, new_origin_id: OriginId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        assert_eq!(
+            self.origin_data(origin_id).kind,
+            self.origin_data(new_origin_id).kind
+        );
+        assert_eq!(
+            self.origin_region(origin_id),
+            self.origin_region(new_origin_id)
+        );
+        if origin_id == new_origin_id {
+            return;
+        }
+
+        let users: Vec<UserId> = self
+            .origin_ref(origin_id)
+            .users()
+            .map(|user| user.id())
+            .collect();
+        for user_id in users {
+            self.reconnect(user_id, new_origin_id);
+        }
+
+        if self.config.opt_transfer_names {
+            self.transfer_name(origin_id, new_origin_id);
         }
-        writeln!(out, "}}")
     }
 
-    pub(crate) fn node_data(&self, id: NodeId) -> Ref<NodeData<S>> {
-        Ref::map(self.nodes.borrow(), |nodes| &nodes[id.0])
+    /// Gives `origin_id` a name to refer to it by in dumps.
+    pub fn set_origin_name(&self, origin_id: OriginId, name: impl Into<String>) {
+        self.origin_names
+            .borrow_mut()
+            .insert(origin_id, name.into());
     }
 
-    pub(crate) fn region_data(&self, id: RegionId) -> Ref<RegionData> {
-        Ref::map(self.regions.borrow(), |regions| &regions[id.0])
+    pub fn origin_name(&self, origin_id: OriginId) -> Option<String> {
+        self.origin_names.borrow().get(&origin_id).cloned()
     }
 
-    pub(crate) fn user_data(&self, user_id: UserId) -> Ref<UserData> {
-        match user_id {
-            UserId::In { node, index } => {
-                Ref::map(self.node_data(node), |node_data| &node_data.ins[index])
-            }
-            UserId::Res { region, index } => Ref::map(self.region_data(region), |region_data| {
-                &region_data.res[index]
-            }),
-        }
+    /// Gives `node_id` a name to refer to it by in dumps.
+    pub fn set_node_name(&self, node_id: NodeId, name: impl Into<String>) {
+        self.node_names.borrow_mut().insert(node_id, name.into());
     }
 
-    pub(crate) fn origin_data(&self, origin_id: OriginId) -> Ref<OriginData> {
-        match origin_id {
-            OriginId::Out { node, index } => {
-                Ref::map(self.node_data(node), |node_data| &node_data.outs[index])
-            }
-            OriginId::Arg { region, index } => Ref::map(self.region_data(region), |region_data| {
-                &region_data.args[index]
-            }),
+    pub fn node_name(&self, node_id: NodeId) -> Option<String> {
+        self.node_names.borrow().get(node_id).cloned()
+    }
+
+    /// Moves the name of `origin_id` over to `new_origin_id`, merging it with
+    /// the latter's own name, if any.
+    fn transfer_name(&self, origin_id: OriginId, new_origin_id: OriginId) {
+        let mut origin_names = self.origin_names.borrow_mut();
+        if let Some(name) = origin_names.remove(&origin_id) {
+            let merged = match origin_names.remove(&new_origin_id) {
+                Some(new_name) if new_name != name => format!("{}/{}", new_name, name),
+                _ => name,
+            };
+            origin_names.insert(new_origin_id, merged);
         }
     }
 
-    fn compute_node_hash(&self, node_term: &NodeTerm<S>) -> u64
+    /// The term of a node, provided all of its inputs are connected.
+    fn node_term(&self, node_id: NodeId) -> Option<NodeTerm<S>>
     where
-        S: Eq + Hash,
+        S: Clone,
     {
-        let mut hasher = self.interned_nodes.borrow().hasher().build_hasher();
-        node_term.hash(&mut hasher);
-        hasher.finish()
+        let node_data = self.node_data(node_id);
+        let origins: Option<SmallVec<[OriginId; 4]>> =
+            node_data.ins.iter().map(|user| user.origin.get()).collect();
+        Some(NodeTerm {
+            region: node_data.outer_region,
+            kind: node_data.kind.clone(),
+            origins: origins?,
+        })
     }
 
-    fn mk_node_with(&self, kind: NodeKind<S>, origins: &[OriginId]) -> NodeId
+    /// Interns a node whose inputs were connected by hand, such as one made
+    /// by `create_node`, once all of them are connected.
+    ///
+    /// If an equal node already exists, the users of this one are moved over
+    /// to it and this one is removed. Returns the node that remains.
+    pub(crate) fn intern_node(&self, node_id: NodeId) -> NodeId
     where
         S: Sig + Eq + Hash + Clone,
     {
-        assert_eq!(kind.sig().num_input_ports(), origins.len());
+        self.sort_commutative_operands(node_id);
+        let node_term = self
+            .node_term(node_id)
+            .unwrap_or_else(|| panic!("node {:?} has unconnected inputs", node_id));
 
-        let region_id = RegionId(0);
+        if !self.interns(&node_term.kind) {
+            return node_id;
+        }
 
-        let create_node = |kind: NodeKind<S>, origins: &[OriginId]| {
-            // Node creation works as follows:
-            //
-            // 1. Create the UserData sequence, whilst linking the user list of each origin.
-            // 2. Initialize the OriginData sequence with empty users.
-            // 3. Push the new node to the node context and return its id.
+        let node_hash = self.compute_node_hash(&node_term);
+        let interned_id = {
+            let mut interned_nodes = self.interned_nodes.borrow_mut();
+            let entry = interned_nodes
+                .raw_entry_mut()
+                .from_key_hashed_nocheck(node_hash, &node_term);
+            match entry {
+                RawEntryMut::Occupied(e) => *e.get(),
+                RawEntryMut::Vacant(e) => {
+                    e.insert_hashed_nocheck(node_hash, node_term, node_id);
+                    return node_id;
+                }
+            }
+        };
 
-            // Input ports are put into this vector so the node creation comes down to just
-            // a push into the `self.nodes`.
-            let mut new_node_inputs = Vec::<UserData>::with_capacity(kind.sig().num_input_ports());
-            let node_id = NodeId(self.nodes.borrow().len());
+        if interned_id != node_id {
+            let num_outputs = self.node_data(node_id).outs.len();
+            for index in 0..num_outputs {
+                self.replace_all_users(
+                    OriginId::output(node_id, index),
+                    OriginId::output(interned_id, index),
+                );
+            }
+            self.remove_node(node_id);
+        }
+        interned_id
+    }
 
-            for (i, &origin) in origins.iter().enumerate() {
-                let new_in_id = UserId::In {
-                    node: node_id,
-                    index: i,
-                };
+    /// Reconnects the value operands of a commutative node in the canonical
+    /// order nodes made by `mk_node_in_region_with` take them in.
+    fn sort_commutative_operands(&self, node_id: NodeId)
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let origins: Option<Vec<OriginId>> = {
+            let node_data = self.node_data(node_id);
+            if !node_data.kind.properties().is_commutative() {
+                return;
+            }
+            let num_operands = node_data.kind.sig().val_ins;
+            node_data.ins[..num_operands]
+                .iter()
+                .map(|user| user.origin.get())
+                .collect()
+        };
+        // Nodes with unconnected inputs can't be interned anyway.
+        let origins = match origins {
+            Some(origins) => origins,
+            None => return,
+        };
+        let mut sorted = origins.clone();
+        sorted.sort();
+        for (index, (origin_id, sorted_id)) in origins.into_iter().zip(sorted).enumerate() {
+            if origin_id != sorted_id {
+                self.reconnect(UserId::input(node_id, index), sorted_id);
+            }
+        }
+    }
+
+    /// Adds a node to the intern table, unless an equal node is already in
+    /// it, in which case neither is merged into the other. Used when reading
+    /// graphs back, so they keep the nodes they were written with.
+    fn register_interned(&self, node_id: NodeId)
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let node_term = match self.node_term(node_id) {
+            Some(node_term) => node_term,
+            None => return,
+        };
+        if !self.interns(&node_term.kind) {
+            return;
+        }
+        self.interned_nodes
+            .borrow_mut()
+            .entry(node_term)
+            .or_insert(node_id);
+    }
+
+    /// Whether nodes of `kind` are looked up in the intern table, as
+    /// `interning` decides. Structural nodes own their regions, so they
+    /// can never be shared.
+    fn interns(&self, kind: &NodeKind<S>) -> bool
+    where
+        S: Sig,
+    {
+        if kind.is_structural() || kind.sig().is_side_effectful() {
+            return false;
+        }
+        match &self.config.interning {
+            InterningPolicy::Stateless => true,
+            InterningPolicy::Leaves => kind.sig().num_input_ports() == 0,
+            InterningPolicy::Never => false,
+            InterningPolicy::Ops(holds) => matches!(kind, NodeKind::Op(op) if holds(op)),
+        }
+    }
+
+    /// Drops the interning entry of a node, if it's the one the table maps
+    /// its term to, so that later nodes with the same term aren't
+    /// deduplicated into it.
+    fn forget_interned(&self, node_id: NodeId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        let node_term = match self.node_term(node_id) {
+            Some(node_term) => node_term,
+            None => return,
+        };
+
+        let mut interned_nodes = self.interned_nodes.borrow_mut();
+        if interned_nodes.get(&node_term) == Some(&node_id) {
+            interned_nodes.remove(&node_term);
+        }
+    }
+
+    /// Removes a node from the graph, disconnecting its inputs.
+    ///
+    /// None of its outputs may have users left. A structural node is removed
+    /// along with its regions and everything inside them. The node's slot is
+    /// kept as a tombstone, so ids of other nodes remain valid, but the names
+    /// and spans of removed nodes and their ports are dropped.
+    pub fn remove_node(&self, node_id: NodeId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        {
+            let node_data = self.node_data(node_id);
+            assert!(!node_data.removed, "node {:?} was already removed", node_id);
+            self.assert_not_frozen(node_data.outer_region);
+            for (index, out) in node_data.outs.iter().enumerate() {
+                assert!(
+                    out.users.get().is_none(),
+                    "output {} of node {:?} still has users",
+                    index,
+                    node_id
+                );
+            }
+        }
+
+        let mut removed_nodes = vec![];
+        let mut removed_regions = vec![];
+        self.unlink_node(node_id, &mut removed_nodes, &mut removed_regions);
+
+        let mut origin_names = self.origin_names.borrow_mut();
+        origin_names.retain(|&origin_id, _| match origin_id {
+            OriginId::Out { node, .. } => !removed_nodes.contains(&node),
+            OriginId::Arg { region, .. } => !removed_regions.contains(&region),
+        });
+
+        for &node_id in &removed_nodes {
+            self.spans.borrow_mut().remove(node_id);
+            self.node_names.borrow_mut().remove(node_id);
+            let mut node_data = self.node_data_mut(node_id);
+            node_data.removed = true;
+            self.input_ports.release(mem::take(&mut node_data.ins));
+            self.output_ports.release(mem::take(&mut node_data.outs));
+            node_data.inner_regions.set(None);
+        }
+
+        for region_id in removed_regions {
+            let mut region_data = self.region_data_mut(region_id);
+            region_data.removed = true;
+            region_data.args.clear();
+            region_data.res.clear();
+            region_data.nodes.clear();
+        }
+
+        // Nodes in removed regions were dropped along with them, which only
+        // leaves the node that was asked to be removed.
+        let outer_region = self.node_data(node_id).outer_region;
+        self.region_data_mut(outer_region)
+            .nodes
+            .retain(|&region_node| region_node != node_id);
+        self.record(snapshot::Mutation::Removed(node_id));
+    }
+
+    /// Removes the nodes of a region none of whose outputs are used, and then
+    /// the nodes that were only used by those.
+    pub fn remove_unused_nodes(&self, region_id: RegionId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        let mut worklist = self.region_nodes(region_id);
+
+        while let Some(node_id) = worklist.pop() {
+            let operands: Vec<NodeId> = {
+                let node_data = self.node_data(node_id);
+                if node_data.removed || node_data.outs.iter().any(|out| out.users.get().is_some()) {
+                    continue;
+                }
+                node_data
+                    .ins
+                    .iter()
+                    .filter_map(|user| user.origin.get()?.node_id())
+                    .collect()
+            };
+            self.remove_node(node_id);
+            worklist.extend(operands);
+        }
+    }
+
+    /// Disconnects the inputs of a node and, for structural nodes, every
+    /// edge inside their regions, collecting what has to be tombstoned.
+    fn unlink_node(
+        &self,
+        node_id: NodeId,
+        removed_nodes: &mut Vec<NodeId>,
+        removed_regions: &mut Vec<RegionId>,
+    ) where
+        S: Eq + Hash + Clone,
+    {
+        self.forget_interned(node_id);
+
+        let num_inputs = self.node_data(node_id).ins.len();
+        for index in 0..num_inputs {
+            self.unlink_user(UserId::input(node_id, index));
+        }
+
+        let mut inner_region = self
+            .node_data(node_id)
+            .inner_regions
+            .get()
+            .map(|regions| regions.first_region);
+        while let Some(region_id) = inner_region {
+            let num_results = self.region_data(region_id).res.len();
+            for index in 0..num_results {
+                self.unlink_user(UserId::result(region_id, index));
+            }
+
+            for inner_node in self.region_nodes(region_id) {
+                self.unlink_node(inner_node, removed_nodes, removed_regions);
+            }
+
+            removed_regions.push(region_id);
+            inner_region = self.region_data(region_id).next_region.get();
+        }
+
+        removed_nodes.push(node_id);
+    }
+
+    pub(crate) fn node_data(&self, id: NodeId) -> NodeDataRef<'_, S> {
+        let data = self.nodes[id.index()].borrow();
+        let ins = self.input_ports.get(&data.ins);
+        let outs = self.output_ports.get(&data.outs);
+        NodeDataRef { data, ins, outs }
+    }
+
+    pub(crate) fn region_data(&self, id: RegionId) -> Ref<RegionData> {
+        self.regions[id.index()].borrow()
+    }
+
+    fn node_data_mut(&self, id: NodeId) -> RefMut<'_, NodeData<S>> {
+        self.nodes[id.index()].borrow_mut()
+    }
+
+    fn region_data_mut(&self, id: RegionId) -> RefMut<'_, RegionData> {
+        self.regions[id.index()].borrow_mut()
+    }
+
+    pub(crate) fn user_data(&self, user_id: UserId) -> Ref<UserData> {
+        match user_id {
+            UserId::In { node, index } => {
+                Ref::map(self.node_data(node).ins, |ins| &ins[usize::from(index)])
+            }
+            UserId::Res { region, index } => Ref::map(self.region_data(region), |region_data| {
+                &region_data.res[usize::from(index)]
+            }),
+        }
+    }
+
+    pub(crate) fn origin_data(&self, origin_id: OriginId) -> Ref<OriginData> {
+        match origin_id {
+            OriginId::Out { node, index } => {
+                Ref::map(self.node_data(node).outs, |outs| &outs[usize::from(index)])
+            }
+            OriginId::Arg { region, index } => Ref::map(self.region_data(region), |region_data| {
+                &region_data.args[usize::from(index)]
+            }),
+        }
+    }
+
+    fn compute_node_hash(&self, node_term: &NodeTerm<S>) -> u64
+    where
+        S: Eq + Hash,
+    {
+        let mut hasher = self.interned_nodes.borrow().hasher().build_hasher();
+        node_term.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Makes a node in the region of its origins, or in the root region if it
+    /// has none.
+    fn mk_node_with(&self, kind: NodeKind<S>, origins: &[OriginId]) -> NodeId
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let region_id = match origins.first() {
+            Some(&origin_id) => self.origin_region(origin_id),
+            None => self.root_region(),
+        };
+        self.mk_node_in_region_with(region_id, kind, origins)
+    }
+
+    fn mk_node_in_region_with(
+        &self,
+        region_id: RegionId,
+        kind: NodeKind<S>,
+        origins: &[OriginId],
+    ) -> NodeId
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        assert_eq!(kind.sig().num_input_ports(), origins.len());
+        for &origin_id in origins {
+            assert_eq!(
+                self.origin_region(origin_id),
+                region_id,
+                "origins of a node must all be in its region"
+            );
+        }
+
+        // Commutative ops take their operands in a canonical order, so that
+        // the same term is interned however they were given.
+        let mut origins: SmallVec<[OriginId; 4]> = origins.into();
+        if kind.properties().is_commutative() {
+            origins[..kind.sig().val_ins].sort();
+        }
+        let origins = &origins[..];
+        if let Some(err) = self.mistyped_operand(&kind, origins) {
+            panic!("node inputs can't be connected: {}", err);
+        }
+
+        let create_node = |kind: NodeKind<S>, origins: &[OriginId]| {
+            // Node creation works as follows:
+            //
+            // 1. Create the UserData sequence, whilst linking the user list of each origin.
+            // 2. Initialize the OriginData sequence with empty users.
+            // 3. Push the new node to the node context and return its id.
+
+            // Input ports are put into this range so the node creation comes down to just
+            // a push into the `self.nodes`.
+            let mut new_node_inputs = self.input_ports.reserve(kind.sig().num_input_ports());
+            self.assert_not_frozen(region_id);
+            let node_id = NodeId::new(self.nodes.len());
+            let input_kinds = port_kinds(kind.sig().val_ins, kind.sig().st_ins);
+
+            for ((i, &origin), port_kind) in origins.iter().enumerate().zip(input_kinds) {
+                assert_eq!(self.origin_data(origin).kind, port_kind);
+                let new_in_id = UserId::input(node_id, i);
                 let (prev_user, new_user_list) = match self.origin_data(origin).users.get() {
-                    Some(UserIdList { first, last }) => {
+                    Some(UserIdList { first, last, len }) => {
                         match last {
                             UserId::In { node, index } if node == node_id => {
-                                new_node_inputs[index].next_user.set(Some(new_in_id));
+                                self.input_ports.get(&new_node_inputs)[usize::from(index)]
+                                    .next_user
+                                    .set(Some(new_in_id));
                             }
                             _ => {
                                 self.user_data(last).next_user.set(Some(new_in_id));
@@ -476,6 +1503,7 @@ impl<S> NodeCtxt<S> {
                         let new_user_list = UserIdList {
                             first,
                             last: new_in_id,
+                            len: len + 1,
                         };
                         (Some(last), new_user_list)
                     }
@@ -484,28 +1512,39 @@ impl<S> NodeCtxt<S> {
                         UserIdList {
                             first: new_in_id,
                             last: new_in_id,
+                            len: 1,
                         },
                     ),
                 };
-                self.origin_data(origin).users.set(Some(new_user_list));
-                new_node_inputs.push(UserData {
-                    origin: Cell::new(Some(origin)),
-                    sink: None,
-                    prev_user: Cell::new(prev_user),
-                    next_user: Cell::default(),
-                });
+                self.origin_data(origin).set_users(Some(new_user_list));
+                self.input_ports.push(
+                    &mut new_node_inputs,
+                    UserData {
+                        kind: port_kind,
+                        ty: None,
+                        origin: Cell::new(Some(origin)),
+                        sink: None,
+                        prev_user: Cell::new(prev_user),
+                        next_user: Cell::default(),
+                    },
+                );
             }
 
             let sig = kind.sig();
 
-            self.nodes.borrow_mut().push(NodeData {
+            self.nodes.push(RefCell::new(NodeData {
                 ins: new_node_inputs,
-                outs: vec![OriginData::default(); kind.sig().num_output_ports()],
+                outs: self
+                    .output_ports
+                    .alloc(origin_ports(sig.val_outs, sig.st_outs)),
                 inner_regions: Cell::default(),
-                // FIXME replace with an argument from mk_node_with.
                 outer_region: region_id,
                 kind,
-            });
+                removed: false,
+            }));
+            self.type_op_ports(node_id);
+            self.region_data_mut(region_id).nodes.push(node_id);
+            self.record(snapshot::Mutation::Created(node_id));
 
             assert_eq!(self.node_data(node_id).ins.len(), sig.num_input_ports());
             assert_eq!(self.node_data(node_id).outs.len(), sig.num_output_ports());
@@ -519,13 +1558,19 @@ impl<S> NodeCtxt<S> {
             origins: origins.into(),
         };
 
-        if self.config.opt_interning && !kind.sig().is_side_effectful() {
+        if self.interns(&kind) {
             let node_hash = self.compute_node_hash(&node_term);
             let mut interned_nodes = self.interned_nodes.borrow_mut();
             let entry = interned_nodes
                 .raw_entry_mut()
                 .from_key_hashed_nocheck(node_hash, &node_term);
+            let kind_key = match &kind {
+                NodeKind::Op(op) => Some(mem::discriminant(op)),
+                _ => None,
+            };
 
+            let found = matches!(entry, RawEntryMut::Occupied(..));
+            self.count_interning(kind_key, &kind, found);
             match entry {
                 RawEntryMut::Occupied(e) => *e.get(),
                 RawEntryMut::Vacant(e) => {
@@ -540,842 +1585,3602 @@ impl<S> NodeCtxt<S> {
     }
 
     fn mk_region_for_node(&self, node_id: NodeId, region_sig: RegionSigS) -> RegionId {
-        unimplemented!()
+        let region_id = RegionId::new(self.regions.len());
+        let node_data = self.node_data(node_id);
+        self.assert_not_frozen(node_data.outer_region);
+
+        let mut region_data = match node_data.inner_regions.get() {
+            Some(InnerRegionList {
+                first_region,
+                last_region,
+            }) => {
+                let last_region_data = self.region_data(last_region);
+                last_region_data.next_region.set(Some(region_id));
+                node_data.inner_regions.set(Some(InnerRegionList {
+                    first_region,
+                    last_region: region_id,
+                }));
+                let region_data =
+                    RegionData::new(Some(node_id), last_region_data.sequence_index + 1);
+                region_data.prev_region.set(Some(last_region));
+                region_data
+            }
+            None => {
+                node_data.inner_regions.set(Some(InnerRegionList {
+                    first_region: region_id,
+                    last_region: region_id,
+                }));
+                RegionData::new(Some(node_id), 0)
+            }
+        };
+
+        region_data.args = origin_ports(region_sig.val_args, region_sig.st_args).collect();
+        region_data.res = user_ports(region_sig.val_res, region_sig.st_res).collect();
+
+        self.regions.push(RefCell::new(region_data));
+        region_id
     }
 
-    pub(crate) fn mk_node(&self, op: S) -> Node<S>
-    where
-        S: Sig + Eq + Hash + Clone,
-    {
-        let node_id = self.mk_node_with(NodeKind::Op(op), &[]);
-        Node {
-            ctxt: self,
-            id: node_id,
+    /// The region in which the port identified by `origin_id` can be used.
+    pub(crate) fn origin_region(&self, origin_id: OriginId) -> RegionId {
+        match origin_id {
+            OriginId::Out { node, .. } => self.node_data(node).outer_region,
+            OriginId::Arg { region, .. } => region,
         }
     }
 
-    pub(crate) fn node_builder(&self, op: S) -> NodeBuilder<S>
-    where
-        S: Sig,
-    {
-        NodeBuilder::new(self, NodeKind::Op(op))
+    fn add_input(&self, node_id: NodeId, origin_id: OriginId) -> UserId {
+        let kind = self.origin_data(origin_id).kind;
+        let user_id = self.add_unconnected_input(node_id, kind);
+        self.connect_ports(user_id, origin_id);
+        user_id
     }
 
-    pub(crate) fn node_ref(&self, node_id: NodeId) -> Node<S> {
-        assert!(node_id.0 < self.nodes.borrow().len());
-        Node {
-            ctxt: self,
-            id: node_id,
-        }
+    fn add_unconnected_input(&self, node_id: NodeId, kind: PortKind) -> UserId {
+        self.assert_not_frozen(self.node_data(node_id).outer_region);
+        let ins = &mut self.node_data_mut(node_id).ins;
+        self.input_ports.push(
+            ins,
+            UserData {
+                kind,
+                ..UserData::default()
+            },
+        );
+        UserId::input(node_id, ins.len() - 1)
     }
 
-    pub(crate) fn user_ref<'g>(&'g self, user_id: UserId) -> User<'g, S> {
-        match user_id {
-            UserId::In { node, index } => assert!(index < self.node_data(node).ins.len()),
-            UserId::Res { region, index } => assert!(index < self.region_data(region).res.len()),
-        }
-
-        User {
-            ctxt: self,
-            user_id,
-        }
+    fn add_output(&self, node_id: NodeId, kind: PortKind) -> OriginId {
+        self.assert_not_frozen(self.node_data(node_id).outer_region);
+        let outs = &mut self.node_data_mut(node_id).outs;
+        self.output_ports.push(
+            outs,
+            OriginData {
+                kind,
+                ..OriginData::default()
+            },
+        );
+        OriginId::output(node_id, outs.len() - 1)
     }
 
-    pub(crate) fn origin_ref<'g>(&'g self, origin_id: OriginId) -> Origin<'g, S> {
-        match origin_id {
-            OriginId::Out { node, index } => assert!(index < self.node_data(node).outs.len()),
-            OriginId::Arg { region, index } => assert!(index < self.region_data(region).args.len()),
-        }
+    fn add_argument(
+        &self,
+        region_id: RegionId,
+        kind: PortKind,
+        source: Option<UserId>,
+    ) -> OriginId {
+        self.assert_not_frozen(region_id);
+        // Arguments carry the type of what's passed in through them.
+        let ty = source
+            .and_then(|user_id| self.user_data(user_id).origin.get())
+            .and_then(|origin_id| self.origin_type(origin_id));
+        let args = &mut self.region_data_mut(region_id).args;
+        args.push(OriginData {
+            kind,
+            ty,
+            source,
+            ..OriginData::default()
+        });
+        OriginId::argument(region_id, args.len() - 1)
+    }
 
-        Origin {
-            ctxt: self,
-            origin_id,
-        }
+    fn add_result(&self, region_id: RegionId, kind: PortKind, sink: Option<OriginId>) -> UserId {
+        self.assert_not_frozen(region_id);
+        let ty = sink.and_then(|origin_id| self.origin_type(origin_id));
+        let res = &mut self.region_data_mut(region_id).res;
+        res.push(UserData {
+            kind,
+            ty,
+            sink,
+            ..UserData::default()
+        });
+        UserId::result(region_id, res.len() - 1)
     }
-}
 
-impl<S> PartialEq for NodeCtxt<S> {
-    fn eq(&self, other: &NodeCtxt<S>) -> bool {
-        ptr::eq(self, other)
+    /// Adds an entry variable to a gamma, returning its argument in each
+    /// branch.
+    fn add_gamma_entry(&self, gamma_id: NodeId, origin_id: OriginId) -> Vec<OriginId> {
+        assert_eq!(
+            self.origin_region(origin_id),
+            self.node_data(gamma_id).outer_region
+        );
+        let kind = self.origin_data(origin_id).kind;
+        let input = self.add_input(gamma_id, origin_id);
+        self.count_ports(gamma_id, kind, 1, 0);
+        self.inner_regions(gamma_id)
+            .into_iter()
+            .map(|branch| self.add_argument(branch, kind, Some(input)))
+            .collect()
     }
-}
 
-impl<S> Eq for NodeCtxt<S> {}
+    /// Adds a loop variable to a theta, returning its argument in the body
+    /// and its output. Its result is left unconnected.
+    fn add_theta_loop_var(&self, theta_id: NodeId, init: OriginId) -> (OriginId, OriginId) {
+        assert_eq!(
+            self.origin_region(init),
+            self.node_data(theta_id).outer_region
+        );
+        let body = self.inner_regions(theta_id)[0];
+        let kind = self.origin_data(init).kind;
+        let input = self.add_input(theta_id, init);
+        let output = self.add_output(theta_id, kind);
+        self.set_origin_type(output, self.origin_type(init));
+        let arg = self.add_argument(body, kind, Some(input));
+        self.add_result(body, kind, Some(output));
+        self.count_ports(theta_id, kind, 1, 1);
+        (arg, output)
+    }
 
-pub(crate) struct NodeBuilder<'g, S> {
-    ctxt: &'g NodeCtxt<S>,
-    node_kind: NodeKind<S>,
-    val_origins: Vec<ValOrigin<'g, S>>,
-    st_origins: Vec<StOrigin<'g, S>>,
-}
+    /// Adds a context variable to a lambda, returning its argument in the
+    /// body.
+    fn add_lambda_ctx_var(&self, lambda_id: NodeId, origin_id: OriginId) -> OriginId {
+        assert_eq!(
+            self.origin_region(origin_id),
+            self.node_data(lambda_id).outer_region
+        );
+        let body = self.inner_regions(lambda_id)[0];
+        let input = self.add_input(lambda_id, origin_id);
+        self.count_ports(lambda_id, PortKind::Val, 1, 0);
+        self.add_argument(body, PortKind::Val, Some(input))
+    }
 
-impl<'g, S: Sig> NodeBuilder<'g, S> {
-    pub(crate) fn new(ctxt: &'g NodeCtxt<S>, node_kind: NodeKind<S>) -> NodeBuilder<'g, S> {
-        let sig = node_kind.sig();
-        NodeBuilder {
-            ctxt,
-            node_kind,
-            val_origins: Vec::with_capacity(sig.val_ins),
-            st_origins: Vec::with_capacity(sig.st_ins),
-        }
+    /// The nodes in a region, in the order they were made.
+    fn region_nodes(&self, region_id: RegionId) -> Vec<NodeId> {
+        self.region_data(region_id).nodes.clone()
     }
 
-    pub(crate) fn operand(mut self, val_origin: ValOrigin<'g, S>) -> NodeBuilder<'g, S> {
-        assert!(self.val_origins.len() < self.node_kind.sig().val_ins);
-        self.val_origins.push(val_origin);
-        self
+    /// The regions of a node, in order.
+    pub(crate) fn inner_regions(&self, node_id: NodeId) -> Vec<RegionId> {
+        let mut inner_regions = vec![];
+        let mut next_region = self
+            .node_data(node_id)
+            .inner_regions
+            .get()
+            .map(|regions| regions.first_region);
+        while let Some(region_id) = next_region {
+            inner_regions.push(region_id);
+            next_region = self.region_data(region_id).next_region.get();
+        }
+        inner_regions
     }
 
-    pub(crate) fn operands(mut self, val_origins: &[ValOrigin<'g, S>]) -> NodeBuilder<'g, S>
+    /// Removes entry variable `entry` of a gamma, whose arguments must have
+    /// no users left in any branch.
+    pub fn remove_gamma_entry(&self, gamma_id: NodeId, entry: usize)
     where
-        S: Clone,
+        S: Sig + Eq + Hash + Clone,
     {
-        assert!(self.val_origins.is_empty());
-        assert_eq!(self.node_kind.sig().val_ins, val_origins.len());
-        self.val_origins.extend(val_origins.iter().cloned());
-        self
+        for branch in self.inner_regions(gamma_id) {
+            self.remove_argument(branch, entry);
+        }
+        // Skips the predicate.
+        self.remove_input(gamma_id, entry + 1);
+        self.recount_ports(gamma_id);
     }
 
-    pub(crate) fn state(mut self, st_origin: StOrigin<'g, S>) -> NodeBuilder<'g, S> {
-        assert!(self.st_origins.len() < self.node_kind.sig().st_ins);
-        self.st_origins.push(st_origin);
-        self
+    /// Removes an input of a structural node, shifting the inputs after it
+    /// down by one.
+    fn remove_input(&self, node_id: NodeId, index: usize) {
+        let user_id = UserId::input(node_id, index);
+        self.unlink_user(user_id);
+
+        let num_inputs = self.node_data(node_id).ins.len();
+        let shifted_origins: Vec<Option<OriginId>> = (index + 1..num_inputs)
+            .map(|index| {
+                let user_id = UserId::input(node_id, index);
+                let origin_id = self.user_data(user_id).origin.get();
+                self.unlink_user(user_id);
+                origin_id
+            })
+            .collect();
+
+        self.input_ports
+            .remove(&mut self.node_data_mut(node_id).ins, index);
+        for (offset, origin_id) in shifted_origins.into_iter().enumerate() {
+            if let Some(origin_id) = origin_id {
+                let user_id = UserId::input(node_id, index + offset);
+                self.connect_ports(user_id, origin_id);
+            }
+        }
+
+        // Arguments refer back to the inputs they're passed in through.
+        for region_id in self.inner_regions(node_id) {
+            for arg in &mut self.region_data_mut(region_id).args {
+                if let Some(UserId::In {
+                    node,
+                    index: source,
+                }) = arg.source
+                {
+                    if usize::from(source) > index {
+                        arg.source = Some(UserId::In {
+                            node,
+                            index: source - 1,
+                        });
+                    }
+                }
+            }
+        }
     }
 
-    pub(crate) fn states(mut self, st_origins: &[StOrigin<'g, S>]) -> NodeBuilder<'g, S>
+    /// Removes an argument without users, shifting the arguments after it
+    /// down by one.
+    fn remove_argument(&self, region_id: RegionId, index: usize)
     where
-        S: Clone,
+        S: Sig + Eq + Hash + Clone,
     {
-        assert!(self.st_origins.is_empty());
-        assert_eq!(self.node_kind.sig().st_ins, st_origins.len());
-        self.st_origins.extend(st_origins.iter().cloned());
-        self
+        self.assert_not_frozen(region_id);
+        let num_args = self.region_data(region_id).args.len();
+        self.remove_origin(
+            |index| OriginId::argument(region_id, index),
+            index,
+            num_args,
+            || {
+                self.region_data_mut(region_id).args.remove(index);
+            },
+        );
     }
 
-    pub(crate) fn finish(self) -> Node<'g, S>
+    /// Removes an output of a structural node without users, shifting the
+    /// outputs after it down by one.
+    fn remove_output(&self, node_id: NodeId, index: usize)
     where
-        S: Eq + Hash + Clone,
+        S: Sig + Eq + Hash + Clone,
     {
-        let sig = self.node_kind.sig();
-        assert_eq!(self.val_origins.len(), sig.val_ins);
-        assert_eq!(self.st_origins.len(), sig.st_ins);
-
-        let origins: Vec<OriginId> = {
-            let val_origins = self.val_origins.iter().map(|val_origin| val_origin.0.id());
-            let st_origins = self.st_origins.iter().map(|st_origin| st_origin.0.id());
-            val_origins.chain(st_origins).collect()
-        };
+        self.assert_not_frozen(self.node_data(node_id).outer_region);
+        let num_outs = self.node_data(node_id).outs.len();
+        self.remove_origin(
+            |index| OriginId::output(node_id, index),
+            index,
+            num_outs,
+            || {
+                self.output_ports
+                    .remove(&mut self.node_data_mut(node_id).outs, index);
+            },
+        );
 
-        assert_eq!(origins.len(), sig.val_ins + sig.st_ins);
+        // Results refer to the outputs they're passed out through.
+        for region_id in self.inner_regions(node_id) {
+            for res in &mut self.region_data_mut(region_id).res {
+                if let Some(OriginId::Out { node, index: sink }) = res.sink {
+                    if usize::from(sink) > index {
+                        res.sink = Some(OriginId::Out {
+                            node,
+                            index: sink - 1,
+                        });
+                    }
+                }
+            }
+        }
+    }
 
-        let node_id = self.ctxt.mk_node_with(self.node_kind, &origins);
+    /// Removes the origin at `index` of a list of origins, identified by
+    /// `origin_at`, by calling `remove`, and moves the users and names of
+    /// the origins after it over to their shifted ids.
+    fn remove_origin<F, R>(&self, origin_at: F, index: usize, num_origins: usize, remove: R)
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: Fn(usize) -> OriginId,
+        R: FnOnce(),
+    {
+        let origin_id = origin_at(index);
+        assert!(
+            self.origin_data(origin_id).users.get().is_none(),
+            "{:?} still has users",
+            origin_id
+        );
 
-        Node {
-            ctxt: self.ctxt,
-            id: node_id,
+        let shifted_users: Vec<Vec<UserId>> = (index + 1..num_origins)
+            .map(|index| {
+                self.origin_ref(origin_at(index))
+                    .users()
+                    .map(|user| user.id())
+                    .collect()
+            })
+            .collect();
+
+        // The terms of the nodes using the shifted origins change with them.
+        let mut user_nodes: Vec<NodeId> = vec![];
+        for node_id in shifted_users.iter().flatten().filter_map(UserId::node_id) {
+            if !user_nodes.contains(&node_id) {
+                user_nodes.push(node_id);
+            }
+        }
+        for &node_id in &user_nodes {
+            self.forget_interned(node_id);
         }
-    }
-}
 
-#[derive(Clone, Copy, PartialEq)]
-pub(crate) struct Node<'g, S> {
-    ctxt: &'g NodeCtxt<S>,
-    id: NodeId,
-}
+        remove();
+        let mut origin_names = self.origin_names.borrow_mut();
+        origin_names.remove(&origin_id);
+        for (offset, users) in shifted_users.into_iter().enumerate() {
+            let old_origin_id = origin_at(index + offset + 1);
+            let new_origin_id = origin_at(index + offset);
+            for user_id in users {
+                self.user_data(user_id).origin.set(Some(new_origin_id));
+            }
+            if let Some(name) = origin_names.remove(&old_origin_id) {
+                origin_names.insert(new_origin_id, name);
+            }
+        }
+        drop(origin_names);
 
-impl<'g, S: fmt::Debug> fmt::Debug for Node<'g, S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.data().kind)
+        for node_id in user_nodes {
+            if self.node_term(node_id).is_some() {
+                self.intern_node(node_id);
+            }
+        }
     }
-}
 
-impl<'g, S> Node<'g, S> {
-    pub(crate) fn id(&self) -> NodeId {
-        self.id
+    /// Removes a result, shifting the results after it down by one.
+    fn remove_result(&self, region_id: RegionId, index: usize) {
+        let user_id = UserId::result(region_id, index);
+        self.unlink_user(user_id);
+
+        let num_res = self.region_data(region_id).res.len();
+        let shifted_origins: Vec<Option<OriginId>> = (index + 1..num_res)
+            .map(|index| {
+                let user_id = UserId::result(region_id, index);
+                let origin_id = self.user_data(user_id).origin.get();
+                self.unlink_user(user_id);
+                origin_id
+            })
+            .collect();
+
+        self.region_data_mut(region_id).res.remove(index);
+        for (offset, origin_id) in shifted_origins.into_iter().enumerate() {
+            if let Some(origin_id) = origin_id {
+                let user_id = UserId::result(region_id, index + offset);
+                self.connect_ports(user_id, origin_id);
+            }
+        }
     }
 
-    pub(crate) fn data(&self) -> Ref<'g, NodeData<S>> {
-        self.ctxt.node_data(self.id)
+    /// Removes exit variable `exit` of a gamma, whose output must have no
+    /// users left.
+    pub fn remove_gamma_exit(&self, gamma_id: NodeId, exit: usize)
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        for branch in self.inner_regions(gamma_id) {
+            self.remove_result(branch, exit);
+        }
+        self.remove_output(gamma_id, exit);
+        self.recount_ports(gamma_id);
     }
 
-    pub(crate) fn kind(&self) -> Ref<'g, NodeKind<S>> {
-        Ref::map(self.ctxt.node_data(self.id), |node_data| &node_data.kind)
+    /// Removes loop variable `index` of a theta, whose output must have no
+    /// users left, and whose argument no users but its own result.
+    pub fn remove_theta_loop_var(&self, theta_id: NodeId, index: usize)
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let body = self.inner_regions(theta_id)[0];
+        // Skips the predicate.
+        self.remove_result(body, index + 1);
+        self.remove_argument(body, index);
+        self.remove_input(theta_id, index);
+        self.remove_output(theta_id, index);
+        self.recount_ports(theta_id);
     }
-}
 
-impl<'g, S: Sig> Node<'g, S> {
-    pub(crate) fn val_in(&self, port: usize) -> ValUser<'g, S> {
-        let sig = self.data().sig();
-        assert!(port < sig.val_ins);
-        ValUser(self.ctxt.user_ref(UserId::In {
-            node: self.id,
-            index: port,
-        }))
+    /// Sets the port counts in the kind of a structural node to the ports it
+    /// has.
+    fn recount_ports(&self, node_id: NodeId) {
+        let node_data = self.node_data(node_id);
+        let in_kinds: Vec<PortKind> = node_data.ins.iter().map(|user| user.kind).collect();
+        let out_kinds: Vec<PortKind> = node_data.outs.iter().map(|origin| origin.kind).collect();
+        drop(node_data);
+        let mut node_data = self.node_data_mut(node_id);
+        let count =
+            |kinds: &[PortKind], kind| kinds.iter().filter(|&&port_kind| port_kind == kind).count();
+        let val_ins = count(&in_kinds, PortKind::Val);
+        let st_ins = count(&in_kinds, PortKind::St);
+        let val_outs = count(&out_kinds, PortKind::Val);
+        let st_outs = count(&out_kinds, PortKind::St);
+        node_data.kind = match node_data.kind {
+            NodeKind::Gamma { .. } => NodeKind::Gamma {
+                // Skips the predicate.
+                val_ins: val_ins - 1,
+                val_outs,
+                st_ins,
+                st_outs,
+            },
+            NodeKind::Theta { .. } => NodeKind::Theta {
+                val_loop_vars: val_ins,
+                st_loop_vars: st_ins,
+            },
+            NodeKind::Lambda { params, .. } => NodeKind::Lambda {
+                params,
+                ctx_vars: val_ins,
+            },
+            _ => unreachable!(),
+        };
     }
 
-    pub(crate) fn val_out(&self, port: usize) -> ValOrigin<'g, S> {
-        let sig = self.data().sig();
-        assert!(port < sig.val_outs);
-        ValOrigin(self.ctxt.origin_ref(OriginId::Out {
-            node: self.id,
-            index: port,
-        }))
+    fn count_ports(&self, node_id: NodeId, kind: PortKind, ins: usize, outs: usize) {
+        match &mut self.node_data_mut(node_id).kind {
+            NodeKind::Gamma {
+                val_ins,
+                val_outs,
+                st_ins,
+                st_outs,
+            } => match kind {
+                PortKind::Val => {
+                    *val_ins += ins;
+                    *val_outs += outs;
+                }
+                PortKind::St => {
+                    *st_ins += ins;
+                    *st_outs += outs;
+                }
+            },
+            NodeKind::Theta {
+                val_loop_vars,
+                st_loop_vars,
+            } => match kind {
+                PortKind::Val => *val_loop_vars += ins,
+                PortKind::St => *st_loop_vars += ins,
+            },
+            NodeKind::Lambda { ctx_vars, .. } => {
+                assert_eq!(kind, PortKind::Val, "context variables must be values");
+                *ctx_vars += ins;
+            }
+            _ => unreachable!(),
+        }
     }
 
-    pub(crate) fn st_in(&self, port: usize) -> StUser<'g, S> {
-        let sig = self.data().sig();
-        assert!(port < sig.st_ins);
-        StUser(self.ctxt.user_ref(UserId::In {
-            node: self.id,
-            index: sig.val_ins + port,
-        }))
+    /// Starts building a gamma node with `num_branches` regions, whose branch
+    /// is selected by `predicate`.
+    pub fn gamma_builder(
+        &self,
+        predicate: ValOrigin<'_, S>,
+        num_branches: usize,
+    ) -> GammaBuilder<'_, S>
+    where
+        S: Sig,
+    {
+        let region_id = self.origin_region(predicate.id());
+        let gamma = self.create_node(
+            NodeKind::Gamma {
+                val_ins: 0,
+                val_outs: 0,
+                st_ins: 0,
+                st_outs: 0,
+            },
+            region_id,
+        );
+        self.connect_ports(gamma.val_in(0).id(), predicate.id());
+        let branches = (0..num_branches)
+            .map(|_| self.mk_region_for_node(gamma.id, RegionSigS::default()))
+            .collect();
+        GammaBuilder {
+            ctxt: self,
+            node: gamma.id,
+            branches,
+        }
     }
 
-    pub(crate) fn st_out(&self, port: usize) -> StOrigin<'g, S> {
-        let sig = self.data().sig();
-        assert!(port < sig.st_outs);
-        StOrigin(self.ctxt.origin_ref(OriginId::Out {
-            node: self.id,
-            index: sig.val_outs + port,
-        }))
+    /// Starts building a theta node in the given region.
+    pub fn theta_builder(&self, region_id: RegionId) -> ThetaBuilder<'_, S>
+    where
+        S: Sig,
+    {
+        let theta = self.create_node(
+            NodeKind::Theta {
+                val_loop_vars: 0,
+                st_loop_vars: 0,
+            },
+            region_id,
+        );
+        // The first result of a theta body is the loop predicate.
+        let body = self.mk_region_for_node(
+            theta.id,
+            RegionSigS {
+                val_res: 1,
+                ..RegionSigS::default()
+            },
+        );
+        ThetaBuilder {
+            ctxt: self,
+            node: theta.id,
+            body,
+        }
     }
-}
-
-#[derive(Copy, Clone, PartialEq, Eq)]
-pub(crate) struct User<'g, S> {
-    ctxt: &'g NodeCtxt<S>,
-    user_id: UserId,
-}
 
-impl<'g, S: fmt::Debug> fmt::Debug for User<'g, S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.user_id)
+    /// Starts building a lambda node in the given region, whose body takes
+    /// parameters of the given kinds.
+    pub fn lambda_builder(&self, region_id: RegionId, params: &[PortKind]) -> LambdaBuilder<'_, S>
+    where
+        S: Sig,
+    {
+        let lambda = self.create_node(
+            NodeKind::Lambda {
+                params: params.len(),
+                ctx_vars: 0,
+            },
+            region_id,
+        );
+        let body = self.mk_region_for_node(lambda.id, RegionSigS::default());
+        for &kind in params {
+            self.add_argument(body, kind, None);
+        }
+        LambdaBuilder {
+            ctxt: self,
+            node: lambda.id,
+            body,
+        }
     }
-}
 
-impl<'g, S> User<'g, S> {
-    pub(crate) fn id(&self) -> UserId {
-        self.user_id
+    /// Starts building an apply node calling `function`, whose parameters
+    /// and results are described by `sig`. The function is its first input,
+    /// followed by the arguments.
+    pub fn apply_builder<'g>(&'g self, function: ValOrigin<'g, S>, sig: SigS) -> NodeBuilder<'g, S>
+    where
+        S: Sig,
+    {
+        let kind = NodeKind::Apply {
+            arg_val_ins: sig.val_ins,
+            arg_st_ins: sig.st_ins,
+            region_val_res: sig.val_outs,
+            region_st_res: sig.st_outs,
+        };
+        NodeBuilder::new(self, kind).operand(function)
     }
 
-    pub(crate) fn data(&self) -> Ref<'g, UserData> {
-        self.ctxt.user_data(self.user_id)
+    pub fn mk_node(&self, op: S) -> Node<S>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let node_id = self.mk_node_with(NodeKind::Op(op), &[]);
+        Node {
+            ctxt: self,
+            id: node_id,
+        }
     }
 
-    pub(crate) fn origin(&self) -> Origin<'g, S> {
-        let origin_id = self.data().origin.get().unwrap();
-        self.ctxt.origin_ref(origin_id)
+    /// Makes a node without inputs in `region_id`.
+    pub fn mk_node_in(&self, region_id: RegionId, op: S) -> Node<'_, S>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let node_id = self.mk_node_in_region_with(region_id, NodeKind::Op(op), &[]);
+        self.node_ref(node_id)
     }
-}
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
-pub(crate) struct Origin<'g, S> {
-    ctxt: &'g NodeCtxt<S>,
-    origin_id: OriginId,
-}
+    /// Starts building a node in the region of its first operand, or in the
+    /// root region if it has none.
+    pub fn node_builder(&self, op: S) -> NodeBuilder<S>
+    where
+        S: Sig,
+    {
+        NodeBuilder::new(self, NodeKind::Op(op))
+    }
 
-impl<'g, S: fmt::Debug> fmt::Debug for Origin<'g, S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.origin_id)
+    pub fn node_builder_in(&self, region_id: RegionId, op: S) -> NodeBuilder<S>
+    where
+        S: Sig,
+    {
+        NodeBuilder::new(self, NodeKind::Op(op)).in_region(region_id)
     }
-}
 
-impl<'g, S> Origin<'g, S> {
-    pub(crate) fn id(&self) -> OriginId {
-        self.origin_id
+    /// Splices a node between `origin` and its users: `f` builds a node
+    /// consuming `origin`, and every previous user of `origin` is reconnected
+    /// to the origin `f` returns.
+    pub fn interpose<'g, F>(&'g self, origin: ValOrigin<'g, S>, f: F) -> ValOrigin<'g, S>
+    where
+        S: Eq + Hash + Clone,
+        F: FnOnce(ValOrigin<'g, S>) -> ValOrigin<'g, S>,
+    {
+        let origin_id = origin.id();
+        ValOrigin(self.origin_ref(self.interpose_with(origin_id, || f(origin).id())))
     }
 
-    pub(crate) fn data(&self) -> Ref<'g, OriginData> {
-        self.ctxt.origin_data(self.origin_id)
+    /// Splices a node between the state `origin` and its users, like
+    /// `interpose`.
+    pub fn interpose_state<'g, F>(&'g self, origin: StOrigin<'g, S>, f: F) -> StOrigin<'g, S>
+    where
+        S: Eq + Hash + Clone,
+        F: FnOnce(StOrigin<'g, S>) -> StOrigin<'g, S>,
+    {
+        let origin_id = origin.id();
+        StOrigin(self.origin_ref(self.interpose_with(origin_id, || f(origin).id())))
     }
 
-    pub(crate) fn producer(&self) -> Node<'g, S> {
-        match self.origin_id {
-            OriginId::Out { node, .. } => self.ctxt.node_ref(node),
-            _ => unimplemented!(),
+    fn interpose_with<F>(&self, origin_id: OriginId, f: F) -> OriginId
+    where
+        S: Eq + Hash + Clone,
+        F: FnOnce() -> OriginId,
+    {
+        let users: Vec<UserId> = self
+            .origin_ref(origin_id)
+            .users()
+            .map(|user| user.id())
+            .collect();
+        let new_origin_id = f();
+
+        assert_ne!(origin_id, new_origin_id);
+        assert_eq!(
+            self.origin_region(origin_id),
+            self.origin_region(new_origin_id)
+        );
+        // An interned node may turn out to be one of the users, which would
+        // then be made to use itself.
+        if let Some(new_node_id) = new_origin_id.node_id() {
+            assert!(
+                users
+                    .iter()
+                    .all(|user_id| user_id.node_id() != Some(new_node_id)),
+                "interposed node {:?} is already a user of {:?}",
+                new_node_id,
+                origin_id
+            );
         }
-    }
 
-    pub(crate) fn users(&self) -> Users<'g, S> {
-        let user_ref = |user_id| self.ctxt.user_ref(user_id);
-        Users {
-            first_and_last: self
-                .data()
-                .users
-                .get()
-                .map(|users| (user_ref(users.first), user_ref(users.last))),
+        for user_id in users {
+            self.reconnect(user_id, new_origin_id);
         }
+        new_origin_id
     }
-}
-
-pub(crate) struct Users<'g, S> {
-    first_and_last: Option<(User<'g, S>, User<'g, S>)>,
-}
 
-impl<'g, S> Iterator for Users<'g, S> {
-    type Item = User<'g, S>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.first_and_last.take() {
-            Some((first, last)) => {
-                if first.id() != last.id() {
-                    if let Some(next_user) = first.data().next_user.get() {
-                        self.first_and_last = Some((first.ctxt.user_ref(next_user), last));
-                    }
-                }
-                Some(first)
-            }
-            None => None,
+    pub fn node_ref(&self, node_id: NodeId) -> Node<S> {
+        assert!(node_id.index() < self.nodes.len());
+        assert!(!self.node_data(node_id).removed);
+        Node {
+            ctxt: self,
+            id: node_id,
         }
     }
-}
 
-impl<'g, S> DoubleEndedIterator for Users<'g, S> {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        match self.first_and_last.take() {
-            Some((first, last)) => {
-                if first.id() != last.id() {
-                    if let Some(prev_user) = last.data().prev_user.get() {
-                        self.first_and_last = Some((first, last.ctxt.user_ref(prev_user)));
-                    }
-                }
-                Some(last)
-            }
-            None => None,
+    pub fn region_ref(&self, region_id: RegionId) -> Region<S> {
+        assert!(region_id.index() < self.regions.len());
+        assert!(!self.region_data(region_id).removed);
+        Region {
+            ctxt: self,
+            id: region_id,
         }
     }
-}
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub(crate) struct ValUser<'g, S>(User<'g, S>);
+    pub(crate) fn user_ref<'g>(&'g self, user_id: UserId) -> User<'g, S> {
+        let index = user_id.index();
+        match user_id {
+            UserId::In { node, .. } => assert!(index < self.node_data(node).ins.len()),
+            UserId::Res { region, .. } => assert!(index < self.region_data(region).res.len()),
+        }
 
-impl<'g, S> ValUser<'g, S> {
-    fn id(&self) -> UserId {
-        self.0.id()
+        User {
+            ctxt: self,
+            user_id,
+        }
     }
 
-    fn connect(&self, val_origin: ValOrigin<'g, S>) {
-        assert!(self.0.ctxt == val_origin.0.ctxt);
-        self.0.ctxt.connect_ports(self.id(), val_origin.id());
+    pub fn origin_ref<'g>(&'g self, origin_id: OriginId) -> Origin<'g, S> {
+        let index = origin_id.index();
+        match origin_id {
+            OriginId::Out { node, .. } => assert!(index < self.node_data(node).outs.len()),
+            OriginId::Arg { region, .. } => assert!(index < self.region_data(region).args.len()),
+        }
+
+        Origin {
+            ctxt: self,
+            origin_id,
+        }
     }
+}
 
-    pub(crate) fn origin(&self) -> ValOrigin<'g, S> {
-        ValOrigin(self.0.origin())
+impl<S> PartialEq for NodeCtxt<S> {
+    fn eq(&self, other: &NodeCtxt<S>) -> bool {
+        ptr::eq(self, other)
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-pub(crate) struct StUser<'g, S>(User<'g, S>);
+impl<S> Eq for NodeCtxt<S> {}
 
-impl<'g, S> StUser<'g, S> {
-    fn id(&self) -> UserId {
-        self.0.id()
-    }
+pub struct NodeBuilder<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    /// The region to make the node in, unless it's left to the operands.
+    region: Option<RegionId>,
+    node_kind: NodeKind<S>,
+    val_origins: Vec<Option<ValOrigin<'g, S>>>,
+    st_origins: Vec<Option<StOrigin<'g, S>>>,
+    extra_val_ins: Vec<usize>,
+    extra_st_ins: Vec<usize>,
+    span: Option<Span>,
+}
 
-    fn connect(&self, st_origin: StOrigin<'g, S>) {
-        assert!(self.0.ctxt == st_origin.0.ctxt);
-        self.0.ctxt.connect_ports(self.id(), st_origin.id());
-    }
+/// The ports a `NodeBuilder` was left with that don't match the signature of
+/// its node.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ArityError {
+    pub missing_val_ins: Vec<usize>,
+    pub missing_st_ins: Vec<usize>,
+    pub extra_val_ins: Vec<usize>,
+    pub extra_st_ins: Vec<usize>,
+}
 
-    pub(crate) fn origin(&self) -> StOrigin<'g, S> {
-        StOrigin(self.0.origin())
+impl fmt::Display for ArityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let port_lists = [
+            ("missing value inputs", &self.missing_val_ins),
+            ("missing state inputs", &self.missing_st_ins),
+            ("extra value inputs", &self.extra_val_ins),
+            ("extra state inputs", &self.extra_st_ins),
+        ];
+        let mut separator = "";
+        for (what, ports) in port_lists.iter().filter(|(_, ports)| !ports.is_empty()) {
+            write!(f, "{}{} {:?}", separator, what, ports)?;
+            separator = ", ";
+        }
+        Ok(())
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-pub(crate) struct ValOrigin<'g, S>(Origin<'g, S>);
+/// Why a `NodeBuilder` couldn't make its node.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BuildError {
+    /// An operand was given while every value input was already taken.
+    ExtraOperand { port: usize },
+    /// A state was given while every state input was already taken.
+    ExtraState { port: usize },
+    /// An origin given for an input is in another region than the node.
+    OriginInOtherRegion {
+        kind: PortKind,
+        port: usize,
+        region: RegionId,
+    },
+    /// The inputs given don't match the signature of the node.
+    Arity(ArityError),
+    /// An operand has another type than its input takes.
+    TypeMismatch {
+        port: usize,
+        expected: String,
+        found: String,
+    },
+}
 
-impl<'g, S> ValOrigin<'g, S> {
-    fn id(&self) -> OriginId {
-        self.0.id()
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::ExtraOperand { port } => write!(f, "extra value input {}", port),
+            BuildError::ExtraState { port } => write!(f, "extra state input {}", port),
+            BuildError::OriginInOtherRegion { kind, port, region } => write!(
+                f,
+                "{:?} input {} is connected to an origin in {:?}",
+                kind, port, region
+            ),
+            BuildError::Arity(err) => write!(f, "{}", err),
+            BuildError::TypeMismatch {
+                port,
+                expected,
+                found,
+            } => write!(
+                f,
+                "value input {} takes {}, but is given {}",
+                port, expected, found
+            ),
+        }
     }
+}
 
-    fn connect(&self, val_user: ValUser<'g, S>) {
-        assert!(self.0.ctxt == val_user.0.ctxt);
-        self.0.ctxt.connect_ports(val_user.id(), self.id());
-    }
+/// Why an input or result couldn't be connected to an origin.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ConnectError {
+    /// The user is connected to an origin already.
+    AlreadyConnected,
+    /// The user and the origin are in different regions.
+    RegionMismatch {
+        user_region: RegionId,
+        origin_region: RegionId,
+    },
+    /// The origin has another type than the user takes.
+    TypeMismatch { expected: String, found: String },
+}
 
-    pub(crate) fn users(&self) -> impl DoubleEndedIterator<Item = ValUser<'g, S>> {
-        self.0.users().map(ValUser)
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::AlreadyConnected => write!(f, "already connected"),
+            ConnectError::RegionMismatch {
+                user_region,
+                origin_region,
+            } => write!(
+                f,
+                "the user is in {:?}, but the origin is in {:?}",
+                user_region, origin_region
+            ),
+            ConnectError::TypeMismatch { expected, found } => {
+                write!(f, "takes {}, but is given {}", expected, found)
+            }
+        }
     }
+}
 
-    pub(crate) fn producer(&self) -> Node<'g, S> {
-        self.0.producer()
+impl ArityError {
+    fn is_empty(&self) -> bool {
+        self.missing_val_ins.is_empty()
+            && self.missing_st_ins.is_empty()
+            && self.extra_val_ins.is_empty()
+            && self.extra_st_ins.is_empty()
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub(crate) struct StOrigin<'g, S>(Origin<'g, S>);
-
-impl<'g, S> StOrigin<'g, S> {
-    fn id(&self) -> OriginId {
-        self.0.id()
+impl<'g, S: Sig> NodeBuilder<'g, S> {
+    pub(crate) fn new(ctxt: &'g NodeCtxt<S>, node_kind: NodeKind<S>) -> NodeBuilder<'g, S> {
+        let sig = node_kind.sig();
+        NodeBuilder {
+            ctxt,
+            region: None,
+            node_kind,
+            val_origins: (0..sig.val_ins).map(|_| None).collect(),
+            st_origins: (0..sig.st_ins).map(|_| None).collect(),
+            extra_val_ins: vec![],
+            extra_st_ins: vec![],
+            span: None,
+        }
     }
 
-    fn connect(&self, st_user: StUser<'g, S>) {
-        assert!(self.0.ctxt == st_user.0.ctxt);
-        self.0.ctxt.connect_ports(st_user.id(), self.id());
+    /// Makes the node in `region_id`, rather than in the region of its
+    /// first operand.
+    pub fn in_region(mut self, region_id: RegionId) -> NodeBuilder<'g, S> {
+        self.region = Some(region_id);
+        self
     }
 
-    pub(crate) fn users(&self) -> impl DoubleEndedIterator<Item = StUser<'g, S>> {
-        self.0.users().map(StUser)
+    /// The region the node is made in: the one it was given, or else the
+    /// one of its first value or state operand, or else the root region.
+    fn node_region(&self) -> RegionId {
+        if let Some(region_id) = self.region {
+            return region_id;
+        }
+        let val_origins = self
+            .val_origins
+            .iter()
+            .flatten()
+            .map(|origin| origin.0.id());
+        let st_origins = self.st_origins.iter().flatten().map(|origin| origin.0.id());
+        match val_origins.chain(st_origins).next() {
+            Some(origin_id) => self.ctxt.origin_region(origin_id),
+            None => self.ctxt.root_region(),
+        }
     }
 
-    pub(crate) fn producer(&self) -> Node<'g, S> {
+    /// Connects the first value input that isn't connected yet.
+    ///
+    /// Operands past the signature of the node are recorded rather than
+    /// rejected, and reported by `try_finish`.
+    pub fn operand(mut self, val_origin: ValOrigin<'g, S>) -> NodeBuilder<'g, S> {
+        match self.val_origins.iter().position(Option::is_none) {
+            Some(port) => self.val_origins[port] = Some(val_origin),
+            None => {
+                let port = self.val_origins.len() + self.extra_val_ins.len();
+                self.extra_val_ins.push(port);
+            }
+        }
+        self
+    }
+
+    /// Connects the first value input that isn't connected yet, failing if
+    /// there's none left.
+    pub fn try_operand(
+        self,
+        val_origin: ValOrigin<'g, S>,
+    ) -> Result<NodeBuilder<'g, S>, BuildError> {
+        if self.remaining_operands().is_empty() {
+            let port = self.val_origins.len() + self.extra_val_ins.len();
+            return Err(BuildError::ExtraOperand { port });
+        }
+        Ok(self.operand(val_origin))
+    }
+
+    /// Connects the value input `port`, replacing any operand given for it
+    /// before.
+    pub fn operand_at(mut self, port: usize, val_origin: ValOrigin<'g, S>) -> NodeBuilder<'g, S> {
+        match self.val_origins.get_mut(port) {
+            Some(slot) => *slot = Some(val_origin),
+            None => self.extra_val_ins.push(port),
+        }
+        self
+    }
+
+    pub fn operands(mut self, val_origins: &[ValOrigin<'g, S>]) -> NodeBuilder<'g, S>
+    where
+        S: Clone,
+    {
+        for val_origin in val_origins {
+            self = self.operand(val_origin.clone());
+        }
+        self
+    }
+
+    /// Connects the first state input that isn't connected yet.
+    pub fn state(mut self, st_origin: StOrigin<'g, S>) -> NodeBuilder<'g, S> {
+        match self.st_origins.iter().position(Option::is_none) {
+            Some(port) => self.st_origins[port] = Some(st_origin),
+            None => {
+                let port = self.st_origins.len() + self.extra_st_ins.len();
+                self.extra_st_ins.push(port);
+            }
+        }
+        self
+    }
+
+    /// Connects the first state input that isn't connected yet, failing if
+    /// there's none left.
+    pub fn try_state(self, st_origin: StOrigin<'g, S>) -> Result<NodeBuilder<'g, S>, BuildError> {
+        if self.remaining_states().is_empty() {
+            let port = self.st_origins.len() + self.extra_st_ins.len();
+            return Err(BuildError::ExtraState { port });
+        }
+        Ok(self.state(st_origin))
+    }
+
+    /// Connects the state input `port`, replacing any state given for it
+    /// before.
+    pub fn state_at(mut self, port: usize, st_origin: StOrigin<'g, S>) -> NodeBuilder<'g, S> {
+        match self.st_origins.get_mut(port) {
+            Some(slot) => *slot = Some(st_origin),
+            None => self.extra_st_ins.push(port),
+        }
+        self
+    }
+
+    pub fn states(mut self, st_origins: &[StOrigin<'g, S>]) -> NodeBuilder<'g, S>
+    where
+        S: Clone,
+    {
+        for st_origin in st_origins {
+            self = self.state(st_origin.clone());
+        }
+        self
+    }
+
+    /// The value inputs that haven't been given an operand yet.
+    pub fn remaining_operands(&self) -> Vec<usize> {
+        unfilled_ports(&self.val_origins)
+    }
+
+    /// The state inputs that haven't been given a state yet.
+    pub fn remaining_states(&self) -> Vec<usize> {
+        unfilled_ports(&self.st_origins)
+    }
+
+    /// Makes the node, panicking if its inputs can't be connected as given.
+    pub fn finish(self) -> Node<'g, S>
+    where
+        S: Eq + Hash + Clone,
+    {
+        match self.try_finish() {
+            Ok(node) => node,
+            Err(err) => panic!("node inputs can't be connected: {}", err),
+        }
+    }
+
+    /// Makes the node, or reports which of its inputs are missing, were
+    /// given past its signature, are in another region, or have the wrong
+    /// type.
+    pub fn try_finish(self) -> Result<Node<'g, S>, BuildError>
+    where
+        S: Eq + Hash + Clone,
+    {
+        let node_region = self.node_region();
+        let err = ArityError {
+            missing_val_ins: self.remaining_operands(),
+            missing_st_ins: self.remaining_states(),
+            extra_val_ins: self.extra_val_ins,
+            extra_st_ins: self.extra_st_ins,
+        };
+        if !err.is_empty() {
+            return Err(BuildError::Arity(err));
+        }
+
+        let ctxt = self.ctxt;
+        let check_region = |kind, port, origin_id| {
+            let region = ctxt.origin_region(origin_id);
+            if region == node_region {
+                Ok(())
+            } else {
+                Err(BuildError::OriginInOtherRegion { kind, port, region })
+            }
+        };
+        for (port, val_origin) in self.val_origins.iter().flatten().enumerate() {
+            check_region(PortKind::Val, port, val_origin.0.id())?;
+        }
+        for (port, st_origin) in self.st_origins.iter().flatten().enumerate() {
+            check_region(PortKind::St, port, st_origin.0.id())?;
+        }
+
+        let origins: Vec<OriginId> = {
+            let val_origins = self
+                .val_origins
+                .iter()
+                .flatten()
+                .map(|val_origin| val_origin.0.id());
+            let st_origins = self
+                .st_origins
+                .iter()
+                .flatten()
+                .map(|st_origin| st_origin.0.id());
+            val_origins.chain(st_origins).collect()
+        };
+        if let Some(err) = self.ctxt.mistyped_operand(&self.node_kind, &origins) {
+            return Err(err);
+        }
+
+        let node_id = self
+            .ctxt
+            .mk_node_in_region_with(node_region, self.node_kind, &origins);
+        if let Some(span) = self.span {
+            self.ctxt.attach_span(node_id, span);
+        }
+
+        Ok(Node {
+            ctxt: self.ctxt,
+            id: node_id,
+        })
+    }
+}
+
+fn unfilled_ports<T>(slots: &[Option<T>]) -> Vec<usize> {
+    slots
+        .iter()
+        .enumerate()
+        .filter(|(_, slot)| slot.is_none())
+        .map(|(port, _)| port)
+        .collect()
+}
+
+/// Builds a gamma node whose branch regions are filled in by the caller.
+///
+/// Entry and exit variables may be added at any point until `finish`, so
+/// that values can be routed into the branches as they're discovered.
+pub struct GammaBuilder<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    node: NodeId,
+    branches: Vec<RegionId>,
+}
+
+impl<'g, S: Sig> GammaBuilder<'g, S> {
+    pub fn node(&self) -> Node<'g, S> {
+        self.ctxt.node_ref(self.node)
+    }
+
+    pub fn num_branches(&self) -> usize {
+        self.branches.len()
+    }
+
+    pub fn branch(&self, index: usize) -> RegionId {
+        self.branches[index]
+    }
+
+    /// Routes `origin` into every branch, returning one argument per branch.
+    pub fn entry_var(&self, origin: ValOrigin<'g, S>) -> Vec<ValOrigin<'g, S>> {
+        self.add_entry(origin.id())
+            .into_iter()
+            .map(|arg| ValOrigin(self.ctxt.origin_ref(arg)))
+            .collect()
+    }
+
+    /// Routes `origin` into every branch, returning one argument per branch.
+    pub fn entry_state(&self, origin: StOrigin<'g, S>) -> Vec<StOrigin<'g, S>> {
+        self.add_entry(origin.id())
+            .into_iter()
+            .map(|arg| StOrigin(self.ctxt.origin_ref(arg)))
+            .collect()
+    }
+
+    /// Adds an output selecting among `results`, one per branch.
+    pub fn exit_var(&self, results: &[ValOrigin<'g, S>]) -> ValOrigin<'g, S> {
+        let results: Vec<_> = results.iter().map(|result| result.id()).collect();
+        ValOrigin(self.ctxt.origin_ref(self.add_exit(PortKind::Val, &results)))
+    }
+
+    /// Adds an output selecting among `results`, one per branch.
+    pub fn exit_state(&self, results: &[StOrigin<'g, S>]) -> StOrigin<'g, S> {
+        let results: Vec<_> = results.iter().map(|result| result.id()).collect();
+        StOrigin(self.ctxt.origin_ref(self.add_exit(PortKind::St, &results)))
+    }
+
+    pub fn finish(self) -> Node<'g, S>
+    where
+        S: Eq + Hash + Clone,
+    {
+        if self.ctxt.config.opt_region_cleanup {
+            for &branch in &self.branches {
+                self.ctxt.remove_unused_nodes(branch);
+            }
+        }
+        self.node()
+    }
+
+    fn add_entry(&self, origin_id: OriginId) -> Vec<OriginId> {
+        self.ctxt.add_gamma_entry(self.node, origin_id)
+    }
+
+    fn add_exit(&self, kind: PortKind, results: &[OriginId]) -> OriginId {
+        assert_eq!(results.len(), self.branches.len());
+        let output = self.ctxt.add_output(self.node, kind);
+        // The output takes the type of the first branch, which the others
+        // are checked against.
+        self.ctxt
+            .set_origin_type(output, self.ctxt.origin_type(results[0]));
+        for (&branch, &origin_id) in self.branches.iter().zip(results) {
+            assert_eq!(self.ctxt.origin_region(origin_id), branch);
+            let result = self.ctxt.add_result(branch, kind, Some(output));
+            self.ctxt.connect_ports(result, origin_id);
+        }
+        self.ctxt.count_ports(self.node, kind, 0, 1);
+        output
+    }
+}
+
+/// Builds a theta node whose body region is filled in by the caller.
+///
+/// Loop variables pass their argument through unchanged unless a next value
+/// is given for them before `finish`.
+pub struct ThetaBuilder<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    node: NodeId,
+    body: RegionId,
+}
+
+impl<'g, S: Sig> ThetaBuilder<'g, S> {
+    pub fn node(&self) -> Node<'g, S> {
+        self.ctxt.node_ref(self.node)
+    }
+
+    pub fn body(&self) -> RegionId {
+        self.body
+    }
+
+    /// Adds a loop variable initialized with `init`, returning its argument
+    /// in the body and its output after the loop.
+    pub fn loop_var(&self, init: ValOrigin<'g, S>) -> (ValOrigin<'g, S>, ValOrigin<'g, S>) {
+        let (arg, output) = self.add_loop_var(init.id());
+        (
+            ValOrigin(self.ctxt.origin_ref(arg)),
+            ValOrigin(self.ctxt.origin_ref(output)),
+        )
+    }
+
+    /// Adds a loop state initialized with `init`, returning its argument in
+    /// the body and its output after the loop.
+    pub fn loop_state(&self, init: StOrigin<'g, S>) -> (StOrigin<'g, S>, StOrigin<'g, S>) {
+        let (arg, output) = self.add_loop_var(init.id());
+        (
+            StOrigin(self.ctxt.origin_ref(arg)),
+            StOrigin(self.ctxt.origin_ref(output)),
+        )
+    }
+
+    /// Sets the value the loop variable `arg` takes in the next iteration.
+    pub fn set_next(&self, arg: ValOrigin<'g, S>, next: ValOrigin<'g, S>) {
+        self.set_next_origin(arg.id(), next.id());
+    }
+
+    /// Sets the state the loop state `arg` takes in the next iteration.
+    pub fn set_next_state(&self, arg: StOrigin<'g, S>, next: StOrigin<'g, S>) {
+        self.set_next_origin(arg.id(), next.id());
+    }
+
+    /// Connects the loop predicate, which repeats the body while true.
+    pub fn finish(self, predicate: ValOrigin<'g, S>) -> Node<'g, S>
+    where
+        S: Eq + Hash + Clone,
+    {
+        assert_eq!(self.ctxt.origin_region(predicate.id()), self.body);
+        let predicate_res = UserId::Res {
+            region: self.body,
+            index: 0,
+        };
+        self.ctxt.connect_ports(predicate_res, predicate.id());
+
+        let num_loop_vars = self.ctxt.region_data(self.body).args.len();
+        for index in 0..num_loop_vars {
+            let result = self.loop_var_result(index);
+            if self.ctxt.user_data(result).origin.get().is_none() {
+                let arg = OriginId::argument(self.body, index);
+                self.ctxt.connect_ports(result, arg);
+            }
+        }
+
+        if self.ctxt.config.opt_region_cleanup {
+            self.ctxt.remove_unused_nodes(self.body);
+        }
+        self.node()
+    }
+
+    fn add_loop_var(&self, init: OriginId) -> (OriginId, OriginId) {
+        self.ctxt.add_theta_loop_var(self.node, init)
+    }
+
+    fn set_next_origin(&self, arg: OriginId, next: OriginId) {
+        assert_eq!(self.ctxt.origin_region(next), self.body);
+        match arg {
+            OriginId::Arg { region, index } if region == self.body => {
+                self.ctxt
+                    .connect_ports(self.loop_var_result(usize::from(index)), next);
+            }
+            _ => panic!("{:?} isn't a loop variable of this theta", arg),
+        }
+    }
+
+    fn loop_var_result(&self, index: usize) -> UserId {
+        // Skips the predicate result.
+        UserId::result(self.body, index + 1)
+    }
+}
+
+/// Builds a lambda node whose body region is filled in by the caller.
+///
+/// Context variables may be added at any point until `finish`, so that
+/// values of enclosing regions can be used in the body.
+pub struct LambdaBuilder<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    node: NodeId,
+    body: RegionId,
+}
+
+impl<'g, S: Sig> LambdaBuilder<'g, S> {
+    pub fn node(&self) -> Node<'g, S> {
+        self.ctxt.node_ref(self.node)
+    }
+
+    pub fn body(&self) -> RegionId {
+        self.body
+    }
+
+    /// The value parameter `port`, counting only value parameters.
+    pub fn val_param(&self, port: usize) -> ValOrigin<'g, S> {
+        ValOrigin(self.ctxt.origin_ref(self.param(PortKind::Val, port)))
+    }
+
+    /// The state parameter `port`, counting only state parameters.
+    pub fn st_param(&self, port: usize) -> StOrigin<'g, S> {
+        StOrigin(self.ctxt.origin_ref(self.param(PortKind::St, port)))
+    }
+
+    /// Makes `origin` of the enclosing region usable in the body, returning
+    /// its argument there.
+    pub fn ctx_var(&self, origin: ValOrigin<'g, S>) -> ValOrigin<'g, S> {
+        let arg = self.ctxt.add_lambda_ctx_var(self.node, origin.id());
+        ValOrigin(self.ctxt.origin_ref(arg))
+    }
+
+    /// Connects the results of the function, values first.
+    pub fn finish(self, results: &[ValOrigin<'g, S>], states: &[StOrigin<'g, S>]) -> Node<'g, S>
+    where
+        S: Eq + Hash + Clone,
+    {
+        let results = results.iter().map(|result| result.id());
+        let states = states.iter().map(|state| state.id());
+        for origin_id in results.chain(states) {
+            assert_eq!(self.ctxt.origin_region(origin_id), self.body);
+            let kind = self.ctxt.origin_data(origin_id).kind;
+            let result = self.ctxt.add_result(self.body, kind, None);
+            self.ctxt.connect_ports(result, origin_id);
+        }
+
+        if self.ctxt.config.opt_region_cleanup {
+            self.ctxt.remove_unused_nodes(self.body);
+        }
+        self.node()
+    }
+
+    fn param(&self, kind: PortKind, port: usize) -> OriginId {
+        let params = match self.ctxt.node_data(self.node).kind {
+            NodeKind::Lambda { params, .. } => params,
+            _ => unreachable!(),
+        };
+        let region_data = self.ctxt.region_data(self.body);
+        let index = region_data.args[..params]
+            .iter()
+            .enumerate()
+            .filter(|(_, arg)| arg.kind == kind)
+            .nth(port)
+            .map(|(index, _)| index)
+            .unwrap_or_else(|| panic!("the lambda has no {:?} parameter {}", kind, port));
+        OriginId::argument(self.body, index)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Node<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    id: NodeId,
+}
+
+impl<'g, S: fmt::Debug> fmt::Debug for Node<'g, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.data().kind)
+    }
+}
+
+impl<'g, S> Node<'g, S> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn region(&self) -> RegionId {
+        self.data().outer_region
+    }
+
+    pub(crate) fn data(&self) -> NodeDataRef<'g, S> {
+        self.ctxt.node_data(self.id)
+    }
+
+    /// Interns this node once its inputs are connected, returning the node
+    /// that ends up in the graph.
+    pub fn intern(self) -> Node<'g, S>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        self.ctxt.node_ref(self.ctxt.intern_node(self.id))
+    }
+
+    pub fn kind(&self) -> Ref<'g, NodeKind<S>> {
+        Ref::map(self.ctxt.node_data(self.id).data, |node_data| {
+            &node_data.kind
+        })
+    }
+
+    /// The name the node is shown with in dumps, if it was given one.
+    pub fn name(&self) -> Option<String> {
+        self.ctxt.node_name(self.id)
+    }
+
+    /// Gives the node a name to show it with in dumps.
+    pub fn set_name(&self, name: impl Into<String>) {
+        self.ctxt.set_node_name(self.id, name);
+    }
+
+    /// The nodes producing the origins of this node's inputs, each once, in
+    /// the order of the inputs they're first connected to.
+    ///
+    /// Inputs connected to region arguments have no producing node.
+    pub fn predecessors(&self) -> impl Iterator<Item = Node<'g, S>> {
+        let mut predecessors = vec![];
+        for user in self.data().ins.iter() {
+            if let Some(OriginId::Out { node, .. }) = user.origin.get() {
+                if !predecessors.contains(&node) {
+                    predecessors.push(node);
+                }
+            }
+        }
+        let ctxt = self.ctxt;
+        predecessors
+            .into_iter()
+            .map(move |node_id| ctxt.node_ref(node_id))
+    }
+
+    /// The nodes using this node's outputs, each once, in the order of the
+    /// outputs they first use.
+    ///
+    /// Region results using the outputs have no consuming node.
+    pub fn successors(&self) -> impl Iterator<Item = Node<'g, S>> {
+        let mut successors = vec![];
+        let num_outputs = self.data().outs.len();
+        for index in 0..num_outputs {
+            let origin = self.ctxt.origin_ref(OriginId::output(self.id, index));
+            for user in origin.users() {
+                if let UserId::In { node, .. } = user.id() {
+                    if !successors.contains(&node) {
+                        successors.push(node);
+                    }
+                }
+            }
+        }
+        let ctxt = self.ctxt;
+        successors
+            .into_iter()
+            .map(move |node_id| ctxt.node_ref(node_id))
+    }
+
+    /// The inputs of both kinds, in order.
+    pub fn inputs(&self) -> impl DoubleEndedIterator<Item = Input<'g, S>> + ExactSizeIterator {
+        let (ctxt, id) = (self.ctxt, self.id);
+        let kinds: Vec<PortKind> = self.data().ins.iter().map(|user| user.kind).collect();
+        kinds
+            .into_iter()
+            .enumerate()
+            .map(move |(index, kind)| Input::new(ctxt.user_ref(UserId::input(id, index)), kind))
+    }
+
+    /// The outputs of both kinds, in order.
+    pub fn outputs(&self) -> impl DoubleEndedIterator<Item = Output<'g, S>> + ExactSizeIterator {
+        let (ctxt, id) = (self.ctxt, self.id);
+        let kinds: Vec<PortKind> = self.data().outs.iter().map(|origin| origin.kind).collect();
+        kinds.into_iter().enumerate().map(move |(index, kind)| {
+            Output::new(ctxt.origin_ref(OriginId::output(id, index)), kind)
+        })
+    }
+
+    /// The value inputs, in the order `val_in` numbers them.
+    pub fn val_ins(&self) -> impl DoubleEndedIterator<Item = ValUser<'g, S>> {
+        self.inputs().filter_map(|input| match input {
+            Input::Val(user) => Some(user),
+            Input::St(..) => None,
+        })
+    }
+
+    /// The value outputs, in the order `val_out` numbers them.
+    pub fn val_outs(&self) -> impl DoubleEndedIterator<Item = ValOrigin<'g, S>> {
+        self.outputs().filter_map(|output| match output {
+            Output::Val(origin) => Some(origin),
+            Output::St(..) => None,
+        })
+    }
+
+    /// The state inputs, in the order `st_in` numbers them.
+    pub fn st_ins(&self) -> impl DoubleEndedIterator<Item = StUser<'g, S>> {
+        self.inputs().filter_map(|input| match input {
+            Input::St(user) => Some(user),
+            Input::Val(..) => None,
+        })
+    }
+
+    /// The state outputs, in the order `st_out` numbers them.
+    pub fn st_outs(&self) -> impl DoubleEndedIterator<Item = StOrigin<'g, S>> {
+        self.outputs().filter_map(|output| match output {
+            Output::St(origin) => Some(origin),
+            Output::Val(..) => None,
+        })
+    }
+}
+
+impl<'g, S: Sig> Node<'g, S> {
+    pub fn val_in(&self, port: usize) -> ValUser<'g, S> {
+        ValUser(
+            self.ctxt
+                .user_ref(UserId::input(self.id, self.in_index(PortKind::Val, port))),
+        )
+    }
+
+    pub fn val_out(&self, port: usize) -> ValOrigin<'g, S> {
+        ValOrigin(self.ctxt.origin_ref(OriginId::output(
+            self.id,
+            self.out_index(PortKind::Val, port),
+        )))
+    }
+
+    pub fn st_in(&self, port: usize) -> StUser<'g, S> {
+        StUser(
+            self.ctxt
+                .user_ref(UserId::input(self.id, self.in_index(PortKind::St, port))),
+        )
+    }
+
+    pub fn st_out(&self, port: usize) -> StOrigin<'g, S> {
+        StOrigin(self.ctxt.origin_ref(OriginId::output(
+            self.id,
+            self.out_index(PortKind::St, port),
+        )))
+    }
+
+    fn in_index(&self, kind: PortKind, port: usize) -> usize {
+        port_index(self.data().ins.iter().map(|user| user.kind), kind, port)
+    }
+
+    fn out_index(&self, kind: PortKind, port: usize) -> usize {
+        port_index(
+            self.data().outs.iter().map(|origin| origin.kind),
+            kind,
+            port,
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Region<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    id: RegionId,
+}
+
+impl<'g, S> fmt::Debug for Region<'g, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.id)
+    }
+}
+
+impl<'g, S> Region<'g, S> {
+    pub fn id(&self) -> RegionId {
+        self.id
+    }
+
+    /// The structural node this region belongs to, or `None` for the root
+    /// region.
+    pub fn node(&self) -> Option<Node<'g, S>> {
+        let node_id = self.data().node?;
+        Some(self.ctxt.node_ref(node_id))
+    }
+
+    pub(crate) fn data(&self) -> Ref<'g, RegionData> {
+        self.ctxt.region_data(self.id)
+    }
+
+    /// The nodes in this region, not including those nested in their
+    /// regions, in the order they were made.
+    pub fn nodes(&self) -> impl DoubleEndedIterator<Item = Node<'g, S>> {
+        let ctxt = self.ctxt;
+        ctxt.region_nodes(self.id)
+            .into_iter()
+            .map(move |node_id| ctxt.node_ref(node_id))
+    }
+
+    pub fn val_arg(&self, port: usize) -> ValOrigin<'g, S> {
+        ValOrigin(self.ctxt.origin_ref(OriginId::argument(
+            self.id,
+            self.arg_index(PortKind::Val, port),
+        )))
+    }
+
+    /// Value result `port`. The first value result of a theta body is its
+    /// predicate, so its loop variables start at 1.
+    pub fn val_res(&self, port: usize) -> ValUser<'g, S> {
+        ValUser(
+            self.ctxt
+                .user_ref(UserId::result(self.id, self.res_index(PortKind::Val, port))),
+        )
+    }
+
+    pub fn st_arg(&self, port: usize) -> StOrigin<'g, S> {
+        StOrigin(self.ctxt.origin_ref(OriginId::argument(
+            self.id,
+            self.arg_index(PortKind::St, port),
+        )))
+    }
+
+    pub fn st_res(&self, port: usize) -> StUser<'g, S> {
+        StUser(
+            self.ctxt
+                .user_ref(UserId::result(self.id, self.res_index(PortKind::St, port))),
+        )
+    }
+
+    fn arg_index(&self, kind: PortKind, port: usize) -> usize {
+        port_index(
+            self.data().args.iter().map(|origin| origin.kind),
+            kind,
+            port,
+        )
+    }
+
+    fn res_index(&self, kind: PortKind, port: usize) -> usize {
+        port_index(self.data().res.iter().map(|user| user.kind), kind, port)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) struct User<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    user_id: UserId,
+}
+
+impl<'g, S: fmt::Debug> fmt::Debug for User<'g, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.user_id)
+    }
+}
+
+impl<'g, S> User<'g, S> {
+    pub(crate) fn id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub(crate) fn data(&self) -> Ref<'g, UserData> {
+        self.ctxt.user_data(self.user_id)
+    }
+
+    pub(crate) fn origin(&self) -> Origin<'g, S> {
+        let origin_id = self.data().origin.get().unwrap();
+        self.ctxt.origin_ref(origin_id)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Origin<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    origin_id: OriginId,
+}
+
+impl<'g, S: fmt::Debug> fmt::Debug for Origin<'g, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.origin_id)
+    }
+}
+
+impl<'g, S> Origin<'g, S> {
+    pub fn id(&self) -> OriginId {
+        self.origin_id
+    }
+
+    pub(crate) fn data(&self) -> Ref<'g, OriginData> {
+        self.ctxt.origin_data(self.origin_id)
+    }
+
+    pub(crate) fn producer(&self) -> Node<'g, S> {
+        match self.origin_id {
+            OriginId::Out { node, .. } => self.ctxt.node_ref(node),
+            _ => unimplemented!(),
+        }
+    }
+
+    pub(crate) fn users(&self) -> Users<'g, S> {
+        let user_ref = |user_id| self.ctxt.user_ref(user_id);
+        let data = self.data();
+        Users {
+            origin: self.ctxt.origin_ref(self.origin_id),
+            generation: data.generation.get(),
+            first_and_last: data
+                .users
+                .get()
+                .map(|users| (user_ref(users.first), user_ref(users.last))),
+            len: data.num_users(),
+        }
+    }
+
+    /// How many users the origin has.
+    pub(crate) fn num_users(&self) -> usize {
+        self.data().num_users()
+    }
+
+    /// Whether the origin has exactly one user, as folding it into that
+    /// user often asks for.
+    pub(crate) fn has_single_user(&self) -> bool {
+        self.num_users() == 1
+    }
+
+    /// Makes every user of this origin use `other` instead.
+    pub fn replace_all_users_with(&self, other: Origin<'g, S>)
+    where
+        S: Eq + Hash + Clone,
+    {
+        assert!(self.ctxt == other.ctxt);
+        self.ctxt.replace_all_users(self.origin_id, other.origin_id);
+    }
+}
+
+/// The users of an origin, in the order they were connected.
+///
+/// Connecting or disconnecting users of the origin while iterating over
+/// them panics, rather than skipping or repeating some, so the users have
+/// to be collected first when they're changed along the way.
+pub(crate) struct Users<'g, S> {
+    origin: Origin<'g, S>,
+    generation: u32,
+    first_and_last: Option<(User<'g, S>, User<'g, S>)>,
+    len: usize,
+}
+
+impl<'g, S> Users<'g, S> {
+    fn check_generation(&self) {
+        let generation = self.origin.data().generation.get();
+        assert!(
+            generation == self.generation,
+            "users of {:?} changed while iterating over them",
+            self.origin.origin_id
+        );
+    }
+}
+
+impl<'g, S> Iterator for Users<'g, S> {
+    type Item = User<'g, S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.check_generation();
+        self.len = self.len.saturating_sub(1);
+        match self.first_and_last.take() {
+            Some((first, last)) => {
+                if first.id() != last.id() {
+                    if let Some(next_user) = first.data().next_user.get() {
+                        self.first_and_last = Some((first.ctxt.user_ref(next_user), last));
+                    }
+                }
+                Some(first)
+            }
+            None => None,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'g, S> DoubleEndedIterator for Users<'g, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.check_generation();
+        self.len = self.len.saturating_sub(1);
+        match self.first_and_last.take() {
+            Some((first, last)) => {
+                if first.id() != last.id() {
+                    if let Some(prev_user) = last.data().prev_user.get() {
+                        self.first_and_last = Some((first, last.ctxt.user_ref(prev_user)));
+                    }
+                }
+                Some(last)
+            }
+            None => None,
+        }
+    }
+}
+
+impl<'g, S> ExactSizeIterator for Users<'g, S> {}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ValUser<'g, S>(User<'g, S>);
+
+impl<'g, S> ValUser<'g, S> {
+    fn id(&self) -> UserId {
+        self.0.id()
+    }
+
+    fn connect(&self, val_origin: ValOrigin<'g, S>) {
+        assert!(self.0.ctxt == val_origin.0.ctxt);
+        self.0.ctxt.connect_ports(self.id(), val_origin.id());
+    }
+
+    /// Connects the user to `val_origin`, or reports why it can't be.
+    pub fn try_connect(&self, val_origin: ValOrigin<'g, S>) -> Result<(), ConnectError> {
+        assert!(self.0.ctxt == val_origin.0.ctxt);
+        self.0.ctxt.try_connect_ports(self.id(), val_origin.id())
+    }
+
+    pub fn disconnect(&self)
+    where
+        S: Eq + Hash + Clone,
+    {
+        self.0.ctxt.disconnect(self.id());
+    }
+
+    pub fn reconnect(&self, val_origin: ValOrigin<'g, S>)
+    where
+        S: Eq + Hash + Clone,
+    {
+        assert!(self.0.ctxt == val_origin.0.ctxt);
+        self.0.ctxt.reconnect(self.id(), val_origin.id());
+    }
+
+    pub fn origin(&self) -> ValOrigin<'g, S> {
+        ValOrigin(self.0.origin())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct StUser<'g, S>(User<'g, S>);
+
+impl<'g, S> StUser<'g, S> {
+    fn id(&self) -> UserId {
+        self.0.id()
+    }
+
+    fn connect(&self, st_origin: StOrigin<'g, S>) {
+        assert!(self.0.ctxt == st_origin.0.ctxt);
+        self.0.ctxt.connect_ports(self.id(), st_origin.id());
+    }
+
+    /// Connects the user to `st_origin`, or reports why it can't be.
+    pub fn try_connect(&self, st_origin: StOrigin<'g, S>) -> Result<(), ConnectError> {
+        assert!(self.0.ctxt == st_origin.0.ctxt);
+        self.0.ctxt.try_connect_ports(self.id(), st_origin.id())
+    }
+
+    pub fn disconnect(&self)
+    where
+        S: Eq + Hash + Clone,
+    {
+        self.0.ctxt.disconnect(self.id());
+    }
+
+    pub fn reconnect(&self, st_origin: StOrigin<'g, S>)
+    where
+        S: Eq + Hash + Clone,
+    {
+        assert!(self.0.ctxt == st_origin.0.ctxt);
+        self.0.ctxt.reconnect(self.id(), st_origin.id());
+    }
+
+    pub fn origin(&self) -> StOrigin<'g, S> {
+        StOrigin(self.0.origin())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ValOrigin<'g, S>(Origin<'g, S>);
+
+impl<'g, S> ValOrigin<'g, S> {
+    fn id(&self) -> OriginId {
+        self.0.id()
+    }
+
+    fn connect(&self, val_user: ValUser<'g, S>) {
+        assert!(self.0.ctxt == val_user.0.ctxt);
+        self.0.ctxt.connect_ports(val_user.id(), self.id());
+    }
+
+    /// The users of the value, in the order they were connected. Panics if
+    /// they're connected or disconnected while iterating over them.
+    pub fn users(&self) -> impl DoubleEndedIterator<Item = ValUser<'g, S>> + ExactSizeIterator {
+        self.0.users().map(ValUser)
+    }
+
+    pub fn num_users(&self) -> usize {
+        self.0.num_users()
+    }
+
+    pub fn has_single_user(&self) -> bool {
+        self.0.has_single_user()
+    }
+
+    pub fn replace_all_users_with(&self, val_origin: ValOrigin<'g, S>)
+    where
+        S: Eq + Hash + Clone,
+    {
+        self.0.replace_all_users_with(val_origin.0);
+    }
+
+    pub fn producer(&self) -> Node<'g, S> {
+        self.0.producer()
+    }
+
+    /// The name the origin is shown with in dumps, if it was given one.
+    pub fn name(&self) -> Option<String> {
+        self.0.ctxt.origin_name(self.id())
+    }
+
+    /// Gives the origin a name to show it with in dumps. Replacing its
+    /// users passes the name on, unless `opt_transfer_names` is off.
+    pub fn set_name(&self, name: impl Into<String>) {
+        self.0.ctxt.set_origin_name(self.id(), name);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct StOrigin<'g, S>(Origin<'g, S>);
+
+impl<'g, S> StOrigin<'g, S> {
+    fn id(&self) -> OriginId {
+        self.0.id()
+    }
+
+    fn connect(&self, st_user: StUser<'g, S>) {
+        assert!(self.0.ctxt == st_user.0.ctxt);
+        self.0.ctxt.connect_ports(st_user.id(), self.id());
+    }
+
+    /// The users of the state, in the order they were connected. Panics if
+    /// they're connected or disconnected while iterating over them.
+    pub fn users(&self) -> impl DoubleEndedIterator<Item = StUser<'g, S>> + ExactSizeIterator {
+        self.0.users().map(StUser)
+    }
+
+    pub fn num_users(&self) -> usize {
+        self.0.num_users()
+    }
+
+    pub fn has_single_user(&self) -> bool {
+        self.0.has_single_user()
+    }
+
+    pub fn replace_all_users_with(&self, st_origin: StOrigin<'g, S>)
+    where
+        S: Eq + Hash + Clone,
+    {
+        self.0.replace_all_users_with(st_origin.0);
+    }
+
+    pub fn producer(&self) -> Node<'g, S> {
         self.0.producer()
     }
+
+    /// The name the origin is shown with in dumps, if it was given one.
+    pub fn name(&self) -> Option<String> {
+        self.0.ctxt.origin_name(self.id())
+    }
+
+    /// Gives the origin a name to show it with in dumps. Replacing its
+    /// users passes the name on, unless `opt_transfer_names` is off.
+    pub fn set_name(&self, name: impl Into<String>) {
+        self.0.ctxt.set_origin_name(self.id(), name);
+    }
+}
+
+/// An input or region result, of either kind.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Input<'g, S> {
+    Val(ValUser<'g, S>),
+    St(StUser<'g, S>),
+}
+
+impl<'g, S> Input<'g, S> {
+    pub fn kind(&self) -> PortKind {
+        match self {
+            Input::Val(..) => PortKind::Val,
+            Input::St(..) => PortKind::St,
+        }
+    }
+
+    fn new(user: User<'g, S>, kind: PortKind) -> Input<'g, S> {
+        match kind {
+            PortKind::Val => Input::Val(ValUser(user)),
+            PortKind::St => Input::St(StUser(user)),
+        }
+    }
+}
+
+/// An output or region argument, of either kind.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Output<'g, S> {
+    Val(ValOrigin<'g, S>),
+    St(StOrigin<'g, S>),
+}
+
+impl<'g, S> Output<'g, S> {
+    pub fn kind(&self) -> PortKind {
+        match self {
+            Output::Val(..) => PortKind::Val,
+            Output::St(..) => PortKind::St,
+        }
+    }
+
+    fn new(origin: Origin<'g, S>, kind: PortKind) -> Output<'g, S> {
+        match kind {
+            PortKind::Val => Output::Val(ValOrigin(origin)),
+            PortKind::St => Output::St(StOrigin(origin)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ArityError, BuildError, ConnectError, Input, InterningPolicy, NodeCtxt, NodeCtxtConfig,
+        NodeId, NodeKind, OriginId, Output, PortKind, RegionId, RegionSigS, Sig, SigS, Span,
+        UserId,
+    };
+    use std::{mem, rc::Rc};
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    enum TestData {
+        Lit(u32),
+        Neg,
+        St,
+        BinAdd,
+        BinSub,
+        LoadOffset,
+        Load,
+        Store,
+        OpA,
+        OpB,
+        OpC,
+    }
+
+    impl Sig for TestData {
+        fn sig(&self) -> SigS {
+            match self {
+                TestData::Lit(..) => SigS {
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+                TestData::Neg | TestData::OpA | TestData::OpB | TestData::OpC => SigS {
+                    val_ins: 1,
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+                TestData::St => SigS {
+                    st_outs: 1,
+                    ..SigS::default()
+                },
+                TestData::BinAdd | TestData::BinSub => SigS {
+                    val_ins: 2,
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+                TestData::LoadOffset => SigS {
+                    val_ins: 2,
+                    val_outs: 1,
+                    st_ins: 1,
+                    st_outs: 1,
+                    ..SigS::default()
+                },
+                TestData::Load => SigS {
+                    val_ins: 1,
+                    val_outs: 1,
+                    st_ins: 1,
+                    st_outs: 0,
+                    ..SigS::default()
+                },
+                TestData::Store => SigS {
+                    val_ins: 2,
+                    val_outs: 0,
+                    st_ins: 1,
+                    st_outs: 1,
+                    ..SigS::default()
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn create_single_node() {
+        let ncx = NodeCtxt::new();
+        let n0 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(0)), &[]);
+        assert_eq!(0, ncx.node_data(n0).ins.len());
+        assert_eq!(1, ncx.node_data(n0).outs.len());
+    }
+
+    #[test]
+    fn create_node_with_an_input() {
+        let ncx = NodeCtxt::new();
+        let n0 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(0)), &[]);
+        let n1 = ncx.mk_node_with(
+            NodeKind::Op(TestData::Neg),
+            &[OriginId::Out { node: n0, index: 0 }],
+        );
+
+        assert_eq!(
+            Some(n0),
+            ncx.node_data(n1).ins[0].origin.get().unwrap().node_id()
+        );
+    }
+
+    #[test]
+    fn create_node_with_an_input_using_builder() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx
+            .node_builder(TestData::Neg)
+            .operand(n0.val_out(0))
+            .finish();
+
+        assert_eq!(
+            Some(n0.id),
+            n1.data().ins[0].origin.get().unwrap().node_id()
+        );
+
+        assert_eq!(n0.val_out(0), n1.val_in(0).origin());
+    }
+
+    #[test]
+    fn create_node_with_input_ports() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(2)), &[]);
+
+        assert_eq!(0, ncx.node_data(n0).ins.len());
+        assert_eq!(1, ncx.node_data(n0).outs.len());
+
+        let n1 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(3)), &[]);
+
+        assert_eq!(0, ncx.node_data(n1).ins.len());
+        assert_eq!(1, ncx.node_data(n1).outs.len());
+
+        let n2 = ncx.mk_node_with(
+            NodeKind::Op(TestData::BinAdd),
+            &[
+                OriginId::Out { node: n0, index: 0 },
+                OriginId::Out { node: n1, index: 0 },
+            ],
+        );
+
+        assert_eq!(2, ncx.node_data(n2).ins.len());
+        assert_eq!(1, ncx.node_data(n2).outs.len());
+
+        assert_eq!(
+            Some(n2),
+            ncx.node_data(n0).outs[0]
+                .users
+                .get()
+                .unwrap()
+                .first
+                .node_id()
+        );
+        assert_eq!(
+            Some(n2),
+            ncx.node_data(n0).outs[0]
+                .users
+                .get()
+                .unwrap()
+                .last
+                .node_id()
+        );
+        assert_eq!(
+            Some(n2),
+            ncx.node_data(n1).outs[0]
+                .users
+                .get()
+                .unwrap()
+                .first
+                .node_id()
+        );
+        assert_eq!(
+            Some(n2),
+            ncx.node_data(n1).outs[0]
+                .users
+                .get()
+                .unwrap()
+                .last
+                .node_id()
+        );
+    }
+
+    #[test]
+    fn create_node_operands_and_states_using_builder_single() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(2));
+        let n1 = ncx.mk_node(TestData::Lit(3));
+        let n2 = ncx.mk_node(TestData::St);
+
+        let n3 = ncx
+            .node_builder(TestData::LoadOffset)
+            .operand(n0.val_out(0))
+            .operand(n1.val_out(0))
+            .state(n2.st_out(0))
+            .finish();
+
+        assert_eq!(n0.val_out(0), n3.val_in(0).origin());
+        assert_eq!(n1.val_out(0), n3.val_in(1).origin());
+        assert_eq!(n2.st_out(0), n3.st_in(0).origin());
+    }
+
+    #[test]
+    fn create_node_operands_and_states_using_builder_slice() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(2));
+        let n1 = ncx.mk_node(TestData::Lit(3));
+        let n2 = ncx.mk_node(TestData::St);
+
+        let n3 = ncx
+            .node_builder(TestData::LoadOffset)
+            .operands(&[n0.val_out(0), n1.val_out(0)])
+            .states(&[n2.st_out(0)])
+            .finish();
+
+        assert_eq!(n0.val_out(0), n3.val_in(0).origin());
+        assert_eq!(n1.val_out(0), n3.val_in(1).origin());
+        assert_eq!(n2.st_out(0), n3.st_in(0).origin());
+    }
+
+    #[test]
+    fn users_iterator() {
+        // TODO: state port users
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+
+        let n1 = ncx
+            .node_builder(TestData::OpA)
+            .operand(n0.val_out(0))
+            .finish();
+
+        let n2 = ncx
+            .node_builder(TestData::OpB)
+            .operand(n0.val_out(0))
+            .finish();
+
+        let n3 = ncx
+            .node_builder(TestData::OpC)
+            .operand(n0.val_out(0))
+            .finish();
+
+        let mut users = n0.val_out(0).users();
+
+        assert_eq!(Some(n1.val_in(0)), users.next());
+        assert_eq!(Some(n2.val_in(0)), users.next());
+        assert_eq!(Some(n3.val_in(0)), users.next());
+        assert_eq!(None, users.next());
+    }
+
+    #[test]
+    fn users_double_ended_iterator() {
+        // TODO: state port users
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+
+        let n1 = ncx
+            .node_builder(TestData::OpA)
+            .operand(n0.val_out(0))
+            .finish();
+
+        let n2 = ncx
+            .node_builder(TestData::OpB)
+            .operand(n0.val_out(0))
+            .finish();
+
+        let n3 = ncx
+            .node_builder(TestData::OpC)
+            .operand(n0.val_out(0))
+            .finish();
+
+        let mut users = n0.val_out(0).users();
+
+        assert_eq!(Some(n1.val_in(0)), users.next());
+        assert_eq!(Some(n3.val_in(0)), users.next_back());
+        assert_eq!(Some(n2.val_in(0)), users.next_back());
+        assert_eq!(None, users.next());
+        assert_eq!(None, users.next_back());
+    }
+
+    #[test]
+    fn users_exact_size_iterator() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        assert_eq!(0, n0.val_out(0).users().len());
+
+        let n2 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+        ncx.node_builder(TestData::OpA)
+            .operand(n0.val_out(0))
+            .finish();
+        assert_eq!(3, n0.val_out(0).num_users());
+
+        let mut users = n0.val_out(0).users();
+        assert_eq!(3, users.len());
+        users.next_back();
+        users.next();
+        assert_eq!(1, users.len());
+
+        n2.val_in(1).reconnect(n1.val_out(0));
+        assert_eq!(2, n0.val_out(0).users().len());
+        assert_eq!(1, n1.val_out(0).num_users());
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn port_iterators() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::St);
+        let n2 = ncx
+            .node_builder(TestData::Store)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .state(n1.st_out(0))
+            .finish();
+        assert_eq!(
+            vec![n2.val_in(0), n2.val_in(1)],
+            n2.val_ins().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![n2.st_in(0)], n2.st_ins().collect::<Vec<_>>());
+        assert_eq!(0, n2.val_outs().count());
+        assert_eq!(vec![n2.st_out(0)], n2.st_outs().collect::<Vec<_>>());
+        assert_eq!(
+            vec![PortKind::Val, PortKind::Val, PortKind::St],
+            n2.inputs().map(|input| input.kind()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(Output::St(n2.st_out(0))), n2.outputs().next());
+
+        // Ports of structural nodes come in the order they're added.
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (st_arg, _) = theta.loop_state(n1.st_out(0));
+        let (val_arg, _) = theta.loop_var(n0.val_out(0));
+        theta.set_next_state(st_arg, st_arg);
+        let n3 = theta.finish(val_arg);
+        assert_eq!(2, n3.inputs().len());
+        assert_eq!(Some(Input::St(n3.st_in(0))), n3.inputs().next());
+        assert_eq!(vec![n3.val_in(0)], n3.val_ins().collect::<Vec<_>>());
+        assert_eq!(Some(n3.val_out(0)), n3.val_outs().next_back());
+    }
+
+    #[test]
+    fn region_ports() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::St);
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (st_arg, _) = theta.loop_state(n1.st_out(0));
+        let (val_arg, _) = theta.loop_var(n0.val_out(0));
+        let body = ncx.region_ref(theta.body());
+        assert_eq!(val_arg, body.val_arg(0));
+        assert_eq!(st_arg, body.st_arg(0));
+
+        let n2 = ncx
+            .node_builder_in(body.id(), TestData::Neg)
+            .operand(body.val_arg(0))
+            .finish();
+        theta.set_next(val_arg, n2.val_out(0));
+        theta.set_next_state(st_arg, st_arg);
+        theta.finish(val_arg);
+        assert_eq!(val_arg, body.val_res(0).origin());
+        assert_eq!(n2.val_out(0), body.val_res(1).origin());
+        assert_eq!(st_arg, body.st_res(0).origin());
+    }
+
+    #[test]
+    fn single_users() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::St);
+        assert!(!n0.val_out(0).has_single_user());
+
+        let n2 = ncx
+            .node_builder(TestData::OpA)
+            .operand(n0.val_out(0))
+            .finish();
+        let n3 = ncx
+            .node_builder(TestData::Store)
+            .operand(n0.val_out(0))
+            .operand(n2.val_out(0))
+            .state(n1.st_out(0))
+            .finish();
+        assert!(!n0.val_out(0).has_single_user());
+        assert!(n2.val_out(0).has_single_user());
+        assert!(n1.st_out(0).has_single_user());
+
+        n3.val_in(0).reconnect(n2.val_out(0));
+        assert!(n0.val_out(0).has_single_user());
+        assert!(!n2.val_out(0).has_single_user());
+    }
+
+    #[test]
+    #[should_panic(expected = "changed while iterating over them")]
+    fn changing_users_while_iterating() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        ncx.node_builder(TestData::OpA)
+            .operand(n0.val_out(0))
+            .finish();
+        ncx.node_builder(TestData::OpB)
+            .operand(n0.val_out(0))
+            .finish();
+
+        for user in n0.val_out(0).users() {
+            user.reconnect(n1.val_out(0));
+        }
+    }
+
+    #[test]
+    fn reuse_existing_eq_nodes_at_creation() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(2));
+        let n1 = ncx.mk_node(TestData::Lit(3));
+        let n2 = ncx.mk_node(TestData::Lit(2));
+
+        assert_eq!(n0.id, n2.id);
+        assert_ne!(n0.id, n1.id);
+        assert_ne!(n1.id, n2.id);
+
+        let n3 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n1.val_out(0))
+            .finish();
+
+        let n4 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n2.val_out(0))
+            .finish();
+
+        let n5 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n2.val_out(0))
+            .operand(n1.val_out(0))
+            .finish();
+
+        assert_ne!(n3.id, n4.id);
+        assert_ne!(n4.id, n5.id);
+        assert_eq!(n3.id, n5.id);
+    }
+
+    #[test]
+    fn printing_load_store_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n_x = ncx.mk_node(TestData::Lit(100));
+        let n_y = ncx.mk_node(TestData::Lit(104));
+        let n_4 = ncx.mk_node(TestData::Lit(4));
+        let n_5 = ncx.mk_node(TestData::Lit(5));
+        let n_s = ncx.mk_node(TestData::St);
+
+        let n_l1 = ncx
+            .node_builder(TestData::Load)
+            .operand(n_x.val_out(0))
+            .state(n_s.st_out(0))
+            .finish();
+
+        let n_add_4 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n_l1.val_out(0))
+            .operand(n_4.val_out(0))
+            .finish();
+
+        let n_store1 = ncx
+            .node_builder(TestData::Store)
+            .operand(n_x.val_out(0))
+            .operand(n_add_4.val_out(0))
+            .state(n_s.st_out(0))
+            .finish();
+
+        let n_l2 = ncx
+            .node_builder(TestData::Load)
+            .operand(n_y.val_out(0))
+            .state(n_store1.st_out(0))
+            .finish();
+
+        let n_add_5 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n_l2.val_out(0))
+            .operand(n_5.val_out(0))
+            .finish();
+
+        let _ = ncx
+            .node_builder(TestData::Store)
+            .operand(n_y.val_out(0))
+            .operand(n_add_5.val_out(0))
+            .state(n_store1.st_out(0))
+            .finish();
+
+        let mut buffer = Vec::new();
+        ncx.print(&mut buffer).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            content,
+            r#"digraph rvsdg {
+    node [shape=record]
+    edge [arrowhead=none]
+    n0 [label="{{Lit(100)}|{<o0>0}}"]
+    n1 [label="{{Lit(104)}|{<o0>0}}"]
+    n2 [label="{{Lit(4)}|{<o0>0}}"]
+    n3 [label="{{Lit(5)}|{<o0>0}}"]
+    n4 [label="{{St}|{<o0>0}}"]
+    n5 [label="{{<i0>0|<i1>1}|{Load}|{<o0>0}}"]
+    n0:o0 -> n5:i0 [color=blue]
+    n4:o0 -> n5:i1 [style=dashed, color=red]
+    n6 [label="{{<i0>0|<i1>1}|{BinAdd}|{<o0>0}}"]
+    n5:o0 -> n6:i0 [color=blue]
+    n2:o0 -> n6:i1 [color=blue]
+    n7 [label="{{<i0>0|<i1>1|<i2>2}|{Store}|{<o0>0}}"]
+    n0:o0 -> n7:i0 [color=blue]
+    n6:o0 -> n7:i1 [color=blue]
+    n4:o0 -> n7:i2 [style=dashed, color=red]
+    n8 [label="{{<i0>0|<i1>1}|{Load}|{<o0>0}}"]
+    n1:o0 -> n8:i0 [color=blue]
+    n7:o0 -> n8:i1 [style=dashed, color=red]
+    n9 [label="{{<i0>0|<i1>1}|{BinAdd}|{<o0>0}}"]
+    n8:o0 -> n9:i0 [color=blue]
+    n3:o0 -> n9:i1 [color=blue]
+    n10 [label="{{<i0>0|<i1>1|<i2>2}|{Store}|{<o0>0}}"]
+    n1:o0 -> n10:i0 [color=blue]
+    n9:o0 -> n10:i1 [color=blue]
+    n7:o0 -> n10:i2 [style=dashed, color=red]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn manually_connecting_ports() {
+        let ncx = NodeCtxt::new();
+
+        let lit_a = ncx.create_node(NodeKind::Op(TestData::Lit(2)), RegionId(0));
+        let lit_b = ncx.create_node(NodeKind::Op(TestData::Lit(3)), RegionId(0));
+        let add = ncx.create_node(NodeKind::Op(TestData::BinAdd), RegionId(0));
+
+        add.val_in(0).connect(lit_a.val_out(0));
+        add.val_in(1).connect(lit_b.val_out(0));
+
+        let mut users = lit_a.val_out(0).users();
+
+        assert_eq!(Some(add.val_in(0)), users.next());
+        assert_eq!(None, users.next());
+
+        let mut users = lit_b.val_out(0).users();
+
+        assert_eq!(Some(add.val_in(1)), users.next());
+        assert_eq!(None, users.next());
+    }
+
+    #[test]
+    fn trying_to_connect_ports() {
+        let ncx = NodeCtxt::new();
+
+        let lit = ncx.mk_node(TestData::Lit(2));
+        let neg = ncx.create_node(NodeKind::Op(TestData::Neg), ncx.root_region());
+        let gamma = ncx.gamma_builder(lit.val_out(0), 1);
+        let inner = ncx.create_node(NodeKind::Op(TestData::Neg), gamma.branch(0));
+
+        assert_eq!(
+            Err(ConnectError::RegionMismatch {
+                user_region: gamma.branch(0),
+                origin_region: ncx.root_region(),
+            }),
+            inner.val_in(0).try_connect(lit.val_out(0))
+        );
+        assert_eq!(Ok(()), neg.val_in(0).try_connect(lit.val_out(0)));
+        assert_eq!(
+            Err(ConnectError::AlreadyConnected),
+            neg.val_in(0).try_connect(lit.val_out(0))
+        );
+        assert_eq!(Some(neg.val_in(0)), lit.val_out(0).users().last());
+    }
+
+    #[test]
+    #[should_panic(expected = "but the origin is in")]
+    fn connecting_ports_across_regions() {
+        let ncx = NodeCtxt::new();
+
+        let lit = ncx.mk_node(TestData::Lit(2));
+        let gamma = ncx.gamma_builder(lit.val_out(0), 1);
+        let inner = ncx.create_node(NodeKind::Op(TestData::Neg), gamma.branch(0));
+        inner.val_in(0).connect(lit.val_out(0));
+    }
+
+    #[test]
+    fn interning_manually_connected_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let lit_a = ncx.mk_node(TestData::Lit(2));
+        let lit_b = ncx.mk_node(TestData::Lit(3));
+        let add = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(lit_a.val_out(0))
+            .operand(lit_b.val_out(0))
+            .finish();
+
+        let manual_add = ncx.create_node(NodeKind::Op(TestData::BinAdd), RegionId(0));
+        manual_add.val_in(0).connect(lit_a.val_out(0));
+        manual_add.val_in(1).connect(lit_b.val_out(0));
+        let neg = ncx
+            .node_builder(TestData::Neg)
+            .operand(manual_add.val_out(0))
+            .finish();
+
+        let interned = manual_add.intern();
+
+        assert_eq!(add.id(), interned.id());
+        assert!(ncx.node_data(manual_add.id()).removed);
+        assert_eq!(add.val_out(0), neg.val_in(0).origin());
+
+        // A node without an equal one is interned as is.
+        let manual_sub = ncx.create_node(NodeKind::Op(TestData::BinSub), RegionId(0));
+        manual_sub.val_in(0).connect(lit_a.val_out(0));
+        manual_sub.val_in(1).connect(lit_b.val_out(0));
+
+        assert_eq!(manual_sub.id(), manual_sub.intern().id());
+
+        let sub = ncx
+            .node_builder(TestData::BinSub)
+            .operand(lit_a.val_out(0))
+            .operand(lit_b.val_out(0))
+            .finish();
+
+        assert_eq!(manual_sub.id(), sub.id());
+    }
+
+    #[test]
+    fn interning_policies() {
+        let shares = |policy: InterningPolicy<TestData>, op| {
+            let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+                interning: policy,
+                ..NodeCtxtConfig::default()
+            });
+            let n_lit = ncx.mk_node(TestData::Lit(0));
+            let mk = || {
+                let builder = ncx.node_builder(op);
+                match op {
+                    TestData::Lit(..) => builder,
+                    _ => builder.operand(n_lit.val_out(0)),
+                }
+                .finish()
+                .id()
+            };
+            mk() == mk()
+        };
+
+        assert!(shares(InterningPolicy::Stateless, TestData::Lit(1)));
+        assert!(shares(InterningPolicy::Stateless, TestData::Neg));
+        assert!(shares(InterningPolicy::Leaves, TestData::Lit(1)));
+        assert!(!shares(InterningPolicy::Leaves, TestData::Neg));
+        assert!(!shares(InterningPolicy::Never, TestData::Lit(1)));
+
+        let only_neg = || InterningPolicy::Ops(Rc::new(|op| *op == TestData::Neg));
+        assert!(shares(only_neg(), TestData::Neg));
+        assert!(!shares(only_neg(), TestData::OpA));
+        assert!(!shares(only_neg(), TestData::Lit(1)));
+    }
+
+    #[test]
+    fn interning_commutative_operands() {
+        use super::testing::Op;
+
+        let ncx = NodeCtxt::new();
+
+        let lit_a = ncx.mk_node(Op::Lit(2));
+        let lit_b = ncx.mk_node(Op::Lit(3));
+        let add = ncx
+            .node_builder(Op::Add)
+            .operand(lit_b.val_out(0))
+            .operand(lit_a.val_out(0))
+            .finish();
+        assert_eq!(add.val_in(0).origin(), lit_a.val_out(0));
+        assert_eq!(add.val_in(1).origin(), lit_b.val_out(0));
+
+        let swapped = ncx
+            .node_builder(Op::Add)
+            .operand(lit_a.val_out(0))
+            .operand(lit_b.val_out(0))
+            .finish();
+        assert_eq!(add.id(), swapped.id());
+
+        let manual_add = ncx.create_node(NodeKind::Op(Op::Add), RegionId(0));
+        manual_add.val_in(0).connect(lit_b.val_out(0));
+        manual_add.val_in(1).connect(lit_a.val_out(0));
+        assert_eq!(add.id(), manual_add.intern().id());
+
+        // Operands of other ops keep their order.
+        let sub = ncx
+            .node_builder(Op::Sub)
+            .operand(lit_b.val_out(0))
+            .operand(lit_a.val_out(0))
+            .finish();
+        let other_sub = ncx
+            .node_builder(Op::Sub)
+            .operand(lit_a.val_out(0))
+            .operand(lit_b.val_out(0))
+            .finish();
+        assert_eq!(sub.val_in(0).origin(), lit_b.val_out(0));
+        assert_ne!(sub.id(), other_sub.id());
+    }
+
+    #[test]
+    fn op_properties_of_node_kinds() {
+        use super::testing::Op;
+
+        let add = NodeKind::Op(Op::Add);
+        assert!(add.properties().is_commutative());
+        assert!(add.properties().is_associative());
+        let sub = NodeKind::Op(Op::Sub);
+        assert!(!sub.properties().is_commutative());
+        assert!(!sub.properties().is_associative());
+
+        // Ops that don't declare any properties have none, and neither do
+        // structural nodes.
+        assert!(!NodeKind::Op(TestData::BinAdd).properties().is_commutative());
+        let gamma = NodeKind::<Op>::Gamma {
+            val_ins: 2,
+            val_outs: 1,
+            st_ins: 0,
+            st_outs: 0,
+        };
+        assert!(!gamma.properties().is_commutative());
+    }
+
+    #[test]
+    #[should_panic]
+    fn interning_unconnected_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let lit = ncx.mk_node(TestData::Lit(2));
+        let add = ncx.create_node(NodeKind::Op(TestData::BinAdd), RegionId(0));
+        add.val_in(0).connect(lit.val_out(0));
+
+        add.intern();
+    }
+
+    #[test]
+    fn disconnecting_ports() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx
+            .node_builder(TestData::OpA)
+            .operand(n0.val_out(0))
+            .finish();
+        let n2 = ncx
+            .node_builder(TestData::OpB)
+            .operand(n0.val_out(0))
+            .finish();
+        let n3 = ncx
+            .node_builder(TestData::OpC)
+            .operand(n0.val_out(0))
+            .finish();
+
+        n1.val_in(0).disconnect();
+
+        let mut users = n0.val_out(0).users();
+        assert_eq!(Some(n2.val_in(0)), users.next());
+        assert_eq!(Some(n3.val_in(0)), users.next());
+        assert_eq!(None, users.next());
+        assert_eq!(None, n1.data().ins[0].origin.get());
+        assert_eq!(None, n1.data().ins[0].prev_user.get());
+        assert_eq!(None, n1.data().ins[0].next_user.get());
+
+        n3.val_in(0).disconnect();
+
+        let mut users = n0.val_out(0).users();
+        assert_eq!(Some(n2.val_in(0)), users.next_back());
+        assert_eq!(None, users.next_back());
+        assert_eq!(None, n2.data().ins[0].prev_user.get());
+        assert_eq!(None, n2.data().ins[0].next_user.get());
+    }
+
+    #[test]
+    fn reconnecting_ports() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let n2 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+
+        n2.val_in(0).reconnect(n1.val_out(0));
+
+        assert_eq!(n1.val_out(0), n2.val_in(0).origin());
+        assert_eq!(n0.val_out(0), n2.val_in(1).origin());
+
+        let mut users = n0.val_out(0).users();
+        assert_eq!(Some(n2.val_in(1)), users.next());
+        assert_eq!(None, users.next());
+
+        let mut users = n1.val_out(0).users();
+        assert_eq!(Some(n2.val_in(0)), users.next());
+        assert_eq!(None, users.next());
+
+        // The old term no longer describes n2, so it must not be reused.
+        let n3 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+
+        assert_ne!(n2.id(), n3.id());
+    }
+
+    #[test]
+    fn replacing_all_users() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let n2 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n1.val_out(0))
+            .finish();
+        let n3 = ncx
+            .node_builder(TestData::Neg)
+            .operand(n0.val_out(0))
+            .finish();
+
+        n0.val_out(0).replace_all_users_with(n1.val_out(0));
+
+        assert_eq!(None, n0.val_out(0).users().next());
+        assert_eq!(n1.val_out(0), n2.val_in(0).origin());
+        assert_eq!(n1.val_out(0), n3.val_in(0).origin());
+
+        let mut users = n1.val_out(0).users();
+        assert_eq!(Some(n2.val_in(1)), users.next());
+        assert_eq!(Some(n2.val_in(0)), users.next());
+        assert_eq!(Some(n3.val_in(0)), users.next());
+        assert_eq!(None, users.next());
+
+        let mut users = n1.val_out(0).users();
+        assert_eq!(Some(n3.val_in(0)), users.next_back());
+        assert_eq!(Some(n2.val_in(0)), users.next_back());
+        assert_eq!(Some(n2.val_in(1)), users.next_back());
+        assert_eq!(None, users.next_back());
+    }
+
+    #[test]
+    #[should_panic]
+    fn replacing_users_with_a_different_port_kind() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::St);
+        let _ = ncx
+            .node_builder(TestData::Neg)
+            .operand(n0.val_out(0))
+            .finish();
+
+        n0.val_out(0).0.replace_all_users_with(n1.st_out(0).0);
+    }
+
+    #[test]
+    fn interposing_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+
+        let neg = ncx.interpose(n0.val_out(0), |origin| {
+            ncx.node_builder(TestData::Neg)
+                .operand(origin)
+                .finish()
+                .val_out(0)
+        });
+
+        assert_eq!(neg, n1.val_in(0).origin());
+        assert_eq!(neg, n1.val_in(1).origin());
+        assert_eq!(n0.val_out(0), neg.producer().val_in(0).origin());
+
+        let mut users = n0.val_out(0).users();
+        assert_eq!(Some(neg.producer().val_in(0)), users.next());
+        assert_eq!(None, users.next());
+
+        let n_st = ncx.mk_node(TestData::St);
+        let n_store = ncx
+            .node_builder(TestData::Store)
+            .operands(&[n0.val_out(0), n0.val_out(0)])
+            .state(n_st.st_out(0))
+            .finish();
+
+        let st = ncx.interpose_state(n_st.st_out(0), |origin| {
+            ncx.node_builder(TestData::Store)
+                .operands(&[n1.val_out(0), n1.val_out(0)])
+                .state(origin)
+                .finish()
+                .st_out(0)
+        });
+
+        assert_eq!(st, n_store.st_in(0).origin());
+    }
+
+    #[test]
+    fn names_follow_replaced_origins() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let n2 = ncx.mk_node(TestData::Lit(2));
+        let n3 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n2.val_out(0))
+            .finish();
+
+        ncx.set_origin_name(n0.val_out(0).id(), "x");
+        n0.val_out(0).replace_all_users_with(n1.val_out(0));
+
+        assert_eq!(None, ncx.origin_name(n0.val_out(0).id()));
+        assert_eq!(Some("x".to_string()), ncx.origin_name(n1.val_out(0).id()));
+
+        ncx.set_origin_name(n2.val_out(0).id(), "y");
+        n1.val_out(0).replace_all_users_with(n2.val_out(0));
+
+        assert_eq!(Some("y/x".to_string()), ncx.origin_name(n2.val_out(0).id()));
+        assert_eq!(n2.val_out(0), n3.val_in(0).origin());
+
+        let n3_out = n3.val_out(0).id();
+        ncx.set_origin_name(n3_out, "z");
+        ncx.remove_node(n3.id());
+
+        assert_eq!(None, ncx.origin_name(n3_out));
+    }
+
+    #[test]
+    fn names_stay_put_when_not_transferred() {
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_transfer_names: false,
+            ..NodeCtxtConfig::default()
+        });
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let _ = ncx
+            .node_builder(TestData::Neg)
+            .operand(n0.val_out(0))
+            .finish();
+
+        ncx.set_origin_name(n0.val_out(0).id(), "x");
+        n0.val_out(0).replace_all_users_with(n1.val_out(0));
+
+        assert_eq!(Some("x".to_string()), ncx.origin_name(n0.val_out(0).id()));
+        assert_eq!(None, ncx.origin_name(n1.val_out(0).id()));
+    }
+
+    #[test]
+    fn printing_named_origins() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        ncx.set_origin_name(n0.val_out(0).id(), "<x>");
+
+        let mut buffer = Vec::new();
+        ncx.print(&mut buffer).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            content,
+            r#"digraph rvsdg {
+    node [shape=record]
+    edge [arrowhead=none]
+    n0 [label="{{Lit(0)}|{<o0>\<x\>}}"]
 }
+"#
+        );
+    }
 
-#[cfg(test)]
-mod test {
-    use super::{NodeCtxt, NodeKind, OriginId, RegionId, RegionSigS, Sig, SigS};
+    #[test]
+    fn interning_is_region_aware() {
+        let ncx = NodeCtxt::new();
 
-    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-    enum TestData {
-        Lit(u32),
-        Neg,
-        St,
-        BinAdd,
-        BinSub,
-        LoadOffset,
-        Load,
-        Store,
-        OpA,
-        OpB,
-        OpC,
+        let n_pred = ncx.mk_node(TestData::Lit(0));
+        let n_x = ncx.mk_node(TestData::Lit(5));
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let (b0, b1) = (gamma.branch(0), gamma.branch(1));
+
+        // Equal nodes in different regions are never shared.
+        let lit_b0 = ncx.node_builder_in(b0, TestData::Lit(5)).finish();
+        let lit_b1 = ncx.node_builder_in(b1, TestData::Lit(5)).finish();
+        assert_ne!(n_x.id(), lit_b0.id());
+        assert_ne!(lit_b0.id(), lit_b1.id());
+        assert_eq!(b0, lit_b0.region());
+        assert_eq!(b1, lit_b1.region());
+
+        // But they are within the same region.
+        let lit_b0_again = ncx.node_builder_in(b0, TestData::Lit(5)).finish();
+        assert_eq!(lit_b0.id(), lit_b0_again.id());
+
+        // Nodes made from origins are put in their region.
+        let neg_id = ncx.mk_node_with(NodeKind::Op(TestData::Neg), &[x_args[1].id()]);
+        let neg = ncx
+            .node_builder_in(b1, TestData::Neg)
+            .operand(x_args[1])
+            .finish();
+        assert_eq!(neg_id, neg.id());
+        assert_eq!(b1, neg.region());
+
+        gamma.exit_var(&[lit_b0.val_out(0), neg.val_out(0)]);
+        gamma.finish();
     }
 
-    impl Sig for TestData {
-        fn sig(&self) -> SigS {
-            match self {
-                TestData::Lit(..) => SigS {
-                    val_outs: 1,
-                    ..SigS::default()
-                },
-                TestData::Neg | TestData::OpA | TestData::OpB | TestData::OpC => SigS {
-                    val_ins: 1,
-                    val_outs: 1,
-                    ..SigS::default()
-                },
-                TestData::St => SigS {
-                    st_outs: 1,
-                    ..SigS::default()
-                },
-                TestData::BinAdd | TestData::BinSub => SigS {
-                    val_ins: 2,
-                    val_outs: 1,
-                    ..SigS::default()
-                },
-                TestData::LoadOffset => SigS {
-                    val_ins: 2,
-                    val_outs: 1,
-                    st_ins: 1,
-                    st_outs: 1,
-                    ..SigS::default()
-                },
-                TestData::Load => SigS {
-                    val_ins: 1,
-                    val_outs: 1,
-                    st_ins: 1,
-                    st_outs: 0,
-                    ..SigS::default()
-                },
-                TestData::Store => SigS {
-                    val_ins: 2,
-                    val_outs: 0,
-                    st_ins: 1,
-                    st_outs: 1,
-                    ..SigS::default()
-                },
-            }
-        }
+    #[test]
+    #[should_panic]
+    fn origins_from_different_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(TestData::Lit(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let args = gamma.entry_var(n_pred.val_out(0));
+
+        ncx.mk_node_with(
+            NodeKind::Op(TestData::BinAdd),
+            &[n_pred.val_out(0).id(), args[0].id()],
+        );
     }
 
     #[test]
-    fn create_single_node() {
+    fn partially_applied_builder() {
         let ncx = NodeCtxt::new();
-        let n0 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(0)), &[]);
-        assert_eq!(0, ncx.node_data(n0).ins.len());
-        assert_eq!(1, ncx.node_data(n0).outs.len());
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let n_st = ncx.mk_node(TestData::St);
+
+        let builder = ncx
+            .node_builder(TestData::LoadOffset)
+            .operand_at(1, n1.val_out(0));
+        assert_eq!(vec![0], builder.remaining_operands());
+        assert_eq!(vec![0], builder.remaining_states());
+
+        let builder = builder.operand(n0.val_out(0)).state(n_st.st_out(0));
+        assert!(builder.remaining_operands().is_empty());
+        assert!(builder.remaining_states().is_empty());
+
+        let node = builder.try_finish().unwrap();
+        assert_eq!(n0.val_out(0), node.val_in(0).origin());
+        assert_eq!(n1.val_out(0), node.val_in(1).origin());
+        assert_eq!(n_st.st_out(0), node.st_in(0).origin());
     }
 
     #[test]
-    fn create_node_with_an_input() {
+    fn builder_arity_errors() {
         let ncx = NodeCtxt::new();
-        let n0 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(0)), &[]);
-        let n1 = ncx.mk_node_with(
-            NodeKind::Op(TestData::Neg),
-            &[OriginId::Out { node: n0, index: 0 }],
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+
+        let err = ncx
+            .node_builder(TestData::LoadOffset)
+            .operand_at(1, n0.val_out(0))
+            .try_finish()
+            .unwrap_err();
+        assert_eq!(
+            BuildError::Arity(ArityError {
+                missing_val_ins: vec![0],
+                missing_st_ins: vec![0],
+                ..ArityError::default()
+            }),
+            err
+        );
+        assert_eq!(
+            "missing value inputs [0], missing state inputs [0]",
+            err.to_string()
         );
 
+        let err = ncx
+            .node_builder(TestData::Neg)
+            .operands(&[n0.val_out(0), n0.val_out(0), n0.val_out(0)])
+            .operand_at(5, n0.val_out(0))
+            .try_finish()
+            .unwrap_err();
         assert_eq!(
-            Some(n0),
-            ncx.node_data(n1).ins[0].origin.get().unwrap().node_id()
+            BuildError::Arity(ArityError {
+                extra_val_ins: vec![1, 2, 5],
+                ..ArityError::default()
+            }),
+            err
         );
+
+        // Nothing was added to the graph.
+        assert_eq!(1, ncx.num_nodes());
     }
 
     #[test]
-    fn create_node_with_an_input_using_builder() {
+    fn fallible_node_building() {
         let ncx = NodeCtxt::new();
 
         let n0 = ncx.mk_node(TestData::Lit(0));
-        let n1 = ncx
-            .node_builder(TestData::Neg)
+        let n_st = ncx.mk_node(TestData::St);
+
+        let err = ncx
+            .node_builder(TestData::Load)
+            .try_operand(n0.val_out(0))
+            .and_then(|builder| builder.try_state(n_st.st_out(0)))
+            .and_then(|builder| builder.try_operand(n0.val_out(0)))
+            .err();
+        assert_eq!(Some(BuildError::ExtraOperand { port: 1 }), err);
+
+        let gamma = ncx.gamma_builder(n0.val_out(0), 1);
+        let branch = gamma.branch(0);
+        gamma.finish();
+        let err = ncx
+            .node_builder_in(branch, TestData::Load)
             .operand(n0.val_out(0))
+            .state(n_st.st_out(0))
+            .try_finish()
+            .unwrap_err();
+        assert_eq!(
+            BuildError::OriginInOtherRegion {
+                kind: PortKind::Val,
+                port: 0,
+                region: ncx.root_region(),
+            },
+            err
+        );
+
+        let node = ncx
+            .node_builder(TestData::Load)
+            .try_operand(n0.val_out(0))
+            .and_then(|builder| builder.try_state(n_st.st_out(0)))
+            .and_then(|builder| builder.try_finish())
+            .unwrap();
+        assert_eq!(n0.val_out(0), node.val_in(0).origin());
+    }
+
+    #[test]
+    fn building_nodes_in_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let args = gamma.entry_var(n0.val_out(0));
+        let branch = gamma.branch(0);
+
+        let n1 = ncx.mk_node_in(branch, TestData::Lit(1));
+        assert_eq!(branch, n1.region());
+        // Nodes go in the region of their operands.
+        let n2 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(args[0])
+            .operand(n1.val_out(0))
             .finish();
+        assert_eq!(branch, n2.region());
+        assert_eq!(
+            ncx.root_region(),
+            ncx.node_builder(TestData::Lit(2)).finish().region()
+        );
 
+        let err = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(args[1])
+            .operand(n1.val_out(0))
+            .try_finish()
+            .unwrap_err();
         assert_eq!(
-            Some(n0.id),
-            n1.data().ins[0].origin.get().unwrap().node_id()
+            BuildError::OriginInOtherRegion {
+                kind: PortKind::Val,
+                port: 1,
+                region: branch,
+            },
+            err
         );
+    }
 
-        assert_eq!(n0.val_out(0), n1.val_in(0).origin());
+    #[test]
+    #[should_panic(expected = "missing value inputs [1]")]
+    fn finishing_with_missing_operands() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        ncx.node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .finish();
     }
 
     #[test]
-    fn create_node_with_input_ports() {
+    fn region_nodes() {
         let ncx = NodeCtxt::new();
 
-        let n0 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(2)), &[]);
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 1);
+        let branch = gamma.branch(0);
+        let n2 = ncx.node_builder_in(branch, TestData::Lit(2)).finish();
+        let gamma = gamma.finish();
+
+        let root = ncx.region_ref(ncx.root_region());
+        assert_eq!(vec![n0, n1, gamma], root.nodes().collect::<Vec<_>>());
+        assert_eq!(vec![n2], ncx.region_ref(branch).nodes().collect::<Vec<_>>());
+
+        ncx.remove_node(gamma.id());
+        ncx.remove_node(n1.id());
+        assert_eq!(vec![n0], root.nodes().collect::<Vec<_>>());
+    }
 
-        assert_eq!(0, ncx.node_data(n0).ins.len());
-        assert_eq!(1, ncx.node_data(n0).outs.len());
+    #[test]
+    fn predecessors_and_successors() {
+        let ncx = NodeCtxt::new();
 
-        let n1 = ncx.mk_node_with(NodeKind::Op(TestData::Lit(3)), &[]);
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let n_st = ncx.mk_node(TestData::St);
+        let n2 = ncx
+            .node_builder(TestData::LoadOffset)
+            .operand(n1.val_out(0))
+            .operand(n0.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+        let n3 = ncx
+            .node_builder(TestData::BinAdd)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+        let gamma = ncx.gamma_builder(n2.val_out(0), 1);
+        let args = gamma.entry_var(n0.val_out(0));
+        gamma.exit_var(&args);
+        let gamma = gamma.finish();
+
+        assert_eq!(vec![n1, n0, n_st], n2.predecessors().collect::<Vec<_>>());
+        assert_eq!(vec![n0], n3.predecessors().collect::<Vec<_>>());
+        assert_eq!(vec![n2, n3, gamma], n0.successors().collect::<Vec<_>>());
+        assert_eq!(vec![gamma], n2.successors().collect::<Vec<_>>());
+        assert_eq!(0, gamma.successors().count());
+    }
 
-        assert_eq!(0, ncx.node_data(n1).ins.len());
-        assert_eq!(1, ncx.node_data(n1).outs.len());
+    #[test]
+    fn removing_gamma_entries() {
+        let ncx = NodeCtxt::new();
 
-        let n2 = ncx.mk_node_with(
-            NodeKind::Op(TestData::BinAdd),
-            &[
-                OriginId::Out { node: n0, index: 0 },
-                OriginId::Out { node: n1, index: 0 },
-            ],
-        );
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx.mk_node(TestData::Lit(1));
+        let n2 = ncx.mk_node(TestData::Lit(2));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 1);
+        gamma.entry_var(n1.val_out(0));
+        let args = gamma.entry_var(n2.val_out(0));
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(0), TestData::Neg)
+            .operand(args[0])
+            .finish();
+        gamma.exit_var(&[n_neg.val_out(0)]);
+        let branch = gamma.branch(0);
+        let gamma = gamma.finish();
+        ncx.set_origin_name(args[0].id(), "x");
 
-        assert_eq!(2, ncx.node_data(n2).ins.len());
-        assert_eq!(1, ncx.node_data(n2).outs.len());
+        ncx.remove_gamma_entry(gamma.id(), 0);
 
+        let arg = OriginId::Arg {
+            region: branch,
+            index: 0,
+        };
+        assert_eq!(arg, n_neg.val_in(0).origin().id());
+        assert_eq!(Some("x".to_owned()), ncx.origin_name(arg));
+        assert_eq!(n2.val_out(0), gamma.val_in(1).origin());
         assert_eq!(
-            Some(n2),
-            ncx.node_data(n0).outs[0]
-                .users
-                .get()
-                .unwrap()
-                .first
-                .node_id()
-        );
-        assert_eq!(
-            Some(n2),
-            ncx.node_data(n0).outs[0]
-                .users
-                .get()
-                .unwrap()
-                .last
-                .node_id()
+            NodeKind::Gamma {
+                val_ins: 1,
+                val_outs: 1,
+                st_ins: 0,
+                st_outs: 0,
+            },
+            *gamma.kind()
         );
-        assert_eq!(
-            Some(n2),
-            ncx.node_data(n1).outs[0]
-                .users
-                .get()
-                .unwrap()
-                .first
-                .node_id()
+        assert_eq!(Ok(()), ncx.verify());
+
+        // The negation is still interned under its new operand.
+        let n_neg2 = ncx
+            .node_builder_in(branch, TestData::Neg)
+            .operand(n_neg.val_in(0).origin())
+            .finish();
+        assert_eq!(n_neg, n_neg2);
+    }
+
+    #[test]
+    fn regions() {
+        let ncx = NodeCtxt::<TestData>::new();
+
+        let omega_id = ncx.mk_node_with(
+            NodeKind::Omega {
+                imports: 1,
+                exports: 1,
+            },
+            &[],
         );
-        assert_eq!(
-            Some(n2),
-            ncx.node_data(n1).outs[0]
-                .users
-                .get()
-                .unwrap()
-                .last
-                .node_id()
+
+        let r0_id = ncx.mk_region_for_node(
+            omega_id,
+            RegionSigS {
+                val_args: 2,
+                val_res: 1,
+                ..RegionSigS::default()
+            },
         );
+
+        let r1_id = ncx.mk_region_for_node(omega_id, RegionSigS::default());
+
+        assert_eq!(2, ncx.region_data(r0_id).args.len());
+        assert_eq!(1, ncx.region_data(r0_id).res.len());
+        assert_eq!(Some(omega_id), ncx.region_data(r0_id).node);
+        assert_eq!(0, ncx.region_data(r0_id).sequence_index);
+        assert_eq!(1, ncx.region_data(r1_id).sequence_index);
+        assert_eq!(Some(r1_id), ncx.region_data(r0_id).next_region.get());
+        assert_eq!(Some(r0_id), ncx.region_data(r1_id).prev_region.get());
     }
 
     #[test]
-    fn create_node_operands_and_states_using_builder_single() {
+    fn gamma_entry_and_exit_vars() {
         let ncx = NodeCtxt::new();
 
-        let n0 = ncx.mk_node(TestData::Lit(2));
-        let n1 = ncx.mk_node(TestData::Lit(3));
-        let n2 = ncx.mk_node(TestData::St);
+        let n_pred = ncx.mk_node(TestData::Lit(1));
+        let n_x = ncx.mk_node(TestData::Lit(2));
+        let n_s = ncx.mk_node(TestData::St);
 
-        let n3 = ncx
-            .node_builder(TestData::LoadOffset)
-            .operand(n0.val_out(0))
-            .operand(n1.val_out(0))
-            .state(n2.st_out(0))
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let s_args = gamma.entry_state(n_s.st_out(0));
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(1), TestData::Neg)
+            .operand(x_args[1])
             .finish();
+        let x_out = gamma.exit_var(&[x_args[0], x_neg.val_out(0)]);
+        let s_out = gamma.exit_state(&s_args);
+        let branch = gamma.branch(1);
+        let gamma = gamma.finish();
 
-        assert_eq!(n0.val_out(0), n3.val_in(0).origin());
-        assert_eq!(n1.val_out(0), n3.val_in(1).origin());
-        assert_eq!(n2.st_out(0), n3.st_in(0).origin());
+        assert_eq!(
+            NodeKind::Gamma {
+                val_ins: 1,
+                val_outs: 1,
+                st_ins: 1,
+                st_outs: 1,
+            },
+            *gamma.kind()
+        );
+        assert_eq!(ncx.root_region(), gamma.region());
+        assert_eq!(n_x.val_out(0), gamma.val_in(1).origin());
+        assert_eq!(n_s.st_out(0), gamma.st_in(0).origin());
+        assert_eq!(x_out, gamma.val_out(0));
+        assert_eq!(s_out, gamma.st_out(0));
+        assert_eq!(branch, x_neg.region());
     }
 
     #[test]
-    fn create_node_operands_and_states_using_builder_slice() {
+    fn theta_loop_vars() {
         let ncx = NodeCtxt::new();
 
-        let n0 = ncx.mk_node(TestData::Lit(2));
-        let n1 = ncx.mk_node(TestData::Lit(3));
-        let n2 = ncx.mk_node(TestData::St);
+        let n_x = ncx.mk_node(TestData::Lit(2));
 
-        let n3 = ncx
-            .node_builder(TestData::LoadOffset)
-            .operands(&[n0.val_out(0), n1.val_out(0)])
-            .states(&[n2.st_out(0)])
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, x_out) = theta.loop_var(n_x.val_out(0));
+        let (y_arg, y_out) = theta.loop_var(n_x.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(theta.body(), TestData::Neg)
+            .operand(x_arg)
             .finish();
+        theta.set_next(x_arg, x_neg.val_out(0));
+        let theta = theta.finish(x_neg.val_out(0));
 
-        assert_eq!(n0.val_out(0), n3.val_in(0).origin());
-        assert_eq!(n1.val_out(0), n3.val_in(1).origin());
-        assert_eq!(n2.st_out(0), n3.st_in(0).origin());
+        assert_eq!(
+            NodeKind::Theta {
+                val_loop_vars: 2,
+                st_loop_vars: 0,
+            },
+            *theta.kind()
+        );
+        assert_eq!(x_out, theta.val_out(0));
+        assert_eq!(y_out, theta.val_out(1));
+
+        let body = ncx.region_data(
+            ncx.node_data(theta.id())
+                .inner_regions
+                .get()
+                .unwrap()
+                .first_region,
+        );
+        assert_eq!(Some(x_neg.val_out(0).id()), body.res[0].origin.get());
+        assert_eq!(Some(x_neg.val_out(0).id()), body.res[1].origin.get());
+        assert_eq!(Some(y_arg.id()), body.res[2].origin.get());
     }
 
     #[test]
-    fn users_iterator() {
-        // TODO: state port users
-        let ncx = NodeCtxt::new();
+    fn ids_are_compact() {
+        assert_eq!(4, mem::size_of::<NodeId>());
+        assert_eq!(8, mem::size_of::<UserId>());
+        assert_eq!(8, mem::size_of::<Option<OriginId>>());
+    }
 
+    #[test]
+    #[should_panic(expected = "port index 65536 doesn't fit in 16 bits")]
+    fn port_indices_are_checked() {
+        OriginId::output(NodeId::new(0), 1 << 16);
+    }
+
+    #[test]
+    fn reserving_capacity() {
+        let ncx = NodeCtxt::with_capacity(100, 10);
+        assert!(ncx.interned_nodes.borrow().capacity() >= 100);
         let n0 = ncx.mk_node(TestData::Lit(0));
+        ncx.reserve(1000, 0);
+        assert!(ncx.interned_nodes.borrow().capacity() >= 1001);
+        assert_eq!(n0, ncx.mk_node(TestData::Lit(0)));
+        assert_eq!(1, ncx.num_nodes());
+        assert_eq!(1, ncx.regions.len());
+    }
+
+    #[test]
+    fn bug_traverse() {
+        let ncx = NodeCtxt::new();
 
+        let n0 = ncx.mk_node(TestData::Lit(0));
         let n1 = ncx
-            .node_builder(TestData::OpA)
+            .node_builder(TestData::Neg)
             .operand(n0.val_out(0))
             .finish();
-
+        let o = n1.val_in(0).origin().producer();
+        // Reading a node doesn't get in the way of creating others.
+        let op = o.kind();
         let n2 = ncx
-            .node_builder(TestData::OpB)
-            .operand(n0.val_out(0))
-            .finish();
-
-        let n3 = ncx
-            .node_builder(TestData::OpC)
-            .operand(n0.val_out(0))
+            .node_builder(TestData::Neg)
+            .operand(n1.val_out(0))
             .finish();
-
-        let mut users = n0.val_out(0).users();
-
-        assert_eq!(Some(n1.val_in(0)), users.next());
-        assert_eq!(Some(n2.val_in(0)), users.next());
-        assert_eq!(Some(n3.val_in(0)), users.next());
-        assert_eq!(None, users.next());
+        assert_eq!(NodeKind::Op(TestData::Lit(0)), *op);
+        assert_eq!(n1.val_out(0), n2.val_in(0).origin());
     }
 
     #[test]
-    fn users_double_ended_iterator() {
-        // TODO: state port users
+    fn remove_node_unlinks_its_inputs() {
         let ncx = NodeCtxt::new();
 
         let n0 = ncx.mk_node(TestData::Lit(0));
-
         let n1 = ncx
             .node_builder(TestData::OpA)
             .operand(n0.val_out(0))
             .finish();
-
         let n2 = ncx
             .node_builder(TestData::OpB)
             .operand(n0.val_out(0))
             .finish();
-
         let n3 = ncx
             .node_builder(TestData::OpC)
             .operand(n0.val_out(0))
             .finish();
 
-        let mut users = n0.val_out(0).users();
+        ncx.remove_node(n2.id());
 
+        let mut users = n0.val_out(0).users();
         assert_eq!(Some(n1.val_in(0)), users.next());
-        assert_eq!(Some(n3.val_in(0)), users.next_back());
-        assert_eq!(Some(n2.val_in(0)), users.next_back());
+        assert_eq!(Some(n3.val_in(0)), users.next());
         assert_eq!(None, users.next());
+
+        let mut users = n0.val_out(0).users();
+        assert_eq!(Some(n3.val_in(0)), users.next_back());
+        assert_eq!(Some(n1.val_in(0)), users.next_back());
         assert_eq!(None, users.next_back());
+
+        ncx.remove_node(n1.id());
+        ncx.remove_node(n3.id());
+
+        assert_eq!(None, n0.val_out(0).users().next());
+        assert_eq!(1, ncx.num_nodes());
+        assert_eq!(0, ncx.num_edges());
     }
 
     #[test]
-    fn reuse_existing_eq_nodes_at_creation() {
+    fn removed_nodes_are_not_interned() {
         let ncx = NodeCtxt::new();
 
-        let n0 = ncx.mk_node(TestData::Lit(2));
-        let n1 = ncx.mk_node(TestData::Lit(3));
-        let n2 = ncx.mk_node(TestData::Lit(2));
-
-        assert_eq!(n0.id, n2.id);
-        assert_ne!(n0.id, n1.id);
-        assert_ne!(n1.id, n2.id);
-
-        let n3 = ncx
-            .node_builder(TestData::BinAdd)
+        let n0 = ncx.mk_node(TestData::Lit(0));
+        let n1 = ncx
+            .node_builder(TestData::Neg)
             .operand(n0.val_out(0))
-            .operand(n1.val_out(0))
             .finish();
 
-        let n4 = ncx
-            .node_builder(TestData::BinAdd)
-            .operand(n0.val_out(0))
-            .operand(n2.val_out(0))
-            .finish();
+        ncx.remove_node(n1.id());
 
-        let n5 = ncx
-            .node_builder(TestData::BinAdd)
-            .operand(n2.val_out(0))
-            .operand(n1.val_out(0))
+        let n2 = ncx
+            .node_builder(TestData::Neg)
+            .operand(n0.val_out(0))
             .finish();
 
-        assert_ne!(n3.id, n4.id);
-        assert_ne!(n4.id, n5.id);
-        assert_eq!(n3.id, n5.id);
+        assert_ne!(n1.id(), n2.id());
+        assert_eq!(Some(n2.val_in(0)), n0.val_out(0).users().next());
     }
 
     #[test]
-    fn printing_load_store_nodes() {
+    #[should_panic]
+    fn remove_node_with_users() {
         let ncx = NodeCtxt::new();
 
-        let n_x = ncx.mk_node(TestData::Lit(100));
-        let n_y = ncx.mk_node(TestData::Lit(104));
-        let n_4 = ncx.mk_node(TestData::Lit(4));
-        let n_5 = ncx.mk_node(TestData::Lit(5));
-        let n_s = ncx.mk_node(TestData::St);
-
-        let n_l1 = ncx
-            .node_builder(TestData::Load)
-            .operand(n_x.val_out(0))
-            .state(n_s.st_out(0))
-            .finish();
-
-        let n_add_4 = ncx
-            .node_builder(TestData::BinAdd)
-            .operand(n_l1.val_out(0))
-            .operand(n_4.val_out(0))
-            .finish();
-
-        let n_store1 = ncx
-            .node_builder(TestData::Store)
-            .operand(n_x.val_out(0))
-            .operand(n_add_4.val_out(0))
-            .state(n_s.st_out(0))
-            .finish();
-
-        let n_l2 = ncx
-            .node_builder(TestData::Load)
-            .operand(n_y.val_out(0))
-            .state(n_store1.st_out(0))
-            .finish();
-
-        let n_add_5 = ncx
-            .node_builder(TestData::BinAdd)
-            .operand(n_l2.val_out(0))
-            .operand(n_5.val_out(0))
-            .finish();
-
+        let n0 = ncx.mk_node(TestData::Lit(0));
         let _ = ncx
-            .node_builder(TestData::Store)
-            .operand(n_y.val_out(0))
-            .operand(n_add_5.val_out(0))
-            .state(n_store1.st_out(0))
+            .node_builder(TestData::Neg)
+            .operand(n0.val_out(0))
             .finish();
 
-        let mut buffer = Vec::new();
-        ncx.print(&mut buffer).unwrap();
-        let content = String::from_utf8(buffer).unwrap();
-        assert_eq!(
-            content,
-            r#"digraph rvsdg {
-    node [shape=record]
-    edge [arrowhead=none]
-    n0 [label="{{Lit(100)}|{<o0>0}}"]
-    n1 [label="{{Lit(104)}|{<o0>0}}"]
-    n2 [label="{{Lit(4)}|{<o0>0}}"]
-    n3 [label="{{Lit(5)}|{<o0>0}}"]
-    n4 [label="{{St}|{<o0>0}}"]
-    n5 [label="{{<i0>0|<i1>1}|{Load}|{<o0>0}}"]
-    n0:o0 -> n5:i0 [color=blue]
-    n4:o0 -> n5:i1 [style=dashed, color=red]
-    n6 [label="{{<i0>0|<i1>1}|{BinAdd}|{<o0>0}}"]
-    n5:o0 -> n6:i0 [color=blue]
-    n2:o0 -> n6:i1 [color=blue]
-    n7 [label="{{<i0>0|<i1>1|<i2>2}|{Store}|{<o0>0}}"]
-    n0:o0 -> n7:i0 [color=blue]
-    n6:o0 -> n7:i1 [color=blue]
-    n4:o0 -> n7:i2 [style=dashed, color=red]
-    n8 [label="{{<i0>0|<i1>1}|{Load}|{<o0>0}}"]
-    n1:o0 -> n8:i0 [color=blue]
-    n7:o0 -> n8:i1 [style=dashed, color=red]
-    n9 [label="{{<i0>0|<i1>1}|{BinAdd}|{<o0>0}}"]
-    n8:o0 -> n9:i0 [color=blue]
-    n3:o0 -> n9:i1 [color=blue]
-    n10 [label="{{<i0>0|<i1>1|<i2>2}|{Store}|{<o0>0}}"]
-    n1:o0 -> n10:i0 [color=blue]
-    n9:o0 -> n10:i1 [color=blue]
-    n7:o0 -> n10:i2 [style=dashed, color=red]
-}
-"#
-        );
+        ncx.remove_node(n0.id());
     }
 
     #[test]
-    fn manually_connecting_ports() {
+    fn remove_structural_node() {
         let ncx = NodeCtxt::new();
 
-        let lit_a = ncx.create_node(NodeKind::Op(TestData::Lit(2)), RegionId(0));
-        let lit_b = ncx.create_node(NodeKind::Op(TestData::Lit(3)), RegionId(0));
-        let add = ncx.create_node(NodeKind::Op(TestData::BinAdd), RegionId(0));
-
-        add.val_in(0).connect(lit_a.val_out(0));
-        add.val_in(1).connect(lit_b.val_out(0));
-
-        let mut users = lit_a.val_out(0).users();
+        let n_pred = ncx.mk_node(TestData::Lit(0));
+        let n_x = ncx.mk_node(TestData::Lit(1));
 
-        assert_eq!(Some(add.val_in(0)), users.next());
-        assert_eq!(None, users.next());
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), TestData::Neg)
+            .operand(x_args[0])
+            .finish();
+        gamma.exit_var(&[x_neg.val_out(0), x_args[1]]);
+        let branch = gamma.branch(0);
+        let gamma = gamma.finish();
 
-        let mut users = lit_b.val_out(0).users();
+        ncx.remove_node(gamma.id());
 
-        assert_eq!(Some(add.val_in(1)), users.next());
-        assert_eq!(None, users.next());
+        assert_eq!(2, ncx.num_nodes());
+        assert!(ncx.node_data(x_neg.id()).removed);
+        assert!(ncx.region_data(branch).removed);
+        assert_eq!(None, n_pred.val_out(0).users().next());
+        assert_eq!(None, n_x.val_out(0).users().next());
     }
 
     #[test]
-    #[should_panic]
-    fn regions() {
-        let ncx = NodeCtxt::<TestData>::new();
+    fn removed_nodes_lose_their_names_and_spans() {
+        let ncx = NodeCtxt::new();
 
-        let omega_id = ncx.mk_node_with(
-            NodeKind::Omega {
-                imports: 1,
-                exports: 1,
-            },
-            &[],
+        let n_pred = ncx.mk_node(TestData::Lit(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let x_args = gamma.entry_var(n_pred.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), TestData::Neg)
+            .operand(x_args[0])
+            .finish();
+        gamma.exit_var(&[x_neg.val_out(0)]);
+        let gamma = gamma.finish();
+
+        let (x, y) = (x_args[0].id(), x_neg.val_out(0).id());
+        ncx.set_node_name(gamma.id(), "g");
+        ncx.set_node_name(x_neg.id(), "neg");
+        ncx.set_origin_name(x, "x");
+        ncx.set_origin_name(y, "y");
+        ncx.attach_span(x_neg.id(), Span { line: 2, column: 5 });
+        ncx.attach_span(n_pred.id(), Span { line: 1, column: 1 });
+        ncx.remove_node(gamma.id());
+
+        assert_eq!(None, ncx.node_name(gamma.id()));
+        assert_eq!(None, ncx.node_name(x_neg.id()));
+        assert_eq!(None, ncx.origin_name(x));
+        assert_eq!(None, ncx.origin_name(y));
+        assert_eq!(None, ncx.node_span(x_neg.id()));
+        assert_eq!(
+            Some(Span { line: 1, column: 1 }),
+            ncx.node_span(n_pred.id())
         );
+    }
 
-        let r0_id = ncx.mk_region_for_node(
-            omega_id,
-            RegionSigS {
-                val_args: 2,
-                val_res: 1,
-                ..RegionSigS::default()
-            },
-        );
+    #[test]
+    fn region_cleanup_on_finish() {
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_region_cleanup: true,
+            ..NodeCtxtConfig::default()
+        });
+
+        let n_pred = ncx.mk_node(TestData::Lit(0));
+        let n_x = ncx.mk_node(TestData::Lit(1));
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), TestData::Neg)
+            .operand(x_args[0])
+            .finish();
+        let n_lit = ncx
+            .node_builder_in(gamma.branch(1), TestData::Lit(2))
+            .finish();
+        let n_unused = ncx
+            .node_builder_in(gamma.branch(1), TestData::Neg)
+            .operand(n_lit.val_out(0))
+            .finish();
+        gamma.exit_var(&[x_neg.val_out(0), x_args[1]]);
+        gamma.finish();
+
+        assert!(!ncx.node_data(x_neg.id()).removed);
+        assert!(ncx.node_data(n_unused.id()).removed);
+        assert!(ncx.node_data(n_lit.id()).removed);
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n_x.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(theta.body(), TestData::Neg)
+            .operand(x_arg)
+            .finish();
+        let n_unused = ncx
+            .node_builder_in(theta.body(), TestData::OpA)
+            .operand(x_arg)
+            .finish();
+        theta.set_next(x_arg, x_neg.val_out(0));
+        theta.finish(x_arg);
+
+        assert!(!ncx.node_data(x_neg.id()).removed);
+        assert!(ncx.node_data(n_unused.id()).removed);
     }
 
     #[test]
-    fn bug_traverse() {
+    fn no_region_cleanup_by_default() {
         let ncx = NodeCtxt::new();
 
-        let n0 = ncx.mk_node(TestData::Lit(0));
-        let n1 = ncx
-            .node_builder(TestData::Neg)
-            .operand(n0.val_out(0))
-            .finish();
-        let o = n1.val_in(0).origin().producer();
-        let op = *o.kind();
-        let n2 = ncx
-            .node_builder(TestData::Neg)
-            .operand(n1.val_out(0))
+        let n_pred = ncx.mk_node(TestData::Lit(0));
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let n_unused = ncx
+            .node_builder_in(gamma.branch(0), TestData::Lit(1))
             .finish();
+        gamma.finish();
+
+        assert!(!ncx.node_data(n_unused.id()).removed);
     }
 
     #[test]
@@ -0,0 +1,327 @@
+use super::{
+    MemoryAccess, MemoryOp, NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, Sig,
+    StOrigin, UserId,
+};
+use std::hash::Hash;
+
+/// Tells which memory operations may access the same memory.
+pub trait AliasAnalysis<S> {
+    /// Whether ops `a` and `b` may access overlapping memory. Only asked
+    /// about ops that access memory.
+    fn may_alias(&self, a: &S, b: &S) -> bool;
+}
+
+/// Ops that merge states.
+pub trait JoinStates {
+    /// The op taking `num_states` states and producing one that's ordered
+    /// after all of them.
+    fn join_states(num_states: usize) -> Self;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Splits linear chains of memory operations into parallel state edges,
+    /// so that each operation is only ordered after the ones it conflicts
+    /// with. Returns how many chains were split.
+    ///
+    /// Two operations conflict if `alias` says they may alias and at least
+    /// one of them is a store. An operation ordered after several others,
+    /// and the end of the chain, take the states of those joined together.
+    pub fn split_state_chains<A>(&self, alias: &A) -> usize
+    where
+        A: AliasAnalysis<S>,
+        S: MemoryOp + JoinStates + Sig + Eq + Hash + Clone,
+    {
+        let num_nodes = self.nodes.len();
+        let heads: Vec<NodeId> = (0..num_nodes)
+            .map(NodeId::new)
+            .filter(|&node_id| self.is_chain_head(node_id))
+            .collect();
+
+        let mut num_split = 0;
+        for head in heads {
+            let mut chain = vec![head];
+            while let Some(next) = self.next_in_chain(*chain.last().unwrap()) {
+                chain.push(next);
+            }
+            if self.split_chain(&chain, alias) {
+                num_split += 1;
+            }
+        }
+        num_split
+    }
+
+    /// The state output of a memory operation that takes and produces a
+    /// single state, and so can be part of a chain.
+    fn chain_state(&self, node_id: NodeId) -> Option<OriginId>
+    where
+        S: MemoryOp,
+    {
+        self.memory_access_of(node_id)?;
+        self.state_operand(node_id)?;
+        let node_data = self.node_data(node_id);
+        let num_st_ins = node_data
+            .ins
+            .iter()
+            .filter(|user| user.kind == PortKind::St)
+            .count();
+        let num_st_outs = node_data
+            .outs
+            .iter()
+            .filter(|out| out.kind == PortKind::St)
+            .count();
+        if num_st_ins != 1 || num_st_outs != 1 {
+            return None;
+        }
+        let index = self.first_output(node_id, PortKind::St)?;
+        Some(OriginId::output(node_id, index))
+    }
+
+    /// The operation that alone takes the state of `node_id`, if it belongs
+    /// to the same chain.
+    fn next_in_chain(&self, node_id: NodeId) -> Option<NodeId>
+    where
+        S: MemoryOp,
+    {
+        let state = self.chain_state(node_id)?;
+        let users = self.origin_data(state).users.get()?;
+        if users.first != users.last {
+            return None;
+        }
+        match users.first {
+            UserId::In { node, .. } if self.chain_state(node).is_some() => Some(node),
+            _ => None,
+        }
+    }
+
+    fn is_chain_head(&self, node_id: NodeId) -> bool
+    where
+        S: MemoryOp,
+    {
+        if self.chain_state(node_id).is_none() {
+            return false;
+        }
+        match self.state_operand(node_id) {
+            Some(OriginId::Out { node, .. }) => self.next_in_chain(node) != Some(node_id),
+            _ => true,
+        }
+    }
+
+    fn split_chain<A>(&self, chain: &[NodeId], alias: &A) -> bool
+    where
+        A: AliasAnalysis<S>,
+        S: MemoryOp + JoinStates + Sig + Eq + Hash + Clone,
+    {
+        let ops: Vec<(S, bool)> = chain
+            .iter()
+            .map(|&node_id| {
+                let is_store = matches!(
+                    self.memory_access_of(node_id),
+                    Some(MemoryAccess::Store { .. })
+                );
+                match self.node_data(node_id).kind {
+                    NodeKind::Op(ref op) => (op.clone(), is_store),
+                    _ => unreachable!(),
+                }
+            })
+            .collect();
+
+        // The operations each one is ordered after, directly or not, and
+        // the ones it directly takes its state from.
+        let len = chain.len();
+        let mut ordered_after: Vec<Vec<bool>> = Vec::with_capacity(len);
+        let mut preds: Vec<Vec<usize>> = Vec::with_capacity(len);
+        for (i, (op, is_store)) in ops.iter().enumerate() {
+            let conflicts: Vec<usize> = (0..i)
+                .filter(|&j| (*is_store || ops[j].1) && alias.may_alias(&ops[j].0, op))
+                .collect();
+            let mut ordered = vec![false; len];
+            for &j in &conflicts {
+                ordered[j] = true;
+                for (k, &after) in ordered_after[j].iter().enumerate() {
+                    ordered[k] |= after;
+                }
+            }
+            let direct = conflicts
+                .iter()
+                .copied()
+                .filter(|&j| !conflicts.iter().any(|&k| ordered_after[k][j]))
+                .collect();
+            ordered_after.push(ordered);
+            preds.push(direct);
+        }
+        if (1..len).all(|i| preds[i] == [i - 1]) {
+            return false;
+        }
+        let sinks: Vec<usize> = (0..len)
+            .filter(|&j| !ordered_after[j + 1..].iter().any(|ordered| ordered[j]))
+            .collect();
+
+        let region_id = self.node_data(chain[0]).outer_region;
+        let start = self.state_operand(chain[0]).unwrap();
+        let states: Vec<OriginId> = chain
+            .iter()
+            .map(|&node_id| self.chain_state(node_id).unwrap())
+            .collect();
+        let end_users: Vec<UserId> = self
+            .origin_ref(states[len - 1])
+            .users()
+            .map(|user| user.id())
+            .collect();
+
+        for (&node_id, preds) in chain.iter().zip(&preds) {
+            let origins: Vec<OriginId> = preds.iter().map(|&j| states[j]).collect();
+            let state = self.join_states_in(region_id, start, &origins);
+            let index = self
+                .node_data(node_id)
+                .ins
+                .iter()
+                .position(|user| user.kind == PortKind::St)
+                .unwrap();
+            self.reconnect(UserId::input(node_id, index), state);
+        }
+        let origins: Vec<OriginId> = sinks.iter().map(|&j| states[j]).collect();
+        let end = self.join_states_in(region_id, start, &origins);
+        for user_id in end_users {
+            self.reconnect(user_id, end);
+        }
+        true
+    }
+
+    /// A state ordered after `origins`, or `start` if there are none.
+    fn join_states_in(&self, region_id: RegionId, start: OriginId, origins: &[OriginId]) -> OriginId
+    where
+        S: JoinStates + Sig + Eq + Hash + Clone,
+    {
+        match origins {
+            [] => start,
+            [origin_id] => *origin_id,
+            _ => {
+                let builder = self.node_builder_in(region_id, S::join_states(origins.len()));
+                let builder = origins.iter().fold(builder, |builder, &origin_id| {
+                    builder.state(StOrigin(self.origin_ref(origin_id)))
+                });
+                builder.finish().st_out(0).id()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AliasAnalysis;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    /// The bank of memory an op accesses, of several disjoint ones.
+    fn bank(op: &Op) -> Option<u32> {
+        match *op {
+            Op::Load(bank) | Op::Write(bank) => Some(bank),
+            _ => None,
+        }
+    }
+
+    struct Banks;
+
+    impl AliasAnalysis<Op> for Banks {
+        fn may_alias(&self, a: &Op, b: &Op) -> bool {
+            bank(a) == bank(b)
+        }
+    }
+
+    #[test]
+    fn splitting_accesses_to_different_banks() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_store_a = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_store_b = ncx
+            .node_builder(Op::Write(1))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(n_store_a.st_out(0))
+            .finish();
+        let n_load_a = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_store_b.st_out(0))
+            .finish();
+        let n_load_b = ncx
+            .node_builder(Op::Load(1))
+            .operand(n_addr.val_out(0))
+            .state(n_load_a.st_out(0))
+            .finish();
+        let n_ret = ncx.node_builder(Op::Ret).state(n_load_b.st_out(0)).finish();
+
+        assert_eq!(1, ncx.split_state_chains(&Banks));
+        assert_eq!(n_store_a.st_in(0).origin(), st.st_out(0));
+        assert_eq!(n_store_b.st_in(0).origin(), st.st_out(0));
+        assert_eq!(n_load_a.st_in(0).origin(), n_store_a.st_out(0));
+        assert_eq!(n_load_b.st_in(0).origin(), n_store_b.st_out(0));
+
+        let n_join = n_ret.st_in(0).origin().producer();
+        assert_eq!(*n_join.kind(), NodeKind::Op(Op::Join(2)));
+        assert_eq!(n_join.st_in(0).origin(), n_load_a.st_out(0));
+        assert_eq!(n_join.st_in(1).origin(), n_load_b.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn loads_are_not_ordered_among_themselves() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_first = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_second = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_first.st_out(0))
+            .finish();
+        let n_store = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_second.val_out(0))
+            .state(n_second.st_out(0))
+            .finish();
+        let n_ret = ncx.node_builder(Op::Ret).state(n_store.st_out(0)).finish();
+
+        assert_eq!(1, ncx.split_state_chains(&Banks));
+        assert_eq!(n_second.st_in(0).origin(), st.st_out(0));
+        let n_join = n_store.st_in(0).origin().producer();
+        assert_eq!(*n_join.kind(), NodeKind::Op(Op::Join(2)));
+        assert_eq!(n_ret.st_in(0).origin(), n_store.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn chains_of_aliasing_accesses_stay() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_store = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_load = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_store.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Ret).state(n_load.st_out(0)).finish();
+
+        assert_eq!(0, ncx.split_state_chains(&Banks));
+        assert_eq!(n_load.st_in(0).origin(), n_store.st_out(0));
+    }
+}
@@ -0,0 +1,244 @@
+use super::{NodeCtxt, RegionId, Sig, StOrigin, ValOrigin};
+use arbitrary::{Arbitrary, Result, Unstructured};
+use std::hash::Hash;
+
+/// The most nodes a generated graph has, counting those in nested regions.
+const MAX_NODES: usize = 64;
+
+/// How deep gamma and theta nodes nest in a generated graph.
+const MAX_DEPTH: usize = 3;
+
+/// The most ports of a kind an op may have to be put in a generated graph.
+const MAX_PORTS: usize = 8;
+
+/// The most values routed into or out of a generated gamma or theta node.
+const MAX_ROUTED: usize = 3;
+
+impl<'a, S> Arbitrary<'a> for NodeCtxt<S>
+where
+    S: Arbitrary<'a> + Sig + Eq + Hash + Clone,
+{
+    /// Generates a graph of arbitrary ops, each of whose operands is a value
+    /// or state made earlier in the same region, with gamma and theta nodes
+    /// routing some of them into nested regions.
+    ///
+    /// Ops taking more than `MAX_PORTS` ports of a kind, or operands of a
+    /// kind nothing earlier in their region makes, are left out.
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<NodeCtxt<S>> {
+        let ncx = NodeCtxt::new();
+        Generator {
+            ctxt: &ncx,
+            u,
+            num_nodes: 0,
+        }
+        .fill(ncx.root_region(), &mut Pool::default(), 0)?;
+        Ok(ncx)
+    }
+}
+
+/// The values and states that nodes of a region may use.
+struct Pool<'g, S> {
+    vals: Vec<ValOrigin<'g, S>>,
+    states: Vec<StOrigin<'g, S>>,
+}
+
+impl<S> Default for Pool<'_, S> {
+    fn default() -> Self {
+        Pool {
+            vals: vec![],
+            states: vec![],
+        }
+    }
+}
+
+struct Generator<'g, 'u, 'a, S> {
+    ctxt: &'g NodeCtxt<S>,
+    u: &'u mut Unstructured<'a>,
+    num_nodes: usize,
+}
+
+impl<'g, 'a, S> Generator<'g, '_, 'a, S>
+where
+    S: Arbitrary<'a> + Sig + Eq + Hash + Clone,
+{
+    /// Adds nodes to `region` for as long as the data says so, using and
+    /// extending `pool`.
+    fn fill(&mut self, region: RegionId, pool: &mut Pool<'g, S>, depth: usize) -> Result<()> {
+        while self.num_nodes < MAX_NODES && self.u.ratio(15, 16)? {
+            self.num_nodes += 1;
+            let can_nest = depth < MAX_DEPTH && !pool.vals.is_empty();
+            match self.u.int_in_range(0..=7)? {
+                0 if can_nest => self.gamma(pool, depth)?,
+                1 if can_nest => self.theta(region, pool, depth)?,
+                _ => self.op(region, pool)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, region: RegionId, pool: &mut Pool<'g, S>) -> Result<()> {
+        let op = S::arbitrary(self.u)?;
+        let sig = op.sig();
+        let too_many_ports = [sig.val_ins, sig.val_outs, sig.st_ins, sig.st_outs]
+            .iter()
+            .any(|&num_ports| num_ports > MAX_PORTS);
+        let lacks_operands =
+            (sig.val_ins > 0 && pool.vals.is_empty()) || (sig.st_ins > 0 && pool.states.is_empty());
+        if too_many_ports || lacks_operands {
+            return Ok(());
+        }
+        let mut builder = self.ctxt.node_builder_in(region, op);
+        for _ in 0..sig.val_ins {
+            builder = builder.operand(self.u.choose(&pool.vals)?.clone());
+        }
+        for _ in 0..sig.st_ins {
+            builder = builder.state(self.u.choose(&pool.states)?.clone());
+        }
+        let node = builder.finish();
+        pool.vals
+            .extend((0..sig.val_outs).map(|index| node.val_out(index)));
+        pool.states
+            .extend((0..sig.st_outs).map(|index| node.st_out(index)));
+        Ok(())
+    }
+
+    fn gamma(&mut self, pool: &mut Pool<'g, S>, depth: usize) -> Result<()> {
+        let predicate = self.u.choose(&pool.vals)?.clone();
+        let num_branches = self.u.int_in_range(2..=3)?;
+        let gamma = self.ctxt.gamma_builder(predicate, num_branches);
+        let mut branches: Vec<Pool<S>> = (0..num_branches).map(|_| Pool::default()).collect();
+        for _ in 0..self.u.int_in_range(0..=MAX_ROUTED)? {
+            let args = gamma.entry_var(self.u.choose(&pool.vals)?.clone());
+            for (branch, arg) in branches.iter_mut().zip(args) {
+                branch.vals.push(arg);
+            }
+        }
+        if !pool.states.is_empty() && self.u.arbitrary()? {
+            let args = gamma.entry_state(self.u.choose(&pool.states)?.clone());
+            for (branch, arg) in branches.iter_mut().zip(args) {
+                branch.states.push(arg);
+            }
+        }
+        for (index, branch) in branches.iter_mut().enumerate() {
+            self.fill(gamma.branch(index), branch, depth + 1)?;
+        }
+
+        let mut vals = vec![];
+        if branches.iter().all(|branch| !branch.vals.is_empty()) {
+            for _ in 0..self.u.int_in_range(0..=MAX_ROUTED)? {
+                let results = self.choose_in_each(&branches, |branch| &branch.vals)?;
+                vals.push(gamma.exit_var(&results));
+            }
+        }
+        let mut states = vec![];
+        if branches.iter().all(|branch| !branch.states.is_empty()) && self.u.arbitrary()? {
+            let results = self.choose_in_each(&branches, |branch| &branch.states)?;
+            states.push(gamma.exit_state(&results));
+        }
+        gamma.finish();
+        pool.vals.extend(vals);
+        pool.states.extend(states);
+        Ok(())
+    }
+
+    fn theta(&mut self, region: RegionId, pool: &mut Pool<'g, S>, depth: usize) -> Result<()> {
+        let theta = self.ctxt.theta_builder(region);
+        let mut body = Pool::default();
+        // The body needs a value for its predicate.
+        let mut loop_vars = vec![];
+        for _ in 0..self.u.int_in_range(1..=MAX_ROUTED)? {
+            let (arg, output) = theta.loop_var(self.u.choose(&pool.vals)?.clone());
+            body.vals.push(arg.clone());
+            loop_vars.push((arg, output));
+        }
+        let mut loop_states = vec![];
+        if !pool.states.is_empty() && self.u.arbitrary()? {
+            let (arg, output) = theta.loop_state(self.u.choose(&pool.states)?.clone());
+            body.states.push(arg.clone());
+            loop_states.push((arg, output));
+        }
+        self.fill(theta.body(), &mut body, depth + 1)?;
+
+        for (arg, _) in &loop_vars {
+            theta.set_next(arg.clone(), self.u.choose(&body.vals)?.clone());
+        }
+        for (arg, _) in &loop_states {
+            theta.set_next_state(arg.clone(), self.u.choose(&body.states)?.clone());
+        }
+        theta.finish(self.u.choose(&body.vals)?.clone());
+        pool.vals
+            .extend(loop_vars.into_iter().map(|(_, output)| output));
+        pool.states
+            .extend(loop_states.into_iter().map(|(_, output)| output));
+        Ok(())
+    }
+
+    /// Chooses one origin among those `origins` gives for each branch.
+    fn choose_in_each<T: Clone>(
+        &mut self,
+        branches: &[Pool<'g, S>],
+        origins: for<'p> fn(&'p Pool<'g, S>) -> &'p [T],
+    ) -> Result<Vec<T>> {
+        branches
+            .iter()
+            .map(|branch| self.u.choose(origins(branch)).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{differential::Rng, testing::Op, NodeCtxt, NodeKind};
+    use arbitrary::{Arbitrary, Result, Unstructured};
+
+    impl<'a> Arbitrary<'a> for Op {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Op> {
+            Ok(match u.int_in_range(0..=6)? {
+                0 => Op::Lit(u.arbitrary()?),
+                1 => Op::Param(0),
+                2 => Op::St,
+                3 => Op::Neg,
+                4 => Op::Add,
+                5 => Op::Load(0),
+                _ => Op::Write(0),
+            })
+        }
+    }
+
+    fn arbitrary_graphs(num_graphs: u64) -> impl Iterator<Item = NodeCtxt<Op>> {
+        (0..num_graphs).map(|seed| {
+            let mut rng = Rng::new(seed);
+            let data: Vec<u8> = (0..1024).map(|_| rng.next_u64() as u8).collect();
+            NodeCtxt::arbitrary(&mut Unstructured::new(&data)).unwrap()
+        })
+    }
+
+    fn has_node(ncx: &NodeCtxt<Op>, is_kind: impl Fn(&NodeKind<Op>) -> bool) -> bool {
+        ncx.nodes
+            .iter()
+            .map(|node| node.borrow())
+            .any(|node_data| !node_data.removed && is_kind(&node_data.kind))
+    }
+
+    #[test]
+    fn generated_graphs_are_well_formed() {
+        let (mut gammas, mut thetas) = (0, 0);
+        for ncx in arbitrary_graphs(200) {
+            assert_eq!(Ok(()), ncx.verify());
+            gammas += has_node(&ncx, |kind| matches!(kind, NodeKind::Gamma { .. })) as usize;
+            thetas += has_node(&ncx, |kind| matches!(kind, NodeKind::Theta { .. })) as usize;
+        }
+        assert!(gammas > 0 && thetas > 0);
+    }
+
+    #[test]
+    fn passes_keep_generated_graphs_well_formed() {
+        for ncx in arbitrary_graphs(200) {
+            ncx.global_value_numbering();
+            ncx.remove_dead_state_edges();
+            ncx.push_into_gammas();
+            ncx.pull_out_of_gammas();
+            assert_eq!(Ok(()), ncx.verify());
+        }
+    }
+}
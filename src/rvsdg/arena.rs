@@ -0,0 +1,147 @@
+use std::{
+    cell::{Cell, RefCell},
+    iter::{Flatten, FromIterator},
+    ops::Index,
+    vec,
+};
+
+/// How many values a chunk holds. Chunks are made with room for exactly as
+/// many, so they never reallocate.
+const CHUNK_LEN: usize = 256;
+
+/// Append-only storage of the nodes and regions of a graph.
+///
+/// Values can be pushed while references to earlier ones are held, so
+/// reading a node never conflicts with creating another. The chunks are
+/// still kept in a `RefCell`, but it's only borrowed within each method,
+/// never across calls. Chunks have a fixed capacity, which means pushing
+/// never moves the values already stored, and that is what lets `get` hand
+/// out references outliving its borrow of the chunks, in the arena's one
+/// `unsafe` block. Values are only dropped along with the arena.
+///
+/// Only the storage is append-only: the graph keeps each node and region
+/// behind a `RefCell` of its own, so mutating one still conflicts with
+/// holding a reference to that same one.
+pub(super) struct Arena<T> {
+    chunks: RefCell<Vec<Vec<T>>>,
+    len: Cell<usize>,
+}
+
+impl<T> Arena<T> {
+    pub(super) fn new() -> Arena<T> {
+        Arena {
+            chunks: RefCell::new(vec![]),
+            len: Cell::new(0),
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    /// Makes room for at least `additional` more values, so that pushing
+    /// them doesn't allocate.
+    pub(super) fn reserve(&self, additional: usize) {
+        let needed = (self.len() + additional).div_ceil(CHUNK_LEN);
+        let mut chunks = self.chunks.borrow_mut();
+        while chunks.len() < needed {
+            chunks.push(Vec::with_capacity(CHUNK_LEN));
+        }
+    }
+
+    /// Adds `value` after the others, returning its index.
+    pub(super) fn push(&self, value: T) -> usize {
+        let index = self.len();
+        let mut chunks = self.chunks.borrow_mut();
+        if index == chunks.len() * CHUNK_LEN {
+            chunks.push(Vec::with_capacity(CHUNK_LEN));
+        }
+        chunks[index / CHUNK_LEN].push(value);
+        self.len.set(index + 1);
+        index
+    }
+
+    pub(super) fn get(&self, index: usize) -> Option<&T> {
+        let chunks = self.chunks.borrow();
+        let value: *const T = chunks.get(index / CHUNK_LEN)?.get(index % CHUNK_LEN)?;
+        // SAFETY: the value is never moved, as its chunk never grows past
+        // the capacity it was made with, and isn't dropped or borrowed
+        // mutably before the arena is.
+        Some(unsafe { &*value })
+    }
+
+    /// The values pushed so far, in the order they were pushed.
+    pub(super) fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len()).map(move |index| &self[index])
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Arena<T> {
+        Arena::new()
+    }
+}
+
+impl<T> Index<usize> for Arena<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        self.get(index)
+            .unwrap_or_else(|| panic!("index {} is out of bounds", index))
+    }
+}
+
+impl<T> IntoIterator for Arena<T> {
+    type Item = T;
+    type IntoIter = Flatten<vec::IntoIter<Vec<T>>>;
+
+    /// Takes the values out in the order they were pushed.
+    fn into_iter(self) -> Self::IntoIter {
+        self.chunks.into_inner().into_iter().flatten()
+    }
+}
+
+impl<T> FromIterator<T> for Arena<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Arena<T> {
+        let arena = Arena::new();
+        for value in iter {
+            arena.push(value);
+        }
+        arena
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Arena, CHUNK_LEN};
+
+    #[test]
+    fn pushing_while_values_are_borrowed() {
+        let arena = Arena::new();
+        arena.push(String::from("first"));
+        let first = &arena[0];
+        for index in 1..3 * CHUNK_LEN {
+            assert_eq!(index, arena.push(index.to_string()));
+        }
+        assert_eq!("first", first);
+        assert_eq!(3 * CHUNK_LEN, arena.len());
+        assert_eq!(Some("256"), arena.get(CHUNK_LEN).map(String::as_str));
+        assert_eq!(None, arena.get(3 * CHUNK_LEN));
+        assert_eq!(arena.iter().skip(1).take(2).collect::<Vec<_>>(), ["1", "2"]);
+    }
+
+    #[test]
+    fn pushing_into_reserved_chunks() {
+        let arena = Arena::new();
+        arena.push(0);
+        arena.reserve(2 * CHUNK_LEN);
+        assert_eq!(1, arena.len());
+        assert_eq!(3, arena.chunks.borrow().len());
+        for index in 1..3 * CHUNK_LEN {
+            arena.push(index);
+        }
+        assert_eq!(3, arena.chunks.borrow().len());
+        assert!(arena.iter().copied().eq(0..3 * CHUNK_LEN));
+        assert!(arena.into_iter().eq(0..3 * CHUNK_LEN));
+    }
+}
@@ -0,0 +1,196 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, UserId};
+use std::collections::HashSet;
+
+/// A point in a region at which operands are about to be used.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InsertionPoint {
+    /// The inputs of an existing node.
+    Node(NodeId),
+    /// A new node appended to a region.
+    Region(RegionId),
+}
+
+/// An origin that may legally be used as an operand at an insertion point.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AvailableOrigin {
+    pub origin: OriginId,
+    pub kind: PortKind,
+    /// For arguments of entry variables and invariant loop variables, the
+    /// origin in the enclosing region whose value they carry.
+    pub outer: Option<OriginId>,
+}
+
+impl<S> NodeCtxt<S> {
+    /// Lists the origins usable as operands at `point`: the arguments of its
+    /// region, and the outputs of the nodes in its region that don't depend
+    /// on the node at `point`, which would otherwise form a cycle.
+    ///
+    /// Values from enclosing regions are only usable through arguments, in
+    /// which case the outer origin they carry is reported as well.
+    pub fn available_origins(&self, point: InsertionPoint) -> Vec<AvailableOrigin> {
+        let (region, dependents) = match point {
+            InsertionPoint::Node(node) => (
+                self.node_data(node).outer_region,
+                self.dependent_nodes(node),
+            ),
+            InsertionPoint::Region(region) => (region, HashSet::new()),
+        };
+
+        let mut available = vec![];
+
+        for (index, arg) in self.region_data(region).args.iter().enumerate() {
+            let origin = OriginId::argument(region, index);
+            available.push(AvailableOrigin {
+                origin,
+                kind: arg.kind,
+                outer: self.invariant_outer_origin(origin),
+            });
+        }
+
+        for (node_index, node_data) in self.nodes.iter().map(|node| node.borrow()).enumerate() {
+            let node = NodeId::new(node_index);
+            if node_data.outer_region != region || dependents.contains(&node) {
+                continue;
+            }
+            for (index, out) in self.output_ports.get(&node_data.outs).iter().enumerate() {
+                available.push(AvailableOrigin {
+                    origin: OriginId::output(node, index),
+                    kind: out.kind,
+                    outer: None,
+                });
+            }
+        }
+
+        available
+    }
+
+    /// Collects `node` and every node that transitively uses its outputs.
+    fn dependent_nodes(&self, node: NodeId) -> HashSet<NodeId> {
+        let mut dependents = HashSet::new();
+        let mut worklist = vec![node];
+
+        while let Some(node) = worklist.pop() {
+            if !dependents.insert(node) {
+                continue;
+            }
+            for index in 0..self.node_data(node).outs.len() {
+                let users = self.origin_ref(OriginId::output(node, index)).users();
+                worklist.extend(users.filter_map(|user| user.id().node_id()));
+            }
+        }
+
+        dependents
+    }
+
+    /// The origin an argument carries from its enclosing region, provided it
+    /// holds that same value everywhere in its region.
+    fn invariant_outer_origin(&self, arg: OriginId) -> Option<OriginId> {
+        let (region, index) = match arg {
+            OriginId::Arg { region, index } => (region, index),
+            OriginId::Out { .. } => return None,
+        };
+        let source = self.origin_data(arg).source?;
+        let outer = self.user_data(source).origin.get()?;
+
+        let node = self.region_data(region).node?;
+        if let NodeKind::Theta { .. } = self.node_data(node).kind {
+            // A loop variable only keeps its initial value if it's passed
+            // unchanged to the next iteration.
+            let result = UserId::Res {
+                region,
+                index: index + 1,
+            };
+            if self.user_data(result).origin.get() != Some(arg) {
+                return None;
+            }
+        }
+
+        Some(outer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AvailableOrigin, InsertionPoint};
+    use crate::rvsdg::{testing::Op, NodeCtxt, OriginId, PortKind};
+
+    fn origins(available: &[AvailableOrigin]) -> Vec<OriginId> {
+        available.iter().map(|available| available.origin).collect()
+    }
+
+    #[test]
+    fn excludes_dependent_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let n2 = ncx.node_builder(Op::Neg).operand(n1.val_out(0)).finish();
+        let n3 = ncx.mk_node(Op::Lit(3));
+
+        assert_eq!(
+            vec![n0.val_out(0).id(), n3.val_out(0).id()],
+            origins(&ncx.available_origins(InsertionPoint::Node(n1.id())))
+        );
+        assert_eq!(
+            vec![
+                n0.val_out(0).id(),
+                n1.val_out(0).id(),
+                n2.val_out(0).id(),
+                n3.val_out(0).id(),
+            ],
+            origins(&ncx.available_origins(InsertionPoint::Region(ncx.root_region())))
+        );
+    }
+
+    #[test]
+    fn arguments_carry_outer_origins() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::Lit(1));
+
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let args = gamma.entry_var(n1.val_out(0));
+        let neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+
+        let available = ncx.available_origins(InsertionPoint::Region(gamma.branch(0)));
+        assert_eq!(
+            vec![
+                AvailableOrigin {
+                    origin: args[0].id(),
+                    kind: PortKind::Val,
+                    outer: Some(n1.val_out(0).id()),
+                },
+                AvailableOrigin {
+                    origin: neg.val_out(0).id(),
+                    kind: PortKind::Val,
+                    outer: None,
+                },
+            ],
+            available
+        );
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (invariant, _) = theta.loop_var(n0.val_out(0));
+        let (variant, _) = theta.loop_var(n1.val_out(0));
+        let neg = ncx
+            .node_builder_in(theta.body(), Op::Neg)
+            .operand(variant)
+            .finish();
+        theta.set_next(variant, neg.val_out(0));
+        let body = theta.body();
+        theta.finish(invariant);
+
+        let available = ncx.available_origins(InsertionPoint::Node(neg.id()));
+        assert_eq!(Some(n0.val_out(0).id()), available[0].outer);
+        assert_eq!(None, available[1].outer);
+        assert_eq!(
+            vec![invariant.id(), variant.id()],
+            origins(&ncx.available_origins(InsertionPoint::Node(neg.id())))
+        );
+        assert_eq!(3, ncx.available_origins(InsertionPoint::Region(body)).len());
+    }
+}
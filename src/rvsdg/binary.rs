@@ -0,0 +1,900 @@
+//! A compact binary encoding of graphs, for graphs too big to go through
+//! JSON.
+//!
+//! Unlike JSON dumps, binary ones keep removed nodes and regions around, so
+//! a decoded graph has the same node and region ids as the encoded one, and
+//! the same intern table. Integers are LEB128 varints, and ops are encoded
+//! by the caller into length-prefixed bytes.
+
+use super::{
+    InnerRegionList, NodeCtxt, NodeData, NodeId, NodeKind, OriginData, OriginId, PortKind,
+    PortPool, RegionData, RegionId, Sig, UserData, UserId,
+};
+use std::{
+    cell::RefCell,
+    fmt,
+    hash::Hash,
+    io::{self, Read, Write},
+};
+
+const MAGIC: &[u8; 4] = b"RVSD";
+
+/// The version of the encoding, bumped whenever it changes in a way older
+/// encodings can't be decoded with.
+const FORMAT_VERSION: u32 = 1;
+
+/// How many bytes are encoded before they're written out.
+const CHUNK_LEN: usize = 1 << 16;
+
+/// Why a graph couldn't be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    /// The input doesn't start like an encoded graph.
+    NotAGraph,
+    /// The graph was encoded with a format this version can't decode.
+    UnsupportedVersion(u32),
+    /// The input ends before the graph does.
+    Truncated,
+    /// A tag, or a number used as one, that means nothing where it was
+    /// found.
+    BadTag(u64),
+    /// The op of a node couldn't be decoded.
+    UnknownOp(usize),
+    /// A node that isn't in the graph is referred to.
+    UnknownNode(usize),
+    /// A region that isn't in the graph is referred to.
+    UnknownRegion(usize),
+    /// A port that its node or region doesn't have is referred to.
+    UnknownPort(String),
+    /// An edge connects ports of different kinds or in different regions,
+    /// or a user that's already connected.
+    BadEdge(String),
+    /// The graph was decoded, but it isn't well-formed.
+    Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(err) => write!(f, "{}", err),
+            DecodeError::NotAGraph => write!(f, "not an encoded graph"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            DecodeError::Truncated => write!(f, "unexpected end of input"),
+            DecodeError::BadTag(tag) => write!(f, "bad tag {}", tag),
+            DecodeError::UnknownOp(node) => write!(f, "undecodable op of node {}", node),
+            DecodeError::UnknownNode(node) => write!(f, "unknown node {}", node),
+            DecodeError::UnknownRegion(region) => write!(f, "unknown region {}", region),
+            DecodeError::UnknownPort(port) => write!(f, "unknown port {}", port),
+            DecodeError::BadEdge(edge) => write!(f, "bad edge {}", edge),
+            DecodeError::Invalid(violations) => write!(f, "malformed graph: {}", violations),
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Encodes the whole graph, removed nodes and regions included, calling
+    /// `encode_op` to encode each op.
+    ///
+    /// Frozen regions are encoded like any other, and come out thawed.
+    pub fn write_binary<F>(&self, out: &mut dyn Write, mut encode_op: F) -> io::Result<()>
+    where
+        F: FnMut(&S, &mut Vec<u8>),
+    {
+        let mut enc = Encoder {
+            out,
+            buf: Vec::with_capacity(CHUNK_LEN),
+        };
+        enc.buf.extend_from_slice(MAGIC);
+        enc.varint(u64::from(FORMAT_VERSION));
+
+        let num_nodes = self.nodes.len();
+        let num_regions = self.regions.len();
+
+        enc.varint(num_regions as u64);
+        for pos in 0..num_regions {
+            let region_data = self.region_data(RegionId::new(pos));
+            enc.bool(region_data.removed);
+            enc.opt_id(region_data.node.map(NodeId::index));
+            enc.varint(region_data.sequence_index as u64);
+            enc.varint(region_data.args.len() as u64);
+            for arg in &region_data.args {
+                enc.port_kind(arg.kind);
+                enc.opt_user(arg.source);
+            }
+            enc.varint(region_data.res.len() as u64);
+            for res in &region_data.res {
+                enc.port_kind(res.kind);
+                enc.opt_origin(res.sink);
+            }
+            enc.varint(region_data.nodes.len() as u64);
+            for node_id in &region_data.nodes {
+                enc.varint(node_id.0 as u64);
+            }
+            enc.flush_if_full()?;
+        }
+
+        let mut op_buf = vec![];
+        enc.varint(num_nodes as u64);
+        for pos in 0..num_nodes {
+            let node_data = self.node_data(NodeId::new(pos));
+            enc.bool(node_data.removed);
+            enc.varint(node_data.outer_region.0 as u64);
+            match node_data.kind {
+                NodeKind::Op(ref op) => {
+                    enc.varint(0);
+                    op_buf.clear();
+                    encode_op(op, &mut op_buf);
+                    enc.bytes(&op_buf);
+                }
+                NodeKind::Apply {
+                    arg_val_ins,
+                    arg_st_ins,
+                    region_val_res,
+                    region_st_res,
+                } => {
+                    enc.varint(1);
+                    for &n in &[arg_val_ins, arg_st_ins, region_val_res, region_st_res] {
+                        enc.varint(n as u64);
+                    }
+                }
+                NodeKind::Gamma {
+                    val_ins,
+                    val_outs,
+                    st_ins,
+                    st_outs,
+                } => {
+                    enc.varint(2);
+                    for &n in &[val_ins, val_outs, st_ins, st_outs] {
+                        enc.varint(n as u64);
+                    }
+                }
+                NodeKind::Theta {
+                    val_loop_vars,
+                    st_loop_vars,
+                } => {
+                    enc.varint(3);
+                    enc.varint(val_loop_vars as u64);
+                    enc.varint(st_loop_vars as u64);
+                }
+                NodeKind::Lambda { params, ctx_vars } => {
+                    enc.varint(5);
+                    enc.varint(params as u64);
+                    enc.varint(ctx_vars as u64);
+                }
+                NodeKind::Omega { imports, exports } => {
+                    enc.varint(4);
+                    enc.varint(imports as u64);
+                    enc.varint(exports as u64);
+                }
+            }
+            enc.varint(node_data.ins.len() as u64);
+            for user in node_data.ins.iter() {
+                enc.port_kind(user.kind);
+            }
+            enc.varint(node_data.outs.len() as u64);
+            for origin in node_data.outs.iter() {
+                enc.port_kind(origin.kind);
+            }
+            drop(node_data);
+            let inner_regions = self.inner_regions(NodeId::new(pos));
+            enc.varint(inner_regions.len() as u64);
+            for region_id in inner_regions {
+                enc.varint(region_id.0 as u64);
+            }
+            enc.flush_if_full()?;
+        }
+
+        // The users of each origin, in order, so that decoding connects them
+        // in the same order.
+        for origin_id in self.all_origins() {
+            let users: Vec<UserId> = self
+                .origin_ref(origin_id)
+                .users()
+                .map(|user| user.id())
+                .collect();
+            enc.varint(users.len() as u64);
+            for user_id in users {
+                enc.user(user_id);
+            }
+            enc.flush_if_full()?;
+        }
+
+        let mut names: Vec<(OriginId, String)> = self
+            .origin_names
+            .borrow()
+            .iter()
+            .map(|(&origin_id, name)| (origin_id, name.clone()))
+            .collect();
+        names.sort_by_key(|&(origin_id, _)| origin_key(origin_id));
+        enc.varint(names.len() as u64);
+        for (origin_id, name) in names {
+            enc.origin(origin_id);
+            enc.bytes(name.as_bytes());
+        }
+
+        let mut interned: Vec<usize> = self
+            .interned_nodes
+            .borrow()
+            .values()
+            .map(|node_id| node_id.index())
+            .collect();
+        interned.sort_unstable();
+        enc.varint(interned.len() as u64);
+        for node in interned {
+            enc.varint(node as u64);
+        }
+
+        enc.out.write_all(&enc.buf)?;
+        enc.out.flush()
+    }
+
+    /// Decodes a graph encoded by `write_binary`, calling `decode_op` to
+    /// decode each op from the bytes it was encoded into.
+    ///
+    /// The graph is verified before it's returned.
+    pub fn read_binary<F>(
+        input: &mut dyn Read,
+        mut decode_op: F,
+    ) -> Result<NodeCtxt<S>, DecodeError>
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(&[u8]) -> Option<S>,
+    {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes).map_err(DecodeError::Io)?;
+        let mut dec = Decoder {
+            bytes: &bytes,
+            pos: 0,
+        };
+        if !bytes.starts_with(MAGIC) {
+            return Err(DecodeError::NotAGraph);
+        }
+        dec.pos = MAGIC.len();
+        let version = dec.varint()?;
+        if version != u64::from(FORMAT_VERSION) {
+            return Err(DecodeError::UnsupportedVersion(version as u32));
+        }
+
+        let num_regions = dec.len()?;
+        let mut regions = Vec::with_capacity(num_regions);
+        for _ in 0..num_regions {
+            let removed = dec.bool()?;
+            let node = dec.opt_id()?.map(NodeId::new);
+            let mut region_data = RegionData::new(node, dec.len()?);
+            region_data.removed = removed;
+            for _ in 0..dec.len()? {
+                let kind = dec.port_kind()?;
+                let source = dec.opt_user()?;
+                region_data.args.push(OriginData {
+                    kind,
+                    source,
+                    ..OriginData::default()
+                });
+            }
+            for _ in 0..dec.len()? {
+                let kind = dec.port_kind()?;
+                let sink = dec.opt_origin()?;
+                region_data.res.push(UserData {
+                    kind,
+                    sink,
+                    ..UserData::default()
+                });
+            }
+            for _ in 0..dec.len()? {
+                region_data.nodes.push(NodeId::new(dec.len()?));
+            }
+            regions.push(region_data);
+        }
+        if regions.is_empty() {
+            return Err(DecodeError::UnknownRegion(0));
+        }
+
+        let num_nodes = dec.len()?;
+        let input_ports = PortPool::new();
+        let output_ports = PortPool::new();
+        let mut nodes = Vec::with_capacity(num_nodes);
+        let mut inner_regions = Vec::with_capacity(num_nodes);
+        for pos in 0..num_nodes {
+            let removed = dec.bool()?;
+            let outer_region = dec.len()?;
+            if outer_region >= regions.len() {
+                return Err(DecodeError::UnknownRegion(outer_region));
+            }
+            let kind = match dec.varint()? {
+                0 => NodeKind::Op(decode_op(dec.bytes()?).ok_or(DecodeError::UnknownOp(pos))?),
+                1 => NodeKind::Apply {
+                    arg_val_ins: dec.len()?,
+                    arg_st_ins: dec.len()?,
+                    region_val_res: dec.len()?,
+                    region_st_res: dec.len()?,
+                },
+                2 => NodeKind::Gamma {
+                    val_ins: dec.len()?,
+                    val_outs: dec.len()?,
+                    st_ins: dec.len()?,
+                    st_outs: dec.len()?,
+                },
+                3 => NodeKind::Theta {
+                    val_loop_vars: dec.len()?,
+                    st_loop_vars: dec.len()?,
+                },
+                4 => NodeKind::Omega {
+                    imports: dec.len()?,
+                    exports: dec.len()?,
+                },
+                5 => NodeKind::Lambda {
+                    params: dec.len()?,
+                    ctx_vars: dec.len()?,
+                },
+                tag => return Err(DecodeError::BadTag(tag)),
+            };
+            let mut ins = input_ports.reserve(0);
+            for _ in 0..dec.len()? {
+                let kind = dec.port_kind()?;
+                input_ports.push(
+                    &mut ins,
+                    UserData {
+                        kind,
+                        ..UserData::default()
+                    },
+                );
+            }
+            let mut outs = output_ports.reserve(0);
+            for _ in 0..dec.len()? {
+                let kind = dec.port_kind()?;
+                output_ports.push(
+                    &mut outs,
+                    OriginData {
+                        kind,
+                        ..OriginData::default()
+                    },
+                );
+            }
+            let mut node_regions = vec![];
+            for _ in 0..dec.len()? {
+                node_regions.push(dec.len()?);
+            }
+            inner_regions.push(node_regions);
+            nodes.push(NodeData {
+                ins,
+                outs,
+                inner_regions: Default::default(),
+                outer_region: RegionId::new(outer_region),
+                kind,
+                removed,
+            });
+        }
+
+        for (pos, region_data) in regions.iter().enumerate() {
+            if !region_data.removed && (pos == 0) != region_data.node.is_none() {
+                return Err(DecodeError::Invalid(format!(
+                    "misplaced root region {}",
+                    pos
+                )));
+            }
+            if let Some(node_id) = region_data.node {
+                if node_id.index() >= nodes.len() {
+                    return Err(DecodeError::UnknownNode(node_id.index()));
+                }
+            }
+            if let Some(&node_id) = region_data
+                .nodes
+                .iter()
+                .find(|node_id| node_id.index() >= nodes.len())
+            {
+                return Err(DecodeError::UnknownNode(node_id.index()));
+            }
+        }
+
+        // Chains the regions of each node in the order they were encoded in.
+        let mut chained = vec![false; regions.len()];
+        for (pos, node_regions) in inner_regions.iter().enumerate() {
+            let mut prev: Option<RegionId> = None;
+            for &region in node_regions {
+                let region_data = regions
+                    .get(region)
+                    .ok_or(DecodeError::UnknownRegion(region))?;
+                if region_data.node != Some(NodeId::new(pos)) || chained[region] {
+                    return Err(DecodeError::Invalid(format!(
+                        "region {} is misplaced in node {}",
+                        region, pos
+                    )));
+                }
+                chained[region] = true;
+                if let Some(prev) = prev {
+                    regions[prev.index()]
+                        .next_region
+                        .set(Some(RegionId::new(region)));
+                    regions[region].prev_region.set(Some(prev));
+                }
+                prev = Some(RegionId::new(region));
+            }
+            if let (Some(&first), Some(&last)) = (node_regions.first(), node_regions.last()) {
+                nodes[pos].inner_regions.set(Some(InnerRegionList {
+                    first_region: RegionId::new(first),
+                    last_region: RegionId::new(last),
+                }));
+            }
+        }
+
+        let ncx = NodeCtxt {
+            nodes: nodes.into_iter().map(RefCell::new).collect(),
+            regions: regions.into_iter().map(RefCell::new).collect(),
+            input_ports,
+            output_ports,
+            ..NodeCtxt::new()
+        };
+
+        for origin_id in ncx.all_origins() {
+            let origin_kind = ncx.origin_data(origin_id).kind;
+            for _ in 0..dec.len()? {
+                let user_id = dec.user()?;
+                match ncx.try_user_kind(user_id) {
+                    Some((kind, false)) if kind == origin_kind => {}
+                    Some(_) => {
+                        return Err(DecodeError::BadEdge(format!(
+                            "{:?} -> {:?}",
+                            origin_id, user_id
+                        )))
+                    }
+                    None => return Err(DecodeError::UnknownPort(format!("{:?}", user_id))),
+                }
+                ncx.try_connect_ports(user_id, origin_id).map_err(|err| {
+                    DecodeError::BadEdge(format!("{:?} -> {:?}: {}", origin_id, user_id, err))
+                })?;
+            }
+        }
+        for region_data in ncx.regions.iter().map(|region| region.borrow()) {
+            if let Some(user_id) = region_data.args.iter().find_map(|arg| arg.source) {
+                ncx.try_user_kind(user_id)
+                    .ok_or_else(|| DecodeError::UnknownPort(format!("{:?}", user_id)))?;
+            }
+            if let Some(origin_id) = region_data.res.iter().find_map(|res| res.sink) {
+                ncx.try_origin_kind(origin_id)
+                    .ok_or_else(|| DecodeError::UnknownPort(format!("{:?}", origin_id)))?;
+            }
+        }
+
+        for _ in 0..dec.len()? {
+            let origin_id = dec.origin()?;
+            ncx.try_origin_kind(origin_id)
+                .ok_or_else(|| DecodeError::UnknownPort(format!("{:?}", origin_id)))?;
+            let name = String::from_utf8(dec.bytes()?.to_vec())
+                .map_err(|_| DecodeError::Invalid("origin name isn't UTF-8".to_owned()))?;
+            ncx.set_origin_name(origin_id, name);
+        }
+
+        for _ in 0..dec.len()? {
+            let node = dec.len()?;
+            if node >= num_nodes || ncx.node_data(NodeId::new(node)).removed {
+                return Err(DecodeError::UnknownNode(node));
+            }
+            let node_term = ncx.node_term(NodeId::new(node)).ok_or_else(|| {
+                DecodeError::Invalid(format!("interned node {} has unconnected inputs", node))
+            })?;
+            ncx.interned_nodes
+                .borrow_mut()
+                .insert(node_term, NodeId::new(node));
+        }
+
+        if dec.pos != bytes.len() {
+            return Err(DecodeError::Invalid("trailing bytes".to_owned()));
+        }
+        ncx.verify()
+            .map_err(|violations| DecodeError::Invalid(format!("{:?}", violations)))?;
+        Ok(ncx)
+    }
+
+    /// The outputs of every node followed by the arguments of every region,
+    /// in the order their users are encoded in.
+    fn all_origins(&self) -> Vec<OriginId> {
+        let mut origins = vec![];
+        for (pos, node_data) in self.nodes.iter().map(|node| node.borrow()).enumerate() {
+            origins.extend(
+                (0..node_data.outs.len()).map(|index| OriginId::output(NodeId::new(pos), index)),
+            );
+        }
+        for (pos, region_data) in self
+            .regions
+            .iter()
+            .map(|region| region.borrow())
+            .enumerate()
+        {
+            origins.extend(
+                (0..region_data.args.len())
+                    .map(|index| OriginId::argument(RegionId::new(pos), index)),
+            );
+        }
+        origins
+    }
+
+    /// The kind of a user, and whether it's connected, if it exists.
+    fn try_user_kind(&self, user_id: UserId) -> Option<(PortKind, bool)> {
+        let kind = |user: &UserData| (user.kind, user.origin.get().is_some());
+        match user_id {
+            UserId::In { node, index } => {
+                let node_data = self.nodes.get(node.index())?.borrow();
+                self.input_ports
+                    .get(&node_data.ins)
+                    .get(usize::from(index))
+                    .map(kind)
+            }
+            UserId::Res { region, index } => {
+                let region_data = self.regions.get(region.index())?.borrow();
+                region_data.res.get(usize::from(index)).map(kind)
+            }
+        }
+    }
+
+    /// The kind of an origin, if it exists.
+    fn try_origin_kind(&self, origin_id: OriginId) -> Option<PortKind> {
+        match origin_id {
+            OriginId::Out { node, index } => {
+                let node_data = self.nodes.get(node.index())?.borrow();
+                self.output_ports
+                    .get(&node_data.outs)
+                    .get(usize::from(index))
+                    .map(|origin| origin.kind)
+            }
+            OriginId::Arg { region, index } => {
+                let region_data = self.regions.get(region.index())?.borrow();
+                region_data
+                    .args
+                    .get(usize::from(index))
+                    .map(|origin| origin.kind)
+            }
+        }
+    }
+}
+
+/// Orders origins so that names are encoded in the same order every time.
+fn origin_key(origin_id: OriginId) -> (usize, usize, usize) {
+    match origin_id {
+        OriginId::Out { node, index } => (0, node.index(), usize::from(index)),
+        OriginId::Arg { region, index } => (1, region.index(), usize::from(index)),
+    }
+}
+
+struct Encoder<'o> {
+    out: &'o mut dyn Write,
+    buf: Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn flush_if_full(&mut self) -> io::Result<()> {
+        if self.buf.len() >= CHUNK_LEN {
+            self.out.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    fn varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.buf.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        self.buf.push(n as u8);
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.buf.push(b as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn port_kind(&mut self, kind: PortKind) {
+        self.buf.push(match kind {
+            PortKind::Val => 0,
+            PortKind::St => 1,
+        });
+    }
+
+    /// Encodes `None` as 0, and ids shifted by one.
+    fn opt_id(&mut self, id: Option<usize>) {
+        self.varint(id.map_or(0, |id| id as u64 + 1));
+    }
+
+    /// Encodes `None` as 0, and ports with a tag saying what they are
+    /// followed by the ids and indices of their nodes or regions.
+    fn opt_user(&mut self, user_id: Option<UserId>) {
+        match user_id {
+            Some(UserId::In { node, index }) => self.pair(1, node.index(), usize::from(index)),
+            Some(UserId::Res { region, index }) => self.pair(2, region.index(), usize::from(index)),
+            None => self.varint(0),
+        }
+    }
+
+    fn opt_origin(&mut self, origin_id: Option<OriginId>) {
+        match origin_id {
+            Some(OriginId::Out { node, index }) => self.pair(1, node.index(), usize::from(index)),
+            Some(OriginId::Arg { region, index }) => {
+                self.pair(2, region.index(), usize::from(index))
+            }
+            None => self.varint(0),
+        }
+    }
+
+    fn user(&mut self, user_id: UserId) {
+        self.opt_user(Some(user_id));
+    }
+
+    fn origin(&mut self, origin_id: OriginId) {
+        self.opt_origin(Some(origin_id));
+    }
+
+    fn pair(&mut self, tag: u64, id: usize, index: usize) {
+        self.varint(tag);
+        self.varint(id as u64);
+        self.varint(index as u64);
+    }
+}
+
+struct Decoder<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Decoder<'b> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(DecodeError::BadTag(n))
+    }
+
+    /// A count, index or id, which can't be bigger than the input without
+    /// it being truncated.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let n = self.varint()?;
+        if n > self.bytes.len() as u64 * 8 {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(n as usize)
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::BadTag(u64::from(tag))),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'b [u8], DecodeError> {
+        let len = self.len()?;
+        let end = self.pos.checked_add(len).ok_or(DecodeError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(DecodeError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn port_kind(&mut self) -> Result<PortKind, DecodeError> {
+        match self.byte()? {
+            0 => Ok(PortKind::Val),
+            1 => Ok(PortKind::St),
+            tag => Err(DecodeError::BadTag(u64::from(tag))),
+        }
+    }
+
+    fn opt_id(&mut self) -> Result<Option<usize>, DecodeError> {
+        match self.len()? {
+            0 => Ok(None),
+            id => Ok(Some(id - 1)),
+        }
+    }
+
+    fn user(&mut self) -> Result<UserId, DecodeError> {
+        self.opt_user()?.ok_or(DecodeError::BadTag(0))
+    }
+
+    fn origin(&mut self) -> Result<OriginId, DecodeError> {
+        self.opt_origin()?.ok_or(DecodeError::BadTag(0))
+    }
+
+    fn opt_user(&mut self) -> Result<Option<UserId>, DecodeError> {
+        match self.varint()? {
+            0 => Ok(None),
+            1 => {
+                let (node, index) = self.port()?;
+                Ok(Some(UserId::input(NodeId::new(node), index)))
+            }
+            2 => {
+                let (region, index) = self.port()?;
+                Ok(Some(UserId::result(RegionId::new(region), index)))
+            }
+            tag => Err(DecodeError::BadTag(tag)),
+        }
+    }
+
+    fn opt_origin(&mut self) -> Result<Option<OriginId>, DecodeError> {
+        match self.varint()? {
+            0 => Ok(None),
+            1 => {
+                let (node, index) = self.port()?;
+                Ok(Some(OriginId::output(NodeId::new(node), index)))
+            }
+            2 => {
+                let (region, index) = self.port()?;
+                Ok(Some(OriginId::argument(RegionId::new(region), index)))
+            }
+            tag => Err(DecodeError::BadTag(tag)),
+        }
+    }
+
+    /// The id of the node or region of a port, and the index of the port
+    /// in it, which ids and indices have to be small enough to be.
+    fn port(&mut self) -> Result<(usize, usize), DecodeError> {
+        let (id, index) = (self.len()?, self.len()?);
+        if id > u32::MAX as usize || index > usize::from(u16::MAX) {
+            return Err(DecodeError::UnknownPort(format!("{}:{}", id, index)));
+        }
+        Ok((id, index))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DecodeError;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeId};
+
+    fn encode_op(op: &Op, out: &mut Vec<u8>) {
+        match *op {
+            Op::Lit(n) => {
+                out.push(0);
+                out.extend_from_slice(&(n as u32).to_le_bytes());
+            }
+            Op::Neg => out.push(1),
+            Op::St => out.push(2),
+            Op::Store => out.push(3),
+            op => unimplemented!("encoding {:?}", op),
+        }
+    }
+
+    fn decode_op(bytes: &[u8]) -> Option<Op> {
+        match bytes {
+            &[0, a, b, c, d] => Some(Op::Lit(i64::from(u32::from_le_bytes([a, b, c, d])))),
+            [1] => Some(Op::Neg),
+            [2] => Some(Op::St),
+            [3] => Some(Op::Store),
+            _ => None,
+        }
+    }
+
+    fn encode(ncx: &NodeCtxt<Op>) -> Vec<u8> {
+        let mut buffer = vec![];
+        ncx.write_binary(&mut buffer, encode_op).unwrap();
+        buffer
+    }
+
+    fn text(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = vec![];
+        ncx.print_text(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn graph() -> NodeCtxt<Op> {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let unused = ncx.mk_node(Op::Lit(7));
+        let st = ncx.mk_node(Op::St);
+        ncx.set_origin_name(n1.val_out(0).id(), "x");
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n1.val_out(0));
+        let (st_arg, st_out) = theta.loop_state(st.st_out(0));
+        let gamma = ncx.gamma_builder(x_arg, 2);
+        let args = gamma.entry_var(x_arg);
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let x_next = gamma.exit_var(&[x_neg.val_out(0), args[1]]);
+        gamma.finish();
+        let store = ncx
+            .node_builder_in(theta.body(), Op::Store)
+            .operand(x_arg)
+            .state(st_arg)
+            .finish();
+        theta.set_next(x_arg, x_next);
+        theta.set_next_state(st_arg, store.st_out(0));
+        theta.finish(x_arg);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st_out)
+            .finish();
+        ncx.remove_node(unused.id());
+        ncx
+    }
+
+    #[test]
+    fn round_trip() {
+        let ncx = graph();
+        let bytes = encode(&ncx);
+        let decoded = NodeCtxt::read_binary(&mut bytes.as_slice(), decode_op).unwrap();
+
+        assert_eq!(bytes, encode(&decoded));
+        assert_eq!(text(&ncx), text(&decoded));
+        assert_eq!(ncx.num_nodes(), decoded.num_nodes());
+        assert_eq!(ncx.num_edges(), decoded.num_edges());
+
+        // Ids are kept, removed nodes included, and so is the intern table.
+        assert!(decoded.node_data(NodeId(2)).removed);
+        let n1 = decoded
+            .node_builder(Op::Neg)
+            .operand(decoded.node_ref(NodeId(0)).val_out(0))
+            .finish();
+        assert_eq!(NodeId(1), n1.id());
+        let num_ids = decoded.nodes.len();
+        assert_eq!(NodeId::new(num_ids), decoded.mk_node(Op::Lit(7)).id());
+    }
+
+    #[test]
+    fn malformed_encodings() {
+        let bytes = encode(&graph());
+
+        assert!(matches!(
+            NodeCtxt::read_binary(&mut &b"digraph"[..], decode_op),
+            Err(DecodeError::NotAGraph)
+        ));
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(matches!(
+            NodeCtxt::read_binary(&mut newer.as_slice(), decode_op),
+            Err(DecodeError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            NodeCtxt::read_binary(&mut bytes.as_slice(), |_| None::<Op>),
+            Err(DecodeError::UnknownOp(0))
+        ));
+        for len in 0..bytes.len() {
+            assert!(NodeCtxt::read_binary(&mut &bytes[..len], decode_op).is_err());
+        }
+    }
+
+    #[test]
+    fn cross_region_edges() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let n_lit = ncx.node_builder_in(gamma.branch(0), Op::Lit(2)).finish();
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(n_lit.val_out(0))
+            .finish();
+        gamma.exit_var(&[n_neg.val_out(0)]);
+        gamma.finish();
+        // Skips the region check, to encode an edge into the branch.
+        n_neg.val_in(0).disconnect();
+        ncx.link_user(n_neg.val_in(0).id(), n_x.val_out(0).id());
+
+        let bytes = encode(&ncx);
+        assert!(matches!(
+            NodeCtxt::read_binary(&mut bytes.as_slice(), decode_op),
+            Err(DecodeError::BadEdge(_))
+        ));
+    }
+}
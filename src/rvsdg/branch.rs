@@ -0,0 +1,183 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, Sig, UserId};
+use std::hash::Hash;
+
+/// Ops producing gamma predicates that may be known before running.
+pub trait ConstBranch {
+    /// The branch selected by the predicate this op produces, if it's a
+    /// constant.
+    fn const_branch(&self) -> Option<usize>;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Replaces gamma nodes whose predicate is a constant with a copy of the
+    /// branch it selects, made in the region of the gamma. Returns how many
+    /// gammas were replaced.
+    ///
+    /// Gammas in the copied branches are replaced as well, since they're
+    /// visited after the ones they were copied from.
+    pub fn simplify_const_gammas(&self) -> usize
+    where
+        S: ConstBranch + Sig + Eq + Hash + Clone,
+    {
+        let mut num_simplified = 0;
+        let mut index = 0;
+        while index < self.nodes.len() {
+            let gamma = NodeId::new(index);
+            index += 1;
+            if let Some(branch) = self.const_branch_of(gamma) {
+                self.select_branch(gamma, branch);
+                num_simplified += 1;
+            }
+        }
+        num_simplified
+    }
+
+    /// The branch a gamma always takes, provided its predicate is a constant
+    /// and every port the branch needs is connected.
+    fn const_branch_of(&self, node_id: NodeId) -> Option<usize>
+    where
+        S: ConstBranch,
+    {
+        let node_data = self.node_data(node_id);
+        if node_data.removed {
+            return None;
+        }
+        if !matches!(node_data.kind, NodeKind::Gamma { .. }) {
+            return None;
+        }
+
+        let branch = match node_data.ins[0].origin.get()? {
+            OriginId::Out { node, .. } => match self.node_data(node).kind {
+                NodeKind::Op(ref op) => op.const_branch()?,
+                _ => return None,
+            },
+            OriginId::Arg { .. } => return None,
+        };
+        let branches = self.inner_regions(node_id);
+        let region_data = self.region_data(*branches.get(branch)?);
+
+        // Inputs and results left unconnected have nothing to stand for the
+        // arguments and outputs.
+        if node_data.ins.iter().any(|user| user.origin.get().is_none()) {
+            return None;
+        }
+        let used_outputs = node_data.outs.iter().map(|out| out.users.get().is_some());
+        let results = region_data.res.iter().map(|res| res.origin.get().is_some());
+        if used_outputs
+            .zip(results)
+            .any(|(used, connected)| used && !connected)
+        {
+            return None;
+        }
+        Some(branch)
+    }
+
+    fn select_branch(&self, gamma: NodeId, branch: usize)
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let region_id = self.node_data(gamma).outer_region;
+        let branch = self.inner_regions(gamma)[branch];
+        // Skips the predicate.
+        let num_inputs = self.node_data(gamma).ins.len();
+        let args: Vec<OriginId> = (1..num_inputs)
+            .map(|index| {
+                self.user_data(UserId::input(gamma, index))
+                    .origin
+                    .get()
+                    .unwrap()
+            })
+            .collect();
+
+        let results = self.copy_region(branch, region_id, &args);
+        for (index, result) in results.into_iter().enumerate() {
+            if let Some(result) = result {
+                self.replace_all_users(OriginId::output(gamma, index), result);
+            }
+        }
+        self.remove_node(gamma);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn selecting_the_taken_branch() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(1));
+        let n_param = ncx.mk_node(Op::Param(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_param.val_out(0));
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(1), Op::Neg)
+            .operand(args[1])
+            .finish();
+        let out = gamma.exit_var(&[args[0], n_neg.val_out(0)]);
+        let gamma = gamma.finish();
+        let n_user = ncx.node_builder(Op::Neg).operand(out).finish();
+
+        assert_eq!(1, ncx.simplify_const_gammas());
+        assert!(ncx.node_data(gamma.id()).removed);
+
+        // The negation in the branch was copied out.
+        let n_copy = n_user.val_in(0).origin().producer();
+        assert_eq!(*n_copy.kind(), NodeKind::Op(Op::Neg));
+        assert_eq!(n_copy.val_in(0).origin(), n_param.val_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn nested_gammas() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_param = ncx.mk_node(Op::Param(0));
+        let outer = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let params = outer.entry_var(n_param.val_out(0));
+        let preds = outer.entry_var(n_pred.val_out(0));
+
+        // The inner gamma takes its predicate from the outer one, which only
+        // becomes known once the outer one is gone.
+        let inner = ncx.gamma_builder(preds[0], 2);
+        let args = inner.entry_var(params[0]);
+        let n_sum = ncx
+            .node_builder_in(inner.branch(0), Op::Add)
+            .operand(args[0])
+            .operand(args[0])
+            .finish();
+        let inner_out = inner.exit_var(&[n_sum.val_out(0), args[1]]);
+        inner.finish();
+
+        let out = outer.exit_var(&[inner_out, params[1]]);
+        outer.finish();
+        let n_user = ncx.node_builder(Op::Neg).operand(out).finish();
+
+        assert_eq!(2, ncx.simplify_const_gammas());
+        let n_sum = n_user.val_in(0).origin().producer();
+        assert_eq!(*n_sum.kind(), NodeKind::Op(Op::Add));
+        assert_eq!(n_sum.val_in(0).origin(), n_param.val_out(0));
+        assert_eq!(ncx.region_ref(ncx.root_region()).nodes().count(), 4);
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn unknown_predicates_are_left_alone() {
+        let ncx = NodeCtxt::new();
+
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_pred = ncx.mk_node(Op::Lit(2));
+        let gamma = ncx.gamma_builder(n_param.val_out(0), 2);
+        gamma.entry_var(n_param.val_out(0));
+        gamma.finish();
+
+        // Out of range predicates select no branch either.
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        gamma.entry_var(n_param.val_out(0));
+        gamma.finish();
+
+        assert_eq!(0, ncx.simplify_const_gammas());
+    }
+}
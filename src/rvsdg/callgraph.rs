@@ -0,0 +1,358 @@
+use super::{InlineSite, NodeCtxt, NodeId, NodeKind, NodeMap, RegionId, Sig};
+use std::hash::Hash;
+
+/// Which lambdas the lambdas of the graph may apply, as found by
+/// `NodeCtxt::call_graph`.
+///
+/// There are no phi nodes yet, so a lambda is recursive when it's passed
+/// its own function, or that of a lambda calling it, as a context variable.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CallGraph {
+    lambdas: Vec<NodeId>,
+    callees: NodeMap<Vec<NodeId>>,
+    calls_unknown: NodeMap<bool>,
+    sccs: Vec<Vec<NodeId>>,
+    scc_indices: NodeMap<usize>,
+}
+
+impl CallGraph {
+    /// The lambdas of the graph, in the order they were made.
+    pub fn lambdas(&self) -> &[NodeId] {
+        &self.lambdas
+    }
+
+    /// The lambdas that the apply nodes in the body of `lambda` call, not
+    /// counting those in the bodies of the lambdas nested in it.
+    pub fn callees(&self, lambda: NodeId) -> &[NodeId] {
+        self.callees.get(lambda).map_or(&[], |callees| &callees[..])
+    }
+
+    /// Whether the body of `lambda` applies functions that aren't
+    /// statically known, which may be any lambda.
+    pub fn calls_unknown(&self, lambda: NodeId) -> bool {
+        self.calls_unknown.get(lambda) == Some(&true)
+    }
+
+    /// The strongly connected components of the call graph, each coming
+    /// after the components of the lambdas it calls.
+    pub fn sccs(&self) -> &[Vec<NodeId>] {
+        &self.sccs
+    }
+
+    /// Whether `lambda` may end up calling itself.
+    pub fn is_recursive(&self, lambda: NodeId) -> bool {
+        match self.scc_indices.get(lambda) {
+            Some(&index) => self.sccs[index].len() > 1 || self.callees(lambda).contains(&lambda),
+            None => false,
+        }
+    }
+}
+
+/// Finds the strongly connected components of a call graph, with Tarjan's
+/// algorithm.
+struct Components<'a> {
+    callees: &'a NodeMap<Vec<NodeId>>,
+    indices: NodeMap<usize>,
+    low_links: NodeMap<usize>,
+    stack: Vec<NodeId>,
+    on_stack: NodeMap<bool>,
+    sccs: Vec<Vec<NodeId>>,
+}
+
+impl<'a> Components<'a> {
+    fn visit(&mut self, lambda: NodeId) {
+        let index = self.indices.len();
+        self.indices.insert(lambda, index);
+        self.low_links.insert(lambda, index);
+        self.stack.push(lambda);
+        self.on_stack.insert(lambda, true);
+
+        let callees = self.callees.get(lambda).into_iter().flatten();
+        for &callee in callees {
+            if !self.indices.contains_key(callee) {
+                self.visit(callee);
+                self.low_links[lambda] = self.low_links[lambda].min(self.low_links[callee]);
+            } else if self.on_stack[callee] {
+                self.low_links[lambda] = self.low_links[lambda].min(self.indices[callee]);
+            }
+        }
+
+        if self.low_links[lambda] == index {
+            let mut scc = vec![];
+            loop {
+                let member = self.stack.pop().unwrap();
+                self.on_stack[member] = false;
+                scc.push(member);
+                if member == lambda {
+                    break;
+                }
+            }
+            scc.reverse();
+            self.sccs.push(scc);
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Finds which lambdas each lambda of the graph may apply, following
+    /// the functions of its apply nodes through the structural nodes that
+    /// pass them on unchanged.
+    pub fn call_graph(&self) -> CallGraph {
+        let lambdas: Vec<NodeId> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| {
+                let node_data = node.borrow();
+                !node_data.removed && matches!(node_data.kind, NodeKind::Lambda { .. })
+            })
+            .map(|(index, _)| NodeId::new(index))
+            .collect();
+
+        let mut callees = NodeMap::new();
+        let mut calls_unknown = NodeMap::new();
+        for &lambda in &lambdas {
+            let mut lambda_callees = vec![];
+            let mut unknown = false;
+            for apply in self.applies_in(self.inner_regions(lambda)[0]) {
+                let function = self.node_data(apply).ins[0].origin.get();
+                match function.and_then(|origin_id| self.known_lambda(origin_id)) {
+                    Some(callee) if !lambda_callees.contains(&callee) => {
+                        lambda_callees.push(callee)
+                    }
+                    Some(_) => {}
+                    None => unknown = true,
+                }
+            }
+            callees.insert(lambda, lambda_callees);
+            calls_unknown.insert(lambda, unknown);
+        }
+
+        let mut components = Components {
+            callees: &callees,
+            indices: NodeMap::new(),
+            low_links: NodeMap::new(),
+            stack: vec![],
+            on_stack: NodeMap::new(),
+            sccs: vec![],
+        };
+        for &lambda in &lambdas {
+            if !components.indices.contains_key(lambda) {
+                components.visit(lambda);
+            }
+        }
+        let sccs = components.sccs;
+
+        let mut scc_indices = NodeMap::new();
+        for (index, scc) in sccs.iter().enumerate() {
+            for &lambda in scc {
+                scc_indices.insert(lambda, index);
+            }
+        }
+        CallGraph {
+            lambdas,
+            callees,
+            calls_unknown,
+            sccs,
+            scc_indices,
+        }
+    }
+
+    /// Inlines the apply nodes of the graph whose function is a statically
+    /// known lambda, for which `should_inline` agrees. Returns how many were
+    /// inlined.
+    ///
+    /// The bodies of lambdas are visited bottom-up in the call graph, so the
+    /// calls in the bodies being copied have been inlined already, and the
+    /// regions outside of lambdas last. Calls of recursive lambdas are left
+    /// alone.
+    pub fn inline_bottom_up<F>(&self, mut should_inline: F) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(&InlineSite) -> bool,
+    {
+        let call_graph = self.call_graph();
+        let bodies = call_graph
+            .sccs()
+            .iter()
+            .flatten()
+            .map(|&lambda| self.inner_regions(lambda)[0]);
+        let regions: Vec<RegionId> = bodies.chain(Some(self.root_region())).collect();
+
+        let mut num_inlined = 0;
+        for region_id in regions {
+            for apply in self.applies_in(region_id) {
+                let site = match self.inline_site(apply) {
+                    Some(site) => site,
+                    None => continue,
+                };
+                if !call_graph.is_recursive(site.lambda)
+                    && should_inline(&site)
+                    && self.inline_apply(apply)
+                {
+                    num_inlined += 1;
+                }
+            }
+        }
+        num_inlined
+    }
+
+    /// The apply nodes in `region_id` and the regions nested in it, other
+    /// than the bodies of lambdas.
+    fn applies_in(&self, region_id: RegionId) -> Vec<NodeId> {
+        let mut applies = vec![];
+        let mut regions = vec![region_id];
+        while let Some(region_id) = regions.pop() {
+            for node_id in self.region_nodes(region_id) {
+                match self.node_data(node_id).kind {
+                    NodeKind::Apply { .. } => applies.push(node_id),
+                    NodeKind::Lambda { .. } => {}
+                    _ => regions.extend(self.inner_regions(node_id)),
+                }
+            }
+        }
+        applies
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, PortKind, SigS};
+
+    const UNARY: SigS = SigS {
+        val_ins: 1,
+        val_outs: 1,
+        st_ins: 0,
+        st_outs: 0,
+    };
+
+    #[test]
+    fn calls_and_recursion() {
+        let ncx = NodeCtxt::<Op>::new();
+        let root = ncx.root_region();
+
+        let leaf = ncx.lambda_builder(root, &[PortKind::Val]);
+        let n_sum = ncx
+            .node_builder(Op::Add)
+            .operand(leaf.val_param(0))
+            .operand(leaf.val_param(0))
+            .finish();
+        let leaf = leaf.finish(&[n_sum.val_out(0)], &[]);
+
+        // Calls the leaf twice, and whatever it's passed.
+        let caller = ncx.lambda_builder(root, &[PortKind::Val, PortKind::Val]);
+        let f = caller.ctx_var(leaf.val_out(0));
+        let n_call = ncx
+            .apply_builder(f, UNARY)
+            .operand(caller.val_param(1))
+            .finish();
+        let n_call2 = ncx
+            .apply_builder(f, UNARY)
+            .operand(n_call.val_out(0))
+            .finish();
+        let n_unknown = ncx
+            .apply_builder(caller.val_param(0), UNARY)
+            .operand(n_call2.val_out(0))
+            .finish();
+        let caller = caller.finish(&[n_unknown.val_out(0)], &[]);
+
+        // Two lambdas passed each other's function, as a phi would.
+        let even = ncx.lambda_builder(root, &[PortKind::Val]);
+        let odd = ncx.lambda_builder(root, &[PortKind::Val]);
+        let to_odd = even.ctx_var(odd.node().val_out(0));
+        let to_even = odd.ctx_var(even.node().val_out(0));
+        let to_leaf = odd.ctx_var(leaf.val_out(0));
+        let n_even = ncx
+            .apply_builder(to_odd, UNARY)
+            .operand(even.val_param(0))
+            .finish();
+        let n_odd = ncx
+            .apply_builder(to_even, UNARY)
+            .operand(odd.val_param(0))
+            .finish();
+        let n_leaf = ncx
+            .apply_builder(to_leaf, UNARY)
+            .operand(n_odd.val_out(0))
+            .finish();
+        let even = even.finish(&[n_even.val_out(0)], &[]);
+        let odd = odd.finish(&[n_leaf.val_out(0)], &[]);
+
+        let graph = ncx.call_graph();
+        assert_eq!(
+            &[leaf.id(), caller.id(), even.id(), odd.id()],
+            graph.lambdas()
+        );
+        assert!(graph.callees(leaf.id()).is_empty());
+        assert_eq!(&[leaf.id()], graph.callees(caller.id()));
+        assert_eq!(&[even.id(), leaf.id()], graph.callees(odd.id()));
+        assert!(graph.calls_unknown(caller.id()));
+        assert!(!graph.calls_unknown(odd.id()));
+
+        // Callees come first.
+        assert_eq!(
+            &[
+                vec![leaf.id()],
+                vec![caller.id()],
+                vec![even.id(), odd.id()]
+            ],
+            graph.sccs()
+        );
+        assert!(graph.is_recursive(even.id()));
+        assert!(!graph.is_recursive(caller.id()));
+    }
+
+    #[test]
+    fn inlining_bottom_up() {
+        let ncx = NodeCtxt::<Op>::new();
+        let root = ncx.root_region();
+
+        let leaf = ncx.lambda_builder(root, &[PortKind::Val]);
+        let n_sum = ncx
+            .node_builder(Op::Add)
+            .operand(leaf.val_param(0))
+            .operand(leaf.val_param(0))
+            .finish();
+        let leaf = leaf.finish(&[n_sum.val_out(0)], &[]);
+
+        let middle = ncx.lambda_builder(root, &[PortKind::Val]);
+        let f = middle.ctx_var(leaf.val_out(0));
+        let n_call = ncx
+            .apply_builder(f, UNARY)
+            .operand(middle.val_param(0))
+            .finish();
+        let middle = middle.finish(&[n_call.val_out(0)], &[]);
+
+        // Loops forever, so it's never inlined.
+        let forever = ncx.lambda_builder(root, &[PortKind::Val]);
+        let itself = forever.ctx_var(forever.node().val_out(0));
+        let n_again = ncx
+            .apply_builder(itself, UNARY)
+            .operand(forever.val_param(0))
+            .finish();
+        let forever = forever.finish(&[n_again.val_out(0)], &[]);
+
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_top = ncx
+            .apply_builder(middle.val_out(0), UNARY)
+            .operand(n_one.val_out(0))
+            .finish();
+        let n_loop = ncx
+            .apply_builder(forever.val_out(0), UNARY)
+            .operand(n_top.val_out(0))
+            .finish();
+
+        // The middle lambda has its call inlined before it's inlined
+        // itself, so no call is left.
+        let mut sizes = vec![];
+        let num_inlined = ncx.inline_bottom_up(|site| {
+            sizes.push(site.size);
+            true
+        });
+        assert_eq!(2, num_inlined);
+        assert_eq!(vec![1, 1], sizes);
+        let n_add = n_loop.val_in(1).origin().producer();
+        assert_eq!(NodeKind::Op(Op::Add), *n_add.kind());
+        assert_eq!(n_one.val_out(0), n_add.val_in(0).origin());
+        assert!(ncx.call_graph().callees(middle.id()).is_empty());
+    }
+}
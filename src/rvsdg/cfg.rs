@@ -0,0 +1,512 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, Sig, UserId};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+};
+
+/// A variable of a CFG, defined once. Only values are held in variables,
+/// states are implied by the order of instructions.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Var(pub usize);
+
+/// An index for a block of a CFG.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct BlockId(pub usize);
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Inst<S> {
+    /// Applies an op to `args`, defining its value outputs.
+    Op {
+        op: S,
+        args: Vec<Var>,
+        results: Vec<Var>,
+    },
+    /// Calls the function held in `callee`.
+    Call {
+        callee: Var,
+        args: Vec<Var>,
+        results: Vec<Var>,
+    },
+}
+
+/// A jump to a block, passing it the arguments for its parameters.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Jump {
+    pub block: BlockId,
+    pub args: Vec<Var>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Terminator {
+    Jump(Jump),
+    /// Takes the `n`th jump, where `n` is the value of `on`.
+    Switch {
+        on: Var,
+        jumps: Vec<Jump>,
+    },
+    /// Returns from the CFG with the values of the results of its region.
+    Return(Vec<Var>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Block<S> {
+    pub params: Vec<Var>,
+    pub insts: Vec<Inst<S>>,
+    pub terminator: Terminator,
+}
+
+/// A control flow graph of basic blocks with parameters, starting at the
+/// first block.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cfg<S> {
+    pub blocks: Vec<Block<S>>,
+    pub num_vars: usize,
+}
+
+/// Why a region couldn't be turned into a CFG.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CfgError {
+    /// A value input of a node isn't connected.
+    UnconnectedInput { node: NodeId, port: usize },
+    /// A value result of a region isn't connected.
+    UnconnectedResult { region: RegionId, port: usize },
+    /// Lambda nodes are lowered on their own, from their body region.
+    Lambda(NodeId),
+    /// Omega nodes have no counterpart in a CFG.
+    Omega(NodeId),
+}
+
+impl fmt::Display for CfgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CfgError::UnconnectedInput { node, port } => {
+                write!(f, "input {} of {:?} isn't connected", port, node)
+            }
+            CfgError::UnconnectedResult { region, port } => {
+                write!(f, "result {} of {:?} isn't connected", port, region)
+            }
+            CfgError::Lambda(node) => write!(f, "{:?} is a lambda node", node),
+            CfgError::Omega(node) => write!(f, "{:?} is an omega node", node),
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Turns a region into a CFG, whose first block takes the value
+    /// arguments of the region and whose last block returns its value
+    /// results.
+    ///
+    /// Gammas become a switch on their predicate into a block for each
+    /// branch, all jumping to a block that takes the outputs. Thetas become
+    /// a loop header taking the loop variables, with the end of the body
+    /// switching on the predicate to either leave the loop or go around.
+    /// Apply nodes become calls.
+    pub fn to_cfg(&self, region_id: RegionId) -> Result<Cfg<S>, CfgError>
+    where
+        S: Sig + Clone,
+    {
+        self.destruct(region_id, NodeCtxt::region_topo_order)
+    }
+
+    /// Turns a region into a CFG like `to_cfg`, with the instructions of
+    /// each block in the order `NodeCtxt::schedule` puts their nodes in.
+    pub fn to_scheduled_cfg(&self, region_id: RegionId) -> Result<Cfg<S>, CfgError>
+    where
+        S: Sig + Clone,
+    {
+        self.destruct(region_id, NodeCtxt::schedule)
+    }
+
+    fn destruct(
+        &self,
+        region_id: RegionId,
+        order: fn(&NodeCtxt<S>, RegionId) -> Vec<NodeId>,
+    ) -> Result<Cfg<S>, CfgError>
+    where
+        S: Sig + Clone,
+    {
+        let mut destructor = Destructor {
+            ctxt: self,
+            order,
+            blocks: vec![],
+            vars: HashMap::new(),
+            num_vars: 0,
+        };
+        let params = destructor.arg_vars(region_id);
+        let entry = destructor.new_block(params);
+        let exit = destructor.lower_region(region_id, entry)?;
+        let results = destructor.result_vars(region_id)?;
+        destructor.blocks[exit.0].terminator = Terminator::Return(results);
+        Ok(Cfg {
+            blocks: destructor.blocks,
+            num_vars: destructor.num_vars,
+        })
+    }
+}
+
+struct Destructor<'a, S> {
+    ctxt: &'a NodeCtxt<S>,
+    /// The order the nodes of a region are lowered in.
+    order: fn(&NodeCtxt<S>, RegionId) -> Vec<NodeId>,
+    blocks: Vec<Block<S>>,
+    /// The variables holding value origins lowered so far.
+    vars: HashMap<OriginId, Var>,
+    num_vars: usize,
+}
+
+impl<'a, S: Sig + Clone> Destructor<'a, S> {
+    fn new_var(&mut self, origin_id: OriginId) -> Var {
+        let var = Var(self.num_vars);
+        self.num_vars += 1;
+        self.vars.insert(origin_id, var);
+        var
+    }
+
+    /// Makes a block with the given parameters, to be terminated later.
+    fn new_block(&mut self, params: Vec<Var>) -> BlockId {
+        self.blocks.push(Block {
+            params,
+            insts: vec![],
+            terminator: Terminator::Return(vec![]),
+        });
+        BlockId(self.blocks.len() - 1)
+    }
+
+    /// Lowers the nodes of a region into `block`, returning the block
+    /// control ends up in.
+    fn lower_region(
+        &mut self,
+        region_id: RegionId,
+        mut block: BlockId,
+    ) -> Result<BlockId, CfgError> {
+        for node_id in (self.order)(self.ctxt, region_id) {
+            block = self.lower_node(node_id, block)?;
+        }
+        Ok(block)
+    }
+
+    fn lower_node(&mut self, node_id: NodeId, block: BlockId) -> Result<BlockId, CfgError> {
+        let kind = self.ctxt.node_data(node_id).kind.clone();
+        match kind {
+            NodeKind::Op(op) => {
+                let args = self.input_vars(node_id, 0)?;
+                let results = self.output_vars(node_id);
+                self.blocks[block.0]
+                    .insts
+                    .push(Inst::Op { op, args, results });
+                Ok(block)
+            }
+            NodeKind::Apply { .. } => {
+                let callee = self.input_var(node_id, 0)?;
+                let args = self.input_vars(node_id, 1)?;
+                let results = self.output_vars(node_id);
+                self.blocks[block.0].insts.push(Inst::Call {
+                    callee,
+                    args,
+                    results,
+                });
+                Ok(block)
+            }
+            NodeKind::Gamma { .. } => self.lower_gamma(node_id, block),
+            NodeKind::Theta { .. } => self.lower_theta(node_id, block),
+            NodeKind::Lambda { .. } => Err(CfgError::Lambda(node_id)),
+            NodeKind::Omega { .. } => Err(CfgError::Omega(node_id)),
+        }
+    }
+
+    fn lower_gamma(&mut self, node_id: NodeId, block: BlockId) -> Result<BlockId, CfgError> {
+        let predicate = self.input_var(node_id, 0)?;
+        let branches = self.ctxt.inner_regions(node_id);
+
+        // Branches see the same values as the gamma, so their arguments are
+        // held in the variables of its inputs.
+        for &branch in &branches {
+            let num_args = self.ctxt.region_data(branch).args.len();
+            for index in 0..num_args {
+                let arg = OriginId::argument(branch, index);
+                if self.ctxt.origin_data(arg).kind == PortKind::Val {
+                    let var = self.input_var(node_id, index + 1)?;
+                    self.vars.insert(arg, var);
+                }
+            }
+        }
+
+        let mut jumps = Vec::with_capacity(branches.len());
+        let mut exits = Vec::with_capacity(branches.len());
+        for branch in branches {
+            let entry = self.new_block(vec![]);
+            let exit = self.lower_region(branch, entry)?;
+            exits.push((exit, self.result_vars(branch)?));
+            jumps.push(Jump {
+                block: entry,
+                args: vec![],
+            });
+        }
+        let outputs = self.output_vars(node_id);
+        let join = self.new_block(outputs);
+        for (exit, args) in exits {
+            self.blocks[exit.0].terminator = Terminator::Jump(Jump { block: join, args });
+        }
+        self.blocks[block.0].terminator = Terminator::Switch {
+            on: predicate,
+            jumps,
+        };
+        Ok(join)
+    }
+
+    fn lower_theta(&mut self, node_id: NodeId, block: BlockId) -> Result<BlockId, CfgError> {
+        let body = self.ctxt.inner_regions(node_id)[0];
+        let inits = self.input_vars(node_id, 0)?;
+        let params = self.arg_vars(body);
+        let header = self.new_block(params);
+        self.blocks[block.0].terminator = Terminator::Jump(Jump {
+            block: header,
+            args: inits,
+        });
+
+        let latch = self.lower_region(body, header)?;
+        let mut results = self.result_vars(body)?;
+        // The first result of the body is the predicate.
+        let predicate = results.remove(0);
+        let outputs = self.output_vars(node_id);
+        let exit = self.new_block(outputs);
+        self.blocks[latch.0].terminator = Terminator::Switch {
+            on: predicate,
+            jumps: vec![
+                Jump {
+                    block: exit,
+                    args: results.clone(),
+                },
+                Jump {
+                    block: header,
+                    args: results,
+                },
+            ],
+        };
+        Ok(exit)
+    }
+
+    fn input_var(&self, node_id: NodeId, port: usize) -> Result<Var, CfgError> {
+        let user = UserId::input(node_id, port);
+        match self.ctxt.user_data(user).origin.get() {
+            Some(origin_id) => Ok(self.vars[&origin_id]),
+            None => Err(CfgError::UnconnectedInput {
+                node: node_id,
+                port,
+            }),
+        }
+    }
+
+    /// The variables of the value inputs of a node, starting at `first`.
+    fn input_vars(&self, node_id: NodeId, first: usize) -> Result<Vec<Var>, CfgError> {
+        let num_ins = self.ctxt.node_data(node_id).ins.len();
+        let mut vars = vec![];
+        for port in first..num_ins {
+            if self.ctxt.node_data(node_id).ins[port].kind == PortKind::Val {
+                vars.push(self.input_var(node_id, port)?);
+            }
+        }
+        Ok(vars)
+    }
+
+    /// Makes variables for the value outputs of a node.
+    fn output_vars(&mut self, node_id: NodeId) -> Vec<Var> {
+        let kinds: Vec<PortKind> = self
+            .ctxt
+            .node_data(node_id)
+            .outs
+            .iter()
+            .map(|out| out.kind)
+            .collect();
+        kinds
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, kind)| kind == PortKind::Val)
+            .map(|(index, _)| self.new_var(OriginId::output(node_id, index)))
+            .collect()
+    }
+
+    /// Makes variables for the value arguments of a region.
+    fn arg_vars(&mut self, region_id: RegionId) -> Vec<Var> {
+        let kinds: Vec<PortKind> = self
+            .ctxt
+            .region_data(region_id)
+            .args
+            .iter()
+            .map(|arg| arg.kind)
+            .collect();
+        kinds
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, kind)| kind == PortKind::Val)
+            .map(|(index, _)| self.new_var(OriginId::argument(region_id, index)))
+            .collect()
+    }
+
+    /// The variables of the value results of a region.
+    fn result_vars(&self, region_id: RegionId) -> Result<Vec<Var>, CfgError> {
+        let region_data = self.ctxt.region_data(region_id);
+        let mut vars = vec![];
+        for (port, res) in region_data.res.iter().enumerate() {
+            if res.kind != PortKind::Val {
+                continue;
+            }
+            match res.origin.get() {
+                Some(origin_id) => vars.push(self.vars[&origin_id]),
+                None => {
+                    return Err(CfgError::UnconnectedResult {
+                        region: region_id,
+                        port,
+                    })
+                }
+            }
+        }
+        Ok(vars)
+    }
+}
+
+impl<S: Debug> fmt::Display for Cfg<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "block{}({}):", index, list(&block.params))?;
+            for inst in &block.insts {
+                let (results, callee, args) = match inst {
+                    Inst::Op { op, args, results } => (results, format!("{:?}", op), args),
+                    Inst::Call {
+                        callee,
+                        args,
+                        results,
+                    } => (results, format!("call v{}", callee.0), args),
+                };
+                write!(f, "    ")?;
+                if !results.is_empty() {
+                    write!(f, "{} = ", list(results))?;
+                }
+                if args.is_empty() {
+                    writeln!(f, "{}", callee)?;
+                } else {
+                    writeln!(f, "{}({})", callee, list(args))?;
+                }
+            }
+            let jump = |jump: &Jump| format!("block{}({})", jump.block.0, list(&jump.args));
+            match &block.terminator {
+                Terminator::Jump(target) => writeln!(f, "    jump {}", jump(target))?,
+                Terminator::Switch { on, jumps } => {
+                    let jumps: Vec<String> = jumps.iter().map(jump).collect();
+                    writeln!(f, "    switch v{} [{}]", on.0, jumps.join(", "))?
+                }
+                Terminator::Return(vars) if vars.is_empty() => writeln!(f, "    return")?,
+                Terminator::Return(vars) => writeln!(f, "    return {}", list(vars))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn list(vars: &[Var]) -> String {
+    vars.iter()
+        .map(|var| format!("v{}", var.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::CfgError;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn gammas_become_switches() {
+        let ncx = NodeCtxt::new();
+
+        let x = ncx.mk_node(Op::Lit(3));
+        let st = ncx.mk_node(Op::St);
+        let gamma = ncx.gamma_builder(x.val_out(0), 2);
+        let args = gamma.entry_var(x.val_out(0));
+        let states = gamma.entry_state(st.st_out(0));
+        let neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let print = ncx
+            .node_builder_in(gamma.branch(1), Op::Print)
+            .operand(args[1])
+            .state(states[1])
+            .finish();
+        gamma.exit_var(&[neg.val_out(0), args[1]]);
+        gamma.exit_state(&[states[0], print.st_out(0)]);
+        gamma.finish();
+
+        let cfg = ncx.to_cfg(ncx.root_region()).unwrap();
+        assert_eq!(
+            cfg.to_string(),
+            "block0():
+    v0 = Lit(3)
+    St
+    switch v0 [block1(), block2()]
+block1():
+    v1 = Neg(v0)
+    jump block3(v1)
+block2():
+    Print(v0)
+    jump block3(v0)
+block3(v2):
+    return
+"
+        );
+    }
+
+    #[test]
+    fn thetas_become_loops() {
+        let ncx = NodeCtxt::new();
+
+        let n = ncx.mk_node(Op::Lit(10));
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (i, _) = theta.loop_var(n.val_out(0));
+        let next = ncx
+            .node_builder_in(theta.body(), Op::Neg)
+            .operand(i)
+            .finish();
+        let zero = ncx.node_builder_in(theta.body(), Op::Lit(0)).finish();
+        let repeat = ncx
+            .node_builder_in(theta.body(), Op::Lt)
+            .operand(zero.val_out(0))
+            .operand(next.val_out(0))
+            .finish();
+        theta.set_next(i, next.val_out(0));
+        let theta = theta.finish(repeat.val_out(0));
+        ncx.node_builder(Op::Neg).operand(theta.val_out(0)).finish();
+
+        let cfg = ncx.to_cfg(ncx.root_region()).unwrap();
+        assert_eq!(
+            cfg.to_string(),
+            "block0():
+    v0 = Lit(10)
+    jump block1(v0)
+block1(v1):
+    v2 = Neg(v1)
+    v3 = Lit(0)
+    v4 = Lt(v3, v2)
+    switch v4 [block2(v2), block1(v2)]
+block2(v5):
+    v6 = Neg(v5)
+    return
+"
+        );
+    }
+
+    #[test]
+    fn unconnected_inputs() {
+        let ncx = NodeCtxt::new();
+
+        let neg = ncx.create_node(NodeKind::Op(Op::Neg), ncx.root_region());
+
+        assert_eq!(
+            Err(CfgError::UnconnectedInput {
+                node: neg.id(),
+                port: 0
+            }),
+            ncx.to_cfg(ncx.root_region())
+        );
+    }
+}
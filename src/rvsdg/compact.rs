@@ -0,0 +1,318 @@
+use super::{
+    InnerRegionList, NodeCtxt, NodeData, NodeId, NodeMap, NodeTerm, OriginData, OriginId, PortPool,
+    RegionData, RegionId, RegionMap, UserData, UserId, UserIdList,
+};
+use std::{
+    cell::{Cell, RefCell},
+    hash::Hash,
+    mem,
+};
+
+/// Where `NodeCtxt::compact` moved the nodes and regions that weren't
+/// removed.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Remapping {
+    nodes: NodeMap<NodeId>,
+    regions: RegionMap<RegionId>,
+}
+
+impl Remapping {
+    /// The id `node_id` has now, unless the node was removed.
+    pub fn node(&self, node_id: NodeId) -> Option<NodeId> {
+        self.nodes.get(node_id).cloned()
+    }
+
+    /// The id `region_id` has now, unless the region was removed.
+    pub fn region(&self, region_id: RegionId) -> Option<RegionId> {
+        self.regions.get(region_id).cloned()
+    }
+
+    /// Moves the values of `table` to the ids their nodes have now,
+    /// dropping those of removed nodes.
+    pub fn remap_nodes<T>(&self, table: &mut NodeMap<T>) {
+        let node_ids: Vec<NodeId> = table.iter().map(|(node_id, _)| node_id).collect();
+        let mut remapped = NodeMap::new();
+        for node_id in node_ids {
+            let value = table.remove(node_id).unwrap();
+            if let Some(node_id) = self.node(node_id) {
+                remapped.insert(node_id, value);
+            }
+        }
+        *table = remapped;
+    }
+
+    /// Moves the values of `table` to the ids their regions have now,
+    /// dropping those of removed regions.
+    pub fn remap_regions<T>(&self, table: &mut RegionMap<T>) {
+        let region_ids: Vec<RegionId> = table.iter().map(|(region_id, _)| region_id).collect();
+        let mut remapped = RegionMap::new();
+        for region_id in region_ids {
+            let value = table.remove(region_id).unwrap();
+            if let Some(region_id) = self.region(region_id) {
+                remapped.insert(region_id, value);
+            }
+        }
+        *table = remapped;
+    }
+
+    /// The id `origin_id` has now, unless its node or region was removed.
+    pub fn origin(&self, origin_id: OriginId) -> Option<OriginId> {
+        match origin_id {
+            OriginId::Out { node, index } => Some(OriginId::Out {
+                node: self.node(node)?,
+                index,
+            }),
+            OriginId::Arg { region, index } => Some(OriginId::Arg {
+                region: self.region(region)?,
+                index,
+            }),
+        }
+    }
+
+    /// The id `user_id` has now, unless its node or region was removed.
+    pub fn user(&self, user_id: UserId) -> Option<UserId> {
+        match user_id {
+            UserId::In { node, index } => Some(UserId::In {
+                node: self.node(node)?,
+                index,
+            }),
+            UserId::Res { region, index } => Some(UserId::Res {
+                region: self.region(region)?,
+                index,
+            }),
+        }
+    }
+
+    // Ports that weren't removed are only ever connected to ports that
+    // weren't either, so these can't fail on them.
+
+    fn live_node(&self, node_id: NodeId) -> NodeId {
+        self.node(node_id)
+            .unwrap_or_else(|| panic!("{:?} was removed", node_id))
+    }
+
+    fn live_region(&self, region_id: RegionId) -> RegionId {
+        self.region(region_id)
+            .unwrap_or_else(|| panic!("{:?} was removed", region_id))
+    }
+
+    fn live_origin(&self, origin_id: OriginId) -> OriginId {
+        self.origin(origin_id)
+            .unwrap_or_else(|| panic!("{:?} was removed", origin_id))
+    }
+
+    fn live_user(&self, user_id: UserId) -> UserId {
+        self.user(user_id)
+            .unwrap_or_else(|| panic!("{:?} was removed", user_id))
+    }
+
+    fn user_data(&self, user: UserData) -> UserData {
+        UserData {
+            origin: Cell::new(user.origin.get().map(|origin| self.live_origin(origin))),
+            sink: user.sink.map(|origin| self.live_origin(origin)),
+            prev_user: Cell::new(user.prev_user.get().map(|prev| self.live_user(prev))),
+            next_user: Cell::new(user.next_user.get().map(|next| self.live_user(next))),
+            ..user
+        }
+    }
+
+    fn origin_data(&self, origin: OriginData) -> OriginData {
+        let users = origin.users.get().map(|users| UserIdList {
+            first: self.live_user(users.first),
+            last: self.live_user(users.last),
+            len: users.len,
+        });
+        OriginData {
+            source: origin.source.map(|user| self.live_user(user)),
+            users: Cell::new(users),
+            ..origin
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Renumbers the nodes and regions that weren't removed, keeping them
+    /// in the order they were made, and reclaims the room the removed ones
+    /// took up. Returns where each of them went, so that side tables kept
+    /// outside of the context can follow.
+    ///
+    /// Names, spans and the intern table are remapped along, while the
+    /// mutation journal is cleared, as it names ids that may be gone.
+    pub fn compact(&mut self) -> Remapping
+    where
+        S: Eq + Hash,
+    {
+        let mut remapping = Remapping::default();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if !node.borrow().removed {
+                let node_id = NodeId::new(remapping.nodes.len());
+                remapping.nodes.insert(NodeId::new(index), node_id);
+            }
+        }
+        for (index, region) in self.regions.iter().enumerate() {
+            if !region.borrow().removed {
+                let region_id = RegionId::new(remapping.regions.len());
+                remapping.regions.insert(RegionId::new(index), region_id);
+            }
+        }
+
+        // Ports are copied into fresh pools, leaving behind those of
+        // removed nodes and those nodes outgrew.
+        let input_ports = PortPool::new();
+        let output_ports = PortPool::new();
+        let nodes = mem::take(&mut self.nodes)
+            .into_iter()
+            .map(RefCell::into_inner)
+            .filter(|node_data| !node_data.removed)
+            .map(|node_data| {
+                let mut ins = self.input_ports.get_mut(&node_data.ins);
+                let mut outs = self.output_ports.get_mut(&node_data.outs);
+                let ins = ins.iter_mut().map(mem::take);
+                let outs = outs.iter_mut().map(mem::take);
+                let inner_regions = node_data
+                    .inner_regions
+                    .get()
+                    .map(|regions| InnerRegionList {
+                        first_region: remapping.live_region(regions.first_region),
+                        last_region: remapping.live_region(regions.last_region),
+                    });
+                RefCell::new(NodeData {
+                    ins: input_ports.alloc(ins.map(|user| remapping.user_data(user))),
+                    outs: output_ports.alloc(outs.map(|origin| remapping.origin_data(origin))),
+                    inner_regions: Cell::new(inner_regions),
+                    outer_region: remapping.live_region(node_data.outer_region),
+                    ..node_data
+                })
+            })
+            .collect();
+
+        let regions = mem::take(&mut self.regions)
+            .into_iter()
+            .map(RefCell::into_inner)
+            .filter(|region_data| !region_data.removed)
+            .map(|region_data| {
+                let prev_region = region_data.prev_region.get();
+                let next_region = region_data.next_region.get();
+                RefCell::new(RegionData {
+                    node: region_data.node.map(|node| remapping.live_node(node)),
+                    res: region_data
+                        .res
+                        .into_iter()
+                        .map(|user| remapping.user_data(user))
+                        .collect(),
+                    args: region_data
+                        .args
+                        .into_iter()
+                        .map(|origin| remapping.origin_data(origin))
+                        .collect(),
+                    prev_region: Cell::new(prev_region.map(|prev| remapping.live_region(prev))),
+                    next_region: Cell::new(next_region.map(|next| remapping.live_region(next))),
+                    nodes: region_data
+                        .nodes
+                        .iter()
+                        .map(|&node| remapping.live_node(node))
+                        .collect(),
+                    ..region_data
+                })
+            })
+            .collect();
+
+        self.nodes = nodes;
+        self.regions = regions;
+        self.input_ports = input_ports;
+        self.output_ports = output_ports;
+
+        let interned_nodes = mem::take(self.interned_nodes.get_mut());
+        *self.interned_nodes.get_mut() = interned_nodes
+            .into_iter()
+            .filter_map(|(node_term, node_id)| {
+                let node_term = NodeTerm {
+                    region: remapping.region(node_term.region)?,
+                    origins: node_term
+                        .origins
+                        .iter()
+                        .map(|&origin| remapping.origin(origin))
+                        .collect::<Option<_>>()?,
+                    kind: node_term.kind,
+                };
+                Some((node_term, remapping.node(node_id)?))
+            })
+            .collect();
+
+        let origin_names = mem::take(self.origin_names.get_mut());
+        *self.origin_names.get_mut() = origin_names
+            .into_iter()
+            .filter_map(|(origin_id, name)| Some((remapping.origin(origin_id)?, name)))
+            .collect();
+        remapping.remap_nodes(self.spans.get_mut());
+        remapping.remap_nodes(self.node_names.get_mut());
+        self.journal.get_mut().clear();
+
+        remapping
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, NodeMap, OriginId};
+
+    fn text(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = vec![];
+        ncx.print_text(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn compacting_after_removals() {
+        let mut ncx = NodeCtxt::new();
+        let (n_dead, n_one, n_gamma, n_two) = {
+            let n_dead = ncx.mk_node(Op::Lit(0)).id();
+            let n_one = ncx.mk_node(Op::Lit(1));
+            let gamma = ncx.gamma_builder(n_one.val_out(0), 2);
+            let args = gamma.entry_var(n_one.val_out(0));
+            let n_neg = ncx
+                .node_builder_in(gamma.branch(0), Op::Neg)
+                .operand(args[0])
+                .finish();
+            gamma.exit_var(&[n_neg.val_out(0), args[1]]);
+            let n_gamma = gamma.finish();
+            let n_two = ncx
+                .node_builder(Op::Add)
+                .operand(n_one.val_out(0))
+                .operand(n_one.val_out(0))
+                .finish();
+            ncx.set_origin_name(n_two.val_out(0).id(), "two");
+            (n_dead, n_one.id(), n_gamma.id(), n_two.id())
+        };
+        ncx.remove_node(n_dead);
+        ncx.remove_node(n_gamma);
+        let before = text(&ncx);
+        let mut names = NodeMap::new();
+        names.insert(n_dead, "dead");
+        names.insert(n_two, "two");
+
+        let remapping = ncx.compact();
+        assert_eq!(None, remapping.node(n_dead));
+        assert_eq!(None, remapping.node(n_gamma));
+        let n_one = remapping.node(n_one).unwrap();
+        let n_two = remapping.node(n_two).unwrap();
+        assert_eq!((0, 1), (n_one.index(), n_two.index()));
+        assert_eq!(Some(ncx.root_region()), remapping.region(ncx.root_region()));
+        remapping.remap_nodes(&mut names);
+        assert_eq!(vec![(n_two, &"two")], names.iter().collect::<Vec<_>>());
+
+        assert_eq!(2, ncx.nodes.len());
+        assert_eq!(1, ncx.regions.len());
+        assert_eq!(Ok(()), ncx.verify());
+        assert_eq!(before, text(&ncx));
+        assert_eq!(
+            Some("two".to_owned()),
+            ncx.origin_name(OriginId::output(n_two, 0))
+        );
+        // Interning finds the nodes under their new ids.
+        let one = ncx.node_ref(n_one).val_out(0);
+        let n_add = ncx.node_builder(Op::Add).operand(one).operand(one).finish();
+        assert_eq!(n_two, n_add.id());
+        assert_eq!(&NodeKind::Op(Op::Lit(1)), &*ncx.node_ref(n_one).kind());
+    }
+}
@@ -0,0 +1,484 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, RegionId, UserId};
+use std::collections::HashMap;
+
+/// Which way the facts of a `DataflowAnalysis` flow.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// From the origins of values to their users, as for constants.
+    Forward,
+    /// From the users of values back to their origins, as for liveness.
+    Backward,
+}
+
+/// An analysis of what's known about the values of a graph, as facts from
+/// a lattice that are joined where values meet. The lattice must have no
+/// infinitely ascending chains, or solving may not terminate.
+///
+/// Facts are found for every origin. Going backward, the fact of an origin
+/// is the join of what its users ask of it.
+pub trait DataflowAnalysis<S> {
+    type Fact: Clone + PartialEq;
+
+    const DIRECTION: Direction;
+
+    /// The fact of values nothing is known about yet, which joining with
+    /// any other fact gives that fact.
+    fn bottom(&self) -> Self::Fact;
+
+    /// The fact of values the analysis can't see into, such as lambda
+    /// parameters and the outputs of applies.
+    fn top(&self) -> Self::Fact;
+
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact;
+
+    /// Going forward, the facts of the outputs of `op` given `facts`, those
+    /// of its inputs. Going backward, those of its inputs given `facts`,
+    /// those of its outputs. Value ports come before state ports.
+    fn transfer(&self, op: &S, facts: &[Self::Fact]) -> Vec<Self::Fact>;
+
+    /// The branch a gamma takes given `predicate`, the fact of its
+    /// predicate, if that's known. Going forward, the other branches are
+    /// left unsolved.
+    fn taken_branch(&self, _predicate: &Self::Fact) -> Option<usize> {
+        None
+    }
+}
+
+/// The facts found by `NodeCtxt::solve_dataflow`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DataflowSolution<F> {
+    facts: HashMap<OriginId, F>,
+}
+
+impl<F> DataflowSolution<F> {
+    /// What's known about `origin_id`, or `None` if it's outside of the
+    /// regions that were solved or in a branch that's never taken.
+    pub fn fact(&self, origin_id: OriginId) -> Option<&F> {
+        self.facts.get(&origin_id)
+    }
+
+    /// The origins that were solved, along with their facts.
+    pub fn iter(&self) -> impl Iterator<Item = (OriginId, &F)> {
+        self.facts
+            .iter()
+            .map(|(&origin_id, fact)| (origin_id, fact))
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Solves `analysis` for `region_id` and the regions nested in it,
+    /// iterating over the bodies of thetas until their loop variables don't
+    /// change. What comes into the region from outside is `top`.
+    ///
+    /// Gamma branches take the facts of the inputs they're entered through,
+    /// and their outputs are the join of those of the branches, which going
+    /// backward is the other way around. Going forward, the regions of
+    /// lambdas and omegas are solved on their own. Going backward, their
+    /// results are asked for the join of what their outputs are, or `top`
+    /// for the exports of omegas, and their inputs for the join of what
+    /// their arguments are.
+    pub fn solve_dataflow<A>(&self, region_id: RegionId, analysis: &A) -> DataflowSolution<A::Fact>
+    where
+        A: DataflowAnalysis<S>,
+    {
+        let mut solver = Solver {
+            ncx: self,
+            analysis,
+            facts: HashMap::new(),
+            demands: HashMap::new(),
+        };
+        let num_args = self.region_data(region_id).args.len();
+        let num_res = self.region_data(region_id).res.len();
+        match A::DIRECTION {
+            Direction::Forward => {
+                solver.forward_region(region_id, vec![analysis.top(); num_args]);
+            }
+            Direction::Backward => {
+                solver.backward_region(region_id, vec![analysis.top(); num_res]);
+            }
+        }
+        DataflowSolution {
+            facts: solver.facts,
+        }
+    }
+}
+
+struct Solver<'a, S, A: DataflowAnalysis<S>> {
+    ncx: &'a NodeCtxt<S>,
+    analysis: &'a A,
+    facts: HashMap<OriginId, A::Fact>,
+    /// Going backward, what the users ask of their origins.
+    demands: HashMap<UserId, A::Fact>,
+}
+
+impl<'a, S, A: DataflowAnalysis<S>> Solver<'a, S, A> {
+    fn join_all(&self, facts: impl Iterator<Item = A::Fact>) -> A::Fact {
+        facts.fold(self.analysis.bottom(), |joined, fact| {
+            self.analysis.join(&joined, &fact)
+        })
+    }
+
+    fn origin_fact(&self, origin_id: OriginId) -> A::Fact {
+        self.facts[&origin_id].clone()
+    }
+
+    /// Going forward, the fact of the origin `user_id` is connected to.
+    fn user_fact(&self, user_id: UserId) -> A::Fact {
+        match self.ncx.user_data(user_id).origin.get() {
+            Some(origin_id) => self.origin_fact(origin_id),
+            None => self.analysis.top(),
+        }
+    }
+
+    /// Going backward, the join of what the users of `origin_id` ask.
+    fn demanded_fact(&self, origin_id: OriginId) -> A::Fact {
+        let users = self.ncx.origin_ref(origin_id).users();
+        self.join_all(users.map(|user| self.demands[&user.id()].clone()))
+    }
+
+    fn forward_region(&mut self, region_id: RegionId, args: Vec<A::Fact>) {
+        for (index, fact) in args.into_iter().enumerate() {
+            self.facts
+                .insert(OriginId::argument(region_id, index), fact);
+        }
+        for node_id in self.ncx.region_topo_order(region_id) {
+            let num_ins = self.ncx.node_data(node_id).ins.len();
+            let inputs: Vec<A::Fact> = (0..num_ins)
+                .map(|index| self.user_fact(UserId::input(node_id, index)))
+                .collect();
+            let outputs = match &self.ncx.node_data(node_id).kind {
+                NodeKind::Op(op) => self.analysis.transfer(op, &inputs),
+                NodeKind::Gamma { .. } => self.forward_gamma(node_id, inputs),
+                NodeKind::Theta { .. } => self.forward_theta(node_id, inputs),
+                _ => self.forward_opaque(node_id),
+            };
+            for (index, fact) in outputs.into_iter().enumerate() {
+                self.facts.insert(OriginId::output(node_id, index), fact);
+            }
+        }
+    }
+
+    fn forward_gamma(&mut self, gamma: NodeId, inputs: Vec<A::Fact>) -> Vec<A::Fact> {
+        let mut branches = self.ncx.inner_regions(gamma);
+        if let Some(taken) = self.analysis.taken_branch(&inputs[0]) {
+            if taken < branches.len() {
+                branches = vec![branches[taken]];
+            }
+        }
+        for &branch in &branches {
+            // Skips the predicate.
+            self.forward_region(branch, inputs[1..].to_vec());
+        }
+        let num_outs = self.ncx.node_data(gamma).outs.len();
+        (0..num_outs)
+            .map(|index| {
+                let results = branches
+                    .iter()
+                    .map(|&branch| self.user_fact(UserId::result(branch, index)));
+                self.join_all(results)
+            })
+            .collect()
+    }
+
+    fn forward_theta(&mut self, theta: NodeId, inputs: Vec<A::Fact>) -> Vec<A::Fact> {
+        let body = self.ncx.inner_regions(theta)[0];
+        let mut args = inputs;
+        loop {
+            self.forward_region(body, args.clone());
+            // Skips the predicate.
+            let next: Vec<A::Fact> = (0..args.len())
+                .map(|index| {
+                    let result = self.user_fact(UserId::result(body, index + 1));
+                    self.analysis.join(&args[index], &result)
+                })
+                .collect();
+            if next == args {
+                break;
+            }
+            args = next;
+        }
+        (0..args.len())
+            .map(|index| self.user_fact(UserId::result(body, index + 1)))
+            .collect()
+    }
+
+    fn forward_opaque(&mut self, node_id: NodeId) -> Vec<A::Fact> {
+        for region_id in self.ncx.inner_regions(node_id) {
+            let num_args = self.ncx.region_data(region_id).args.len();
+            self.forward_region(region_id, vec![self.analysis.top(); num_args]);
+        }
+        let num_outs = self.ncx.node_data(node_id).outs.len();
+        vec![self.analysis.top(); num_outs]
+    }
+
+    fn backward_region(&mut self, region_id: RegionId, results: Vec<A::Fact>) {
+        for (index, fact) in results.into_iter().enumerate() {
+            self.demands.insert(UserId::result(region_id, index), fact);
+        }
+        for node_id in self.ncx.region_topo_order(region_id).into_iter().rev() {
+            let num_outs = self.ncx.node_data(node_id).outs.len();
+            let outputs: Vec<A::Fact> = (0..num_outs)
+                .map(|index| self.demanded_fact(OriginId::output(node_id, index)))
+                .collect();
+            let inputs = match &self.ncx.node_data(node_id).kind {
+                NodeKind::Op(op) => self.analysis.transfer(op, &outputs),
+                NodeKind::Gamma { .. } => self.backward_gamma(node_id, &outputs),
+                NodeKind::Theta { .. } => self.backward_theta(node_id, &outputs),
+                _ => self.backward_opaque(node_id, &outputs),
+            };
+            for (index, fact) in outputs.into_iter().enumerate() {
+                self.facts.insert(OriginId::output(node_id, index), fact);
+            }
+            for (index, fact) in inputs.into_iter().enumerate() {
+                self.demands.insert(UserId::input(node_id, index), fact);
+            }
+        }
+        let num_args = self.ncx.region_data(region_id).args.len();
+        for index in 0..num_args {
+            let arg = OriginId::argument(region_id, index);
+            let fact = self.demanded_fact(arg);
+            self.facts.insert(arg, fact);
+        }
+    }
+
+    fn backward_gamma(&mut self, gamma: NodeId, outputs: &[A::Fact]) -> Vec<A::Fact> {
+        let branches = self.ncx.inner_regions(gamma);
+        for &branch in &branches {
+            self.backward_region(branch, outputs.to_vec());
+        }
+        let num_entries = self.ncx.node_data(gamma).ins.len() - 1;
+        let entries = (0..num_entries).map(|index| {
+            let args = branches
+                .iter()
+                .map(|&branch| self.origin_fact(OriginId::argument(branch, index)));
+            self.join_all(args)
+        });
+        // The predicate is asked for whatever the branches do with it.
+        std::iter::once(self.analysis.top())
+            .chain(entries)
+            .collect()
+    }
+
+    fn backward_theta(&mut self, theta: NodeId, outputs: &[A::Fact]) -> Vec<A::Fact> {
+        let body = self.ncx.inner_regions(theta)[0];
+        let mut args = vec![self.analysis.bottom(); outputs.len()];
+        loop {
+            let next = outputs
+                .iter()
+                .zip(&args)
+                .map(|(output, arg)| self.analysis.join(output, arg));
+            // The predicate decides whether there's a next iteration.
+            let results = std::iter::once(self.analysis.top()).chain(next).collect();
+            self.backward_region(body, results);
+            let joined: Vec<A::Fact> = (0..args.len())
+                .map(|index| {
+                    let arg = self.origin_fact(OriginId::argument(body, index));
+                    self.analysis.join(&args[index], &arg)
+                })
+                .collect();
+            if joined == args {
+                break;
+            }
+            args = joined;
+        }
+        args
+    }
+
+    fn backward_opaque(&mut self, node_id: NodeId, outputs: &[A::Fact]) -> Vec<A::Fact> {
+        let regions = self.ncx.inner_regions(node_id);
+        let num_ins = self.ncx.node_data(node_id).ins.len();
+        if regions.is_empty() {
+            return vec![self.analysis.top(); num_ins];
+        }
+        let results = if outputs.is_empty() {
+            self.analysis.top()
+        } else {
+            self.join_all(outputs.iter().cloned())
+        };
+        for &region_id in &regions {
+            let num_res = self.ncx.region_data(region_id).res.len();
+            self.backward_region(region_id, vec![results.clone(); num_res]);
+        }
+        let solver = &*self;
+        let args = regions.iter().flat_map(|&region_id| {
+            let num_args = solver.ncx.region_data(region_id).args.len();
+            (0..num_args).map(move |index| solver.origin_fact(OriginId::argument(region_id, index)))
+        });
+        vec![solver.join_all(args); num_ins]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DataflowAnalysis, Direction};
+    use crate::rvsdg::{testing::Op, NodeCtxt, OriginId, Sig};
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum Const {
+        Undef,
+        Known(i64),
+        Varying,
+    }
+
+    struct ConstProp;
+
+    impl DataflowAnalysis<Op> for ConstProp {
+        type Fact = Const;
+
+        const DIRECTION: Direction = Direction::Forward;
+
+        fn bottom(&self) -> Const {
+            Const::Undef
+        }
+
+        fn top(&self) -> Const {
+            Const::Varying
+        }
+
+        fn join(&self, a: &Const, b: &Const) -> Const {
+            match (*a, *b) {
+                (Const::Undef, fact) | (fact, Const::Undef) => fact,
+                (a, b) if a == b => a,
+                _ => Const::Varying,
+            }
+        }
+
+        fn transfer(&self, op: &Op, facts: &[Const]) -> Vec<Const> {
+            let binary = |f: fn(i64, i64) -> i64| match (facts[0], facts[1]) {
+                (Const::Known(a), Const::Known(b)) => Const::Known(f(a, b)),
+                (Const::Undef, _) | (_, Const::Undef) => Const::Undef,
+                _ => Const::Varying,
+            };
+            match op {
+                Op::Lit(value) => vec![Const::Known(*value)],
+                Op::Add => vec![binary(|a, b| a + b)],
+                Op::Lt => vec![binary(|a, b| i64::from(a < b))],
+                _ => vec![Const::Varying; op.sig().val_outs],
+            }
+        }
+    }
+
+    /// Whether values are used by a sink, directly or not.
+    struct Liveness;
+
+    impl DataflowAnalysis<Op> for Liveness {
+        type Fact = bool;
+
+        const DIRECTION: Direction = Direction::Backward;
+
+        fn bottom(&self) -> bool {
+            false
+        }
+
+        fn top(&self) -> bool {
+            true
+        }
+
+        fn join(&self, a: &bool, b: &bool) -> bool {
+            *a || *b
+        }
+
+        fn transfer(&self, op: &Op, facts: &[bool]) -> Vec<bool> {
+            let live = *op == Op::Sink || facts.iter().any(|&live| live);
+            vec![live; op.sig().val_ins]
+        }
+    }
+
+    #[test]
+    fn constants_through_gammas_and_thetas() {
+        let ncx = NodeCtxt::new();
+
+        let n_zero = ncx.mk_node(Op::Lit(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let gamma = ncx.gamma_builder(n_zero.val_out(0), 2);
+        let args = gamma.entry_var(n_one.val_out(0));
+        let n_two = ncx
+            .node_builder(Op::Add)
+            .operand(args[0])
+            .operand(args[0])
+            .finish();
+        let n_three = ncx.mk_node_in(gamma.branch(1), Op::Lit(3));
+        let same = gamma.exit_var(&[args[0], args[1]]);
+        let differing = gamma.exit_var(&[n_two.val_out(0), n_three.val_out(0)]);
+        gamma.finish();
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x, x_out) = theta.loop_var(n_one.val_out(0));
+        let (i, i_out) = theta.loop_var(n_zero.val_out(0));
+        let n_zero_in = ncx.mk_node_in(theta.body(), Op::Lit(0));
+        let n_x_next = ncx
+            .node_builder(Op::Add)
+            .operand(x)
+            .operand(n_zero_in.val_out(0))
+            .finish();
+        let n_one_in = ncx.mk_node_in(theta.body(), Op::Lit(1));
+        let n_i_next = ncx
+            .node_builder(Op::Add)
+            .operand(i)
+            .operand(n_one_in.val_out(0))
+            .finish();
+        let n_pred = ncx
+            .node_builder(Op::Lt)
+            .operand(n_i_next.val_out(0))
+            .operand(x)
+            .finish();
+        theta.set_next(x, n_x_next.val_out(0));
+        theta.set_next(i, n_i_next.val_out(0));
+        theta.finish(n_pred.val_out(0));
+
+        let solution = ncx.solve_dataflow(ncx.root_region(), &ConstProp);
+        let fact = |origin: OriginId| *solution.fact(origin).unwrap();
+        assert_eq!(Const::Known(2), fact(n_two.val_out(0).id()));
+        assert_eq!(Const::Known(1), fact(same.id()));
+        assert_eq!(Const::Varying, fact(differing.id()));
+        // Adding zero keeps the loop variable constant, but adding one
+        // doesn't.
+        assert_eq!(Const::Known(1), fact(x.id()));
+        assert_eq!(Const::Known(1), fact(x_out.id()));
+        assert_eq!(Const::Varying, fact(i.id()));
+        assert_eq!(Const::Varying, fact(i_out.id()));
+    }
+
+    #[test]
+    fn liveness_through_gammas_and_thetas() {
+        let ncx = NodeCtxt::new();
+
+        let n_zero = ncx.mk_node(Op::Lit(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_dead = ncx
+            .node_builder(Op::Add)
+            .operand(n_one.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        let gamma = ncx.gamma_builder(n_zero.val_out(0), 2);
+        let args = gamma.entry_var(n_one.val_out(0));
+        let n_two = ncx.mk_node_in(gamma.branch(0), Op::Lit(2));
+        let n_three = ncx.mk_node_in(gamma.branch(1), Op::Lit(3));
+        let printed = gamma.exit_var(&[args[0], n_three.val_out(0)]);
+        gamma.exit_var(&[n_two.val_out(0), args[1]]);
+        gamma.finish();
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x, _) = theta.loop_var(n_one.val_out(0));
+        let (y, y_out) = theta.loop_var(printed);
+        let n_x_next = ncx.node_builder(Op::Add).operand(x).operand(x).finish();
+        theta.set_next(x, n_x_next.val_out(0));
+        theta.finish(y);
+        ncx.node_builder(Op::Sink).operand(y_out).finish();
+
+        let solution = ncx.solve_dataflow(ncx.root_region(), &Liveness);
+        let live = |origin: OriginId| *solution.fact(origin).unwrap();
+        assert!(!live(n_dead.val_out(0).id()));
+        assert!(live(printed.id()));
+        assert!(live(y.id()));
+        assert!(live(n_three.val_out(0).id()));
+        assert!(!live(n_two.val_out(0).id()));
+        // Entered for the printed output of one branch.
+        assert!(live(args[0].id()));
+        assert!(!live(args[1].id()));
+        assert!(live(n_one.val_out(0).id()));
+        // The loop variable only feeds itself.
+        assert!(!live(x.id()));
+        assert!(!live(n_x_next.val_out(0).id()));
+    }
+}
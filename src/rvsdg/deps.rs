@@ -0,0 +1,159 @@
+use super::{NodeCtxt, OriginId, Region, RegionId, UserId};
+use std::collections::HashSet;
+
+/// An origin outside of a region that something inside it depends on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExternalDep {
+    /// Passed in through `arg`, an argument of the region connected to an
+    /// input of its node.
+    Arg { arg: OriginId, origin: OriginId },
+    /// Used by `user`, a port inside the region, without going through an
+    /// argument, which isn't a valid edge.
+    Direct { user: UserId, origin: OriginId },
+}
+
+impl ExternalDep {
+    /// The outer origin depended on.
+    pub fn origin(&self) -> OriginId {
+        match *self {
+            ExternalDep::Arg { origin, .. } | ExternalDep::Direct { origin, .. } => origin,
+        }
+    }
+}
+
+impl<'g, S> Region<'g, S> {
+    pub fn external_deps(&self) -> Vec<ExternalDep> {
+        self.ctxt.external_deps(self.id)
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Lists the origins outside of a region that it, or any region nested
+    /// in it, depends on.
+    ///
+    /// The arguments of the region come first, in order, followed by the
+    /// ports inside it that reference outer origins directly.
+    pub fn external_deps(&self, region_id: RegionId) -> Vec<ExternalDep> {
+        let inner_regions = self.enclosed_regions(region_id);
+        let is_inner: HashSet<RegionId> = inner_regions.iter().cloned().collect();
+
+        let num_args = self.region_data(region_id).args.len();
+        let mut deps: Vec<ExternalDep> = (0..num_args)
+            .filter_map(|index| {
+                let arg = OriginId::argument(region_id, index);
+                let source = self.origin_data(arg).source?;
+                let origin = self.user_data(source).origin.get()?;
+                Some(ExternalDep::Arg { arg, origin })
+            })
+            .collect();
+
+        for &inner_region in &inner_regions {
+            let num_results = self.region_data(inner_region).res.len();
+            let results = (0..num_results).map(|index| UserId::result(inner_region, index));
+            let inputs = self
+                .region_nodes(inner_region)
+                .into_iter()
+                .flat_map(|node_id| {
+                    let num_inputs = self.node_data(node_id).ins.len();
+                    (0..num_inputs).map(move |index| UserId::input(node_id, index))
+                });
+
+            for user in results.chain(inputs) {
+                if let Some(origin) = self.user_data(user).origin.get() {
+                    if !is_inner.contains(&self.origin_region(origin)) {
+                        deps.push(ExternalDep::Direct { user, origin });
+                    }
+                }
+            }
+        }
+
+        deps
+    }
+
+    /// A region and every region nested in it, outermost first.
+    fn enclosed_regions(&self, region_id: RegionId) -> Vec<RegionId> {
+        let mut regions = vec![region_id];
+        let mut index = 0;
+        while let Some(&region_id) = regions.get(index) {
+            for node_id in self.region_nodes(region_id) {
+                regions.extend(self.inner_regions(node_id));
+            }
+            index += 1;
+        }
+        regions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExternalDep;
+    use crate::rvsdg::{testing::Op, ConnectError, NodeCtxt};
+
+    #[test]
+    fn dependencies_through_arguments() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+
+        let outer = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let x_args = outer.entry_var(n_x.val_out(0));
+        let pred_args = outer.entry_var(n_pred.val_out(0));
+        let inner = ncx.gamma_builder(pred_args[0], 1);
+        let inner_x_args = inner.entry_var(x_args[0]);
+        let n_neg = ncx
+            .node_builder_in(inner.branch(0), Op::Neg)
+            .operand(inner_x_args[0])
+            .finish();
+        inner.exit_var(&[n_neg.val_out(0)]);
+        inner.finish();
+        let outer_branch = outer.branch(0);
+        outer.finish();
+
+        let deps = ncx.region_ref(outer_branch).external_deps();
+
+        assert_eq!(
+            vec![
+                ExternalDep::Arg {
+                    arg: x_args[0].id(),
+                    origin: n_x.val_out(0).id(),
+                },
+                ExternalDep::Arg {
+                    arg: pred_args[0].id(),
+                    origin: n_pred.val_out(0).id(),
+                },
+            ],
+            deps
+        );
+    }
+
+    #[test]
+    fn outer_origins_cannot_be_used_directly() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let branch = gamma.branch(0);
+        let n_lit = ncx.node_builder_in(branch, Op::Lit(2)).finish();
+        let n_neg = ncx
+            .node_builder_in(branch, Op::Neg)
+            .operand(n_lit.val_out(0))
+            .finish();
+        gamma.exit_var(&[n_neg.val_out(0)]);
+        gamma.finish();
+
+        // Outer origins can't be used without going through an argument, so
+        // there are no direct dependencies to find.
+        n_neg.val_in(0).disconnect();
+        assert_eq!(
+            Err(ConnectError::RegionMismatch {
+                user_region: branch,
+                origin_region: ncx.root_region(),
+            }),
+            n_neg.val_in(0).try_connect(n_x.val_out(0))
+        );
+        assert!(ncx.external_deps(branch).is_empty());
+    }
+}
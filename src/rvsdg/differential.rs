@@ -0,0 +1,630 @@
+use super::{ConstBranch, Fold, NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, Sig};
+use std::{collections::HashMap, hash::Hash};
+
+/// How many theta iterations and calls an evaluation may take. Past it,
+/// the graph is taken not to terminate, and whatever is left to compute is
+/// unknown.
+const STEP_LIMIT: usize = 10_000;
+
+/// A small xorshift generator, so that random tests are reproducible from
+/// their seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // A zero state would only ever give zeros, so the one seed leading
+        // to it starts from another state.
+        match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => Rng(0x9e37_79b9_7f4a_7c15),
+            state => Rng(state),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// A number in `0..bound`, which must not be zero.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+/// An output whose value changed across a pass, for some inputs.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Divergence<S, V> {
+    /// The value given to each input op.
+    pub inputs: Vec<(S, V)>,
+    /// The index of the output, counting the operands of every sink in the
+    /// order of the root region.
+    pub output: usize,
+    pub before: Option<V>,
+    pub after: Option<V>,
+}
+
+/// Runs `pass` over the graph, checking that it computes the same outputs
+/// before and after for `num_runs` sets of random inputs.
+///
+/// Inputs are ops without operands that aren't constants, wherever they
+/// are in the graph, each of which gets a value from `random_value`.
+/// Outputs are the operands of sinks, ops in the root region without value
+/// outputs. Ops are evaluated with `Fold`, gammas take the branch their
+/// predicate selects by `ConstBranch`, thetas repeat their body until the
+/// predicate selects the first branch, and apply nodes evaluate the body of
+/// the lambda they call.
+///
+/// Values that can't be computed are unknown, as are those computed from
+/// them: the values of stateful ops, and those of loops and calls that don't
+/// end within a bounded number of steps. Only outputs known before the pass
+/// are compared. Returns the first output that diverged.
+pub fn check_pass_outputs<S, F, G>(
+    ncx: &NodeCtxt<S>,
+    num_runs: usize,
+    seed: u64,
+    mut random_value: G,
+    pass: F,
+) -> Result<(), Divergence<S, S::ConstValue>>
+where
+    S: Fold + ConstBranch + Sig + Eq + Hash + Clone,
+    S::ConstValue: Clone + PartialEq,
+    F: FnOnce(&NodeCtxt<S>),
+    G: FnMut(&mut Rng) -> S::ConstValue,
+{
+    let mut rng = Rng::new(seed);
+    let input_ops = ncx.input_ops();
+    let runs: Vec<Vec<(S, S::ConstValue)>> = (0..num_runs)
+        .map(|_| {
+            input_ops
+                .iter()
+                .map(|op| (op.clone(), random_value(&mut rng)))
+                .collect()
+        })
+        .collect();
+
+    let before: Vec<_> = runs.iter().map(|inputs| ncx.evaluate(inputs)).collect();
+    pass(ncx);
+    for (inputs, before) in runs.into_iter().zip(before) {
+        let after = ncx.evaluate(&inputs);
+        for (output, before) in before.into_iter().enumerate() {
+            let after = after.get(output).cloned().unwrap_or(None);
+            if before.is_some() && before != after {
+                return Err(Divergence {
+                    inputs,
+                    output,
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A value carried by an edge while the graph is evaluated.
+#[derive(Clone)]
+enum Value<V> {
+    Const(V),
+    /// States carry nothing but the order they impose.
+    State,
+    /// The function a lambda defines.
+    Function(NodeId),
+}
+
+/// The values computed so far by an evaluation.
+struct Evaluation<'a, S, V> {
+    inputs: &'a [(S, V)],
+    values: HashMap<OriginId, Value<V>>,
+    /// The values of the context variables of each lambda evaluated.
+    contexts: HashMap<NodeId, Vec<Option<Value<V>>>>,
+    steps: usize,
+}
+
+impl<'a, S, V: Clone> Evaluation<'a, S, V> {
+    fn value(&self, origin_id: Option<OriginId>) -> Option<Value<V>> {
+        self.values.get(&origin_id?).cloned()
+    }
+
+    fn set_value(&mut self, origin_id: OriginId, value: Option<Value<V>>) {
+        match value {
+            Some(value) => self.values.insert(origin_id, value),
+            None => self.values.remove(&origin_id),
+        };
+    }
+
+    /// Takes a step of a loop or call, unless the evaluation ran out of
+    /// them.
+    fn step(&mut self) -> bool {
+        self.steps += 1;
+        self.steps <= STEP_LIMIT
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// The distinct ops that take no operands and aren't constants, found
+    /// region by region from the root.
+    fn input_ops(&self) -> Vec<S>
+    where
+        S: Fold + Sig + Eq + Clone,
+    {
+        let mut input_ops = vec![];
+        let mut regions = vec![self.root_region()];
+        while let Some(region_id) = regions.pop() {
+            for node_id in self.region_topo_order(region_id) {
+                let node_data = self.node_data(node_id);
+                if let NodeKind::Op(ref op) = node_data.kind {
+                    let sig = op.sig();
+                    let is_input = sig.num_input_ports() == 0
+                        && sig.val_outs > 0
+                        && op.as_const().is_none()
+                        && op.try_fold(&[]).is_none();
+                    if is_input && !input_ops.contains(op) {
+                        input_ops.push(op.clone());
+                    }
+                }
+                drop(node_data);
+                regions.extend(self.inner_regions(node_id).into_iter().rev());
+            }
+        }
+        input_ops
+    }
+
+    /// The values of the operands of every sink in the root region, given
+    /// the values of input ops.
+    fn evaluate(&self, inputs: &[(S, S::ConstValue)]) -> Vec<Option<S::ConstValue>>
+    where
+        S: Fold + ConstBranch + Sig + Eq + Clone,
+        S::ConstValue: Clone,
+    {
+        let mut eval = Evaluation {
+            inputs,
+            values: HashMap::new(),
+            contexts: HashMap::new(),
+            steps: 0,
+        };
+        let root = self.root_region();
+        let num_args = self.region_data(root).args.len();
+        self.evaluate_region(root, vec![None; num_args], &mut eval);
+
+        let mut outputs = vec![];
+        for node_id in self.region_topo_order(root) {
+            let node_data = self.node_data(node_id);
+            match node_data.kind {
+                NodeKind::Op(ref op) if op.sig().val_outs == 0 => {}
+                _ => continue,
+            }
+            let operands = node_data
+                .ins
+                .iter()
+                .filter(|user| user.kind == PortKind::Val)
+                .map(|user| match eval.value(user.origin.get()) {
+                    Some(Value::Const(value)) => Some(value),
+                    _ => None,
+                });
+            outputs.extend(operands);
+        }
+        outputs
+    }
+
+    /// Evaluates the nodes of a region given the values of its arguments,
+    /// returning the values of its results.
+    fn evaluate_region<V>(
+        &self,
+        region_id: RegionId,
+        args: Vec<Option<Value<V>>>,
+        eval: &mut Evaluation<S, V>,
+    ) -> Vec<Option<Value<V>>>
+    where
+        S: Fold<ConstValue = V> + ConstBranch + Sig + Eq + Clone,
+        V: Clone,
+    {
+        for (index, arg) in args.into_iter().enumerate() {
+            eval.set_value(OriginId::argument(region_id, index), arg);
+        }
+        for node_id in self.region_topo_order(region_id) {
+            let outputs = self.evaluate_node(node_id, eval);
+            for (index, output) in outputs.into_iter().enumerate() {
+                eval.set_value(OriginId::output(node_id, index), output);
+            }
+        }
+        let region_data = self.region_data(region_id);
+        region_data
+            .res
+            .iter()
+            .map(|user| eval.value(user.origin.get()))
+            .collect()
+    }
+
+    /// The values of the outputs of a node, given those of its operands.
+    fn evaluate_node<V>(
+        &self,
+        node_id: NodeId,
+        eval: &mut Evaluation<S, V>,
+    ) -> Vec<Option<Value<V>>>
+    where
+        S: Fold<ConstValue = V> + ConstBranch + Sig + Eq + Clone,
+        V: Clone,
+    {
+        let node_data = self.node_data(node_id);
+        let kind = node_data.kind.clone();
+        let in_kinds: Vec<PortKind> = node_data.ins.iter().map(|user| user.kind).collect();
+        let out_kinds: Vec<PortKind> = node_data.outs.iter().map(|origin| origin.kind).collect();
+        let operands: Vec<Option<Value<V>>> = node_data
+            .ins
+            .iter()
+            .map(|user| eval.value(user.origin.get()))
+            .collect();
+        drop(node_data);
+        let unknown = vec![None; out_kinds.len()];
+        let branch = |value: &Option<Value<V>>| match value {
+            Some(Value::Const(value)) => S::from_const(value.clone()).const_branch(),
+            _ => None,
+        };
+
+        let mut outputs = match kind {
+            NodeKind::Op(ref op) => {
+                let value = self.evaluate_op(op, &in_kinds, &operands, eval);
+                out_kinds
+                    .iter()
+                    .map(|kind| match kind {
+                        PortKind::Val => value.clone().map(Value::Const),
+                        PortKind::St => Some(Value::State),
+                    })
+                    .collect()
+            }
+            NodeKind::Gamma { .. } => {
+                let branches = self.inner_regions(node_id);
+                match branch(&operands[0]).and_then(|branch| branches.get(branch)) {
+                    Some(&branch) => self.evaluate_region(branch, operands[1..].to_vec(), eval),
+                    None => unknown,
+                }
+            }
+            NodeKind::Theta { .. } => {
+                let body = self.inner_regions(node_id)[0];
+                let mut loop_vars = operands;
+                loop {
+                    if !eval.step() {
+                        break unknown;
+                    }
+                    let mut results = self.evaluate_region(body, loop_vars, eval);
+                    let predicate = results.remove(0);
+                    loop_vars = results;
+                    match branch(&predicate) {
+                        Some(0) => break loop_vars,
+                        Some(1) => {}
+                        _ => break unknown,
+                    }
+                }
+            }
+            NodeKind::Lambda { .. } => {
+                eval.contexts.insert(node_id, operands);
+                vec![Some(Value::Function(node_id))]
+            }
+            NodeKind::Apply { .. } => match operands[0] {
+                Some(Value::Function(lambda)) if eval.step() => {
+                    self.evaluate_call(lambda, &in_kinds, &operands, eval)
+                }
+                _ => unknown,
+            },
+            NodeKind::Omega { .. } => unknown,
+        };
+        outputs.resize(out_kinds.len(), None);
+        outputs
+    }
+
+    /// The value an op produces, if it produces a single value and no
+    /// states.
+    fn evaluate_op<V>(
+        &self,
+        op: &S,
+        in_kinds: &[PortKind],
+        operands: &[Option<Value<V>>],
+        eval: &Evaluation<S, V>,
+    ) -> Option<V>
+    where
+        S: Fold<ConstValue = V> + Sig + Eq,
+        V: Clone,
+    {
+        let sig = op.sig();
+        if sig.st_ins > 0 || sig.st_outs > 0 || sig.val_outs != 1 {
+            return None;
+        }
+        if let Some((_, value)) = eval.inputs.iter().find(|(input, _)| input == op) {
+            return Some(value.clone());
+        }
+        if let Some(value) = op.as_const() {
+            return Some(value);
+        }
+        let operands = in_kinds
+            .iter()
+            .zip(operands)
+            .filter(|(&kind, _)| kind == PortKind::Val)
+            .map(|(_, operand)| match operand {
+                Some(Value::Const(value)) => Some(value.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        op.try_fold(&operands)
+    }
+
+    /// Evaluates the body of a lambda for an apply node, whose first operand
+    /// is the function and whose value and state arguments go to the value
+    /// and state parameters, in order.
+    fn evaluate_call<V>(
+        &self,
+        lambda: NodeId,
+        in_kinds: &[PortKind],
+        operands: &[Option<Value<V>>],
+        eval: &mut Evaluation<S, V>,
+    ) -> Vec<Option<Value<V>>>
+    where
+        S: Fold<ConstValue = V> + ConstBranch + Sig + Eq + Clone,
+        V: Clone,
+    {
+        let params = match self.node_data(lambda).kind {
+            NodeKind::Lambda { params, .. } => params,
+            _ => unreachable!("{:?} isn't a lambda", lambda),
+        };
+        let body = self.inner_regions(lambda)[0];
+        let mut val_args = in_kinds
+            .iter()
+            .zip(operands)
+            .skip(1)
+            .filter(|(&kind, _)| kind == PortKind::Val)
+            .map(|(_, operand)| operand.clone());
+        let param_kinds: Vec<PortKind> = self.region_data(body).args[..params]
+            .iter()
+            .map(|arg| arg.kind)
+            .collect();
+        let mut args: Vec<Option<Value<V>>> = param_kinds
+            .into_iter()
+            .map(|kind| match kind {
+                PortKind::Val => val_args.next().unwrap_or(None),
+                PortKind::St => Some(Value::State),
+            })
+            .collect();
+        args.extend(eval.contexts.get(&lambda).cloned().unwrap_or_default());
+        self.evaluate_region(body, args, eval)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_pass_outputs, Rng};
+    use crate::rvsdg::{testing::Op, NodeCtxt, Pattern as P, PortKind, Replacement as R, Rewriter};
+
+    /// Prints `x - y + 0` of two parameters.
+    fn print_difference(ncx: &NodeCtxt<Op>) {
+        let n_x = ncx.mk_node(Op::Param(0));
+        let n_y = ncx.mk_node(Op::Param(1));
+        let n_sub = ncx
+            .node_builder(Op::Sub)
+            .operand(n_x.val_out(0))
+            .operand(n_y.val_out(0))
+            .finish();
+        // Made after the difference, so that sorting the operands of the sum
+        // keeps it second.
+        let n_zero = ncx.mk_node(Op::Lit(0));
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_sub.val_out(0))
+            .operand(n_zero.val_out(0))
+            .finish();
+        let n_st = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(n_add.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+    }
+
+    #[test]
+    fn sound_passes_keep_outputs() {
+        let ncx = NodeCtxt::new();
+        print_difference(&ncx);
+
+        let rewriter = Rewriter::new().rule(
+            P::Op(Op::Add, vec![P::Var(0), P::Op(Op::Lit(0), vec![])]),
+            R::Var(0),
+        );
+        let result = check_pass_outputs(
+            &ncx,
+            16,
+            1,
+            |rng| rng.next_u64() as i64,
+            |ncx| {
+                assert_eq!(1, rewriter.rewrite(ncx));
+            },
+        );
+        assert_eq!(Ok(()), result);
+    }
+
+    #[test]
+    fn finding_diverging_outputs() {
+        let ncx = NodeCtxt::new();
+        print_difference(&ncx);
+
+        // Loses `y` on the way.
+        let rewriter = Rewriter::new().rule(
+            P::Op(Op::Sub, vec![P::Var(0), P::Var(1)]),
+            R::Op(Op::Add, vec![R::Var(0), R::Op(Op::Neg, vec![R::Var(0)])]),
+        );
+        let divergence = check_pass_outputs(
+            &ncx,
+            16,
+            1,
+            |rng| rng.below(100) as i64 + 1,
+            |ncx| {
+                rewriter.rewrite(ncx);
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(0, divergence.output);
+        let (x, y) = (divergence.inputs[0].1, divergence.inputs[1].1);
+        assert_eq!(divergence.inputs[0].0, Op::Param(0));
+        assert_eq!(Some(x - y), divergence.before);
+        assert_eq!(Some(0), divergence.after);
+    }
+
+    #[test]
+    fn passes_are_checked_inside_gammas() {
+        let ncx = NodeCtxt::new();
+
+        // Prints `-x` if `x < y`, and `x` otherwise.
+        let n_x = ncx.mk_node(Op::Param(0));
+        let n_y = ncx.mk_node(Op::Param(1));
+        let n_lt = ncx
+            .node_builder(Op::Lt)
+            .operand(n_x.val_out(0))
+            .operand(n_y.val_out(0))
+            .finish();
+        let gamma = ncx.gamma_builder(n_lt.val_out(0), 2);
+        let args = gamma.entry_var(n_x.val_out(0));
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(1), Op::Neg)
+            .operand(args[1])
+            .finish();
+        let out = gamma.exit_var(&[args[0], n_neg.val_out(0)]);
+        gamma.finish();
+        let n_st = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(out)
+            .state(n_st.st_out(0))
+            .finish();
+
+        let rewriter = Rewriter::new().rule(P::Op(Op::Neg, vec![P::Var(0)]), R::Var(0));
+        let divergence = check_pass_outputs(
+            &ncx,
+            16,
+            1,
+            |rng| rng.below(100) as i64 + 1,
+            |ncx| {
+                assert_eq!(1, rewriter.rewrite(ncx));
+            },
+        )
+        .unwrap_err();
+
+        let (x, y) = (divergence.inputs[0].1, divergence.inputs[1].1);
+        assert!(x < y);
+        assert_eq!(Some(-x), divergence.before);
+        assert_eq!(Some(x), divergence.after);
+    }
+
+    /// Prints the first power of two of `x` at least 100, doubling it in a
+    /// loop.
+    fn print_doubling_loop(ncx: &NodeCtxt<Op>) {
+        let n_x = ncx.mk_node(Op::Param(0));
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, x_out) = theta.loop_var(n_x.val_out(0));
+        let n_double = ncx
+            .node_builder_in(theta.body(), Op::Add)
+            .operand(x_arg)
+            .operand(x_arg)
+            .finish();
+        let n_limit = ncx.mk_node_in(theta.body(), Op::Lit(100));
+        let n_lt = ncx
+            .node_builder_in(theta.body(), Op::Lt)
+            .operand(n_double.val_out(0))
+            .operand(n_limit.val_out(0))
+            .finish();
+        theta.set_next(x_arg, n_double.val_out(0));
+        theta.finish(n_lt.val_out(0));
+        let n_st = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(x_out)
+            .state(n_st.st_out(0))
+            .finish();
+    }
+
+    #[test]
+    fn passes_are_checked_inside_thetas() {
+        let ncx = NodeCtxt::new();
+        print_doubling_loop(&ncx);
+
+        let result = check_pass_outputs(&ncx, 16, 1, |rng| rng.below(10) as i64 + 1, |_| {});
+        assert_eq!(Ok(()), result);
+
+        // Squares instead, which never grows from 1.
+        let rewriter = Rewriter::new().rule(
+            P::Op(Op::Add, vec![P::Var(0), P::Var(0)]),
+            R::Op(Op::Mul, vec![R::Var(0), R::Var(0)]),
+        );
+        let divergence = check_pass_outputs(
+            &ncx,
+            16,
+            1,
+            |rng| rng.below(10) as i64 + 1,
+            |ncx| {
+                assert_eq!(1, rewriter.rewrite(ncx));
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(Op::Param(0), divergence.inputs[0].0);
+        let x = divergence.inputs[0].1;
+        let doubled = (0..).map(|n| x << n).find(|&x| x >= 100);
+        assert_eq!(doubled, divergence.before);
+        assert_ne!(divergence.before, divergence.after);
+    }
+
+    #[test]
+    fn endless_loops_are_unknown() {
+        let ncx = NodeCtxt::new();
+
+        let n_x = ncx.mk_node(Op::Param(0));
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, x_out) = theta.loop_var(n_x.val_out(0));
+        let n_true = ncx.mk_node_in(theta.body(), Op::Lit(1));
+        theta.set_next(x_arg, x_arg);
+        theta.finish(n_true.val_out(0));
+        let n_st = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(x_out)
+            .state(n_st.st_out(0))
+            .finish();
+
+        assert_eq!(vec![None], ncx.evaluate(&[(Op::Param(0), 1)]));
+    }
+
+    #[test]
+    fn calls_evaluate_their_lambda() {
+        let ncx = NodeCtxt::new();
+        let root = ncx.root_region();
+
+        // Subtracts its argument from a captured value.
+        let n_ten = ncx.mk_node(Op::Lit(10));
+        let lambda = ncx.lambda_builder(root, &[PortKind::St, PortKind::Val]);
+        let ten = lambda.ctx_var(n_ten.val_out(0));
+        let n_sub = ncx
+            .node_builder_in(lambda.body(), Op::Sub)
+            .operand(ten)
+            .operand(lambda.val_param(0))
+            .finish();
+        let st = lambda.st_param(0);
+        let lambda = lambda.finish(&[n_sub.val_out(0)], &[st]);
+
+        let n_x = ncx.mk_node(Op::Param(0));
+        let n_st = ncx.mk_node(Op::St);
+        let apply = ncx
+            .apply_builder(lambda.val_out(0), ncx.lambda_sig(lambda.id()))
+            .operand(n_x.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Store)
+            .operand(apply.val_out(0))
+            .state(apply.st_out(0))
+            .finish();
+
+        assert_eq!(vec![Some(7)], ncx.evaluate(&[(Op::Param(0), 3)]));
+    }
+
+    #[test]
+    fn seeds_never_give_a_zero_state() {
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+        assert_ne!(0, rng.next_u64());
+    }
+}
@@ -0,0 +1,211 @@
+use super::{NodeCtxt, NodeId, NodeMap, OriginId, RegionId};
+
+/// The dominator tree of the nodes of a region, as found by
+/// `NodeCtxt::dominators`.
+///
+/// A node dominates another if every path of edges reaching the latter
+/// goes through it. Paths start at the arguments of the region, and at the
+/// nodes taking none of their operands from within it, so a node using an
+/// argument directly is only dominated by itself and is a root of the tree.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Dominators {
+    idoms: NodeMap<Option<NodeId>>,
+    depths: NodeMap<usize>,
+    children: NodeMap<Vec<NodeId>>,
+    roots: Vec<NodeId>,
+}
+
+impl Dominators {
+    /// The closest node dominating `node_id` other than itself, or `None`
+    /// if it's a root or isn't in the region.
+    pub fn immediate_dominator(&self, node_id: NodeId) -> Option<NodeId> {
+        self.idoms.get(node_id).cloned().flatten()
+    }
+
+    /// Whether every path to `b` goes through `a`. Nodes dominate
+    /// themselves.
+    pub fn dominates(&self, a: NodeId, b: NodeId) -> bool {
+        let depth = match self.depths.get(a) {
+            Some(&depth) => depth,
+            None => return false,
+        };
+        let mut node_id = b;
+        while matches!(self.depths.get(node_id), Some(&d) if d > depth) {
+            node_id = self.idoms[node_id].unwrap();
+        }
+        node_id == a
+    }
+
+    /// The nodes `node_id` immediately dominates, in the order they come in
+    /// the region.
+    pub fn children(&self, node_id: NodeId) -> &[NodeId] {
+        self.children
+            .get(node_id)
+            .map_or(&[], |children| &children[..])
+    }
+
+    /// The nodes no other node dominates, in the order they come in the
+    /// region.
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    /// Where `a` and `b` are first dominated by the same node, going up
+    /// the tree, if anywhere.
+    fn intersect(&self, mut a: Option<NodeId>, mut b: Option<NodeId>) -> Option<NodeId> {
+        while let (Some(x), Some(y)) = (a, b) {
+            if x == y {
+                return a;
+            }
+            if self.depths[x] >= self.depths[y] {
+                a = self.idoms[x];
+            } else {
+                b = self.idoms[y];
+            }
+        }
+        None
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Computes the dominator tree of the nodes of `region_id`, through
+    /// both value and state edges.
+    ///
+    /// Regions are acyclic, so visiting the nodes in topological order
+    /// finds the dominators of each node's operands before its own.
+    pub fn dominators(&self, region_id: RegionId) -> Dominators {
+        let mut dominators = Dominators::default();
+        for node_id in self.region_topo_order(region_id) {
+            let idom = self.immediate_dominator(&dominators, region_id, node_id);
+            let depth = match idom {
+                Some(idom) => {
+                    dominators
+                        .children
+                        .get_or_insert_with(idom, Vec::new)
+                        .push(node_id);
+                    dominators.depths[idom] + 1
+                }
+                None => {
+                    dominators.roots.push(node_id);
+                    0
+                }
+            };
+            dominators.idoms.insert(node_id, idom);
+            dominators.depths.insert(node_id, depth);
+        }
+        dominators
+    }
+
+    /// The immediate dominator of `node_id`, given those of the nodes of
+    /// `region_id` it uses.
+    fn immediate_dominator(
+        &self,
+        dominators: &Dominators,
+        region_id: RegionId,
+        node_id: NodeId,
+    ) -> Option<NodeId> {
+        let node_data = self.node_data(node_id);
+        let mut operands = node_data.ins.iter().map(|user| match user.origin.get() {
+            Some(OriginId::Out { node, .. }) if self.node_data(node).outer_region == region_id => {
+                Some(node)
+            }
+            _ => None,
+        });
+        let first = operands.next()??;
+        operands.try_fold(first, |idom, operand| {
+            dominators.intersect(Some(idom), Some(operand?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn diamond() {
+        let ncx = NodeCtxt::new();
+
+        let n_a = ncx.mk_node(Op::Lit(0));
+        let n_b = ncx.node_builder(Op::Neg).operand(n_a.val_out(0)).finish();
+        let n_c = ncx.node_builder(Op::Not).operand(n_a.val_out(0)).finish();
+        let n_d = ncx
+            .node_builder(Op::Add)
+            .operand(n_b.val_out(0))
+            .operand(n_c.val_out(0))
+            .finish();
+        let n_e = ncx.node_builder(Op::Neg).operand(n_d.val_out(0)).finish();
+        let n_f = ncx.mk_node(Op::Lit(1));
+        let n_g = ncx
+            .node_builder(Op::Add)
+            .operand(n_e.val_out(0))
+            .operand(n_f.val_out(0))
+            .finish();
+
+        let doms = ncx.dominators(ncx.root_region());
+        assert_eq!(None, doms.immediate_dominator(n_a.id()));
+        assert_eq!(Some(n_a.id()), doms.immediate_dominator(n_b.id()));
+        assert_eq!(Some(n_a.id()), doms.immediate_dominator(n_c.id()));
+        assert_eq!(Some(n_a.id()), doms.immediate_dominator(n_d.id()));
+        assert_eq!(Some(n_d.id()), doms.immediate_dominator(n_e.id()));
+        // `n_g` can be reached from `n_f` without going through `n_a`.
+        assert_eq!(None, doms.immediate_dominator(n_g.id()));
+
+        assert!(doms.dominates(n_a.id(), n_e.id()));
+        assert!(doms.dominates(n_d.id(), n_d.id()));
+        assert!(!doms.dominates(n_b.id(), n_d.id()));
+        assert!(!doms.dominates(n_a.id(), n_g.id()));
+
+        let mut children = doms.children(n_a.id()).to_vec();
+        children.sort();
+        assert_eq!(vec![n_b.id(), n_c.id(), n_d.id()], children);
+        let mut roots = doms.roots().to_vec();
+        roots.sort();
+        assert_eq!(vec![n_a.id(), n_f.id(), n_g.id()], roots);
+    }
+
+    #[test]
+    fn arguments_and_state_edges() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_st = ncx.mk_node(Op::St);
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_pred.val_out(0));
+        let states = gamma.entry_state(n_st.st_out(0));
+        let branch = gamma.branch(0);
+        let n_lit = ncx.node_builder_in(branch, Op::Lit(1)).finish();
+        let n_store = ncx
+            .node_builder(Op::Store)
+            .operand(n_lit.val_out(0))
+            .state(states[0])
+            .finish();
+        let n_neg = ncx.node_builder(Op::Neg).operand(args[0]).finish();
+        let n_not = ncx.node_builder(Op::Not).operand(n_neg.val_out(0)).finish();
+        let n_last = ncx
+            .node_builder(Op::Store)
+            .operand(n_lit.val_out(0))
+            .state(n_store.st_out(0))
+            .finish();
+        let n_ret = ncx
+            .node_builder(Op::Join(1))
+            .state(n_last.st_out(0))
+            .finish();
+        gamma.exit_var(&[n_not.val_out(0), args[1]]);
+        gamma.exit_state(&[n_ret.st_out(0), states[1]]);
+        gamma.finish();
+
+        let doms = ncx.dominators(branch);
+        // The store takes its state from an argument.
+        assert_eq!(None, doms.immediate_dominator(n_store.id()));
+        assert_eq!(None, doms.immediate_dominator(n_neg.id()));
+        assert_eq!(Some(n_neg.id()), doms.immediate_dominator(n_not.id()));
+        // The last store can be reached from either the literal or the
+        // first store, but the return only through the last store.
+        assert_eq!(None, doms.immediate_dominator(n_last.id()));
+        assert_eq!(Some(n_last.id()), doms.immediate_dominator(n_ret.id()));
+        assert!(!doms.dominates(n_lit.id(), n_ret.id()));
+        assert_eq!(None, doms.immediate_dominator(n_pred.id()));
+        assert!(doms.children(n_pred.id()).is_empty());
+    }
+}
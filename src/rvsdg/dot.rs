@@ -0,0 +1,681 @@
+use super::{
+    escape_record_label, NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, Sig, UserId,
+};
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+};
+
+/// Makes the label of an op.
+type NodeLabel<'a, S> = Box<dyn Fn(&S) -> String + 'a>;
+
+/// How `NodeCtxt::print_with` draws a graph.
+pub struct DotOptions<'a, S> {
+    /// The color of value edges.
+    pub val_color: String,
+    /// The color of state edges.
+    pub st_color: String,
+    /// The direction ranks are laid out in, left to Graphviz if `None`.
+    pub rankdir: Option<RankDir>,
+    /// Whether ports are labeled with their indices. Named ports are labeled
+    /// with their names either way.
+    pub port_indices: bool,
+    /// Labels the nodes of ops, which are labeled with the `Debug` form of
+    /// their op otherwise.
+    pub node_label: Option<NodeLabel<'a, S>>,
+    /// Whether to add a legend explaining the styles of edges.
+    pub legend: bool,
+    /// Whether to label regions with a summary of their contents.
+    pub region_summaries: bool,
+}
+
+impl<S> Default for DotOptions<'_, S> {
+    fn default() -> Self {
+        DotOptions {
+            val_color: "blue".to_owned(),
+            st_color: "red".to_owned(),
+            rankdir: None,
+            port_indices: true,
+            node_label: None,
+            legend: false,
+            region_summaries: false,
+        }
+    }
+}
+
+/// The direction in which the ranks of a graph are laid out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RankDir {
+    TopToBottom,
+    BottomToTop,
+    LeftToRight,
+    RightToLeft,
+}
+
+impl RankDir {
+    fn as_str(self) -> &'static str {
+        match self {
+            RankDir::TopToBottom => "TB",
+            RankDir::BottomToTop => "BT",
+            RankDir::LeftToRight => "LR",
+            RankDir::RightToLeft => "RL",
+        }
+    }
+}
+
+/// Counts describing a region, for annotating dumps.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegionSummary {
+    /// The nodes directly in the region, not counting those of inner
+    /// regions.
+    pub nodes: usize,
+    /// The state chains starting in the region, from a state argument or a
+    /// node producing state without consuming any.
+    pub state_chains: usize,
+    /// How many structural nodes the region is nested in.
+    pub depth: usize,
+}
+
+impl<S> NodeCtxt<S> {
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Sig + Debug,
+    {
+        self.print_with(out, &DotOptions::default())
+    }
+
+    /// Like `print`, but drawing the graph as `options` says.
+    pub fn print_with(&self, out: &mut dyn Write, options: &DotOptions<S>) -> io::Result<()>
+    where
+        S: Sig + Debug,
+    {
+        writeln!(out, "digraph rvsdg {{")?;
+        if let Some(rankdir) = options.rankdir {
+            writeln!(out, "    rankdir={}", rankdir.as_str())?;
+        }
+        writeln!(out, "    node [shape=record]")?;
+        writeln!(out, "    edge [arrowhead=none]")?;
+        if options.region_summaries {
+            self.write_region_summary(out, self.root_region(), "    ")?;
+        }
+        self.print_region_nodes(out, self.root_region(), 1, options)?;
+        if options.legend {
+            write_legend(out, options)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    /// Prints the nodes of a region followed each by the edges into it.
+    /// Structural nodes are put in a cluster along with their regions.
+    fn print_region_nodes(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+        options: &DotOptions<S>,
+    ) -> io::Result<()>
+    where
+        S: Sig + Debug,
+    {
+        let indent = "    ".repeat(depth);
+        for node_id in self.region_nodes(region_id) {
+            let inner_regions = self.inner_regions(node_id);
+            let label = self.node_record_label(node_id, options);
+            if inner_regions.is_empty() {
+                writeln!(out, r#"{}n{} [label="{}"]"#, indent, node_id.0, label)?;
+            } else {
+                writeln!(out, "{}subgraph cluster_n{} {{", indent, node_id.0)?;
+                writeln!(out, r#"{}    n{} [label="{}"]"#, indent, node_id.0, label)?;
+                for inner_region in inner_regions {
+                    self.print_inner_region(out, inner_region, depth + 1, options)?;
+                }
+                writeln!(out, "{}}}", indent)?;
+            }
+
+            let num_ins = self.node_data(node_id).ins.len();
+            for index in 0..num_ins {
+                let (kind, origin) = {
+                    let user = &self.node_data(node_id).ins[index];
+                    (user.kind, user.origin.get())
+                };
+                if let Some(origin_id) = origin {
+                    writeln!(
+                        out,
+                        "{}{} -> n{}:i{} [{}]",
+                        indent,
+                        origin_endpoint(origin_id),
+                        node_id.0,
+                        index,
+                        edge_style(kind, options)
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints a region of a structural node as a cluster, with a record for
+    /// its arguments at the top and one for its results at the bottom.
+    ///
+    /// Arguments are joined to the inputs of the node they stand for, and
+    /// results to the outputs they define, with edges styled as crossing the
+    /// region's boundary.
+    fn print_inner_region(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+        options: &DotOptions<S>,
+    ) -> io::Result<()>
+    where
+        S: Sig + Debug,
+    {
+        let indent = "    ".repeat(depth);
+        writeln!(out, "{}subgraph cluster_r{} {{", indent, region_id.0)?;
+        if options.region_summaries {
+            self.write_region_summary(out, region_id, &format!("{}    ", indent))?;
+        }
+
+        let num_args = self.region_data(region_id).args.len();
+        if num_args > 0 {
+            let args = (0..num_args)
+                .map(|index| {
+                    let origin_id = OriginId::argument(region_id, index);
+                    port_label('a', index, self.origin_name(origin_id), options)
+                })
+                .collect::<Vec<_>>()
+                .join("|");
+            writeln!(
+                out,
+                r#"{}    r{}_args [label="{}"]"#,
+                indent, region_id.0, args
+            )?;
+            for index in 0..num_args {
+                let (kind, source) = {
+                    let arg = &self.region_data(region_id).args[index];
+                    (arg.kind, arg.source)
+                };
+                if let Some(UserId::In { node, index: input }) = source {
+                    writeln!(
+                        out,
+                        "{}    n{}:i{} -> r{}_args:a{} [{}]",
+                        indent,
+                        node.0,
+                        input,
+                        region_id.0,
+                        index,
+                        boundary_edge_style(kind, options)
+                    )?;
+                }
+            }
+        }
+
+        self.print_region_nodes(out, region_id, depth + 1, options)?;
+
+        let num_res = self.region_data(region_id).res.len();
+        if num_res > 0 {
+            let res = (0..num_res)
+                .map(|index| port_label('r', index, None, options))
+                .collect::<Vec<_>>()
+                .join("|");
+            writeln!(
+                out,
+                r#"{}    r{}_res [label="{}"]"#,
+                indent, region_id.0, res
+            )?;
+            for index in 0..num_res {
+                let (kind, origin) = {
+                    let user = &self.region_data(region_id).res[index];
+                    (user.kind, user.origin.get())
+                };
+                if let Some(origin_id) = origin {
+                    writeln!(
+                        out,
+                        "{}    {} -> r{}_res:r{} [{}]",
+                        indent,
+                        origin_endpoint(origin_id),
+                        region_id.0,
+                        index,
+                        edge_style(kind, options)
+                    )?;
+                }
+            }
+            for index in 0..num_res {
+                let (kind, sink) = {
+                    let res = &self.region_data(region_id).res[index];
+                    (res.kind, res.sink)
+                };
+                // Outputs sit above the cluster, so these edges would pull it
+                // upwards if they took part in ranking.
+                if let Some(OriginId::Out {
+                    node,
+                    index: output,
+                }) = sink
+                {
+                    writeln!(
+                        out,
+                        "{}    r{}_res:r{} -> n{}:o{} [{}, constraint=false]",
+                        indent,
+                        region_id.0,
+                        index,
+                        node.0,
+                        output,
+                        boundary_edge_style(kind, options)
+                    )?;
+                }
+            }
+        }
+
+        writeln!(out, "{}}}", indent)
+    }
+
+    /// The label of the record of a node: its inputs, its kind and its
+    /// outputs, named after their debug names if they have one.
+    fn node_record_label(&self, node_id: NodeId, options: &DotOptions<S>) -> String
+    where
+        S: Debug,
+    {
+        let node_data = self.node_data(node_id);
+        let dot_ins = (0..node_data.ins.len())
+            .map(|i| port_label('i', i, None, options))
+            .collect::<Vec<_>>()
+            .join("|");
+        let dot_outs = (0..node_data.outs.len())
+            .map(|i| {
+                let origin_id = OriginId::output(node_id, i);
+                port_label('o', i, self.origin_name(origin_id), options)
+            })
+            .collect::<Vec<_>>()
+            .join("|");
+        let kind = match node_data.kind {
+            NodeKind::Op(ref operation) => match options.node_label {
+                Some(ref node_label) => escape_record_label(&node_label(operation)),
+                None => format!("{:?}", operation),
+            },
+            NodeKind::Apply { .. } => "Apply".to_owned(),
+            NodeKind::Gamma { .. } => "Gamma".to_owned(),
+            NodeKind::Theta { .. } => "Theta".to_owned(),
+            NodeKind::Lambda { .. } => "Lambda".to_owned(),
+            NodeKind::Omega { .. } => "Omega".to_owned(),
+        };
+        let kind = match self.node_span(node_id) {
+            Some(span) => format!("{} @{}", kind, span),
+            None => kind,
+        };
+        let mut label_op = String::with_capacity(16);
+        for c in kind.chars() {
+            if c == '{' || c == '}' {
+                label_op.push('\\');
+            }
+            label_op.push(c);
+        }
+        if let Some(name) = self.node_name(node_id) {
+            label_op = format!("{}: {}", escape_record_label(&name), label_op);
+        }
+        let label_value = vec![dot_ins, label_op, dot_outs]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("}|{");
+        format!("{{{{{}}}}}", label_value)
+    }
+
+    pub fn region_summary(&self, region_id: RegionId) -> RegionSummary {
+        let region_data = self.region_data(region_id);
+
+        let state_args = region_data
+            .args
+            .iter()
+            .filter(|arg| arg.kind == PortKind::St)
+            .count();
+        let state_sources = region_data
+            .nodes
+            .iter()
+            .filter(|&&node_id| {
+                let node_data = self.node_data(node_id);
+                node_data.outs.iter().any(|out| out.kind == PortKind::St)
+                    && node_data.ins.iter().all(|user| user.kind != PortKind::St)
+            })
+            .count();
+
+        let mut depth = 0;
+        let mut node = region_data.node;
+        while let Some(node_id) = node {
+            depth += 1;
+            node = self.region_data(self.node_data(node_id).outer_region).node;
+        }
+
+        RegionSummary {
+            nodes: region_data.nodes.len(),
+            state_chains: state_args + state_sources,
+            depth,
+        }
+    }
+
+    /// Writes the `label` attribute of a region's graph or cluster.
+    pub(super) fn write_region_summary(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        indent: &str,
+    ) -> io::Result<()> {
+        let summary = self.region_summary(region_id);
+        writeln!(
+            out,
+            r#"{}label="region {}: {} nodes, {} state chains, depth {}""#,
+            indent, region_id.0, summary.nodes, summary.state_chains, summary.depth
+        )?;
+        writeln!(out, "{}labelloc=t", indent)
+    }
+}
+
+/// The field of a port in a record, labeled with its name if it has one.
+fn port_label<S>(
+    prefix: char,
+    index: usize,
+    name: Option<String>,
+    options: &DotOptions<S>,
+) -> String {
+    match name {
+        Some(name) => format!("<{}{}>{}", prefix, index, escape_record_label(&name)),
+        None if options.port_indices => format!("<{0}{1}>{1}", prefix, index),
+        None => format!("<{}{}>", prefix, index),
+    }
+}
+
+/// The port of an origin, as edges from it refer to it.
+fn origin_endpoint(origin_id: OriginId) -> String {
+    match origin_id {
+        OriginId::Out { node, index } => format!("n{}:o{}", node.0, index),
+        OriginId::Arg { region, index } => format!("r{}_args:a{}", region.0, index),
+    }
+}
+
+fn edge_style<S>(kind: PortKind, options: &DotOptions<S>) -> String {
+    match kind {
+        PortKind::Val => format!("color={}", options.val_color),
+        PortKind::St => format!("style=dashed, color={}", options.st_color),
+    }
+}
+
+/// Like `edge_style`, but for edges between a structural node and one of
+/// its regions.
+fn boundary_edge_style<S>(kind: PortKind, options: &DotOptions<S>) -> String {
+    match kind {
+        PortKind::Val => format!("style=bold, color={}", options.val_color),
+        PortKind::St => format!(r#"style="dashed,bold", color={}"#, options.st_color),
+    }
+}
+
+/// Writes a cluster with a sample of each kind of edge, styled like
+/// `NodeCtxt::print` styles them.
+pub(super) fn write_legend<S>(out: &mut dyn Write, options: &DotOptions<S>) -> io::Result<()> {
+    let samples = [
+        ("val", edge_style(PortKind::Val, options), "value"),
+        ("st", edge_style(PortKind::St, options), "state"),
+        (
+            "val_boundary",
+            boundary_edge_style(PortKind::Val, options),
+            "value across regions",
+        ),
+        (
+            "st_boundary",
+            boundary_edge_style(PortKind::St, options),
+            "state across regions",
+        ),
+    ];
+    writeln!(out, "    subgraph cluster_legend {{")?;
+    writeln!(out, r#"        label="legend""#)?;
+    writeln!(out, "        node [shape=point]")?;
+    for (name, style, label) in samples.iter() {
+        writeln!(
+            out,
+            r#"        legend_{0}_origin -> legend_{0}_user [{1}, label="{2}"]"#,
+            name, style, label
+        )?;
+    }
+    writeln!(out, "    }}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DotOptions, RankDir, RegionSummary};
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn legend_and_summary() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n1.st_out(0))
+            .finish();
+
+        let mut buffer = Vec::new();
+        let options = DotOptions {
+            legend: true,
+            region_summaries: true,
+            ..DotOptions::default()
+        };
+        ncx.print_with(&mut buffer, &options).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            content,
+            r#"digraph rvsdg {
+    node [shape=record]
+    edge [arrowhead=none]
+    label="region 0: 3 nodes, 1 state chains, depth 0"
+    labelloc=t
+    n0 [label="{{Lit(0)}|{<o0>0}}"]
+    n1 [label="{{St}|{<o0>0}}"]
+    n2 [label="{{<i0>0|<i1>1}|{Store}|{<o0>0}}"]
+    n0:o0 -> n2:i0 [color=blue]
+    n1:o0 -> n2:i1 [style=dashed, color=red]
+    subgraph cluster_legend {
+        label="legend"
+        node [shape=point]
+        legend_val_origin -> legend_val_user [color=blue, label="value"]
+        legend_st_origin -> legend_st_user [style=dashed, color=red, label="state"]
+        legend_val_boundary_origin -> legend_val_boundary_user [style=bold, color=blue, label="value across regions"]
+        legend_st_boundary_origin -> legend_st_boundary_user [style="dashed,bold", color=red, label="state across regions"]
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn custom_styles_and_labels() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n1.st_out(0))
+            .finish()
+            .set_name("sink");
+        ncx.set_origin_name(n0.val_out(0).id(), "x");
+
+        let mut buffer = Vec::new();
+        let options = DotOptions {
+            val_color: "black".to_owned(),
+            st_color: "gray".to_owned(),
+            rankdir: Some(RankDir::LeftToRight),
+            port_indices: false,
+            node_label: Some(Box::new(|op: &Op| format!("{:?}", op).to_lowercase())),
+            ..DotOptions::default()
+        };
+        ncx.print_with(&mut buffer, &options).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            content,
+            r#"digraph rvsdg {
+    rankdir=LR
+    node [shape=record]
+    edge [arrowhead=none]
+    n0 [label="{{lit(0)}|{<o0>x}}"]
+    n1 [label="{{st}|{<o0>}}"]
+    n2 [label="{{<i0>|<i1>}|{sink: store}|{<o0>}}"]
+    n0:o0 -> n2:i0 [color=black]
+    n1:o0 -> n2:i1 [style=dashed, color=gray]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn summaries_of_nested_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let theta = ncx.theta_builder(ncx.root_region());
+        let body = theta.body();
+        let (x_arg, _) = theta.loop_var(n0.val_out(0));
+        let (st_arg, _) = theta.loop_state(st.st_out(0));
+        let gamma = ncx.gamma_builder(x_arg, 2);
+        let states = gamma.entry_state(st_arg);
+        let store = ncx
+            .node_builder_in(gamma.branch(0), Op::Store)
+            .operand(
+                ncx.node_builder_in(gamma.branch(0), Op::Lit(1))
+                    .finish()
+                    .val_out(0),
+            )
+            .state(states[0])
+            .finish();
+        let st_next = gamma.exit_state(&[store.st_out(0), states[1]]);
+        gamma.finish();
+        theta.set_next_state(st_arg, st_next);
+        theta.finish(x_arg);
+
+        assert_eq!(
+            RegionSummary {
+                nodes: 3,
+                state_chains: 1,
+                depth: 0,
+            },
+            ncx.region_summary(ncx.root_region())
+        );
+        assert_eq!(
+            RegionSummary {
+                nodes: 1,
+                state_chains: 1,
+                depth: 1,
+            },
+            ncx.region_summary(body)
+        );
+        assert_eq!(
+            RegionSummary {
+                nodes: 2,
+                state_chains: 1,
+                depth: 2,
+            },
+            ncx.region_summary(store.region())
+        );
+    }
+
+    #[test]
+    fn boundary_edges_of_a_theta() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n0.val_out(0));
+        let (st_arg, _) = theta.loop_state(st.st_out(0));
+        let store = ncx
+            .node_builder_in(theta.body(), Op::Store)
+            .operand(x_arg)
+            .state(st_arg)
+            .finish();
+        theta.set_next_state(st_arg, store.st_out(0));
+        theta.finish(x_arg);
+
+        let mut buffer = Vec::new();
+        ncx.print(&mut buffer).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        let boundary_edges: Vec<&str> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.contains("bold"))
+            .collect();
+        assert_eq!(
+            boundary_edges,
+            [
+                "n2:i0 -> r1_args:a0 [style=bold, color=blue]",
+                r#"n2:i1 -> r1_args:a1 [style="dashed,bold", color=red]"#,
+                "r1_res:r1 -> n2:o0 [style=bold, color=blue, constraint=false]",
+                r#"r1_res:r2 -> n2:o1 [style="dashed,bold", color=red, constraint=false]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn printing_structural_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let args = gamma.entry_var(n0.val_out(0));
+        let states = gamma.entry_state(st.st_out(0));
+        let store = ncx
+            .node_builder_in(gamma.branch(0), Op::Store)
+            .operand(args[0])
+            .state(states[0])
+            .finish();
+        let st_next = gamma.exit_state(&[store.st_out(0), states[1]]);
+        gamma.finish();
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st_next)
+            .finish();
+        ncx.set_origin_name(args[1].id(), "x");
+
+        let mut buffer = Vec::new();
+        ncx.print(&mut buffer).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            content,
+            r#"digraph rvsdg {
+    node [shape=record]
+    edge [arrowhead=none]
+    n0 [label="{{Lit(0)}|{<o0>0}}"]
+    n1 [label="{{St}|{<o0>0}}"]
+    subgraph cluster_n2 {
+        n2 [label="{{<i0>0|<i1>1|<i2>2}|{Gamma}|{<o0>0}}"]
+        subgraph cluster_r1 {
+            r1_args [label="<a0>0|<a1>1"]
+            n2:i1 -> r1_args:a0 [style=bold, color=blue]
+            n2:i2 -> r1_args:a1 [style="dashed,bold", color=red]
+            n3 [label="{{<i0>0|<i1>1}|{Store}|{<o0>0}}"]
+            r1_args:a0 -> n3:i0 [color=blue]
+            r1_args:a1 -> n3:i1 [style=dashed, color=red]
+            r1_res [label="<r0>0"]
+            n3:o0 -> r1_res:r0 [style=dashed, color=red]
+            r1_res:r0 -> n2:o0 [style="dashed,bold", color=red, constraint=false]
+        }
+        subgraph cluster_r2 {
+            r2_args [label="<a0>x|<a1>1"]
+            n2:i1 -> r2_args:a0 [style=bold, color=blue]
+            n2:i2 -> r2_args:a1 [style="dashed,bold", color=red]
+            r2_res [label="<r0>0"]
+            r2_args:a1 -> r2_res:r0 [style=dashed, color=red]
+            r2_res:r0 -> n2:o0 [style="dashed,bold", color=red, constraint=false]
+        }
+    }
+    n0:o0 -> n2:i0 [color=blue]
+    n0:o0 -> n2:i1 [color=blue]
+    n1:o0 -> n2:i2 [style=dashed, color=red]
+    n4 [label="{{<i0>0|<i1>1}|{Store}|{<o0>0}}"]
+    n0:o0 -> n4:i0 [color=blue]
+    n2:o0 -> n4:i1 [style=dashed, color=red]
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, UserId};
+use std::collections::HashSet;
+
+/// Ops whose effects can be observed from outside of the program, such as
+/// calls, volatile stores and IO.
+pub trait Observable {
+    fn is_externally_observable(&self) -> bool;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Checks that every externally observable op lies on a state chain that
+    /// reaches an export of an omega node, or a result of a lambda, returning
+    /// the ones that don't.
+    ///
+    /// Such ops are dead as far as the graph is concerned, and would be
+    /// removed by dead code elimination, which is almost always a bug in the
+    /// frontend that forgot to thread their state through.
+    pub fn verify_effect_ordering(&self) -> Result<(), Vec<NodeId>>
+    where
+        S: Observable,
+    {
+        let ordered = self.state_ordered_nodes();
+        let unordered: Vec<NodeId> = self
+            .nodes
+            .iter()
+            .map(|node| node.borrow())
+            .enumerate()
+            .filter(|(_, node_data)| !node_data.removed)
+            .filter(|(_, node_data)| match &node_data.kind {
+                NodeKind::Op(op) => op.is_externally_observable(),
+                _ => false,
+            })
+            .map(|(index, _)| NodeId::new(index))
+            .filter(|node| !ordered.contains(node))
+            .collect();
+
+        if unordered.is_empty() {
+            Ok(())
+        } else {
+            Err(unordered)
+        }
+    }
+
+    /// Collects the nodes whose state outputs are transitively used by the
+    /// state exports of omega nodes, or the state results of lambdas, which
+    /// are observed by their callers.
+    fn state_ordered_nodes(&self) -> HashSet<NodeId> {
+        let mut ordered = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut worklist = vec![];
+
+        for (index, node_data) in self.nodes.iter().map(|node| node.borrow()).enumerate() {
+            if let NodeKind::Omega { .. } | NodeKind::Lambda { .. } = node_data.kind {
+                if !node_data.removed {
+                    self.push_state_results(NodeId::new(index), &mut worklist);
+                }
+            }
+        }
+
+        while let Some(origin) = worklist.pop() {
+            if !visited.insert(origin) {
+                continue;
+            }
+            match origin {
+                OriginId::Out { node, index } => {
+                    self.push_output_results(node, usize::from(index), &mut worklist);
+                    if !ordered.insert(node) {
+                        continue;
+                    }
+                    let node_data = self.node_data(node);
+                    worklist.extend(
+                        node_data
+                            .ins
+                            .iter()
+                            .filter(|user| user.kind == PortKind::St)
+                            .filter_map(|user| user.origin.get()),
+                    );
+                }
+                OriginId::Arg { region, index } => {
+                    // Arguments pass on the state of the enclosing region.
+                    let source = self.origin_data(origin).source;
+                    if let Some(origin) = source.and_then(|user| self.user_data(user).origin.get())
+                    {
+                        worklist.push(origin);
+                    }
+                    // The arguments of a loop body also take the state its
+                    // results left in the previous iteration.
+                    let node = self.region_data(region).node;
+                    if let Some(node) = node {
+                        if let NodeKind::Theta { .. } = self.node_data(node).kind {
+                            let result = UserId::result(region, usize::from(index) + 1);
+                            worklist.extend(self.user_data(result).origin.get());
+                        }
+                    }
+                }
+            }
+        }
+
+        ordered
+    }
+
+    /// Pushes the origins of the state results of every region inside `node`.
+    fn push_state_results(&self, node: NodeId, worklist: &mut Vec<OriginId>) {
+        for region in self.inner_regions(node) {
+            let region_data = self.region_data(region);
+            for (index, res) in region_data.res.iter().enumerate() {
+                if res.kind == PortKind::St {
+                    worklist.extend(self.user_data(UserId::result(region, index)).origin.get());
+                }
+            }
+        }
+    }
+
+    /// Pushes the origins of the results that output `index` of `node` is
+    /// taken from: the result of that index in each branch of a gamma, or
+    /// the one after the predicate in the body of a theta.
+    fn push_output_results(&self, node: NodeId, index: usize, worklist: &mut Vec<OriginId>) {
+        let index = match self.node_data(node).kind {
+            NodeKind::Gamma { .. } => index,
+            NodeKind::Theta { .. } => index + 1,
+            _ => return,
+        };
+        for region in self.inner_regions(node) {
+            worklist.extend(self.user_data(UserId::result(region, index)).origin.get());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, OriginId, RegionSigS, StOrigin, UserId};
+
+    #[test]
+    fn effects_reaching_an_export() {
+        let ncx = NodeCtxt::new();
+
+        let omega = ncx.mk_node_with(
+            NodeKind::Omega {
+                imports: 1,
+                exports: 1,
+            },
+            &[],
+        );
+        let region = ncx.mk_region_for_node(
+            omega,
+            RegionSigS {
+                st_args: 1,
+                st_res: 1,
+                ..RegionSigS::default()
+            },
+        );
+
+        let st = ncx.origin_ref(OriginId::Arg { region, index: 0 });
+        let n0 = ncx.node_builder_in(region, Op::Lit(0)).finish();
+        let n1 = ncx
+            .node_builder_in(region, Op::Print)
+            .operand(n0.val_out(0))
+            .state(StOrigin(st))
+            .finish();
+        let n2 = ncx
+            .node_builder_in(region, Op::Print)
+            .operand(n0.val_out(0))
+            .state(n1.st_out(0))
+            .finish();
+
+        assert_eq!(Err(vec![n1.id(), n2.id()]), ncx.verify_effect_ordering());
+
+        ncx.connect_ports(UserId::Res { region, index: 0 }, n2.st_out(0).id());
+
+        assert_eq!(Ok(()), ncx.verify_effect_ordering());
+    }
+
+    #[test]
+    fn effects_inside_structural_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let omega = ncx.mk_node_with(
+            NodeKind::Omega {
+                imports: 1,
+                exports: 1,
+            },
+            &[],
+        );
+        let region = ncx.mk_region_for_node(
+            omega,
+            RegionSigS {
+                st_args: 1,
+                st_res: 1,
+                ..RegionSigS::default()
+            },
+        );
+
+        let st = ncx.origin_ref(OriginId::Arg { region, index: 0 });
+        let n0 = ncx.node_builder_in(region, Op::Lit(0)).finish();
+
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let x_args = gamma.entry_var(n0.val_out(0));
+        let st_args = gamma.entry_state(StOrigin(st));
+        let n1 = ncx
+            .node_builder_in(gamma.branch(1), Op::Print)
+            .operand(x_args[1])
+            .state(st_args[1])
+            .finish();
+        // The effect of this print is dropped on the floor.
+        let n2 = ncx
+            .node_builder_in(gamma.branch(0), Op::Print)
+            .operand(x_args[0])
+            .state(st_args[0])
+            .finish();
+        let st_out = gamma.exit_state(&[st_args[0], n1.st_out(0)]);
+        gamma.finish();
+
+        ncx.connect_ports(UserId::Res { region, index: 0 }, st_out.id());
+
+        assert_eq!(Err(vec![n2.id()]), ncx.verify_effect_ordering());
+    }
+
+    #[test]
+    fn effects_reaching_unused_exits() {
+        let ncx = NodeCtxt::new();
+
+        let omega = ncx.mk_node_with(
+            NodeKind::Omega {
+                imports: 1,
+                exports: 1,
+            },
+            &[],
+        );
+        let region = ncx.mk_region_for_node(
+            omega,
+            RegionSigS {
+                st_args: 1,
+                st_res: 1,
+                ..RegionSigS::default()
+            },
+        );
+
+        let st = ncx.origin_ref(OriginId::Arg { region, index: 0 });
+        let n0 = ncx.node_builder_in(region, Op::Lit(0)).finish();
+
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let x_args = gamma.entry_var(n0.val_out(0));
+        let st_args = gamma.entry_state(StOrigin(st));
+        let n1 = ncx
+            .node_builder_in(gamma.branch(1), Op::Print)
+            .operand(x_args[1])
+            .state(st_args[1])
+            .finish();
+        let n2 = ncx
+            .node_builder_in(gamma.branch(0), Op::Print)
+            .operand(x_args[0])
+            .state(st_args[0])
+            .finish();
+        let st_out = gamma.exit_state(&[st_args[0], n1.st_out(0)]);
+        // The gamma leaves the state of the second print by an exit that
+        // nothing uses.
+        gamma.exit_state(&[n2.st_out(0), st_args[1]]);
+        gamma.finish();
+
+        ncx.connect_ports(UserId::Res { region, index: 0 }, st_out.id());
+
+        assert_eq!(Err(vec![n2.id()]), ncx.verify_effect_ordering());
+    }
+}
@@ -0,0 +1,521 @@
+use super::{
+    NodeCtxt, NodeId, NodeKind, OriginId, Pattern, RegionId, Replacement, Rewriter, Sig, UserId,
+    ValOrigin,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+};
+
+/// The cost of ops, for choosing among equal values.
+pub trait CostModel<S> {
+    /// The cost of a node of `op`, not counting its operands.
+    fn cost(&self, op: &S) -> usize;
+}
+
+/// An equivalence class of e-nodes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+struct ClassId(usize);
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum ENode<S> {
+    /// A value from outside the stateless dataflow, such as an argument or
+    /// the output of a stateful node.
+    Leaf(OriginId),
+    Op(S, Vec<ClassId>),
+}
+
+/// The stateless dataflow of a region as an e-graph, where equal values
+/// share a class.
+pub struct EGraph<S> {
+    region: RegionId,
+    /// The union-find forest of classes.
+    parents: Vec<ClassId>,
+    /// Every e-node, with its children canonical as of the last rebuild.
+    memo: HashMap<ENode<S>, ClassId>,
+    /// The e-nodes of `memo` in the order they were added, so that ties are
+    /// broken the same way every time.
+    enodes: Vec<ENode<S>>,
+    /// The nodes the e-graph was made from, with their classes.
+    exported: Vec<(NodeId, ClassId)>,
+}
+
+impl<S> EGraph<S>
+where
+    S: Sig + Eq + Hash + Clone,
+{
+    /// Makes an e-graph of the stateless nodes with a single value output
+    /// in `region_id`. Everything else they use becomes a leaf.
+    pub fn from_region(ncx: &NodeCtxt<S>, region_id: RegionId) -> EGraph<S> {
+        let mut egraph = EGraph {
+            region: region_id,
+            parents: vec![],
+            memo: HashMap::new(),
+            enodes: vec![],
+            exported: vec![],
+        };
+        let mut classes: HashMap<OriginId, ClassId> = HashMap::new();
+        for node_id in ncx.region_topo_order(region_id) {
+            let (op, origins) = match ncx.dataflow_term(node_id) {
+                Some(term) => term,
+                None => continue,
+            };
+            let children = origins
+                .into_iter()
+                .map(|origin_id| match classes.get(&origin_id) {
+                    Some(&class) => class,
+                    None => egraph.add(ENode::Leaf(origin_id)),
+                })
+                .collect();
+            let class = egraph.add(ENode::Op(op, children));
+            classes.insert(
+                OriginId::Out {
+                    node: node_id,
+                    index: 0,
+                },
+                class,
+            );
+            egraph.exported.push((node_id, class));
+        }
+        egraph
+    }
+
+    /// Applies the rules of `rewriter` until they add no new equalities, or
+    /// until they were applied `max_iterations` times. Returns whether the
+    /// e-graph was saturated.
+    pub fn saturate(&mut self, rewriter: &Rewriter<S>, max_iterations: usize) -> bool {
+        for _ in 0..max_iterations {
+            let members = self.members();
+            let mut matches = vec![];
+            for (pattern, replacement) in rewriter.rules() {
+                for &class in members.keys() {
+                    for bindings in self.ematch(pattern, class, vec![], &members) {
+                        matches.push((class, replacement, bindings));
+                    }
+                }
+            }
+
+            let mut changed = false;
+            for (class, replacement, bindings) in matches {
+                let new_class = self.add_replacement(replacement, &bindings);
+                changed |= self.union(class, new_class);
+            }
+            self.rebuild();
+            if !changed {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Replaces the values of the nodes the e-graph was made from with the
+    /// cheapest equal ones under `costs`. Returns how many values used
+    /// outside of the stateless dataflow were replaced.
+    ///
+    /// Nodes left without users that had some before are removed.
+    pub fn rebuild_region<C>(&self, ncx: &NodeCtxt<S>, costs: &C) -> usize
+    where
+        C: CostModel<S>,
+    {
+        let best = self.extract(costs);
+        let exported: HashSet<NodeId> = self.exported.iter().map(|&(node_id, _)| node_id).collect();
+        let had_users: Vec<bool> = self
+            .exported
+            .iter()
+            .map(|&(node_id, _)| ncx.node_data(node_id).outs[0].users.get().is_some())
+            .collect();
+
+        let mut built = HashMap::new();
+        let mut num_replaced = 0;
+        for &(node_id, class) in &self.exported {
+            let origin_id = OriginId::Out {
+                node: node_id,
+                index: 0,
+            };
+            let outer_users: Vec<UserId> = ncx
+                .origin_ref(origin_id)
+                .users()
+                .map(|user| user.id())
+                .filter(|&user_id| match user_id {
+                    UserId::In { node, .. } => !exported.contains(&node),
+                    UserId::Res { .. } => true,
+                })
+                .collect();
+            if outer_users.is_empty() {
+                continue;
+            }
+            let new_origin_id = self.build(ncx, self.find(class), &best, &mut built);
+            if new_origin_id != origin_id {
+                for user_id in outer_users {
+                    ncx.reconnect(user_id, new_origin_id);
+                }
+                num_replaced += 1;
+            }
+        }
+
+        // Users come after their operands, so going backwards removes whole
+        // trees at once.
+        for (&(node_id, _), &had_users) in self.exported.iter().zip(&had_users).rev() {
+            let node_data = ncx.node_data(node_id);
+            let is_dead = !node_data.removed && node_data.outs[0].users.get().is_none();
+            drop(node_data);
+            if had_users && is_dead {
+                ncx.remove_node(node_id);
+            }
+        }
+        num_replaced
+    }
+
+    fn find(&self, mut class: ClassId) -> ClassId {
+        while self.parents[class.0] != class {
+            class = self.parents[class.0];
+        }
+        class
+    }
+
+    fn canonicalize(&self, enode: &ENode<S>) -> ENode<S> {
+        match enode {
+            ENode::Leaf(origin_id) => ENode::Leaf(*origin_id),
+            ENode::Op(op, children) => ENode::Op(
+                op.clone(),
+                children.iter().map(|&child| self.find(child)).collect(),
+            ),
+        }
+    }
+
+    fn add(&mut self, enode: ENode<S>) -> ClassId {
+        let enode = self.canonicalize(&enode);
+        if let Some(&class) = self.memo.get(&enode) {
+            return self.find(class);
+        }
+        let class = ClassId(self.parents.len());
+        self.parents.push(class);
+        self.memo.insert(enode.clone(), class);
+        self.enodes.push(enode);
+        class
+    }
+
+    /// Merges the classes of `a` and `b`, returning whether they were
+    /// different.
+    fn union(&mut self, a: ClassId, b: ClassId) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (root, child) = if a.0 < b.0 { (a, b) } else { (b, a) };
+        self.parents[child.0] = root;
+        true
+    }
+
+    /// Restores congruence: e-nodes that became equal after a union put
+    /// their classes together.
+    fn rebuild(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let memo = std::mem::take(&mut self.memo);
+            let enodes = std::mem::take(&mut self.enodes);
+            for enode in enodes {
+                let class = self.find(memo[&enode]);
+                let enode = self.canonicalize(&enode);
+                match self.memo.get(&enode) {
+                    Some(&other) => changed |= self.union(class, other),
+                    None => {
+                        self.memo.insert(enode.clone(), class);
+                        self.enodes.push(enode);
+                    }
+                }
+            }
+        }
+    }
+
+    fn members(&self) -> BTreeMap<ClassId, Vec<ENode<S>>> {
+        let mut members: BTreeMap<ClassId, Vec<ENode<S>>> = BTreeMap::new();
+        for enode in &self.enodes {
+            members
+                .entry(self.find(self.memo[enode]))
+                .or_default()
+                .push(self.canonicalize(enode));
+        }
+        members
+    }
+
+    /// Every way the values of `class` match `pattern`, extending
+    /// `bindings`.
+    fn ematch(
+        &self,
+        pattern: &Pattern<S>,
+        class: ClassId,
+        mut bindings: Vec<Option<ClassId>>,
+        members: &BTreeMap<ClassId, Vec<ENode<S>>>,
+    ) -> Vec<Vec<Option<ClassId>>> {
+        match pattern {
+            Pattern::Var(var) => {
+                if bindings.len() <= *var {
+                    bindings.resize(var + 1, None);
+                }
+                match bindings[*var] {
+                    Some(bound) if bound != class => vec![],
+                    _ => {
+                        bindings[*var] = Some(class);
+                        vec![bindings]
+                    }
+                }
+            }
+            Pattern::Op(op, operands) => {
+                let enodes = members.get(&class).map_or(&[][..], Vec::as_slice);
+                let mut all_bindings = vec![];
+                for enode in enodes {
+                    let children = match enode {
+                        ENode::Op(enode_op, children)
+                            if enode_op == op && children.len() == operands.len() =>
+                        {
+                            children
+                        }
+                        _ => continue,
+                    };
+                    let mut partial = vec![bindings.clone()];
+                    for (&child, operand) in children.iter().zip(operands) {
+                        partial = partial
+                            .into_iter()
+                            .flat_map(|bindings| self.ematch(operand, child, bindings, members))
+                            .collect();
+                    }
+                    all_bindings.extend(partial);
+                }
+                all_bindings
+            }
+        }
+    }
+
+    fn add_replacement(
+        &mut self,
+        replacement: &Replacement<S>,
+        bindings: &[Option<ClassId>],
+    ) -> ClassId {
+        match replacement {
+            Replacement::Var(var) => bindings[*var].unwrap(),
+            Replacement::Op(op, operands) => {
+                let children = operands
+                    .iter()
+                    .map(|operand| self.add_replacement(operand, bindings))
+                    .collect();
+                self.add(ENode::Op(op.clone(), children))
+            }
+        }
+    }
+
+    /// The cheapest e-node of each class, along with the cost of the whole
+    /// tree it stands for. Leaves cost nothing.
+    fn extract<C>(&self, costs: &C) -> HashMap<ClassId, (usize, ENode<S>)>
+    where
+        C: CostModel<S>,
+    {
+        let mut best: HashMap<ClassId, (usize, ENode<S>)> = HashMap::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for enode in &self.enodes {
+                let class = self.find(self.memo[enode]);
+                let cost = match enode {
+                    ENode::Leaf(..) => Some(0),
+                    ENode::Op(op, children) => {
+                        children.iter().try_fold(costs.cost(op), |sum, child| {
+                            best.get(&self.find(*child))
+                                .map(|&(cost, _)| sum.saturating_add(cost))
+                        })
+                    }
+                };
+                let is_better = match (cost, best.get(&class)) {
+                    (Some(cost), Some(&(best_cost, _))) => cost < best_cost,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                if is_better {
+                    best.insert(class, (cost.unwrap(), self.canonicalize(enode)));
+                    changed = true;
+                }
+            }
+        }
+        best
+    }
+
+    fn build(
+        &self,
+        ncx: &NodeCtxt<S>,
+        class: ClassId,
+        best: &HashMap<ClassId, (usize, ENode<S>)>,
+        built: &mut HashMap<ClassId, OriginId>,
+    ) -> OriginId {
+        if let Some(&origin_id) = built.get(&class) {
+            return origin_id;
+        }
+        let origin_id = match &best[&class].1 {
+            ENode::Leaf(origin_id) => *origin_id,
+            ENode::Op(op, children) => {
+                let builder = ncx.node_builder_in(self.region, op.clone());
+                let builder = children.iter().fold(builder, |builder, &child| {
+                    let origin_id = self.build(ncx, self.find(child), best, built);
+                    builder.operand(ValOrigin(ncx.origin_ref(origin_id)))
+                });
+                builder.finish().val_out(0).id()
+            }
+        };
+        built.insert(class, origin_id);
+        origin_id
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// The op and operands of a stateless node with a single value output,
+    /// if all of its operands are connected.
+    pub(super) fn dataflow_term(&self, node_id: NodeId) -> Option<(S, Vec<OriginId>)>
+    where
+        S: Sig + Clone,
+    {
+        let node_data = self.node_data(node_id);
+        let op = match node_data.kind {
+            NodeKind::Op(ref op) if !node_data.removed => op,
+            _ => return None,
+        };
+        let sig = op.sig();
+        if sig.st_ins > 0 || sig.st_outs > 0 || sig.val_outs != 1 {
+            return None;
+        }
+        let origins = node_data
+            .ins
+            .iter()
+            .map(|user| user.origin.get())
+            .collect::<Option<_>>()?;
+        Some((op.clone(), origins))
+    }
+
+    /// Runs the rules of `rewriter` to saturation over the stateless
+    /// dataflow of `region_id`, or until `max_iterations`, then rebuilds it
+    /// from the cheapest values under `costs`. Returns how many values used
+    /// outside of the dataflow were replaced.
+    pub fn saturate_region<C>(
+        &self,
+        region_id: RegionId,
+        rewriter: &Rewriter<S>,
+        costs: &C,
+        max_iterations: usize,
+    ) -> usize
+    where
+        C: CostModel<S>,
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut egraph = EGraph::from_region(self, region_id);
+        egraph.saturate(rewriter, max_iterations);
+        egraph.rebuild_region(self, costs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CostModel, EGraph};
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, Pattern as P, Replacement as R, Rewriter};
+
+    struct Latency;
+
+    impl CostModel<Op> for Latency {
+        fn cost(&self, op: &Op) -> usize {
+            match op {
+                Op::Mul => 4,
+                Op::Add | Op::Shl => 1,
+                _ => 0,
+            }
+        }
+    }
+
+    fn rewriter() -> Rewriter<Op> {
+        Rewriter::new()
+            .rule(
+                P::Op(Op::Add, vec![P::Var(0), P::Var(1)]),
+                R::Op(Op::Add, vec![R::Var(1), R::Var(0)]),
+            )
+            .rule(
+                P::Op(Op::Mul, vec![P::Var(0), P::Op(Op::Lit(1), vec![])]),
+                R::Var(0),
+            )
+            .rule(
+                P::Op(Op::Mul, vec![P::Var(0), P::Op(Op::Lit(2), vec![])]),
+                R::Op(Op::Shl, vec![R::Var(0), R::Op(Op::Lit(1), vec![])]),
+            )
+    }
+
+    #[test]
+    fn extracting_the_cheapest_values() {
+        let ncx = NodeCtxt::new();
+
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_two = ncx.mk_node(Op::Lit(2));
+        let n_mul = ncx
+            .node_builder(Op::Mul)
+            .operand(n_param.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        let n_mul = ncx
+            .node_builder(Op::Mul)
+            .operand(n_mul.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+        let n_ret = ncx
+            .node_builder(Op::Sink)
+            .operand(n_mul.val_out(0))
+            .finish();
+
+        assert_eq!(
+            1,
+            ncx.saturate_region(ncx.root_region(), &rewriter(), &Latency, 8)
+        );
+        let n_shl = n_ret.val_in(0).origin().producer();
+        assert_eq!(*n_shl.kind(), NodeKind::Op(Op::Shl));
+        assert_eq!(n_shl.val_in(0).origin(), n_param.val_out(0));
+        assert_eq!(n_shl.val_in(1).origin(), n_one.val_out(0));
+        assert!(ncx.node_data(n_mul.id()).removed);
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn saturating_commutative_rules() {
+        let ncx = NodeCtxt::new();
+
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_param.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        ncx.node_builder(Op::Sink)
+            .operand(n_add.val_out(0))
+            .finish();
+
+        // Swapping operands twice gives the term back, so there's nothing
+        // new after a while.
+        let mut egraph = EGraph::from_region(&ncx, ncx.root_region());
+        assert!(egraph.saturate(&rewriter(), 8));
+        assert_eq!(0, egraph.rebuild_region(&ncx, &Latency));
+    }
+
+    #[test]
+    fn limiting_iterations() {
+        let ncx = NodeCtxt::new();
+
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_param.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        ncx.node_builder(Op::Sink)
+            .operand(n_add.val_out(0))
+            .finish();
+
+        let mut egraph = EGraph::from_region(&ncx, ncx.root_region());
+        assert!(!egraph.saturate(&rewriter(), 1));
+    }
+}
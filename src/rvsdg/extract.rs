@@ -0,0 +1,148 @@
+use super::{IdMap, NodeCtxt, NodeCtxtConfig, NodeId, OriginId, Sig};
+use std::{collections::HashSet, hash::Hash};
+
+impl<S> NodeCtxt<S> {
+    /// Copies the producers of `roots`, and those of their operands in turn,
+    /// into a fresh context with the same interning options.
+    ///
+    /// An origin in a nested region counts as produced by the node of the
+    /// root region enclosing it, which is copied whole, along with its
+    /// regions. Nodes are interned again as they're copied, so the copies of
+    /// shared producers stay shared.
+    pub fn extract_subgraph(&self, roots: &[OriginId]) -> NodeCtxt<S>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut producers = HashSet::new();
+        let mut worklist: Vec<NodeId> = roots
+            .iter()
+            .map(|&origin_id| self.enclosing_node(origin_id))
+            .collect();
+        while let Some(node_id) = worklist.pop() {
+            if !producers.insert(node_id) {
+                continue;
+            }
+            let node_data = self.node_data(node_id);
+            worklist.extend(
+                node_data
+                    .ins
+                    .iter()
+                    .filter_map(|user| user.origin.get())
+                    .map(|origin_id| self.enclosing_node(origin_id)),
+            );
+        }
+
+        let subgraph = NodeCtxt::with_config(NodeCtxtConfig {
+            interning: self.config.interning.clone(),
+            opt_region_cleanup: self.config.opt_region_cleanup,
+            opt_transfer_names: self.config.opt_transfer_names,
+            opt_type_check: self.config.opt_type_check,
+            opt_cycle_check: self.config.opt_cycle_check,
+            opt_linear_state: self.config.opt_linear_state,
+            crash_snapshot: None,
+        });
+        let into = subgraph.root_region();
+        let mut map = IdMap::default();
+        for node_id in self.region_topo_order(self.root_region()) {
+            if producers.contains(&node_id) {
+                subgraph.copy_node_from(self, node_id, into, &mut map);
+            }
+        }
+        subgraph
+    }
+
+    /// The node of the root region that produces `origin_id`, or encloses
+    /// the region it's in.
+    fn enclosing_node(&self, origin_id: OriginId) -> NodeId {
+        let mut node_id = match origin_id {
+            OriginId::Out { node, .. } => node,
+            OriginId::Arg { region, .. } => self
+                .region_data(region)
+                .node
+                .expect("the root region has no arguments"),
+        };
+        loop {
+            let outer_region = self.node_data(node_id).outer_region;
+            match self.region_data(outer_region).node {
+                Some(outer_node) => node_id = outer_node,
+                None => return node_id,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn extracting_shared_producers() {
+        let ncx = NodeCtxt::new();
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_two = ncx.mk_node(Op::Lit(2));
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_one.val_out(0)).finish();
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_neg.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        ncx.node_builder(Op::Neg).operand(n_two.val_out(0)).finish();
+
+        let subgraph = ncx.extract_subgraph(&[n_add.val_out(0).id(), n_neg.val_out(0).id()]);
+        assert_eq!(Ok(()), subgraph.verify());
+        // The literal both use is copied once, and the unrelated nodes are
+        // left out.
+        assert_eq!(3, subgraph.num_nodes());
+        let n_one = subgraph.mk_node(Op::Lit(1));
+        let n_neg = subgraph
+            .node_builder(Op::Neg)
+            .operand(n_one.val_out(0))
+            .finish();
+        let n_add = subgraph
+            .node_builder(Op::Add)
+            .operand(n_neg.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        assert_eq!(3, subgraph.num_nodes());
+        assert_eq!(*n_add.kind(), NodeKind::Op(Op::Add));
+    }
+
+    #[test]
+    fn extracting_values_of_nested_regions() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        ncx.mk_node(Op::Lit(2));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_one.val_out(0));
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        gamma.exit_var(&[n_neg.val_out(0), args[1]]);
+        gamma.finish();
+
+        let subgraph = ncx.extract_subgraph(&[n_neg.val_out(0).id()]);
+        assert_eq!(Ok(()), subgraph.verify());
+        // The whole gamma node is copied, with what its inputs use.
+        assert_eq!(4, subgraph.num_nodes());
+        let region = subgraph.region_ref(subgraph.root_region());
+        let gamma = region
+            .nodes()
+            .find(|node| matches!(*node.kind(), NodeKind::Gamma { .. }))
+            .unwrap();
+        assert_eq!(
+            *gamma.kind(),
+            NodeKind::Gamma {
+                val_ins: 1,
+                val_outs: 1,
+                st_ins: 0,
+                st_outs: 0,
+            }
+        );
+        assert_eq!(
+            *gamma.val_in(0).origin().producer().kind(),
+            NodeKind::Op(Op::Lit(0))
+        );
+    }
+}
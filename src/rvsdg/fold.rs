@@ -0,0 +1,142 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, Sig};
+use std::hash::Hash;
+
+/// Evaluation of ops on constant operands.
+pub trait Fold: Sized {
+    type ConstValue;
+
+    /// The value of an op that produces a constant, such as a literal.
+    fn as_const(&self) -> Option<Self::ConstValue>;
+
+    /// The op producing the constant `value`.
+    fn from_const(value: Self::ConstValue) -> Self;
+
+    /// Evaluates the op on `operands`, if possible.
+    fn try_fold(&self, operands: &[Self::ConstValue]) -> Option<Self::ConstValue>;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Replaces stateless nodes whose operands are all constants with the
+    /// constant they evaluate to, until no more nodes can be folded. Returns
+    /// how many nodes were folded.
+    ///
+    /// Folded nodes are removed, but the constants they used are left for
+    /// dead code elimination to deal with.
+    pub fn fold_constants(&self) -> usize
+    where
+        S: Fold + Sig + Eq + Hash + Clone,
+    {
+        let mut num_folded = 0;
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+            let num_nodes = self.nodes.len();
+            for index in 0..num_nodes {
+                let node_id = NodeId::new(index);
+                if let Some(value) = self.try_fold_node(node_id) {
+                    let region_id = self.node_data(node_id).outer_region;
+                    let node = self.node_ref(node_id);
+                    let folded = self
+                        .node_builder_in(region_id, S::from_const(value))
+                        .finish();
+                    node.val_out(0).replace_all_users_with(folded.val_out(0));
+                    self.remove_node(node_id);
+                    num_folded += 1;
+                    changed = true;
+                }
+            }
+        }
+
+        num_folded
+    }
+
+    fn try_fold_node(&self, node_id: NodeId) -> Option<S::ConstValue>
+    where
+        S: Fold + Sig,
+    {
+        let node_data = self.node_data(node_id);
+        if node_data.removed {
+            return None;
+        }
+        let op = match &node_data.kind {
+            NodeKind::Op(op) => op,
+            _ => return None,
+        };
+        let sig = op.sig();
+        if sig.st_ins > 0 || sig.st_outs > 0 || sig.val_outs != 1 || op.as_const().is_some() {
+            return None;
+        }
+
+        let operands = node_data
+            .ins
+            .iter()
+            .map(|user| match user.origin.get()? {
+                OriginId::Out { node, .. } => match &self.node_data(node).kind {
+                    NodeKind::Op(operand) => operand.as_const(),
+                    _ => None,
+                },
+                OriginId::Arg { .. } => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        op.try_fold(&operands)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn folding_constant_expressions() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(2));
+        let n1 = ncx.mk_node(Op::Lit(3));
+        let n2 = ncx
+            .node_builder(Op::Add)
+            .operand(n0.val_out(0))
+            .operand(n1.val_out(0))
+            .finish();
+        let n3 = ncx.node_builder(Op::Neg).operand(n2.val_out(0)).finish();
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n4 = ncx
+            .node_builder(Op::Add)
+            .operand(n3.val_out(0))
+            .operand(n_param.val_out(0))
+            .finish();
+
+        assert_eq!(2, ncx.fold_constants());
+
+        let folded = n4.val_in(0).origin().producer();
+        assert_eq!(*folded.kind(), NodeKind::Op(Op::Lit(-5)));
+        assert!(ncx.node_data(n2.id()).removed);
+        assert!(ncx.node_data(n3.id()).removed);
+    }
+
+    #[test]
+    fn folding_reuses_existing_literals() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(2));
+        let n1 = ncx.mk_node(Op::Lit(0));
+        let n2 = ncx.mk_node(Op::Lit(4));
+        let n3 = ncx
+            .node_builder(Op::Add)
+            .operand(n0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+        let n4 = ncx
+            .node_builder(Op::Div)
+            .operand(n3.val_out(0))
+            .operand(n1.val_out(0))
+            .finish();
+
+        assert_eq!(1, ncx.fold_constants());
+
+        // Division by zero is left alone.
+        assert_eq!(n2.val_out(0), n4.val_in(0).origin());
+        assert_eq!(n1.val_out(0), n4.val_in(1).origin());
+    }
+}
@@ -0,0 +1,272 @@
+use super::{NodeCtxt, NodeId, OriginId, RegionId, UserId};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+impl<S> NodeCtxt<S> {
+    /// Freezes a region and every region nested in it, so that none of them
+    /// can be changed until thawed, and returns its structural hash.
+    ///
+    /// The hash only depends on the shape of the region, not on the ids of
+    /// the nodes in it or the order they were made in, so equal regions get
+    /// equal hashes. Results of
+    /// analyses over a frozen region stay valid for as long as it's frozen.
+    pub fn freeze_region(&self, region_id: RegionId) -> u64
+    where
+        S: Hash,
+    {
+        if let Some(hash) = self.region_data(region_id).frozen {
+            return hash;
+        }
+
+        let nodes = self.canonical_node_order(region_id);
+        let local_index: HashMap<NodeId, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, &node_id)| (node_id, index))
+            .collect();
+        let local_origin = |origin_id: Option<OriginId>| match origin_id {
+            Some(OriginId::Out { node, index }) => {
+                Some((0, local_index.get(&node).cloned(), index))
+            }
+            Some(OriginId::Arg { index, .. }) => Some((1, None, index)),
+            None => None,
+        };
+
+        let mut hasher = DefaultHasher::new();
+
+        for arg in &self.region_data(region_id).args {
+            arg.kind.hash(&mut hasher);
+        }
+
+        for &node_id in &nodes {
+            let inner_regions = self.inner_regions(node_id);
+            let inner_hashes: Vec<u64> = inner_regions
+                .into_iter()
+                .map(|inner_region| self.freeze_region(inner_region))
+                .collect();
+
+            let node_data = self.node_data(node_id);
+            node_data.kind.hash(&mut hasher);
+            for user in node_data.ins.iter() {
+                local_origin(user.origin.get()).hash(&mut hasher);
+            }
+            node_data.outs.len().hash(&mut hasher);
+            inner_hashes.hash(&mut hasher);
+        }
+
+        for index in 0..self.region_data(region_id).res.len() {
+            let result = self.user_data(UserId::result(region_id, index));
+            result.kind.hash(&mut hasher);
+            local_origin(result.origin.get()).hash(&mut hasher);
+        }
+
+        let hash = hasher.finish();
+        self.region_data_mut(region_id).frozen = Some(hash);
+        hash
+    }
+
+    /// The nodes of a region in an order that doesn't depend on how it was
+    /// built: each after the nodes its inputs come from, walking depth first
+    /// from the results and then from the nodes no result depends on, in
+    /// the order they were made, with the inputs of each node in order.
+    fn canonical_node_order(&self, region_id: RegionId) -> Vec<NodeId> {
+        let num_results = self.region_data(region_id).res.len();
+        let roots: Vec<NodeId> = (0..num_results)
+            .filter_map(|index| {
+                self.user_data(UserId::result(region_id, index))
+                    .origin
+                    .get()
+            })
+            .filter_map(|origin_id| match origin_id {
+                OriginId::Out { node, .. } => Some(node),
+                OriginId::Arg { .. } => None,
+            })
+            .chain(self.region_nodes(region_id))
+            .collect();
+
+        let mut order = vec![];
+        let mut visited = HashSet::new();
+        for root in roots {
+            if !visited.insert(root) {
+                continue;
+            }
+            // Each entry is a node and the next of its inputs to walk.
+            let mut stack = vec![(root, 0)];
+            while let Some((node_id, input)) = stack.pop() {
+                let origin = self
+                    .node_data(node_id)
+                    .ins
+                    .get(input)
+                    .map(|user| user.origin.get());
+                match origin {
+                    Some(origin) => {
+                        stack.push((node_id, input + 1));
+                        if let Some(OriginId::Out { node, .. }) = origin {
+                            if visited.insert(node) {
+                                stack.push((node, 0));
+                            }
+                        }
+                    }
+                    None => order.push(node_id),
+                }
+            }
+        }
+        order
+    }
+
+    /// Makes a frozen region and every region nested in it mutable again.
+    ///
+    /// The region must not be nested in a region that's still frozen.
+    pub fn thaw_region(&self, region_id: RegionId) {
+        if let Some(node_id) = self.region_data(region_id).node {
+            self.assert_not_frozen(self.node_data(node_id).outer_region);
+        }
+
+        self.region_data_mut(region_id).frozen = None;
+        for node_id in self.region_nodes(region_id) {
+            for inner_region in self.inner_regions(node_id) {
+                self.thaw_region(inner_region);
+            }
+        }
+    }
+
+    /// The structural hash of a region, if it's frozen.
+    pub fn frozen_hash(&self, region_id: RegionId) -> Option<u64> {
+        self.region_data(region_id).frozen
+    }
+
+    pub(super) fn assert_not_frozen(&self, region_id: RegionId) {
+        assert!(
+            self.region_data(region_id).frozen.is_none(),
+            "region {:?} is frozen",
+            region_id
+        );
+    }
+
+    /// The region whose contents change when `user_id` is (dis)connected.
+    pub(super) fn user_region(&self, user_id: UserId) -> RegionId {
+        match user_id {
+            UserId::In { node, .. } => self.node_data(node).outer_region,
+            UserId::Res { region, .. } => region,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn equal_regions_have_equal_hashes() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 3);
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let x_negs: Vec<_> = (0..3)
+            .map(|branch| {
+                ncx.node_builder_in(gamma.branch(branch), Op::Neg)
+                    .operand(x_args[branch])
+                    .finish()
+                    .val_out(0)
+            })
+            .collect();
+        let n_lit = ncx.node_builder_in(gamma.branch(2), Op::Lit(2)).finish();
+        gamma.exit_var(&[x_negs[0], x_negs[1], n_lit.val_out(0)]);
+        let branches: Vec<_> = (0..3).map(|branch| gamma.branch(branch)).collect();
+        gamma.finish();
+
+        let hashes: Vec<_> = branches
+            .iter()
+            .map(|&branch| ncx.freeze_region(branch))
+            .collect();
+
+        assert_eq!(hashes[0], hashes[1]);
+        assert_ne!(hashes[0], hashes[2]);
+        assert_eq!(Some(hashes[2]), ncx.frozen_hash(branches[2]));
+        assert_eq!(None, ncx.frozen_hash(ncx.root_region()));
+    }
+
+    #[test]
+    fn hashes_ignore_the_order_nodes_were_made_in() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+
+        // Both branches compute `-x - 2`, making the literal first in one
+        // and last in the other.
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let n_two = ncx.node_builder_in(gamma.branch(0), Op::Lit(2)).finish();
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(x_args[0])
+            .finish();
+        let n_first = ncx
+            .node_builder_in(gamma.branch(0), Op::Sub)
+            .operand(n_neg.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(1), Op::Neg)
+            .operand(x_args[1])
+            .finish();
+        let n_two = ncx.node_builder_in(gamma.branch(1), Op::Lit(2)).finish();
+        let n_second = ncx
+            .node_builder_in(gamma.branch(1), Op::Sub)
+            .operand(n_neg.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+        gamma.exit_var(&[n_first.val_out(0), n_second.val_out(0)]);
+        let branches = [gamma.branch(0), gamma.branch(1)];
+        gamma.finish();
+
+        assert_eq!(
+            ncx.freeze_region(branches[0]),
+            ncx.freeze_region(branches[1])
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn frozen_regions_are_immutable() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let branch = gamma.branch(0);
+        gamma.finish();
+
+        ncx.freeze_region(branch);
+        ncx.node_builder_in(branch, Op::Lit(1)).finish();
+    }
+
+    #[test]
+    fn thawing_nested_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let outer = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let pred_args = outer.entry_var(n_pred.val_out(0));
+        let inner = ncx.gamma_builder(pred_args[0], 1);
+        let inner_branch = inner.branch(0);
+        inner.finish();
+        let outer_branch = outer.branch(0);
+        outer.finish();
+
+        ncx.freeze_region(outer_branch);
+        assert!(ncx.frozen_hash(inner_branch).is_some());
+
+        // The rest of the graph stays mutable.
+        ncx.mk_node(Op::Lit(1));
+
+        ncx.thaw_region(outer_branch);
+        assert_eq!(None, ncx.frozen_hash(inner_branch));
+        ncx.node_builder_in(inner_branch, Op::Lit(1)).finish();
+    }
+}
@@ -0,0 +1,390 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, UserId};
+use std::{convert::TryFrom, ops::Range};
+
+/// An immutable copy of a graph, as made by `NodeCtxt::freeze`, for
+/// read-only analyses.
+///
+/// The ports of nodes and regions are stored next to each other, as are the
+/// users of each origin, so walking the graph takes no borrows. Nodes and
+/// regions keep the ids they have in the context, removed ones included.
+/// Having no interior mutability, a frozen graph can be shared between
+/// threads if its ops can.
+pub struct FrozenGraph<S> {
+    nodes: Vec<FrozenNode<S>>,
+    regions: Vec<FrozenRegion>,
+    /// The inputs of every node, followed by the results of every region.
+    users: Vec<FrozenUser>,
+    /// The kinds of the outputs of every node, followed by those of the
+    /// arguments of every region.
+    origin_kinds: Vec<PortKind>,
+    /// Where the users of each origin start in `origin_users`, in the order
+    /// of `origin_kinds`, and where those of the last one end.
+    user_offsets: Vec<u32>,
+    origin_users: Vec<UserId>,
+    inner_regions: Vec<RegionId>,
+    region_nodes: Vec<NodeId>,
+}
+
+struct FrozenNode<S> {
+    kind: NodeKind<S>,
+    outer_region: RegionId,
+    removed: bool,
+    ins: Range<u32>,
+    outs: Range<u32>,
+    inner_regions: Range<u32>,
+}
+
+struct FrozenRegion {
+    node: Option<NodeId>,
+    removed: bool,
+    args: Range<u32>,
+    res: Range<u32>,
+    nodes: Range<u32>,
+}
+
+struct FrozenUser {
+    kind: PortKind,
+    origin: Option<OriginId>,
+}
+
+/// Appends `items` to `all`, returning where they ended up.
+fn append<T>(all: &mut Vec<T>, items: impl IntoIterator<Item = T>) -> Range<u32> {
+    let start = offset(all.len());
+    all.extend(items);
+    start..offset(all.len())
+}
+
+fn offset(len: usize) -> u32 {
+    u32::try_from(len).expect("graph has too many ports to be frozen")
+}
+
+fn slice<'a, T>(all: &'a [T], range: &Range<u32>) -> &'a [T] {
+    &all[range.start as usize..range.end as usize]
+}
+
+impl<S> NodeCtxt<S> {
+    /// Copies the graph into a `FrozenGraph`, which later changes to the
+    /// context don't affect.
+    pub fn freeze(&self) -> FrozenGraph<S>
+    where
+        S: Clone,
+    {
+        let mut frozen = FrozenGraph {
+            nodes: Vec::with_capacity(self.nodes.len()),
+            regions: Vec::with_capacity(self.regions.len()),
+            users: vec![],
+            origin_kinds: vec![],
+            user_offsets: vec![0],
+            origin_users: vec![],
+            inner_regions: vec![],
+            region_nodes: vec![],
+        };
+
+        for node_id in (0..self.nodes.len()).map(NodeId::new) {
+            let inner_regions = append(&mut frozen.inner_regions, self.inner_regions(node_id));
+            let node_data = self.node_data(node_id);
+            let ins = append(
+                &mut frozen.users,
+                node_data.ins.iter().map(|user| FrozenUser {
+                    kind: user.kind,
+                    origin: user.origin.get(),
+                }),
+            );
+            let outs = append(
+                &mut frozen.origin_kinds,
+                node_data.outs.iter().map(|origin| origin.kind),
+            );
+            frozen.nodes.push(FrozenNode {
+                kind: node_data.kind.clone(),
+                outer_region: node_data.outer_region,
+                removed: node_data.removed,
+                ins,
+                outs,
+                inner_regions,
+            });
+        }
+
+        for region_id in (0..self.regions.len()).map(RegionId::new) {
+            let region_data = self.region_data(region_id);
+            let res = append(
+                &mut frozen.users,
+                region_data.res.iter().map(|user| FrozenUser {
+                    kind: user.kind,
+                    origin: user.origin.get(),
+                }),
+            );
+            let args = append(
+                &mut frozen.origin_kinds,
+                region_data.args.iter().map(|origin| origin.kind),
+            );
+            let nodes = append(&mut frozen.region_nodes, region_data.nodes.iter().cloned());
+            frozen.regions.push(FrozenRegion {
+                node: region_data.node,
+                removed: region_data.removed,
+                args,
+                res,
+                nodes,
+            });
+        }
+
+        // Origins come in the order their kinds were appended in.
+        let node_origins = (0..self.nodes.len()).flat_map(|node| {
+            let node_id = NodeId::new(node);
+            let num_outputs = self.node_data(node_id).outs.len();
+            (0..num_outputs).map(move |index| OriginId::output(node_id, index))
+        });
+        let region_origins = (0..self.regions.len()).flat_map(|region| {
+            let region_id = RegionId::new(region);
+            let num_args = self.region_data(region_id).args.len();
+            (0..num_args).map(move |index| OriginId::argument(region_id, index))
+        });
+        for origin_id in node_origins.chain(region_origins) {
+            let mut next_user = self
+                .origin_data(origin_id)
+                .users
+                .get()
+                .map(|users| users.first);
+            while let Some(user_id) = next_user {
+                frozen.origin_users.push(user_id);
+                next_user = self.user_data(user_id).next_user.get();
+            }
+            frozen.user_offsets.push(offset(frozen.origin_users.len()));
+        }
+
+        frozen
+    }
+}
+
+impl<S> FrozenGraph<S> {
+    /// How many nodes weren't removed.
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.iter().filter(|node| !node.removed).count()
+    }
+
+    /// The nodes that weren't removed, in the order they were made.
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        (0..self.nodes.len())
+            .filter(move |&node| !self.nodes[node].removed)
+            .map(NodeId::new)
+    }
+
+    /// The regions that weren't removed, the root region among them, in the
+    /// order they were made.
+    pub fn regions(&self) -> impl Iterator<Item = RegionId> + '_ {
+        (0..self.regions.len())
+            .filter(move |&region| !self.regions[region].removed)
+            .map(RegionId::new)
+    }
+
+    /// The top-level region, which isn't owned by any node.
+    pub fn root_region(&self) -> RegionId {
+        RegionId::new(0)
+    }
+
+    pub fn kind(&self, node_id: NodeId) -> &NodeKind<S> {
+        &self.nodes[node_id.index()].kind
+    }
+
+    /// The region the node is in.
+    pub fn outer_region(&self, node_id: NodeId) -> RegionId {
+        self.nodes[node_id.index()].outer_region
+    }
+
+    /// The regions of a node, in order.
+    pub fn inner_regions(&self, node_id: NodeId) -> &[RegionId] {
+        slice(
+            &self.inner_regions,
+            &self.nodes[node_id.index()].inner_regions,
+        )
+    }
+
+    /// The node owning a region, unless it's the root region.
+    pub fn region_owner(&self, region_id: RegionId) -> Option<NodeId> {
+        self.regions[region_id.index()].node
+    }
+
+    /// The nodes in a region that weren't removed, in the order they were
+    /// made.
+    pub fn region_nodes(&self, region_id: RegionId) -> &[NodeId] {
+        slice(&self.region_nodes, &self.regions[region_id.index()].nodes)
+    }
+
+    pub fn num_inputs(&self, node_id: NodeId) -> usize {
+        self.nodes[node_id.index()].ins.len()
+    }
+
+    pub fn num_outputs(&self, node_id: NodeId) -> usize {
+        self.nodes[node_id.index()].outs.len()
+    }
+
+    pub fn num_arguments(&self, region_id: RegionId) -> usize {
+        self.regions[region_id.index()].args.len()
+    }
+
+    pub fn num_results(&self, region_id: RegionId) -> usize {
+        self.regions[region_id.index()].res.len()
+    }
+
+    /// The nodes whose outputs the inputs of `node_id` are connected to,
+    /// once per input.
+    pub fn producers(&self, node_id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        slice(&self.users, &self.nodes[node_id.index()].ins)
+            .iter()
+            .filter_map(|user| user.origin?.node_id())
+    }
+
+    /// How many nodes were ever made, removed ones included.
+    pub fn node_bound(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn user(&self, user_id: UserId) -> &FrozenUser {
+        let ports = match user_id {
+            UserId::In { node, .. } => &self.nodes[node.index()].ins,
+            UserId::Res { region, .. } => &self.regions[region.index()].res,
+        };
+        &slice(&self.users, ports)[user_id.index()]
+    }
+
+    /// Where `origin_id` is among the origins of the graph.
+    fn origin_slot(&self, origin_id: OriginId) -> usize {
+        let ports = match origin_id {
+            OriginId::Out { node, .. } => &self.nodes[node.index()].outs,
+            OriginId::Arg { region, .. } => &self.regions[region.index()].args,
+        };
+        assert!(
+            origin_id.index() < ports.len(),
+            "{:?} doesn't exist",
+            origin_id
+        );
+        ports.start as usize + origin_id.index()
+    }
+
+    /// Whether `user_id` takes a value or a state.
+    pub fn user_kind(&self, user_id: UserId) -> PortKind {
+        self.user(user_id).kind
+    }
+
+    /// Whether `origin_id` carries a value or a state.
+    pub fn origin_kind(&self, origin_id: OriginId) -> PortKind {
+        self.origin_kinds[self.origin_slot(origin_id)]
+    }
+
+    /// The origin `user_id` is connected to, if any.
+    pub fn origin(&self, user_id: UserId) -> Option<OriginId> {
+        self.user(user_id).origin
+    }
+
+    /// The users connected to `origin_id`, in the order they were
+    /// connected.
+    pub fn users(&self, origin_id: OriginId) -> &[UserId] {
+        let slot = self.origin_slot(origin_id);
+        slice(
+            &self.origin_users,
+            &(self.user_offsets[slot]..self.user_offsets[slot + 1]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FrozenGraph;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, OriginId, PortKind, UserId};
+    use std::thread;
+
+    #[test]
+    fn frozen_graphs_mirror_the_context() {
+        let ncx = NodeCtxt::new();
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_st = ncx.mk_node(Op::St);
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_one.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (arg, _) = theta.loop_var(n_add.val_out(0));
+        let (st_arg, _) = theta.loop_state(n_st.st_out(0));
+        theta.set_next_state(st_arg, st_arg);
+        let n_theta = theta.finish(arg);
+
+        let frozen = ncx.freeze();
+        // Later changes don't show up.
+        ncx.mk_node(Op::Lit(2));
+        assert_eq!(4, frozen.num_nodes());
+        assert_eq!(
+            vec![n_one.id(), n_st.id(), n_add.id(), n_theta.id()],
+            frozen.nodes().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            frozen.region_nodes(frozen.root_region()),
+            &[n_one.id(), n_st.id(), n_add.id(), n_theta.id()]
+        );
+        assert_eq!(&NodeKind::Op(Op::Add), frozen.kind(n_add.id()));
+
+        let one = OriginId::output(n_one.id(), 0);
+        assert_eq!(
+            frozen.users(one),
+            &[UserId::input(n_add.id(), 0), UserId::input(n_add.id(), 1)]
+        );
+        assert_eq!(
+            vec![n_one.id(), n_one.id()],
+            frozen.producers(n_add.id()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(one), frozen.origin(UserId::input(n_add.id(), 1)));
+
+        let body = frozen.inner_regions(n_theta.id())[0];
+        assert_eq!(
+            vec![frozen.root_region(), body],
+            frozen.regions().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(n_theta.id()), frozen.region_owner(body));
+        assert_eq!(2, frozen.num_arguments(body));
+        assert_eq!(3, frozen.num_results(body));
+        assert!(frozen.region_nodes(body).is_empty());
+        let st_arg = OriginId::argument(body, 1);
+        assert_eq!(PortKind::St, frozen.origin_kind(st_arg));
+        assert_eq!(PortKind::St, frozen.user_kind(UserId::result(body, 2)));
+        assert_eq!(frozen.users(st_arg), &[UserId::result(body, 2)]);
+        assert_eq!(
+            frozen.users(OriginId::argument(body, 0)),
+            &[UserId::result(body, 0), UserId::result(body, 1)]
+        );
+        assert!(frozen.users(OriginId::output(n_theta.id(), 0)).is_empty());
+    }
+
+    #[test]
+    fn sharing_frozen_graphs_between_threads() {
+        let ncx = NodeCtxt::new();
+        let mut origin = ncx.mk_node(Op::Lit(0)).val_out(0);
+        for _ in 0..10 {
+            origin = ncx
+                .node_builder(Op::Add)
+                .operand(origin)
+                .operand(origin)
+                .finish()
+                .val_out(0);
+        }
+        let frozen: FrozenGraph<Op> = ncx.freeze();
+        let frozen = &frozen;
+        let edges: usize = thread::scope(|scope| {
+            let handles: Vec<_> = (0..2)
+                .map(|half| {
+                    scope.spawn(move || {
+                        frozen
+                            .nodes()
+                            .skip(half * 6)
+                            .take(6)
+                            .map(|node_id| frozen.producers(node_id).count())
+                            .sum::<usize>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum()
+        });
+        assert_eq!(20, edges);
+    }
+}
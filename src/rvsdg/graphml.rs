@@ -0,0 +1,353 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, UserId};
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+};
+
+/// A node and one of its ports, as GraphML edges refer to them.
+type Endpoint = (String, String);
+
+impl<S> NodeCtxt<S> {
+    /// Writes the graph as GraphML, for tools such as yEd and Gephi.
+    ///
+    /// Every region is a graph, nested in its structural node, with a node
+    /// for its arguments and one for its results like `print` draws them.
+    /// Nodes have a `kind` and a `label`, and edges have a `kind`, either
+    /// `value` or `state`.
+    pub fn write_graphml(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            out,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        writeln!(
+            out,
+            r#"  <key id="kind" for="node" attr.name="kind" attr.type="string"/>"#
+        )?;
+        writeln!(
+            out,
+            r#"  <key id="label" for="node" attr.name="label" attr.type="string"/>"#
+        )?;
+        writeln!(
+            out,
+            r#"  <key id="edge_kind" for="edge" attr.name="kind" attr.type="string"/>"#
+        )?;
+        self.write_graphml_region(out, self.root_region(), 1)?;
+        writeln!(out, "</graphml>")
+    }
+
+    /// Writes a region as a graph, followed by the edges into its nodes and
+    /// results, and the edges between its structural nodes and their
+    /// regions.
+    fn write_graphml_region(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let indent = "  ".repeat(depth);
+        let inner_indent = "  ".repeat(depth + 1);
+        writeln!(
+            out,
+            r#"{}<graph id="r{}" edgedefault="directed">"#,
+            indent, region_id.0
+        )?;
+
+        let num_args = self.region_data(region_id).args.len();
+        let num_res = self.region_data(region_id).res.len();
+        if num_args > 0 {
+            let id = format!("r{}_args", region_id.0);
+            write_boundary_node(out, &inner_indent, &id, "arguments", 'a', num_args)?;
+        }
+        let nodes = self.region_nodes(region_id);
+        for &node_id in &nodes {
+            self.write_graphml_node(out, node_id, depth + 1)?;
+        }
+        if num_res > 0 {
+            let id = format!("r{}_res", region_id.0);
+            write_boundary_node(out, &inner_indent, &id, "results", 'r', num_res)?;
+        }
+
+        for &node_id in &nodes {
+            let num_ins = self.node_data(node_id).ins.len();
+            for index in 0..num_ins {
+                let (kind, origin) = {
+                    let user = &self.node_data(node_id).ins[index];
+                    (user.kind, user.origin.get())
+                };
+                if let Some(origin_id) = origin {
+                    let target = (format!("n{}", node_id.0), format!("i{}", index));
+                    write_edge(out, &inner_indent, origin_endpoint(origin_id), target, kind)?;
+                }
+            }
+            for inner_region in self.inner_regions(node_id) {
+                self.write_boundary_edges(out, inner_region, &inner_indent)?;
+            }
+        }
+        for index in 0..num_res {
+            let (kind, origin) = {
+                let user = &self.region_data(region_id).res[index];
+                (user.kind, user.origin.get())
+            };
+            if let Some(origin_id) = origin {
+                let target = (format!("r{}_res", region_id.0), format!("r{}", index));
+                write_edge(out, &inner_indent, origin_endpoint(origin_id), target, kind)?;
+            }
+        }
+
+        writeln!(out, "{}</graph>", indent)
+    }
+
+    fn write_graphml_node(
+        &self,
+        out: &mut dyn Write,
+        node_id: NodeId,
+        depth: usize,
+    ) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let indent = "  ".repeat(depth);
+        let (kind, label, num_ins, num_outs) = {
+            let node_data = self.node_data(node_id);
+            let (kind, label) = match node_data.kind {
+                NodeKind::Op(ref op) => ("op", format!("{:?}", op)),
+                NodeKind::Apply { .. } => ("apply", "Apply".to_owned()),
+                NodeKind::Gamma { .. } => ("gamma", "Gamma".to_owned()),
+                NodeKind::Theta { .. } => ("theta", "Theta".to_owned()),
+                NodeKind::Lambda { .. } => ("lambda", "Lambda".to_owned()),
+                NodeKind::Omega { .. } => ("omega", "Omega".to_owned()),
+            };
+            (kind, label, node_data.ins.len(), node_data.outs.len())
+        };
+
+        writeln!(out, r#"{}<node id="n{}">"#, indent, node_id.0)?;
+        writeln!(out, r#"{}  <data key="kind">{}</data>"#, indent, kind)?;
+        writeln!(
+            out,
+            r#"{}  <data key="label">{}</data>"#,
+            indent,
+            escape_xml(&label)
+        )?;
+        for index in 0..num_ins {
+            writeln!(out, r#"{}  <port name="i{}"/>"#, indent, index)?;
+        }
+        for index in 0..num_outs {
+            writeln!(out, r#"{}  <port name="o{}"/>"#, indent, index)?;
+        }
+        for inner_region in self.inner_regions(node_id) {
+            self.write_graphml_region(out, inner_region, depth + 1)?;
+        }
+        writeln!(out, "{}</node>", indent)
+    }
+
+    /// Writes the edges from the inputs of a structural node to the
+    /// arguments of one of its regions, and from the results of the region
+    /// to the outputs of the node.
+    fn write_boundary_edges(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        indent: &str,
+    ) -> io::Result<()> {
+        let region_data = self.region_data(region_id);
+        for (index, arg) in region_data.args.iter().enumerate() {
+            if let Some(UserId::In { node, index: input }) = arg.source {
+                let source = (format!("n{}", node.0), format!("i{}", input));
+                let target = (format!("r{}_args", region_id.0), format!("a{}", index));
+                write_edge(out, indent, source, target, arg.kind)?;
+            }
+        }
+        for (index, res) in region_data.res.iter().enumerate() {
+            if let Some(OriginId::Out {
+                node,
+                index: output,
+            }) = res.sink
+            {
+                let source = (format!("r{}_res", region_id.0), format!("r{}", index));
+                let target = (format!("n{}", node.0), format!("o{}", output));
+                write_edge(out, indent, source, target, res.kind)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_boundary_node(
+    out: &mut dyn Write,
+    indent: &str,
+    id: &str,
+    kind: &str,
+    port_prefix: char,
+    num_ports: usize,
+) -> io::Result<()> {
+    writeln!(out, r#"{}<node id="{}">"#, indent, id)?;
+    writeln!(out, r#"{}  <data key="kind">{}</data>"#, indent, kind)?;
+    for index in 0..num_ports {
+        writeln!(
+            out,
+            r#"{}  <port name="{}{}"/>"#,
+            indent, port_prefix, index
+        )?;
+    }
+    writeln!(out, "{}</node>", indent)
+}
+
+fn write_edge(
+    out: &mut dyn Write,
+    indent: &str,
+    (source, source_port): Endpoint,
+    (target, target_port): Endpoint,
+    kind: PortKind,
+) -> io::Result<()> {
+    let kind = match kind {
+        PortKind::Val => "value",
+        PortKind::St => "state",
+    };
+    writeln!(
+        out,
+        r#"{}<edge source="{}" sourceport="{}" target="{}" targetport="{}">"#,
+        indent, source, source_port, target, target_port
+    )?;
+    writeln!(out, r#"{}  <data key="edge_kind">{}</data>"#, indent, kind)?;
+    writeln!(out, "{}</edge>", indent)
+}
+
+/// The node and port of an origin, as edges from it refer to them.
+fn origin_endpoint(origin_id: OriginId) -> Endpoint {
+    match origin_id {
+        OriginId::Out { node, index } => (format!("n{}", node.0), format!("o{}", index)),
+        OriginId::Arg { region, index } => (format!("r{}_args", region.0), format!("a{}", index)),
+    }
+}
+
+pub(super) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn nested_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let gamma = ncx.gamma_builder(n0.val_out(0), 1);
+        let states = gamma.entry_state(st.st_out(0));
+        let store = ncx
+            .node_builder_in(gamma.branch(0), Op::Store)
+            .operand(
+                ncx.node_builder_in(gamma.branch(0), Op::Lit(1))
+                    .finish()
+                    .val_out(0),
+            )
+            .state(states[0])
+            .finish();
+        gamma.exit_state(&[store.st_out(0)]);
+        gamma.finish();
+
+        let mut buffer = Vec::new();
+        ncx.write_graphml(&mut buffer).unwrap();
+        let content = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            content,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="kind" for="node" attr.name="kind" attr.type="string"/>
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="edge_kind" for="edge" attr.name="kind" attr.type="string"/>
+  <graph id="r0" edgedefault="directed">
+    <node id="n0">
+      <data key="kind">op</data>
+      <data key="label">Lit(0)</data>
+      <port name="o0"/>
+    </node>
+    <node id="n1">
+      <data key="kind">op</data>
+      <data key="label">St</data>
+      <port name="o0"/>
+    </node>
+    <node id="n2">
+      <data key="kind">gamma</data>
+      <data key="label">Gamma</data>
+      <port name="i0"/>
+      <port name="i1"/>
+      <port name="o0"/>
+      <graph id="r1" edgedefault="directed">
+        <node id="r1_args">
+          <data key="kind">arguments</data>
+          <port name="a0"/>
+        </node>
+        <node id="n3">
+          <data key="kind">op</data>
+          <data key="label">Lit(1)</data>
+          <port name="o0"/>
+        </node>
+        <node id="n4">
+          <data key="kind">op</data>
+          <data key="label">Store</data>
+          <port name="i0"/>
+          <port name="i1"/>
+          <port name="o0"/>
+        </node>
+        <node id="r1_res">
+          <data key="kind">results</data>
+          <port name="r0"/>
+        </node>
+        <edge source="n3" sourceport="o0" target="n4" targetport="i0">
+          <data key="edge_kind">value</data>
+        </edge>
+        <edge source="r1_args" sourceport="a0" target="n4" targetport="i1">
+          <data key="edge_kind">state</data>
+        </edge>
+        <edge source="n4" sourceport="o0" target="r1_res" targetport="r0">
+          <data key="edge_kind">state</data>
+        </edge>
+      </graph>
+    </node>
+    <edge source="n0" sourceport="o0" target="n2" targetport="i0">
+      <data key="edge_kind">value</data>
+    </edge>
+    <edge source="n1" sourceport="o0" target="n2" targetport="i1">
+      <data key="edge_kind">state</data>
+    </edge>
+    <edge source="n2" sourceport="i1" target="r1_args" targetport="a0">
+      <data key="edge_kind">state</data>
+    </edge>
+    <edge source="r1_res" sourceport="r0" target="n2" targetport="o0">
+      <data key="edge_kind">state</data>
+    </edge>
+  </graph>
+</graphml>
+"#
+        );
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(
+            super::escape_xml(r#"<a & "b">"#),
+            "&lt;a &amp; &quot;b&quot;&gt;"
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, RegionId, Sig};
+use std::{collections::HashMap, hash::Hash};
+
+/// A partition of the origins of a graph into congruence classes: origins
+/// in the same class are the same output of equal terms, and would be
+/// merged by value numbering.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ValueNumbering {
+    class_of: HashMap<OriginId, usize>,
+    classes: Vec<Vec<OriginId>>,
+}
+
+impl ValueNumbering {
+    /// The class of an origin, if it's in the graph.
+    pub fn class_of(&self, origin_id: OriginId) -> Option<usize> {
+        self.class_of.get(&origin_id).cloned()
+    }
+
+    /// The origins of a class, in the order they were numbered.
+    pub fn members(&self, class: usize) -> &[OriginId] {
+        &self.classes[class]
+    }
+
+    pub fn num_classes(&self) -> usize {
+        self.classes.len()
+    }
+
+    pub fn congruent(&self, a: OriginId, b: OriginId) -> bool {
+        match (self.class_of(a), self.class_of(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// The classes with more than one member, which are redundant values.
+    pub fn redundant_classes(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.classes.len()).filter(move |&class| self.classes[class].len() > 1)
+    }
+
+    fn add_class(&mut self, origin_id: OriginId) -> usize {
+        let class = self.classes.len();
+        self.classes.push(vec![origin_id]);
+        self.class_of.insert(origin_id, class);
+        class
+    }
+
+    fn join_class(&mut self, origin_id: OriginId, class: usize) {
+        self.classes[class].push(origin_id);
+        self.class_of.insert(origin_id, class);
+    }
+}
+
+/// Why two origins aren't congruent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Distinction {
+    /// One of them is a region argument, which is only congruent to itself.
+    Argument,
+    /// They're in different regions.
+    OtherRegion,
+    /// Their nodes are of different kinds.
+    OtherKind,
+    /// They're different outputs of their nodes.
+    OtherOutput,
+    /// One of their nodes is never numbered, being structural, stateful or
+    /// having unconnected inputs.
+    Unnumbered(NodeId),
+    /// The operands of their nodes at input `port` aren't congruent.
+    OtherOperand { port: usize },
+}
+
+impl<S> NodeCtxt<S> {
+    /// Merges structurally identical stateless nodes in every region that
+    /// isn't frozen, moving the users of duplicates over to the node they
+    /// duplicate. Returns how many nodes were merged.
+    ///
+    /// Interning already avoids duplicates when nodes are made, but rewrites
+    /// such as reconnecting inputs can introduce them afterwards.
+    pub fn global_value_numbering(&self) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let num_regions = self.regions.len();
+        (0..num_regions)
+            .map(RegionId::new)
+            .filter(|&region_id| {
+                let region_data = self.region_data(region_id);
+                !region_data.removed && region_data.frozen.is_none()
+            })
+            .map(|region_id| self.number_region_values(region_id))
+            .sum()
+    }
+
+    fn number_region_values(&self, region_id: RegionId) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut numbered = HashMap::new();
+        let mut num_merged = 0;
+
+        for node_id in self.region_topo_order(region_id) {
+            let node_term = match self.node_term(node_id) {
+                Some(node_term) => node_term,
+                None => continue,
+            };
+            if node_term.kind.is_structural() || node_term.kind.sig().is_side_effectful() {
+                continue;
+            }
+
+            match numbered.get(&node_term) {
+                Some(&leader_id) => {
+                    let num_outputs = self.node_data(node_id).outs.len();
+                    for index in 0..num_outputs {
+                        self.replace_all_users(
+                            OriginId::output(node_id, index),
+                            OriginId::output(leader_id, index),
+                        );
+                    }
+                    self.remove_node(node_id);
+                    num_merged += 1;
+                }
+                None => {
+                    if self.interns(&node_term.kind) {
+                        self.interned_nodes
+                            .borrow_mut()
+                            .insert(node_term.clone(), node_id);
+                    }
+                    numbered.insert(node_term, node_id);
+                }
+            }
+        }
+
+        num_merged
+    }
+
+    /// Partitions the origins of every region into congruence classes, the
+    /// way value numbering would, without merging anything.
+    ///
+    /// Arguments, and outputs of nodes that can't be merged, are each in a
+    /// class of their own. Classes are numbered region by region.
+    pub fn value_numbering(&self) -> ValueNumbering
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut numbering = ValueNumbering::default();
+        let num_regions = self.regions.len();
+        for region_id in (0..num_regions).map(RegionId::new) {
+            if self.region_data(region_id).removed {
+                continue;
+            }
+
+            let num_args = self.region_data(region_id).args.len();
+            for index in 0..num_args {
+                numbering.add_class(OriginId::argument(region_id, index));
+            }
+
+            let mut numbered: HashMap<(NodeKind<S>, Vec<usize>), Vec<usize>> = HashMap::new();
+            for node_id in self.region_topo_order(region_id) {
+                let num_outputs = self.node_data(node_id).outs.len();
+                let outputs = (0..num_outputs).map(|index| OriginId::output(node_id, index));
+
+                match self.numbering_key(&numbering, node_id) {
+                    Some(key) => match numbered.get(&key) {
+                        Some(classes) => {
+                            for (origin_id, &class) in outputs.zip(classes) {
+                                numbering.join_class(origin_id, class);
+                            }
+                        }
+                        None => {
+                            let classes = outputs
+                                .map(|origin_id| numbering.add_class(origin_id))
+                                .collect();
+                            numbered.insert(key, classes);
+                        }
+                    },
+                    None => {
+                        for origin_id in outputs {
+                            numbering.add_class(origin_id);
+                        }
+                    }
+                }
+            }
+        }
+        numbering
+    }
+
+    /// Explains why two origins aren't in the same class of `numbering`, or
+    /// returns `None` if they are.
+    pub fn why_distinct(
+        &self,
+        numbering: &ValueNumbering,
+        a: OriginId,
+        b: OriginId,
+    ) -> Option<Distinction>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        if numbering.congruent(a, b) {
+            return None;
+        }
+
+        let (a_node, a_index, b_node, b_index) = match (a, b) {
+            (
+                OriginId::Out {
+                    node: a_node,
+                    index: a_index,
+                },
+                OriginId::Out {
+                    node: b_node,
+                    index: b_index,
+                },
+            ) => (a_node, a_index, b_node, b_index),
+            _ => return Some(Distinction::Argument),
+        };
+        if self.origin_region(a) != self.origin_region(b) {
+            return Some(Distinction::OtherRegion);
+        }
+        if self.node_data(a_node).kind != self.node_data(b_node).kind {
+            return Some(Distinction::OtherKind);
+        }
+        if a_index != b_index {
+            return Some(Distinction::OtherOutput);
+        }
+
+        let a_key = self.numbering_key(numbering, a_node);
+        let b_key = self.numbering_key(numbering, b_node);
+        let (a_classes, b_classes) = match (a_key, b_key) {
+            (Some((_, a_classes)), Some((_, b_classes))) => (a_classes, b_classes),
+            (None, _) => return Some(Distinction::Unnumbered(a_node)),
+            (_, None) => return Some(Distinction::Unnumbered(b_node)),
+        };
+        a_classes
+            .iter()
+            .zip(&b_classes)
+            .position(|(a_class, b_class)| a_class != b_class)
+            .map(|port| Distinction::OtherOperand { port })
+    }
+
+    /// The kind of a node along with the classes of its operands, if it's a
+    /// node value numbering would merge with equal ones.
+    fn numbering_key(
+        &self,
+        numbering: &ValueNumbering,
+        node_id: NodeId,
+    ) -> Option<(NodeKind<S>, Vec<usize>)>
+    where
+        S: Sig + Clone,
+    {
+        let node_data = self.node_data(node_id);
+        if node_data.kind.is_structural() || node_data.kind.sig().is_side_effectful() {
+            return None;
+        }
+        let classes = node_data
+            .ins
+            .iter()
+            .map(|user| numbering.class_of(user.origin.get()?))
+            .collect::<Option<Vec<usize>>>()?;
+        Some((node_data.kind.clone(), classes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Distinction;
+    use crate::rvsdg::{
+        testing::{run_pass, Op},
+        InterningPolicy, NodeCtxt, NodeCtxtConfig, OriginId,
+    };
+
+    #[test]
+    fn merging_duplicates_left_by_rewrites() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::Lit(1));
+        let neg0 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let neg1 = ncx.node_builder(Op::Neg).operand(n1.val_out(0)).finish();
+        let add0 = ncx
+            .node_builder(Op::Add)
+            .operand(neg0.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+        let add1 = ncx
+            .node_builder(Op::Add)
+            .operand(neg1.val_out(0))
+            .operand(n0.val_out(0))
+            .finish();
+        let user = ncx.node_builder(Op::Neg).operand(add1.val_out(0)).finish();
+
+        // Turns `neg1` into a duplicate of `neg0`, and thus `add1` into one of
+        // `add0`.
+        neg1.val_in(0).reconnect(n0.val_out(0));
+
+        let run = run_pass(&ncx, |ncx| ncx.global_value_numbering());
+        run.nodes_decreased();
+
+        assert_eq!(2, run.result);
+        assert_eq!(add0.val_out(0), user.val_in(0).origin());
+
+        // The merged nodes are interned again.
+        let neg = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        assert_eq!(neg0.id(), neg.id());
+
+        run_pass(&ncx, |ncx| ncx.global_value_numbering()).unchanged();
+    }
+
+    #[test]
+    fn equal_nodes_in_different_regions_are_kept() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let lits: Vec<_> = (0..2)
+            .map(|index| {
+                ncx.node_builder_in(gamma.branch(index), Op::Lit(0))
+                    .finish()
+                    .val_out(0)
+            })
+            .collect();
+        gamma.exit_var(&lits);
+        gamma.finish();
+
+        assert_eq!(0, ncx.global_value_numbering());
+    }
+
+    #[test]
+    fn congruence_classes() {
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            interning: InterningPolicy::Never,
+            ..NodeCtxtConfig::default()
+        });
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::Lit(0));
+        let n2 = ncx.mk_node(Op::Lit(1));
+        let neg0 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let neg1 = ncx.node_builder(Op::Neg).operand(n1.val_out(0)).finish();
+        let neg2 = ncx.node_builder(Op::Neg).operand(n2.val_out(0)).finish();
+        let add = ncx
+            .node_builder(Op::Add)
+            .operand(neg0.val_out(0))
+            .operand(neg2.val_out(0))
+            .finish();
+        let st = ncx.mk_node(Op::St);
+        let store0 = ncx
+            .node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let store1 = ncx
+            .node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+
+        let numbering = ncx.value_numbering();
+
+        let class = numbering.class_of(neg0.val_out(0).id()).unwrap();
+        assert_eq!(
+            &[neg0.val_out(0).id(), neg1.val_out(0).id()],
+            numbering.members(class)
+        );
+        assert!(numbering.congruent(n0.val_out(0).id(), n1.val_out(0).id()));
+        assert!(!numbering.congruent(n0.val_out(0).id(), n2.val_out(0).id()));
+        assert_eq!(2, numbering.redundant_classes().count());
+        assert_eq!(8, numbering.num_classes());
+
+        let why = |a: OriginId, b: OriginId| ncx.why_distinct(&numbering, a, b);
+        assert_eq!(None, why(neg0.val_out(0).id(), neg1.val_out(0).id()));
+        assert_eq!(
+            Some(Distinction::OtherKind),
+            why(neg0.val_out(0).id(), add.val_out(0).id())
+        );
+        assert_eq!(
+            Some(Distinction::OtherOperand { port: 0 }),
+            why(neg0.val_out(0).id(), neg2.val_out(0).id())
+        );
+        assert_eq!(
+            Some(Distinction::Unnumbered(store0.id())),
+            why(store0.st_out(0).id(), store1.st_out(0).id())
+        );
+    }
+
+    #[test]
+    fn arguments_are_only_congruent_to_themselves() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let args = gamma.entry_var(n0.val_out(0));
+        let lits: Vec<_> = (0..2)
+            .map(|index| {
+                ncx.node_builder_in(gamma.branch(index), Op::Lit(0))
+                    .finish()
+                    .val_out(0)
+            })
+            .collect();
+        gamma.exit_var(&lits);
+        gamma.finish();
+
+        let numbering = ncx.value_numbering();
+        let why = |a: OriginId, b: OriginId| ncx.why_distinct(&numbering, a, b);
+
+        assert_eq!(Some(Distinction::Argument), why(args[0].id(), args[1].id()));
+        assert_eq!(
+            Some(Distinction::OtherRegion),
+            why(lits[0].id(), lits[1].id())
+        );
+        assert_eq!(0, numbering.redundant_classes().count());
+    }
+}
@@ -0,0 +1,161 @@
+use super::{NodeCtxt, NodeId, NodeKind, NodeMap, RegionId};
+
+/// How far the nodes of a region are from where it starts and ends, as
+/// found by `NodeCtxt::region_heights`.
+///
+/// Paths go from nodes to the nodes in the region using their outputs, and
+/// are as long as the latencies of the nodes along them add up to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RegionHeights {
+    depths: NodeMap<usize>,
+    heights: NodeMap<usize>,
+    critical_path: Vec<NodeId>,
+}
+
+impl RegionHeights {
+    /// The length of the longest path to `node_id`, up to where it starts.
+    /// Nodes taking no operands from the region are at depth 0.
+    pub fn depth(&self, node_id: NodeId) -> usize {
+        self.depths[node_id]
+    }
+
+    /// The length of the longest path from `node_id` to a node none of
+    /// whose outputs are used in the region, both included.
+    pub fn height(&self, node_id: NodeId) -> usize {
+        self.heights[node_id]
+    }
+
+    /// The nodes along a longest path through the region, each using the
+    /// outputs of the one before.
+    pub fn critical_path(&self) -> &[NodeId] {
+        &self.critical_path
+    }
+
+    /// The length of the critical path, which no schedule of the region
+    /// can take less than.
+    pub fn critical_path_len(&self) -> usize {
+        self.critical_path
+            .first()
+            .map_or(0, |&node_id| self.heights[node_id])
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Computes the depth and height of the nodes of `region_id`, taking
+    /// every node to have a latency of 1.
+    pub fn region_heights(&self, region_id: RegionId) -> RegionHeights {
+        self.region_heights_by(region_id, |_| 1)
+    }
+
+    /// Computes the depth and height of the nodes of `region_id`, taking
+    /// nodes to have the latency `latency` gives their kind.
+    pub fn region_heights_by<F>(&self, region_id: RegionId, latency: F) -> RegionHeights
+    where
+        F: Fn(&NodeKind<S>) -> usize,
+    {
+        let order = self.region_topo_order(region_id);
+        let mut latencies = NodeMap::new();
+        let mut depths = NodeMap::new();
+        let mut users: NodeMap<Vec<NodeId>> = NodeMap::new();
+        for &node_id in &order {
+            latencies.insert(node_id, latency(&self.node_data(node_id).kind));
+            let mut depth = 0;
+            for operand in self.operand_nodes(node_id) {
+                users.get_or_insert_with(operand, Vec::new).push(node_id);
+                depth = depth.max(depths[operand] + latencies[operand]);
+            }
+            depths.insert(node_id, depth);
+        }
+
+        let users_of = |node_id| users.get(node_id).into_iter().flatten().copied();
+        let mut heights = NodeMap::new();
+        for &node_id in order.iter().rev() {
+            let below = users_of(node_id).map(|user| heights[user]).max();
+            heights.insert(node_id, latencies[node_id] + below.unwrap_or(0));
+        }
+
+        // Follows the users that are as high as what's left of the path,
+        // starting from the highest node the region starts with.
+        let mut critical_path = vec![];
+        let start = order
+            .iter()
+            .copied()
+            .filter(|&node_id| depths[node_id] == 0)
+            .fold(None, |highest: Option<NodeId>, node_id| match highest {
+                Some(highest) if heights[highest] >= heights[node_id] => Some(highest),
+                _ => Some(node_id),
+            });
+        let mut next = start;
+        while let Some(node_id) = next {
+            critical_path.push(node_id);
+            let rest = heights[node_id] - latencies[node_id];
+            next = users_of(node_id).find(|&user| heights[user] == rest && rest > 0);
+        }
+
+        RegionHeights {
+            depths,
+            heights,
+            critical_path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn heights_and_critical_paths() {
+        let ncx = NodeCtxt::new();
+
+        let n_a = ncx.mk_node(Op::Lit(0));
+        let n_b = ncx.mk_node(Op::Lit(1));
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_a.val_out(0)).finish();
+        let n_neg2 = ncx.node_builder(Op::Neg).operand(n_neg.val_out(0)).finish();
+        let n_mul = ncx
+            .node_builder(Op::Mul)
+            .operand(n_b.val_out(0))
+            .operand(n_b.val_out(0))
+            .finish();
+        let n_mul2 = ncx
+            .node_builder(Op::Mul)
+            .operand(n_neg2.val_out(0))
+            .operand(n_mul.val_out(0))
+            .finish();
+
+        let heights = ncx.region_heights(ncx.root_region());
+        assert_eq!(0, heights.depth(n_a.id()));
+        assert_eq!(2, heights.depth(n_neg2.id()));
+        assert_eq!(3, heights.depth(n_mul2.id()));
+        assert_eq!(4, heights.height(n_a.id()));
+        assert_eq!(3, heights.height(n_b.id()));
+        assert_eq!(1, heights.height(n_mul2.id()));
+        assert_eq!(4, heights.critical_path_len());
+        assert_eq!(
+            &[n_a.id(), n_neg.id(), n_neg2.id(), n_mul2.id()],
+            heights.critical_path()
+        );
+
+        // Slow multiplications put the critical path through both of them.
+        let heights = ncx.region_heights_by(ncx.root_region(), |kind| match kind {
+            NodeKind::Op(Op::Mul) => 4,
+            _ => 1,
+        });
+        assert_eq!(5, heights.depth(n_mul2.id()));
+        assert_eq!(9, heights.height(n_b.id()));
+        assert_eq!(9, heights.critical_path_len());
+        assert_eq!(
+            &[n_b.id(), n_mul.id(), n_mul2.id()],
+            heights.critical_path()
+        );
+    }
+
+    #[test]
+    fn empty_regions() {
+        let ncx = NodeCtxt::<Op>::new();
+
+        let heights = ncx.region_heights(ncx.root_region());
+        assert!(heights.critical_path().is_empty());
+        assert_eq!(0, heights.critical_path_len());
+    }
+}
@@ -0,0 +1,107 @@
+use super::{NodeCtxt, NodeId, OriginId, RegionId, Sig};
+use std::{collections::HashMap, hash::Hash};
+
+/// Where the nodes, regions and origins of a graph were copied to.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct IdMap {
+    pub nodes: HashMap<NodeId, NodeId>,
+    pub regions: HashMap<RegionId, RegionId>,
+    pub origins: HashMap<OriginId, OriginId>,
+}
+
+impl<S> NodeCtxt<S> {
+    /// Copies the nodes of the root region of `other`, along with their
+    /// regions, into the root region of this context. Nodes are interned
+    /// again as they're copied, so they're shared with equal nodes already
+    /// here. Returns `mapping` with every copy recorded in it.
+    ///
+    /// Origins `mapping` already maps stand for the origins of `other`, so
+    /// nodes whose outputs are all mapped aren't copied, and their users are
+    /// connected to what they're mapped to instead.
+    pub fn import_from(&self, other: &NodeCtxt<S>, mut mapping: IdMap) -> IdMap
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let into = self.root_region();
+        for node_id in other.region_topo_order(other.root_region()) {
+            let num_outs = other.node_data(node_id).outs.len();
+            let is_mapped = (0..num_outs).all(|index| {
+                mapping
+                    .origins
+                    .contains_key(&OriginId::output(node_id, index))
+            });
+            if num_outs == 0 || !is_mapped {
+                self.copy_node_from(other, node_id, into, &mut mapping);
+            }
+        }
+        mapping
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IdMap;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn importing_shares_equal_nodes() {
+        let ncx = NodeCtxt::new();
+        let n_one = ncx.mk_node(Op::Lit(1));
+
+        let other = NodeCtxt::new();
+        let o_one = other.mk_node(Op::Lit(1));
+        let o_two = other.mk_node(Op::Lit(2));
+        let theta = other.theta_builder(other.root_region());
+        let (arg, _) = theta.loop_var(o_two.val_out(0));
+        let o_neg = other
+            .node_builder_in(theta.body(), Op::Neg)
+            .operand(arg)
+            .finish();
+        theta.set_next(arg, o_neg.val_out(0));
+        let body = theta.body();
+        let theta = theta.finish(arg);
+        let o_add = other
+            .node_builder(Op::Add)
+            .operand(o_one.val_out(0))
+            .operand(theta.val_out(0))
+            .finish();
+
+        let mapping = ncx.import_from(&other, IdMap::default());
+        assert_eq!(Ok(()), ncx.verify());
+        assert_eq!(mapping.nodes[&o_one.id()], n_one.id());
+        assert_eq!(ncx.num_nodes(), 5);
+
+        let n_add = ncx.node_ref(mapping.nodes[&o_add.id()]);
+        assert_eq!(n_add.val_in(0).origin(), n_one.val_out(0));
+        let n_theta = n_add.val_in(1).origin().producer();
+        assert_eq!(n_theta.id(), mapping.nodes[&theta.id()]);
+        let n_neg = ncx.node_ref(mapping.nodes[&o_neg.id()]);
+        assert_eq!(n_neg.region(), mapping.regions[&body]);
+    }
+
+    #[test]
+    fn importing_with_mapped_origins() {
+        let ncx = NodeCtxt::new();
+        let n_five = ncx.mk_node(Op::Lit(5));
+
+        let other = NodeCtxt::new();
+        let o_extern = other.mk_node(Op::Param(0));
+        let o_neg = other
+            .node_builder(Op::Neg)
+            .operand(o_extern.val_out(0))
+            .finish();
+
+        let mut mapping = IdMap::default();
+        mapping
+            .origins
+            .insert(o_extern.val_out(0).id(), n_five.val_out(0).id());
+        let mapping = ncx.import_from(&other, mapping);
+        assert_eq!(Ok(()), ncx.verify());
+        assert_eq!(ncx.num_nodes(), 2);
+        assert!(!mapping.nodes.contains_key(&o_extern.id()));
+
+        let n_neg = ncx.node_ref(mapping.nodes[&o_neg.id()]);
+        assert_eq!(*n_neg.kind(), NodeKind::Op(Op::Neg));
+        assert_eq!(n_neg.val_in(0).origin(), n_five.val_out(0));
+    }
+}
@@ -0,0 +1,349 @@
+use super::{types::erase, NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, Sig, UserId};
+use std::collections::HashMap;
+
+/// How the types of an op's value outputs follow from those of its value
+/// inputs.
+pub trait TypeRule: Sig {
+    /// The types of the op's value outputs given `inputs`, the types of its
+    /// value inputs, with `None` for those that aren't known. Returns why
+    /// the inputs don't fit the op otherwise.
+    ///
+    /// By default, the types are the ones the op declares, as for literals.
+    fn output_types(
+        &self,
+        inputs: &[Option<Self::Type>],
+    ) -> Result<Vec<Option<Self::Type>>, String> {
+        declared_output_types(self, inputs)
+    }
+}
+
+/// The types of the value outputs `op` declares with `Sig::val_out_type`,
+/// provided `inputs` have the types it declares with `Sig::val_in_type`, if
+/// any.
+pub fn declared_output_types<S: Sig + ?Sized>(
+    op: &S,
+    inputs: &[Option<S::Type>],
+) -> Result<Vec<Option<S::Type>>, String> {
+    for (port, input) in inputs.iter().enumerate() {
+        if let (Some(expected), Some(found)) = (op.val_in_type(port), input) {
+            if expected != *found {
+                return Err(format!(
+                    "value input {} takes {:?}, but is given {:?}",
+                    port, expected, found
+                ));
+            }
+        }
+    }
+    Ok((0..op.sig().val_outs)
+        .map(|port| op.val_out_type(port))
+        .collect())
+}
+
+/// A conflict found by `NodeCtxt::infer_types`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TypeError<T> {
+    /// The typing rule of an op rejected the types of its inputs.
+    Rejected {
+        node: NodeId,
+        inputs: Vec<Option<T>>,
+        reason: String,
+    },
+    /// A value passed out of a region has another type than the others
+    /// passed out through the same output, or than its loop variable.
+    Conflict { user: UserId, expected: T, found: T },
+}
+
+impl<S> NodeCtxt<S> {
+    /// Infers the type of every value origin from the typing rules of the
+    /// ops using them, and annotates the origins with it. Returns every
+    /// conflict found.
+    ///
+    /// Arguments take the type of what's passed in through them, gamma
+    /// outputs that of their results, and loop variables that of their
+    /// initial or next value, whichever is known. Lambda parameters and the
+    /// outputs of applies are left untyped.
+    pub fn infer_types(&self) -> Result<(), Vec<TypeError<S::Type>>>
+    where
+        S: TypeRule,
+    {
+        let mut errors = vec![];
+        self.infer_region_types(self.root_region(), &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn infer_region_types(&self, region_id: RegionId, errors: &mut Vec<TypeError<S::Type>>)
+    where
+        S: TypeRule,
+    {
+        for node_id in self.region_topo_order(region_id) {
+            let is_op = matches!(self.node_data(node_id).kind, NodeKind::Op(..));
+            if is_op {
+                self.infer_op_types(node_id, errors);
+            } else {
+                self.infer_structural_types(node_id, errors);
+            }
+        }
+    }
+
+    fn infer_op_types(&self, node_id: NodeId, errors: &mut Vec<TypeError<S::Type>>)
+    where
+        S: TypeRule,
+    {
+        let inputs: Vec<Option<S::Type>> = self
+            .node_data(node_id)
+            .ins
+            .iter()
+            .filter(|user| user.kind == PortKind::Val)
+            .map(|user| self.typed_origin(user.origin.get()?))
+            .collect();
+        let outputs = match &self.node_data(node_id).kind {
+            NodeKind::Op(op) => op.output_types(&inputs),
+            _ => unreachable!(),
+        };
+        match outputs {
+            Ok(outputs) => {
+                for (index, ty) in outputs.into_iter().enumerate() {
+                    let output = OriginId::output(node_id, index);
+                    self.set_origin_type(output, ty.map(erase));
+                }
+            }
+            Err(reason) => errors.push(TypeError::Rejected {
+                node: node_id,
+                inputs,
+                reason,
+            }),
+        }
+    }
+
+    fn infer_structural_types(&self, node_id: NodeId, errors: &mut Vec<TypeError<S::Type>>)
+    where
+        S: TypeRule,
+    {
+        let regions = self.inner_regions(node_id);
+        for &region_id in &regions {
+            self.type_routed_args(region_id);
+        }
+        let is_theta = matches!(self.node_data(node_id).kind, NodeKind::Theta { .. });
+        if !is_theta {
+            for &region_id in &regions {
+                self.infer_region_types(region_id, errors);
+            }
+            self.type_region_outputs(&regions, errors);
+            return;
+        }
+
+        // Loop variables whose type is only known from their next value
+        // make the body be inferred again, which ends once none are left.
+        let body = regions[0];
+        loop {
+            let mut body_errors = vec![];
+            self.infer_region_types(body, &mut body_errors);
+            if !self.type_loop_vars(body, &mut body_errors) {
+                errors.extend(body_errors);
+                break;
+            }
+        }
+        let num_loop_vars = self.region_data(body).args.len();
+        for index in 0..num_loop_vars {
+            let arg = OriginId::argument(body, index);
+            let output = OriginId::output(node_id, index);
+            self.set_origin_type(output, self.origin_type(arg));
+        }
+    }
+
+    /// Gives the arguments of `region_id` the type of what's passed in
+    /// through them.
+    fn type_routed_args(&self, region_id: RegionId) {
+        let num_args = self.region_data(region_id).args.len();
+        for index in 0..num_args {
+            let arg = OriginId::argument(region_id, index);
+            let origin_id = self
+                .origin_data(arg)
+                .source
+                .and_then(|source| self.user_data(source).origin.get());
+            if let Some(origin_id) = origin_id {
+                self.set_origin_type(arg, self.origin_type(origin_id));
+            }
+        }
+    }
+
+    /// Gives the outputs that the results of `regions` are passed out
+    /// through the type of those results.
+    fn type_region_outputs(&self, regions: &[RegionId], errors: &mut Vec<TypeError<S::Type>>)
+    where
+        S: Sig,
+    {
+        let mut output_types: HashMap<OriginId, S::Type> = HashMap::new();
+        for &region_id in regions {
+            let num_res = self.region_data(region_id).res.len();
+            for index in 0..num_res {
+                let user = UserId::result(region_id, index);
+                let (sink, origin_id) = {
+                    let user_data = self.user_data(user);
+                    match (user_data.sink, user_data.origin.get()) {
+                        (Some(sink), Some(origin_id)) => (sink, origin_id),
+                        _ => continue,
+                    }
+                };
+                let found = match self.typed_origin(origin_id) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                match output_types.get(&sink) {
+                    Some(expected) if *expected != found => errors.push(TypeError::Conflict {
+                        user,
+                        expected: expected.clone(),
+                        found,
+                    }),
+                    Some(_) => {}
+                    None => {
+                        output_types.insert(sink, found);
+                    }
+                }
+            }
+        }
+        for (output, ty) in output_types {
+            self.set_origin_type(output, Some(erase(ty)));
+        }
+    }
+
+    /// Gives the untyped loop variables of theta body `body` the type of
+    /// their next value, and reports those whose next value has another
+    /// type. Returns whether any loop variable was given a type.
+    fn type_loop_vars(&self, body: RegionId, errors: &mut Vec<TypeError<S::Type>>) -> bool
+    where
+        S: Sig,
+    {
+        let mut changed = false;
+        let num_loop_vars = self.region_data(body).args.len();
+        for index in 0..num_loop_vars {
+            let arg = OriginId::argument(body, index);
+            // Skips the predicate result.
+            let user = UserId::result(body, index + 1);
+            let next = match self.user_data(user).origin.get() {
+                Some(next) => next,
+                None => continue,
+            };
+            match (self.typed_origin(arg), self.typed_origin(next)) {
+                (None, Some(found)) => {
+                    self.set_origin_type(arg, Some(erase(found)));
+                    changed = true;
+                }
+                (Some(expected), Some(found)) if expected != found => {
+                    errors.push(TypeError::Conflict {
+                        user,
+                        expected,
+                        found,
+                    });
+                }
+                _ => {}
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TypeError;
+    use crate::rvsdg::{
+        testing::{Op, Ty},
+        NodeCtxt, UserId,
+    };
+
+    #[test]
+    fn inferring_types_of_routed_values() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Param(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_one.val_out(0));
+        let n_add = ncx
+            .node_builder_in(gamma.branch(0), Op::Add)
+            .operand(args[0])
+            .operand(args[0])
+            .finish();
+        let output = gamma.exit_var(&[n_add.val_out(0), args[1]]);
+        gamma.finish();
+        let n_float = ncx.node_builder(Op::ToFloat).operand(output).finish();
+        assert_eq!(None, n_add.val_out(0).ty());
+
+        assert_eq!(Ok(()), ncx.infer_types());
+        assert_eq!(Some(Ty::Int), n_add.val_out(0).ty());
+        assert_eq!(Some(Ty::Int), output.ty());
+        assert_eq!(Some(Ty::Float), n_float.val_out(0).ty());
+        assert_eq!(None, n_pred.val_out(0).ty());
+    }
+
+    #[test]
+    fn inferring_loop_variables_from_their_next_value() {
+        let ncx = NodeCtxt::new();
+        let n_init = ncx.mk_node(Op::Param(0));
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (arg, output) = theta.loop_var(n_init.val_out(0));
+        let n_one = ncx.node_builder_in(theta.body(), Op::Lit(1)).finish();
+        let n_next = ncx
+            .node_builder_in(theta.body(), Op::Add)
+            .operand(arg)
+            .operand(n_one.val_out(0))
+            .finish();
+        theta.set_next(arg, n_next.val_out(0));
+        theta.finish(n_next.val_out(0));
+
+        assert_eq!(Ok(()), ncx.infer_types());
+        assert_eq!(Some(Ty::Int), arg.ty());
+        assert_eq!(Some(Ty::Int), output.ty());
+    }
+
+    #[test]
+    fn reporting_conflicts() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Param(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_half = ncx.mk_node(Op::Float(1));
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_one.val_out(0))
+            .operand(n_half.val_out(0))
+            .finish();
+        ncx.node_builder(Op::ToFloat)
+            .operand(n_half.val_out(0))
+            .finish();
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let ints = gamma.entry_var(n_one.val_out(0));
+        let floats = gamma.entry_var(n_half.val_out(0));
+        gamma.exit_var(&[ints[0], floats[1]]);
+        let branch = gamma.branch(1);
+        gamma.finish();
+
+        let errors = ncx.infer_types().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[0],
+            TypeError::Rejected {
+                node: n_add.id(),
+                inputs: vec![Some(Ty::Int), Some(Ty::Float)],
+                reason: "operands differ".to_owned(),
+            }
+        );
+        assert!(matches!(
+            &errors[1],
+            TypeError::Rejected { reason, .. } if reason == "value input 0 takes Int, but is given Float"
+        ));
+        assert_eq!(
+            errors[2],
+            TypeError::Conflict {
+                user: UserId::Res {
+                    region: branch,
+                    index: 0,
+                },
+                expected: Ty::Int,
+                found: Ty::Float,
+            }
+        );
+    }
+}
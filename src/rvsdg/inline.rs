@@ -0,0 +1,658 @@
+use super::{
+    IdMap, NodeCtxt, NodeId, NodeKind, OriginData, OriginId, PortKind, RegionId, RegionSigS, Sig,
+    SigS, UserData, UserId,
+};
+use std::{hash::Hash, mem};
+
+/// An apply node that calls a statically known lambda, as shown to the
+/// inlining heuristic.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InlineSite {
+    pub apply: NodeId,
+    pub lambda: NodeId,
+    /// The number of nodes in the body of the lambda, including the ones in
+    /// nested regions.
+    pub size: usize,
+}
+
+impl<S> NodeCtxt<S> {
+    /// Inlines the apply nodes in `region_id`, and in the regions nested in
+    /// it, whose function is a statically known lambda, for which
+    /// `should_inline` agrees. Returns how many were inlined.
+    ///
+    /// Apply nodes copied over from inlined bodies aren't considered, so
+    /// recursive functions are inlined at most once per call.
+    pub fn inline_applies<F>(&self, region_id: RegionId, mut should_inline: F) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(&InlineSite) -> bool,
+    {
+        let mut applies = vec![];
+        let mut regions = vec![region_id];
+        while let Some(region_id) = regions.pop() {
+            for node_id in self.region_nodes(region_id) {
+                if let NodeKind::Apply { .. } = self.node_data(node_id).kind {
+                    applies.push(node_id);
+                }
+                regions.extend(self.inner_regions(node_id));
+            }
+        }
+
+        let mut num_inlined = 0;
+        for apply in applies {
+            let site = match self.inline_site(apply) {
+                Some(site) => site,
+                None => continue,
+            };
+            if should_inline(&site) && self.inline_apply(apply) {
+                num_inlined += 1;
+            }
+        }
+        num_inlined
+    }
+
+    /// Replaces an apply node with a copy of the body of the lambda it
+    /// calls, returning whether it could.
+    ///
+    /// The lambda must be statically known, take the arguments of the apply
+    /// and produce its outputs, and not enclose the apply. The lambda itself
+    /// is left in place, even if it's no longer used.
+    pub fn inline_apply(&self, apply: NodeId) -> bool
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let lambda = match self.inline_site(apply) {
+            Some(site) => site.lambda,
+            None => return false,
+        };
+        let region_id = self.node_data(apply).outer_region;
+        let body = self.inner_regions(lambda)[0];
+        let apply_sig = self.node_data(apply).kind.sig();
+
+        let (params, ctx_vars) = match self.node_data(lambda).kind {
+            NodeKind::Lambda { params, ctx_vars } => (params, ctx_vars),
+            _ => unreachable!(),
+        };
+        let input_origin = |node, index| {
+            self.user_data(UserId::input(node, index))
+                .origin
+                .get()
+                .unwrap()
+        };
+
+        // Value parameters take the value arguments, after the function, and
+        // state parameters the state arguments.
+        let mut args = Vec::with_capacity(params + ctx_vars);
+        let (mut val_params, mut st_params) = (0, 0);
+        for index in 0..params {
+            let input = match self.region_data(body).args[index].kind {
+                PortKind::Val => {
+                    val_params += 1;
+                    val_params
+                }
+                PortKind::St => {
+                    st_params += 1;
+                    apply_sig.val_ins + st_params - 1
+                }
+            };
+            args.push(input_origin(apply, input));
+        }
+        for index in 0..ctx_vars {
+            args.push(self.route_into(input_origin(lambda, index), region_id));
+        }
+
+        let results = self.copy_region(body, region_id, &args);
+        let (val_results, st_results): (Vec<_>, Vec<_>) = {
+            let region_data = self.region_data(body);
+            results
+                .into_iter()
+                .zip(region_data.res.iter().map(|res| res.kind))
+                .partition(|&(_, kind)| kind == PortKind::Val)
+        };
+        let outputs = val_results.into_iter().chain(st_results);
+        for (index, (result, _)) in outputs.enumerate() {
+            let output = OriginId::output(apply, index);
+            self.replace_all_users(output, result.unwrap());
+        }
+        self.remove_node(apply);
+        true
+    }
+
+    /// The lambda an apply node would be inlined from, provided it can be.
+    pub fn inline_site(&self, apply: NodeId) -> Option<InlineSite>
+    where
+        S: Sig,
+    {
+        let node_data = self.node_data(apply);
+        if node_data.removed {
+            return None;
+        }
+        let apply_sig = match node_data.kind {
+            NodeKind::Apply {
+                arg_val_ins,
+                arg_st_ins,
+                region_val_res,
+                region_st_res,
+            } => SigS {
+                val_ins: arg_val_ins,
+                st_ins: arg_st_ins,
+                val_outs: region_val_res,
+                st_outs: region_st_res,
+            },
+            _ => return None,
+        };
+        let lambda = self.known_lambda(node_data.ins[0].origin.get()?)?;
+        if self.lambda_sig(lambda) != apply_sig || self.encloses(lambda, node_data.outer_region) {
+            return None;
+        }
+
+        // Inputs and results left unconnected have nothing to stand for the
+        // arguments and outputs.
+        let body = self.inner_regions(lambda)[0];
+        let is_unconnected = |user: &UserData| user.origin.get().is_none();
+        if node_data.ins.iter().any(is_unconnected)
+            || self.node_data(lambda).ins.iter().any(is_unconnected)
+            || self.region_data(body).res.iter().any(is_unconnected)
+        {
+            return None;
+        }
+
+        Some(InlineSite {
+            apply,
+            lambda,
+            size: self.region_size(body),
+        })
+    }
+
+    /// The lambda whose function `origin_id` carries, following it through
+    /// the inputs of structural nodes that pass it on unchanged.
+    pub fn known_lambda(&self, mut origin_id: OriginId) -> Option<NodeId> {
+        loop {
+            match origin_id {
+                OriginId::Out { node, .. } => {
+                    return match self.node_data(node).kind {
+                        NodeKind::Lambda { .. } => Some(node),
+                        _ => None,
+                    };
+                }
+                OriginId::Arg { region, index } => {
+                    let input = self.origin_data(origin_id).source?;
+                    let node = input.node_id()?;
+                    let passed_on = match self.node_data(node).kind {
+                        NodeKind::Gamma { .. } | NodeKind::Lambda { .. } => true,
+                        // Only invariant loop variables keep their value.
+                        NodeKind::Theta { .. } => {
+                            let next = self.user_data(UserId::Res {
+                                region,
+                                index: index + 1,
+                            });
+                            next.origin.get() == Some(origin_id)
+                        }
+                        _ => false,
+                    };
+                    if !passed_on {
+                        return None;
+                    }
+                    origin_id = self.user_data(input).origin.get()?;
+                }
+            }
+        }
+    }
+
+    /// The parameters and results of a lambda, as the signature an apply
+    /// node calling it has besides its function input.
+    pub fn lambda_sig(&self, lambda: NodeId) -> SigS {
+        let params = match self.node_data(lambda).kind {
+            NodeKind::Lambda { params, .. } => params,
+            _ => panic!("{:?} isn't a lambda", lambda),
+        };
+        let body = self.inner_regions(lambda)[0];
+        let region_data = self.region_data(body);
+        let count = |kinds: &mut dyn Iterator<Item = PortKind>, kind| {
+            kinds.filter(|&port_kind| port_kind == kind).count()
+        };
+        let args = || region_data.args[..params].iter().map(|arg| arg.kind);
+        let res = || region_data.res.iter().map(|res| res.kind);
+        SigS {
+            val_ins: count(&mut args(), PortKind::Val),
+            st_ins: count(&mut args(), PortKind::St),
+            val_outs: count(&mut res(), PortKind::Val),
+            st_outs: count(&mut res(), PortKind::St),
+        }
+    }
+
+    /// The lambdas whose functions the context variables of `lambda` carry,
+    /// in order, or `None` for those carrying other values.
+    pub fn ctx_var_lambdas(&self, lambda: NodeId) -> Vec<Option<NodeId>> {
+        let (params, ctx_vars) = match self.node_data(lambda).kind {
+            NodeKind::Lambda { params, ctx_vars } => (params, ctx_vars),
+            _ => panic!("{:?} isn't a lambda", lambda),
+        };
+        let body = self.inner_regions(lambda)[0];
+        (0..ctx_vars)
+            .map(|index| self.known_lambda(OriginId::argument(body, params + index)))
+            .collect()
+    }
+
+    /// Whether `region_id` is one of the regions of `node_id`, or nested in
+    /// one of them.
+    fn encloses(&self, node_id: NodeId, mut region_id: RegionId) -> bool {
+        while let Some(node) = self.region_data(region_id).node {
+            if node == node_id {
+                return true;
+            }
+            region_id = self.node_data(node).outer_region;
+        }
+        false
+    }
+
+    /// The number of nodes in a region, including the ones in nested
+    /// regions.
+    fn region_size(&self, region_id: RegionId) -> usize {
+        self.region_nodes(region_id)
+            .into_iter()
+            .map(|node_id| {
+                let inner_regions = self.inner_regions(node_id);
+                1 + inner_regions
+                    .into_iter()
+                    .map(|region_id| self.region_size(region_id))
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Copies the nodes of region `from` into region `into`, with `args`
+    /// standing for the arguments of `from`. Returns the origins of the
+    /// copied results, where they're connected.
+    pub fn copy_region(
+        &self,
+        from: RegionId,
+        into: RegionId,
+        args: &[OriginId],
+    ) -> Vec<Option<OriginId>>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        self.copy_region_from(self, from, into, args, &mut IdMap::default())
+    }
+
+    /// Copies region `region_id`, along with the regions nested in it, as a
+    /// new region of the structural node `into`, returning the copy.
+    ///
+    /// The arguments and results of the copy stand for the same inputs and
+    /// outputs of `into` as the original ones do for their node, so `into`
+    /// is usually the node owning `region_id`, as when duplicating a branch.
+    pub fn clone_region(&self, region_id: RegionId, into: NodeId) -> RegionId
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        self.copy_inner_region(self, region_id, into, &mut IdMap::default())
+    }
+
+    /// Copies the nodes of region `from` of `src`, which may be another
+    /// context, into region `into` of this one, recording the copies in
+    /// `map`. See `copy_region`.
+    pub fn copy_region_from(
+        &self,
+        src: &NodeCtxt<S>,
+        from: RegionId,
+        into: RegionId,
+        args: &[OriginId],
+        map: &mut IdMap,
+    ) -> Vec<Option<OriginId>>
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        for (index, &arg) in args.iter().enumerate() {
+            map.origins.insert(OriginId::argument(from, index), arg);
+        }
+        for node_id in src.region_topo_order(from) {
+            self.copy_node_from(src, node_id, into, map);
+        }
+
+        let region_data = src.region_data(from);
+        region_data
+            .res
+            .iter()
+            .map(|res| res.origin.get().map(|origin_id| map.origins[&origin_id]))
+            .collect()
+    }
+
+    /// Copies a node of `src` into region `into`, along with its regions,
+    /// connecting its inputs to the copies of their origins. The copy, and
+    /// those of its outputs and regions, are recorded in `map`.
+    pub fn copy_node_from(
+        &self,
+        src: &NodeCtxt<S>,
+        node_id: NodeId,
+        into: RegionId,
+        map: &mut IdMap,
+    ) -> NodeId
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let (kind, origins, in_kinds, out_kinds) = {
+            let node_data = src.node_data(node_id);
+            let origins: Vec<Option<OriginId>> = node_data
+                .ins
+                .iter()
+                .map(|user| user.origin.get().map(|origin_id| map.origins[&origin_id]))
+                .collect();
+            let in_kinds: Vec<PortKind> = node_data.ins.iter().map(|user| user.kind).collect();
+            let out_kinds: Vec<PortKind> =
+                node_data.outs.iter().map(|origin| origin.kind).collect();
+            (node_data.kind.clone(), origins, in_kinds, out_kinds)
+        };
+        let num_outs = out_kinds.len();
+        let record = |map: &mut IdMap, copy: NodeId| {
+            if let Some(span) = src.node_span(node_id) {
+                self.attach_span(copy, span);
+            }
+            if let Some(name) = src.node_name(node_id) {
+                if self.node_name(copy).is_none() {
+                    self.set_node_name(copy, name);
+                }
+            }
+            map.nodes.insert(node_id, copy);
+            for index in 0..num_outs {
+                map.origins.insert(
+                    OriginId::output(node_id, index),
+                    OriginId::output(copy, index),
+                );
+            }
+            copy
+        };
+
+        if !kind.is_structural() {
+            if let Some(origins) = origins.iter().cloned().collect::<Option<Vec<_>>>() {
+                let copy = self.mk_node_in_region_with(into, kind, &origins);
+                return record(map, copy);
+            }
+        }
+
+        // Ports of structural nodes are added as they're needed, so their
+        // kinds may come in any order, which the copy has to keep.
+        let copy = self.create_node(kind, into).id();
+        {
+            let mut copy_data = self.node_data_mut(copy);
+            let ins = self
+                .input_ports
+                .alloc(in_kinds.into_iter().map(|kind| UserData {
+                    kind,
+                    ..UserData::default()
+                }));
+            let outs = self
+                .output_ports
+                .alloc(out_kinds.into_iter().map(|kind| OriginData {
+                    kind,
+                    ..OriginData::default()
+                }));
+            self.input_ports
+                .release(mem::replace(&mut copy_data.ins, ins));
+            self.output_ports
+                .release(mem::replace(&mut copy_data.outs, outs));
+        }
+        for (index, origin_id) in origins.into_iter().enumerate() {
+            if let Some(origin_id) = origin_id {
+                self.connect_ports(UserId::input(copy, index), origin_id);
+            }
+        }
+
+        for region_id in src.inner_regions(node_id) {
+            self.copy_inner_region(src, region_id, copy, map);
+        }
+        record(map, copy)
+    }
+
+    /// Copies region `region_id` of `src` as a new region of `node_id`,
+    /// whose arguments and results stand for the same inputs and outputs
+    /// the original ones do. The copy, and those of the nodes and regions
+    /// in it, are recorded in `map`.
+    fn copy_inner_region(
+        &self,
+        src: &NodeCtxt<S>,
+        region_id: RegionId,
+        node_id: NodeId,
+        map: &mut IdMap,
+    ) -> RegionId
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let region_copy = self.mk_region_for_node(node_id, RegionSigS::default());
+        map.regions.insert(region_id, region_copy);
+        let (args, res) = {
+            let region_data = src.region_data(region_id);
+            let args: Vec<_> = region_data
+                .args
+                .iter()
+                .map(|arg| (arg.kind, arg.source))
+                .collect();
+            let res: Vec<_> = region_data
+                .res
+                .iter()
+                .map(|res| (res.kind, res.sink))
+                .collect();
+            (args, res)
+        };
+
+        let args: Vec<OriginId> = args
+            .into_iter()
+            .map(|(kind, source)| {
+                let source = source.map(|source| match source {
+                    UserId::In { index, .. } => UserId::In {
+                        node: node_id,
+                        index,
+                    },
+                    UserId::Res { .. } => unreachable!(),
+                });
+                self.add_argument(region_copy, kind, source)
+            })
+            .collect();
+        let results = self.copy_region_from(src, region_id, region_copy, &args, map);
+        for ((kind, sink), result) in res.into_iter().zip(results) {
+            let sink = sink.map(|sink| match sink {
+                OriginId::Out { index, .. } => OriginId::Out {
+                    node: node_id,
+                    index,
+                },
+                OriginId::Arg { .. } => unreachable!(),
+            });
+            let user_id = self.add_result(region_copy, kind, sink);
+            if let Some(origin_id) = result {
+                self.connect_ports(user_id, origin_id);
+            }
+        }
+        region_copy
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InlineSite;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, OriginId, PortKind, SigS};
+
+    #[test]
+    fn inlining_a_call() {
+        let ncx = NodeCtxt::new();
+        let root = ncx.root_region();
+
+        // Subtracts a captured value from the loaded value of its argument.
+        let n_ten = ncx.mk_node(Op::Lit(10));
+        let lambda = ncx.lambda_builder(root, &[PortKind::St, PortKind::Val]);
+        let ten = lambda.ctx_var(n_ten.val_out(0));
+        let load = ncx
+            .node_builder_in(lambda.body(), Op::Load(0))
+            .operand(lambda.val_param(0))
+            .state(lambda.st_param(0))
+            .finish();
+        let sum = ncx
+            .node_builder_in(lambda.body(), Op::Sub)
+            .operand(load.val_out(0))
+            .operand(ten)
+            .finish();
+        let lambda = lambda.finish(&[sum.val_out(0)], &[load.st_out(0)]);
+        assert_eq!(
+            ncx.lambda_sig(lambda.id()),
+            SigS {
+                val_ins: 1,
+                st_ins: 1,
+                val_outs: 1,
+                st_outs: 1,
+            }
+        );
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(1));
+        let apply = ncx
+            .apply_builder(lambda.val_out(0), ncx.lambda_sig(lambda.id()))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_user = ncx
+            .node_builder(Op::Add)
+            .operand(apply.val_out(0))
+            .operand(apply.val_out(0))
+            .finish();
+        let n_next = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(apply.st_out(0))
+            .finish();
+
+        let mut sites = vec![];
+        let num_inlined = ncx.inline_applies(root, |site| {
+            sites.push(*site);
+            true
+        });
+        assert_eq!(1, num_inlined);
+        assert_eq!(
+            sites,
+            [InlineSite {
+                apply: apply.id(),
+                lambda: lambda.id(),
+                size: 2,
+            }]
+        );
+        assert!(ncx.node_data(apply.id()).removed);
+
+        let sum = n_user.val_in(0).origin().producer();
+        assert_eq!(*sum.kind(), NodeKind::Op(Op::Sub));
+        assert_eq!(sum.val_in(1).origin(), n_ten.val_out(0));
+        let load = sum.val_in(0).origin().producer();
+        assert_eq!(load.val_in(0).origin(), n_addr.val_out(0));
+        assert_eq!(load.st_in(0).origin(), st.st_out(0));
+        assert_eq!(n_next.st_in(0).origin(), load.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn inlining_structural_nodes_into_nested_regions() {
+        let ncx = NodeCtxt::new();
+        let root = ncx.root_region();
+
+        let lambda = ncx.lambda_builder(root, &[PortKind::Val]);
+        let theta = ncx.theta_builder(lambda.body());
+        let (x, x_out) = theta.loop_var(lambda.val_param(0));
+        let one = ncx.node_builder_in(theta.body(), Op::Lit(1)).finish();
+        let next = ncx
+            .node_builder_in(theta.body(), Op::Add)
+            .operand(x)
+            .operand(one.val_out(0))
+            .finish();
+        theta.set_next(x, next.val_out(0));
+        theta.finish(next.val_out(0));
+        let lambda = lambda.finish(&[x_out], &[]);
+
+        // The call sits in a gamma branch, into which the function is
+        // passed.
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let function = gamma.entry_var(lambda.val_out(0))[1];
+        let arg = gamma.entry_var(n_pred.val_out(0))[1];
+        let apply = ncx
+            .apply_builder(function, ncx.lambda_sig(lambda.id()))
+            .operand(arg)
+            .finish();
+        let result = gamma.exit_var(&[gamma.entry_var(n_pred.val_out(0))[0], apply.val_out(0)]);
+        gamma.finish();
+
+        // Too big for the heuristic.
+        assert_eq!(0, ncx.inline_applies(root, |site| site.size <= 2));
+        assert_eq!(1, ncx.inline_applies(root, |site| site.size <= 3));
+
+        let branch = ncx.region_ref(ncx.inner_regions(result.producer().id())[1]);
+        let copies: Vec<_> = branch.nodes().map(|node| *node.kind()).collect();
+        assert_eq!(
+            copies,
+            [NodeKind::Theta {
+                val_loop_vars: 1,
+                st_loop_vars: 0,
+            }]
+        );
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn unknown_functions_are_left_alone() {
+        let ncx = NodeCtxt::<Op>::new();
+        let root = ncx.root_region();
+
+        let outer = ncx.lambda_builder(root, &[PortKind::Val, PortKind::Val]);
+        let apply = ncx
+            .apply_builder(
+                outer.val_param(0),
+                SigS {
+                    val_ins: 1,
+                    val_outs: 1,
+                    ..SigS::default()
+                },
+            )
+            .operand(outer.val_param(1))
+            .finish();
+        outer.finish(&[apply.val_out(0)], &[]);
+
+        assert_eq!(0, ncx.inline_applies(root, |_| true));
+        assert!(!ncx.inline_apply(apply.id()));
+    }
+
+    #[test]
+    fn cloning_a_branch() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_one.val_out(0));
+        let theta = ncx.theta_builder(gamma.branch(0));
+        let (arg, out) = theta.loop_var(args[0]);
+        let n_add = ncx
+            .node_builder_in(theta.body(), Op::Add)
+            .operand(arg)
+            .operand(arg)
+            .finish();
+        theta.set_next(arg, n_add.val_out(0));
+        theta.finish(arg);
+        gamma.exit_var(&[out, args[1]]);
+        let gamma = gamma.finish();
+
+        let branch = ncx.inner_regions(gamma.id())[0];
+        let copy = ncx.clone_region(branch, gamma.id());
+        assert_eq!(ncx.inner_regions(gamma.id())[2], copy);
+        assert_eq!(Ok(()), ncx.verify());
+
+        let region = ncx.region_ref(copy);
+        let theta = region.nodes().next().unwrap();
+        assert_ne!(theta.id(), out.producer().id());
+        assert_eq!(region.nodes().count(), 1);
+        let body = ncx.inner_regions(theta.id())[0];
+        let n_add = ncx.region_ref(body).nodes().next().unwrap();
+        assert_eq!(*n_add.kind(), NodeKind::Op(Op::Add));
+        assert_eq!(n_add.val_in(0).origin(), n_add.val_in(1).origin());
+        assert_eq!(
+            n_add.val_in(0).origin().id(),
+            OriginId::Arg {
+                region: body,
+                index: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use super::{stats::kind_name, NodeCtxt, NodeId, NodeKind, OriginId, RegionId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hash,
+    io::{self, Write},
+    mem::Discriminant,
+};
+
+/// An entry of the intern table: the term a node was interned under.
+#[derive(Clone, PartialEq, Debug)]
+pub struct InternedTerm<S> {
+    pub node: NodeId,
+    pub region: RegionId,
+    pub kind: NodeKind<S>,
+    pub origins: Vec<OriginId>,
+}
+
+/// How full the intern table is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InternTableStats {
+    pub terms: usize,
+    pub capacity: usize,
+}
+
+/// How many of the nodes of a kind looked up in the intern table were
+/// made, and how many were found there instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct InternCounts {
+    pub created: usize,
+    pub deduplicated: usize,
+}
+
+impl InternCounts {
+    pub fn lookups(&self) -> usize {
+        self.created + self.deduplicated
+    }
+
+    /// The share of lookups that found a node, if any were made.
+    pub fn hit_rate(&self) -> Option<f64> {
+        match self.lookups() {
+            0 => None,
+            lookups => Some(self.deduplicated as f64 / lookups as f64),
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// How the nodes looked up in the intern table fared so far, by the
+    /// name of their kind, as in `GraphStats::nodes`.
+    ///
+    /// Only the nodes `interning` lets be shared are looked up.
+    pub fn intern_metrics(&self) -> BTreeMap<String, InternCounts>
+    where
+        S: Debug,
+    {
+        let mut metrics: BTreeMap<String, InternCounts> = BTreeMap::new();
+        for (kind, counts) in self.intern_counts.borrow().values() {
+            let name = kind_name(kind);
+            let total = metrics.entry(name).or_default();
+            total.created += counts.created;
+            total.deduplicated += counts.deduplicated;
+        }
+        metrics
+    }
+
+    /// Records that a node of `kind`, which `kind_key` stands for, was
+    /// looked up in the intern table, and whether it was `found` there.
+    pub(super) fn count_interning(
+        &self,
+        kind_key: Option<Discriminant<S>>,
+        kind: &NodeKind<S>,
+        found: bool,
+    ) where
+        S: Clone,
+    {
+        let mut intern_counts = self.intern_counts.borrow_mut();
+        let (_, counts) = intern_counts
+            .entry(kind_key)
+            .or_insert_with(|| (kind.clone(), InternCounts::default()));
+        if found {
+            counts.deduplicated += 1;
+        } else {
+            counts.created += 1;
+        }
+    }
+
+    /// The terms in the intern table, ordered by the node they map to.
+    pub fn interned_terms(&self) -> Vec<InternedTerm<S>>
+    where
+        S: Clone,
+    {
+        let mut terms: Vec<InternedTerm<S>> = self
+            .interned_nodes
+            .borrow()
+            .iter()
+            .map(|(node_term, &node_id)| InternedTerm {
+                node: node_id,
+                region: node_term.region,
+                kind: node_term.kind.clone(),
+                origins: node_term.origins.to_vec(),
+            })
+            .collect();
+        terms.sort_by_key(|term| term.node.0);
+        terms
+    }
+
+    pub fn intern_table_stats(&self) -> InternTableStats {
+        let interned_nodes = self.interned_nodes.borrow();
+        InternTableStats {
+            terms: interned_nodes.len(),
+            capacity: interned_nodes.capacity(),
+        }
+    }
+
+    /// Groups of interned nodes whose terms are equal but for their region,
+    /// which is why they weren't merged.
+    pub fn terms_differing_by_region(&self) -> Vec<Vec<NodeId>>
+    where
+        S: Eq + Hash + Clone,
+    {
+        let mut groups: HashMap<(NodeKind<S>, Vec<OriginId>), Vec<NodeId>> = HashMap::new();
+        for term in self.interned_terms() {
+            groups
+                .entry((term.kind, term.origins))
+                .or_default()
+                .push(term.node);
+        }
+
+        let mut groups: Vec<Vec<NodeId>> = groups
+            .into_values()
+            .filter(|nodes| nodes.len() > 1)
+            .collect();
+        groups.sort_by_key(|nodes| nodes[0].0);
+        groups
+    }
+
+    /// Prints the intern table, a term per line, followed by its stats.
+    pub fn dump_interned(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Clone + Debug,
+    {
+        for term in self.interned_terms() {
+            writeln!(
+                out,
+                "{:?} = {:?} in {:?} {:?}",
+                term.node, term.kind, term.region, term.origins
+            )?;
+        }
+        let stats = self.intern_table_stats();
+        writeln!(out, "{} terms, capacity {}", stats.terms, stats.capacity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InternCounts;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn inspecting_interned_terms() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+
+        let terms = ncx.interned_terms();
+        assert_eq!(2, terms.len());
+        assert_eq!(n1.id(), terms[1].node);
+        assert_eq!(ncx.root_region(), terms[1].region);
+        assert_eq!(NodeKind::Op(Op::Neg), terms[1].kind);
+        assert_eq!(vec![n0.val_out(0).id()], terms[1].origins);
+
+        let stats = ncx.intern_table_stats();
+        assert_eq!(2, stats.terms);
+        assert!(stats.capacity >= 2);
+
+        let mut out = vec![];
+        ncx.dump_interned(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            "NodeId(1) = Op(Neg) in RegionId(0) [Out { node: NodeId(0), index: 0 }]",
+            lines[1]
+        );
+        assert!(lines[2].starts_with("2 terms"));
+    }
+
+    #[test]
+    fn terms_differing_only_by_region() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 2);
+        let lits: Vec<_> = (0..2)
+            .map(|index| {
+                ncx.node_builder_in(gamma.branch(index), Op::Lit(0))
+                    .finish()
+            })
+            .collect();
+        gamma.exit_var(&[lits[0].val_out(0), lits[1].val_out(0)]);
+        gamma.finish();
+
+        assert_eq!(
+            vec![vec![n0.id(), lits[0].id(), lits[1].id()]],
+            ncx.terms_differing_by_region()
+        );
+    }
+
+    #[test]
+    fn counting_deduplicated_nodes_by_kind() {
+        let ncx = NodeCtxt::new();
+        let n0 = ncx.mk_node(Op::Lit(0));
+        ncx.mk_node(Op::Lit(0));
+        ncx.mk_node(Op::Lit(1));
+        for _ in 0..3 {
+            ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        }
+
+        let metrics = ncx.intern_metrics();
+        assert_eq!(
+            vec![
+                (
+                    "Lit".to_owned(),
+                    InternCounts {
+                        created: 2,
+                        deduplicated: 1,
+                    }
+                ),
+                (
+                    "Neg".to_owned(),
+                    InternCounts {
+                        created: 1,
+                        deduplicated: 2,
+                    }
+                ),
+            ],
+            metrics.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(2.0 / 3.0), ncx.intern_metrics()["Neg"].hit_rate());
+        assert_eq!(None, InternCounts::default().hit_rate());
+    }
+}
@@ -0,0 +1,861 @@
+//! The XML format jlm writes graphs in, which its viewer and other RVSDG
+//! tools read.
+//!
+//! A region lists its arguments, its nodes and its results, and a node its
+//! inputs, its outputs and its regions. Edges join an output or argument to
+//! the inputs and results using it, by the ids of the ports:
+//!
+//! ```text
+//! <?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+//! <rvsdg>
+//!   <region id="r0">
+//!     <node id="n0" name="Lit(0)" type="">
+//!       <output id="n0o0" type="val"/>
+//!     </node>
+//!     <edge source="n0o0" target="n1i0"/>
+//!     <node id="n1" name="Neg" type="">
+//!       <input id="n1i0" type="val"/>
+//!       <output id="n1o0" type="val"/>
+//!     </node>
+//!   </region>
+//! </rvsdg>
+//! ```
+//!
+//! Ops are named by the `Debug` form of `S` and have an empty `type`, which
+//! is `gamma`, `theta`, `lambda`, `omega` or `apply` for the other nodes.
+//! Ports have the `type` of their values, `val` or `state`.
+
+use super::{
+    graphml::escape_xml, text::Head, NodeCtxt, NodeId, NodeKind, OriginId, ParseError, PortKind,
+    RegionId, Sig, UserId,
+};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    io::{self, Write},
+    iter::Peekable,
+    str::CharIndices,
+};
+
+impl<S> NodeCtxt<S> {
+    /// Writes the graph in the XML format of jlm, with the nodes of each
+    /// region ordered so that they're written after the nodes they use.
+    pub fn write_jlm_xml(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        writeln!(
+            out,
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#
+        )?;
+        writeln!(out, "<rvsdg>")?;
+        self.write_jlm_region(out, self.root_region(), 1)?;
+        writeln!(out, "</rvsdg>")
+    }
+
+    /// Reads a graph in the XML format of jlm, as `write_jlm_xml` writes
+    /// it, parsing ops with `parse_op`.
+    ///
+    /// Nodes must come after those they use. Ports of the state types jlm
+    /// has, `mem`, `iostate` and `loopstate`, are read as states, and ports
+    /// of types other than `state` as values. The graph is verified before
+    /// it's returned.
+    pub fn read_jlm_xml<F>(input: &str, parse_op: F) -> Result<NodeCtxt<S>, ParseError>
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(&str) -> Option<S>,
+    {
+        let root = XmlParser {
+            input,
+            chars: input.char_indices().peekable(),
+            line: 1,
+        }
+        .parse_document()?;
+        if root.name != "rvsdg" {
+            return Err(root.error(format!("expected `<rvsdg>`, found `<{}>`", root.name)));
+        }
+        let mut regions = root.children("region");
+        let region = match (regions.next(), regions.next()) {
+            (Some(region), None) => region,
+            _ => return Err(root.error("expected a single region".to_owned())),
+        };
+        if let Some(port) = region
+            .children("argument")
+            .chain(region.children("result"))
+            .next()
+        {
+            return Err(port.error("the root region has no arguments or results".to_owned()));
+        }
+
+        let mut sources = HashMap::new();
+        root.collect_edges(&mut sources)?;
+        let ncx = NodeCtxt::new();
+        let mut reader = JlmReader {
+            ctxt: &ncx,
+            parse_op,
+            sources,
+            origins: HashMap::new(),
+        };
+        reader.read_nodes(ncx.root_region(), region)?;
+
+        let last_line = input.lines().count().max(1);
+        ncx.verify().map_err(|violations| ParseError {
+            line: last_line,
+            message: format!("malformed graph: {:?}", violations),
+        })?;
+        Ok(ncx)
+    }
+
+    fn write_jlm_region(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let indent = "  ".repeat(depth);
+        let inner_indent = "  ".repeat(depth + 1);
+        writeln!(out, r#"{}<region id="r{}">"#, indent, region_id.0)?;
+        let arg_kinds: Vec<PortKind> = self
+            .region_data(region_id)
+            .args
+            .iter()
+            .map(|arg| arg.kind)
+            .collect();
+        for (index, &kind) in arg_kinds.iter().enumerate() {
+            let arg_id = OriginId::argument(region_id, index);
+            write_port(out, &inner_indent, "argument", &origin_xml_id(arg_id), kind)?;
+        }
+        for index in 0..arg_kinds.len() {
+            self.write_jlm_edges(out, &inner_indent, OriginId::argument(region_id, index))?;
+        }
+
+        for node_id in self.region_topo_order(region_id) {
+            self.write_jlm_node(out, node_id, depth + 1)?;
+        }
+
+        let res_kinds: Vec<PortKind> = self
+            .region_data(region_id)
+            .res
+            .iter()
+            .map(|res| res.kind)
+            .collect();
+        for (index, &kind) in res_kinds.iter().enumerate() {
+            let res_id = UserId::result(region_id, index);
+            write_port(out, &inner_indent, "result", &user_xml_id(res_id), kind)?;
+        }
+        writeln!(out, "{}</region>", indent)
+    }
+
+    fn write_jlm_node(&self, out: &mut dyn Write, node_id: NodeId, depth: usize) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let indent = "  ".repeat(depth);
+        let inner_indent = "  ".repeat(depth + 1);
+        let (name, kind, in_kinds, out_kinds) = {
+            let node_data = self.node_data(node_id);
+            let (name, kind) = match node_data.kind {
+                NodeKind::Op(ref op) => (format!("{:?}", op), ""),
+                NodeKind::Apply { .. } => (String::new(), "apply"),
+                NodeKind::Gamma { .. } => (String::new(), "gamma"),
+                NodeKind::Theta { .. } => (String::new(), "theta"),
+                NodeKind::Lambda { .. } => (String::new(), "lambda"),
+                NodeKind::Omega { .. } => (String::new(), "omega"),
+            };
+            let in_kinds: Vec<PortKind> = node_data.ins.iter().map(|user| user.kind).collect();
+            let out_kinds: Vec<PortKind> =
+                node_data.outs.iter().map(|origin| origin.kind).collect();
+            (name, kind, in_kinds, out_kinds)
+        };
+
+        writeln!(
+            out,
+            r#"{}<node id="n{}" name="{}" type="{}">"#,
+            indent,
+            node_id.0,
+            escape_xml(&name),
+            kind
+        )?;
+        for (index, &kind) in in_kinds.iter().enumerate() {
+            let user_id = UserId::input(node_id, index);
+            write_port(out, &inner_indent, "input", &user_xml_id(user_id), kind)?;
+        }
+        for (index, &kind) in out_kinds.iter().enumerate() {
+            let origin_id = OriginId::output(node_id, index);
+            write_port(
+                out,
+                &inner_indent,
+                "output",
+                &origin_xml_id(origin_id),
+                kind,
+            )?;
+        }
+        for inner_region in self.inner_regions(node_id) {
+            self.write_jlm_region(out, inner_region, depth + 1)?;
+        }
+        writeln!(out, "{}</node>", indent)?;
+
+        for index in 0..out_kinds.len() {
+            self.write_jlm_edges(out, &indent, OriginId::output(node_id, index))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the edges from an origin to its users.
+    fn write_jlm_edges(
+        &self,
+        out: &mut dyn Write,
+        indent: &str,
+        origin_id: OriginId,
+    ) -> io::Result<()> {
+        for user in self.origin_ref(origin_id).users() {
+            writeln!(
+                out,
+                r#"{}<edge source="{}" target="{}"/>"#,
+                indent,
+                origin_xml_id(origin_id),
+                user_xml_id(user.id())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_port(
+    out: &mut dyn Write,
+    indent: &str,
+    tag: &str,
+    id: &str,
+    kind: PortKind,
+) -> io::Result<()> {
+    let kind = match kind {
+        PortKind::Val => "val",
+        PortKind::St => "state",
+    };
+    writeln!(out, r#"{}<{} id="{}" type="{}"/>"#, indent, tag, id, kind)
+}
+
+fn origin_xml_id(origin_id: OriginId) -> String {
+    match origin_id {
+        OriginId::Out { node, index } => format!("n{}o{}", node.0, index),
+        OriginId::Arg { region, index } => format!("r{}a{}", region.0, index),
+    }
+}
+
+fn user_xml_id(user_id: UserId) -> String {
+    match user_id {
+        UserId::In { node, index } => format!("n{}i{}", node.0, index),
+        UserId::Res { region, index } => format!("r{}r{}", region.0, index),
+    }
+}
+
+/// The kind of the ports of a jlm type.
+fn type_kind(name: &str) -> PortKind {
+    match name {
+        "state" | "mem" | "iostate" | "loopstate" => PortKind::St,
+        _ => PortKind::Val,
+    }
+}
+
+struct Element {
+    line: usize,
+    name: String,
+    attrs: Vec<(String, String)>,
+    elements: Vec<Element>,
+}
+
+impl Element {
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    fn required_attr(&self, name: &str) -> Result<&str, ParseError> {
+        self.attr(name)
+            .ok_or_else(|| self.error(format!("`<{}>` needs a `{}` attribute", self.name, name)))
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+        self.elements
+            .iter()
+            .filter(move |element| element.name == name)
+    }
+
+    fn port_kinds(&self, tag: &str) -> Vec<PortKind> {
+        self.children(tag)
+            .map(|port| type_kind(port.attr("type").unwrap_or("")))
+            .collect()
+    }
+
+    /// Finds the source of every target of the edges in the element, or in
+    /// the elements nested in it.
+    fn collect_edges<'a>(
+        &'a self,
+        sources: &mut HashMap<&'a str, &'a str>,
+    ) -> Result<(), ParseError> {
+        for element in &self.elements {
+            if element.name == "edge" {
+                let source = element.required_attr("source")?;
+                let target = element.required_attr("target")?;
+                if sources.insert(target, source).is_some() {
+                    return Err(element.error(format!("`{}` has several sources", target)));
+                }
+            } else {
+                element.collect_edges(sources)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the elements of an XML document, leaving out text, comments,
+/// declarations and processing instructions.
+struct XmlParser<'i> {
+    input: &'i str,
+    chars: Peekable<CharIndices<'i>>,
+    line: usize,
+}
+
+impl<'i> XmlParser<'i> {
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn rest(&mut self) -> &'i str {
+        match self.chars.peek() {
+            Some(&(start, _)) => &self.input[start..],
+            None => "",
+        }
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if !self.rest().starts_with(prefix) {
+            return false;
+        }
+        for _ in prefix.chars() {
+            self.bump();
+        }
+        true
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<(), ParseError> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", prefix)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    /// Skips past `end`.
+    fn skip_past(&mut self, end: &str) -> Result<(), ParseError> {
+        while !self.eat(end) {
+            if self.bump().is_none() {
+                return Err(self.error(format!("expected `{}`", end)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips text and markup other than elements, returning whether an
+    /// element starts next.
+    fn skip_misc(&mut self) -> Result<bool, ParseError> {
+        loop {
+            if self.eat("<!--") {
+                self.skip_past("-->")?;
+            } else if self.eat("<?") {
+                self.skip_past("?>")?;
+            } else if self.eat("<!") {
+                self.skip_past(">")?;
+            } else if self.rest().starts_with('<') {
+                return Ok(true);
+            } else if self.bump().is_none() {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn parse_document(mut self) -> Result<Element, ParseError> {
+        if !self.skip_misc()? {
+            return Err(self.error("expected an element".to_owned()));
+        }
+        let root = self.parse_element()?;
+        if self.skip_misc()? {
+            return Err(self.error("expected a single root element".to_owned()));
+        }
+        Ok(root)
+    }
+
+    fn parse_name(&mut self) -> Result<String, ParseError> {
+        let mut name = String::new();
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !(c.is_alphanumeric() || "_-.:".contains(c)) {
+                break;
+            }
+            name.push(c);
+            self.bump();
+        }
+        if name.is_empty() {
+            return Err(self.error("expected a name".to_owned()));
+        }
+        Ok(name)
+    }
+
+    fn parse_element(&mut self) -> Result<Element, ParseError> {
+        let line = self.line;
+        self.expect("<")?;
+        let name = self.parse_name()?;
+        let mut attrs = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(Element {
+                    line,
+                    name,
+                    attrs,
+                    elements: vec![],
+                });
+            }
+            if self.eat(">") {
+                break;
+            }
+            let attr = self.parse_name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            attrs.push((attr, self.parse_value()?));
+        }
+
+        let mut elements = vec![];
+        loop {
+            if !self.skip_misc()? {
+                return Err(self.error(format!("`<{}>` isn't closed", name)));
+            }
+            if self.eat("</") {
+                let end = self.parse_name()?;
+                if end != name {
+                    return Err(self.error(format!("`<{}>` is closed by `</{}>`", name, end)));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(Element {
+                    line,
+                    name,
+                    attrs,
+                    elements,
+                });
+            }
+            elements.push(self.parse_element()?);
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<String, ParseError> {
+        let quote = match self.bump() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => return Err(self.error("expected a quoted value".to_owned())),
+        };
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => return Ok(value),
+                Some('&') => {
+                    let entity: String = {
+                        let rest = self.rest();
+                        match rest.find(';') {
+                            Some(end) => rest[..end].to_owned(),
+                            None => return Err(self.error("unterminated entity".to_owned())),
+                        }
+                    };
+                    value.push(match &entity[..] {
+                        "amp" => '&',
+                        "lt" => '<',
+                        "gt" => '>',
+                        "quot" => '"',
+                        "apos" => '\'',
+                        _ => return Err(self.error(format!("unknown entity `&{};`", entity))),
+                    });
+                    self.expect(&format!("{};", entity))?;
+                }
+                Some(c) => value.push(c),
+                None => return Err(self.error("unterminated value".to_owned())),
+            }
+        }
+    }
+}
+
+struct JlmReader<'g, 'x, S, F> {
+    ctxt: &'g NodeCtxt<S>,
+    parse_op: F,
+    /// The port each input and result is connected to, by their ids.
+    sources: HashMap<&'x str, &'x str>,
+    /// The origins read so far, by their ids.
+    origins: HashMap<&'x str, OriginId>,
+}
+
+impl<'g, 'x, S, F> JlmReader<'g, 'x, S, F>
+where
+    S: Sig + Eq + Hash + Clone,
+    F: FnMut(&str) -> Option<S>,
+{
+    fn read_nodes(&mut self, region_id: RegionId, region: &'x Element) -> Result<(), ParseError> {
+        for node in region.children("node") {
+            self.read_node(region_id, node)?;
+        }
+        Ok(())
+    }
+
+    fn read_node(&mut self, region_id: RegionId, node: &'x Element) -> Result<(), ParseError> {
+        let regions: Vec<&Element> = node.children("region").collect();
+        let num_ins = node.children("input").count();
+        let head = match node.attr("type").unwrap_or("") {
+            "" => {
+                let name = node.required_attr("name")?;
+                match (self.parse_op)(name) {
+                    Some(op) => Head::Op(op),
+                    None => return Err(node.error(format!("unknown op {:?}", name))),
+                }
+            }
+            "apply" => Head::Apply,
+            "gamma" => Head::Gamma,
+            "theta" => Head::Theta,
+            // The arguments of a lambda are its parameters, followed by its
+            // context variables, and those of an omega its imports.
+            "lambda" => match regions[..] {
+                [body] => Head::Lambda {
+                    params: body.children("argument").count().saturating_sub(num_ins),
+                },
+                _ => return Err(node.error("a lambda has a single region".to_owned())),
+            },
+            "omega" => match regions[..] {
+                [body] => Head::Omega {
+                    imports: body.children("argument").count(),
+                    exports: body.children("result").count(),
+                },
+                _ => return Err(node.error("an omega has a single region".to_owned())),
+            },
+            kind => return Err(node.error(format!("unknown node type `{}`", kind))),
+        };
+
+        let uses = node
+            .children("input")
+            .map(|input| self.source(region_id, input))
+            .collect::<Result<Vec<_>, _>>()?;
+        let node_id = self
+            .ctxt
+            .make_parsed_node(region_id, head, &uses, &node.port_kinds("output"))
+            .map_err(|message| node.error(message))?;
+
+        let is_structural = self.ctxt.node_data(node_id).kind.is_structural();
+        if !is_structural && !regions.is_empty() {
+            return Err(node.error("only structural nodes have regions".to_owned()));
+        }
+        for region in regions {
+            let inner_region = self
+                .ctxt
+                .add_parsed_region(node_id, &region.port_kinds("argument"))
+                .map_err(|message| region.error(message))?;
+            for (index, arg) in region.children("argument").enumerate() {
+                self.define(arg, OriginId::argument(inner_region, index))?;
+            }
+            self.read_nodes(inner_region, region)?;
+            let results = region
+                .children("result")
+                .map(|result| self.source(inner_region, result))
+                .collect::<Result<Vec<_>, _>>()?;
+            self.ctxt
+                .connect_parsed_results(inner_region, &results)
+                .map_err(|message| region.error(message))?;
+        }
+        for (index, output) in node.children("output").enumerate() {
+            self.define(output, OriginId::output(node_id, index))?;
+        }
+        Ok(())
+    }
+
+    /// Finds the origin an input or result is connected to.
+    fn source(&self, region_id: RegionId, port: &'x Element) -> Result<OriginId, ParseError> {
+        let id = port.required_attr("id")?;
+        let source = match self.sources.get(id) {
+            Some(&source) => source,
+            None => return Err(port.error(format!("`{}` isn't connected", id))),
+        };
+        let origin_id = match self.origins.get(source) {
+            Some(&origin_id) => origin_id,
+            None => {
+                return Err(port.error(format!(
+                    "`{}` is connected to `{}`, which comes after it or isn't a port",
+                    id, source
+                )))
+            }
+        };
+        if self.ctxt.origin_region(origin_id) != region_id {
+            return Err(port.error(format!(
+                "`{}` is connected to `{}`, outside its region",
+                id, source
+            )));
+        }
+        Ok(origin_id)
+    }
+
+    fn define(&mut self, port: &'x Element, origin_id: OriginId) -> Result<(), ParseError> {
+        let id = port.required_attr("id")?;
+        if self.origins.insert(id, origin_id).is_some() {
+            return Err(port.error(format!("`{}` is defined twice", id)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, ParseError};
+
+    fn parse_op(op: &str) -> Option<Op> {
+        match op {
+            "Neg" => Some(Op::Neg),
+            "Add" => Some(Op::Add),
+            "St" => Some(Op::St),
+            "Store" => Some(Op::Store),
+            _ if op.starts_with("Lit(") && op.ends_with(')') => {
+                op[4..op.len() - 1].parse().ok().map(Op::Lit)
+            }
+            _ => None,
+        }
+    }
+
+    fn write(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = Vec::new();
+        ncx.write_jlm_xml(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn read(xml: &str) -> Result<NodeCtxt<Op>, ParseError> {
+        NodeCtxt::read_jlm_xml(xml, parse_op)
+    }
+
+    fn print(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = Vec::new();
+        ncx.print_text(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn writing_regions() {
+        let ncx = NodeCtxt::parse_text(
+            r#"%0 = "Lit(1)"()
+%1 = gamma(%0, %0) {
+    (%2) {
+        %3 = "Neg"(%2)
+    } -> (%3)
+    (%4) {
+    } -> (%4)
+}
+"#,
+            parse_op,
+        )
+        .unwrap();
+
+        let xml = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<rvsdg>
+  <region id="r0">
+    <node id="n0" name="Lit(1)" type="">
+      <output id="n0o0" type="val"/>
+    </node>
+    <edge source="n0o0" target="n1i0"/>
+    <edge source="n0o0" target="n1i1"/>
+    <node id="n1" name="" type="gamma">
+      <input id="n1i0" type="val"/>
+      <input id="n1i1" type="val"/>
+      <output id="n1o0" type="val"/>
+      <region id="r1">
+        <argument id="r1a0" type="val"/>
+        <edge source="r1a0" target="n2i0"/>
+        <node id="n2" name="Neg" type="">
+          <input id="n2i0" type="val"/>
+          <output id="n2o0" type="val"/>
+        </node>
+        <edge source="n2o0" target="r1r0"/>
+        <result id="r1r0" type="val"/>
+      </region>
+      <region id="r2">
+        <argument id="r2a0" type="val"/>
+        <edge source="r2a0" target="r2r0"/>
+        <result id="r2r0" type="val"/>
+      </region>
+    </node>
+  </region>
+</rvsdg>
+"#;
+        assert_eq!(xml, write(&ncx));
+        assert_eq!(xml, write(&read(xml).unwrap()));
+    }
+
+    #[test]
+    fn round_trips() {
+        for text in &[
+            r#"%0 = "Lit(0)"()
+!1 = "St"()
+%2, !3 = theta(%0, !1) {
+    (%4, !5) {
+        %6, !7 = gamma(%4, %4, !5) {
+            (%8, !9) {
+                %10 = "Neg"(%8)
+                !11 = "Store"(%10, !9)
+            } -> (%10, !11)
+            (%12, !13) {
+            } -> (%12, !13)
+        }
+    } -> (%4, %6, !7)
+}
+!14 = "Store"(%0, !3)
+"#,
+            r#"%0 = "Lit(0)"()
+%1 = lambda<2>(%0) {
+    (%2, !3, %4) {
+        %5 = "Add"(%2, %4)
+        !6 = "Store"(%5, !3)
+    } -> (%5, !6)
+}
+!7 = "St"()
+%8, !9 = apply(%1, %0, !7)
+"#,
+        ] {
+            let ncx = NodeCtxt::parse_text(text, parse_op).unwrap();
+            let read_back = read(&write(&ncx)).unwrap();
+            assert_eq!(*text, print(&read_back));
+        }
+    }
+
+    #[test]
+    fn reading_jlm_output() {
+        // jlm writes edges after the regions using them, and names the
+        // types of ports as they're printed.
+        let parsed = read(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<rvsdg>
+<region id="0x1">
+<node id="0x2" name="Lit(4)" type="">
+<output id="0x3" type="bit32"/>
+</node>
+<node id="0x4" name="St" type="">
+<output id="0x5" type="mem"/>
+</node>
+<!-- Stores 4. -->
+<node id="0x6" name="Store" type="">
+<input id="0x7" type="bit32"/>
+<input id="0x8" type="mem"/>
+<output id="0x9" type="mem"/>
+</node>
+<edge source="0x3" target="0x7"/>
+<edge source="0x5" target="0x8"/>
+</region>
+</rvsdg>
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            "%0 = \"Lit(4)\"()\n!1 = \"St\"()\n!2 = \"Store\"(%0, !1)\n",
+            print(&parsed)
+        );
+    }
+
+    #[test]
+    fn read_errors() {
+        let error = |xml: &str| read(xml).map(|_| ()).unwrap_err();
+        let lit = r#"<rvsdg><region id="r0">
+<node id="n0" name="Lit(0)" type=""><output id="o" type="val"/></node>
+"#;
+
+        assert_eq!(
+            ParseError {
+                line: 3,
+                message: "unknown op \"Mul\"".to_owned(),
+            },
+            error(&format!(
+                "{}<node id=\"n1\" name=\"Mul\" type=\"\"/>\n</region></rvsdg>",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 4,
+                message: "`i` isn't connected".to_owned(),
+            },
+            error(&format!(
+                "{}<node id=\"n1\" name=\"Neg\" type=\"\">\n<input id=\"i\"/>\n<output \
+                 id=\"p\"/></node>\n</region></rvsdg>",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 3,
+                message: "`i` is connected to `p`, which comes after it or isn't a port".to_owned(),
+            },
+            error(&format!(
+                "{}<node id=\"n1\" name=\"Neg\" type=\"\"><input id=\"i\"/><output \
+                 id=\"p\"/></node>\n<edge source=\"p\" target=\"i\"/>\n</region></rvsdg>",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 4,
+                message: "`r` is connected to `o`, outside its region".to_owned(),
+            },
+            error(&format!(
+                "{}<node id=\"n1\" name=\"\" type=\"gamma\"><input id=\"p\" \
+                 type=\"val\"/>\n<region id=\"r1\"><result id=\"r\" \
+                 type=\"val\"/></region>\n<output id=\"g\" type=\"val\"/></node>\n<edge \
+                 source=\"o\" target=\"p\"/>\n<edge source=\"o\" target=\"r\"/>\n</region></rvsdg>",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 3,
+                message: "`<region>` is closed by `</rvsdg>`".to_owned(),
+            },
+            error(&format!("{}</rvsdg>", lit))
+        );
+        assert_eq!(
+            ParseError {
+                line: 1,
+                message: "expected `<rvsdg>`, found `<graphml>`".to_owned(),
+            },
+            error("<graphml/>")
+        );
+    }
+}
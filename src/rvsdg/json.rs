@@ -0,0 +1,574 @@
+use super::{
+    InnerRegionList, NodeCtxt, NodeData, NodeId, NodeKind, OriginData, OriginId, PortKind,
+    PortPool, RegionData, RegionId, Sig, UserData, UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io::{Read, Write},
+};
+
+/// The version of the JSON schema, bumped whenever it changes in a way
+/// older dumps can't be read with.
+const SCHEMA_VERSION: u32 = 1;
+
+/// A whole graph as it's laid out in JSON.
+///
+/// Nodes and regions are numbered by their position in the dump, which
+/// leaves out removed ones, so ids may differ from those of the dumped
+/// context. The first region is the root region.
+#[derive(Serialize, Deserialize)]
+struct GraphDump<S> {
+    version: u32,
+    nodes: Vec<NodeDump<S>>,
+    regions: Vec<RegionDump>,
+    edges: Vec<EdgeDump>,
+    #[serde(default)]
+    names: Vec<NameDump>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeDump<S> {
+    region: usize,
+    kind: NodeKind<S>,
+    inputs: Vec<PortKind>,
+    outputs: Vec<PortKind>,
+}
+
+/// A region, owned by `node` unless it's the root region. Its inner regions
+/// are ordered as they appear in the dump.
+#[derive(Serialize, Deserialize)]
+struct RegionDump {
+    node: Option<usize>,
+    args: Vec<ArgDump>,
+    results: Vec<ResultDump>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArgDump {
+    kind: PortKind,
+    source: Option<UserRef>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResultDump {
+    kind: PortKind,
+    sink: Option<OriginRef>,
+}
+
+/// An edge. The edges of an origin are listed in the order of its users.
+#[derive(Serialize, Deserialize)]
+struct EdgeDump {
+    origin: OriginRef,
+    user: UserRef,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NameDump {
+    origin: OriginRef,
+    name: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OriginRef {
+    Out { node: usize, index: usize },
+    Arg { region: usize, index: usize },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UserRef {
+    In { node: usize, index: usize },
+    Res { region: usize, index: usize },
+}
+
+/// Why a graph couldn't be read back from JSON.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The input isn't JSON following the schema.
+    Json(serde_json::Error),
+    /// The dump was made with a schema this version can't read.
+    UnsupportedVersion(u32),
+    /// The first region isn't the root region, or another one is.
+    MisplacedRootRegion,
+    /// A node that isn't in the dump is referred to.
+    UnknownNode(usize),
+    /// A region that isn't in the dump is referred to.
+    UnknownRegion(usize),
+    /// A port that its node or region doesn't have is referred to.
+    UnknownPort(String),
+    /// An edge connects ports of different kinds or in different regions,
+    /// or a user that's already connected.
+    BadEdge(usize),
+    /// The graph was read, but it isn't well-formed.
+    Invalid(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Json(err) => write!(f, "{}", err),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "unsupported schema version {}", version)
+            }
+            LoadError::MisplacedRootRegion => write!(f, "the first region must be the only root"),
+            LoadError::UnknownNode(node) => write!(f, "unknown node {}", node),
+            LoadError::UnknownRegion(region) => write!(f, "unknown region {}", region),
+            LoadError::UnknownPort(port) => write!(f, "unknown port {}", port),
+            LoadError::BadEdge(edge) => write!(f, "edge {} can't be connected", edge),
+            LoadError::Invalid(violations) => write!(f, "malformed graph: {}", violations),
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Writes the graph as JSON, listing its nodes, regions, edges and
+    /// origin names. Removed nodes are left out and frozen regions are
+    /// dumped like any other.
+    pub fn write_json(&self, out: &mut dyn Write) -> serde_json::Result<()>
+    where
+        S: Serialize + Clone,
+    {
+        let node_ids: Vec<NodeId> = (0..self.nodes.len())
+            .map(NodeId::new)
+            .filter(|&node_id| !self.node_data(node_id).removed)
+            .collect();
+        let region_ids: Vec<RegionId> = (0..self.regions.len())
+            .map(RegionId::new)
+            .filter(|&region_id| !self.region_data(region_id).removed)
+            .collect();
+        let node_pos: HashMap<NodeId, usize> = node_ids
+            .iter()
+            .enumerate()
+            .map(|(pos, &node_id)| (node_id, pos))
+            .collect();
+        let region_pos: HashMap<RegionId, usize> = region_ids
+            .iter()
+            .enumerate()
+            .map(|(pos, &region_id)| (region_id, pos))
+            .collect();
+
+        let origin_ref = |origin_id: OriginId| match origin_id {
+            OriginId::Out { node, index } => OriginRef::Out {
+                node: node_pos[&node],
+                index: usize::from(index),
+            },
+            OriginId::Arg { region, index } => OriginRef::Arg {
+                region: region_pos[&region],
+                index: usize::from(index),
+            },
+        };
+        let user_ref = |user_id: UserId| match user_id {
+            UserId::In { node, index } => UserRef::In {
+                node: node_pos[&node],
+                index: usize::from(index),
+            },
+            UserId::Res { region, index } => UserRef::Res {
+                region: region_pos[&region],
+                index: usize::from(index),
+            },
+        };
+
+        let nodes = node_ids
+            .iter()
+            .map(|&node_id| {
+                let node_data = self.node_data(node_id);
+                NodeDump {
+                    region: region_pos[&node_data.outer_region],
+                    kind: node_data.kind.clone(),
+                    inputs: node_data.ins.iter().map(|user| user.kind).collect(),
+                    outputs: node_data.outs.iter().map(|origin| origin.kind).collect(),
+                }
+            })
+            .collect();
+
+        let regions = region_ids
+            .iter()
+            .map(|&region_id| {
+                let region_data = self.region_data(region_id);
+                RegionDump {
+                    node: region_data.node.map(|node_id| node_pos[&node_id]),
+                    args: region_data
+                        .args
+                        .iter()
+                        .map(|arg| ArgDump {
+                            kind: arg.kind,
+                            source: arg.source.map(user_ref),
+                        })
+                        .collect(),
+                    results: region_data
+                        .res
+                        .iter()
+                        .map(|res| ResultDump {
+                            kind: res.kind,
+                            sink: res.sink.map(origin_ref),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        let outputs = node_ids.iter().flat_map(|&node_id| {
+            let num_outs = self.node_data(node_id).outs.len();
+            (0..num_outs).map(move |index| OriginId::output(node_id, index))
+        });
+        let args = region_ids.iter().flat_map(|&region_id| {
+            let num_args = self.region_data(region_id).args.len();
+            (0..num_args).map(move |index| OriginId::argument(region_id, index))
+        });
+        let mut edges = vec![];
+        for origin_id in outputs.chain(args) {
+            for user in self.origin_ref(origin_id).users() {
+                edges.push(EdgeDump {
+                    origin: origin_ref(origin_id),
+                    user: user_ref(user.id()),
+                });
+            }
+        }
+
+        let mut names: Vec<NameDump> = self
+            .origin_names
+            .borrow()
+            .iter()
+            .map(|(&origin_id, name)| NameDump {
+                origin: origin_ref(origin_id),
+                name: name.clone(),
+            })
+            .collect();
+        names.sort_by_key(|name| name.origin);
+
+        let dump = GraphDump {
+            version: SCHEMA_VERSION,
+            nodes,
+            regions,
+            edges,
+            names,
+        };
+        serde_json::to_writer_pretty(out, &dump)
+    }
+
+    /// Reads a graph written by `write_json`.
+    ///
+    /// Nodes get the ids they're numbered by in the dump. Stateless nodes are
+    /// interned as they're read, but equal ones aren't merged, and the graph
+    /// is verified before it's returned.
+    pub fn read_json(input: &mut dyn Read) -> Result<NodeCtxt<S>, LoadError>
+    where
+        S: Sig + Eq + Hash + Clone + DeserializeOwned,
+    {
+        let dump: GraphDump<S> = serde_json::from_reader(input).map_err(LoadError::Json)?;
+        if dump.version != SCHEMA_VERSION {
+            return Err(LoadError::UnsupportedVersion(dump.version));
+        }
+        let is_root = |(pos, region): (usize, &RegionDump)| (pos == 0) == region.node.is_none();
+        if dump.regions.is_empty() || !dump.regions.iter().enumerate().all(is_root) {
+            return Err(LoadError::MisplacedRootRegion);
+        }
+
+        let origin_id = |origin: OriginRef| match origin {
+            OriginRef::Out { node, index } => {
+                let node_dump = dump.nodes.get(node).ok_or(LoadError::UnknownNode(node))?;
+                match node_dump.outputs.get(index) {
+                    Some(&kind) => Ok((OriginId::output(NodeId::new(node), index), kind)),
+                    None => Err(LoadError::UnknownPort(format!("{:?}", origin))),
+                }
+            }
+            OriginRef::Arg { region, index } => {
+                let region_dump = dump
+                    .regions
+                    .get(region)
+                    .ok_or(LoadError::UnknownRegion(region))?;
+                match region_dump.args.get(index) {
+                    Some(arg) => Ok((OriginId::argument(RegionId::new(region), index), arg.kind)),
+                    None => Err(LoadError::UnknownPort(format!("{:?}", origin))),
+                }
+            }
+        };
+        let user_id = |user: UserRef| match user {
+            UserRef::In { node, index } => {
+                let node_dump = dump.nodes.get(node).ok_or(LoadError::UnknownNode(node))?;
+                match node_dump.inputs.get(index) {
+                    Some(&kind) => Ok((UserId::input(NodeId::new(node), index), kind)),
+                    None => Err(LoadError::UnknownPort(format!("{:?}", user))),
+                }
+            }
+            UserRef::Res { region, index } => {
+                let region_dump = dump
+                    .regions
+                    .get(region)
+                    .ok_or(LoadError::UnknownRegion(region))?;
+                match region_dump.results.get(index) {
+                    Some(res) => Ok((UserId::result(RegionId::new(region), index), res.kind)),
+                    None => Err(LoadError::UnknownPort(format!("{:?}", user))),
+                }
+            }
+        };
+
+        let mut regions = Vec::with_capacity(dump.regions.len());
+        for region_dump in &dump.regions {
+            let mut region_data = RegionData::new(None, 0);
+            if let Some(node) = region_dump.node {
+                if node >= dump.nodes.len() {
+                    return Err(LoadError::UnknownNode(node));
+                }
+                region_data.node = Some(NodeId::new(node));
+            }
+            for arg in &region_dump.args {
+                let source = match arg.source {
+                    Some(user) => Some(user_id(user)?.0),
+                    None => None,
+                };
+                region_data.args.push(OriginData {
+                    kind: arg.kind,
+                    source,
+                    ..OriginData::default()
+                });
+            }
+            for res in &region_dump.results {
+                let sink = match res.sink {
+                    Some(origin) => Some(origin_id(origin)?.0),
+                    None => None,
+                };
+                region_data.res.push(UserData {
+                    kind: res.kind,
+                    sink,
+                    ..UserData::default()
+                });
+            }
+            regions.push(region_data);
+        }
+
+        let input_ports = PortPool::new();
+        let output_ports = PortPool::new();
+        let mut nodes = Vec::with_capacity(dump.nodes.len());
+        for (pos, node_dump) in dump.nodes.iter().enumerate() {
+            let outer_region = regions
+                .get_mut(node_dump.region)
+                .ok_or(LoadError::UnknownRegion(node_dump.region))?;
+            outer_region.nodes.push(NodeId::new(pos));
+            nodes.push(NodeData {
+                ins: input_ports.alloc(node_dump.inputs.iter().map(|&kind| UserData {
+                    kind,
+                    ..UserData::default()
+                })),
+                outs: output_ports.alloc(node_dump.outputs.iter().map(|&kind| OriginData {
+                    kind,
+                    ..OriginData::default()
+                })),
+                inner_regions: Default::default(),
+                outer_region: RegionId::new(node_dump.region),
+                kind: node_dump.kind.clone(),
+                removed: false,
+            });
+        }
+
+        // Chains the regions of each node in the order they were dumped in,
+        // like `mk_region_for_node` does as they're made.
+        for pos in 1..regions.len() {
+            let region_id = RegionId::new(pos);
+            let node_data = &nodes[regions[pos].node.unwrap().index()];
+            match node_data.inner_regions.get() {
+                Some(InnerRegionList {
+                    first_region,
+                    last_region,
+                }) => {
+                    regions[last_region.index()]
+                        .next_region
+                        .set(Some(region_id));
+                    regions[pos].prev_region.set(Some(last_region));
+                    regions[pos].sequence_index = regions[last_region.index()].sequence_index + 1;
+                    node_data.inner_regions.set(Some(InnerRegionList {
+                        first_region,
+                        last_region: region_id,
+                    }));
+                }
+                None => node_data.inner_regions.set(Some(InnerRegionList {
+                    first_region: region_id,
+                    last_region: region_id,
+                })),
+            }
+        }
+
+        let ncx = NodeCtxt {
+            nodes: nodes.into_iter().map(RefCell::new).collect(),
+            regions: regions.into_iter().map(RefCell::new).collect(),
+            input_ports,
+            output_ports,
+            ..NodeCtxt::new()
+        };
+
+        for (pos, edge) in dump.edges.iter().enumerate() {
+            let (origin, origin_kind) = origin_id(edge.origin)?;
+            let (user, user_kind) = user_id(edge.user)?;
+            if origin_kind != user_kind {
+                return Err(LoadError::BadEdge(pos));
+            }
+            ncx.try_connect_ports(user, origin)
+                .map_err(|_| LoadError::BadEdge(pos))?;
+        }
+
+        for name in &dump.names {
+            ncx.set_origin_name(origin_id(name.origin)?.0, name.name.clone());
+        }
+
+        for pos in 0..dump.nodes.len() {
+            ncx.register_interned(NodeId::new(pos));
+        }
+
+        ncx.verify()
+            .map_err(|violations| LoadError::Invalid(format!("{:?}", violations)))?;
+        Ok(ncx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LoadError;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeId, NodeKind};
+
+    fn dump(ncx: &NodeCtxt<Op>) -> String {
+        let mut out = vec![];
+        ncx.write_json(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let st = ncx.mk_node(Op::St);
+        ncx.set_origin_name(n1.val_out(0).id(), "x");
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n1.val_out(0));
+        let (st_arg, st_out) = theta.loop_state(st.st_out(0));
+        let gamma = ncx.gamma_builder(x_arg, 2);
+        let args = gamma.entry_var(x_arg);
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let x_next = gamma.exit_var(&[x_neg.val_out(0), args[1]]);
+        gamma.finish();
+        let store = ncx
+            .node_builder_in(theta.body(), Op::Store)
+            .operand(x_arg)
+            .state(st_arg)
+            .finish();
+        theta.set_next(x_arg, x_next);
+        theta.set_next_state(st_arg, store.st_out(0));
+        theta.finish(x_arg);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st_out)
+            .finish();
+
+        let json = dump(&ncx);
+        let loaded = NodeCtxt::<Op>::read_json(&mut json.as_bytes()).unwrap();
+
+        assert_eq!(json, dump(&loaded));
+        assert_eq!(ncx.num_nodes(), loaded.num_nodes());
+        assert_eq!(ncx.num_edges(), loaded.num_edges());
+
+        // Stateless nodes are interned again.
+        let n2 = loaded
+            .node_builder(Op::Neg)
+            .operand(loaded.node_ref(NodeId(0)).val_out(0))
+            .finish();
+        assert_eq!(NodeId(1), n2.id());
+    }
+
+    #[test]
+    fn removed_nodes_are_left_out() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::Lit(1));
+        ncx.node_builder(Op::Neg).operand(n1.val_out(0)).finish();
+        ncx.remove_node(n0.id());
+
+        let loaded = NodeCtxt::<Op>::read_json(&mut dump(&ncx).as_bytes()).unwrap();
+
+        assert_eq!(2, loaded.num_nodes());
+        assert_eq!(NodeKind::Op(Op::Lit(1)), *loaded.node_ref(NodeId(0)).kind());
+        assert_eq!(
+            NodeId(0),
+            loaded
+                .node_ref(NodeId(1))
+                .val_in(0)
+                .origin()
+                .producer()
+                .id()
+        );
+    }
+
+    #[test]
+    fn malformed_dumps() {
+        let ncx = NodeCtxt::new();
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n1.st_out(0))
+            .finish();
+        let json = dump(&ncx);
+
+        let read = |json: &str| NodeCtxt::<Op>::read_json(&mut json.as_bytes());
+
+        match read("{") {
+            Err(LoadError::Json(..)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        match read(&json.replacen("\"version\": 1", "\"version\": 2", 1)) {
+            Err(LoadError::UnsupportedVersion(2)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        // Connects the state input of the store to the literal.
+        let swapped = json.replacen(
+            "\"out\": {\n          \"node\": 1,",
+            "\"out\": {\n          \"node\": 0,",
+            1,
+        );
+        assert_ne!(json, swapped);
+        match read(&swapped) {
+            Err(LoadError::BadEdge(..)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        let dangling = json.replacen("\"node\": 2,", "\"node\": 5,", 1);
+        assert_ne!(json, dangling);
+        match read(&dangling) {
+            Err(LoadError::UnknownNode(5)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn edges_into_sibling_regions() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let n_x = ncx.node_builder_in(gamma.branch(0), Op::Lit(1)).finish();
+        let n_y = ncx.node_builder_in(gamma.branch(1), Op::Lit(2)).finish();
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(1), Op::Neg)
+            .operand(n_y.val_out(0))
+            .finish();
+        gamma.exit_var(&[n_x.val_out(0), n_neg.val_out(0)]);
+        gamma.finish();
+        // Skips the region check, to dump an edge from the first branch into
+        // the second.
+        n_neg.val_in(0).disconnect();
+        ncx.link_user(n_neg.val_in(0).id(), n_x.val_out(0).id());
+
+        match NodeCtxt::<Op>::read_json(&mut dump(&ncx).as_bytes()) {
+            Err(LoadError::BadEdge(..)) => {}
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use super::{
+    DataflowAnalysis, DataflowSolution, Direction, NodeCtxt, NodeId, NodeKind, NodeMap, Origin,
+    OriginId, Sig, UserId,
+};
+use std::collections::HashSet;
+
+/// Which values of the graph are used towards what it exports, as found by
+/// `NodeCtxt::liveness`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Liveness {
+    live: DataflowSolution<bool>,
+    dead_outputs: NodeMap<Vec<usize>>,
+}
+
+impl Liveness {
+    /// Whether the value of `origin_id` is used towards what the graph
+    /// exports.
+    pub fn is_live(&self, origin_id: OriginId) -> bool {
+        self.live.fact(origin_id) == Some(&true)
+    }
+
+    /// The outputs of `node_id` that are dead, if it has more than one
+    /// output, so that passes may drop them from its signature.
+    pub fn dead_outputs(&self, node_id: NodeId) -> &[usize] {
+        self.dead_outputs
+            .get(node_id)
+            .map_or(&[], |outputs| &outputs[..])
+    }
+}
+
+/// Values are live if they're used by the inputs of a node with a live
+/// output, and so forth up to the exports of the graph.
+struct LiveValues;
+
+impl<S: Sig> DataflowAnalysis<S> for LiveValues {
+    type Fact = bool;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn bottom(&self) -> bool {
+        false
+    }
+
+    fn top(&self) -> bool {
+        true
+    }
+
+    fn join(&self, a: &bool, b: &bool) -> bool {
+        *a || *b
+    }
+
+    fn transfer(&self, op: &S, facts: &[bool]) -> Vec<bool> {
+        let sig = op.sig();
+        vec![facts.contains(&true); sig.val_ins + sig.st_ins]
+    }
+}
+
+impl<'g, S> Origin<'g, S> {
+    /// Whether the value of the origin is passed on to any of
+    /// `relative_to`, through the nodes using it and into and out of their
+    /// regions. Only the users of the origin that may end up there are
+    /// visited.
+    pub fn is_live(&self, relative_to: &[UserId]) -> bool {
+        let ctxt = self.ctxt;
+        let mut visited = HashSet::new();
+        let mut worklist = vec![self.id()];
+        while let Some(origin_id) = worklist.pop() {
+            if !visited.insert(origin_id) {
+                continue;
+            }
+            for user in ctxt.origin_ref(origin_id).users() {
+                if relative_to.contains(&user.id()) {
+                    return true;
+                }
+                worklist.extend(ctxt.passed_on_to(user.id()));
+            }
+        }
+        false
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Finds the values used towards the exports of the omega nodes of the
+    /// graph, and the outputs of nodes with several outputs that aren't.
+    ///
+    /// A node's inputs are live when any of its outputs is, so outputs are
+    /// only found dead when nothing uses them, or their users are dead.
+    pub fn liveness(&self) -> Liveness
+    where
+        S: Sig,
+    {
+        let live = self.solve_dataflow(self.root_region(), &LiveValues);
+        let mut dead_outputs = NodeMap::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let node_data = node.borrow();
+            if node_data.removed || node_data.outs.len() < 2 {
+                continue;
+            }
+            let node_id = NodeId::new(index);
+            let dead: Vec<usize> = (0..node_data.outs.len())
+                .filter(|&index| live.fact(OriginId::output(node_id, index)) != Some(&true))
+                .collect();
+            if !dead.is_empty() {
+                dead_outputs.insert(node_id, dead);
+            }
+        }
+        Liveness { live, dead_outputs }
+    }
+
+    /// The origins carrying on what's passed to `user_id`: the outputs of
+    /// its node, or the arguments of their regions it's routed to, or the
+    /// outputs and arguments the results of a region are routed to.
+    fn passed_on_to(&self, user_id: UserId) -> Vec<OriginId> {
+        let (node_id, is_input) = match user_id {
+            UserId::In { node, .. } => (node, true),
+            UserId::Res { region, .. } => match self.region_data(region).node {
+                Some(node) => (node, false),
+                None => return vec![],
+            },
+        };
+        let index = user_id.index();
+        let outputs = (0..self.node_data(node_id).outs.len())
+            .map(|index| OriginId::output(node_id, index))
+            .collect();
+        let regions = self.inner_regions(node_id);
+        match (&self.node_data(node_id).kind, is_input) {
+            // Skips the predicate, which decides every output.
+            (NodeKind::Gamma { .. }, true) if index > 0 => regions
+                .iter()
+                .map(|&branch| OriginId::argument(branch, index - 1))
+                .collect(),
+            (NodeKind::Gamma { .. }, false) => vec![OriginId::output(node_id, index)],
+            (NodeKind::Theta { .. }, true) => vec![OriginId::argument(regions[0], index)],
+            (NodeKind::Theta { .. }, false) if index > 0 => vec![
+                OriginId::output(node_id, index - 1),
+                OriginId::argument(regions[0], index - 1),
+            ],
+            // The predicate of a theta decides every loop variable.
+            (NodeKind::Theta { .. }, false) => {
+                let num_args = self.region_data(regions[0]).args.len();
+                let args = (0..num_args).map(|index| OriginId::argument(regions[0], index));
+                args.chain(outputs).collect()
+            }
+            (NodeKind::Lambda { .. }, true) | (NodeKind::Omega { .. }, true) => regions
+                .iter()
+                .flat_map(|&region_id| {
+                    let num_args = self.region_data(region_id).args.len();
+                    (0..num_args).map(move |index| OriginId::argument(region_id, index))
+                })
+                .collect(),
+            _ => outputs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, OriginId, RegionSigS, UserId};
+
+    #[test]
+    fn live_values_of_exports() {
+        let ncx = NodeCtxt::new();
+
+        let omega = ncx.mk_node_with(
+            NodeKind::Omega {
+                imports: 0,
+                exports: 2,
+            },
+            &[],
+        );
+        let region = ncx.mk_region_for_node(
+            omega,
+            RegionSigS {
+                val_res: 2,
+                ..RegionSigS::default()
+            },
+        );
+        let n_one = ncx.mk_node_in(region, Op::Lit(1));
+        let n_two = ncx.mk_node_in(region, Op::Lit(2));
+        let n_dead = ncx
+            .node_builder(Op::Add)
+            .operand(n_one.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+        let n_split = ncx
+            .node_builder(Op::Unpack)
+            .operand(n_two.val_out(0))
+            .finish();
+
+        let gamma = ncx.gamma_builder(n_one.val_out(0), 2);
+        let ones = gamma.entry_var(n_one.val_out(0));
+        let splits = gamma.entry_var(n_split.val_out(0));
+        let exported = gamma.exit_var(&[ones[0], splits[1]]);
+        let unused = gamma.exit_var(&[splits[0], ones[1]]);
+        let gamma = gamma.finish();
+        ncx.connect_ports(UserId::result(region, 0), exported.id());
+        ncx.connect_ports(UserId::result(region, 1), n_one.val_out(0).id());
+
+        let liveness = ncx.liveness();
+        assert!(liveness.is_live(exported.id()));
+        assert!(!liveness.is_live(unused.id()));
+        assert!(!liveness.is_live(n_dead.val_out(0).id()));
+        assert!(liveness.is_live(n_split.val_out(0).id()));
+        assert!(!liveness.is_live(n_split.val_out(1).id()));
+        assert!(liveness.is_live(splits[1].id()));
+        assert!(!liveness.is_live(splits[0].id()));
+        assert_eq!(&[1], liveness.dead_outputs(gamma.id()));
+        assert_eq!(&[1], liveness.dead_outputs(n_split.id()));
+        assert!(liveness.dead_outputs(n_dead.id()).is_empty());
+
+        // Relative to the first export only, the second one isn't needed.
+        let first = [UserId::result(region, 0)];
+        let origin = |origin_id: OriginId| ncx.origin_ref(origin_id);
+        assert!(origin(n_two.val_out(0).id()).is_live(&first));
+        assert!(origin(ones[0].id()).is_live(&first));
+        assert!(!origin(ones[1].id()).is_live(&first));
+        assert!(!origin(n_dead.val_out(0).id()).is_live(&first));
+        // Values may be used by the users they're live relative to.
+        assert!(origin(n_one.val_out(0).id()).is_live(&[UserId::input(n_dead.id(), 0)]));
+    }
+
+    #[test]
+    fn live_across_iterations() {
+        let ncx = NodeCtxt::new();
+
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x, _) = theta.loop_var(n_one.val_out(0));
+        let (y, y_out) = theta.loop_var(n_one.val_out(0));
+        let n_sum = ncx.node_builder(Op::Add).operand(x).operand(x).finish();
+        // `x` only reaches the output of `y` in the next iteration.
+        theta.set_next(y, n_sum.val_out(0));
+        let n_pred = ncx.mk_node_in(theta.body(), Op::Lit(1));
+        theta.finish(n_pred.val_out(0));
+        let n_use = ncx
+            .node_builder(Op::Add)
+            .operand(y_out)
+            .operand(y_out)
+            .finish();
+
+        let target = [UserId::input(n_use.id(), 0)];
+        assert!(ncx.origin_ref(x.id()).is_live(&target));
+        assert!(ncx.origin_ref(n_one.val_out(0).id()).is_live(&target));
+        assert!(!ncx.origin_ref(n_use.val_out(0).id()).is_live(&target));
+    }
+}
@@ -0,0 +1,380 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, UserId};
+use std::hash::Hash;
+
+/// How an op accesses memory, by the indices of its value operands.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MemoryAccess {
+    /// Reads the value at `address`, producing it as value output 0.
+    Load { address: usize },
+    /// Writes `value` at `address`.
+    Store { address: usize, value: usize },
+}
+
+/// Ops that read or write memory, ordered by the state they take and
+/// produce.
+pub trait MemoryOp {
+    /// The memory access this op makes, if any.
+    fn memory_access(&self) -> Option<MemoryAccess>;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Removes loads whose value is already known from the state chain they
+    /// take: the value stored by a store to the same address, or the value
+    /// read by a load of the same address. Returns how many loads were
+    /// removed.
+    ///
+    /// Loads of other addresses on the way back are skipped, as they don't
+    /// change memory. Any other node on the chain, including a store to a
+    /// different address that may alias, ends the search. Addresses are the
+    /// same only if they come from the same origin.
+    pub fn forward_loads(&self) -> usize
+    where
+        S: MemoryOp + Eq + Hash + Clone,
+    {
+        let mut num_removed = 0;
+        let num_nodes = self.nodes.len();
+        for node_id in (0..num_nodes).map(NodeId::new) {
+            let address = match self.memory_access_of(node_id) {
+                Some(MemoryAccess::Load { address }) => self.operand(node_id, address),
+                _ => continue,
+            };
+            let known = address.and_then(|address| self.known_value(node_id, address));
+            if let Some(value) = known {
+                self.remove_load(node_id, value);
+                num_removed += 1;
+            }
+        }
+        num_removed
+    }
+
+    /// Removes stores whose state is only taken by a later store to the same
+    /// address, which overwrites the value before anything can read it.
+    /// Returns how many stores were removed.
+    pub fn remove_dead_stores(&self) -> usize
+    where
+        S: MemoryOp + Eq + Hash + Clone,
+    {
+        let mut num_removed = 0;
+        let num_nodes = self.nodes.len();
+        for node_id in (0..num_nodes).map(NodeId::new) {
+            if self.is_overwritten(node_id) {
+                self.bypass_state(node_id);
+                self.remove_node(node_id);
+                num_removed += 1;
+            }
+        }
+        num_removed
+    }
+
+    fn is_overwritten(&self, store: NodeId) -> bool
+    where
+        S: MemoryOp,
+    {
+        let address = match self.memory_access_of(store) {
+            Some(MemoryAccess::Store { address, .. }) => self.operand(store, address),
+            _ => return false,
+        };
+        let index = match (address, self.first_output(store, PortKind::St)) {
+            (Some(_), Some(index)) => index,
+            _ => return false,
+        };
+        let state = OriginId::output(store, index);
+        let users = self.origin_data(state).users.get();
+        let user = match users {
+            Some(users) if users.first == users.last => users.first,
+            _ => return false,
+        };
+        let next = match user {
+            UserId::In { node, .. } => node,
+            UserId::Res { .. } => return false,
+        };
+        match self.memory_access_of(next) {
+            Some(MemoryAccess::Store {
+                address: next_address,
+                ..
+            }) => self.operand(next, next_address) == address,
+            _ => false,
+        }
+    }
+
+    /// The value a load of `address` would read, found by walking back the
+    /// state chain of `load`.
+    fn known_value(&self, load: NodeId, address: OriginId) -> Option<OriginId>
+    where
+        S: MemoryOp,
+    {
+        let mut state = self.state_operand(load)?;
+        loop {
+            let node_id = match state {
+                OriginId::Out { node, .. } => node,
+                OriginId::Arg { .. } => return None,
+            };
+            match self.memory_access_of(node_id)? {
+                MemoryAccess::Store {
+                    address: store_address,
+                    value,
+                } => {
+                    if self.operand(node_id, store_address)? != address {
+                        return None;
+                    }
+                    return self.operand(node_id, value);
+                }
+                MemoryAccess::Load {
+                    address: load_address,
+                } => {
+                    if self.operand(node_id, load_address)? == address {
+                        return Some(OriginId::output(
+                            node_id,
+                            self.first_output(node_id, PortKind::Val)?,
+                        ));
+                    }
+                    state = self.state_operand(node_id)?;
+                }
+            }
+        }
+    }
+
+    /// Diverts the users of a load to `value` and to the state it took, then
+    /// removes it.
+    fn remove_load(&self, load: NodeId, value: OriginId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        if let Some(index) = self.first_output(load, PortKind::Val) {
+            self.replace_all_users(OriginId::output(load, index), value);
+        }
+        self.bypass_state(load);
+        self.remove_node(load);
+    }
+
+    /// Makes the users of the state `node_id` produces take the state it was
+    /// given instead.
+    fn bypass_state(&self, node_id: NodeId)
+    where
+        S: Eq + Hash + Clone,
+    {
+        let state_output = self.first_output(node_id, PortKind::St);
+        if let (Some(index), Some(state)) = (state_output, self.state_operand(node_id)) {
+            self.replace_all_users(OriginId::output(node_id, index), state);
+        }
+    }
+
+    pub(crate) fn memory_access_of(&self, node_id: NodeId) -> Option<MemoryAccess>
+    where
+        S: MemoryOp,
+    {
+        let node_data = self.node_data(node_id);
+        match node_data.kind {
+            NodeKind::Op(ref op) if !node_data.removed => op.memory_access(),
+            _ => None,
+        }
+    }
+
+    /// The origin of value operand `port` of `node_id`.
+    fn operand(&self, node_id: NodeId, port: usize) -> Option<OriginId> {
+        let index = self
+            .node_data(node_id)
+            .ins
+            .iter()
+            .enumerate()
+            .filter(|(_, user)| user.kind == PortKind::Val)
+            .nth(port)?
+            .0;
+        self.user_data(UserId::input(node_id, index)).origin.get()
+    }
+
+    /// The origin of the first state operand of `node_id`.
+    pub(crate) fn state_operand(&self, node_id: NodeId) -> Option<OriginId> {
+        let node_data = self.node_data(node_id);
+        let user = node_data
+            .ins
+            .iter()
+            .find(|user| user.kind == PortKind::St)?;
+        user.origin.get()
+    }
+
+    /// The index of the first output of `node_id` of the given kind.
+    pub(crate) fn first_output(&self, node_id: NodeId, kind: PortKind) -> Option<usize> {
+        self.node_data(node_id)
+            .outs
+            .iter()
+            .position(|out| out.kind == kind)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn forwarding_stored_values() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_value = ncx.mk_node(Op::Lit(42));
+        let n_store = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_value.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_load = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_store.st_out(0))
+            .finish();
+        let n_user = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_load.val_out(0))
+            .state(n_load.st_out(0))
+            .finish();
+
+        assert_eq!(1, ncx.forward_loads());
+        assert_eq!(n_user.val_in(1).origin(), n_value.val_out(0));
+        assert_eq!(n_user.st_in(0).origin(), n_store.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn repeated_loads_past_other_addresses() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_other = ncx.mk_node(Op::Lit(1));
+        let n_first = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_between = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_other.val_out(0))
+            .state(n_first.st_out(0))
+            .finish();
+        let n_again = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_between.st_out(0))
+            .finish();
+        let n_user = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_again.val_out(0))
+            .operand(n_between.val_out(0))
+            .state(n_again.st_out(0))
+            .finish();
+
+        assert_eq!(1, ncx.forward_loads());
+        assert_eq!(n_user.val_in(0).origin(), n_first.val_out(0));
+        assert_eq!(n_user.st_in(0).origin(), n_between.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn clobbered_addresses_are_loaded_again() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_other = ncx.mk_node(Op::Lit(1));
+        let n_first = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        // The other address may alias the loaded one.
+        let n_store = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_other.val_out(0))
+            .operand(n_first.val_out(0))
+            .state(n_first.st_out(0))
+            .finish();
+        let n_call = ncx
+            .node_builder(Op::Call)
+            .operand(n_other.val_out(0))
+            .state(n_first.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_store.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_call.st_out(0))
+            .finish();
+
+        assert_eq!(0, ncx.forward_loads());
+    }
+
+    #[test]
+    fn overwritten_stores() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_first = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_second = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(n_first.st_out(0))
+            .finish();
+        let n_last = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(n_second.st_out(0))
+            .finish();
+
+        assert_eq!(2, ncx.remove_dead_stores());
+        assert_eq!(n_last.st_in(0).origin(), st.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn stores_read_in_between_stay() {
+        let ncx = NodeCtxt::new();
+
+        let st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_other = ncx.mk_node(Op::Lit(1));
+        let n_stored = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        let n_load = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(n_stored.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_load.val_out(0))
+            .state(n_load.st_out(0))
+            .finish();
+
+        // Stores to other addresses don't overwrite it.
+        let n_kept = ncx
+            .node_builder(Op::Write(0))
+            .operand(n_addr.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(st.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Write(0))
+            .operand(n_other.val_out(0))
+            .operand(n_addr.val_out(0))
+            .state(n_kept.st_out(0))
+            .finish();
+
+        assert_eq!(0, ncx.remove_dead_stores());
+    }
+}
@@ -0,0 +1,253 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId};
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+};
+
+impl<S> NodeCtxt<S> {
+    /// Writes the graph as a Mermaid flowchart, which GitHub renders in
+    /// issues and pull requests.
+    ///
+    /// Structural nodes are subgraphs holding a subgraph for each of their
+    /// regions, in which arguments and results are drawn as nodes of their
+    /// own. Edges into and out of structural nodes join their subgraphs.
+    /// Value edges are solid and state edges dotted.
+    pub fn print_mermaid(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        writeln!(out, "flowchart TB")?;
+        self.print_mermaid_nodes(out, self.root_region(), 1)
+    }
+
+    /// Prints the nodes of a region, each followed by the edges into it.
+    fn print_mermaid_nodes(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let indent = "    ".repeat(depth);
+        for node_id in self.region_nodes(region_id) {
+            let label = escape_mermaid(&self.mermaid_label(node_id));
+            let inner_regions = self.inner_regions(node_id);
+            if inner_regions.is_empty() {
+                writeln!(out, r#"{}n{}["{}"]"#, indent, node_id.0, label)?;
+            } else {
+                writeln!(out, r#"{}subgraph n{} ["{}"]"#, indent, node_id.0, label)?;
+                for inner_region in inner_regions {
+                    self.print_mermaid_region(out, inner_region, depth + 1)?;
+                }
+                writeln!(out, "{}end", indent)?;
+            }
+
+            let ins: Vec<(PortKind, Option<OriginId>)> = self
+                .node_data(node_id)
+                .ins
+                .iter()
+                .map(|user| (user.kind, user.origin.get()))
+                .collect();
+            for (kind, origin) in ins {
+                if let Some(origin_id) = origin {
+                    let target = format!("n{}", node_id.0);
+                    write_mermaid_edge(out, &indent, origin_id, &target, kind)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints a region of a structural node as a subgraph, with its
+    /// arguments first and its results last.
+    fn print_mermaid_region(
+        &self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let indent = "    ".repeat(depth);
+        let inner_indent = "    ".repeat(depth + 1);
+        writeln!(
+            out,
+            r#"{}subgraph r{} ["region {}"]"#,
+            indent, region_id.0, region_id.0
+        )?;
+
+        let num_args = self.region_data(region_id).args.len();
+        for index in 0..num_args {
+            let label = self
+                .origin_name(OriginId::argument(region_id, index))
+                .unwrap_or_else(|| format!("a{}", index));
+            writeln!(
+                out,
+                r#"{}r{}a{}(["{}"])"#,
+                inner_indent,
+                region_id.0,
+                index,
+                escape_mermaid(&label)
+            )?;
+        }
+
+        self.print_mermaid_nodes(out, region_id, depth + 1)?;
+
+        let res: Vec<(PortKind, Option<OriginId>)> = self
+            .region_data(region_id)
+            .res
+            .iter()
+            .map(|user| (user.kind, user.origin.get()))
+            .collect();
+        for (index, &(kind, origin)) in res.iter().enumerate() {
+            let target = format!("r{}r{}", region_id.0, index);
+            writeln!(out, r#"{}{}(["r{}"])"#, inner_indent, target, index)?;
+            if let Some(origin_id) = origin {
+                write_mermaid_edge(out, &inner_indent, origin_id, &target, kind)?;
+            }
+        }
+
+        writeln!(out, "{}end", indent)
+    }
+
+    /// The kind of a node, after its name if it has one and followed by its
+    /// span if it has one.
+    fn mermaid_label(&self, node_id: NodeId) -> String
+    where
+        S: Debug,
+    {
+        let kind = match self.node_data(node_id).kind {
+            NodeKind::Op(ref op) => format!("{:?}", op),
+            NodeKind::Apply { .. } => "Apply".to_owned(),
+            NodeKind::Gamma { .. } => "Gamma".to_owned(),
+            NodeKind::Theta { .. } => "Theta".to_owned(),
+            NodeKind::Lambda { .. } => "Lambda".to_owned(),
+            NodeKind::Omega { .. } => "Omega".to_owned(),
+        };
+        let kind = match self.node_span(node_id) {
+            Some(span) => format!("{} @{}", kind, span),
+            None => kind,
+        };
+        match self.node_name(node_id) {
+            Some(name) => format!("{}: {}", name, kind),
+            None => kind,
+        }
+    }
+}
+
+fn write_mermaid_edge(
+    out: &mut dyn Write,
+    indent: &str,
+    origin_id: OriginId,
+    target: &str,
+    kind: PortKind,
+) -> io::Result<()> {
+    let source = match origin_id {
+        OriginId::Out { node, .. } => format!("n{}", node.0),
+        OriginId::Arg { region, index } => format!("r{}a{}", region.0, index),
+    };
+    let arrow = match kind {
+        PortKind::Val => "-->",
+        PortKind::St => "-.->",
+    };
+    writeln!(out, "{}{} {} {}", indent, source, arrow, target)
+}
+
+/// Escapes the characters of a quoted label that Mermaid would read as
+/// markup, with its entity codes.
+fn escape_mermaid(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("#quot;"),
+            '#' => escaped.push_str("#35;"),
+            '<' => escaped.push_str("#lt;"),
+            '>' => escaped.push_str("#gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, Span};
+
+    fn print(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = Vec::new();
+        ncx.print_mermaid(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn printing_structural_nodes() {
+        let ncx = NodeCtxt::new();
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let n_st = ncx.mk_node(Op::St);
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, x_out) = theta.loop_var(n_x.val_out(0));
+        let (st_arg, st_out) = theta.loop_state(n_st.st_out(0));
+        let n_neg = ncx
+            .node_builder_in(theta.body(), Op::Neg)
+            .operand(x_arg)
+            .finish();
+        let n_store = ncx
+            .node_builder_in(theta.body(), Op::Store)
+            .operand(n_neg.val_out(0))
+            .state(st_arg)
+            .finish();
+        theta.set_next(x_arg, n_neg.val_out(0));
+        theta.set_next_state(st_arg, n_store.st_out(0));
+        theta.finish(x_arg);
+        ncx.node_builder(Op::Store)
+            .operand(x_out)
+            .state(st_out)
+            .finish();
+
+        assert_eq!(
+            r#"flowchart TB
+    n0["Lit(1)"]
+    n1["St"]
+    subgraph n2 ["Theta"]
+        subgraph r1 ["region 1"]
+            r1a0(["a0"])
+            r1a1(["a1"])
+            n3["Neg"]
+            r1a0 --> n3
+            n4["Store"]
+            n3 --> n4
+            r1a1 -.-> n4
+            r1r0(["r0"])
+            r1a0 --> r1r0
+            r1r1(["r1"])
+            n3 --> r1r1
+            r1r2(["r2"])
+            n4 -.-> r1r2
+        end
+    end
+    n0 --> n2
+    n1 -.-> n2
+    n5["Store"]
+    n2 --> n5
+    n2 -.-> n5
+"#,
+            print(&ncx)
+        );
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        let ncx = NodeCtxt::new();
+        let n_named = ncx.mk_node(Op::Lit(1));
+        n_named.set_name("\"<#1>\"");
+        ncx.attach_span(n_named.id(), Span { line: 3, column: 5 });
+
+        assert_eq!(
+            "flowchart TB\n    n0[\"#quot;#lt;#35;1#gt;#quot;: Lit(1) @3:5\"]\n",
+            print(&ncx)
+        );
+    }
+}
@@ -0,0 +1,1100 @@
+//! The generic textual form of MLIR, with an `rvsdg` dialect standing for
+//! the nodes of graphs, so that they can be exchanged with tools built on
+//! MLIR, given `--allow-unregistered-dialect`.
+//!
+//! Values are of type `!rvsdg.val` and states of type `!rvsdg.state`.
+//! Structural nodes hold their regions as a single block each, whose
+//! arguments are those of the region and which ends in `rvsdg.yield` with
+//! its results:
+//!
+//! ```text
+//! %0 = "rvsdg.op"() {op = "Lit(0)"} : () -> !rvsdg.val
+//! %1 = "rvsdg.op"() {op = "St"} : () -> !rvsdg.state
+//! %2, %3 = "rvsdg.gamma"(%0, %0, %1) ({
+//! ^bb0(%4: !rvsdg.val, %5: !rvsdg.state):
+//!   %6 = "rvsdg.op"(%4) {op = "Neg"} : (!rvsdg.val) -> !rvsdg.val
+//!   "rvsdg.yield"(%6, %5) : (!rvsdg.val, !rvsdg.state) -> ()
+//! }, {
+//! ^bb0(%7: !rvsdg.val, %8: !rvsdg.state):
+//!   "rvsdg.yield"(%7, %8) : (!rvsdg.val, !rvsdg.state) -> ()
+//! }) : (!rvsdg.val, !rvsdg.val, !rvsdg.state) -> (!rvsdg.val, !rvsdg.state)
+//! ```
+//!
+//! Ops are `rvsdg.op` with the `Debug` form of `S` as their `op` attribute.
+//! The others are `rvsdg.gamma`, `rvsdg.theta`, whose body yields the loop
+//! predicate first, `rvsdg.lambda` with a `params` attribute, `rvsdg.omega`
+//! with `imports` and `exports` attributes, and `rvsdg.apply`. Named nodes
+//! have a `name` attribute, and spans are written as locations.
+
+use super::{
+    text::{is_identifier, quote_op, Head},
+    NodeCtxt, NodeId, NodeKind, OriginId, ParseError, PortKind, RegionId, Sig, Span,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::Hash,
+    io::{self, Write},
+};
+
+impl<S> NodeCtxt<S> {
+    /// Writes the graph in the generic form of MLIR, with the nodes of each
+    /// region ordered so that values are defined before they're used.
+    ///
+    /// Named origins are written with their names, as long as they're
+    /// identifiers that aren't taken already. Unconnected inputs are
+    /// written as `<<NULL VALUE>>`, as MLIR writes them.
+    pub fn print_mlir(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let mut printer = MlirPrinter {
+            ctxt: self,
+            names: HashMap::new(),
+            taken: HashSet::new(),
+            next_number: 0,
+        };
+        printer.print_body(out, self.root_region(), 0)
+    }
+
+    /// Parses a graph from the generic form of MLIR, as `print_mlir` writes
+    /// it, parsing ops with `parse_op`. The root region may be wrapped in a
+    /// module.
+    ///
+    /// Nodes are made in the order they're written in, and stateless ones
+    /// are interned, but equal ones aren't merged. The graph is verified
+    /// before it's returned.
+    pub fn parse_mlir<F>(input: &str, parse_op: F) -> Result<NodeCtxt<S>, ParseError>
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(&str) -> Option<S>,
+    {
+        let mut parser = MlirParser {
+            tokens: tokenize(input)?,
+            pos: 0,
+        };
+        let stmts = parser.parse_top()?;
+
+        let ncx = NodeCtxt::new();
+        let mut builder = MlirBuilder {
+            ctxt: &ncx,
+            parse_op,
+            origins: HashMap::new(),
+        };
+        let root = ncx.root_region();
+        if let Some(stmt) = builder.build_body(root, stmts)? {
+            return Err(ParseError {
+                line: stmt.line,
+                message: "`rvsdg.yield` outside of a region".to_owned(),
+            });
+        }
+
+        let last_line = input.lines().count().max(1);
+        ncx.verify().map_err(|violations| ParseError {
+            line: last_line,
+            message: format!("malformed graph: {:?}", violations),
+        })?;
+        Ok(ncx)
+    }
+}
+
+const NULL_VALUE: &str = "<<NULL VALUE>>";
+
+fn type_name(kind: PortKind) -> &'static str {
+    match kind {
+        PortKind::Val => "!rvsdg.val",
+        PortKind::St => "!rvsdg.state",
+    }
+}
+
+/// Writes the types of an op's operands and results, with a single result
+/// left unparenthesized.
+fn function_type(ins: &[PortKind], outs: &[PortKind]) -> String {
+    let list = |kinds: &[PortKind]| {
+        kinds
+            .iter()
+            .map(|&kind| type_name(kind))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match outs {
+        [out] => format!("({}) -> {}", list(ins), type_name(*out)),
+        _ => format!("({}) -> ({})", list(ins), list(outs)),
+    }
+}
+
+struct MlirPrinter<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    names: HashMap<OriginId, String>,
+    taken: HashSet<String>,
+    next_number: usize,
+}
+
+impl<'g, S: Debug> MlirPrinter<'g, S> {
+    fn print_body(
+        &mut self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()> {
+        let indent = "  ".repeat(depth);
+        for node_id in self.ctxt.region_topo_order(region_id) {
+            let (in_kinds, origins): (Vec<PortKind>, Vec<Option<OriginId>>) = self
+                .ctxt
+                .node_data(node_id)
+                .ins
+                .iter()
+                .map(|user| (user.kind, user.origin.get()))
+                .unzip();
+            let out_kinds: Vec<PortKind> = self
+                .ctxt
+                .node_data(node_id)
+                .outs
+                .iter()
+                .map(|origin| origin.kind)
+                .collect();
+            let defs: Vec<String> = (0..out_kinds.len())
+                .map(|index| self.define(OriginId::output(node_id, index)))
+                .collect();
+
+            write!(out, "{}", indent)?;
+            if !defs.is_empty() {
+                write!(out, "{} = ", defs.join(", "))?;
+            }
+            let mut attrs = vec![];
+            let op = match self.ctxt.node_data(node_id).kind {
+                NodeKind::Op(ref op) => {
+                    attrs.push(format!("op = {}", quote_op(&format!("{:?}", op))));
+                    "rvsdg.op"
+                }
+                NodeKind::Apply { .. } => "rvsdg.apply",
+                NodeKind::Gamma { .. } => "rvsdg.gamma",
+                NodeKind::Theta { .. } => "rvsdg.theta",
+                NodeKind::Lambda { params, .. } => {
+                    attrs.push(format!("params = {} : i64", params));
+                    "rvsdg.lambda"
+                }
+                NodeKind::Omega { imports, exports } => {
+                    attrs.push(format!("imports = {} : i64", imports));
+                    attrs.push(format!("exports = {} : i64", exports));
+                    "rvsdg.omega"
+                }
+            };
+            if let Some(name) = self.ctxt.node_name(node_id) {
+                attrs.push(format!("name = {}", quote_op(&name)));
+            }
+            write!(out, "\"{}\"({})", op, self.uses(&origins))?;
+
+            let inner_regions = self.ctxt.inner_regions(node_id);
+            if !inner_regions.is_empty() {
+                write!(out, " (")?;
+                for (index, &inner_region) in inner_regions.iter().enumerate() {
+                    if index > 0 {
+                        write!(out, ", ")?;
+                    }
+                    self.print_region(out, inner_region, depth + 1)?;
+                }
+                write!(out, ")")?;
+            }
+            if !attrs.is_empty() {
+                write!(out, " {{{}}}", attrs.join(", "))?;
+            }
+            write!(out, " : {}", function_type(&in_kinds, &out_kinds))?;
+            if let Some(span) = self.ctxt.node_span(node_id) {
+                write!(out, " loc(\"\":{}:{})", span.line, span.column)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a region as a braced block, whose arguments are those of the
+    /// region, ending in a yield of its results.
+    fn print_region(
+        &mut self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()> {
+        let outer_indent = "  ".repeat(depth - 1);
+        let indent = "  ".repeat(depth);
+        let num_args = self.ctxt.region_data(region_id).args.len();
+        let args: Vec<String> = (0..num_args)
+            .map(|index| {
+                let origin_id = OriginId::argument(region_id, index);
+                let kind = self.ctxt.origin_data(origin_id).kind;
+                format!("{}: {}", self.define(origin_id), type_name(kind))
+            })
+            .collect();
+        writeln!(out, "{{")?;
+        if args.is_empty() {
+            writeln!(out, "{}^bb0:", outer_indent)?;
+        } else {
+            writeln!(out, "{}^bb0({}):", outer_indent, args.join(", "))?;
+        }
+        self.print_body(out, region_id, depth)?;
+
+        let (kinds, origins): (Vec<PortKind>, Vec<Option<OriginId>>) = self
+            .ctxt
+            .region_data(region_id)
+            .res
+            .iter()
+            .map(|user| (user.kind, user.origin.get()))
+            .unzip();
+        writeln!(
+            out,
+            "{}\"rvsdg.yield\"({}) : {}",
+            indent,
+            self.uses(&origins),
+            function_type(&kinds, &[])
+        )?;
+        write!(out, "{}}}", outer_indent)
+    }
+
+    /// Names an origin after its debug name, or else after the next number
+    /// that isn't taken.
+    fn define(&mut self, origin_id: OriginId) -> String {
+        let name = match self.ctxt.origin_name(origin_id) {
+            Some(name) if is_identifier(&name) && !self.taken.contains(&name) => name,
+            _ => loop {
+                let number = self.next_number.to_string();
+                self.next_number += 1;
+                if !self.taken.contains(&number) {
+                    break number;
+                }
+            },
+        };
+        self.taken.insert(name.clone());
+        let name = format!("%{}", name);
+        self.names.insert(origin_id, name.clone());
+        name
+    }
+
+    fn uses(&self, origins: &[Option<OriginId>]) -> String {
+        origins
+            .iter()
+            .map(|origin| match origin {
+                Some(origin_id) => self.names.get(origin_id).map_or(NULL_VALUE, String::as_str),
+                None => NULL_VALUE,
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Tok {
+    /// The name of a value, without its `%`.
+    Value(String),
+    /// The name of a block, without its `^`.
+    Block(String),
+    /// The name of a type, along with its `!`.
+    Type(String),
+    Str(String),
+    Ident(String),
+    Number(usize),
+    Punct(&'static str),
+}
+
+impl std::fmt::Display for Tok {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Tok::Value(name) => write!(f, "`%{}`", name),
+            Tok::Block(name) => write!(f, "`^{}`", name),
+            Tok::Type(name) | Tok::Ident(name) => write!(f, "`{}`", name),
+            Tok::Str(string) => write!(f, "string {:?}", string),
+            Tok::Number(number) => write!(f, "`{}`", number),
+            Tok::Punct(punct) => write!(f, "`{}`", punct),
+        }
+    }
+}
+
+struct Token {
+    tok: Tok,
+    line: usize,
+}
+
+fn is_suffix_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "$._-".contains(c)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    for (line_index, text) in input.lines().enumerate() {
+        let line = line_index + 1;
+        let error = |message: String| ParseError { line, message };
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let tok = match c {
+                _ if c.is_whitespace() => continue,
+                '/' if text[start..].starts_with("//") => break,
+                '%' | '^' | '!' => {
+                    let mut name = String::new();
+                    while let Some(&(_, c)) = chars.peek() {
+                        // Results are grouped as `%name:2`, and their
+                        // members used as `%name#1`.
+                        let in_group = c == '#' && !name.is_empty();
+                        if !is_suffix_char(c) && !in_group {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    if name.is_empty() {
+                        return Err(error(format!("expected a name after `{}`", c)));
+                    }
+                    match c {
+                        '%' => Tok::Value(name),
+                        '^' => Tok::Block(name),
+                        _ => Tok::Type(format!("!{}", name)),
+                    }
+                }
+                '"' => {
+                    let mut string = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, 'n')) => string.push('\n'),
+                                Some((_, 't')) => string.push('\t'),
+                                Some((_, c)) => string.push(c),
+                                None => return Err(error("unterminated string".to_owned())),
+                            },
+                            Some((_, c)) => string.push(c),
+                            None => return Err(error("unterminated string".to_owned())),
+                        }
+                    }
+                    Tok::Str(string)
+                }
+                '-' if text[start..].starts_with("->") => {
+                    chars.next();
+                    Tok::Punct("->")
+                }
+                '(' => Tok::Punct("("),
+                ')' => Tok::Punct(")"),
+                '{' => Tok::Punct("{"),
+                '}' => Tok::Punct("}"),
+                ',' => Tok::Punct(","),
+                ':' => Tok::Punct(":"),
+                '=' => Tok::Punct("="),
+                _ if c.is_ascii_digit() => {
+                    let mut digits = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !c.is_ascii_digit() {
+                            break;
+                        }
+                        digits.push(c);
+                        chars.next();
+                    }
+                    match digits.parse() {
+                        Ok(number) => Tok::Number(number),
+                        Err(_) => return Err(error(format!("`{}` is too large", digits))),
+                    }
+                }
+                _ if c.is_ascii_alphabetic() || c == '_' => {
+                    let mut word = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !is_suffix_char(c) {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    Tok::Ident(word)
+                }
+                _ => return Err(error(format!("unexpected character `{}`", c))),
+            };
+            tokens.push(Token { tok, line });
+        }
+    }
+    Ok(tokens)
+}
+
+/// An operation, as it's written.
+struct Stmt {
+    line: usize,
+    results: Vec<String>,
+    op: String,
+    operands: Vec<String>,
+    regions: Vec<Block>,
+    attrs: HashMap<String, Attr>,
+    operand_kinds: Vec<PortKind>,
+    result_kinds: Vec<PortKind>,
+    span: Option<Span>,
+}
+
+enum Attr {
+    Str(String),
+    Int(usize),
+}
+
+/// The only block of a region.
+struct Block {
+    line: usize,
+    args: Vec<(String, PortKind)>,
+    stmts: Vec<Stmt>,
+}
+
+struct MlirParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl MlirParser {
+    fn error(&self, message: String) -> ParseError {
+        let line = match self.tokens.get(self.pos).or_else(|| self.tokens.last()) {
+            Some(token) => token.line,
+            None => 1,
+        };
+        ParseError { line, message }
+    }
+
+    fn line(&self) -> usize {
+        self.error(String::new()).line
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|token| &token.tok)
+    }
+
+    fn next(&mut self) -> Result<Tok, ParseError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.tok.clone())
+            }
+            None => Err(self.error("unexpected end of input".to_owned())),
+        }
+    }
+
+    /// Reports that the token just taken isn't the `expected` one.
+    fn unexpected<T>(&mut self, expected: &str, tok: Tok) -> Result<T, ParseError> {
+        self.pos -= 1;
+        Err(self.error(format!("expected {}, found {}", expected, tok)))
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), ParseError> {
+        match self.next()? {
+            Tok::Punct(found) if found == punct => Ok(()),
+            tok => self.unexpected(&format!("`{}`", punct), tok),
+        }
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        if self.peek() == Some(&Tok::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<usize, ParseError> {
+        match self.next()? {
+            Tok::Number(number) => Ok(number),
+            tok => self.unexpected("a number", tok),
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<PortKind, ParseError> {
+        match self.next()? {
+            Tok::Type(ref name) if name == "!rvsdg.val" => Ok(PortKind::Val),
+            Tok::Type(ref name) if name == "!rvsdg.state" => Ok(PortKind::St),
+            tok => self.unexpected("`!rvsdg.val` or `!rvsdg.state`", tok),
+        }
+    }
+
+    /// Parses a comma separated list with `parse_item` up to `end`,
+    /// consuming it.
+    fn parse_list<T>(
+        &mut self,
+        end: &'static str,
+        mut parse_item: impl FnMut(&mut MlirParser) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let mut items = vec![];
+        if self.eat(end) {
+            return Ok(items);
+        }
+        loop {
+            items.push(parse_item(self)?);
+            if !self.eat(",") {
+                self.expect(end)?;
+                return Ok(items);
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<String, ParseError> {
+        match self.next()? {
+            Tok::Value(name) => Ok(name),
+            tok => self.unexpected("a value", tok),
+        }
+    }
+
+    /// Parses the operations of the file, unwrapping the module they're in
+    /// if there's one.
+    fn parse_top(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        if self.peek() == Some(&Tok::Ident("module".to_owned())) {
+            self.pos += 1;
+            self.expect("{")?;
+            let stmts = self.parse_stmts()?;
+            self.expect("}")?;
+            self.expect_end()?;
+            return Ok(stmts);
+        }
+        let mut stmts = self.parse_stmts()?;
+        self.expect_end()?;
+        if stmts.len() == 1 && stmts[0].op == "builtin.module" {
+            let mut module = stmts.pop().unwrap();
+            return match module.regions.pop() {
+                Some(block) if module.regions.is_empty() => Ok(block.stmts),
+                _ => Err(ParseError {
+                    line: module.line,
+                    message: "a module has a single region".to_owned(),
+                }),
+            };
+        }
+        Ok(stmts)
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        match self.next() {
+            Ok(tok) => self.unexpected("the end of input", tok),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Parses operations up to the end of the enclosing braces or input.
+    fn parse_stmts(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut stmts = vec![];
+        while self.peek().is_some() && self.peek() != Some(&Tok::Punct("}")) {
+            stmts.push(self.parse_stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
+        let mut results = vec![];
+        if let Some(Tok::Value(..)) = self.peek() {
+            loop {
+                let name = self.parse_value()?;
+                if self.eat(":") {
+                    let count = self.parse_number()?;
+                    results.extend((0..count).map(|index| format!("{}#{}", name, index)));
+                } else {
+                    results.push(name);
+                }
+                if !self.eat(",") {
+                    break;
+                }
+            }
+            self.expect("=")?;
+        }
+
+        let line = self.line();
+        let op = match self.next()? {
+            Tok::Str(op) => op,
+            tok => return self.unexpected("an operation in its generic form", tok),
+        };
+        self.expect("(")?;
+        let operands = self.parse_list(")", MlirParser::parse_value)?;
+        let mut regions = vec![];
+        if self.eat("(") {
+            regions = self.parse_list(")", MlirParser::parse_region)?;
+        }
+        let mut attrs = HashMap::new();
+        if self.eat("{") {
+            let parsed = self.parse_list("}", |parser| {
+                let name = match parser.next()? {
+                    Tok::Ident(name) => name,
+                    tok => return parser.unexpected("an attribute", tok),
+                };
+                parser.expect("=")?;
+                let value = match parser.next()? {
+                    Tok::Str(string) => Attr::Str(string),
+                    Tok::Number(number) => {
+                        if parser.eat(":") {
+                            parser.next()?;
+                        }
+                        Attr::Int(number)
+                    }
+                    tok => return parser.unexpected("a string or an integer", tok),
+                };
+                Ok((name, value))
+            })?;
+            attrs.extend(parsed);
+        }
+
+        self.expect(":")?;
+        self.expect("(")?;
+        let operand_kinds = self.parse_list(")", MlirParser::parse_type)?;
+        self.expect("->")?;
+        let result_kinds = if self.eat("(") {
+            self.parse_list(")", MlirParser::parse_type)?
+        } else {
+            vec![self.parse_type()?]
+        };
+
+        let mut span = None;
+        if self.peek() == Some(&Tok::Ident("loc".to_owned())) {
+            self.pos += 1;
+            self.expect("(")?;
+            match self.next()? {
+                Tok::Str(..) => {
+                    self.expect(":")?;
+                    let line = self.parse_number()?;
+                    self.expect(":")?;
+                    let column = self.parse_number()?;
+                    span = Some(Span { line, column });
+                }
+                Tok::Ident(ref name) if name == "unknown" => {}
+                tok => return self.unexpected("a file location", tok),
+            }
+            self.expect(")")?;
+        }
+
+        Ok(Stmt {
+            line,
+            results,
+            op,
+            operands,
+            regions,
+            attrs,
+            operand_kinds,
+            result_kinds,
+            span,
+        })
+    }
+
+    fn parse_region(&mut self) -> Result<Block, ParseError> {
+        let line = self.line();
+        self.expect("{")?;
+        let mut args = vec![];
+        if let Some(Tok::Block(..)) = self.peek() {
+            self.pos += 1;
+            if self.eat("(") {
+                args = self.parse_list(")", |parser| {
+                    let name = parser.parse_value()?;
+                    parser.expect(":")?;
+                    Ok((name, parser.parse_type()?))
+                })?;
+            }
+            self.expect(":")?;
+        }
+        let stmts = self.parse_stmts()?;
+        self.expect("}")?;
+        Ok(Block { line, args, stmts })
+    }
+}
+
+struct MlirBuilder<'g, S, F> {
+    ctxt: &'g NodeCtxt<S>,
+    parse_op: F,
+    /// The origins defined so far, by their names.
+    origins: HashMap<String, OriginId>,
+}
+
+impl<'g, S, F> MlirBuilder<'g, S, F>
+where
+    S: Sig + Eq + Hash + Clone,
+    F: FnMut(&str) -> Option<S>,
+{
+    /// Makes the nodes of `stmts` in `region_id`, returning the yield
+    /// ending them, if there's one.
+    fn build_body(
+        &mut self,
+        region_id: RegionId,
+        stmts: Vec<Stmt>,
+    ) -> Result<Option<Stmt>, ParseError> {
+        let num_stmts = stmts.len();
+        for (index, stmt) in stmts.into_iter().enumerate() {
+            if stmt.op == "rvsdg.yield" {
+                if index + 1 < num_stmts {
+                    return Err(ParseError {
+                        line: stmt.line,
+                        message: "`rvsdg.yield` has to end its region".to_owned(),
+                    });
+                }
+                return Ok(Some(stmt));
+            }
+            self.build_stmt(region_id, stmt)?;
+        }
+        Ok(None)
+    }
+
+    fn build_stmt(&mut self, region_id: RegionId, stmt: Stmt) -> Result<(), ParseError> {
+        let line = stmt.line;
+        let error = |message: String| ParseError { line, message };
+        let int_attr = |name: &str| match stmt.attrs.get(name) {
+            Some(Attr::Int(value)) => Ok(*value),
+            _ => Err(error(format!(
+                "`{}` needs an integer `{}` attribute",
+                stmt.op, name
+            ))),
+        };
+
+        let head = match &stmt.op[..] {
+            "rvsdg.op" => match stmt.attrs.get("op") {
+                Some(Attr::Str(op)) => match (self.parse_op)(op) {
+                    Some(op) => Head::Op(op),
+                    None => return Err(error(format!("unknown op {:?}", op))),
+                },
+                _ => return Err(error("`rvsdg.op` needs a string `op` attribute".to_owned())),
+            },
+            "rvsdg.apply" => Head::Apply,
+            "rvsdg.gamma" => Head::Gamma,
+            "rvsdg.theta" => Head::Theta,
+            "rvsdg.lambda" => Head::Lambda {
+                params: int_attr("params")?,
+            },
+            "rvsdg.omega" => Head::Omega {
+                imports: int_attr("imports")?,
+                exports: int_attr("exports")?,
+            },
+            op => return Err(error(format!("unknown operation `{}`", op))),
+        };
+        if stmt.results.len() != stmt.result_kinds.len() {
+            return Err(error(format!(
+                "{} results are given {} types",
+                stmt.results.len(),
+                stmt.result_kinds.len()
+            )));
+        }
+
+        let uses = self.uses(region_id, line, &stmt.operands, &stmt.operand_kinds)?;
+        let node_id = self
+            .ctxt
+            .make_parsed_node(region_id, head, &uses, &stmt.result_kinds)
+            .map_err(error)?;
+        if let Some(span) = stmt.span {
+            self.ctxt.attach_span(node_id, span);
+        }
+        if let Some(Attr::Str(name)) = stmt.attrs.get("name") {
+            self.ctxt.set_node_name(node_id, name.clone());
+        }
+
+        let is_structural = self.ctxt.node_data(node_id).kind.is_structural();
+        if !is_structural && !stmt.regions.is_empty() {
+            return Err(error(format!("`{}` has no regions", stmt.op)));
+        }
+        for block in stmt.regions {
+            self.build_region(node_id, block)?;
+        }
+
+        for (index, name) in stmt.results.into_iter().enumerate() {
+            self.define(line, name, OriginId::output(node_id, index))?;
+        }
+        Ok(())
+    }
+
+    fn build_region(&mut self, node_id: NodeId, block: Block) -> Result<(), ParseError> {
+        let line = block.line;
+        let kinds: Vec<PortKind> = block.args.iter().map(|&(_, kind)| kind).collect();
+        let region_id = self
+            .ctxt
+            .add_parsed_region(node_id, &kinds)
+            .map_err(|message| ParseError { line, message })?;
+        for (index, (name, _)) in block.args.into_iter().enumerate() {
+            self.define(line, name, OriginId::argument(region_id, index))?;
+        }
+
+        let end = match self.build_body(region_id, block.stmts)? {
+            Some(end) => end,
+            None => {
+                return Err(ParseError {
+                    line,
+                    message: "the region doesn't end in `rvsdg.yield`".to_owned(),
+                })
+            }
+        };
+        let results = self.uses(region_id, end.line, &end.operands, &end.operand_kinds)?;
+        self.ctxt
+            .connect_parsed_results(region_id, &results)
+            .map_err(|message| ParseError {
+                line: end.line,
+                message,
+            })
+    }
+
+    /// Finds the origins named by the operands of an operation, checking
+    /// them against their types.
+    fn uses(
+        &self,
+        region_id: RegionId,
+        line: usize,
+        operands: &[String],
+        kinds: &[PortKind],
+    ) -> Result<Vec<OriginId>, ParseError> {
+        let error = |message: String| ParseError { line, message };
+        if operands.len() != kinds.len() {
+            return Err(error(format!(
+                "{} operands are given {} types",
+                operands.len(),
+                kinds.len()
+            )));
+        }
+        operands
+            .iter()
+            .zip(kinds)
+            .map(|(name, &kind)| {
+                let origin_id = match self.origins.get(name) {
+                    Some(&origin_id) => origin_id,
+                    None => return Err(error(format!("`%{}` isn't defined", name))),
+                };
+                if self.ctxt.origin_region(origin_id) != region_id {
+                    return Err(error(format!("`%{}` is used outside its region", name)));
+                }
+                if self.ctxt.origin_data(origin_id).kind != kind {
+                    return Err(error(format!(
+                        "`%{}` isn't of type `{}`",
+                        name,
+                        type_name(kind)
+                    )));
+                }
+                Ok(origin_id)
+            })
+            .collect()
+    }
+
+    fn define(&mut self, line: usize, name: String, origin_id: OriginId) -> Result<(), ParseError> {
+        if self.origins.contains_key(&name) {
+            return Err(ParseError {
+                line,
+                message: format!("`%{}` is defined twice", name),
+            });
+        }
+        if is_identifier(&name) {
+            self.ctxt.set_origin_name(origin_id, name.clone());
+        }
+        self.origins.insert(name, origin_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, ParseError};
+
+    fn parse_op(op: &str) -> Option<Op> {
+        match op {
+            "Neg" => Some(Op::Neg),
+            "Add" => Some(Op::Add),
+            "St" => Some(Op::St),
+            "Store" => Some(Op::Store),
+            _ if op.starts_with("Lit(") && op.ends_with(')') => {
+                op[4..op.len() - 1].parse().ok().map(Op::Lit)
+            }
+            _ => None,
+        }
+    }
+
+    fn print(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = Vec::new();
+        ncx.print_mlir(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn parse(text: &str) -> Result<NodeCtxt<Op>, ParseError> {
+        NodeCtxt::parse_mlir(text, parse_op)
+    }
+
+    #[test]
+    fn printing_structural_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        ncx.set_origin_name(n0.val_out(0).id(), "zero");
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n0.val_out(0));
+        let (st_arg, st_out) = theta.loop_state(st.st_out(0));
+        let gamma = ncx.gamma_builder(x_arg, 2);
+        let args = gamma.entry_var(x_arg);
+        let states = gamma.entry_state(st_arg);
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let store = ncx
+            .node_builder_in(gamma.branch(0), Op::Store)
+            .operand(x_neg.val_out(0))
+            .state(states[0])
+            .finish();
+        let x_next = gamma.exit_var(&[x_neg.val_out(0), args[1]]);
+        let st_next = gamma.exit_state(&[store.st_out(0), states[1]]);
+        gamma.finish();
+        theta.set_next(x_arg, x_next);
+        theta.set_next_state(st_arg, st_next);
+        theta.finish(x_arg);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st_out)
+            .finish();
+
+        let text = r#"%zero = "rvsdg.op"() {op = "Lit(0)"} : () -> !rvsdg.val
+%0 = "rvsdg.op"() {op = "St"} : () -> !rvsdg.state
+%1, %2 = "rvsdg.theta"(%zero, %0) ({
+^bb0(%3: !rvsdg.val, %4: !rvsdg.state):
+  %5, %6 = "rvsdg.gamma"(%3, %3, %4) ({
+  ^bb0(%7: !rvsdg.val, %8: !rvsdg.state):
+    %9 = "rvsdg.op"(%7) {op = "Neg"} : (!rvsdg.val) -> !rvsdg.val
+    %10 = "rvsdg.op"(%9, %8) {op = "Store"} : (!rvsdg.val, !rvsdg.state) -> !rvsdg.state
+    "rvsdg.yield"(%9, %10) : (!rvsdg.val, !rvsdg.state) -> ()
+  }, {
+  ^bb0(%11: !rvsdg.val, %12: !rvsdg.state):
+    "rvsdg.yield"(%11, %12) : (!rvsdg.val, !rvsdg.state) -> ()
+  }) : (!rvsdg.val, !rvsdg.val, !rvsdg.state) -> (!rvsdg.val, !rvsdg.state)
+  "rvsdg.yield"(%3, %5, %6) : (!rvsdg.val, !rvsdg.val, !rvsdg.state) -> ()
+}) : (!rvsdg.val, !rvsdg.state) -> (!rvsdg.val, !rvsdg.state)
+%13 = "rvsdg.op"(%zero, %2) {op = "Store"} : (!rvsdg.val, !rvsdg.state) -> !rvsdg.state
+"#;
+        assert_eq!(text, print(&ncx));
+
+        let parsed = parse(text).unwrap();
+        assert_eq!(text, print(&parsed));
+        assert_eq!(ncx.num_nodes(), parsed.num_nodes());
+        assert_eq!(ncx.num_edges(), parsed.num_edges());
+    }
+
+    #[test]
+    fn lambdas_omegas_and_attributes() {
+        let text = r#""rvsdg.omega"() ({
+^bb0:
+  %0 = "rvsdg.op"() {op = "Lit(0)"} : () -> !rvsdg.val
+  %f = "rvsdg.lambda"(%0) ({
+  ^bb0(%1: !rvsdg.val, %2: !rvsdg.state, %3: !rvsdg.val):
+    %4 = "rvsdg.op"(%1, %3) {op = "Add"} : (!rvsdg.val, !rvsdg.val) -> !rvsdg.val loc("":3:7)
+    %5 = "rvsdg.op"(%4, %2) {op = "Store", name = "store"} : (!rvsdg.val, !rvsdg.state) -> !rvsdg.state
+    "rvsdg.yield"(%4, %5) : (!rvsdg.val, !rvsdg.state) -> ()
+  }) {params = 2 : i64} : (!rvsdg.val) -> !rvsdg.val
+  %6 = "rvsdg.op"() {op = "St"} : () -> !rvsdg.state
+  %7, %8 = "rvsdg.apply"(%f, %0, %6) : (!rvsdg.val, !rvsdg.val, !rvsdg.state) -> (!rvsdg.val, !rvsdg.state)
+  "rvsdg.yield"(%f) : (!rvsdg.val) -> ()
+}) {imports = 0 : i64, exports = 1 : i64} : () -> ()
+"#;
+        let parsed = parse(text).unwrap();
+        assert_eq!(text, print(&parsed));
+        assert_eq!(Ok(()), parsed.verify());
+    }
+
+    #[test]
+    fn parsing_modules() {
+        let expected = r#"%b = "rvsdg.op"() {op = "Lit(1)"} : () -> !rvsdg.val
+%sum = "rvsdg.op"(%b, %b) {op = "Add"} : (!rvsdg.val, !rvsdg.val) -> !rvsdg.val
+"#;
+        let parsed = parse(
+            r#"
+            // Modules may be written in their custom form, or their generic one.
+            module {
+              %b = "rvsdg.op"() {op = "Lit(1)"} : () -> !rvsdg.val loc(unknown)
+              %sum = "rvsdg.op"(%b, %b) {op = "Add"} : (!rvsdg.val, !rvsdg.val) -> !rvsdg.val
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(expected, print(&parsed));
+
+        let parsed = parse(
+            r#"
+            "builtin.module"() ({
+              %b = "rvsdg.op"() {op = "Lit(1)"} : () -> !rvsdg.val
+              %sum = "rvsdg.op"(%b, %b) {op = "Add"} : (!rvsdg.val, !rvsdg.val) -> !rvsdg.val
+            }) : () -> ()
+            "#,
+        )
+        .unwrap();
+        assert_eq!(expected, print(&parsed));
+    }
+
+    #[test]
+    fn grouped_results() {
+        let parsed = parse(
+            r#"
+            %x = "rvsdg.op"() {op = "Lit(1)"} : () -> !rvsdg.val
+            %g:2 = "rvsdg.gamma"(%x, %x) ({
+            ^bb0(%a: !rvsdg.val):
+              "rvsdg.yield"(%a, %a) : (!rvsdg.val, !rvsdg.val) -> ()
+            }, {
+            ^bb0(%b: !rvsdg.val):
+              %c = "rvsdg.op"(%b) {op = "Neg"} : (!rvsdg.val) -> !rvsdg.val
+              "rvsdg.yield"(%c, %b) : (!rvsdg.val, !rvsdg.val) -> ()
+            }) : (!rvsdg.val, !rvsdg.val) -> (!rvsdg.val, !rvsdg.val)
+            %sum = "rvsdg.op"(%g#0, %g#1) {op = "Add"} : (!rvsdg.val, !rvsdg.val) -> !rvsdg.val
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Ok(()), parsed.verify());
+        assert_eq!(4, parsed.num_nodes());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| parse(text).map(|_| ()).unwrap_err();
+        let lit = "%0 = \"rvsdg.op\"() {op = \"Lit(0)\"} : () -> !rvsdg.val\n";
+
+        assert_eq!(
+            ParseError {
+                line: 2,
+                message: "unknown op \"Mul\"".to_owned(),
+            },
+            error(&format!(
+                "{}%1 = \"rvsdg.op\"(%0) {{op = \"Mul\"}} : (!rvsdg.val) -> !rvsdg.val",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 1,
+                message: "`%1` isn't defined".to_owned(),
+            },
+            error("%0 = \"rvsdg.op\"(%1) {op = \"Neg\"} : (!rvsdg.val) -> !rvsdg.val")
+        );
+        assert_eq!(
+            ParseError {
+                line: 2,
+                message: "`%0` isn't of type `!rvsdg.state`".to_owned(),
+            },
+            error(&format!(
+                "{}%1 = \"rvsdg.op\"(%0, %0) {{op = \"Store\"}} : (!rvsdg.val, !rvsdg.state) -> \
+                 !rvsdg.state",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 4,
+                message: "`%0` is used outside its region".to_owned(),
+            },
+            error(&format!(
+                "{}%1 = \"rvsdg.gamma\"(%0) ({{\n^bb0:\n\"rvsdg.yield\"(%0) : (!rvsdg.val) -> \
+                 ()\n}}) : (!rvsdg.val) -> !rvsdg.val",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 2,
+                message: "the region doesn't end in `rvsdg.yield`".to_owned(),
+            },
+            error(&format!(
+                "{}%1 = \"rvsdg.gamma\"(%0) ({{\n}}) : (!rvsdg.val) -> !rvsdg.val",
+                lit
+            ))
+        );
+        assert_eq!(
+            ParseError {
+                line: 1,
+                message: "expected `!rvsdg.val` or `!rvsdg.state`, found `!llvm.ptr`".to_owned(),
+            },
+            error("%0 = \"rvsdg.op\"() {op = \"Lit(0)\"} : () -> !llvm.ptr")
+        );
+        assert_eq!(
+            ParseError {
+                line: 1,
+                message: "unknown operation `arith.constant`".to_owned(),
+            },
+            error("%0 = \"arith.constant\"() {value = 0 : i32} : () -> !rvsdg.val")
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use super::{NodeCtxt, NodeId, OriginId, PortKind, RegionId, Sig, SigS, UserId, ValOrigin};
+use std::{collections::HashMap, hash::Hash};
+
+/// The most nodes a subgraph may have to be outlined, which also bounds how
+/// far the trees of shared operands are unfolded.
+const MAX_OUTLINED_SIZE: usize = 64;
+
+/// A tree of stateless nodes, whose leaves are the parameters of the lambda
+/// it would be outlined into.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Term<S> {
+    Param(usize),
+    Op(S, Vec<Term<S>>),
+}
+
+/// Where a subgraph occurs.
+struct Occurrence {
+    region: RegionId,
+    root: NodeId,
+    /// The origins standing for the parameters of the term.
+    leaves: Vec<OriginId>,
+    /// The nodes of the subgraph, the root first, once for every operand
+    /// they're used as.
+    nodes: Vec<NodeId>,
+}
+
+impl<S> NodeCtxt<S> {
+    /// Outlines stateless subgraphs of at least `min_size` nodes that occur
+    /// more than once, usually in different regions where interning can't
+    /// share them, into a lambda in the root region. Each occurrence is
+    /// replaced with an apply node calling it. Returns how many lambdas were
+    /// made.
+    ///
+    /// A subgraph is rooted at a stateless node with a single value output
+    /// that something other than such nodes of its region uses, and spans
+    /// the nodes of the same kind its operands come from. The other origins
+    /// it uses become the parameters of the lambda.
+    pub fn outline_repeated_subgraphs(&self, min_size: usize) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut terms: Vec<Term<S>> = vec![];
+        let mut occurrences: HashMap<Term<S>, Vec<Occurrence>> = HashMap::new();
+        let num_nodes = self.nodes.len();
+        for root in (0..num_nodes).map(NodeId::new) {
+            if !self.is_subgraph_root(root) {
+                continue;
+            }
+            let mut occurrence = Occurrence {
+                region: self.node_data(root).outer_region,
+                root,
+                leaves: vec![],
+                nodes: vec![],
+            };
+            let term = match self.subgraph_term(root, &mut occurrence) {
+                Some(term) if occurrence.nodes.len() >= min_size => term,
+                _ => continue,
+            };
+            occurrences
+                .entry(term.clone())
+                .or_insert_with(|| {
+                    terms.push(term);
+                    vec![]
+                })
+                .push(occurrence);
+        }
+
+        let mut num_outlined = 0;
+        for term in terms {
+            let occurrences = &occurrences[&term];
+            if occurrences.len() < 2 {
+                continue;
+            }
+            let num_params = occurrences[0].leaves.len();
+            let lambda = self.lambda_builder(self.root_region(), &vec![PortKind::Val; num_params]);
+            let result = self.build_term(lambda.body(), &term, &mut |index| {
+                lambda.val_param(index).id()
+            });
+            let lambda = lambda.finish(&[ValOrigin(self.origin_ref(result))], &[]);
+            let sig = SigS {
+                val_ins: num_params,
+                val_outs: 1,
+                ..SigS::default()
+            };
+            for occurrence in occurrences {
+                let function = self.route_into(lambda.val_out(0).id(), occurrence.region);
+                let leaves: Vec<_> = occurrence
+                    .leaves
+                    .iter()
+                    .map(|&leaf| ValOrigin(self.origin_ref(leaf)))
+                    .collect();
+                let apply = self
+                    .apply_builder(ValOrigin(self.origin_ref(function)), sig)
+                    .operands(&leaves)
+                    .finish();
+                let root_out = OriginId::Out {
+                    node: occurrence.root,
+                    index: 0,
+                };
+                self.replace_all_users(root_out, apply.val_out(0).id());
+                self.remove_unused_subgraph(&occurrence.nodes);
+            }
+            num_outlined += 1;
+        }
+        num_outlined
+    }
+
+    /// Whether `node_id` is a stateless node with a single value output that
+    /// something other than such nodes of its region uses.
+    fn is_subgraph_root(&self, node_id: NodeId) -> bool
+    where
+        S: Sig + Clone,
+    {
+        if self.dataflow_term(node_id).is_none() {
+            return false;
+        }
+        let users: Vec<UserId> = self
+            .origin_ref(OriginId::Out {
+                node: node_id,
+                index: 0,
+            })
+            .users()
+            .map(|user| user.id())
+            .collect();
+        users.into_iter().any(|user_id| match user_id {
+            UserId::In { node, .. } => self.dataflow_term(node).is_none(),
+            UserId::Res { .. } => true,
+        })
+    }
+
+    /// The term of the subgraph rooted at `node_id`, filling in the leaves
+    /// and nodes of `occurrence`, or `None` if it's too large.
+    fn subgraph_term(&self, node_id: NodeId, occurrence: &mut Occurrence) -> Option<Term<S>>
+    where
+        S: Sig + Clone,
+    {
+        if occurrence.nodes.len() == MAX_OUTLINED_SIZE {
+            return None;
+        }
+        let (op, operands) = self.dataflow_term(node_id)?;
+        occurrence.nodes.push(node_id);
+        let operands = operands
+            .into_iter()
+            .map(|origin_id| {
+                let inner_node = match origin_id {
+                    OriginId::Out { node, .. } if self.dataflow_term(node).is_some() => Some(node),
+                    _ => None,
+                };
+                match inner_node {
+                    Some(node) => self.subgraph_term(node, occurrence),
+                    None => {
+                        let index =
+                            match occurrence.leaves.iter().position(|&leaf| leaf == origin_id) {
+                                Some(index) => index,
+                                None => {
+                                    occurrence.leaves.push(origin_id);
+                                    occurrence.leaves.len() - 1
+                                }
+                            };
+                        Some(Term::Param(index))
+                    }
+                }
+            })
+            .collect::<Option<_>>()?;
+        Some(Term::Op(op, operands))
+    }
+
+    /// Builds the nodes of `term` in `region_id`, with `param` giving the
+    /// origins of its parameters. Returns the origin of its value.
+    fn build_term<F>(&self, region_id: RegionId, term: &Term<S>, param: &mut F) -> OriginId
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(usize) -> OriginId,
+    {
+        match term {
+            Term::Param(index) => param(*index),
+            Term::Op(op, operands) => {
+                let builder = self.node_builder_in(region_id, op.clone());
+                let builder = operands.iter().fold(builder, |builder, operand| {
+                    let origin_id = self.build_term(region_id, operand, param);
+                    builder.operand(ValOrigin(self.origin_ref(origin_id)))
+                });
+                builder.finish().val_out(0).id()
+            }
+        }
+    }
+
+    /// Removes the nodes of an outlined subgraph that are no longer used.
+    fn remove_unused_subgraph(&self, nodes: &[NodeId])
+    where
+        S: Eq + Hash + Clone,
+    {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node_id in nodes {
+                let is_unused = {
+                    let node_data = self.node_data(node_id);
+                    !node_data.removed && node_data.outs.iter().all(|out| out.users.get().is_none())
+                };
+                if is_unused {
+                    self.remove_node(node_id);
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn outlining_subgraphs_repeated_across_branches() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(7));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_x.val_out(0));
+        // (x + 1) * x, in both branches.
+        let results: Vec<_> = (0..2)
+            .map(|index| {
+                let branch = gamma.branch(index);
+                let n_one = ncx.node_builder_in(branch, Op::Lit(1)).finish();
+                let n_add = ncx
+                    .node_builder_in(branch, Op::Add)
+                    .operand(args[index])
+                    .operand(n_one.val_out(0))
+                    .finish();
+                ncx.node_builder_in(branch, Op::Mul)
+                    .operand(n_add.val_out(0))
+                    .operand(args[index])
+                    .finish()
+                    .val_out(0)
+            })
+            .collect();
+        gamma.exit_var(&results);
+        let gamma = gamma.finish();
+
+        assert_eq!(0, ncx.outline_repeated_subgraphs(4));
+        assert_eq!(1, ncx.outline_repeated_subgraphs(3));
+        assert_eq!(Ok(()), ncx.verify());
+
+        let lambdas: Vec<_> = ncx
+            .region_ref(ncx.root_region())
+            .nodes()
+            .filter(|node| matches!(*node.kind(), NodeKind::Lambda { .. }))
+            .collect();
+        assert_eq!(lambdas.len(), 1);
+        let body = ncx.inner_regions(lambdas[0].id())[0];
+        assert_eq!(ncx.region_ref(body).nodes().count(), 3);
+
+        for branch in ncx.inner_regions(gamma.id()) {
+            let nodes: Vec<_> = ncx.region_ref(branch).nodes().collect();
+            assert_eq!(nodes.len(), 1);
+            assert!(matches!(*nodes[0].kind(), NodeKind::Apply { .. }));
+        }
+    }
+}
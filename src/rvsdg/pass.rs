@@ -0,0 +1,244 @@
+use super::NodeCtxt;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+/// How many times a fixpoint group may run before it's considered to never
+/// settle.
+const MAX_FIXPOINT_ITERATIONS: usize = 100;
+
+/// Whether a pass changed the graph.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Changed {
+    No,
+    Yes,
+}
+
+impl From<bool> for Changed {
+    fn from(changed: bool) -> Changed {
+        if changed {
+            Changed::Yes
+        } else {
+            Changed::No
+        }
+    }
+}
+
+/// A transformation of the graph run by a `PassManager`.
+pub trait Pass<S> {
+    /// The name the pass goes by in statistics and crash snapshots.
+    fn name(&self) -> &str;
+
+    /// Runs the pass over the graph, telling whether it changed anything.
+    fn run(&mut self, ncx: &NodeCtxt<S>) -> Changed;
+}
+
+/// What a `PassManager` recorded about runs of one of its passes.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PassStats {
+    pub name: String,
+    /// How many times the pass ran.
+    pub runs: usize,
+    /// How many of those runs changed the graph.
+    pub changes: usize,
+    /// The time spent in the pass, over every run.
+    pub time: Duration,
+}
+
+struct FnPass<F> {
+    name: &'static str,
+    run: F,
+}
+
+impl<S, F> Pass<S> for FnPass<F>
+where
+    F: FnMut(&NodeCtxt<S>) -> Changed,
+{
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&mut self, ncx: &NodeCtxt<S>) -> Changed {
+        (self.run)(ncx)
+    }
+}
+
+/// Passes that run one after another. A fixpoint group runs again as long
+/// as any of its passes changes the graph.
+struct Group<S> {
+    passes: Vec<(Box<dyn Pass<S>>, PassStats)>,
+    fixpoint: bool,
+}
+
+/// Runs a pipeline of passes over a graph, in the order they were added,
+/// keeping statistics of each.
+pub struct PassManager<S> {
+    groups: Vec<Group<S>>,
+}
+
+impl<S> Default for PassManager<S> {
+    fn default() -> PassManager<S> {
+        PassManager { groups: vec![] }
+    }
+}
+
+impl<S: Debug> PassManager<S> {
+    pub fn new() -> PassManager<S> {
+        PassManager::default()
+    }
+
+    /// Adds a pass that runs once.
+    pub fn pass(self, pass: impl Pass<S> + 'static) -> PassManager<S> {
+        self.group(vec![Box::new(pass)], false)
+    }
+
+    /// Adds a pass that runs once, made from `run`.
+    pub fn pass_fn<F>(self, name: &'static str, run: F) -> PassManager<S>
+    where
+        F: FnMut(&NodeCtxt<S>) -> Changed + 'static,
+    {
+        self.pass(FnPass { name, run })
+    }
+
+    /// Adds passes that run in order over and over, until none of them
+    /// changes the graph.
+    pub fn fixpoint(self, passes: Vec<Box<dyn Pass<S>>>) -> PassManager<S> {
+        self.group(passes, true)
+    }
+
+    fn group(mut self, passes: Vec<Box<dyn Pass<S>>>, fixpoint: bool) -> PassManager<S> {
+        let passes = passes
+            .into_iter()
+            .map(|pass| {
+                let stats = PassStats {
+                    name: pass.name().to_owned(),
+                    ..PassStats::default()
+                };
+                (pass, stats)
+            })
+            .collect();
+        self.groups.push(Group { passes, fixpoint });
+        self
+    }
+
+    /// Runs the pipeline over the graph, telling whether any pass changed
+    /// it.
+    ///
+    /// Passes run through `NodeCtxt::run_pass`, so a crash snapshot is taken
+    /// if one of them panics.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a fixpoint group still changes the graph after
+    /// `MAX_FIXPOINT_ITERATIONS` runs.
+    pub fn run(&mut self, ncx: &NodeCtxt<S>) -> Changed {
+        let mut changed = false;
+        for group in &mut self.groups {
+            let mut iterations = 0;
+            loop {
+                let group_changed = group.run(ncx);
+                changed |= group_changed;
+                iterations += 1;
+                if !group.fixpoint || !group_changed {
+                    break;
+                }
+                assert!(
+                    iterations < MAX_FIXPOINT_ITERATIONS,
+                    "passes didn't reach a fixpoint after {} iterations",
+                    iterations
+                );
+            }
+        }
+        Changed::from(changed)
+    }
+
+    /// Statistics of every pass, in the order they were added.
+    pub fn stats(&self) -> impl Iterator<Item = &PassStats> {
+        self.groups
+            .iter()
+            .flat_map(|group| group.passes.iter().map(|(_, stats)| stats))
+    }
+}
+
+impl<S: Debug> Group<S> {
+    fn run(&mut self, ncx: &NodeCtxt<S>) -> bool {
+        let mut changed = false;
+        for (pass, stats) in &mut self.passes {
+            let start = Instant::now();
+            let pass_changed = ncx.run_pass(&stats.name, |ncx| pass.run(ncx)) == Changed::Yes;
+            stats.time += start.elapsed();
+            stats.runs += 1;
+            if pass_changed {
+                stats.changes += 1;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Changed, Pass, PassManager};
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    /// Removes one unused negation per run.
+    struct RemoveUnusedNeg;
+
+    impl Pass<Op> for RemoveUnusedNeg {
+        fn name(&self) -> &str {
+            "remove-unused-neg"
+        }
+
+        fn run(&mut self, ncx: &NodeCtxt<Op>) -> Changed {
+            let region = ncx.region_ref(ncx.root_region());
+            let unused = region.nodes().find(|node| {
+                *node.kind() == NodeKind::Op(Op::Neg) && node.val_out(0).users().next().is_none()
+            });
+            match unused {
+                Some(node) => {
+                    ncx.remove_node(node.id());
+                    Changed::Yes
+                }
+                None => Changed::No,
+            }
+        }
+    }
+
+    #[test]
+    fn running_a_pipeline() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.mk_node(Op::Lit(1));
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_lit.val_out(0)).finish();
+        ncx.node_builder(Op::Neg).operand(n_neg.val_out(0)).finish();
+
+        let mut passes = PassManager::new()
+            .pass_fn("nothing", |_| Changed::No)
+            .fixpoint(vec![Box::new(RemoveUnusedNeg)]);
+
+        assert_eq!(Changed::Yes, passes.run(&ncx));
+        assert_eq!(ncx.region_ref(ncx.root_region()).nodes().count(), 1);
+
+        let stats: Vec<_> = passes
+            .stats()
+            .map(|stats| (stats.name.as_str(), stats.runs, stats.changes))
+            .collect();
+        // The fixpoint group runs once more to find out nothing changes.
+        assert_eq!(stats, [("nothing", 1, 0), ("remove-unused-neg", 3, 2)]);
+
+        assert_eq!(Changed::No, passes.run(&ncx));
+    }
+
+    #[test]
+    #[should_panic(expected = "passes didn't reach a fixpoint after 100 iterations")]
+    fn groups_that_never_settle() {
+        let ncx = NodeCtxt::<Op>::new();
+        PassManager::new()
+            .fixpoint(vec![Box::new(super::FnPass {
+                name: "always",
+                run: |_: &NodeCtxt<Op>| Changed::Yes,
+            })])
+            .run(&ncx);
+    }
+}
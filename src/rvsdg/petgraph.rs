@@ -0,0 +1,303 @@
+//! Views of regions that implement the traits of `petgraph::visit`, so that
+//! the algorithms of petgraph run on graphs without copying them.
+
+use super::{FrozenGraph, NodeCtxt, NodeId, OriginId, RegionId, UserId};
+use petgraph::{
+    visit::{
+        GraphBase, GraphRef, IntoNeighbors, IntoNeighborsDirected, IntoNodeIdentifiers, NodeCount,
+        NodeIndexable, Visitable,
+    },
+    Direction,
+};
+use std::{collections::HashSet, vec};
+
+/// The nodes of a region, with an edge from each node to every node using
+/// its outputs, as made by `NodeCtxt::region_graph` and
+/// `FrozenGraph::region_graph`.
+///
+/// Edges stay within the region: nodes nested in the regions of its
+/// structural nodes aren't part of the view, and neither are the arguments
+/// and results of the region. Each pair of nodes has at most one edge, for
+/// however many outputs of one the other uses.
+///
+/// ```ignore
+/// let order = petgraph::algo::toposort(ncx.region_graph(region_id), None)?;
+/// ```
+pub struct RegionGraph<'g, S> {
+    graph: Graph<'g, S>,
+    region_id: RegionId,
+}
+
+enum Graph<'g, S> {
+    Live(&'g NodeCtxt<S>),
+    Frozen(&'g FrozenGraph<S>),
+}
+
+impl<S> NodeCtxt<S> {
+    /// A view of the nodes in `region_id` for the algorithms of petgraph.
+    ///
+    /// The view borrows the context, whose nodes it sees as they are when
+    /// it's walked.
+    pub fn region_graph(&self, region_id: RegionId) -> RegionGraph<'_, S> {
+        RegionGraph {
+            graph: Graph::Live(self),
+            region_id,
+        }
+    }
+}
+
+impl<S> FrozenGraph<S> {
+    /// A view of the nodes in `region_id` for the algorithms of petgraph.
+    pub fn region_graph(&self, region_id: RegionId) -> RegionGraph<'_, S> {
+        RegionGraph {
+            graph: Graph::Frozen(self),
+            region_id,
+        }
+    }
+}
+
+impl<'g, S> RegionGraph<'g, S> {
+    /// The region the view is of.
+    pub fn region_id(&self) -> RegionId {
+        self.region_id
+    }
+
+    fn nodes(&self) -> Vec<NodeId> {
+        match self.graph {
+            Graph::Live(ncx) => ncx.region_nodes(self.region_id),
+            Graph::Frozen(frozen) => frozen.region_nodes(self.region_id).to_vec(),
+        }
+    }
+
+    /// The nodes producing the origins of the inputs of `node_id`, each
+    /// once.
+    fn producers(&self, node_id: NodeId) -> Vec<NodeId> {
+        let mut producers = vec![];
+        match self.graph {
+            Graph::Live(ncx) => {
+                producers.extend(ncx.node_ref(node_id).predecessors().map(|node| node.id()))
+            }
+            Graph::Frozen(frozen) => {
+                for producer in frozen.producers(node_id) {
+                    if !producers.contains(&producer) {
+                        producers.push(producer);
+                    }
+                }
+            }
+        }
+        producers
+    }
+
+    /// The nodes using the outputs of `node_id`, each once.
+    fn consumers(&self, node_id: NodeId) -> Vec<NodeId> {
+        let mut consumers = vec![];
+        match self.graph {
+            Graph::Live(ncx) => {
+                consumers.extend(ncx.node_ref(node_id).successors().map(|node| node.id()))
+            }
+            Graph::Frozen(frozen) => {
+                for index in 0..frozen.num_outputs(node_id) {
+                    for &user_id in frozen.users(OriginId::output(node_id, index)) {
+                        if let UserId::In { node, .. } = user_id {
+                            if !consumers.contains(&node) {
+                                consumers.push(node);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        consumers
+    }
+}
+
+impl<'g, S> Clone for RegionGraph<'g, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'g, S> Copy for RegionGraph<'g, S> {}
+
+impl<'g, S> Clone for Graph<'g, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'g, S> Copy for Graph<'g, S> {}
+
+impl<'g, S> GraphBase for RegionGraph<'g, S> {
+    /// Edges are told apart by the nodes they join.
+    type EdgeId = (NodeId, NodeId);
+    type NodeId = NodeId;
+}
+
+impl<'g, S> GraphRef for RegionGraph<'g, S> {}
+
+impl<'g, S> IntoNeighbors for RegionGraph<'g, S> {
+    type Neighbors = vec::IntoIter<NodeId>;
+
+    fn neighbors(self, node_id: NodeId) -> Self::Neighbors {
+        self.consumers(node_id).into_iter()
+    }
+}
+
+impl<'g, S> IntoNeighborsDirected for RegionGraph<'g, S> {
+    type NeighborsDirected = vec::IntoIter<NodeId>;
+
+    fn neighbors_directed(self, node_id: NodeId, direction: Direction) -> Self::NeighborsDirected {
+        match direction {
+            Direction::Outgoing => self.consumers(node_id).into_iter(),
+            Direction::Incoming => self.producers(node_id).into_iter(),
+        }
+    }
+}
+
+impl<'g, S> IntoNodeIdentifiers for RegionGraph<'g, S> {
+    type NodeIdentifiers = vec::IntoIter<NodeId>;
+
+    fn node_identifiers(self) -> Self::NodeIdentifiers {
+        self.nodes().into_iter()
+    }
+}
+
+impl<'g, S> NodeCount for RegionGraph<'g, S> {
+    fn node_count(&self) -> usize {
+        match self.graph {
+            Graph::Live(ncx) => ncx.region_data(self.region_id).nodes.len(),
+            Graph::Frozen(frozen) => frozen.region_nodes(self.region_id).len(),
+        }
+    }
+}
+
+/// Nodes are indexed as in the whole graph, so indices are bounded by the
+/// number of nodes ever made rather than those in the region.
+impl<'g, S> NodeIndexable for RegionGraph<'g, S> {
+    fn node_bound(&self) -> usize {
+        match self.graph {
+            Graph::Live(ncx) => ncx.nodes.len(),
+            Graph::Frozen(frozen) => frozen.node_bound(),
+        }
+    }
+
+    fn to_index(&self, node_id: NodeId) -> usize {
+        node_id.index()
+    }
+
+    fn from_index(&self, index: usize) -> NodeId {
+        NodeId::new(index)
+    }
+}
+
+impl<'g, S> Visitable for RegionGraph<'g, S> {
+    type Map = HashSet<NodeId>;
+
+    fn visit_map(&self) -> HashSet<NodeId> {
+        HashSet::new()
+    }
+
+    fn reset_map(&self, map: &mut HashSet<NodeId>) {
+        map.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeId, RegionId};
+    use petgraph::{
+        algo::{dominators, has_path_connecting, kosaraju_scc, tarjan_scc, toposort},
+        visit::{Dfs, IntoNeighborsDirected, NodeIndexable},
+        Direction,
+    };
+
+    /// Makes `-x + -(-x)` in the first branch of a gamma, returning the
+    /// nodes in the order they're made, and the branch.
+    fn diamond(ncx: &NodeCtxt<Op>) -> (Vec<NodeId>, RegionId) {
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let x = gamma.entry_var(n_x.val_out(0));
+        let branch = gamma.branch(0);
+        let n_neg = ncx.node_builder_in(branch, Op::Neg).operand(x[0]).finish();
+        let n_neg_neg = ncx
+            .node_builder_in(branch, Op::Neg)
+            .operand(n_neg.val_out(0))
+            .finish();
+        let n_add = ncx
+            .node_builder_in(branch, Op::Add)
+            .operand(n_neg.val_out(0))
+            .operand(n_neg_neg.val_out(0))
+            .finish();
+        gamma.exit_var(&[n_add.val_out(0), x[1]]);
+        let n_gamma = gamma.finish();
+        let nodes = vec![
+            n_pred.id(),
+            n_x.id(),
+            n_gamma.id(),
+            n_neg.id(),
+            n_neg_neg.id(),
+            n_add.id(),
+        ];
+        (nodes, branch)
+    }
+
+    #[test]
+    fn sorting_live_regions() {
+        let ncx = NodeCtxt::new();
+        let (nodes, branch) = diamond(&ncx);
+        let root = ncx.region_graph(ncx.root_region());
+        let inner = ncx.region_graph(branch);
+
+        let order = toposort(root, None).unwrap();
+        assert_eq!(3, order.len());
+        assert_eq!(Some(&nodes[2]), order.last());
+        assert_eq!(
+            Ok(vec![nodes[3], nodes[4], nodes[5]]),
+            toposort(inner, None)
+        );
+
+        // Nodes in the regions of the gamma aren't reached from outside.
+        assert!(!has_path_connecting(root, nodes[0], nodes[5], None));
+        assert!(has_path_connecting(inner, nodes[3], nodes[5], None));
+        assert_eq!(
+            vec![nodes[3], nodes[4]],
+            inner
+                .neighbors_directed(nodes[5], Direction::Incoming)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![nodes[4], nodes[5]],
+            inner
+                .neighbors_directed(nodes[3], Direction::Outgoing)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn walking_frozen_regions() {
+        let ncx = NodeCtxt::new();
+        let (nodes, branch) = diamond(&ncx);
+        let frozen = ncx.freeze();
+        let inner = frozen.region_graph(branch);
+
+        assert_eq!(
+            Ok(vec![nodes[3], nodes[4], nodes[5]]),
+            toposort(inner, None)
+        );
+        assert_eq!(3, tarjan_scc(inner).len());
+        assert_eq!(3, kosaraju_scc(inner).len());
+        assert_eq!(ncx.num_nodes(), inner.node_bound());
+
+        let doms = dominators::simple_fast(inner, nodes[3]);
+        assert_eq!(Some(nodes[3]), doms.immediate_dominator(nodes[5]));
+        assert_eq!(Some(nodes[3]), doms.immediate_dominator(nodes[4]));
+
+        let mut dfs = Dfs::new(inner, nodes[4]);
+        let mut reached = vec![];
+        while let Some(node_id) = dfs.next(inner) {
+            reached.push(node_id);
+        }
+        assert_eq!(vec![nodes[4], nodes[5]], reached);
+    }
+}
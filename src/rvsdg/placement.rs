@@ -0,0 +1,333 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, RegionId, Sig, UserId};
+use std::hash::Hash;
+
+/// The costs and branch weights guiding `place_by_branch_weights`.
+pub trait PlacementModel<S> {
+    /// The cost of evaluating `op` once.
+    fn cost(&self, op: &S) -> u32;
+
+    /// How often each branch of `gamma` is taken relative to the others, or
+    /// `None` if unknown, in which case the gamma is left alone.
+    fn branch_weights(&self, gamma: NodeId) -> Option<Vec<u32>>;
+
+    /// The cost from which computations are kept off the paths that don't
+    /// need them most of the time. Cheaper ones may be computed ahead of a
+    /// gamma instead.
+    fn expensive_cost(&self) -> u32;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Moves pure computations in and out of gamma branches by how likely
+    /// they are to be needed: expensive ones passed only to branches taken
+    /// less than half of the time are sunk into them, and cheap ones in a
+    /// branch taken more than half of the time are hoisted ahead of the
+    /// gamma. Returns how many nodes were moved.
+    pub fn place_by_branch_weights<M>(&self, model: &M) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+        M: PlacementModel<S>,
+    {
+        let num_nodes = self.nodes.len();
+        let gammas: Vec<NodeId> = (0..num_nodes)
+            .map(NodeId::new)
+            .filter(|&node_id| {
+                let node_data = self.node_data(node_id);
+                !node_data.removed && matches!(node_data.kind, NodeKind::Gamma { .. })
+            })
+            .collect();
+
+        let mut num_moved = 0;
+        for gamma_id in gammas {
+            if self.node_data(gamma_id).removed {
+                continue;
+            }
+            let outer_region = self.node_data(gamma_id).outer_region;
+            let branches = self.inner_regions(gamma_id);
+            let is_frozen = |region_id: RegionId| self.region_data(region_id).frozen.is_some();
+            if is_frozen(outer_region) || branches.iter().any(|&branch| is_frozen(branch)) {
+                continue;
+            }
+            let weights = match model.branch_weights(gamma_id) {
+                Some(weights) if weights.len() == branches.len() => weights,
+                _ => continue,
+            };
+
+            num_moved += self.sink_into_cold_branches(gamma_id, &weights, model);
+            let total_weight: u32 = weights.iter().sum();
+            for (&branch, &weight) in branches.iter().zip(&weights) {
+                if 2 * weight > total_weight {
+                    num_moved += self.hoist_out_of_hot_branch(branch, model);
+                }
+            }
+        }
+        num_moved
+    }
+
+    fn sink_into_cold_branches<M>(&self, gamma_id: NodeId, weights: &[u32], model: &M) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+        M: PlacementModel<S>,
+    {
+        let branches = self.inner_regions(gamma_id);
+        let total_weight: u32 = weights.iter().sum();
+        let num_entries = self.node_data(gamma_id).ins.len() - 1;
+
+        let mut num_sunk = 0;
+        // Goes backwards, as removing an entry variable shifts the ones after
+        // it.
+        for entry in (0..num_entries).rev() {
+            let input = UserId::input(gamma_id, entry + 1);
+            let (node_id, output) = match self.user_data(input).origin.get() {
+                Some(OriginId::Out { node, index }) => (node, index),
+                _ => continue,
+            };
+            if !self.is_placeable(node_id) || self.op_cost(node_id, model) < model.expensive_cost()
+            {
+                continue;
+            }
+            // The entry variable must be the node's only user.
+            let num_users: usize = self.node_ref(node_id).successors().count();
+            let single_user = self
+                .origin_ref(OriginId::Out {
+                    node: node_id,
+                    index: output,
+                })
+                .has_single_user();
+            if num_users != 1 || !single_user {
+                continue;
+            }
+
+            let using_branches: Vec<RegionId> = branches
+                .iter()
+                .cloned()
+                .filter(|&branch| {
+                    let arg = OriginId::argument(branch, entry);
+                    self.origin_data(arg).users.get().is_some()
+                })
+                .collect();
+            let used_weight: u32 = branches
+                .iter()
+                .zip(weights)
+                .filter(|(branch, _)| using_branches.contains(branch))
+                .map(|(_, &weight)| weight)
+                .sum();
+            if using_branches.is_empty() || 2 * used_weight >= total_weight {
+                continue;
+            }
+
+            let operands: Vec<OriginId> = self
+                .node_data(node_id)
+                .ins
+                .iter()
+                .map(|user| user.origin.get().unwrap())
+                .collect();
+            for branch in using_branches {
+                let routed: Vec<OriginId> = operands
+                    .iter()
+                    .map(|&operand| self.route_into(operand, branch))
+                    .collect();
+                let kind = self.node_data(node_id).kind.clone();
+                let sunk_id = self.mk_node_in_region_with(branch, kind, &routed);
+                self.replace_all_users(
+                    OriginId::argument(branch, entry),
+                    OriginId::Out {
+                        node: sunk_id,
+                        index: output,
+                    },
+                );
+            }
+            self.remove_gamma_entry(gamma_id, entry);
+            self.remove_node(node_id);
+            num_sunk += 1;
+        }
+        num_sunk
+    }
+
+    fn hoist_out_of_hot_branch<M>(&self, branch: RegionId, model: &M) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+        M: PlacementModel<S>,
+    {
+        let gamma_id = self.region_data(branch).node.unwrap();
+        let outer_region = self.node_data(gamma_id).outer_region;
+
+        let mut num_hoisted = 0;
+        for node_id in self.region_topo_order(branch) {
+            if !self.is_placeable(node_id) || self.op_cost(node_id, model) >= model.expensive_cost()
+            {
+                continue;
+            }
+            // Only nodes computed from values passed in can be computed ahead
+            // of the gamma.
+            let operands: Option<Vec<OriginId>> = self
+                .node_data(node_id)
+                .ins
+                .iter()
+                .map(|user| match user.origin.get() {
+                    Some(arg @ OriginId::Arg { .. }) => Some(arg),
+                    _ => None,
+                })
+                .collect();
+            let operands = match operands {
+                Some(ref operands) if !operands.is_empty() => operands,
+                _ => continue,
+            };
+            let is_used = self
+                .node_data(node_id)
+                .outs
+                .iter()
+                .any(|out| out.users.get().is_some());
+            if !is_used {
+                continue;
+            }
+
+            let outer_operands: Vec<OriginId> = operands
+                .iter()
+                .map(|&operand| self.route_out_of(operand, outer_region))
+                .collect();
+            let kind = self.node_data(node_id).kind.clone();
+            let hoisted_id = self.mk_node_in_region_with(outer_region, kind, &outer_operands);
+
+            let num_outputs = self.node_data(node_id).outs.len();
+            for index in 0..num_outputs {
+                let output = OriginId::output(node_id, index);
+                if self.origin_data(output).users.get().is_none() {
+                    continue;
+                }
+                let hoisted_output = OriginId::output(hoisted_id, index);
+                let arg = self.route_into(hoisted_output, branch);
+                self.replace_all_users(output, arg);
+            }
+            self.remove_node(node_id);
+            num_hoisted += 1;
+        }
+        num_hoisted
+    }
+
+    /// Whether a node can be computed anywhere its operands are available.
+    fn is_placeable(&self, node_id: NodeId) -> bool
+    where
+        S: Sig,
+    {
+        let node_data = self.node_data(node_id);
+        match node_data.kind {
+            NodeKind::Op(..) => !node_data.kind.sig().is_side_effectful(),
+            _ => false,
+        }
+    }
+
+    fn op_cost<M: PlacementModel<S>>(&self, node_id: NodeId, model: &M) -> u32 {
+        match self.node_data(node_id).kind {
+            NodeKind::Op(ref op) => model.cost(op),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PlacementModel;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeId, NodeKind};
+
+    struct Model {
+        gamma: NodeId,
+        weights: Vec<u32>,
+    }
+
+    impl PlacementModel<Op> for Model {
+        fn cost(&self, op: &Op) -> u32 {
+            match op {
+                Op::Mul => 10,
+                _ => 1,
+            }
+        }
+
+        fn branch_weights(&self, gamma: NodeId) -> Option<Vec<u32>> {
+            if gamma == self.gamma {
+                Some(self.weights.clone())
+            } else {
+                None
+            }
+        }
+
+        fn expensive_cost(&self) -> u32 {
+            5
+        }
+    }
+
+    #[test]
+    fn sinking_and_hoisting() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let n_mul = ncx
+            .node_builder(Op::Mul)
+            .operand(n_x.val_out(0))
+            .operand(n_x.val_out(0))
+            .finish();
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let mul_args = gamma.entry_var(n_mul.val_out(0));
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(1), Op::Neg)
+            .operand(x_args[1])
+            .finish();
+        let output = gamma.exit_var(&[mul_args[0], x_neg.val_out(0)]);
+        let branches = [gamma.branch(0), gamma.branch(1)];
+        let gamma = gamma.finish();
+        let n_user = ncx.node_builder(Op::Neg).operand(output).finish();
+
+        let model = Model {
+            gamma: gamma.id(),
+            weights: vec![1, 9],
+        };
+        assert_eq!(2, ncx.place_by_branch_weights(&model));
+        assert_eq!(Ok(()), ncx.verify());
+
+        // The multiplication is only computed in the cold branch.
+        let cold_result = ncx.region_ref(branches[0]).nodes().next().unwrap();
+        assert_eq!(NodeKind::Op(Op::Mul), *cold_result.kind());
+        assert_eq!(branches[0], cold_result.region());
+
+        // The negation is computed ahead of the gamma.
+        let hot_nodes: Vec<_> = ncx.region_ref(branches[1]).nodes().collect();
+        assert!(hot_nodes.is_empty());
+        let root_kinds: Vec<_> = ncx
+            .region_ref(ncx.root_region())
+            .nodes()
+            .map(|node| *node.kind())
+            .collect();
+        assert!(root_kinds.contains(&NodeKind::Op(Op::Neg)));
+        assert!(!root_kinds.contains(&NodeKind::Op(Op::Mul)));
+        assert_eq!(output, n_user.val_in(0).origin());
+
+        assert_eq!(0, ncx.place_by_branch_weights(&model));
+    }
+
+    #[test]
+    fn even_weights_keep_placement() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let n_mul = ncx
+            .node_builder(Op::Mul)
+            .operand(n_x.val_out(0))
+            .operand(n_x.val_out(0))
+            .finish();
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let mul_args = gamma.entry_var(n_mul.val_out(0));
+        let x_args = gamma.entry_var(n_x.val_out(0));
+        gamma.exit_var(&[mul_args[0], x_args[1]]);
+        let gamma = gamma.finish();
+
+        let model = Model {
+            gamma: gamma.id(),
+            weights: vec![1, 1],
+        };
+        assert_eq!(0, ncx.place_by_branch_weights(&model));
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    cmp,
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    marker::PhantomData,
+    mem,
+};
+
+/// How many ports a chunk has room for, unless a node needs more.
+const CHUNK_LEN: usize = 1024;
+
+/// Storage that the ports of every node are carved out of, so that nodes
+/// don't make allocations of their own.
+///
+/// Nodes refer to their ports by `Ports`, ranges of the chunks of the pool
+/// that are read and written through it. Ranges a node outgrows or no
+/// longer needs are kept in a free list, by their capacity, and handed out
+/// again before the chunks are extended.
+///
+/// Every slot of a range holds a value, whether it's within the length of
+/// its ports or not: ranges are filled with defaults when taken from a
+/// chunk, and slots past the length are reset to the default.
+pub(super) struct PortPool<T> {
+    chunks: RefCell<Vec<Vec<T>>>,
+    free: RefCell<HashMap<u32, Vec<(u32, u32)>>>,
+}
+
+/// The ports of a node, as a range of a chunk of a `PortPool` along with
+/// room for more.
+///
+/// The range is owned by the ports, which only mean something to the pool
+/// they came from, and may only be grown or released by it.
+pub(super) struct Ports<T> {
+    chunk: u32,
+    start: u32,
+    len: u32,
+    cap: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Default> PortPool<T> {
+    pub(super) fn new() -> PortPool<T> {
+        PortPool {
+            chunks: RefCell::new(vec![]),
+            free: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The values of `ports`.
+    pub(super) fn get(&self, ports: &Ports<T>) -> Ref<'_, [T]> {
+        Ref::map(self.chunks.borrow(), |chunks| {
+            match chunks.get(ports.chunk as usize) {
+                Some(chunk) => &chunk[ports.range()],
+                None => &[],
+            }
+        })
+    }
+
+    /// The values of `ports`, to be changed in place.
+    pub(super) fn get_mut(&self, ports: &Ports<T>) -> RefMut<'_, [T]> {
+        RefMut::map(self.chunks.borrow_mut(), |chunks| {
+            match chunks.get_mut(ports.chunk as usize) {
+                Some(chunk) => &mut chunk[ports.range()],
+                None => &mut [],
+            }
+        })
+    }
+
+    /// Takes room for `cap` ports, reusing a released range of that size if
+    /// there is one.
+    pub(super) fn reserve(&self, cap: usize) -> Ports<T> {
+        if cap == 0 {
+            return Ports::default();
+        }
+        let cap = u32::try_from(cap).expect("too many ports for a node");
+        if let Some((chunk, start)) = self.free.borrow_mut().get_mut(&cap).and_then(Vec::pop) {
+            return Ports::new(chunk, start, cap);
+        }
+
+        let mut chunks = self.chunks.borrow_mut();
+        let fits = match chunks.last() {
+            Some(chunk) => chunk.capacity() - chunk.len() >= cap as usize,
+            None => false,
+        };
+        if !fits {
+            chunks.push(Vec::with_capacity(cmp::max(cap as usize, CHUNK_LEN)));
+        }
+        let chunk = u32::try_from(chunks.len() - 1).expect("too many ports");
+        let last = chunks.last_mut().unwrap();
+        let start = u32::try_from(last.len()).unwrap();
+        last.extend((0..cap).map(|_| T::default()));
+        Ports::new(chunk, start, cap)
+    }
+
+    /// Takes the ports that `values` yields.
+    pub(super) fn alloc(&self, values: impl Iterator<Item = T>) -> Ports<T> {
+        let mut ports = self.reserve(values.size_hint().0);
+        for value in values {
+            self.push(&mut ports, value);
+        }
+        ports
+    }
+
+    /// Adds `value` after the other ports, moving them to a larger range if
+    /// they have no room left, and releasing the range they leave.
+    pub(super) fn push(&self, ports: &mut Ports<T>, value: T) {
+        if ports.len == ports.cap {
+            let mut grown = self.reserve(cmp::max(4, 2 * ports.cap as usize));
+            {
+                let mut chunks = self.chunks.borrow_mut();
+                for offset in 0..ports.len() {
+                    let from = &mut chunks[ports.chunk as usize][ports.start as usize + offset];
+                    let moved = mem::take(from);
+                    chunks[grown.chunk as usize][grown.start as usize + offset] = moved;
+                }
+            }
+            grown.len = ports.len;
+            let outgrown = mem::replace(ports, grown);
+            self.release(outgrown);
+        }
+        ports.len += 1;
+        *self.get_mut(ports).last_mut().unwrap() = value;
+    }
+
+    /// Removes the port at `index` of `ports`, shifting the ones after it
+    /// down.
+    pub(super) fn remove(&self, ports: &mut Ports<T>, index: usize) -> T {
+        let mut values = self.get_mut(ports);
+        values[index..].rotate_left(1);
+        let removed = mem::take(values.last_mut().unwrap());
+        drop(values);
+        ports.len -= 1;
+        removed
+    }
+
+    /// Gives the range of `ports` back to the pool, to be handed out again.
+    pub(super) fn release(&self, ports: Ports<T>) {
+        if ports.cap == 0 {
+            return;
+        }
+        for value in self.get_mut(&ports).iter_mut() {
+            *value = T::default();
+        }
+        self.free
+            .borrow_mut()
+            .entry(ports.cap)
+            .or_default()
+            .push((ports.chunk, ports.start));
+    }
+}
+
+impl<T> Ports<T> {
+    fn new(chunk: u32, start: u32, cap: u32) -> Ports<T> {
+        Ports {
+            chunk,
+            start,
+            len: 0,
+            cap,
+            marker: PhantomData,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn range(&self) -> std::ops::Range<usize> {
+        self.start as usize..(self.start + self.len) as usize
+    }
+}
+
+impl<T> Default for Ports<T> {
+    fn default() -> Ports<T> {
+        Ports::new(0, 0, 0)
+    }
+}
+
+impl<T> fmt::Debug for Ports<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{:?}", self.chunk, self.range())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PortPool;
+
+    #[test]
+    fn growing_and_shrinking_ports() {
+        let pool = PortPool::new();
+        let mut a = pool.alloc(0..3);
+        let mut b = pool.alloc(10..12);
+        for value in 3..6 {
+            pool.push(&mut a, value);
+        }
+        pool.push(&mut b, 12);
+        assert_eq!(*pool.get(&a), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(*pool.get(&b), [10, 11, 12]);
+
+        assert_eq!(1, pool.remove(&mut a, 1));
+        pool.get_mut(&a)[0] = 7;
+        assert_eq!(*pool.get(&a), [7, 2, 3, 4, 5]);
+        pool.release(b);
+        let mut b = pool.reserve(0);
+        assert!(pool.get(&b).is_empty());
+        pool.push(&mut b, 13);
+        assert_eq!(*pool.get(&b), [13]);
+    }
+
+    #[test]
+    fn reusing_released_ranges() {
+        let pool = PortPool::new();
+        let mut a = pool.alloc(0..2);
+        // Growing `a` leaves its first two slots behind.
+        pool.push(&mut a, 2);
+        assert_eq!(6, pool.chunks.borrow()[0].len());
+
+        let b = pool.alloc(3..5);
+        assert_eq!(*pool.get(&b), [3, 4]);
+        assert_eq!(6, pool.chunks.borrow()[0].len());
+
+        pool.release(a);
+        let c = pool.alloc(5..9);
+        assert_eq!(*pool.get(&c), [5, 6, 7, 8]);
+        assert_eq!(*pool.get(&b), [3, 4]);
+        assert_eq!(6, pool.chunks.borrow()[0].len());
+    }
+}
@@ -0,0 +1,486 @@
+use super::{
+    NodeCtxt, NodeId, NodeKind, NodeMap, OriginId, PortKind, RegionId, RegionMap, Sig, SigS,
+};
+use std::{collections::HashMap, hash::Hash};
+
+/// Which regions of the graph touch state, and which lambdas are pure, as
+/// found by `NodeCtxt::effect_summary`.
+///
+/// The summary is a snapshot of the graph, computed once so that queries
+/// are cheap. It has to be computed again after the graph changes.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EffectSummary {
+    touches_state: RegionMap<bool>,
+    pure_lambdas: NodeMap<bool>,
+}
+
+impl EffectSummary {
+    /// Whether a node in `region_id`, or in the regions nested in it, has
+    /// state ports, other than the applies of pure lambdas. The bodies of
+    /// nested lambdas are only counted where they're called.
+    pub fn touches_state(&self, region_id: RegionId) -> bool {
+        self.touches_state.get(region_id) != Some(&false)
+    }
+
+    /// Whether the body of `lambda` doesn't touch state, passes its state
+    /// parameters on to its state results unchanged, and always returns, so
+    /// that calling it only depends on its value arguments.
+    pub fn is_pure(&self, lambda: NodeId) -> bool {
+        self.pure_lambdas.get(lambda) == Some(&true)
+    }
+}
+
+impl<S: Sig> NodeCtxt<S> {
+    /// Summarizes the effects of every region and lambda of the graph.
+    ///
+    /// Lambdas are summarized bottom-up in the call graph, so that calls
+    /// of pure lambdas don't count as touching state. Lambdas that loop,
+    /// recurse or call unknown functions may never return, and so are only
+    /// pure if the op assumes termination. Then, lambdas calling each other
+    /// are taken to be pure until one of them is found not to be.
+    pub fn effect_summary(&self) -> EffectSummary {
+        let call_graph = self.call_graph();
+        let mut pure_lambdas = NodeMap::new();
+        for scc in call_graph.sccs() {
+            let may_recurse = scc.iter().any(|&lambda| call_graph.is_recursive(lambda));
+            for &lambda in scc {
+                pure_lambdas.insert(lambda, S::assumes_termination() || !may_recurse);
+            }
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &lambda in scc {
+                    if pure_lambdas[lambda] && !self.is_pure_lambda(lambda, &pure_lambdas) {
+                        pure_lambdas[lambda] = false;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut touches_state = RegionMap::new();
+        let regions: Vec<RegionId> = self
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| !region.borrow().removed)
+            .map(|(index, _)| RegionId::new(index))
+            .collect();
+        for region_id in regions {
+            self.region_touches_state(region_id, &pure_lambdas, &mut touches_state);
+        }
+        EffectSummary {
+            touches_state,
+            pure_lambdas,
+        }
+    }
+
+    /// Removes the calls of pure lambdas whose value outputs are unused,
+    /// and the calls of a pure lambda with the same value arguments as an
+    /// earlier one in their region, which takes over their users. Returns
+    /// how many were removed.
+    ///
+    /// The state outputs of the calls are taken from their state arguments
+    /// instead, as the lambda passes them on unchanged.
+    pub fn simplify_pure_calls(&self) -> usize
+    where
+        S: Eq + Hash + Clone,
+    {
+        let summary = self.effect_summary();
+        let regions: Vec<RegionId> = self
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| !region.borrow().removed)
+            .map(|(index, _)| RegionId::new(index))
+            .collect();
+
+        let mut num_removed = 0;
+        for region_id in regions {
+            let mut calls: HashMap<(NodeId, Vec<OriginId>), NodeId> = HashMap::new();
+            for apply in self.region_topo_order(region_id) {
+                let lambda = match self.pure_callee(apply, &summary) {
+                    Some(lambda) => lambda,
+                    None => continue,
+                };
+                let sig = self.lambda_sig(lambda);
+                self.bypass_pure_call(apply, lambda, &summary);
+
+                let outputs = (0..sig.val_outs).map(|index| OriginId::output(apply, index));
+                let is_unused = outputs
+                    .clone()
+                    .all(|origin_id| self.origin_data(origin_id).num_users() == 0);
+                if !is_unused {
+                    let args = (1..=sig.val_ins)
+                        .map(|index| self.node_data(apply).ins[index].origin.get().unwrap())
+                        .collect();
+                    let earlier = *calls.entry((lambda, args)).or_insert(apply);
+                    if earlier == apply {
+                        continue;
+                    }
+                    for (index, origin_id) in outputs.enumerate() {
+                        self.replace_all_users(origin_id, OriginId::output(earlier, index));
+                    }
+                }
+                self.remove_node(apply);
+                num_removed += 1;
+            }
+        }
+        num_removed
+    }
+
+    /// The pure lambda `node_id` calls, if it's an apply node calling a
+    /// statically known one that takes its arguments.
+    fn pure_callee(&self, node_id: NodeId, summary: &EffectSummary) -> Option<NodeId> {
+        let node_data = self.node_data(node_id);
+        let apply_sig = match node_data.kind {
+            NodeKind::Apply {
+                arg_val_ins,
+                arg_st_ins,
+                region_val_res,
+                region_st_res,
+            } => SigS {
+                val_ins: arg_val_ins,
+                st_ins: arg_st_ins,
+                val_outs: region_val_res,
+                st_outs: region_st_res,
+            },
+            _ => return None,
+        };
+        let lambda = self.known_lambda(node_data.ins[0].origin.get()?)?;
+        if !summary.is_pure(lambda) || self.lambda_sig(lambda) != apply_sig {
+            return None;
+        }
+        if node_data.ins.iter().any(|user| user.origin.get().is_none()) {
+            return None;
+        }
+        Some(lambda)
+    }
+
+    /// Moves the users of the state outputs of a call of a pure lambda over
+    /// to the state arguments the lambda passes on to them.
+    fn bypass_pure_call(&self, apply: NodeId, lambda: NodeId, summary: &EffectSummary)
+    where
+        S: Eq + Hash + Clone,
+    {
+        let sig = self.lambda_sig(lambda);
+        for index in 0..sig.st_outs {
+            let st_param = self
+                .passed_on_state(lambda, index, &summary.pure_lambdas, &mut vec![])
+                .unwrap();
+            let state = self.node_data(apply).ins[1 + sig.val_ins + st_param]
+                .origin
+                .get()
+                .unwrap();
+            self.replace_all_users(OriginId::output(apply, sig.val_outs + index), state);
+        }
+    }
+
+    /// Whether `lambda` is pure, given which of the lambdas it calls are.
+    fn is_pure_lambda(&self, lambda: NodeId, pure_lambdas: &NodeMap<bool>) -> bool {
+        let body = self.inner_regions(lambda)[0];
+        if self.region_touches_state(body, pure_lambdas, &mut RegionMap::new()) {
+            return false;
+        }
+        if !S::assumes_termination() && self.may_not_return(body) {
+            return false;
+        }
+        let st_outs = self.lambda_sig(lambda).st_outs;
+        (0..st_outs).all(|index| {
+            self.passed_on_state(lambda, index, pure_lambdas, &mut vec![])
+                .is_some()
+        })
+    }
+
+    /// The state parameter of `lambda` that its state result `st_result` is
+    /// connected to, directly or through the calls of pure lambdas passing
+    /// it on. Lambdas in `visiting` are being followed already, and give up
+    /// rather than going around in circles.
+    fn passed_on_state(
+        &self,
+        lambda: NodeId,
+        st_result: usize,
+        pure_lambdas: &NodeMap<bool>,
+        visiting: &mut Vec<NodeId>,
+    ) -> Option<usize> {
+        if visiting.contains(&lambda) {
+            return None;
+        }
+        let params = match self.node_data(lambda).kind {
+            NodeKind::Lambda { params, .. } => params,
+            _ => unreachable!(),
+        };
+        let body = self.inner_regions(lambda)[0];
+        let mut origin_id = {
+            let region_data = self.region_data(body);
+            let mut st_res = region_data
+                .res
+                .iter()
+                .filter(|res| res.kind == PortKind::St);
+            st_res.nth(st_result)?.origin.get()?
+        };
+
+        visiting.push(lambda);
+        let st_param = loop {
+            match origin_id {
+                OriginId::Arg { .. } if origin_id.index() < params => {
+                    let args = &self.region_data(body).args[..origin_id.index()];
+                    break Some(args.iter().filter(|arg| arg.kind == PortKind::St).count());
+                }
+                OriginId::Out { node, .. } => {
+                    let (val_ins, val_outs) = match self.node_data(node).kind {
+                        NodeKind::Apply {
+                            arg_val_ins,
+                            region_val_res,
+                            ..
+                        } => (arg_val_ins, region_val_res),
+                        _ => break None,
+                    };
+                    let function = self.node_data(node).ins[0].origin.get();
+                    let callee = match function.and_then(|function| self.known_lambda(function)) {
+                        Some(callee) if pure_lambdas.get(callee) == Some(&true) => callee,
+                        _ => break None,
+                    };
+                    let index = origin_id.index() - val_outs;
+                    let st_param = match self.passed_on_state(callee, index, pure_lambdas, visiting)
+                    {
+                        Some(st_param) => st_param,
+                        None => break None,
+                    };
+                    origin_id = match self.node_data(node).ins[1 + val_ins + st_param]
+                        .origin
+                        .get()
+                    {
+                        Some(origin_id) => origin_id,
+                        None => break None,
+                    };
+                }
+                OriginId::Arg { .. } => break None,
+            }
+        };
+        visiting.pop();
+        st_param
+    }
+
+    /// Whether `region_id`, or a region nested in it, holds a theta or a call
+    /// of an unknown function, either of which may never return. Calls of
+    /// known lambdas are left to their own summary.
+    fn may_not_return(&self, region_id: RegionId) -> bool {
+        self.region_nodes(region_id)
+            .into_iter()
+            .any(|node_id| match self.node_data(node_id).kind {
+                NodeKind::Theta { .. } => true,
+                NodeKind::Apply { .. } => {
+                    let function = self.node_data(node_id).ins[0].origin.get();
+                    function
+                        .and_then(|origin_id| self.known_lambda(origin_id))
+                        .is_none()
+                }
+                NodeKind::Gamma { .. } => self
+                    .inner_regions(node_id)
+                    .into_iter()
+                    .any(|inner_region| self.may_not_return(inner_region)),
+                NodeKind::Op(..) | NodeKind::Lambda { .. } | NodeKind::Omega { .. } => false,
+            })
+    }
+
+    /// Whether a node in `region_id`, or in the regions nested in it, touches
+    /// state, recording the answer for each region visited in `cache`.
+    fn region_touches_state(
+        &self,
+        region_id: RegionId,
+        pure_lambdas: &NodeMap<bool>,
+        cache: &mut RegionMap<bool>,
+    ) -> bool {
+        if let Some(&touches) = cache.get(region_id) {
+            return touches;
+        }
+        let mut touches = false;
+        for node_id in self.region_nodes(region_id) {
+            let node_data = self.node_data(node_id);
+            let sig = node_data.kind.sig();
+            let has_state = sig.st_ins + sig.st_outs > 0;
+            let node_touches = match node_data.kind {
+                NodeKind::Op(..) => has_state,
+                NodeKind::Apply { .. } => {
+                    let function = node_data.ins[0].origin.get();
+                    let callee = function.and_then(|origin_id| self.known_lambda(origin_id));
+                    match callee {
+                        Some(lambda) => pure_lambdas.get(lambda) != Some(&true),
+                        None => has_state,
+                    }
+                }
+                NodeKind::Lambda { .. } => {
+                    self.region_touches_state(self.inner_regions(node_id)[0], pure_lambdas, cache);
+                    false
+                }
+                NodeKind::Gamma { .. } | NodeKind::Theta { .. } | NodeKind::Omega { .. } => {
+                    // Every region is visited, so that each gets summarized.
+                    let mut inner = false;
+                    for inner_region in self.inner_regions(node_id) {
+                        inner |= self.region_touches_state(inner_region, pure_lambdas, cache);
+                    }
+                    has_state || inner
+                }
+            };
+            touches |= node_touches;
+        }
+        cache.insert(region_id, touches);
+        touches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, PortKind, SigS};
+
+    const CALL: SigS = SigS {
+        val_ins: 1,
+        st_ins: 1,
+        val_outs: 1,
+        st_outs: 1,
+    };
+
+    #[test]
+    fn summarizing_effects() {
+        let ncx = NodeCtxt::<Op>::new();
+        let root = ncx.root_region();
+
+        // Passes the state on, as pure lambdas called in order do.
+        let double = ncx.lambda_builder(root, &[PortKind::Val, PortKind::St]);
+        let n_sum = ncx
+            .node_builder(Op::Add)
+            .operand(double.val_param(0))
+            .operand(double.val_param(0))
+            .finish();
+        let st = double.st_param(0);
+        let double = double.finish(&[n_sum.val_out(0)], &[st]);
+
+        let load = ncx.lambda_builder(root, &[PortKind::Val, PortKind::St]);
+        let n_load = ncx
+            .node_builder(Op::Load(0))
+            .operand(load.val_param(0))
+            .state(load.st_param(0))
+            .finish();
+        let load = load.finish(&[n_load.val_out(0)], &[n_load.st_out(0)]);
+
+        // Only calls the pure lambda, so it's pure too.
+        let caller = ncx.lambda_builder(root, &[PortKind::Val, PortKind::St]);
+        let f = caller.ctx_var(double.val_out(0));
+        let n_call = ncx
+            .apply_builder(f, CALL)
+            .operand(caller.val_param(0))
+            .state(caller.st_param(0))
+            .finish();
+        let caller_body = caller.body();
+        let caller = caller.finish(&[n_call.val_out(0)], &[n_call.st_out(0)]);
+
+        let summary = ncx.effect_summary();
+        assert!(summary.is_pure(double.id()));
+        assert!(!summary.is_pure(load.id()));
+        assert!(summary.is_pure(caller.id()));
+        assert!(!summary.touches_state(ncx.inner_regions(double.id())[0]));
+        assert!(summary.touches_state(ncx.inner_regions(load.id())[0]));
+        assert!(!summary.touches_state(caller_body));
+        assert!(!summary.touches_state(root));
+    }
+
+    #[test]
+    fn removing_and_deduplicating_pure_calls() {
+        let ncx = NodeCtxt::<Op>::new();
+        let root = ncx.root_region();
+
+        let double = ncx.lambda_builder(root, &[PortKind::Val, PortKind::St]);
+        let n_sum = ncx
+            .node_builder(Op::Add)
+            .operand(double.val_param(0))
+            .operand(double.val_param(0))
+            .finish();
+        let st = double.st_param(0);
+        let double = double.finish(&[n_sum.val_out(0)], &[st]);
+
+        let n_init = ncx.mk_node(Op::St);
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let call = |arg, state| {
+            ncx.apply_builder(double.val_out(0), CALL)
+                .operand(arg)
+                .state(state)
+                .finish()
+        };
+        let n_dead = call(n_one.val_out(0), n_init.st_out(0));
+        let n_first = call(n_one.val_out(0), n_dead.st_out(0));
+        let n_second = call(n_one.val_out(0), n_first.st_out(0));
+        let n_load = ncx
+            .node_builder(Op::Load(0))
+            .operand(n_second.val_out(0))
+            .state(n_second.st_out(0))
+            .finish();
+        let n_use = ncx
+            .node_builder(Op::Add)
+            .operand(n_first.val_out(0))
+            .operand(n_load.val_out(0))
+            .finish();
+
+        assert_eq!(2, ncx.simplify_pure_calls());
+        assert!(ncx.node_data(n_dead.id()).removed);
+        assert!(ncx.node_data(n_second.id()).removed);
+        assert_eq!(n_first.val_out(0), n_load.val_in(0).origin());
+        assert_eq!(n_init.st_out(0), n_load.st_in(0).origin());
+        assert_eq!(n_init.st_out(0), n_first.st_in(0).origin());
+        assert_eq!(n_first.val_out(0), n_use.val_in(0).origin());
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn calls_that_may_not_return_are_kept() {
+        let ncx = NodeCtxt::<Op>::new();
+        let root = ncx.root_region();
+
+        // Calls itself forever, without touching state.
+        let forever = ncx.lambda_builder(root, &[PortKind::Val, PortKind::St]);
+        let itself = forever.ctx_var(forever.node().val_out(0));
+        let n_call = ncx
+            .apply_builder(itself, CALL)
+            .operand(forever.val_param(0))
+            .state(forever.st_param(0))
+            .finish();
+        let st = forever.st_param(0);
+        let forever = forever.finish(&[n_call.val_out(0)], &[st]);
+
+        // Counts down to zero, which never happens for negative arguments.
+        let countdown = ncx.lambda_builder(root, &[PortKind::Val, PortKind::St]);
+        let theta = ncx.theta_builder(countdown.body());
+        let (x, x_out) = theta.loop_var(countdown.val_param(0));
+        let n_one = ncx.node_builder_in(theta.body(), Op::Lit(1)).finish();
+        let n_next = ncx
+            .node_builder_in(theta.body(), Op::Sub)
+            .operand(x)
+            .operand(n_one.val_out(0))
+            .finish();
+        theta.set_next(x, n_next.val_out(0));
+        theta.finish(n_next.val_out(0));
+        let st = countdown.st_param(0);
+        let countdown = countdown.finish(&[x_out], &[st]);
+
+        let summary = ncx.effect_summary();
+        assert!(!summary.is_pure(forever.id()));
+        assert!(!summary.is_pure(countdown.id()));
+
+        let n_init = ncx.mk_node(Op::St);
+        let n_arg = ncx.mk_node(Op::Lit(-1));
+        let n_forever = ncx
+            .apply_builder(forever.val_out(0), CALL)
+            .operand(n_arg.val_out(0))
+            .state(n_init.st_out(0))
+            .finish();
+        let n_countdown = ncx
+            .apply_builder(countdown.val_out(0), CALL)
+            .operand(n_arg.val_out(0))
+            .state(n_forever.st_out(0))
+            .finish();
+
+        assert_eq!(0, ncx.simplify_pure_calls());
+        assert!(!ncx.node_data(n_forever.id()).removed);
+        assert!(!ncx.node_data(n_countdown.id()).removed);
+    }
+}
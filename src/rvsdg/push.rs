@@ -0,0 +1,375 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, RegionId, Sig, UserId};
+use std::hash::Hash;
+
+impl<S> NodeCtxt<S> {
+    /// Moves stateless nodes whose outputs are only passed into a gamma, and
+    /// only used in one of its branches, into that branch, so they're only
+    /// computed when it's taken. Returns how many nodes were moved.
+    ///
+    /// Their operands are passed into the branch in their place, which lets
+    /// the nodes computing them be moved in turn.
+    pub fn push_into_gammas(&self) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut num_pushed = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let num_nodes = self.nodes.len();
+            for node_id in (0..num_nodes).map(NodeId::new) {
+                if let Some((gamma, branch)) = self.push_target(node_id) {
+                    self.push_node(node_id, gamma, branch);
+                    num_pushed += 1;
+                    changed = true;
+                }
+            }
+        }
+        num_pushed
+    }
+
+    /// Hoists stateless nodes that every branch of a gamma computes from the
+    /// same values out of the gamma, passing their outputs in instead.
+    /// Returns how many nodes were hoisted.
+    ///
+    /// Values count as the same when they come from the same origin outside
+    /// of the gamma, even if through different entry variables. Interning
+    /// can't merge such nodes, since they're in different regions.
+    pub fn pull_out_of_gammas(&self) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut num_pulled = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let num_nodes = self.nodes.len();
+            for gamma in (0..num_nodes).map(NodeId::new) {
+                let is_gamma = {
+                    let node_data = self.node_data(gamma);
+                    !node_data.removed && matches!(node_data.kind, NodeKind::Gamma { .. })
+                };
+                if !is_gamma {
+                    continue;
+                }
+                while let Some(copies) = self.common_branch_nodes(gamma) {
+                    self.pull_nodes(gamma, &copies);
+                    num_pulled += 1;
+                    changed = true;
+                }
+            }
+        }
+        num_pulled
+    }
+
+    /// The gamma and the branch of it a node can be pushed into.
+    fn push_target(&self, node_id: NodeId) -> Option<(NodeId, RegionId)>
+    where
+        S: Sig,
+    {
+        let node_data = self.node_data(node_id);
+        if node_data.removed || !self.is_movable(node_id) {
+            return None;
+        }
+
+        let mut gamma = None;
+        let mut entries = vec![];
+        for (index, out) in node_data.outs.iter().enumerate() {
+            // Dead outputs are left for dead code elimination.
+            out.users.get()?;
+            let origin_id = OriginId::output(node_id, index);
+            for user in self.origin_ref(origin_id).users() {
+                match user.id() {
+                    UserId::In { node, index } if index > 0 => {
+                        if !matches!(self.node_data(node).kind, NodeKind::Gamma { .. })
+                            || gamma.is_some() && gamma != Some(node)
+                        {
+                            return None;
+                        }
+                        gamma = Some(node);
+                        // Skips the predicate.
+                        entries.push(index - 1);
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        let gamma = gamma?;
+
+        let mut used_in = self.inner_regions(gamma).into_iter().filter(|&branch| {
+            entries.iter().any(|&index| {
+                let arg = OriginId::Arg {
+                    region: branch,
+                    index,
+                };
+                self.origin_data(arg).users.get().is_some()
+            })
+        });
+        match (used_in.next(), used_in.next()) {
+            (Some(branch), None) => Some((gamma, branch)),
+            _ => None,
+        }
+    }
+
+    fn push_node(&self, node_id: NodeId, gamma: NodeId, branch: RegionId)
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let (kind, origins) = {
+            let node_data = self.node_data(node_id);
+            let origins: Vec<OriginId> = node_data
+                .ins
+                .iter()
+                .map(|user| user.origin.get().unwrap())
+                .collect();
+            (node_data.kind.clone(), origins)
+        };
+        let origins: Vec<OriginId> = origins
+            .into_iter()
+            .map(|origin_id| self.route_into(origin_id, branch))
+            .collect();
+        let copy = self.mk_node_in_region_with(branch, kind, &origins);
+
+        // The entries are removed from the last, so the indices of the ones
+        // left to remove don't shift.
+        let mut entries: Vec<(usize, usize)> = vec![];
+        let num_outs = self.node_data(node_id).outs.len();
+        for index in 0..num_outs {
+            let origin_id = OriginId::output(node_id, index);
+            for user in self.origin_ref(origin_id).users() {
+                if let UserId::In { index: input, .. } = user.id() {
+                    entries.push((usize::from(input) - 1, index));
+                }
+            }
+        }
+        entries.sort_unstable();
+        for &(entry, index) in entries.iter().rev() {
+            let arg = OriginId::argument(branch, entry);
+            self.replace_all_users(arg, OriginId::output(copy, index));
+            self.remove_gamma_entry(gamma, entry);
+        }
+        self.remove_node(node_id);
+    }
+
+    /// A node in each branch of a gamma, all of which compute the same thing
+    /// from the same values, or none if there isn't any.
+    fn common_branch_nodes(&self, gamma: NodeId) -> Option<Vec<NodeId>>
+    where
+        S: Sig + Eq,
+    {
+        let branches = self.inner_regions(gamma);
+        let (first, others) = branches.split_first()?;
+        self.region_nodes(*first).into_iter().find_map(|node_id| {
+            if !self.is_movable(node_id) {
+                return None;
+            }
+            let operands = self.context_operands(node_id)?;
+            let mut copies = vec![node_id];
+            for &branch in others {
+                let copy = self.region_nodes(branch).into_iter().find(|&other| {
+                    self.is_movable(other)
+                        && self.node_data(other).kind == self.node_data(node_id).kind
+                        && self.context_operands(other).as_ref() == Some(&operands)
+                })?;
+                copies.push(copy);
+            }
+            Some(copies)
+        })
+    }
+
+    /// Replaces the given nodes, one per branch of a gamma, with one node
+    /// before the gamma, whose outputs are passed in as entry variables.
+    fn pull_nodes(&self, gamma: NodeId, copies: &[NodeId])
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let region_id = self.node_data(gamma).outer_region;
+        let kind = self.node_data(copies[0]).kind.clone();
+        let origins = self.context_operands(copies[0]).unwrap();
+        let hoisted = self.mk_node_in_region_with(region_id, kind, &origins);
+
+        let num_outs = self.node_data(hoisted).outs.len();
+        for index in 0..num_outs {
+            let args = self.add_gamma_entry(gamma, OriginId::output(hoisted, index));
+            for (&copy, arg) in copies.iter().zip(args) {
+                self.replace_all_users(OriginId::output(copy, index), arg);
+            }
+        }
+        for &copy in copies {
+            self.remove_node(copy);
+        }
+    }
+
+    /// Whether a node can be moved across region boundaries without changing
+    /// what it computes, which means it has no state ports or regions.
+    fn is_movable(&self, node_id: NodeId) -> bool
+    where
+        S: Sig,
+    {
+        let node_data = self.node_data(node_id);
+        let sig = node_data.kind.sig();
+        matches!(node_data.kind, NodeKind::Op(..)) && sig.st_ins == 0 && sig.st_outs == 0
+    }
+
+    /// The origins outside of a gamma passed in to the inputs of a node in
+    /// one of its branches, provided they're all entry variables.
+    fn context_operands(&self, node_id: NodeId) -> Option<Vec<OriginId>> {
+        let node_data = self.node_data(node_id);
+        let region_id = node_data.outer_region;
+        let gamma = self.region_data(region_id).node?;
+        node_data
+            .ins
+            .iter()
+            .map(|user| match user.origin.get()? {
+                // Skips the predicate.
+                OriginId::Arg { region, index } if region == region_id => self
+                    .user_data(UserId::In {
+                        node: gamma,
+                        index: index + 1,
+                    })
+                    .origin
+                    .get(),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn pushing_chains_into_a_branch() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Param(0));
+        let n_x = ncx.mk_node(Op::Lit(2));
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_x.val_out(0)).finish();
+        let n_sum = ncx
+            .node_builder(Op::Add)
+            .operand(n_neg.val_out(0))
+            .operand(n_pred.val_out(0))
+            .finish();
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let sums = gamma.entry_var(n_sum.val_out(0));
+        let preds = gamma.entry_var(n_pred.val_out(0));
+        let out = gamma.exit_var(&[sums[0], preds[1]]);
+        let gamma = gamma.finish();
+        ncx.node_builder(Op::Neg).operand(out).finish();
+
+        // The literal is pushed too, once the negation is.
+        assert_eq!(3, ncx.push_into_gammas());
+
+        let branch = ncx.region_ref(ncx.inner_regions(gamma.id())[0]);
+        let kinds: Vec<_> = branch.topo_iter().map(|node| *node.kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                NodeKind::Op(Op::Lit(2)),
+                NodeKind::Op(Op::Neg),
+                NodeKind::Op(Op::Add)
+            ]
+        );
+        // Only the predicate is left to be passed in.
+        assert_eq!(gamma.data().ins.len(), 2);
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn nodes_used_in_several_branches_stay() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Param(0));
+        let n_neg = ncx
+            .node_builder(Op::Neg)
+            .operand(n_pred.val_out(0))
+            .finish();
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let negs = gamma.entry_var(n_neg.val_out(0));
+        gamma.exit_var(&[negs[0], negs[1]]);
+        gamma.finish();
+
+        assert_eq!(0, ncx.push_into_gammas());
+    }
+
+    #[test]
+    fn pulling_common_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Param(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let preds = gamma.entry_var(n_pred.val_out(0));
+        let negs: Vec<_> = (0..2)
+            .map(|branch| {
+                ncx.node_builder_in(gamma.branch(branch), Op::Neg)
+                    .operand(preds[branch])
+                    .finish()
+            })
+            .collect();
+        let n_one = ncx.node_builder_in(gamma.branch(1), Op::Lit(1)).finish();
+        let n_sum = ncx
+            .node_builder_in(gamma.branch(1), Op::Add)
+            .operand(negs[1].val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        let out = gamma.exit_var(&[negs[0].val_out(0), n_sum.val_out(0)]);
+        let gamma = gamma.finish();
+        ncx.node_builder(Op::Neg).operand(out).finish();
+
+        // The literal is only in one branch, so it stays there.
+        assert_eq!(1, ncx.pull_out_of_gammas());
+
+        let branches = ncx.inner_regions(gamma.id());
+        assert_eq!(ncx.region_nodes(branches[0]), []);
+        assert_eq!(ncx.region_nodes(branches[1]), [n_one.id(), n_sum.id()]);
+        let hoisted = gamma.val_in(2).origin().producer();
+        assert_eq!(*hoisted.kind(), NodeKind::Op(Op::Neg));
+        assert_eq!(hoisted.val_in(0).origin(), n_pred.val_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn pulling_through_different_entries() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Param(0));
+        let n_x = ncx.mk_node(Op::Lit(3));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let xs = gamma.entry_var(n_x.val_out(0));
+        let more_xs = gamma.entry_var(n_x.val_out(0));
+        let preds = gamma.entry_var(n_pred.val_out(0));
+        let n_neg0 = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(xs[0])
+            .finish();
+        let n_neg1 = ncx
+            .node_builder_in(gamma.branch(1), Op::Neg)
+            .operand(more_xs[1])
+            .finish();
+        // These add different values.
+        let n_sum0 = ncx
+            .node_builder_in(gamma.branch(0), Op::Add)
+            .operand(xs[0])
+            .operand(preds[0])
+            .finish();
+        let n_sum1 = ncx
+            .node_builder_in(gamma.branch(1), Op::Add)
+            .operand(xs[1])
+            .operand(more_xs[1])
+            .finish();
+        gamma.exit_var(&[n_neg0.val_out(0), n_neg1.val_out(0)]);
+        gamma.exit_var(&[n_sum0.val_out(0), n_sum1.val_out(0)]);
+        let gamma = gamma.finish();
+
+        assert_eq!(1, ncx.pull_out_of_gammas());
+
+        let branches = ncx.inner_regions(gamma.id());
+        assert_eq!(ncx.region_nodes(branches[0]), [n_sum0.id()]);
+        assert_eq!(ncx.region_nodes(branches[1]), [n_sum1.id()]);
+        let hoisted = gamma.val_in(4).origin().producer();
+        assert_eq!(*hoisted.kind(), NodeKind::Op(Op::Neg));
+        assert_eq!(hoisted.val_in(0).origin(), n_x.val_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+}
@@ -0,0 +1,323 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, RegionId, Sig, ValOrigin};
+use std::hash::Hash;
+
+/// A tree of stateless nodes to look for, rooted at the value of a node.
+#[derive(Clone, Debug)]
+pub enum Pattern<S> {
+    /// Matches any value and binds it to the variable. Every use of a
+    /// variable in a pattern must match the same value.
+    Var(usize),
+    /// Matches the value of a node of the op, whose operands match the
+    /// patterns.
+    Op(S, Vec<Pattern<S>>),
+}
+
+/// A tree of nodes to build in place of a matched pattern.
+#[derive(Clone, Debug)]
+pub enum Replacement<S> {
+    /// The value bound to the variable by the pattern.
+    Var(usize),
+    /// A node of the op, taking the replacements as operands.
+    Op(S, Vec<Replacement<S>>),
+}
+
+/// A set of peephole rewrite rules.
+pub struct Rewriter<S> {
+    rules: Vec<(Pattern<S>, Replacement<S>)>,
+}
+
+impl<S> Default for Rewriter<S> {
+    fn default() -> Rewriter<S> {
+        Rewriter { rules: vec![] }
+    }
+}
+
+impl<S> Rewriter<S> {
+    pub fn new() -> Rewriter<S> {
+        Rewriter::default()
+    }
+
+    /// Adds a rule replacing values matching `pattern` with `replacement`.
+    /// Rules are tried in the order they were added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is a variable, or if `replacement` uses a
+    /// variable `pattern` doesn't bind.
+    pub fn rule(mut self, pattern: Pattern<S>, replacement: Replacement<S>) -> Rewriter<S> {
+        assert!(
+            matches!(pattern, Pattern::Op(..)),
+            "patterns must be rooted at an op"
+        );
+        let mut bound = vec![];
+        pattern.collect_vars(&mut bound);
+        replacement.check_vars(&bound);
+        self.rules.push((pattern, replacement));
+        self
+    }
+
+    /// The rules, in the order they're tried.
+    pub fn rules(&self) -> &[(Pattern<S>, Replacement<S>)] {
+        &self.rules
+    }
+
+    /// Applies the rules to stateless nodes with a single value output until
+    /// none matches any more. Returns how many nodes were rewritten.
+    ///
+    /// The users of a rewritten node are diverted to its replacement, and
+    /// the node is removed. The nodes it used are left for dead code
+    /// elimination to deal with. Rules that keep on matching what they
+    /// build never stop.
+    pub fn rewrite(&self, ncx: &NodeCtxt<S>) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut num_rewritten = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let num_nodes = ncx.nodes.len();
+            for node_id in (0..num_nodes).map(NodeId::new) {
+                if self.rewrite_node(ncx, node_id) {
+                    num_rewritten += 1;
+                    changed = true;
+                }
+            }
+        }
+        num_rewritten
+    }
+
+    fn rewrite_node(&self, ncx: &NodeCtxt<S>, node_id: NodeId) -> bool
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        if ncx.node_data(node_id).removed {
+            return false;
+        }
+        let origin_id = OriginId::Out {
+            node: node_id,
+            index: 0,
+        };
+        let region_id = ncx.node_data(node_id).outer_region;
+        for (pattern, replacement) in &self.rules {
+            let mut bindings = vec![];
+            if !ncx.matches(pattern, origin_id, &mut bindings) {
+                continue;
+            }
+            let new_origin_id = ncx.build_replacement(region_id, replacement, &bindings);
+            // Building the root again would only find it interned.
+            if new_origin_id == origin_id {
+                continue;
+            }
+            ncx.replace_all_users(origin_id, new_origin_id);
+            ncx.remove_node(node_id);
+            return true;
+        }
+        false
+    }
+}
+
+impl<S> Pattern<S> {
+    fn collect_vars(&self, vars: &mut Vec<usize>) {
+        match self {
+            Pattern::Var(var) => vars.push(*var),
+            Pattern::Op(_, operands) => {
+                for operand in operands {
+                    operand.collect_vars(vars);
+                }
+            }
+        }
+    }
+}
+
+impl<S> Replacement<S> {
+    fn check_vars(&self, bound: &[usize]) {
+        match self {
+            Replacement::Var(var) => {
+                assert!(bound.contains(var), "variable {} isn't bound", var);
+            }
+            Replacement::Op(_, operands) => {
+                for operand in operands {
+                    operand.check_vars(bound);
+                }
+            }
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Whether the value at `origin_id` matches `pattern`, binding the
+    /// variables of the pattern in `bindings`.
+    fn matches(
+        &self,
+        pattern: &Pattern<S>,
+        origin_id: OriginId,
+        bindings: &mut Vec<Option<OriginId>>,
+    ) -> bool
+    where
+        S: Sig + Eq,
+    {
+        match pattern {
+            Pattern::Var(var) => {
+                if bindings.len() <= *var {
+                    bindings.resize(var + 1, None);
+                }
+                match bindings[*var] {
+                    Some(bound) => bound == origin_id,
+                    None => {
+                        bindings[*var] = Some(origin_id);
+                        true
+                    }
+                }
+            }
+            Pattern::Op(op, operands) => {
+                let node_id = match origin_id {
+                    OriginId::Out { node, index: 0 } => node,
+                    _ => return false,
+                };
+                let node_data = self.node_data(node_id);
+                let is_match = match node_data.kind {
+                    NodeKind::Op(ref node_op) => {
+                        let sig = node_op.sig();
+                        node_op == op
+                            && sig.st_ins == 0
+                            && sig.st_outs == 0
+                            && sig.val_outs == 1
+                            && node_data.ins.len() == operands.len()
+                    }
+                    _ => false,
+                };
+                if !is_match || node_data.removed {
+                    return false;
+                }
+                node_data
+                    .ins
+                    .iter()
+                    .zip(operands)
+                    .all(|(user, operand)| match user.origin.get() {
+                        Some(origin_id) => self.matches(operand, origin_id, bindings),
+                        None => false,
+                    })
+            }
+        }
+    }
+
+    fn build_replacement(
+        &self,
+        region_id: RegionId,
+        replacement: &Replacement<S>,
+        bindings: &[Option<OriginId>],
+    ) -> OriginId
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        match replacement {
+            Replacement::Var(var) => bindings[*var].unwrap(),
+            Replacement::Op(op, operands) => {
+                let builder = self.node_builder_in(region_id, op.clone());
+                let builder = operands.iter().fold(builder, |builder, operand| {
+                    let origin_id = self.build_replacement(region_id, operand, bindings);
+                    builder.operand(ValOrigin(self.origin_ref(origin_id)))
+                });
+                builder.finish().val_out(0).id()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Pattern as P, Replacement as R, Rewriter};
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    fn rewriter() -> Rewriter<Op> {
+        Rewriter::new()
+            .rule(
+                P::Op(Op::Neg, vec![P::Op(Op::Neg, vec![P::Var(0)])]),
+                R::Var(0),
+            )
+            .rule(
+                P::Op(Op::Add, vec![P::Var(0), P::Op(Op::Lit(0), vec![])]),
+                R::Var(0),
+            )
+            .rule(
+                P::Op(Op::Sub, vec![P::Var(0), P::Var(0)]),
+                R::Op(Op::Lit(0), vec![]),
+            )
+            .rule(
+                P::Op(Op::Mul, vec![P::Var(0), P::Op(Op::Lit(2), vec![])]),
+                R::Op(Op::Add, vec![R::Var(0), R::Var(0)]),
+            )
+    }
+
+    #[test]
+    fn rewriting_nested_patterns() {
+        let ncx = NodeCtxt::new();
+
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_neg = ncx
+            .node_builder(Op::Neg)
+            .operand(n_param.val_out(0))
+            .finish();
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_neg.val_out(0)).finish();
+        let n_zero = ncx.mk_node(Op::Lit(0));
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_neg.val_out(0))
+            .operand(n_zero.val_out(0))
+            .finish();
+        let n_user = ncx.node_builder(Op::Neg).operand(n_add.val_out(0)).finish();
+
+        // The addition goes first, then the double negation it used.
+        assert_eq!(2, rewriter().rewrite(&ncx));
+        assert_eq!(n_user.val_in(0).origin(), n_param.val_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn repeated_variables_and_built_replacements() {
+        let ncx = NodeCtxt::new();
+
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_two = ncx.mk_node(Op::Lit(2));
+        let n_mul = ncx
+            .node_builder(Op::Mul)
+            .operand(n_param.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+        let n_sub = ncx
+            .node_builder(Op::Sub)
+            .operand(n_mul.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+        let n_user = ncx.node_builder(Op::Neg).operand(n_sub.val_out(0)).finish();
+
+        assert_eq!(1, rewriter().rewrite(&ncx));
+        let n_sub = n_user.val_in(0).origin().producer();
+        assert_eq!(*n_sub.kind(), NodeKind::Op(Op::Sub));
+        let n_add = n_sub.val_in(0).origin().producer();
+        assert_eq!(*n_add.kind(), NodeKind::Op(Op::Add));
+        assert_eq!(n_add.val_in(0).origin(), n_param.val_out(0));
+        assert_eq!(n_add.val_in(1).origin(), n_param.val_out(0));
+
+        let n_diff = ncx
+            .node_builder(Op::Sub)
+            .operand(n_add.val_out(0))
+            .operand(n_add.val_out(0))
+            .finish();
+        let n_user = ncx
+            .node_builder(Op::Neg)
+            .operand(n_diff.val_out(0))
+            .finish();
+        assert_eq!(1, rewriter().rewrite(&ncx));
+        let n_lit = n_user.val_in(0).origin().producer();
+        assert_eq!(*n_lit.kind(), NodeKind::Op(Op::Lit(0)));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    #[should_panic(expected = "variable 1 isn't bound")]
+    fn unbound_variables() {
+        Rewriter::<Op>::new().rule(P::Op(Op::Neg, vec![P::Var(0)]), R::Var(1));
+    }
+}
@@ -0,0 +1,309 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, RegionId, UserId};
+
+impl<S> NodeCtxt<S> {
+    /// Makes `origin_id` usable in `region_id`, which must be nested in the
+    /// region of the origin, returning the argument that carries it there.
+    ///
+    /// Entry variables, invariant loop variables for thetas, or context
+    /// variables for lambdas, are added to every structural node on the way
+    /// down, unless there's already one carrying the same value.
+    pub fn route_into(&self, origin_id: OriginId, region_id: RegionId) -> OriginId {
+        let origin_region = self.origin_region(origin_id);
+
+        let mut path = vec![];
+        let mut region = region_id;
+        while region != origin_region {
+            path.push(region);
+            let node_id = self.region_data(region).node.unwrap_or_else(|| {
+                panic!(
+                    "{:?} isn't nested in the region of {:?}",
+                    region_id, origin_id
+                )
+            });
+            region = self.node_data(node_id).outer_region;
+        }
+
+        path.into_iter()
+            .rev()
+            .fold(origin_id, |origin_id, region_id| {
+                self.route_into_inner_region(origin_id, region_id)
+            })
+    }
+
+    /// Makes `origin_id` usable in `region_id`, which must enclose the region
+    /// of the origin, returning the output that carries it there.
+    ///
+    /// Arguments of entry variables, and of invariant loop variables, are
+    /// mapped back to the origins passed in. Otherwise an exit variable is
+    /// added to every gamma on the way out, whose results in the other
+    /// branches are left for the caller to connect, and a loop variable to
+    /// every theta, unless one already has the value as its next value. The
+    /// output of a theta is the value from its last iteration, so the input
+    /// of a loop variable added this way is never observed and left
+    /// unconnected.
+    pub fn route_out_of(&self, origin_id: OriginId, region_id: RegionId) -> OriginId {
+        let mut path = vec![];
+        let mut region = self.origin_region(origin_id);
+        while region != region_id {
+            path.push(region);
+            let node_id = self.region_data(region).node.unwrap_or_else(|| {
+                panic!(
+                    "the region of {:?} isn't nested in {:?}",
+                    origin_id, region_id
+                )
+            });
+            region = self.node_data(node_id).outer_region;
+        }
+
+        path.into_iter().fold(origin_id, |origin_id, region_id| {
+            self.route_out_of_inner_region(origin_id, region_id)
+        })
+    }
+
+    /// Routes an origin from the enclosing region of a structural node into
+    /// one of its regions.
+    fn route_into_inner_region(&self, origin_id: OriginId, region_id: RegionId) -> OriginId {
+        let node_id = self.region_data(region_id).node.unwrap();
+        let num_inputs = self.node_data(node_id).ins.len();
+        let is_input = |index: usize| {
+            self.user_data(UserId::input(node_id, index)).origin.get() == Some(origin_id)
+        };
+
+        let lambda_params = match self.node_data(node_id).kind {
+            NodeKind::Lambda { params, .. } => Some(params),
+            _ => None,
+        };
+        if let Some(params) = lambda_params {
+            // Context variables follow the parameters.
+            return match (0..num_inputs).find(|&index| is_input(index)) {
+                Some(index) => OriginId::argument(region_id, params + index),
+                None => self.add_lambda_ctx_var(node_id, origin_id),
+            };
+        }
+
+        let is_gamma = match self.node_data(node_id).kind {
+            NodeKind::Gamma { .. } => true,
+            NodeKind::Theta { .. } => false,
+            _ => panic!("values can only be routed into gamma, theta and lambda regions"),
+        };
+
+        if is_gamma {
+            // The first input is the predicate, which isn't passed to the
+            // branches.
+            match (1..num_inputs).find(|&index| is_input(index)) {
+                Some(index) => OriginId::argument(region_id, index - 1),
+                None => {
+                    let sequence_index = self.region_data(region_id).sequence_index;
+                    self.add_gamma_entry(node_id, origin_id)[sequence_index]
+                }
+            }
+        } else {
+            let loop_var_result = |index: usize| UserId::result(region_id, index + 1);
+            let is_invariant = |index: usize| {
+                let arg = OriginId::argument(region_id, index);
+                self.user_data(loop_var_result(index)).origin.get() == Some(arg)
+            };
+            match (0..num_inputs).find(|&index| is_input(index) && is_invariant(index)) {
+                Some(index) => OriginId::argument(region_id, index),
+                None => {
+                    let (arg, _) = self.add_theta_loop_var(node_id, origin_id);
+                    self.connect_ports(loop_var_result(num_inputs), arg);
+                    arg
+                }
+            }
+        }
+    }
+
+    /// Routes an origin from one of the regions of a structural node out to
+    /// the region enclosing it.
+    fn route_out_of_inner_region(&self, origin_id: OriginId, region_id: RegionId) -> OriginId {
+        let node_id = self.region_data(region_id).node.unwrap();
+        let kind = self.origin_data(origin_id).kind;
+        let input_origin =
+            |index: usize| self.user_data(UserId::input(node_id, index)).origin.get();
+
+        let is_gamma = match self.node_data(node_id).kind {
+            NodeKind::Gamma { .. } => true,
+            NodeKind::Theta { .. } => false,
+            _ => panic!("values can only be routed out of gamma and theta regions"),
+        };
+
+        if is_gamma {
+            if let OriginId::Arg { .. } = origin_id {
+                // Skips the predicate.
+                return input_origin(origin_id.index() + 1).unwrap();
+            }
+            let output = self.add_output(node_id, kind);
+            for branch in self.inner_regions(node_id) {
+                let result = self.add_result(branch, kind, Some(output));
+                if branch == region_id {
+                    self.connect_ports(result, origin_id);
+                }
+            }
+            self.count_ports(node_id, kind, 0, 1);
+            output
+        } else {
+            let num_inputs = self.node_data(node_id).ins.len();
+            let next_value = |index: usize| {
+                self.user_data(UserId::result(region_id, index + 1))
+                    .origin
+                    .get()
+            };
+            if let OriginId::Arg { .. } = origin_id {
+                if next_value(origin_id.index()) == Some(origin_id) {
+                    if let Some(init) = input_origin(origin_id.index()) {
+                        return init;
+                    }
+                }
+            }
+            match (0..num_inputs).find(|&index| next_value(index) == Some(origin_id)) {
+                Some(index) => OriginId::output(node_id, index),
+                None => self.add_theta_exit(node_id, origin_id),
+            }
+        }
+    }
+
+    /// Adds a loop variable to a theta whose next value is `origin_id`, with
+    /// an unconnected input, returning its output.
+    fn add_theta_exit(&self, theta_id: NodeId, origin_id: OriginId) -> OriginId {
+        let body = self.inner_regions(theta_id)[0];
+        let kind = self.origin_data(origin_id).kind;
+        let input = self.add_unconnected_input(theta_id, kind);
+        let output = self.add_output(theta_id, kind);
+        self.add_argument(body, kind, Some(input));
+        let result = self.add_result(body, kind, Some(output));
+        self.connect_ports(result, origin_id);
+        self.count_ports(theta_id, kind, 1, 1);
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind, OriginId, UserId, ValOrigin};
+
+    #[test]
+    fn routing_through_nested_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (pred_arg, _) = theta.loop_var(n_pred.val_out(0));
+        let gamma = ncx.gamma_builder(pred_arg, 2);
+        let branch = gamma.branch(1);
+        gamma.finish();
+        let body = theta.body();
+        let theta = theta.finish(pred_arg);
+
+        let routed = ncx.route_into(n_x.val_out(0).id(), branch);
+
+        assert_eq!(
+            OriginId::Arg {
+                region: branch,
+                index: 0
+            },
+            routed
+        );
+        assert_eq!(
+            NodeKind::Theta {
+                val_loop_vars: 2,
+                st_loop_vars: 0,
+            },
+            *theta.kind()
+        );
+        assert_eq!(n_x.val_out(0), theta.val_in(1).origin());
+
+        // Routing the same value again reuses the ports.
+        assert_eq!(routed, ncx.route_into(n_x.val_out(0).id(), branch));
+        assert_eq!(2, ncx.region_data(body).args.len());
+
+        let neg = ncx
+            .node_builder_in(branch, Op::Neg)
+            .operand(ValOrigin(
+                ncx.origin_ref(ncx.route_into(n_pred.val_out(0).id(), branch)),
+            ))
+            .finish();
+        assert_eq!(branch, neg.region());
+        assert_eq!(2, ncx.region_data(body).args.len());
+    }
+
+    #[test]
+    fn routing_out_of_nested_regions() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (pred_arg, _) = theta.loop_var(n_pred.val_out(0));
+        let gamma = ncx.gamma_builder(pred_arg, 2);
+        let branches = [gamma.branch(0), gamma.branch(1)];
+        let n_x = ncx.node_builder_in(branches[1], Op::Lit(1)).finish();
+        let gamma = gamma.finish();
+        let body = theta.body();
+        let theta = theta.finish(pred_arg);
+
+        let routed = ncx.route_out_of(n_x.val_out(0).id(), ncx.root_region());
+
+        assert_eq!(
+            OriginId::Out {
+                node: theta.id(),
+                index: 1
+            },
+            routed
+        );
+        assert_eq!(
+            NodeKind::Theta {
+                val_loop_vars: 2,
+                st_loop_vars: 0,
+            },
+            *theta.kind()
+        );
+        let result_origin =
+            |region, index| ncx.user_data(UserId::Res { region, index }).origin.get();
+        assert_eq!(Some(gamma.val_out(0).id()), result_origin(body, 2));
+        assert_eq!(Some(n_x.val_out(0).id()), result_origin(branches[1], 0));
+        assert_eq!(None, result_origin(branches[0], 0));
+
+        // The theta now has the gamma output as a next value, so it's reused.
+        assert_eq!(
+            routed,
+            ncx.route_out_of(gamma.val_out(0).id(), ncx.root_region())
+        );
+        assert_eq!(2, ncx.region_data(body).args.len());
+
+        // Invariant loop variables are mapped back to their inputs.
+        assert_eq!(
+            n_pred.val_out(0).id(),
+            ncx.route_out_of(pred_arg.id(), ncx.root_region())
+        );
+    }
+
+    #[test]
+    fn routing_skips_variant_loop_vars() {
+        let ncx = NodeCtxt::new();
+
+        let n_x = ncx.mk_node(Op::Lit(1));
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n_x.val_out(0));
+        let x_neg = ncx
+            .node_builder_in(theta.body(), Op::Neg)
+            .operand(x_arg)
+            .finish();
+        theta.set_next(x_arg, x_neg.val_out(0));
+        let body = theta.body();
+        theta.finish(x_arg);
+
+        let routed = ncx.route_into(n_x.val_out(0).id(), body);
+
+        assert_eq!(
+            OriginId::Arg {
+                region: body,
+                index: 1
+            },
+            routed
+        );
+    }
+}
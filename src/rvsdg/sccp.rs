@@ -0,0 +1,211 @@
+use super::{
+    ConstBranch, DataflowAnalysis, Direction, Fold, NodeCtxt, NodeKind, OriginId, PortKind, Sig,
+};
+use std::hash::Hash;
+
+/// What's known about a value while propagating constants.
+#[derive(Clone, PartialEq, Debug)]
+enum ConstFact<V> {
+    /// No value reaches it, as far as is known yet.
+    Undefined,
+    Const(V),
+    /// It may take different values.
+    Varying,
+}
+
+/// Constant propagation, folding the ops whose operands are constants.
+struct ConstPropagation;
+
+impl<S> DataflowAnalysis<S> for ConstPropagation
+where
+    S: Fold + ConstBranch + Sig,
+    S::ConstValue: Clone + PartialEq,
+{
+    type Fact = ConstFact<S::ConstValue>;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn bottom(&self) -> Self::Fact {
+        ConstFact::Undefined
+    }
+
+    fn top(&self) -> Self::Fact {
+        ConstFact::Varying
+    }
+
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        match (a, b) {
+            (ConstFact::Undefined, fact) | (fact, ConstFact::Undefined) => fact.clone(),
+            (a, b) if a == b => a.clone(),
+            _ => ConstFact::Varying,
+        }
+    }
+
+    fn transfer(&self, op: &S, facts: &[Self::Fact]) -> Vec<Self::Fact> {
+        let sig = op.sig();
+        let num_outs = sig.val_outs + sig.st_outs;
+        let value = if let Some(value) = op.as_const() {
+            Some(value)
+        } else if facts.contains(&ConstFact::Undefined) {
+            return vec![ConstFact::Undefined; num_outs];
+        } else if sig.st_ins == 0 && sig.st_outs == 0 && sig.val_outs == 1 {
+            let operands = facts
+                .iter()
+                .map(|fact| match fact {
+                    ConstFact::Const(value) => Some(value.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            operands.and_then(|operands| op.try_fold(&operands))
+        } else {
+            None
+        };
+        match value {
+            Some(value) => vec![ConstFact::Const(value)],
+            None => vec![ConstFact::Varying; num_outs],
+        }
+    }
+
+    fn taken_branch(&self, predicate: &Self::Fact) -> Option<usize> {
+        match predicate {
+            ConstFact::Const(value) => S::from_const(value.clone()).const_branch(),
+            _ => None,
+        }
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Propagates constants through the graph, gammas and thetas included,
+    /// and has the users of values found to be constant use the constant
+    /// instead. Then gammas whose predicate is a constant are replaced by
+    /// the branch they take. Returns how many values and gammas were
+    /// replaced.
+    ///
+    /// Branches that are never taken don't count towards the outputs of
+    /// their gamma, which finds constants that folding alone doesn't. Nodes
+    /// left unused are left for dead code elimination to deal with.
+    pub fn propagate_constants(&self) -> usize
+    where
+        S: Fold + ConstBranch + Sig + Eq + Hash + Clone,
+        S::ConstValue: Clone + PartialEq,
+    {
+        let solution = self.solve_dataflow(self.root_region(), &ConstPropagation);
+        let mut constants: Vec<(OriginId, S::ConstValue)> = solution
+            .iter()
+            .filter_map(|(origin_id, fact)| match fact {
+                ConstFact::Const(value) if self.can_become_const(origin_id) => {
+                    Some((origin_id, value.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        constants.sort_by_key(|&(origin_id, _)| origin_id);
+
+        for (origin_id, value) in &constants {
+            let region_id = self.origin_region(*origin_id);
+            let n_const = self
+                .node_builder_in(region_id, S::from_const(value.clone()))
+                .finish();
+            self.replace_all_users(*origin_id, n_const.val_out(0).id());
+        }
+        constants.len() + self.simplify_const_gammas()
+    }
+
+    /// Whether `origin_id` is a value that's used and isn't the output of
+    /// a constant already.
+    fn can_become_const(&self, origin_id: OriginId) -> bool
+    where
+        S: Fold,
+    {
+        let origin_data = self.origin_data(origin_id);
+        if origin_data.kind != PortKind::Val || origin_data.users.get().is_none() {
+            return false;
+        }
+        match origin_id {
+            OriginId::Out { node, .. } => match &self.node_data(node).kind {
+                NodeKind::Op(op) => op.as_const().is_none(),
+                _ => true,
+            },
+            OriginId::Arg { .. } => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn constants_through_gammas_and_thetas() {
+        let ncx = NodeCtxt::new();
+
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_param = ncx.mk_node(Op::Param(0));
+        let n_two = ncx
+            .node_builder(Op::Add)
+            .operand(n_one.val_out(0))
+            .operand(n_one.val_out(0))
+            .finish();
+        let n_pred = ncx
+            .node_builder(Op::Lt)
+            .operand(n_one.val_out(0))
+            .operand(n_two.val_out(0))
+            .finish();
+
+        // Only the second branch is ever taken, so the output is a constant
+        // even though the first branch passes the parameter out.
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let ones = gamma.entry_var(n_one.val_out(0));
+        let params = gamma.entry_var(n_param.val_out(0));
+        let n_sum = ncx
+            .node_builder(Op::Add)
+            .operand(ones[1])
+            .operand(ones[1])
+            .finish();
+        let out = gamma.exit_var(&[params[0], n_sum.val_out(0)]);
+        let gamma = gamma.finish();
+        let n_use = ncx
+            .node_builder(Op::Sub)
+            .operand(out)
+            .operand(n_param.val_out(0))
+            .finish();
+
+        // Adding zero keeps the loop variable at one.
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x, x_out) = theta.loop_var(n_one.val_out(0));
+        let n_zero = ncx.mk_node_in(theta.body(), Op::Lit(0));
+        let n_next = ncx
+            .node_builder(Op::Add)
+            .operand(x)
+            .operand(n_zero.val_out(0))
+            .finish();
+        let n_cond = ncx
+            .node_builder(Op::Lt)
+            .operand(x)
+            .operand(n_zero.val_out(0))
+            .finish();
+        theta.set_next(x, n_next.val_out(0));
+        theta.finish(n_cond.val_out(0));
+        let n_loop_use = ncx
+            .node_builder(Op::Sub)
+            .operand(x_out)
+            .operand(n_param.val_out(0))
+            .finish();
+
+        // The sum, the predicates of the gamma and of the loop, the entry
+        // and the sum in the taken branch, the gamma output, and the loop
+        // variable along with its next value and output, then the gamma.
+        assert_eq!(10, ncx.propagate_constants());
+        assert!(ncx.node_data(gamma.id()).removed);
+        assert_eq!(
+            NodeKind::Op(Op::Lit(2)),
+            *n_use.val_in(0).origin().producer().kind()
+        );
+        assert_eq!(n_one.val_out(0), n_loop_use.val_in(0).origin());
+        assert_eq!(
+            NodeKind::Op(Op::Lit(1)),
+            *n_cond.val_in(0).origin().producer().kind()
+        );
+        assert_eq!(Ok(()), ncx.verify());
+    }
+}
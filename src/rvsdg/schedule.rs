@@ -0,0 +1,167 @@
+use super::{NodeCtxt, NodeId, NodeMap, PortKind, RegionHeights, RegionId};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// Decides which of the nodes ready to be scheduled goes first.
+pub trait SchedulePriority {
+    /// How urgently `node_id` should be scheduled once the nodes whose
+    /// outputs it uses are. Ties go to the node that comes first in the
+    /// region.
+    fn priority(&self, node_id: NodeId) -> i64;
+}
+
+/// Schedules the nodes on the longest paths through the region first.
+impl SchedulePriority for RegionHeights {
+    fn priority(&self, node_id: NodeId) -> i64 {
+        self.height(node_id) as i64
+    }
+}
+
+/// Schedules the nodes that end the most values and start the fewest first,
+/// as an estimate of how many values are live at once, as found by
+/// `NodeCtxt::pressure_priority`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PressurePriority {
+    deltas: NodeMap<i64>,
+}
+
+impl SchedulePriority for PressurePriority {
+    fn priority(&self, node_id: NodeId) -> i64 {
+        self.deltas[node_id]
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Orders the nodes of `region_id` so that each comes after the nodes
+    /// whose outputs it uses through value or state edges, scheduling those
+    /// on the critical path of the region first.
+    pub fn schedule(&self, region_id: RegionId) -> Vec<NodeId> {
+        let heights = self.region_heights(region_id);
+        self.schedule_by(region_id, &heights)
+    }
+
+    /// Orders the nodes of `region_id` so that each comes after the nodes
+    /// whose outputs it uses, picking whichever of the nodes ready to be
+    /// scheduled `priority` puts first.
+    pub fn schedule_by<P>(&self, region_id: RegionId, priority: &P) -> Vec<NodeId>
+    where
+        P: SchedulePriority,
+    {
+        let nodes = self.region_nodes(region_id);
+        let mut positions = NodeMap::new();
+        let mut num_operands = NodeMap::new();
+        let mut users: NodeMap<Vec<NodeId>> = NodeMap::new();
+        for (position, &node_id) in nodes.iter().enumerate() {
+            positions.insert(node_id, position);
+            let mut operands = self.operand_nodes(node_id);
+            operands.sort();
+            operands.dedup();
+            num_operands.insert(node_id, operands.len());
+            for operand in operands {
+                users.get_or_insert_with(operand, Vec::new).push(node_id);
+            }
+        }
+
+        let mut ready: BinaryHeap<_> = nodes
+            .iter()
+            .filter(|&&node_id| num_operands[node_id] == 0)
+            .map(|&node_id| {
+                (
+                    priority.priority(node_id),
+                    Reverse(positions[node_id]),
+                    node_id,
+                )
+            })
+            .collect();
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some((_, _, node_id)) = ready.pop() {
+            order.push(node_id);
+            for &user in users.get(node_id).into_iter().flatten() {
+                num_operands[user] -= 1;
+                if num_operands[user] == 0 {
+                    ready.push((priority.priority(user), Reverse(positions[user]), user));
+                }
+            }
+        }
+        order
+    }
+
+    /// How much scheduling each node of `region_id` lowers the number of
+    /// values live at once: the values it's the only user of, less the
+    /// values it produces.
+    pub fn pressure_priority(&self, region_id: RegionId) -> PressurePriority {
+        let mut deltas = NodeMap::new();
+        for node_id in self.region_nodes(region_id) {
+            let node_data = self.node_data(node_id);
+            let ended = node_data
+                .ins
+                .iter()
+                .filter(|user| user.kind == PortKind::Val)
+                .filter_map(|user| user.origin.get())
+                .filter(|&origin_id| self.origin_data(origin_id).num_users() == 1)
+                .count();
+            let started = node_data
+                .outs
+                .iter()
+                .filter(|origin| origin.kind == PortKind::Val)
+                .count();
+            deltas.insert(node_id, ended as i64 - started as i64);
+        }
+        PressurePriority { deltas }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn scheduling_by_priority() {
+        let ncx = NodeCtxt::new();
+
+        let n_p = ncx.mk_node(Op::Lit(0));
+        let n_1 = ncx.node_builder(Op::Neg).operand(n_p.val_out(0)).finish();
+        let n_2 = ncx.node_builder(Op::Neg).operand(n_1.val_out(0)).finish();
+        let n_3 = ncx.node_builder(Op::Neg).operand(n_2.val_out(0)).finish();
+        let n_q = ncx.mk_node(Op::Lit(1));
+        let n_r = ncx.node_builder(Op::Neg).operand(n_q.val_out(0)).finish();
+        let region = ncx.root_region();
+
+        // The short chain is started as soon as it's as high as what's
+        // left of the long one.
+        assert_eq!(
+            vec![n_p.id(), n_1.id(), n_2.id(), n_q.id(), n_3.id(), n_r.id()],
+            ncx.schedule(region)
+        );
+        // Chains are finished before others are started, as that keeps
+        // fewer values live.
+        assert_eq!(
+            vec![n_p.id(), n_1.id(), n_2.id(), n_3.id(), n_q.id(), n_r.id()],
+            ncx.schedule_by(region, &ncx.pressure_priority(region))
+        );
+    }
+
+    #[test]
+    fn state_edges_order_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n_st = ncx.mk_node(Op::St);
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let n_store = ncx
+            .node_builder(Op::Store)
+            .operand(n_addr.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+        let n_store2 = ncx
+            .node_builder(Op::Store)
+            .operand(n_addr.val_out(0))
+            .state(n_store.st_out(0))
+            .finish();
+
+        let order = ncx.schedule(ncx.root_region());
+        assert_eq!(4, order.len());
+        let position = |node_id| order.iter().position(|&n| n == node_id).unwrap();
+        assert!(position(n_st.id()) < position(n_store.id()));
+        assert!(position(n_addr.id()) < position(n_store.id()));
+        assert!(position(n_store.id()) < position(n_store2.id()));
+    }
+}
@@ -0,0 +1,145 @@
+use super::{NodeCtxt, NodeId, OriginId, UserId};
+use std::{
+    any::Any,
+    fmt::Debug,
+    fs::File,
+    io::{self, BufWriter, Write},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
+
+/// How many of the latest mutations are kept around for snapshots.
+const JOURNAL_LEN: usize = 16;
+
+/// A change made to the graph, as recorded in the mutation journal.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Mutation {
+    Created(NodeId),
+    Connected { user: UserId, origin: OriginId },
+    Disconnected { user: UserId, origin: OriginId },
+    Removed(NodeId),
+}
+
+impl<S> NodeCtxt<S> {
+    /// Runs `pass` over the graph under the name `name`.
+    ///
+    /// If `crash_snapshot` is configured and the pass panics, the graph is
+    /// written to it in its textual form before the panic carries on
+    /// unwinding, headed by the name of the pass, the panic message and the
+    /// last mutations the pass made.
+    ///
+    /// This isn't done from a panic hook, since hooks are global and can't
+    /// get to the graph, so only panics of passes run this way are caught.
+    pub fn run_pass<'g, R, F>(&'g self, name: &str, pass: F) -> R
+    where
+        S: Debug,
+        F: FnOnce(&'g NodeCtxt<S>) -> R,
+    {
+        let path = match &self.config.crash_snapshot {
+            Some(path) => path,
+            None => return pass(self),
+        };
+
+        self.journal.borrow_mut().clear();
+        match panic::catch_unwind(AssertUnwindSafe(|| pass(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                // The graph may be broken in ways the printer doesn't expect,
+                // and a panic while writing it must not hide the original.
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                    self.write_snapshot(path, name, &*payload)
+                }));
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Records a change made to the graph, as long as snapshots are taken.
+    pub(super) fn record(&self, mutation: Mutation) {
+        if self.config.crash_snapshot.is_none() {
+            return;
+        }
+        let mut journal = self.journal.borrow_mut();
+        if journal.len() == JOURNAL_LEN {
+            journal.pop_front();
+        }
+        journal.push_back(mutation);
+    }
+
+    fn write_snapshot(&self, path: &Path, name: &str, payload: &(dyn Any + Send)) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message,
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.as_str(),
+                None => "<unknown>",
+            },
+        };
+
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "// pass `{}` panicked: {}", name, message)?;
+        let journal = self.journal.borrow();
+        writeln!(out, "// last {} mutations, oldest first:", journal.len())?;
+        for mutation in journal.iter() {
+            writeln!(out, "//     {:?}", mutation)?;
+        }
+        drop(journal);
+        self.print_text(&mut out)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mutation;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeCtxtConfig, NodeId, NodeKind};
+    use std::{env, fs, panic, process};
+
+    #[test]
+    fn snapshot_of_a_panicking_pass() {
+        let path = env::temp_dir().join(format!("oxide-snapshot-{}.txt", process::id()));
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            crash_snapshot: Some(path.clone()),
+            ..NodeCtxtConfig::default()
+        });
+        let lit = ncx.mk_node(Op::Lit(2));
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            ncx.run_pass("negate", |ncx| {
+                let neg = ncx.node_builder(Op::Neg).operand(lit.val_out(0)).finish();
+                panic!("gave up on {:?}", neg.id());
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            ncx.journal.borrow().iter().copied().collect::<Vec<_>>(),
+            [Mutation::Created(NodeId(1))]
+        );
+
+        let snapshot = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut text = vec![];
+        ncx.print_text(&mut text).unwrap();
+        assert_eq!(
+            snapshot,
+            format!(
+                "// pass `negate` panicked: gave up on NodeId(1)\n// last 1 mutations, oldest \
+                 first:\n//     Created(NodeId(1))\n{}",
+                String::from_utf8(text).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn passes_run_as_usual_without_snapshots() {
+        let ncx = NodeCtxt::new();
+        let neg = ncx.run_pass("negate", |ncx| {
+            let lit = ncx.mk_node(Op::Lit(2));
+            ncx.node_builder(Op::Neg).operand(lit.val_out(0)).finish()
+        });
+        assert_eq!(*neg.kind(), NodeKind::Op(Op::Neg));
+        assert!(ncx.journal.borrow().is_empty());
+    }
+}
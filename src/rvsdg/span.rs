@@ -0,0 +1,113 @@
+use super::{Node, NodeBuilder, NodeCtxt, NodeId};
+use std::fmt;
+
+/// Where in the source a node comes from, for frontends to report
+/// diagnostics at.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Span {
+    /// The line, counting from 1.
+    pub line: usize,
+    /// The column, counting from 1.
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    pub fn node_span(&self, node_id: NodeId) -> Option<Span> {
+        self.spans.borrow().get(node_id).cloned()
+    }
+
+    /// Gives a node a span, unless it has one already, so that a node made
+    /// again and found interned keeps the span it was first made with.
+    pub fn attach_span(&self, node_id: NodeId, span: Span) {
+        self.spans.borrow_mut().get_or_insert_with(node_id, || span);
+    }
+}
+
+impl<'g, S> NodeBuilder<'g, S> {
+    /// Gives the node a span. If an equal node already exists and is reused
+    /// instead, it's given the span only if it has none.
+    pub fn span(mut self, span: Span) -> NodeBuilder<'g, S> {
+        self.span = Some(span);
+        self
+    }
+}
+
+impl<'g, S> Node<'g, S> {
+    pub fn span(&self) -> Option<Span> {
+        self.ctxt.node_span(self.id)
+    }
+
+    /// Gives the node a span, replacing the one it had.
+    pub fn set_span(&self, span: Span) {
+        self.ctxt.spans.borrow_mut().insert(self.id, span);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Span;
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    fn parse_op(op: &str) -> Option<Op> {
+        match op {
+            "Neg" => Some(Op::Neg),
+            _ => {
+                let value = op.strip_prefix("Lit(")?.strip_suffix(')')?;
+                Some(Op::Lit(value.parse().ok()?))
+            }
+        }
+    }
+
+    fn span(line: usize, column: usize) -> Span {
+        Span { line, column }
+    }
+
+    #[test]
+    fn interned_nodes_keep_their_first_span() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.node_builder(Op::Lit(1)).span(span(1, 5)).finish();
+        let n_same = ncx.node_builder(Op::Lit(1)).span(span(7, 2)).finish();
+        assert_eq!(n_lit, n_same);
+        assert_eq!(Some(span(1, 5)), n_same.span());
+
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_lit.val_out(0)).finish();
+        assert_eq!(None, n_neg.span());
+        ncx.node_builder(Op::Neg)
+            .operand(n_lit.val_out(0))
+            .span(span(2, 3))
+            .finish();
+        assert_eq!(Some(span(2, 3)), n_neg.span());
+        n_neg.set_span(span(4, 1));
+        assert_eq!(Some(span(4, 1)), n_neg.span());
+    }
+
+    #[test]
+    fn printing_spans() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.node_builder(Op::Lit(1)).span(span(3, 7)).finish();
+        ncx.node_builder(Op::Neg).operand(n_lit.val_out(0)).finish();
+
+        let mut text = vec![];
+        ncx.print_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text, "%0 = \"Lit(1)\"() @3:7\n%1 = \"Neg\"(%0)\n");
+        let parsed = NodeCtxt::parse_text(&text, parse_op).unwrap();
+        let spans: Vec<_> = parsed
+            .region_ref(parsed.root_region())
+            .nodes()
+            .map(|node| node.span())
+            .collect();
+        assert_eq!(spans, [Some(span(3, 7)), None]);
+
+        let mut dot = vec![];
+        ncx.print(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("Lit(1) @3:7"));
+    }
+}
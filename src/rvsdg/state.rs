@@ -0,0 +1,315 @@
+use super::{JoinStates, NodeCtxt, NodeId, NodeKind, OriginId, PortKind, Sig, StOrigin, UserId};
+use std::hash::Hash;
+
+/// Ops that fan a state out.
+pub trait SplitState {
+    /// The op taking a state and producing `num_states` states, each
+    /// ordered after it but not after one another.
+    fn split_state(num_states: usize) -> Self;
+}
+
+impl<S> NodeCtxt<S> {
+    /// Fans `state` out to `num_states` states, one for each of as many
+    /// operations that may happen in parallel, rather than giving it that
+    /// many users.
+    pub fn split_state<'g>(
+        &'g self,
+        state: StOrigin<'g, S>,
+        num_states: usize,
+    ) -> Vec<StOrigin<'g, S>>
+    where
+        S: SplitState + Sig + Eq + Hash + Clone,
+    {
+        if num_states == 1 {
+            return vec![state];
+        }
+        let n_split = self
+            .node_builder(S::split_state(num_states))
+            .state(state)
+            .finish();
+        (0..num_states).map(|port| n_split.st_out(port)).collect()
+    }
+
+    /// Merges `states`, as split by `split_state`, back into a state ordered
+    /// after all of them.
+    pub fn merge_states<'g>(&'g self, states: &[StOrigin<'g, S>]) -> StOrigin<'g, S>
+    where
+        S: JoinStates + Sig + Eq + Hash + Clone,
+    {
+        assert!(!states.is_empty(), "there are no states to merge");
+        if let [state] = states {
+            return state.clone();
+        }
+        let builder = self.node_builder(S::join_states(states.len()));
+        let builder = states
+            .iter()
+            .fold(builder, |builder, state| builder.state(state.clone()));
+        builder.finish().st_out(0)
+    }
+
+    /// Removes the state ports of gamma and theta nodes that don't order any
+    /// side effect, until there are none left. Returns how many were
+    /// removed, counting an input or output along with its arguments or
+    /// results.
+    ///
+    /// States that a gamma passes through every branch unchanged, or that a
+    /// theta passes to the next iteration unchanged, are taken from the
+    /// input instead. Then outputs whose states aren't used any further, and
+    /// inputs whose states aren't used inside, are removed. Signatures of
+    /// ops are fixed, so their state ports are left alone.
+    pub fn remove_dead_state_edges(&self) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let mut num_removed = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let num_nodes = self.nodes.len();
+            for node_id in (0..num_nodes).map(NodeId::new) {
+                let (is_gamma, is_theta) = {
+                    let node_data = self.node_data(node_id);
+                    let is_live = !node_data.removed;
+                    (
+                        is_live && matches!(node_data.kind, NodeKind::Gamma { .. }),
+                        is_live && matches!(node_data.kind, NodeKind::Theta { .. }),
+                    )
+                };
+                let removed = if is_gamma {
+                    self.remove_dead_gamma_states(node_id)
+                } else if is_theta {
+                    self.remove_dead_theta_states(node_id)
+                } else {
+                    0
+                };
+                if removed > 0 {
+                    num_removed += removed;
+                    changed = true;
+                }
+            }
+        }
+        num_removed
+    }
+
+    fn remove_dead_gamma_states(&self, gamma: NodeId) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let branches = self.inner_regions(gamma);
+        let mut num_removed = 0;
+
+        let num_outs = self.node_data(gamma).outs.len();
+        for exit in (0..num_outs).rev() {
+            let output = OriginId::output(gamma, exit);
+            if self.origin_data(output).kind != PortKind::St {
+                continue;
+            }
+
+            let passed_through = branches.iter().map(|&branch| {
+                let result = UserId::result(branch, exit);
+                match self.user_data(result).origin.get() {
+                    Some(OriginId::Arg { index, .. }) => Some(usize::from(index)),
+                    _ => None,
+                }
+            });
+            let entries: Option<Vec<usize>> = passed_through.collect();
+            if let Some(entries) = entries {
+                let same_entry = entries.windows(2).all(|pair| pair[0] == pair[1]);
+                if let (true, Some(&entry)) = (same_entry, entries.first()) {
+                    // Skips the predicate.
+                    let input = UserId::input(gamma, entry + 1);
+                    if let Some(origin_id) = self.user_data(input).origin.get() {
+                        self.replace_all_users(output, origin_id);
+                    }
+                }
+            }
+
+            if self.origin_data(output).users.get().is_none() {
+                self.remove_gamma_exit(gamma, exit);
+                num_removed += 1;
+            }
+        }
+
+        let num_entries = self.node_data(gamma).ins.len() - 1;
+        for entry in (0..num_entries).rev() {
+            let is_dead = branches.iter().all(|&branch| {
+                let arg = OriginId::argument(branch, entry);
+                let arg_data = self.origin_data(arg);
+                arg_data.kind == PortKind::St && arg_data.users.get().is_none()
+            });
+            if is_dead {
+                self.remove_gamma_entry(gamma, entry);
+                num_removed += 1;
+            }
+        }
+
+        num_removed
+    }
+
+    fn remove_dead_theta_states(&self, theta: NodeId) -> usize
+    where
+        S: Sig + Eq + Hash + Clone,
+    {
+        let body = self.inner_regions(theta)[0];
+        let mut num_removed = 0;
+
+        let num_loop_vars = self.node_data(theta).outs.len();
+        for index in (0..num_loop_vars).rev() {
+            let output = OriginId::output(theta, index);
+            if self.origin_data(output).kind != PortKind::St {
+                continue;
+            }
+            let arg = OriginId::argument(body, index);
+            // Skips the predicate.
+            let result = UserId::result(body, index + 1);
+
+            let is_invariant = self.user_data(result).origin.get() == Some(arg);
+            if is_invariant {
+                let input = UserId::input(theta, index);
+                if let Some(origin_id) = self.user_data(input).origin.get() {
+                    self.replace_all_users(output, origin_id);
+                }
+            }
+
+            // The result is the only user the argument may have left.
+            let only_feeds_result = self.origin_ref(arg).users().all(|user| user.id() == result);
+            if self.origin_data(output).users.get().is_none() && only_feeds_result {
+                self.remove_theta_loop_var(theta, index);
+                num_removed += 1;
+            }
+        }
+
+        num_removed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeCtxtConfig, NodeKind};
+
+    #[test]
+    fn splitting_and_merging_states() {
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_linear_state: true,
+            ..NodeCtxtConfig::default()
+        });
+
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let states = ncx.split_state(st.st_out(0), 2);
+        let n_split = states[0].producer();
+        let stored: Vec<_> = states
+            .into_iter()
+            .map(|state| {
+                ncx.node_builder(Op::Store)
+                    .operand(n_addr.val_out(0))
+                    .state(state)
+                    .finish()
+                    .st_out(0)
+            })
+            .collect();
+        let merged = ncx.merge_states(&stored);
+        ncx.node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(merged)
+            .finish();
+
+        assert_eq!(Ok(()), ncx.verify());
+        assert_eq!(NodeKind::Op(Op::Split(2)), *n_split.kind());
+        assert_eq!(NodeKind::Op(Op::Join(2)), *merged.producer().kind());
+        // A single state needs neither.
+        assert_eq!(vec![merged], ncx.split_state(merged, 1));
+        assert_eq!(merged, ncx.merge_states(&[merged]));
+    }
+
+    #[test]
+    fn states_passed_through_a_gamma() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let preds = gamma.entry_var(n_pred.val_out(0));
+        let states = gamma.entry_state(st.st_out(0));
+        let st_out = gamma.exit_state(&states);
+        let out = gamma.exit_var(&preds);
+        let gamma = gamma.finish();
+        let n_store = ncx
+            .node_builder(Op::Store)
+            .operand(out)
+            .state(st_out)
+            .finish();
+
+        // The exit and then the entry go.
+        assert_eq!(2, ncx.remove_dead_state_edges());
+        assert_eq!(
+            *gamma.kind(),
+            NodeKind::Gamma {
+                val_ins: 1,
+                val_outs: 1,
+                st_ins: 0,
+                st_outs: 0,
+            }
+        );
+        assert_eq!(n_store.st_in(0).origin(), st.st_out(0));
+        assert_eq!(n_store.val_in(0).origin(), gamma.val_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn states_ordering_effects_in_a_branch_stay() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let preds = gamma.entry_var(n_pred.val_out(0));
+        let states = gamma.entry_state(st.st_out(0));
+        let n_store = ncx
+            .node_builder_in(gamma.branch(0), Op::Store)
+            .operand(preds[0])
+            .state(states[0])
+            .finish();
+        let st_out = gamma.exit_state(&[n_store.st_out(0), states[1]]);
+        gamma.finish();
+        ncx.node_builder(Op::Load(0))
+            .operand(n_pred.val_out(0))
+            .state(st_out)
+            .finish();
+
+        assert_eq!(0, ncx.remove_dead_state_edges());
+    }
+
+    #[test]
+    fn invariant_theta_states() {
+        let ncx = NodeCtxt::new();
+
+        let n_addr = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (addr, _) = theta.loop_var(n_addr.val_out(0));
+        let (_, st_out) = theta.loop_state(st.st_out(0));
+        // The loaded states are dropped, so the loop state is never changed.
+        let (loaded_st, loaded_out) = theta.loop_state(st.st_out(0));
+        let n_load = ncx
+            .node_builder_in(theta.body(), Op::Load(0))
+            .operand(addr)
+            .state(loaded_st)
+            .finish();
+        theta.set_next_state(loaded_st, n_load.st_out(0));
+        theta.finish(n_load.val_out(0));
+        let n_store = ncx
+            .node_builder(Op::Store)
+            .operand(n_addr.val_out(0))
+            .state(st_out)
+            .finish();
+        ncx.node_builder(Op::Load(0))
+            .operand(n_addr.val_out(0))
+            .state(loaded_out)
+            .finish();
+
+        assert_eq!(1, ncx.remove_dead_state_edges());
+        assert_eq!(n_store.st_in(0).origin(), st.st_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+}
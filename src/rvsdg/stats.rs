@@ -0,0 +1,198 @@
+use super::{NodeCtxt, NodeId, NodeKind, PortKind, RegionId};
+use std::{collections::BTreeMap, fmt};
+
+/// Counts describing the shape of a graph, as taken by `NodeCtxt::stats`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct GraphStats {
+    /// The nodes that weren't removed, by the name of their kind. Ops are
+    /// named by their `Debug` form without any fields, and other nodes as
+    /// `gamma`, `theta`, `lambda`, `omega` or `apply`.
+    pub nodes: BTreeMap<String, usize>,
+    /// The connected inputs and results carrying values.
+    pub val_edges: usize,
+    /// The connected inputs and results carrying states.
+    pub st_edges: usize,
+    /// The regions that weren't removed, the root region among them.
+    pub regions: usize,
+    /// How deep regions nest, the root region being at depth 0.
+    pub max_region_depth: usize,
+    /// How many nodes were looked up in the intern table.
+    pub intern_lookups: usize,
+    /// How many of those were found there, rather than made.
+    pub intern_hits: usize,
+}
+
+impl GraphStats {
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.values().sum()
+    }
+
+    /// The share of intern table lookups that found a node, if any were
+    /// made.
+    pub fn intern_hit_rate(&self) -> Option<f64> {
+        if self.intern_lookups == 0 {
+            None
+        } else {
+            Some(self.intern_hits as f64 / self.intern_lookups as f64)
+        }
+    }
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "nodes: {}", self.num_nodes())?;
+        for (kind, count) in &self.nodes {
+            writeln!(f, "  {}: {}", kind, count)?;
+        }
+        writeln!(
+            f,
+            "edges: {} value, {} state",
+            self.val_edges, self.st_edges
+        )?;
+        writeln!(
+            f,
+            "regions: {}, nested {} deep",
+            self.regions, self.max_region_depth
+        )?;
+        match self.intern_hit_rate() {
+            Some(rate) => writeln!(
+                f,
+                "interning: {} of {} lookups hit ({:.1}%)",
+                self.intern_hits,
+                self.intern_lookups,
+                rate * 100.0
+            ),
+            None => writeln!(f, "interning: no lookups"),
+        }
+    }
+}
+
+/// The name of `kind` in `GraphStats::nodes`.
+pub(super) fn kind_name<S: fmt::Debug>(kind: &NodeKind<S>) -> String {
+    match kind {
+        NodeKind::Op(op) => {
+            let op = format!("{:?}", op);
+            let end = op.find(['(', ' ', '{']).unwrap_or(op.len());
+            op[..end].to_owned()
+        }
+        NodeKind::Apply { .. } => "apply".to_owned(),
+        NodeKind::Gamma { .. } => "gamma".to_owned(),
+        NodeKind::Theta { .. } => "theta".to_owned(),
+        NodeKind::Lambda { .. } => "lambda".to_owned(),
+        NodeKind::Omega { .. } => "omega".to_owned(),
+    }
+}
+
+impl<S: fmt::Debug> NodeCtxt<S> {
+    /// Counts the nodes, edges and regions of the graph, along with how
+    /// well interning has shared nodes so far.
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        for &(_, counts) in self.intern_counts.borrow().values() {
+            stats.intern_lookups += counts.lookups();
+            stats.intern_hits += counts.deduplicated;
+        }
+        let mut count_edges = |kind: PortKind| match kind {
+            PortKind::Val => stats.val_edges += 1,
+            PortKind::St => stats.st_edges += 1,
+        };
+
+        let num_nodes = self.nodes.len();
+        let mut nodes = BTreeMap::new();
+        for node_id in (0..num_nodes).map(NodeId::new) {
+            let node_data = self.node_data(node_id);
+            if node_data.removed {
+                continue;
+            }
+            *nodes.entry(kind_name(&node_data.kind)).or_insert(0) += 1;
+            for user in node_data.ins.iter() {
+                if user.origin.get().is_some() {
+                    count_edges(user.kind);
+                }
+            }
+        }
+
+        let num_regions = self.regions.len();
+        let mut num_live_regions = 0;
+        let mut max_region_depth = 0;
+        for region_id in (0..num_regions).map(RegionId::new) {
+            let region_data = self.region_data(region_id);
+            if region_data.removed {
+                continue;
+            }
+            num_live_regions += 1;
+            max_region_depth = max_region_depth.max(self.region_depth(region_id));
+            for user in &region_data.res {
+                if user.origin.get().is_some() {
+                    count_edges(user.kind);
+                }
+            }
+        }
+
+        stats.nodes = nodes;
+        stats.regions = num_live_regions;
+        stats.max_region_depth = max_region_depth;
+        stats
+    }
+
+    /// How many regions enclose `region_id`.
+    fn region_depth(&self, region_id: RegionId) -> usize {
+        let mut depth = 0;
+        let mut region_id = region_id;
+        while let Some(node_id) = self.region_data(region_id).node {
+            region_id = self.node_data(node_id).outer_region;
+            depth += 1;
+        }
+        depth
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn counting_nodes_edges_and_regions() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.mk_node(Op::Lit(0));
+        let n_st = ncx.mk_node(Op::St);
+        // Found in the intern table, while stateful ops aren't looked up.
+        ncx.mk_node(Op::Lit(0));
+
+        let gamma = ncx.gamma_builder(n_lit.val_out(0), 2);
+        let args = gamma.entry_var(n_lit.val_out(0));
+        let states = gamma.entry_state(n_st.st_out(0));
+        let theta = ncx.theta_builder(gamma.branch(0));
+        let (arg, _) = theta.loop_var(args[0]);
+        let n_neg = ncx
+            .node_builder_in(theta.body(), Op::Neg)
+            .operand(arg)
+            .finish();
+        theta.set_next(arg, n_neg.val_out(0));
+        theta.finish(arg);
+        let n_store = ncx
+            .node_builder_in(gamma.branch(1), Op::Store)
+            .operand(args[1])
+            .state(states[1])
+            .finish();
+        gamma.exit_state(&[states[0], n_store.st_out(0)]);
+        gamma.finish();
+
+        let stats = ncx.stats();
+        assert_eq!(6, stats.num_nodes());
+        assert_eq!(
+            "nodes: 6
+  Lit: 1
+  Neg: 1
+  St: 1
+  Store: 1
+  gamma: 1
+  theta: 1
+edges: 7 value, 4 state
+regions: 4, nested 2 deep
+interning: 1 of 3 lookups hit (33.3%)
+",
+            stats.to_string()
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use super::{GammaBuilder, Node, NodeCtxt, RegionId, Sig, ValOrigin};
+use std::hash::Hash;
+
+/// Ops able to select the case a value matches, for lowering switches.
+pub trait Switch: Sized {
+    type CaseValue: Clone + PartialEq;
+
+    /// The op taking a value and producing the index of the case in `cases`
+    /// it's equal to, or `cases.len()` if there's none.
+    ///
+    /// The case values are kept in the op so that backends can emit jump
+    /// tables for the gamma it selects branches of.
+    fn match_cases(cases: Vec<Self::CaseValue>) -> Self;
+}
+
+/// Builds a gamma with a branch per case value, in the given order, followed
+/// by a default branch.
+pub struct SwitchBuilder<'g, S: Switch> {
+    gamma: GammaBuilder<'g, S>,
+    cases: Vec<S::CaseValue>,
+}
+
+impl<S> NodeCtxt<S> {
+    /// Starts building a switch over `scrutinee`, matching it against
+    /// `cases`, which must be distinct but may be sparse.
+    pub fn switch_builder<'g>(
+        &'g self,
+        scrutinee: ValOrigin<'g, S>,
+        cases: Vec<S::CaseValue>,
+    ) -> SwitchBuilder<'g, S>
+    where
+        S: Switch + Sig + Eq + Hash + Clone,
+    {
+        for (index, case) in cases.iter().enumerate() {
+            assert!(
+                !cases[..index].contains(case),
+                "case {} repeats an earlier case value",
+                index
+            );
+        }
+
+        let region_id = self.origin_region(scrutinee.id());
+        let predicate = self
+            .node_builder_in(region_id, S::match_cases(cases.clone()))
+            .operand(scrutinee)
+            .finish();
+        SwitchBuilder {
+            gamma: self.gamma_builder(predicate.val_out(0), cases.len() + 1),
+            cases,
+        }
+    }
+}
+
+impl<'g, S: Switch + Sig> SwitchBuilder<'g, S> {
+    /// The gamma being built, to add entry and exit variables to.
+    pub fn gamma(&self) -> &GammaBuilder<'g, S> {
+        &self.gamma
+    }
+
+    pub fn cases(&self) -> &[S::CaseValue] {
+        &self.cases
+    }
+
+    /// The branch taken when the scrutinee equals `value`, if it's one of the
+    /// cases.
+    pub fn case_branch(&self, value: &S::CaseValue) -> Option<RegionId> {
+        let index = self.cases.iter().position(|case| case == value)?;
+        Some(self.gamma.branch(index))
+    }
+
+    /// The branch taken when the scrutinee matches none of the cases.
+    pub fn default_branch(&self) -> RegionId {
+        self.gamma.branch(self.cases.len())
+    }
+
+    pub fn finish(self) -> Node<'g, S>
+    where
+        S: Eq + Hash + Clone,
+    {
+        self.gamma.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn sparse_switch() {
+        let ncx = NodeCtxt::new();
+
+        let x = ncx.mk_node(Op::Lit(7));
+        let switch = ncx.switch_builder(x.val_out(0), vec![1, 10, 100]);
+
+        let results: Vec<_> = (0..4)
+            .map(|index| {
+                ncx.node_builder_in(switch.gamma().branch(index), Op::Lit(index as i64))
+                    .finish()
+                    .val_out(0)
+            })
+            .collect();
+        let output = switch.gamma().exit_var(&results);
+
+        assert_eq!(Some(switch.gamma().branch(1)), switch.case_branch(&10));
+        assert_eq!(None, switch.case_branch(&7));
+        assert_eq!(switch.gamma().branch(3), switch.default_branch());
+        assert_eq!(&[1, 10, 100], switch.cases());
+
+        let gamma = switch.finish();
+
+        assert_eq!(gamma, output.producer());
+        assert_eq!(
+            NodeKind::Op(Op::Match(&[1, 10, 100])),
+            *gamma.val_in(0).origin().producer().kind()
+        );
+        assert_eq!(
+            x.val_out(0),
+            gamma.val_in(0).origin().producer().val_in(0).origin()
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn repeated_case_values() {
+        let ncx = NodeCtxt::new();
+
+        let x = ncx.mk_node(Op::Lit(7));
+        ncx.switch_builder(x.val_out(0), vec![1, 2, 1]);
+    }
+}
@@ -0,0 +1,282 @@
+use super::{NodeId, RegionId};
+use std::ops::{Index, IndexMut};
+
+/// Values stored densely by the index of their id, with room made as ids
+/// beyond the end are inserted.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Dense<T> {
+    values: Vec<Option<T>>,
+    len: usize,
+}
+
+impl<T> Dense<T> {
+    fn new() -> Dense<T> {
+        Dense {
+            values: vec![],
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, index: usize, value: T) -> Option<T> {
+        if index >= self.values.len() {
+            self.values.resize_with(index + 1, || None);
+        }
+        let old = self.values[index].replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    fn remove(&mut self, index: usize) -> Option<T> {
+        let old = self.values.get_mut(index)?.take();
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        self.values.get(index)?.as_ref()
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.values.get_mut(index)?.as_mut()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.values
+            .iter()
+            .enumerate()
+            .filter_map(|(index, value)| Some((index, value.as_ref()?)))
+    }
+}
+
+/// A side table attaching values to nodes, such as analysis results or
+/// source spans, without changing the nodes themselves.
+///
+/// Values are stored densely by node id, so lookups are cheap, and ids stay
+/// valid as nodes are created. Nodes that are removed keep their values
+/// until they're removed from the table too.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NodeMap<T>(Dense<T>);
+
+impl<T> Default for NodeMap<T> {
+    fn default() -> NodeMap<T> {
+        NodeMap::new()
+    }
+}
+
+impl<T> NodeMap<T> {
+    pub fn new() -> NodeMap<T> {
+        NodeMap(Dense::new())
+    }
+
+    /// Attaches `value` to `node_id`, returning the value it had before.
+    pub fn insert(&mut self, node_id: NodeId, value: T) -> Option<T> {
+        self.0.insert(node_id.index(), value)
+    }
+
+    pub fn remove(&mut self, node_id: NodeId) -> Option<T> {
+        self.0.remove(node_id.index())
+    }
+
+    pub fn get(&self, node_id: NodeId) -> Option<&T> {
+        self.0.get(node_id.index())
+    }
+
+    pub fn get_mut(&mut self, node_id: NodeId) -> Option<&mut T> {
+        self.0.get_mut(node_id.index())
+    }
+
+    /// The value of `node_id`, attaching the one `default` makes if it has
+    /// none yet.
+    pub fn get_or_insert_with<F>(&mut self, node_id: NodeId, default: F) -> &mut T
+    where
+        F: FnOnce() -> T,
+    {
+        if self.get(node_id).is_none() {
+            self.insert(node_id, default());
+        }
+        self.0.get_mut(node_id.index()).unwrap()
+    }
+
+    pub fn contains_key(&self, node_id: NodeId) -> bool {
+        self.get(node_id).is_some()
+    }
+
+    /// The number of nodes with a value.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// The nodes with a value, in the order of their ids.
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &T)> {
+        self.0
+            .iter()
+            .map(|(index, value)| (NodeId::new(index), value))
+    }
+}
+
+impl<T> Index<NodeId> for NodeMap<T> {
+    type Output = T;
+
+    fn index(&self, node_id: NodeId) -> &T {
+        self.get(node_id)
+            .unwrap_or_else(|| panic!("{:?} has no value", node_id))
+    }
+}
+
+impl<T> IndexMut<NodeId> for NodeMap<T> {
+    fn index_mut(&mut self, node_id: NodeId) -> &mut T {
+        self.get_mut(node_id)
+            .unwrap_or_else(|| panic!("{:?} has no value", node_id))
+    }
+}
+
+/// A side table attaching values to regions. See `NodeMap`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RegionMap<T>(Dense<T>);
+
+impl<T> Default for RegionMap<T> {
+    fn default() -> RegionMap<T> {
+        RegionMap::new()
+    }
+}
+
+impl<T> RegionMap<T> {
+    pub fn new() -> RegionMap<T> {
+        RegionMap(Dense::new())
+    }
+
+    /// Attaches `value` to `region_id`, returning the value it had before.
+    pub fn insert(&mut self, region_id: RegionId, value: T) -> Option<T> {
+        self.0.insert(region_id.index(), value)
+    }
+
+    pub fn remove(&mut self, region_id: RegionId) -> Option<T> {
+        self.0.remove(region_id.index())
+    }
+
+    pub fn get(&self, region_id: RegionId) -> Option<&T> {
+        self.0.get(region_id.index())
+    }
+
+    pub fn get_mut(&mut self, region_id: RegionId) -> Option<&mut T> {
+        self.0.get_mut(region_id.index())
+    }
+
+    /// The value of `region_id`, attaching the one `default` makes if it has
+    /// none yet.
+    pub fn get_or_insert_with<F>(&mut self, region_id: RegionId, default: F) -> &mut T
+    where
+        F: FnOnce() -> T,
+    {
+        if self.get(region_id).is_none() {
+            self.insert(region_id, default());
+        }
+        self.0.get_mut(region_id.index()).unwrap()
+    }
+
+    pub fn contains_key(&self, region_id: RegionId) -> bool {
+        self.get(region_id).is_some()
+    }
+
+    /// The number of regions with a value.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// The regions with a value, in the order of their ids.
+    pub fn iter(&self) -> impl Iterator<Item = (RegionId, &T)> {
+        self.0
+            .iter()
+            .map(|(index, value)| (RegionId::new(index), value))
+    }
+}
+
+impl<T> Index<RegionId> for RegionMap<T> {
+    type Output = T;
+
+    fn index(&self, region_id: RegionId) -> &T {
+        self.get(region_id)
+            .unwrap_or_else(|| panic!("{:?} has no value", region_id))
+    }
+}
+
+impl<T> IndexMut<RegionId> for RegionMap<T> {
+    fn index_mut(&mut self, region_id: RegionId) -> &mut T {
+        self.get_mut(region_id)
+            .unwrap_or_else(|| panic!("{:?} has no value", region_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NodeMap, RegionMap};
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    #[test]
+    fn attaching_values_to_nodes() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.mk_node(Op::Lit(1));
+        let mut names = NodeMap::new();
+        assert_eq!(None, names.insert(n_lit.id(), "one"));
+
+        // Nodes created afterwards can be attached values too.
+        let n_neg = ncx.node_builder(Op::Neg).operand(n_lit.val_out(0)).finish();
+        let n_other = ncx.mk_node(Op::Lit(2));
+        assert!(!names.contains_key(n_neg.id()));
+        names.insert(n_other.id(), "two");
+        *names.get_or_insert_with(n_neg.id(), || "minus one") = "-one";
+
+        assert_eq!(names.len(), 3);
+        assert_eq!(
+            names.iter().collect::<Vec<_>>(),
+            [
+                (n_lit.id(), &"one"),
+                (n_neg.id(), &"-one"),
+                (n_other.id(), &"two")
+            ]
+        );
+        assert_eq!(Some("two"), names.insert(n_other.id(), "2"));
+        assert_eq!(Some("-one"), names.remove(n_neg.id()));
+        assert_eq!(None, names.remove(n_neg.id()));
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[n_other.id()], "2");
+    }
+
+    #[test]
+    fn attaching_values_to_regions() {
+        let ncx = NodeCtxt::new();
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let mut depths = RegionMap::new();
+        depths.insert(ncx.root_region(), 0);
+        for index in 0..gamma.num_branches() {
+            depths.insert(gamma.branch(index), 1);
+        }
+        depths[ncx.root_region()] += 10;
+        assert_eq!(
+            depths.iter().map(|(_, &depth)| depth).collect::<Vec<_>>(),
+            [10, 1, 1]
+        );
+        assert!(RegionMap::<()>::default().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "has no value")]
+    fn indexing_missing_values() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.mk_node(Op::Lit(0));
+        let _: &() = &NodeMap::new()[n_lit.id()];
+    }
+}
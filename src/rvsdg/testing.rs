@@ -0,0 +1,356 @@
+use super::{
+    infer::declared_output_types, ConstBranch, Fold, JoinStates, MemoryAccess, MemoryOp, NodeCtxt,
+    Observable, OpProperties, PortKind, Sig, SigS, SplitState, Switch, TypeRule, UserData,
+};
+use std::{convert::TryFrom, fmt::Debug, hash::Hash};
+
+/// The ops of the graphs built by tests.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Op {
+    /// An integer literal, which folds and selects branches.
+    Lit(i64),
+    Bool(bool),
+    Float(i64),
+    /// A value only known when running, told apart from others by its
+    /// index.
+    Param(u32),
+    Neg,
+    Not,
+    ToFloat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+    Lt,
+    /// Takes a value and produces two.
+    Unpack,
+    /// Takes a value and produces nothing, keeping the value used.
+    Sink,
+    /// The index of the case a value is equal to, as made by `Switch`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Match(&'static [i64]),
+    /// Produces a new state.
+    St,
+    /// Reads the value at an address from a memory bank.
+    Load(u32),
+    /// Writes a value to an address of a memory bank.
+    Write(u32),
+    /// Takes a value and a state, producing a state.
+    Store,
+    /// Like `Store`, but externally observable.
+    Print,
+    /// Like `Store`, but a scheduling barrier.
+    Call,
+    Split(usize),
+    Join(usize),
+    /// Takes a state and produces nothing.
+    Ret,
+}
+
+/// The types of `Op`'s values, which it declares only for literals and a
+/// few typed ops.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Ty {
+    Int,
+    Bool,
+    Float,
+}
+
+impl Sig for Op {
+    type Type = Ty;
+
+    fn sig(&self) -> SigS {
+        let (val_ins, val_outs, st_ins, st_outs) = match *self {
+            Op::Lit(..) | Op::Bool(..) | Op::Float(..) | Op::Param(..) => (0, 1, 0, 0),
+            Op::Neg | Op::Not | Op::ToFloat | Op::Match(..) => (1, 1, 0, 0),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Shl | Op::Lt => (2, 1, 0, 0),
+            Op::Unpack => (1, 2, 0, 0),
+            Op::Sink => (1, 0, 0, 0),
+            Op::St => (0, 0, 0, 1),
+            Op::Load(..) => (1, 1, 1, 1),
+            Op::Write(..) => (2, 0, 1, 1),
+            Op::Store | Op::Print | Op::Call => (1, 0, 1, 1),
+            Op::Split(num_states) => (0, 0, 1, num_states),
+            Op::Join(num_states) => (0, 0, num_states, 1),
+            Op::Ret => (0, 0, 1, 0),
+        };
+        SigS {
+            val_ins,
+            val_outs,
+            st_ins,
+            st_outs,
+        }
+    }
+
+    fn properties(&self) -> &dyn OpProperties {
+        self
+    }
+
+    fn is_scheduling_barrier(&self) -> bool {
+        *self == Op::Call
+    }
+
+    fn val_in_type(&self, _port: usize) -> Option<Ty> {
+        match self {
+            Op::Neg | Op::Lt | Op::ToFloat => Some(Ty::Int),
+            Op::Not => Some(Ty::Bool),
+            _ => None,
+        }
+    }
+
+    fn val_out_type(&self, _port: usize) -> Option<Ty> {
+        match self {
+            Op::Lit(..) | Op::Neg => Some(Ty::Int),
+            Op::Bool(..) | Op::Not | Op::Lt => Some(Ty::Bool),
+            Op::Float(..) | Op::ToFloat => Some(Ty::Float),
+            _ => None,
+        }
+    }
+}
+
+/// Only adds are taken to be commutative, which leaves the operands of the
+/// other ops in the order patterns expect them in.
+impl OpProperties for Op {
+    fn is_commutative(&self) -> bool {
+        *self == Op::Add
+    }
+
+    fn is_associative(&self) -> bool {
+        *self == Op::Add
+    }
+}
+
+/// Adds take operands of any type, as long as both have the same one.
+impl TypeRule for Op {
+    fn output_types(&self, inputs: &[Option<Ty>]) -> Result<Vec<Option<Ty>>, String> {
+        match (self, inputs) {
+            (Op::Add, [Some(a), Some(b)]) if a != b => Err("operands differ".to_owned()),
+            (Op::Add, [a, b]) => Ok(vec![a.or(*b)]),
+            _ => declared_output_types(self, inputs),
+        }
+    }
+}
+
+/// Arithmetic wraps around, as the random values `check_pass_outputs`
+/// gives inputs may overflow.
+impl Fold for Op {
+    type ConstValue = i64;
+
+    fn as_const(&self) -> Option<i64> {
+        match *self {
+            Op::Lit(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn from_const(value: i64) -> Op {
+        Op::Lit(value)
+    }
+
+    fn try_fold(&self, operands: &[i64]) -> Option<i64> {
+        match (self, operands) {
+            (Op::Neg, &[x]) => Some(x.wrapping_neg()),
+            (Op::Add, &[x, y]) => Some(x.wrapping_add(y)),
+            (Op::Sub, &[x, y]) => Some(x.wrapping_sub(y)),
+            (Op::Mul, &[x, y]) => Some(x.wrapping_mul(y)),
+            (Op::Div, &[x, y]) => x.checked_div(y),
+            (Op::Shl, &[x, y]) => x.checked_shl(u32::try_from(y).ok()?),
+            (Op::Lt, &[x, y]) => Some(i64::from(x < y)),
+            _ => None,
+        }
+    }
+}
+
+impl ConstBranch for Op {
+    fn const_branch(&self) -> Option<usize> {
+        match *self {
+            Op::Lit(value) => usize::try_from(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Observable for Op {
+    fn is_externally_observable(&self) -> bool {
+        *self == Op::Print
+    }
+}
+
+impl MemoryOp for Op {
+    fn memory_access(&self) -> Option<MemoryAccess> {
+        match self {
+            Op::Load(..) => Some(MemoryAccess::Load { address: 0 }),
+            Op::Write(..) => Some(MemoryAccess::Store {
+                address: 0,
+                value: 1,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl SplitState for Op {
+    fn split_state(num_states: usize) -> Op {
+        Op::Split(num_states)
+    }
+}
+
+impl JoinStates for Op {
+    fn join_states(num_states: usize) -> Op {
+        Op::Join(num_states)
+    }
+}
+
+impl Switch for Op {
+    type CaseValue = i64;
+
+    /// Tests make few switches, so their cases are leaked for `Op` to stay
+    /// `Copy`, as the handles of a graph are only for `Copy` ops.
+    fn match_cases(cases: Vec<i64>) -> Op {
+        Op::Match(Box::leak(cases.into_boxed_slice()))
+    }
+}
+
+/// Sizes of a graph that passes are expected to change in known ways.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct GraphSize {
+    pub(crate) nodes: usize,
+    pub(crate) val_edges: usize,
+    pub(crate) st_edges: usize,
+}
+
+impl<S> NodeCtxt<S> {
+    pub(crate) fn graph_size(&self) -> GraphSize {
+        let mut size = GraphSize {
+            nodes: self.num_nodes(),
+            val_edges: 0,
+            st_edges: 0,
+        };
+        let mut count_edges = |users: &[UserData]| {
+            for user in users.iter().filter(|user| user.origin.get().is_some()) {
+                match user.kind {
+                    PortKind::Val => size.val_edges += 1,
+                    PortKind::St => size.st_edges += 1,
+                }
+            }
+        };
+        for node_data in self.nodes.iter().map(|node| node.borrow()) {
+            count_edges(&self.input_ports.get(&node_data.ins));
+        }
+        for region_data in self.regions.iter().map(|region| region.borrow()) {
+            count_edges(&region_data.res);
+        }
+        size
+    }
+}
+
+/// The outcome of a pass run through `run_pass`, to make assertions about how
+/// the pass changed the graph.
+pub(crate) struct PassRun<'g, S, R> {
+    ctxt: &'g NodeCtxt<S>,
+    pub(crate) before: GraphSize,
+    pub(crate) after: GraphSize,
+    pub(crate) result: R,
+}
+
+/// Runs `pass` over the graph, recording its size before and after.
+pub(crate) fn run_pass<'g, S, R, F>(ncx: &'g NodeCtxt<S>, pass: F) -> PassRun<'g, S, R>
+where
+    F: FnOnce(&'g NodeCtxt<S>) -> R,
+{
+    let before = ncx.graph_size();
+    let result = pass(ncx);
+    let after = ncx.graph_size();
+    PassRun {
+        ctxt: ncx,
+        before,
+        after,
+        result,
+    }
+}
+
+impl<'g, S, R> PassRun<'g, S, R> {
+    pub(crate) fn nodes_decreased(&self) -> &Self {
+        assert!(
+            self.after.nodes < self.before.nodes,
+            "node count didn't decrease: {} -> {}",
+            self.before.nodes,
+            self.after.nodes
+        );
+        self
+    }
+
+    pub(crate) fn nodes_unchanged(&self) -> &Self {
+        assert_eq!(self.before.nodes, self.after.nodes, "node count changed");
+        self
+    }
+
+    pub(crate) fn no_new_state_edges(&self) -> &Self {
+        assert!(
+            self.after.st_edges <= self.before.st_edges,
+            "state edges were added: {} -> {}",
+            self.before.st_edges,
+            self.after.st_edges
+        );
+        self
+    }
+
+    pub(crate) fn unchanged(&self) -> &Self {
+        assert_eq!(self.before, self.after, "graph size changed");
+        self
+    }
+
+    /// Asserts that `verifier` accepts the graph left by the pass.
+    pub(crate) fn verified<E, V>(&self, verifier: V) -> &Self
+    where
+        E: Debug,
+        V: FnOnce(&'g NodeCtxt<S>) -> Result<(), E>,
+    {
+        if let Err(err) = verifier(self.ctxt) {
+            panic!("verifier failed after pass: {:?}", err);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_pass, Op};
+    use crate::rvsdg::NodeCtxt;
+
+    #[test]
+    fn folding_shrinks_the_graph() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(1));
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let n2 = ncx.node_builder(Op::Neg).operand(n1.val_out(0)).finish();
+        let n_st = ncx.mk_node(Op::St);
+        ncx.node_builder(Op::Store)
+            .operand(n2.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+
+        let run = run_pass(&ncx, |ncx| ncx.fold_constants());
+        run.nodes_decreased()
+            .no_new_state_edges()
+            .verified(|ncx| ncx.verify_effect_ordering());
+
+        assert_eq!(2, run.result);
+        assert_eq!(1, run.after.st_edges);
+
+        run_pass(&ncx, |ncx| ncx.fold_constants()).unchanged();
+    }
+
+    #[test]
+    #[should_panic]
+    fn failed_size_assertion() {
+        let ncx = NodeCtxt::new();
+
+        ncx.mk_node(Op::Lit(1));
+
+        run_pass(&ncx, |ncx| ncx.fold_constants()).nodes_decreased();
+    }
+}
@@ -0,0 +1,1082 @@
+//! A textual form of graphs, meant to be read and written by hand.
+//!
+//! Each statement defines the outputs of a node, whose inputs refer to
+//! origins defined before it in the same region. Value origins are named
+//! with a `%` and state origins with a `!`:
+//!
+//! ```text
+//! %0 = "Lit(0)"()
+//! !1 = "St"()
+//! %2, !3 = gamma(%0, %0, !1) {
+//!     (%4, !5) {
+//!         %6 = "Neg"(%4)
+//!     } -> (%6, !5)
+//!     (%7, !8) {
+//!     } -> (%7, !8)
+//! }
+//! ```
+//!
+//! Ops are written as the quoted `Debug` form of `S`, and nodes with a span
+//! or a name are followed by them, as in `@3:7 #sum`. Structural nodes list
+//! their regions in braces, each with its arguments, its body and its
+//! results, which for thetas start with the loop predicate. Lambdas are written
+//! as `lambda<params>`, with their parameters coming before their context
+//! variables in the arguments of the body, omegas as
+//! `omega<imports, exports>`, and applies as `apply`.
+
+use super::{
+    port_kinds, NodeCtxt, NodeId, NodeKind, OriginData, OriginId, PortKind, RegionId, RegionSigS,
+    Sig, Span, UserId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug},
+    hash::Hash,
+    io::{self, Write},
+    iter,
+};
+
+/// Why a graph couldn't be parsed from its textual form.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    /// The line the error was found at, counting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// Writes the graph in its textual form, with the nodes of each region
+    /// ordered so that origins are defined before they're used.
+    ///
+    /// Named origins are written with their names, as long as they're
+    /// identifiers that aren't taken already.
+    pub fn print_text(&self, out: &mut dyn Write) -> io::Result<()>
+    where
+        S: Debug,
+    {
+        let mut printer = TextPrinter {
+            ctxt: self,
+            names: HashMap::new(),
+            taken: HashSet::new(),
+            next_number: 0,
+        };
+        printer.print_body(out, self.root_region(), 0)
+    }
+
+    /// Parses a graph from its textual form, parsing ops with `parse_op`.
+    ///
+    /// Nodes are made in the order they're written in, and stateless ones
+    /// are interned, but equal ones aren't merged. The graph is verified
+    /// before it's returned.
+    pub fn parse_text<F>(input: &str, parse_op: F) -> Result<NodeCtxt<S>, ParseError>
+    where
+        S: Sig + Eq + Hash + Clone,
+        F: FnMut(&str) -> Option<S>,
+    {
+        let tokens = tokenize(input)?;
+        let ncx = NodeCtxt::new();
+        let mut parser = TextParser {
+            ctxt: &ncx,
+            tokens,
+            pos: 0,
+            parse_op,
+            origins: HashMap::new(),
+        };
+        parser.parse_body(ncx.root_region())?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(parser.error_at(token.line, format!("unexpected {}", token.tok)));
+        }
+
+        let last_line = input.lines().count().max(1);
+        ncx.verify().map_err(|violations| ParseError {
+            line: last_line,
+            message: format!("malformed graph: {:?}", violations),
+        })?;
+        Ok(ncx)
+    }
+}
+
+struct TextPrinter<'g, S> {
+    ctxt: &'g NodeCtxt<S>,
+    names: HashMap<OriginId, String>,
+    taken: HashSet<String>,
+    next_number: usize,
+}
+
+impl<'g, S: Debug> TextPrinter<'g, S> {
+    fn print_body(
+        &mut self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        for node_id in self.ctxt.region_topo_order(region_id) {
+            let num_outs = self.ctxt.node_data(node_id).outs.len();
+            let defs: Vec<String> = (0..num_outs)
+                .map(|index| self.define(OriginId::output(node_id, index)))
+                .collect();
+            let uses = self.uses(
+                self.ctxt
+                    .node_data(node_id)
+                    .ins
+                    .iter()
+                    .map(|user| user.origin.get())
+                    .collect(),
+            );
+
+            write!(out, "{}", indent)?;
+            if !defs.is_empty() {
+                write!(out, "{} = ", defs.join(", "))?;
+            }
+            let head = match self.ctxt.node_data(node_id).kind {
+                NodeKind::Op(ref op) => quote_op(&format!("{:?}", op)),
+                NodeKind::Apply { .. } => "apply".to_owned(),
+                NodeKind::Gamma { .. } => "gamma".to_owned(),
+                NodeKind::Theta { .. } => "theta".to_owned(),
+                NodeKind::Lambda { params, .. } => format!("lambda<{}>", params),
+                NodeKind::Omega { imports, exports } => format!("omega<{}, {}>", imports, exports),
+            };
+            write!(out, "{}({})", head, uses)?;
+            if let Some(span) = self.ctxt.node_span(node_id) {
+                write!(out, " @{}", span)?;
+            }
+            match self.ctxt.node_name(node_id) {
+                Some(name) if is_identifier(&name) => write!(out, " #{}", name)?,
+                _ => {}
+            }
+
+            let inner_regions = self.ctxt.inner_regions(node_id);
+            if inner_regions.is_empty() {
+                writeln!(out)?;
+                continue;
+            }
+            writeln!(out, " {{")?;
+            for inner_region in inner_regions {
+                self.print_region(out, inner_region, depth + 1)?;
+            }
+            writeln!(out, "{}}}", indent)?;
+        }
+        Ok(())
+    }
+
+    fn print_region(
+        &mut self,
+        out: &mut dyn Write,
+        region_id: RegionId,
+        depth: usize,
+    ) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        let num_args = self.ctxt.region_data(region_id).args.len();
+        let args: Vec<String> = (0..num_args)
+            .map(|index| self.define(OriginId::argument(region_id, index)))
+            .collect();
+        writeln!(out, "{}({}) {{", indent, args.join(", "))?;
+        self.print_body(out, region_id, depth + 1)?;
+        let results = self.uses(
+            self.ctxt
+                .region_data(region_id)
+                .res
+                .iter()
+                .map(|user| user.origin.get())
+                .collect(),
+        );
+        writeln!(out, "{}}} -> ({})", indent, results)
+    }
+
+    /// Names an origin after its debug name, or else after the next number
+    /// that isn't taken.
+    fn define(&mut self, origin_id: OriginId) -> String {
+        let sigil = match self.ctxt.origin_data(origin_id).kind {
+            PortKind::Val => '%',
+            PortKind::St => '!',
+        };
+        let name = match self.ctxt.origin_name(origin_id) {
+            Some(name) if is_identifier(&name) && !self.taken.contains(&name) => name,
+            _ => loop {
+                let number = self.next_number.to_string();
+                self.next_number += 1;
+                if !self.taken.contains(&number) {
+                    break number;
+                }
+            },
+        };
+        self.taken.insert(name.clone());
+        let name = format!("{}{}", sigil, name);
+        self.names.insert(origin_id, name.clone());
+        name
+    }
+
+    /// Lists the names of the given origins, writing unconnected ones and
+    /// ones that aren't in scope as `?`.
+    fn uses(&self, origins: Vec<Option<OriginId>>) -> String {
+        origins
+            .iter()
+            .map(|origin| match origin {
+                Some(origin_id) => self.names.get(origin_id).map_or("?", String::as_str),
+                None => "?",
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Quotes an op, escaping the quotes and backslashes in it.
+pub(super) fn quote_op(op: &str) -> String {
+    let mut quoted = String::with_capacity(op.len() + 2);
+    quoted.push('"');
+    for c in op.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub(super) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(is_name_char)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Tok {
+    /// The name of an origin, without its sigil.
+    Name(PortKind, String),
+    /// The name of a node, without its `#`.
+    NodeName(String),
+    /// The quoted form of an op.
+    Op(String),
+    Keyword(String),
+    Number(usize),
+    Punct(&'static str),
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tok::Name(PortKind::Val, name) => write!(f, "`%{}`", name),
+            Tok::Name(PortKind::St, name) => write!(f, "`!{}`", name),
+            Tok::NodeName(name) => write!(f, "`#{}`", name),
+            Tok::Op(op) => write!(f, "op {:?}", op),
+            Tok::Keyword(keyword) => write!(f, "`{}`", keyword),
+            Tok::Number(number) => write!(f, "`{}`", number),
+            Tok::Punct(punct) => write!(f, "`{}`", punct),
+        }
+    }
+}
+
+struct Token {
+    tok: Tok,
+    line: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    for (line_index, text) in input.lines().enumerate() {
+        let line = line_index + 1;
+        let error = |message: String| ParseError { line, message };
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let tok = match c {
+                _ if c.is_whitespace() => continue,
+                '/' if text[start..].starts_with("//") => break,
+                '%' | '!' | '#' => {
+                    let mut name = String::new();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !is_name_char(c) {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    if name.is_empty() {
+                        return Err(error(format!("expected a name after `{}`", c)));
+                    }
+                    match c {
+                        '%' => Tok::Name(PortKind::Val, name),
+                        '!' => Tok::Name(PortKind::St, name),
+                        _ => Tok::NodeName(name),
+                    }
+                }
+                '"' => {
+                    let mut op = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, c)) => op.push(c),
+                                None => return Err(error("unterminated op".to_owned())),
+                            },
+                            Some((_, c)) => op.push(c),
+                            None => return Err(error("unterminated op".to_owned())),
+                        }
+                    }
+                    Tok::Op(op)
+                }
+                '-' if text[start..].starts_with("->") => {
+                    chars.next();
+                    Tok::Punct("->")
+                }
+                '(' => Tok::Punct("("),
+                ')' => Tok::Punct(")"),
+                '{' => Tok::Punct("{"),
+                '}' => Tok::Punct("}"),
+                '<' => Tok::Punct("<"),
+                '>' => Tok::Punct(">"),
+                ',' => Tok::Punct(","),
+                ':' => Tok::Punct(":"),
+                '@' => Tok::Punct("@"),
+                '=' => Tok::Punct("="),
+                _ if c.is_ascii_digit() || c.is_ascii_alphabetic() => {
+                    let mut word = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if !c.is_ascii_alphanumeric() {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    match word.parse() {
+                        Ok(number) => Tok::Number(number),
+                        Err(_) => Tok::Keyword(word),
+                    }
+                }
+                _ => return Err(error(format!("unexpected character `{}`", c))),
+            };
+            tokens.push(Token { tok, line });
+        }
+    }
+    Ok(tokens)
+}
+
+struct TextParser<'g, S, F> {
+    ctxt: &'g NodeCtxt<S>,
+    tokens: Vec<Token>,
+    pos: usize,
+    parse_op: F,
+    /// The origins defined so far, by their names along with their sigils.
+    origins: HashMap<(PortKind, String), OriginId>,
+}
+
+/// What a statement makes, before its outputs are known.
+pub(super) enum Head<S> {
+    Op(S),
+    Apply,
+    Gamma,
+    Theta,
+    Lambda { params: usize },
+    Omega { imports: usize, exports: usize },
+}
+
+impl<'g, S, F> TextParser<'g, S, F>
+where
+    S: Sig + Eq + Hash + Clone,
+    F: FnMut(&str) -> Option<S>,
+{
+    fn error_at(&self, line: usize, message: String) -> ParseError {
+        ParseError { line, message }
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        let line = match self.tokens.get(self.pos).or_else(|| self.tokens.last()) {
+            Some(token) => token.line,
+            None => 1,
+        };
+        self.error_at(line, message)
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|token| &token.tok)
+    }
+
+    fn next(&mut self) -> Result<Tok, ParseError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.tok.clone())
+            }
+            None => Err(self.error("unexpected end of input".to_owned())),
+        }
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), ParseError> {
+        match self.next()? {
+            Tok::Punct(found) if found == punct => Ok(()),
+            tok => {
+                self.pos -= 1;
+                Err(self.error(format!("expected `{}`, found {}", punct, tok)))
+            }
+        }
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        if self.peek() == Some(&Tok::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parses statements until the end of the enclosing braces or input.
+    fn parse_body(&mut self, region_id: RegionId) -> Result<(), ParseError> {
+        while self.peek().is_some() && self.peek() != Some(&Tok::Punct("}")) {
+            self.parse_stmt(region_id)?;
+        }
+        Ok(())
+    }
+
+    /// Parses a comma separated list of names, up to `end`, not consuming
+    /// it.
+    fn parse_names(&mut self, end: &'static str) -> Result<Vec<(PortKind, String)>, ParseError> {
+        let mut names = vec![];
+        if self.peek() == Some(&Tok::Punct(end)) {
+            return Ok(names);
+        }
+        loop {
+            match self.next()? {
+                Tok::Name(kind, name) => names.push((kind, name)),
+                tok => {
+                    self.pos -= 1;
+                    return Err(self.error(format!("expected a name, found {}", tok)));
+                }
+            }
+            if !self.eat(",") {
+                return Ok(names);
+            }
+        }
+    }
+
+    /// Parses the parenthesized origins used by a node or as results.
+    fn parse_uses(&mut self, region_id: RegionId) -> Result<Vec<OriginId>, ParseError> {
+        self.expect("(")?;
+        let start = self.pos;
+        let names = self.parse_names(")")?;
+        self.expect(")")?;
+        let line = self.tokens[start].line;
+        names
+            .into_iter()
+            .map(|(kind, name)| {
+                let origin_id = *self.origins.get(&(kind, name.clone())).ok_or_else(|| {
+                    self.error_at(line, format!("`{}` isn't defined", sigil_name(kind, &name)))
+                })?;
+                if self.ctxt.origin_region(origin_id) != region_id {
+                    return Err(self.error_at(
+                        line,
+                        format!("`{}` is used outside its region", sigil_name(kind, &name)),
+                    ));
+                }
+                Ok(origin_id)
+            })
+            .collect()
+    }
+
+    fn define(
+        &mut self,
+        line: usize,
+        kind: PortKind,
+        name: String,
+        origin_id: OriginId,
+    ) -> Result<(), ParseError> {
+        if self.origins.contains_key(&(kind, name.clone())) {
+            return Err(self.error_at(
+                line,
+                format!("`{}` is defined twice", sigil_name(kind, &name)),
+            ));
+        }
+        if !name.starts_with(|c: char| c.is_ascii_digit()) {
+            self.ctxt.set_origin_name(origin_id, name.clone());
+        }
+        self.origins.insert((kind, name), origin_id);
+        Ok(())
+    }
+
+    fn parse_stmt(&mut self, region_id: RegionId) -> Result<(), ParseError> {
+        let mut defs = vec![];
+        if let Some(Tok::Name(..)) = self.peek() {
+            defs = self.parse_names("=")?;
+            self.expect("=")?;
+        }
+
+        let head = match self.next()? {
+            Tok::Op(op) => match (self.parse_op)(&op) {
+                Some(op) => Head::Op(op),
+                None => {
+                    self.pos -= 1;
+                    return Err(self.error(format!("unknown op {:?}", op)));
+                }
+            },
+            Tok::Keyword(ref keyword) if keyword == "apply" => Head::Apply,
+            Tok::Keyword(ref keyword) if keyword == "gamma" => Head::Gamma,
+            Tok::Keyword(ref keyword) if keyword == "theta" => Head::Theta,
+            Tok::Keyword(ref keyword) if keyword == "lambda" => {
+                self.expect("<")?;
+                let params = self.parse_number()?;
+                self.expect(">")?;
+                Head::Lambda { params }
+            }
+            Tok::Keyword(ref keyword) if keyword == "omega" => {
+                self.expect("<")?;
+                let imports = self.parse_number()?;
+                self.expect(",")?;
+                let exports = self.parse_number()?;
+                self.expect(">")?;
+                Head::Omega { imports, exports }
+            }
+            tok => {
+                self.pos -= 1;
+                return Err(self.error(format!("expected a node, found {}", tok)));
+            }
+        };
+        let line = self.tokens[self.pos - 1].line;
+        let uses = self.parse_uses(region_id)?;
+        let span = self.parse_span()?;
+        let node_name = match self.peek() {
+            Some(Tok::NodeName(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Some(name)
+            }
+            _ => None,
+        };
+        let out_kinds: Vec<PortKind> = defs.iter().map(|&(kind, _)| kind).collect();
+        let node_id = self
+            .ctxt
+            .make_parsed_node(region_id, head, &uses, &out_kinds)
+            .map_err(|message| self.error_at(line, message))?;
+
+        if let Some(span) = span {
+            self.ctxt.attach_span(node_id, span);
+        }
+        if let Some(name) = node_name {
+            self.ctxt.set_node_name(node_id, name);
+        }
+        if self.ctxt.node_data(node_id).kind.is_structural() {
+            self.parse_regions(node_id)?;
+        }
+
+        for (index, (kind, name)) in defs.into_iter().enumerate() {
+            let origin_id = OriginId::output(node_id, index);
+            self.define(line, kind, name, origin_id)?;
+        }
+        Ok(())
+    }
+
+    /// Parses the span of a node, written as `@line:column`, if there's one.
+    fn parse_span(&mut self) -> Result<Option<Span>, ParseError> {
+        if !self.eat("@") {
+            return Ok(None);
+        }
+        let line = self.parse_number()?;
+        self.expect(":")?;
+        let column = self.parse_number()?;
+        Ok(Some(Span { line, column }))
+    }
+
+    fn parse_number(&mut self) -> Result<usize, ParseError> {
+        match self.next()? {
+            Tok::Number(number) => Ok(number),
+            tok => {
+                self.pos -= 1;
+                Err(self.error(format!("expected a number, found {}", tok)))
+            }
+        }
+    }
+
+    /// Parses the braced regions of a structural node, checking their ports
+    /// against the node's.
+    fn parse_regions(&mut self, node_id: NodeId) -> Result<(), ParseError> {
+        self.expect("{")?;
+        while !self.eat("}") {
+            let line = match self.tokens.get(self.pos) {
+                Some(token) => token.line,
+                None => return Err(self.error("unexpected end of input".to_owned())),
+            };
+
+            self.expect("(")?;
+            let args = self.parse_names(")")?;
+            self.expect(")")?;
+
+            let arg_kinds: Vec<PortKind> = args.iter().map(|&(kind, _)| kind).collect();
+            let region_id = self
+                .ctxt
+                .add_parsed_region(node_id, &arg_kinds)
+                .map_err(|message| self.error_at(line, message))?;
+            for (index, (kind, name)) in args.into_iter().enumerate() {
+                let origin_id = OriginId::argument(region_id, index);
+                self.define(line, kind, name, origin_id)?;
+            }
+
+            self.expect("{")?;
+            self.parse_body(region_id)?;
+            self.expect("}")?;
+            self.expect("->")?;
+
+            let results = self.parse_uses(region_id)?;
+            self.ctxt
+                .connect_parsed_results(region_id, &results)
+                .map_err(|message| self.error(message))?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Sig + Eq + Hash + Clone> NodeCtxt<S> {
+    /// Makes a node of a graph being parsed from `head`, connected to
+    /// `uses` and with outputs of the given kinds, or describes why it
+    /// can't be made. Structural nodes are made without regions.
+    pub(super) fn make_parsed_node(
+        &self,
+        region_id: RegionId,
+        head: Head<S>,
+        uses: &[OriginId],
+        out_kinds: &[PortKind],
+    ) -> Result<NodeId, String> {
+        let in_kinds: Vec<PortKind> = uses
+            .iter()
+            .map(|&origin_id| self.origin_data(origin_id).kind)
+            .collect();
+
+        let kind = match head {
+            Head::Op(op) => NodeKind::Op(op),
+            Head::Apply => {
+                let count = |kinds: &[PortKind], kind| kinds.iter().filter(|&&k| k == kind).count();
+                NodeKind::Apply {
+                    arg_val_ins: count(&in_kinds, PortKind::Val).saturating_sub(1),
+                    arg_st_ins: count(&in_kinds, PortKind::St),
+                    region_val_res: count(out_kinds, PortKind::Val),
+                    region_st_res: count(out_kinds, PortKind::St),
+                }
+            }
+            Head::Gamma => NodeKind::Gamma {
+                val_ins: 0,
+                val_outs: 0,
+                st_ins: 0,
+                st_outs: 0,
+            },
+            Head::Theta => NodeKind::Theta {
+                val_loop_vars: 0,
+                st_loop_vars: 0,
+            },
+            Head::Lambda { params } => NodeKind::Lambda {
+                params,
+                ctx_vars: 0,
+            },
+            Head::Omega { imports, exports } => NodeKind::Omega { imports, exports },
+        };
+
+        let node_id = match kind {
+            NodeKind::Op(..) | NodeKind::Apply { .. } | NodeKind::Omega { .. } => {
+                let sig = kind.sig();
+                let expected_ins: Vec<PortKind> = port_kinds(sig.val_ins, sig.st_ins).collect();
+                let expected_outs: Vec<PortKind> = port_kinds(sig.val_outs, sig.st_outs).collect();
+                if in_kinds != expected_ins {
+                    return Err(format!(
+                        "expected inputs {:?}, found {:?}",
+                        expected_ins, in_kinds
+                    ));
+                }
+                if out_kinds != expected_outs {
+                    return Err(format!(
+                        "expected outputs {:?}, found {:?}",
+                        expected_outs, out_kinds
+                    ));
+                }
+                let node_id = self.create_node(kind, region_id).id();
+                for (index, &origin_id) in uses.iter().enumerate() {
+                    self.connect_parsed(UserId::input(node_id, index), origin_id)?;
+                }
+                self.register_interned(node_id);
+                node_id
+            }
+            NodeKind::Gamma { .. } => {
+                if in_kinds.first() != Some(&PortKind::Val) {
+                    return Err("a gamma needs a predicate".to_owned());
+                }
+                let node_id = self.create_node(kind, region_id).id();
+                self.connect_parsed(
+                    UserId::In {
+                        node: node_id,
+                        index: 0,
+                    },
+                    uses[0],
+                )?;
+                for &origin_id in &uses[1..] {
+                    self.add_parsed_input(node_id, origin_id)?;
+                    self.count_ports(node_id, self.origin_data(origin_id).kind, 1, 0);
+                }
+                for &kind in out_kinds {
+                    self.add_output(node_id, kind);
+                    self.count_ports(node_id, kind, 0, 1);
+                }
+                node_id
+            }
+            NodeKind::Theta { .. } => {
+                if in_kinds != out_kinds {
+                    return Err("the inputs and outputs of a theta must match".to_owned());
+                }
+                let node_id = self.create_node(kind, region_id).id();
+                for &origin_id in uses {
+                    let kind = self.origin_data(origin_id).kind;
+                    self.add_parsed_input(node_id, origin_id)?;
+                    self.add_output(node_id, kind);
+                    self.count_ports(node_id, kind, 1, 1);
+                }
+                node_id
+            }
+            NodeKind::Lambda { .. } => {
+                if in_kinds.iter().any(|&kind| kind != PortKind::Val) {
+                    return Err("the context variables of a lambda must be values".to_owned());
+                }
+                if out_kinds != [PortKind::Val] {
+                    return Err(format!("expected outputs [Val], found {:?}", out_kinds));
+                }
+                let node_id = self.create_node(kind, region_id).id();
+                for &origin_id in uses {
+                    self.add_parsed_input(node_id, origin_id)?;
+                    self.count_ports(node_id, PortKind::Val, 1, 0);
+                }
+                node_id
+            }
+        };
+        Ok(node_id)
+    }
+
+    /// Adds a region to the structural node `node_id` of a graph being
+    /// parsed, with arguments of the given kinds, or describes why it can't
+    /// be added.
+    pub(super) fn add_parsed_region(
+        &self,
+        node_id: NodeId,
+        arg_kinds: &[PortKind],
+    ) -> Result<RegionId, String> {
+        let region_id = self.mk_region_for_node(node_id, RegionSigS::default());
+        let (ins, kind) = {
+            let node_data = self.node_data(node_id);
+            let ins: Vec<PortKind> = node_data.ins.iter().map(|user| user.kind).collect();
+            (ins, node_data.kind.clone())
+        };
+        // Where the arguments come from.
+        let sources: Vec<_> = match kind {
+            NodeKind::Gamma { .. } => (1..ins.len()).map(Some).collect(),
+            NodeKind::Theta { .. } => (0..ins.len()).map(Some).collect(),
+            // Parameters come from callers, and context variables from the
+            // inputs.
+            NodeKind::Lambda { params, .. } => (0..params)
+                .map(|_| None)
+                .chain((0..ins.len()).map(Some))
+                .collect(),
+            _ => vec![None; arg_kinds.len()],
+        };
+
+        if arg_kinds.len() != sources.len() {
+            return Err(format!(
+                "expected {} arguments, found {}",
+                sources.len(),
+                arg_kinds.len()
+            ));
+        }
+        for (index, (&kind, source)) in arg_kinds.iter().zip(sources).enumerate() {
+            if let Some(input) = source {
+                if ins[input] != kind {
+                    return Err(format!(
+                        "argument {} must be of kind {:?}",
+                        index, ins[input]
+                    ));
+                }
+            }
+            self.region_data_mut(region_id).args.push(OriginData {
+                kind,
+                source: source.map(|index| UserId::input(node_id, index)),
+                ..OriginData::default()
+            });
+        }
+        Ok(region_id)
+    }
+
+    /// Connects the results of a region of a graph being parsed to
+    /// `results`, checking them against the outputs of its node, or
+    /// describes why they can't be.
+    pub(super) fn connect_parsed_results(
+        &self,
+        region_id: RegionId,
+        results: &[OriginId],
+    ) -> Result<(), String> {
+        let node_id = self.region_data(region_id).node.unwrap();
+        let (outs, kind) = {
+            let node_data = self.node_data(node_id);
+            let outs: Vec<PortKind> = node_data.outs.iter().map(|origin| origin.kind).collect();
+            (outs, node_data.kind.clone())
+        };
+        // Where the results go to.
+        let sinks: Vec<_> = match kind {
+            NodeKind::Gamma { .. } => (0..outs.len()).map(Some).collect(),
+            NodeKind::Theta { .. } => iter::once(None).chain((0..outs.len()).map(Some)).collect(),
+            _ => vec![],
+        };
+
+        // Results of omegas and lambdas leave the graph, so any number of
+        // any kind goes.
+        let free_results = matches!(kind, NodeKind::Omega { .. } | NodeKind::Lambda { .. });
+        if !free_results && results.len() != sinks.len() {
+            return Err(format!(
+                "expected {} results, found {}",
+                sinks.len(),
+                results.len()
+            ));
+        }
+        for (index, &origin_id) in results.iter().enumerate() {
+            let kind = self.origin_data(origin_id).kind;
+            let sink = sinks.get(index).cloned().flatten();
+            let expected = match sink {
+                Some(output) => outs[output],
+                None if free_results => kind,
+                // The loop predicate.
+                None => PortKind::Val,
+            };
+            if kind != expected {
+                return Err(format!("result {} must be of kind {:?}", index, expected));
+            }
+            let sink = sink.map(|index| OriginId::output(node_id, index));
+            let user_id = self.add_result(region_id, kind, sink);
+            self.connect_parsed(user_id, origin_id)?;
+        }
+        Ok(())
+    }
+
+    /// Adds an input to `node_id` connected to `origin_id`, or describes
+    /// why it can't be connected.
+    fn add_parsed_input(&self, node_id: NodeId, origin_id: OriginId) -> Result<UserId, String> {
+        let user_id = self.add_unconnected_input(node_id, self.origin_data(origin_id).kind);
+        self.connect_parsed(user_id, origin_id)?;
+        Ok(user_id)
+    }
+
+    /// Connects `user_id` to `origin_id`, or describes why they can't be
+    /// connected. Parsers check that origins are used in their region, but
+    /// an input that slips through is an error rather than a panic.
+    fn connect_parsed(&self, user_id: UserId, origin_id: OriginId) -> Result<(), String> {
+        self.try_connect_ports(user_id, origin_id).map_err(|err| {
+            format!(
+                "{:?} can't be connected to {:?}: {}",
+                user_id, origin_id, err
+            )
+        })
+    }
+}
+
+fn sigil_name(kind: PortKind, name: &str) -> String {
+    match kind {
+        PortKind::Val => format!("%{}", name),
+        PortKind::St => format!("!{}", name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ParseError;
+    use crate::rvsdg::{testing::Op, NodeCtxt};
+
+    fn parse_op(op: &str) -> Option<Op> {
+        match op {
+            "Neg" => Some(Op::Neg),
+            "Add" => Some(Op::Add),
+            "St" => Some(Op::St),
+            "Store" => Some(Op::Store),
+            _ if op.starts_with("Lit(") && op.ends_with(')') => {
+                op[4..op.len() - 1].parse().ok().map(Op::Lit)
+            }
+            _ => None,
+        }
+    }
+
+    fn print(ncx: &NodeCtxt<Op>) -> String {
+        let mut buffer = Vec::new();
+        ncx.print_text(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn parse(text: &str) -> Result<NodeCtxt<Op>, ParseError> {
+        NodeCtxt::parse_text(text, parse_op)
+    }
+
+    #[test]
+    fn printing_structural_nodes() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let st = ncx.mk_node(Op::St);
+        ncx.set_origin_name(n0.val_out(0).id(), "zero");
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n0.val_out(0));
+        let (st_arg, st_out) = theta.loop_state(st.st_out(0));
+        let gamma = ncx.gamma_builder(x_arg, 2);
+        let args = gamma.entry_var(x_arg);
+        let states = gamma.entry_state(st_arg);
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let store = ncx
+            .node_builder_in(gamma.branch(0), Op::Store)
+            .operand(x_neg.val_out(0))
+            .state(states[0])
+            .finish();
+        let x_next = gamma.exit_var(&[x_neg.val_out(0), args[1]]);
+        let st_next = gamma.exit_state(&[store.st_out(0), states[1]]);
+        gamma.finish();
+        theta.set_next(x_arg, x_next);
+        theta.set_next_state(st_arg, st_next);
+        theta.finish(x_arg);
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(st_out)
+            .finish();
+
+        let text = r#"%zero = "Lit(0)"()
+!0 = "St"()
+%1, !2 = theta(%zero, !0) {
+    (%3, !4) {
+        %5, !6 = gamma(%3, %3, !4) {
+            (%7, !8) {
+                %9 = "Neg"(%7)
+                !10 = "Store"(%9, !8)
+            } -> (%9, !10)
+            (%11, !12) {
+            } -> (%11, !12)
+        }
+    } -> (%3, %5, !6)
+}
+!13 = "Store"(%zero, !2)
+"#;
+        assert_eq!(text, print(&ncx));
+
+        let parsed = parse(text).unwrap();
+        assert_eq!(text, print(&parsed));
+        assert_eq!(ncx.num_nodes(), parsed.num_nodes());
+        assert_eq!(ncx.num_edges(), parsed.num_edges());
+    }
+
+    #[test]
+    fn lambdas_and_applies() {
+        let text = r#"%0 = "Lit(0)"()
+%1 = lambda<2>(%0) {
+    (%2, !3, %4) {
+        %5 = "Add"(%2, %4)
+        !6 = "Store"(%5, !3)
+    } -> (%5, !6)
+}
+!7 = "St"()
+%8, !9 = apply(%1, %0, !7)
+"#;
+        let parsed = parse(text).unwrap();
+        assert_eq!(text, print(&parsed));
+        assert_eq!(Ok(()), parsed.verify());
+    }
+
+    #[test]
+    fn parsing_names_and_comments() {
+        let parsed = parse(
+            r#"
+            // Names may be given in any order.
+            %b = "Lit(1)"()
+            %a = "Lit(2)"()
+            %sum = "Add"(%a, %b)
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            r#"%b = "Lit(1)"()
+%a = "Lit(2)"()
+%sum = "Add"(%a, %b)
+"#,
+            print(&parsed)
+        );
+    }
+
+    #[test]
+    fn naming_nodes() {
+        let ncx = NodeCtxt::new();
+        let n_lit = ncx.mk_node(Op::Lit(2));
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_lit.val_out(0))
+            .operand(n_lit.val_out(0))
+            .finish();
+        n_lit.val_out(0).set_name("two");
+        n_add.val_out(0).set_name("sum");
+        n_add.set_name("adder");
+        // Names that can't be parsed back are left out.
+        n_lit.set_name("the literal");
+        assert_eq!(Some("adder".to_owned()), n_add.name());
+
+        let text = print(&ncx);
+        assert_eq!(
+            r#"%two = "Lit(2)"()
+%sum = "Add"(%two, %two) #adder
+"#,
+            text
+        );
+        let parsed = parse(&text).unwrap();
+        assert_eq!(text, print(&parsed));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| parse(text).map(|_| ()).unwrap_err();
+
+        assert_eq!(
+            ParseError {
+                line: 2,
+                message: "unknown op \"Mul\"".to_owned(),
+            },
+            error("%0 = \"Lit(0)\"()\n%1 = \"Mul\"(%0)")
+        );
+        assert_eq!(
+            ParseError {
+                line: 1,
+                message: "`%1` isn't defined".to_owned(),
+            },
+            error("%0 = \"Neg\"(%1)")
+        );
+        assert_eq!(
+            ParseError {
+                line: 2,
+                message: "expected inputs [Val, St], found [Val, Val]".to_owned(),
+            },
+            error("%0 = \"Lit(0)\"()\n!1 = \"Store\"(%0, %0)")
+        );
+        assert_eq!(
+            ParseError {
+                line: 4,
+                message: "`%0` is used outside its region".to_owned(),
+            },
+            error("%0 = \"Lit(0)\"()\n%1 = gamma(%0) {\n() {\n} -> (%0)\n}")
+        );
+        assert_eq!(
+            ParseError {
+                line: 2,
+                message: "`%0` is defined twice".to_owned(),
+            },
+            error("%0 = \"Lit(0)\"()\n%0 = \"Lit(1)\"()\n%1 = \"Lit(2)\"()")
+        );
+    }
+}
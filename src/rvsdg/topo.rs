@@ -0,0 +1,213 @@
+use super::{Node, NodeCtxt, NodeId, OriginId, Region, RegionId, UserId};
+use std::collections::{HashMap, HashSet};
+
+impl<'g, S> Region<'g, S> {
+    /// The nodes of this region, each after the nodes in the region whose
+    /// outputs it uses, through value or state edges.
+    ///
+    /// The order is computed when called, so the graph may be changed while
+    /// iterating, but nodes added meanwhile aren't visited.
+    pub fn topo_iter(&self) -> impl DoubleEndedIterator<Item = Node<'g, S>> {
+        let ctxt = self.ctxt;
+        ctxt.region_topo_order(self.id)
+            .into_iter()
+            .map(move |node_id| ctxt.node_ref(node_id))
+    }
+
+    /// The nodes of this region, each before the nodes in the region whose
+    /// outputs it uses.
+    pub fn rev_topo_iter(&self) -> impl Iterator<Item = Node<'g, S>> {
+        self.topo_iter().rev()
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    /// The nodes of a region, each after the nodes in the region it uses.
+    pub(super) fn region_topo_order(&self, region_id: RegionId) -> Vec<NodeId> {
+        let nodes = self.region_nodes(region_id);
+
+        let mut order = Vec::with_capacity(nodes.len());
+        let mut visited = HashSet::new();
+
+        for &root in &nodes {
+            let mut stack = vec![(root, false)];
+            while let Some((node_id, operands_done)) = stack.pop() {
+                if operands_done {
+                    order.push(node_id);
+                    continue;
+                }
+                if !visited.insert(node_id) {
+                    continue;
+                }
+                stack.push((node_id, true));
+                for user in self.node_data(node_id).ins.iter() {
+                    if let Some(OriginId::Out { node, .. }) = user.origin.get() {
+                        if !visited.contains(&node)
+                            && self.node_data(node).outer_region == region_id
+                        {
+                            stack.push((node, false));
+                        }
+                    }
+                }
+            }
+        }
+
+        order
+    }
+
+    /// The nodes of the region of `from` producing its operands.
+    pub(super) fn operand_nodes(&self, from: NodeId) -> Vec<NodeId> {
+        let node_data = self.node_data(from);
+        node_data
+            .ins
+            .iter()
+            .filter_map(|user| match user.origin.get()? {
+                OriginId::Out { node, .. }
+                    if self.node_data(node).outer_region == node_data.outer_region =>
+                {
+                    Some(node)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The nodes through which `from` uses the outputs of `to`, starting
+    /// with `from` and ending with `to`, each using the outputs of the next.
+    pub(super) fn dependency_path(&self, from: NodeId, to: NodeId) -> Option<Vec<NodeId>> {
+        // Maps each node reached to the node using it it was reached from.
+        let mut reached_from = HashMap::new();
+        let mut stack = vec![from];
+        while let Some(node_id) = stack.pop() {
+            if node_id == to {
+                let mut path = vec![to];
+                while let Some(&user) = reached_from.get(path.last().unwrap()) {
+                    path.push(user);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for operand in self.operand_nodes(node_id) {
+                if operand != from && !reached_from.contains_key(&operand) {
+                    reached_from.insert(operand, node_id);
+                    stack.push(operand);
+                }
+            }
+        }
+        None
+    }
+
+    /// Panics if `opt_cycle_check` is on and connecting `user_id` to
+    /// `origin_id` would make a node use its own outputs, naming the nodes
+    /// along the cycle.
+    pub(super) fn check_acyclic(&self, user_id: UserId, origin_id: OriginId) {
+        if !self.config.opt_cycle_check {
+            return;
+        }
+        let (user_node, origin_node) = match (user_id, origin_id) {
+            (
+                UserId::In {
+                    node: user_node, ..
+                },
+                OriginId::Out {
+                    node: origin_node, ..
+                },
+            ) => (user_node, origin_node),
+            _ => return,
+        };
+        if self.node_data(user_node).outer_region != self.node_data(origin_node).outer_region {
+            return;
+        }
+        if let Some(path) = self.dependency_path(origin_node, user_node) {
+            panic!(
+                "connecting {:?} to {:?} closes the cycle {:?}",
+                user_id, origin_id, path
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeCtxtConfig, NodeKind};
+
+    #[test]
+    fn topological_order_after_rewiring() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n_neg = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        let n_st = ncx.mk_node(Op::St);
+        let n_store0 = ncx
+            .node_builder(Op::Store)
+            .operand(n_neg.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+        let n_store1 = ncx
+            .node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n_store0.st_out(0))
+            .finish();
+        let n1 = ncx.mk_node(Op::Lit(1));
+
+        // Makes nodes depend on nodes made after them.
+        n_neg.val_in(0).reconnect(n1.val_out(0));
+        n_store0.st_in(0).reconnect(n_store1.st_out(0));
+        n_store1.st_in(0).reconnect(n_st.st_out(0));
+
+        let root = ncx.region_ref(ncx.root_region());
+        let order: Vec<_> = root.topo_iter().collect();
+        let position = |node| order.iter().position(|&n| n == node).unwrap();
+
+        assert_eq!(6, order.len());
+        assert!(position(n1) < position(n_neg));
+        assert!(position(n_neg) < position(n_store0));
+        assert!(position(n_st) < position(n_store1));
+        assert!(position(n_store1) < position(n_store0));
+
+        let rev_order: Vec<_> = root.rev_topo_iter().collect();
+        assert_eq!(order.into_iter().rev().collect::<Vec<_>>(), rev_order);
+    }
+
+    #[test]
+    fn topological_order_stays_in_region() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let gamma = ncx.gamma_builder(n0.val_out(0), 1);
+        let branch = gamma.branch(0);
+        let args = gamma.entry_var(n0.val_out(0));
+        let n_neg = ncx
+            .node_builder_in(branch, Op::Neg)
+            .operand(args[0])
+            .finish();
+        gamma.exit_var(&[n_neg.val_out(0)]);
+        let gamma = gamma.finish();
+
+        let root: Vec<_> = ncx.region_ref(ncx.root_region()).topo_iter().collect();
+        assert_eq!(vec![n0, gamma], root);
+
+        let branch: Vec<_> = ncx.region_ref(branch).topo_iter().collect();
+        assert_eq!(vec![n_neg], branch);
+    }
+
+    #[test]
+    #[should_panic(expected = "closes the cycle")]
+    fn connecting_into_a_cycle() {
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_cycle_check: true,
+            ..NodeCtxtConfig::default()
+        });
+
+        let n_a = ncx.create_node(NodeKind::Op(Op::Neg), ncx.root_region());
+        let n_b = ncx.node_builder(Op::Neg).operand(n_a.val_out(0)).finish();
+        let n_c = ncx.node_builder(Op::Neg).operand(n_b.val_out(0)).finish();
+        assert_eq!(
+            Some(vec![n_c.id(), n_b.id(), n_a.id()]),
+            ncx.dependency_path(n_c.id(), n_a.id())
+        );
+        assert_eq!(None, ncx.dependency_path(n_a.id(), n_c.id()));
+
+        n_a.val_in(0).connect(n_c.val_out(0));
+    }
+}
@@ -0,0 +1,244 @@
+use super::{
+    BuildError, ConnectError, NodeCtxt, NodeId, NodeKind, OriginId, Sig, UserId, ValOrigin,
+};
+use std::{any::Any, fmt::Debug, rc::Rc};
+
+/// The type of a port, with the `Sig::Type` it was given as erased so that
+/// ports can hold one without knowing the op.
+pub trait PortType: Debug {
+    fn as_any(&self) -> &dyn Any;
+
+    fn same_as(&self, other: &dyn PortType) -> bool;
+}
+
+impl<T: Any + Debug + PartialEq> PortType for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn same_as(&self, other: &dyn PortType) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+}
+
+pub(super) fn erase<T: Any + Debug + PartialEq>(ty: T) -> Rc<dyn PortType> {
+    Rc::new(ty)
+}
+
+/// Whether ports of types `a` and `b` may be connected. Untyped ports may
+/// be connected to anything.
+fn compatible(a: Option<&dyn PortType>, b: Option<&dyn PortType>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.same_as(b),
+        _ => true,
+    }
+}
+
+impl<S> NodeCtxt<S> {
+    pub fn origin_type(&self, origin_id: OriginId) -> Option<Rc<dyn PortType>> {
+        self.origin_data(origin_id).ty.clone()
+    }
+
+    /// The type of `origin_id`, as the ops of the graph give it.
+    pub(super) fn typed_origin(&self, origin_id: OriginId) -> Option<S::Type>
+    where
+        S: Sig,
+    {
+        let ty = self.origin_type(origin_id)?;
+        ty.as_any().downcast_ref::<S::Type>().cloned()
+    }
+
+    pub(super) fn set_origin_type(&self, origin_id: OriginId, ty: Option<Rc<dyn PortType>>) {
+        match origin_id {
+            OriginId::Out { node, index } => {
+                let node_data = self.node_data_mut(node);
+                self.output_ports.get_mut(&node_data.outs)[usize::from(index)].ty = ty
+            }
+            OriginId::Arg { region, index } => {
+                self.region_data_mut(region).args[usize::from(index)].ty = ty
+            }
+        }
+    }
+
+    /// Gives the value ports of an op node the types its op declares.
+    pub(super) fn type_op_ports(&self, node_id: NodeId)
+    where
+        S: Sig,
+    {
+        let node_data = self.node_data_mut(node_id);
+        let op = match &node_data.kind {
+            NodeKind::Op(op) => op,
+            _ => return,
+        };
+        let sig = op.sig();
+        let mut ins = self.input_ports.get_mut(&node_data.ins);
+        for port in 0..sig.val_ins {
+            ins[port].ty = op.val_in_type(port).map(erase);
+        }
+        let mut outs = self.output_ports.get_mut(&node_data.outs);
+        for port in 0..sig.val_outs {
+            outs[port].ty = op.val_out_type(port).map(erase);
+        }
+    }
+
+    /// The error of connecting the value inputs of a `kind` node to
+    /// `origins`, if type checking is on and one of them has another type
+    /// than its input takes.
+    pub(super) fn mistyped_operand(
+        &self,
+        kind: &NodeKind<S>,
+        origins: &[OriginId],
+    ) -> Option<BuildError>
+    where
+        S: Sig,
+    {
+        if !self.config.opt_type_check {
+            return None;
+        }
+        (0..kind.sig().val_ins).find_map(|port| {
+            let expected = kind.val_in_type(port).map(erase);
+            let found = self.origin_type(origins[port]);
+            if compatible(expected.as_deref(), found.as_deref()) {
+                None
+            } else {
+                Some(BuildError::TypeMismatch {
+                    port,
+                    expected: format!("{:?}", expected.unwrap()),
+                    found: format!("{:?}", found.unwrap()),
+                })
+            }
+        })
+    }
+
+    /// The error of connecting `user_id` to `origin_id`, if type checking
+    /// is on and the latter has another type than the former takes.
+    pub(super) fn mistyped_connection(
+        &self,
+        user_id: UserId,
+        origin_id: OriginId,
+    ) -> Option<ConnectError> {
+        if !self.config.opt_type_check {
+            return None;
+        }
+        let expected = self.user_data(user_id).ty.clone();
+        let found = self.origin_type(origin_id);
+        if compatible(expected.as_deref(), found.as_deref()) {
+            None
+        } else {
+            Some(ConnectError::TypeMismatch {
+                expected: format!("{:?}", expected.unwrap()),
+                found: format!("{:?}", found.unwrap()),
+            })
+        }
+    }
+
+    /// Panics if type checking is on and `user_id` takes another type than
+    /// `origin_id` has.
+    pub(super) fn check_connection(&self, user_id: UserId, origin_id: OriginId) {
+        if let Some(err) = self.mistyped_connection(user_id, origin_id) {
+            panic!(
+                "{:?} can't be connected to {:?}: {}",
+                user_id, origin_id, err
+            );
+        }
+    }
+}
+
+impl<'g, S: Sig> ValOrigin<'g, S> {
+    /// The type of the value, if its producer gives it one.
+    pub fn ty(&self) -> Option<S::Type> {
+        self.0.ctxt.typed_origin(self.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rvsdg::{
+        testing::{Op, Ty},
+        BuildError, ConnectError, NodeCtxt, NodeCtxtConfig, NodeKind,
+    };
+
+    fn checked() -> NodeCtxt<Op> {
+        NodeCtxt::with_config(NodeCtxtConfig {
+            opt_type_check: true,
+            ..NodeCtxtConfig::default()
+        })
+    }
+
+    #[test]
+    fn routed_values_keep_their_type() {
+        let ncx = checked();
+        let n_pred = ncx.mk_node(Op::Bool(true));
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_any = ncx.mk_node(Op::Param(0));
+        assert_eq!(Some(Ty::Int), n_one.val_out(0).ty());
+        assert_eq!(None, n_any.val_out(0).ty());
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let args = gamma.entry_var(n_one.val_out(0));
+        assert_eq!(Some(Ty::Int), args[1].ty());
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let output = gamma.exit_var(&[n_neg.val_out(0), args[1]]);
+        gamma.finish();
+        assert_eq!(Some(Ty::Int), output.ty());
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (arg, output) = theta.loop_var(output);
+        assert_eq!(Some(Ty::Int), arg.ty());
+        assert_eq!(Some(Ty::Int), output.ty());
+        // Untyped values may go anywhere.
+        let n_any = ncx.node_builder_in(theta.body(), Op::Param(0)).finish();
+        theta.set_next(arg, n_any.val_out(0));
+        theta.finish(n_any.val_out(0));
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn mistyped_operands() {
+        let ncx = checked();
+        let n_one = ncx.mk_node(Op::Lit(1));
+        let n_true = ncx.mk_node(Op::Bool(true));
+        assert_eq!(
+            BuildError::TypeMismatch {
+                port: 1,
+                expected: "Int".to_owned(),
+                found: "Bool".to_owned(),
+            },
+            ncx.node_builder(Op::Lt)
+                .operand(n_one.val_out(0))
+                .operand(n_true.val_out(0))
+                .try_finish()
+                .unwrap_err()
+        );
+
+        let n_not = ncx.create_node(NodeKind::Op(Op::Not), ncx.root_region());
+        assert_eq!(
+            Err(ConnectError::TypeMismatch {
+                expected: "Bool".to_owned(),
+                found: "Int".to_owned(),
+            }),
+            n_not.val_in(0).try_connect(n_one.val_out(0))
+        );
+
+        // Without type checking, anything goes.
+        let ncx = NodeCtxt::new();
+        let n_one = ncx.mk_node(Op::Lit(1));
+        ncx.node_builder(Op::Not).operand(n_one.val_out(0)).finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "takes Bool, but")]
+    fn mistyped_exit_vars() {
+        let ncx = checked();
+        let n_pred = ncx.mk_node(Op::Bool(false));
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 2);
+        let n_true = ncx
+            .node_builder_in(gamma.branch(0), Op::Bool(true))
+            .finish();
+        let n_two = ncx.node_builder_in(gamma.branch(1), Op::Lit(2)).finish();
+        gamma.exit_var(&[n_true.val_out(0), n_two.val_out(0)]);
+    }
+}
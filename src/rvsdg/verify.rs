@@ -0,0 +1,537 @@
+use super::{NodeCtxt, NodeId, NodeKind, OriginId, PortKind, RegionId, Sig, SigS, UserId};
+use std::{collections::HashSet, iter};
+
+/// A broken invariant of the graph, found by `NodeCtxt::verify`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Violation {
+    /// A user is connected to an origin whose user list doesn't contain it.
+    UnlistedUser { user: UserId, origin: OriginId },
+    /// The user list of an origin contains a user connected elsewhere, or
+    /// links its users inconsistently.
+    BrokenUserList { origin: OriginId },
+    /// A user is connected to an origin in another region.
+    CrossRegionEdge { user: UserId, origin: OriginId },
+    /// A node has a different number of ports of some kind than its
+    /// signature says.
+    PortCountMismatch {
+        node: NodeId,
+        expected: SigS,
+        found: SigS,
+    },
+    /// A structural node has the wrong number of regions, or a simple node
+    /// has any.
+    RegionCountMismatch { node: NodeId, found: usize },
+    /// A region has a different number of arguments or results than its
+    /// node's ports call for.
+    RegionPortMismatch { region: RegionId },
+    /// A scheduling barrier has no state input or output to be ordered by.
+    StatelessBarrier { node: NodeId },
+    /// A node with state ports isn't ordered before or after a scheduling
+    /// barrier in its region.
+    UnorderedAcrossBarrier { barrier: NodeId, node: NodeId },
+    /// The nodes of `path` use each other's outputs in a cycle, each using
+    /// those of the next, and the last those of the first.
+    Cycle { path: Vec<NodeId> },
+    /// A state output has more than one user, which is only reported when
+    /// `opt_linear_state` is on.
+    SharedState { origin: OriginId, num_users: usize },
+}
+
+impl<S: Sig> NodeCtxt<S> {
+    /// Checks the invariants of the whole graph, returning every violation
+    /// found rather than stopping at the first one.
+    pub fn verify(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = vec![];
+        let mut listed_users = HashSet::new();
+
+        for origin in self.live_origins() {
+            if !self.verify_user_list(origin, &mut listed_users) {
+                violations.push(Violation::BrokenUserList { origin });
+            }
+            if self.config.opt_linear_state {
+                let origin_data = self.origin_data(origin);
+                let num_users = origin_data.num_users();
+                if origin_data.kind == PortKind::St && num_users > 1 {
+                    violations.push(Violation::SharedState { origin, num_users });
+                }
+            }
+        }
+
+        for user in self.live_users() {
+            let origin = match self.user_data(user).origin.get() {
+                Some(origin) => origin,
+                None => continue,
+            };
+            if !listed_users.contains(&user) {
+                violations.push(Violation::UnlistedUser { user, origin });
+            }
+            if self.user_region(user) != self.origin_region(origin) {
+                violations.push(Violation::CrossRegionEdge { user, origin });
+            }
+        }
+
+        let num_nodes = self.nodes.len();
+        for node_id in (0..num_nodes).map(NodeId::new) {
+            if !self.node_data(node_id).removed {
+                self.verify_node_shape(node_id, &mut violations);
+                self.verify_barrier(node_id, &mut violations);
+            }
+        }
+
+        let num_regions = self.regions.len();
+        for region_id in (0..num_regions).map(RegionId::new) {
+            if !self.region_data(region_id).removed {
+                self.verify_acyclic(region_id, &mut violations);
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Walks the user list of `origin`, recording the users in it, and
+    /// returns whether it's consistent.
+    fn verify_user_list(&self, origin: OriginId, listed_users: &mut HashSet<UserId>) -> bool {
+        let users = match self.origin_data(origin).users.get() {
+            Some(users) => users,
+            None => return true,
+        };
+
+        let mut prev_user = None;
+        let mut user = users.first;
+        let mut len = 1;
+        loop {
+            let user_data = self.user_data(user);
+            if user_data.origin.get() != Some(origin)
+                || user_data.prev_user.get() != prev_user
+                || !listed_users.insert(user)
+            {
+                return false;
+            }
+            match user_data.next_user.get() {
+                Some(next_user) => {
+                    prev_user = Some(user);
+                    user = next_user;
+                    len += 1;
+                }
+                None => return user == users.last && len == users.len,
+            }
+        }
+    }
+
+    fn verify_node_shape(&self, node_id: NodeId, violations: &mut Vec<Violation>) {
+        let node_data = self.node_data(node_id);
+        let count = |kinds: &mut dyn Iterator<Item = PortKind>| {
+            kinds.fold((0, 0), |(val, st), kind| match kind {
+                PortKind::Val => (val + 1, st),
+                PortKind::St => (val, st + 1),
+            })
+        };
+        let (val_ins, st_ins) = count(&mut node_data.ins.iter().map(|user| user.kind));
+        let (val_outs, st_outs) = count(&mut node_data.outs.iter().map(|origin| origin.kind));
+        let found = SigS {
+            val_ins,
+            val_outs,
+            st_ins,
+            st_outs,
+        };
+        let expected = node_data.kind.sig();
+        if found != expected {
+            violations.push(Violation::PortCountMismatch {
+                node: node_id,
+                expected,
+                found,
+            });
+        }
+
+        let inner_regions = self.inner_regions(node_id);
+        let num_regions = inner_regions.len();
+        let num_ins = node_data.ins.len();
+        let num_outs = node_data.outs.len();
+        let (num_regions_ok, args_and_res) = match node_data.kind {
+            NodeKind::Op(..) | NodeKind::Apply { .. } => (num_regions == 0, None),
+            // The predicate isn't passed to the branches.
+            NodeKind::Gamma { .. } => (
+                num_regions > 0,
+                Some((num_ins.saturating_sub(1), Some(num_outs))),
+            ),
+            // The first result is the loop predicate.
+            NodeKind::Theta { .. } => (num_regions == 1, Some((num_ins, Some(num_outs + 1)))),
+            // The parameters come before the context variables, and the
+            // results are the function's own.
+            NodeKind::Lambda { params, .. } => (num_regions == 1, Some((params + num_ins, None))),
+            NodeKind::Omega { .. } => (num_regions == 1, None),
+        };
+
+        if !num_regions_ok {
+            violations.push(Violation::RegionCountMismatch {
+                node: node_id,
+                found: num_regions,
+            });
+        }
+        if let Some((num_args, num_res)) = args_and_res {
+            for region in inner_regions {
+                let region_data = self.region_data(region);
+                let res_mismatch = num_res.is_some() && num_res != Some(region_data.res.len());
+                if region_data.args.len() != num_args || res_mismatch {
+                    violations.push(Violation::RegionPortMismatch { region });
+                }
+            }
+        }
+    }
+
+    fn verify_barrier(&self, node_id: NodeId, violations: &mut Vec<Violation>) {
+        let (is_barrier, sig) = match self.node_data(node_id).kind {
+            NodeKind::Op(ref op) => (op.is_scheduling_barrier(), op.sig()),
+            _ => return,
+        };
+        if !is_barrier {
+            return;
+        }
+        if sig.st_ins == 0 || sig.st_outs == 0 {
+            violations.push(Violation::StatelessBarrier { node: node_id });
+            return;
+        }
+
+        let ordered: HashSet<NodeId> = self
+            .state_reachable(node_id, false)
+            .union(&self.state_reachable(node_id, true))
+            .cloned()
+            .collect();
+        let region_id = self.node_data(node_id).outer_region;
+        for other in self.region_nodes(region_id) {
+            if other == node_id || ordered.contains(&other) {
+                continue;
+            }
+            let has_state = {
+                let node_data = self.node_data(other);
+                node_data.ins.iter().any(|user| user.kind == PortKind::St)
+                    || node_data
+                        .outs
+                        .iter()
+                        .any(|origin| origin.kind == PortKind::St)
+            };
+            if has_state {
+                violations.push(Violation::UnorderedAcrossBarrier {
+                    barrier: node_id,
+                    node: other,
+                });
+            }
+        }
+    }
+
+    /// The nodes in the region of `node_id` reachable from it by following
+    /// state edges forwards, or backwards.
+    fn state_reachable(&self, node_id: NodeId, forwards: bool) -> HashSet<NodeId> {
+        let mut reachable = HashSet::new();
+        let mut worklist = vec![node_id];
+        while let Some(node_id) = worklist.pop() {
+            let node_data = self.node_data(node_id);
+            let next: Vec<NodeId> = if forwards {
+                (0..node_data.outs.len())
+                    .filter(|&index| node_data.outs[index].kind == PortKind::St)
+                    .flat_map(|index| {
+                        self.origin_ref(OriginId::output(node_id, index))
+                            .users()
+                            .filter_map(|user| user.id().node_id())
+                            .collect::<Vec<_>>()
+                    })
+                    .collect()
+            } else {
+                node_data
+                    .ins
+                    .iter()
+                    .filter(|user| user.kind == PortKind::St)
+                    .filter_map(|user| user.origin.get()?.node_id())
+                    .collect()
+            };
+            for next in next {
+                if reachable.insert(next) {
+                    worklist.push(next);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Reports the cycles of nodes in `region_id`, walking the operands of
+    /// each node depth first.
+    fn verify_acyclic(&self, region_id: RegionId, violations: &mut Vec<Violation>) {
+        let mut done = HashSet::new();
+        for root in self.region_nodes(region_id) {
+            if done.contains(&root) {
+                continue;
+            }
+            // The nodes being walked, each using the outputs of the next,
+            // with the operands of theirs left to walk.
+            let mut path = vec![(root, self.operand_nodes(root))];
+            let mut on_path: HashSet<NodeId> = iter::once(root).collect();
+            while let Some(last) = path.len().checked_sub(1) {
+                match path[last].1.pop() {
+                    Some(operand) if done.contains(&operand) => {}
+                    Some(operand) if on_path.contains(&operand) => {
+                        let start = path.iter().position(|&(node, _)| node == operand);
+                        violations.push(Violation::Cycle {
+                            path: path[start.unwrap()..]
+                                .iter()
+                                .map(|&(node, _)| node)
+                                .collect(),
+                        });
+                    }
+                    Some(operand) => {
+                        on_path.insert(operand);
+                        path.push((operand, self.operand_nodes(operand)));
+                    }
+                    None => {
+                        let (node_id, _) = path.pop().unwrap();
+                        on_path.remove(&node_id);
+                        done.insert(node_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn live_origins(&self) -> Vec<OriginId> {
+        let mut origins = vec![];
+        for (node, node_data) in self.nodes.iter().map(|node| node.borrow()).enumerate() {
+            origins.extend(
+                (0..node_data.outs.len()).map(|index| OriginId::output(NodeId::new(node), index)),
+            );
+        }
+        for (region, region_data) in self
+            .regions
+            .iter()
+            .map(|region| region.borrow())
+            .enumerate()
+        {
+            origins.extend(
+                (0..region_data.args.len())
+                    .map(|index| OriginId::argument(RegionId::new(region), index)),
+            );
+        }
+        origins
+    }
+
+    fn live_users(&self) -> Vec<UserId> {
+        let mut users = vec![];
+        for (node, node_data) in self.nodes.iter().map(|node| node.borrow()).enumerate() {
+            users.extend(
+                (0..node_data.ins.len()).map(|index| UserId::input(NodeId::new(node), index)),
+            );
+        }
+        for (region, region_data) in self
+            .regions
+            .iter()
+            .map(|region| region.borrow())
+            .enumerate()
+        {
+            users.extend(
+                (0..region_data.res.len())
+                    .map(|index| UserId::result(RegionId::new(region), index)),
+            );
+        }
+        users
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Violation;
+    use crate::rvsdg::{
+        testing::Op, NodeCtxt, NodeCtxtConfig, NodeKind, PortKind, Sig, SigS, UserId,
+    };
+
+    #[test]
+    fn well_formed_graph() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        ncx.node_builder(Op::Add)
+            .operand(n0.val_out(0))
+            .operand(n1.val_out(0))
+            .finish();
+
+        let theta = ncx.theta_builder(ncx.root_region());
+        let (x_arg, _) = theta.loop_var(n1.val_out(0));
+        let gamma = ncx.gamma_builder(x_arg, 2);
+        let args = gamma.entry_var(x_arg);
+        let x_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(args[0])
+            .finish();
+        let x_next = gamma.exit_var(&[x_neg.val_out(0), args[1]]);
+        gamma.finish();
+        theta.set_next(x_arg, x_next);
+        theta.finish(x_arg);
+
+        assert_eq!(Ok(()), ncx.verify());
+    }
+
+    #[test]
+    fn violations_are_all_reported() {
+        let ncx = NodeCtxt::new();
+
+        let n_pred = ncx.mk_node(Op::Lit(0));
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let n_add = ncx
+            .node_builder(Op::Sub)
+            .operand(n_x.val_out(0))
+            .operand(n_pred.val_out(0))
+            .finish();
+
+        let gamma = ncx.gamma_builder(n_pred.val_out(0), 1);
+        let n_lit = ncx.node_builder_in(gamma.branch(0), Op::Lit(2)).finish();
+        let n_neg = ncx
+            .node_builder_in(gamma.branch(0), Op::Neg)
+            .operand(n_lit.val_out(0))
+            .finish();
+        gamma.exit_var(&[n_neg.val_out(0)]);
+        gamma.finish();
+
+        // Connecting ports rejects the edge into the branch, so it's linked
+        // without the check to make a graph that isn't well-formed.
+        n_neg.val_in(0).disconnect();
+        ncx.link_user(n_neg.val_in(0).id(), n_x.val_out(0).id());
+        ncx.add_output(n_lit.id(), PortKind::St);
+        let add_in0 = UserId::In {
+            node: n_add.id(),
+            index: 0,
+        };
+        ncx.user_data(add_in0).prev_user.set(Some(add_in0));
+
+        let violations = ncx.verify().unwrap_err();
+
+        // The users past the broken link in the user list of `n_x` aren't
+        // reached, so they're reported as well.
+        assert_eq!(5, violations.len());
+        assert!(violations.contains(&Violation::UnlistedUser {
+            user: UserId::In {
+                node: n_neg.id(),
+                index: 0,
+            },
+            origin: n_x.val_out(0).id(),
+        }));
+        assert!(violations.contains(&Violation::BrokenUserList {
+            origin: n_x.val_out(0).id(),
+        }));
+        assert!(violations.contains(&Violation::CrossRegionEdge {
+            user: UserId::In {
+                node: n_neg.id(),
+                index: 0,
+            },
+            origin: n_x.val_out(0).id(),
+        }));
+        assert!(violations.contains(&Violation::PortCountMismatch {
+            node: n_lit.id(),
+            expected: Op::Lit(2).sig(),
+            found: SigS {
+                val_outs: 1,
+                st_outs: 1,
+                ..SigS::default()
+            },
+        }));
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let ncx = NodeCtxt::new();
+
+        let n_lit = ncx.mk_node(Op::Lit(0));
+        let n_neg = ncx.create_node(NodeKind::Op(Op::Neg), ncx.root_region());
+        let n_add = ncx
+            .node_builder(Op::Add)
+            .operand(n_lit.val_out(0))
+            .operand(n_neg.val_out(0))
+            .finish();
+        n_neg.val_in(0).connect(n_add.val_out(0));
+
+        assert_eq!(
+            Err(vec![Violation::Cycle {
+                path: vec![n_neg.id(), n_add.id()],
+            }]),
+            ncx.verify()
+        );
+    }
+
+    #[test]
+    fn ops_ordered_around_barriers() {
+        let ncx = NodeCtxt::new();
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n_st = ncx.mk_node(Op::St);
+        let n_store0 = ncx
+            .node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n_st.st_out(0))
+            .finish();
+        let n_call = ncx
+            .node_builder(Op::Call)
+            .operand(n0.val_out(0))
+            .state(n_store0.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n_call.st_out(0))
+            .finish();
+
+        assert_eq!(Ok(()), ncx.verify());
+
+        // A store whose state doesn't go through the call could be moved
+        // across it.
+        let n_store2 = ncx
+            .node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n_store0.st_out(0))
+            .finish();
+
+        assert_eq!(
+            Err(vec![Violation::UnorderedAcrossBarrier {
+                barrier: n_call.id(),
+                node: n_store2.id(),
+            }]),
+            ncx.verify()
+        );
+    }
+
+    #[test]
+    fn linear_state() {
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_linear_state: true,
+            ..NodeCtxtConfig::default()
+        });
+
+        let n0 = ncx.mk_node(Op::Lit(0));
+        let n_s = ncx.mk_node(Op::St);
+        let n_store0 = ncx
+            .node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n_s.st_out(0))
+            .finish();
+        ncx.node_builder(Op::Store)
+            .operand(n0.val_out(0))
+            .state(n_store0.st_out(0))
+            .finish();
+
+        assert_eq!(Ok(()), ncx.verify());
+
+        // Values may have any number of users, but state may not.
+        let n1 = ncx.node_builder(Op::Neg).operand(n0.val_out(0)).finish();
+        ncx.node_builder(Op::Store)
+            .operand(n1.val_out(0))
+            .state(n_s.st_out(0))
+            .finish();
+
+        assert_eq!(
+            Err(vec![Violation::SharedState {
+                origin: n_s.st_out(0).id(),
+                num_users: 2,
+            }]),
+            ncx.verify()
+        );
+    }
+}
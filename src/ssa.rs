@@ -0,0 +1,555 @@
+use crate::rvsdg::{GammaBuilder, NodeCtxt, RegionId, Sig, StOrigin, ThetaBuilder, ValOrigin};
+use std::{collections::HashMap, hash::Hash};
+
+/// The current definition of a variable.
+enum Def<'g, S> {
+    Val(ValOrigin<'g, S>),
+    St(StOrigin<'g, S>),
+}
+
+impl<'g, S: Clone> Clone for Def<'g, S> {
+    fn clone(&self) -> Def<'g, S> {
+        match self {
+            Def::Val(origin) => Def::Val(origin.clone()),
+            Def::St(origin) => Def::St(origin.clone()),
+        }
+    }
+}
+
+/// Variable definitions in a region, remembering the order in which
+/// variables were first written so that routed ports are created
+/// deterministically.
+struct Defs<'g, S, V> {
+    defs: HashMap<V, Def<'g, S>>,
+    written: Vec<V>,
+}
+
+impl<'g, S: Clone, V: Clone + Eq + Hash> Defs<'g, S, V> {
+    fn new() -> Defs<'g, S, V> {
+        Defs {
+            defs: HashMap::new(),
+            written: vec![],
+        }
+    }
+
+    fn get(&self, var: &V) -> Option<Def<'g, S>> {
+        self.defs.get(var).cloned()
+    }
+
+    fn write(&mut self, var: V, def: Def<'g, S>) {
+        if !self.written.contains(&var) {
+            self.written.push(var.clone());
+        }
+        self.defs.insert(var, def);
+    }
+}
+
+/// The argument and output of a loop variable.
+type LoopVar<'g, S> = (Def<'g, S>, Def<'g, S>);
+
+enum Scope<'g, S, V> {
+    Region {
+        region: RegionId,
+        defs: Defs<'g, S, V>,
+    },
+    Gamma {
+        builder: GammaBuilder<'g, S>,
+        current_branch: usize,
+        entries: HashMap<V, Vec<Def<'g, S>>>,
+        branches: Vec<Defs<'g, S, V>>,
+    },
+    Theta {
+        builder: ThetaBuilder<'g, S>,
+        loop_vars: Vec<(V, LoopVar<'g, S>)>,
+        defs: Defs<'g, S, V>,
+    },
+}
+
+/// Constructs an RVSDG from a frontend that reads and writes named
+/// variables, instead of wiring origins by hand.
+///
+/// Structured control flow is described with `begin_gamma`/`next_branch`/
+/// `end_gamma` and `begin_theta`/`end_theta`. Whenever a variable defined
+/// outside of a structural node is read inside of it, the corresponding
+/// entry or loop variable is created, and variables written inside are
+/// exported through exit or loop variables once the node is finished.
+///
+/// Nodes computing the values must be created in `region()`.
+pub struct SsaBuilder<'g, S, V> {
+    ncx: &'g NodeCtxt<S>,
+    scopes: Vec<Scope<'g, S, V>>,
+}
+
+impl<'g, S, V> SsaBuilder<'g, S, V>
+where
+    S: Sig + Eq + Hash + Clone,
+    V: Clone + Eq + Hash,
+{
+    pub fn new(ncx: &'g NodeCtxt<S>, region: RegionId) -> SsaBuilder<'g, S, V> {
+        SsaBuilder {
+            ncx,
+            scopes: vec![Scope::Region {
+                region,
+                defs: Defs::new(),
+            }],
+        }
+    }
+
+    /// The region in which the current definitions live.
+    pub fn region(&self) -> RegionId {
+        match self.scopes.last().unwrap() {
+            Scope::Region { region, .. } => *region,
+            Scope::Gamma {
+                builder,
+                current_branch,
+                ..
+            } => builder.branch(*current_branch),
+            Scope::Theta { builder, .. } => builder.body(),
+        }
+    }
+
+    pub fn write_val(&mut self, var: V, origin: ValOrigin<'g, S>) {
+        self.write(var, Def::Val(origin));
+    }
+
+    pub fn write_state(&mut self, var: V, origin: StOrigin<'g, S>) {
+        self.write(var, Def::St(origin));
+    }
+
+    /// Returns the current value of `var`, or None if it was never written.
+    pub fn read_val(&mut self, var: &V) -> Option<ValOrigin<'g, S>> {
+        match self.read(self.scopes.len() - 1, var)? {
+            Def::Val(origin) => Some(origin),
+            Def::St(..) => panic!("variable holds a state, not a value"),
+        }
+    }
+
+    /// Returns the current state of `var`, or None if it was never written.
+    pub fn read_state(&mut self, var: &V) -> Option<StOrigin<'g, S>> {
+        match self.read(self.scopes.len() - 1, var)? {
+            Def::St(origin) => Some(origin),
+            Def::Val(..) => panic!("variable holds a value, not a state"),
+        }
+    }
+
+    /// Enters the first branch of a gamma node selected by `predicate`.
+    pub fn begin_gamma(&mut self, predicate: ValOrigin<'g, S>, num_branches: usize) {
+        assert!(num_branches > 0);
+        let builder = self.ncx.gamma_builder(predicate, num_branches);
+        self.scopes.push(Scope::Gamma {
+            builder,
+            current_branch: 0,
+            entries: HashMap::new(),
+            branches: (0..num_branches).map(|_| Defs::new()).collect(),
+        });
+    }
+
+    /// Leaves the current branch of the innermost gamma node and enters the
+    /// next one.
+    pub fn next_branch(&mut self) {
+        match self.scopes.last_mut().unwrap() {
+            Scope::Gamma {
+                builder,
+                current_branch,
+                ..
+            } => {
+                assert!(*current_branch + 1 < builder.num_branches());
+                *current_branch += 1;
+            }
+            _ => panic!("not inside of a gamma"),
+        }
+    }
+
+    /// Finishes the innermost gamma node, defining every variable that is
+    /// available at the end of all branches with the gamma's outputs.
+    pub fn end_gamma(&mut self) {
+        let (builder, mut entries, branches) = match self.scopes.pop() {
+            Some(Scope::Gamma {
+                builder,
+                entries,
+                branches,
+                ..
+            }) => (builder, entries, branches),
+            _ => panic!("not inside of a gamma"),
+        };
+
+        let mut written: Vec<V> = vec![];
+        for var in branches.iter().flat_map(|defs| defs.written.iter()) {
+            if !written.contains(var) {
+                written.push(var.clone());
+            }
+        }
+
+        for var in written {
+            let mut results = Vec::with_capacity(branches.len());
+            for (index, defs) in branches.iter().enumerate() {
+                let def = match defs.get(&var) {
+                    Some(def) => def,
+                    None => match self.entry(&builder, &mut entries, &var) {
+                        Some(args) => args[index].clone(),
+                        // Only defined in some of the branches, so it's
+                        // unavailable after the gamma.
+                        None => break,
+                    },
+                };
+                results.push(def);
+            }
+            if results.len() != branches.len() {
+                continue;
+            }
+            let exit = match &results[0] {
+                Def::Val(..) => Def::Val(builder.exit_var(&vals(&results))),
+                Def::St(..) => Def::St(builder.exit_state(&states(&results))),
+            };
+            self.write(var, exit);
+        }
+
+        builder.finish();
+    }
+
+    /// Enters the body of a theta node.
+    pub fn begin_theta(&mut self) {
+        let builder = self.ncx.theta_builder(self.region());
+        self.scopes.push(Scope::Theta {
+            builder,
+            loop_vars: vec![],
+            defs: Defs::new(),
+        });
+    }
+
+    /// Finishes the innermost theta node, which repeats while `predicate`
+    /// holds, defining every variable written in its body with the theta's
+    /// outputs.
+    pub fn end_theta(&mut self, predicate: ValOrigin<'g, S>) {
+        let (builder, mut loop_vars, defs) = match self.scopes.pop() {
+            Some(Scope::Theta {
+                builder,
+                loop_vars,
+                defs,
+            }) => (builder, loop_vars, defs),
+            _ => panic!("not inside of a theta"),
+        };
+
+        // Variables written before ever being read in the body still need a
+        // loop variable so their value flows into the next iteration.
+        for var in &defs.written {
+            if loop_vars.iter().any(|(loop_var, _)| loop_var == var) {
+                continue;
+            }
+            let top = self.scopes.len() - 1;
+            if let Some(init) = self.read(top, var) {
+                let arg = add_loop_var(&builder, init);
+                loop_vars.push((var.clone(), arg));
+            }
+        }
+
+        let mut outputs = Vec::with_capacity(loop_vars.len());
+        for (var, (arg, output)) in &loop_vars {
+            match (arg, defs.get(var).unwrap()) {
+                (Def::Val(arg), Def::Val(next)) => builder.set_next(arg.clone(), next),
+                (Def::St(arg), Def::St(next)) => builder.set_next_state(arg.clone(), next),
+                _ => panic!("variable changed between value and state inside of a loop"),
+            }
+            if defs.written.contains(var) {
+                outputs.push((var.clone(), output.clone()));
+            }
+        }
+
+        builder.finish(predicate);
+
+        for (var, output) in outputs {
+            self.write(var, output);
+        }
+    }
+
+    fn write(&mut self, var: V, def: Def<'g, S>) {
+        match self.scopes.last_mut().unwrap() {
+            Scope::Region { defs, .. } | Scope::Theta { defs, .. } => defs.write(var, def),
+            Scope::Gamma {
+                current_branch,
+                branches,
+                ..
+            } => branches[*current_branch].write(var, def),
+        }
+    }
+
+    fn read(&mut self, depth: usize, var: &V) -> Option<Def<'g, S>> {
+        let def = match &self.scopes[depth] {
+            Scope::Region { defs, .. } => return defs.get(var),
+            Scope::Gamma {
+                current_branch,
+                entries,
+                branches,
+                ..
+            } => branches[*current_branch]
+                .get(var)
+                .or_else(|| entries.get(var).map(|args| args[*current_branch].clone())),
+            Scope::Theta { defs, .. } => defs.get(var),
+        };
+        if def.is_some() {
+            return def;
+        }
+
+        // Not defined in this scope yet, so route it in from the outside.
+        let outer = self.read(depth - 1, var)?;
+        match &mut self.scopes[depth] {
+            Scope::Gamma {
+                builder,
+                current_branch,
+                entries,
+                ..
+            } => {
+                let args = add_entry(builder, outer);
+                let arg = args[*current_branch].clone();
+                entries.insert(var.clone(), args);
+                Some(arg)
+            }
+            Scope::Theta {
+                builder,
+                loop_vars,
+                defs,
+            } => {
+                let (arg, output) = add_loop_var(builder, outer);
+                loop_vars.push((var.clone(), (arg.clone(), output)));
+                defs.defs.insert(var.clone(), arg.clone());
+                Some(arg)
+            }
+            Scope::Region { .. } => unreachable!(),
+        }
+    }
+
+    fn entry(
+        &mut self,
+        builder: &GammaBuilder<'g, S>,
+        entries: &mut HashMap<V, Vec<Def<'g, S>>>,
+        var: &V,
+    ) -> Option<Vec<Def<'g, S>>> {
+        if let Some(args) = entries.get(var) {
+            return Some(args.clone());
+        }
+        let top = self.scopes.len() - 1;
+        let outer = self.read(top, var)?;
+        let args = add_entry(builder, outer);
+        entries.insert(var.clone(), args.clone());
+        Some(args)
+    }
+}
+
+fn add_entry<'g, S: Sig + Clone>(
+    builder: &GammaBuilder<'g, S>,
+    outer: Def<'g, S>,
+) -> Vec<Def<'g, S>> {
+    match outer {
+        Def::Val(origin) => builder
+            .entry_var(origin)
+            .into_iter()
+            .map(Def::Val)
+            .collect(),
+        Def::St(origin) => builder
+            .entry_state(origin)
+            .into_iter()
+            .map(Def::St)
+            .collect(),
+    }
+}
+
+fn add_loop_var<'g, S: Sig + Clone>(
+    builder: &ThetaBuilder<'g, S>,
+    init: Def<'g, S>,
+) -> (Def<'g, S>, Def<'g, S>) {
+    match init {
+        Def::Val(origin) => {
+            let (arg, output) = builder.loop_var(origin);
+            (Def::Val(arg), Def::Val(output))
+        }
+        Def::St(origin) => {
+            let (arg, output) = builder.loop_state(origin);
+            (Def::St(arg), Def::St(output))
+        }
+    }
+}
+
+fn vals<'g, S: Clone>(defs: &[Def<'g, S>]) -> Vec<ValOrigin<'g, S>> {
+    defs.iter()
+        .map(|def| match def {
+            Def::Val(origin) => origin.clone(),
+            Def::St(..) => panic!("variable is a state in some branches only"),
+        })
+        .collect()
+}
+
+fn states<'g, S: Clone>(defs: &[Def<'g, S>]) -> Vec<StOrigin<'g, S>> {
+    defs.iter()
+        .map(|def| match def {
+            Def::St(origin) => origin.clone(),
+            Def::Val(..) => panic!("variable is a value in some branches only"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::SsaBuilder;
+    use crate::rvsdg::{testing::Op, NodeCtxt, NodeKind};
+
+    #[test]
+    fn straight_line_code() {
+        let ncx = NodeCtxt::new();
+        let mut ssa = SsaBuilder::new(&ncx, ncx.root_region());
+
+        // x = 1; x = x + x;
+        ssa.write_val("x", ncx.mk_node(Op::Lit(1)).val_out(0));
+        let x = ssa.read_val(&"x").unwrap();
+        let add = ncx
+            .node_builder_in(ssa.region(), Op::Add)
+            .operands(&[x, x])
+            .finish();
+        ssa.write_val("x", add.val_out(0));
+
+        assert_eq!(Some(add.val_out(0)), ssa.read_val(&"x"));
+        assert_eq!(None, ssa.read_val(&"y"));
+    }
+
+    #[test]
+    fn if_else_routes_variables() {
+        let ncx = NodeCtxt::new();
+        let mut ssa = SsaBuilder::new(&ncx, ncx.root_region());
+
+        // x = 1; y = 2;
+        // if p { x = x + y } else { y = 3; z = 4 }
+        let n_x = ncx.mk_node(Op::Lit(1));
+        let n_y = ncx.mk_node(Op::Lit(2));
+        ssa.write_val("x", n_x.val_out(0));
+        ssa.write_val("y", n_y.val_out(0));
+
+        let n_p = ncx.mk_node(Op::Lit(0));
+        ssa.begin_gamma(n_p.val_out(0), 2);
+
+        let x = ssa.read_val(&"x").unwrap();
+        let y = ssa.read_val(&"y").unwrap();
+        let add = ncx
+            .node_builder_in(ssa.region(), Op::Add)
+            .operands(&[x, y])
+            .finish();
+        ssa.write_val("x", add.val_out(0));
+
+        ssa.next_branch();
+        let n_3 = ncx.node_builder_in(ssa.region(), Op::Lit(3)).finish();
+        ssa.write_val("y", n_3.val_out(0));
+        let n_4 = ncx.node_builder_in(ssa.region(), Op::Lit(4)).finish();
+        ssa.write_val("z", n_4.val_out(0));
+
+        ssa.end_gamma();
+
+        let gamma = ssa.read_val(&"x").unwrap().producer();
+        assert_eq!(
+            NodeKind::Gamma {
+                val_ins: 2,
+                val_outs: 2,
+                st_ins: 0,
+                st_outs: 0,
+            },
+            *gamma.kind()
+        );
+        assert_eq!(n_p.val_out(0), gamma.val_in(0).origin());
+        assert_eq!(n_x.val_out(0), gamma.val_in(1).origin());
+        assert_eq!(n_y.val_out(0), gamma.val_in(2).origin());
+        assert_eq!(Some(gamma.val_out(0)), ssa.read_val(&"x"));
+        assert_eq!(Some(gamma.val_out(1)), ssa.read_val(&"y"));
+        // Only defined in one of the branches.
+        assert_eq!(None, ssa.read_val(&"z"));
+    }
+
+    #[test]
+    fn loop_creates_loop_vars() {
+        let ncx = NodeCtxt::new();
+        let mut ssa = SsaBuilder::new(&ncx, ncx.root_region());
+
+        // i = 0; n = 10; s = state;
+        // do { i = i + 1; s = print(i, s) } while i < n
+        let n_0 = ncx.mk_node(Op::Lit(0));
+        let n_10 = ncx.mk_node(Op::Lit(10));
+        let n_s = ncx.mk_node(Op::St);
+        ssa.write_val("i", n_0.val_out(0));
+        ssa.write_val("n", n_10.val_out(0));
+        ssa.write_state("s", n_s.st_out(0));
+
+        ssa.begin_theta();
+        let i = ssa.read_val(&"i").unwrap();
+        let one = ncx.node_builder_in(ssa.region(), Op::Lit(1)).finish();
+        let add = ncx
+            .node_builder_in(ssa.region(), Op::Add)
+            .operands(&[i, one.val_out(0)])
+            .finish();
+        ssa.write_val("i", add.val_out(0));
+        let i = ssa.read_val(&"i").unwrap();
+        let print = ncx
+            .node_builder_in(ssa.region(), Op::Print)
+            .operand(i)
+            .state(ssa.read_state(&"s").unwrap())
+            .finish();
+        ssa.write_state("s", print.st_out(0));
+        let n = ssa.read_val(&"n").unwrap();
+        let lt = ncx
+            .node_builder_in(ssa.region(), Op::Lt)
+            .operands(&[i, n])
+            .finish();
+        ssa.end_theta(lt.val_out(0));
+
+        let theta = ssa.read_val(&"i").unwrap().producer();
+        assert_eq!(
+            NodeKind::Theta {
+                val_loop_vars: 2,
+                st_loop_vars: 1,
+            },
+            *theta.kind()
+        );
+        assert_eq!(n_0.val_out(0), theta.val_in(0).origin());
+        assert_eq!(n_10.val_out(0), theta.val_in(1).origin());
+        assert_eq!(n_s.st_out(0), theta.st_in(0).origin());
+        assert_eq!(Some(theta.val_out(0)), ssa.read_val(&"i"));
+        assert_eq!(Some(theta.st_out(0)), ssa.read_state(&"s"));
+        // Loop invariant, so it keeps its definition from before the loop.
+        assert_eq!(Some(n_10.val_out(0)), ssa.read_val(&"n"));
+    }
+
+    #[test]
+    fn nested_gamma_in_theta() {
+        let ncx = NodeCtxt::new();
+        let mut ssa = SsaBuilder::new(&ncx, ncx.root_region());
+
+        // x = 0; do { if x { x = 1 } } while x
+        let n_0 = ncx.mk_node(Op::Lit(0));
+        ssa.write_val("x", n_0.val_out(0));
+
+        ssa.begin_theta();
+        let x = ssa.read_val(&"x").unwrap();
+        ssa.begin_gamma(x, 2);
+        let n_1 = ncx.node_builder_in(ssa.region(), Op::Lit(1)).finish();
+        ssa.write_val("x", n_1.val_out(0));
+        ssa.next_branch();
+        ssa.end_gamma();
+        let x = ssa.read_val(&"x").unwrap();
+        let gamma = x.producer();
+        ssa.end_theta(x);
+
+        let theta = ssa.read_val(&"x").unwrap().producer();
+        assert_eq!(
+            NodeKind::Theta {
+                val_loop_vars: 1,
+                st_loop_vars: 0,
+            },
+            *theta.kind()
+        );
+        assert_eq!(
+            NodeKind::Gamma {
+                val_ins: 1,
+                val_outs: 1,
+                st_ins: 0,
+                st_outs: 0,
+            },
+            *gamma.kind()
+        );
+        assert_eq!(n_0.val_out(0), theta.val_in(0).origin());
+    }
+}
@@ -0,0 +1,253 @@
+//! Finds how the blocks of a function nest into branches and loops, for the
+//! frontends importing functions from a CFG into gammas and thetas.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+/// A block of a function, as far as its structure is concerned.
+pub(crate) struct FlowBlock<V> {
+    /// The blocks branched to at its end, none for blocks that return.
+    pub(crate) succs: Vec<usize>,
+    /// The values the block defines.
+    pub(crate) defs: Vec<V>,
+    /// The values defined in the function the block uses, counting those
+    /// passed to its parameters on the way there.
+    pub(crate) uses: Vec<V>,
+}
+
+/// A loop of a function, entered at its header.
+pub(crate) struct Loop<V> {
+    /// The only block branching back to the header, and out of the loop.
+    pub(crate) latch: usize,
+    pub(crate) exit: usize,
+    /// The values defined in the loop that are used after it.
+    pub(crate) live_out: Vec<V>,
+}
+
+/// Why the blocks of a function don't nest into thetas.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum StructureError {
+    /// `block` branches back to `header` without going through it first,
+    /// so the loop has several entries.
+    Irreducible { header: usize, block: usize },
+    /// `block` branches back to `header`, as another block does.
+    SeveralLatches { header: usize, block: usize },
+    /// The loop at `header` is left from other blocks than `latch`, the
+    /// block branching back to it, or to several blocks.
+    LeftMidway { header: usize, latch: usize },
+}
+
+/// How the blocks of a function, starting at the first one, nest into
+/// branches and loops.
+pub(crate) struct Structure<V> {
+    /// The immediate post-dominator of each block, or `None` if the
+    /// branches out of it only meet again at returns.
+    pub(crate) ipdoms: Vec<Option<usize>>,
+    /// The loops of the function, by their headers.
+    pub(crate) loops: HashMap<usize, Loop<V>>,
+}
+
+impl<V: Clone + Eq + Hash> Structure<V> {
+    pub(crate) fn new(blocks: &[FlowBlock<V>]) -> Result<Structure<V>, StructureError> {
+        let num_blocks = blocks.len();
+        let succs: Vec<Vec<usize>> = blocks.iter().map(|block| block.succs.clone()).collect();
+        let doms = Dominance::new(&succs, 0);
+        let mut preds = vec![vec![]; num_blocks];
+        for (block, block_succs) in succs.iter().enumerate() {
+            if doms.is_reached(block) {
+                for &succ in block_succs {
+                    preds[succ].push(block);
+                }
+            }
+        }
+
+        // Branching back to a block that doesn't dominate the branch makes a
+        // loop with several entries.
+        let mut latches: HashMap<usize, usize> = HashMap::new();
+        for block in (0..num_blocks).filter(|&block| doms.is_reached(block)) {
+            for &succ in &succs[block] {
+                if !doms.is_retreating(block, succ) {
+                    continue;
+                }
+                if !doms.dominates(succ, block) {
+                    return Err(StructureError::Irreducible {
+                        header: succ,
+                        block,
+                    });
+                }
+                if latches.insert(succ, block).is_some() {
+                    return Err(StructureError::SeveralLatches {
+                        header: succ,
+                        block,
+                    });
+                }
+            }
+        }
+
+        let mut loops = HashMap::new();
+        for (&header, &latch) in &latches {
+            let mut body = HashSet::new();
+            body.insert(header);
+            let mut worklist = vec![latch];
+            while let Some(block) = worklist.pop() {
+                if body.insert(block) {
+                    worklist.extend(preds[block].iter().copied());
+                }
+            }
+
+            let unsupported = StructureError::LeftMidway { header, latch };
+            let mut exits = vec![];
+            for &block in &body {
+                for &succ in &succs[block] {
+                    if !body.contains(&succ) {
+                        if block != latch {
+                            return Err(unsupported);
+                        }
+                        exits.push(succ);
+                    }
+                }
+            }
+            if exits.len() != 1
+                || latches
+                    .values()
+                    .any(|&other| other == header && latch != header)
+            {
+                return Err(unsupported);
+            }
+
+            let defined: HashSet<&V> = body.iter().flat_map(|&block| &blocks[block].defs).collect();
+            let mut live_out = vec![];
+            for (block, block_data) in blocks.iter().enumerate() {
+                if body.contains(&block) {
+                    continue;
+                }
+                for value in &block_data.uses {
+                    if defined.contains(value) && !live_out.contains(value) {
+                        live_out.push(value.clone());
+                    }
+                }
+            }
+            loops.insert(
+                header,
+                Loop {
+                    latch,
+                    exit: exits[0],
+                    live_out,
+                },
+            );
+        }
+
+        // Post-dominators are the dominators of the reversed graph, starting
+        // from a block every return goes to.
+        let exit = num_blocks;
+        let mut reversed: Vec<Vec<usize>> = preds;
+        let returns =
+            (0..num_blocks).filter(|&block| doms.is_reached(block) && succs[block].is_empty());
+        reversed.push(returns.collect());
+        let post_doms = Dominance::new(&reversed, exit);
+        let ipdoms = (0..num_blocks)
+            .map(|block| post_doms.idoms[block].filter(|&ipdom| ipdom != exit))
+            .collect();
+
+        Ok(Structure { ipdoms, loops })
+    }
+}
+
+/// The dominator tree of a graph, found with the algorithm of Cooper,
+/// Harvey and Kennedy.
+struct Dominance {
+    /// The immediate dominator of each node, or `None` for the root and the
+    /// nodes it doesn't reach.
+    idoms: Vec<Option<usize>>,
+    /// Where each node reached from the root comes in a postorder.
+    post_order: Vec<Option<usize>>,
+}
+
+impl Dominance {
+    fn new(succs: &[Vec<usize>], root: usize) -> Dominance {
+        let num_nodes = succs.len();
+        let mut order = vec![];
+        let mut post_order = vec![None; num_nodes];
+        let mut visited = vec![false; num_nodes];
+        let mut stack = vec![(root, 0)];
+        visited[root] = true;
+        while let Some((node, next)) = stack.pop() {
+            match succs[node].get(next) {
+                Some(&succ) => {
+                    stack.push((node, next + 1));
+                    if !visited[succ] {
+                        visited[succ] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => {
+                    post_order[node] = Some(order.len());
+                    order.push(node);
+                }
+            }
+        }
+
+        let mut preds = vec![vec![]; num_nodes];
+        for &node in &order {
+            for &succ in &succs[node] {
+                preds[succ].push(node);
+            }
+        }
+        let mut idoms = vec![None; num_nodes];
+        idoms[root] = Some(root);
+        let intersect = |idoms: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while post_order[a] < post_order[b] {
+                    a = idoms[a].unwrap();
+                }
+                while post_order[b] < post_order[a] {
+                    b = idoms[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in order.iter().rev().filter(|&&node| node != root) {
+                let mut idom = None;
+                for &pred in preds[node].iter().filter(|&&pred| idoms[pred].is_some()) {
+                    idom = Some(match idom {
+                        Some(idom) => intersect(&idoms, pred, idom),
+                        None => pred,
+                    });
+                }
+                if idoms[node] != idom {
+                    idoms[node] = idom;
+                    changed = true;
+                }
+            }
+        }
+        idoms[root] = None;
+        Dominance { idoms, post_order }
+    }
+
+    fn is_reached(&self, node: usize) -> bool {
+        self.post_order[node].is_some()
+    }
+
+    /// Whether the edge from `from` to `to` goes back up the depth-first
+    /// tree, as the edges closing loops do.
+    fn is_retreating(&self, from: usize, to: usize) -> bool {
+        self.post_order[to] >= self.post_order[from]
+    }
+
+    fn dominates(&self, a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idoms[b] {
+                Some(idom) => b = idom,
+                None => return false,
+            }
+        }
+    }
+}
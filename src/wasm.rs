@@ -0,0 +1,1340 @@
+//! A frontend reading WebAssembly modules.
+//!
+//! Each function the module defines becomes a lambda in the root region,
+//! taking its parameters followed by a state and producing its results
+//! followed by the state. Accesses to memory and globals, and calls, are
+//! threaded on that state in the order they run in. Calls are kept as ops
+//! naming the index of their callee, as functions may call each other in any
+//! order.
+//!
+//! Structured control flow maps onto gammas and thetas: an `if` becomes a
+//! gamma, and a `loop` a theta that repeats while its body branches back to
+//! it. Branching out of a block is tracked in a variable holding the label
+//! being branched to, and whatever follows the branch in the blocks being
+//! left is skipped by a gamma on that variable.
+//!
+//! Only the numeric, memory and control instructions of the core language
+//! are understood, without multiple memories or blocks taking parameters.
+//!
+//! Graphs are written back into the module they were read from by
+//! `NodeCtxt::to_wasm`, so that a module can be read, optimized and written
+//! back out.
+
+mod emit;
+
+pub use self::emit::WasmEmitError;
+
+use crate::{
+    rvsdg::{NodeCtxt, NodeCtxtConfig, OpProperties, PortKind, Sig, SigS, ValOrigin},
+    ssa::SsaBuilder,
+};
+use std::{collections::HashMap, fmt};
+use wasmparser::{
+    BinaryReaderError, BlockType, ExternalKind, FunctionBody, MemArg, Operator, OperatorsReader,
+    Parser, Payload, TypeRef, ValType, Validator,
+};
+
+/// The type of a WebAssembly value.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum WasmType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+/// The ops of graphs read by `NodeCtxt::parse_wasm`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum WasmOp {
+    I32Const(i32),
+    I64Const(i64),
+    /// The bits of a float constant.
+    F32Const(u32),
+    F64Const(u64),
+    /// A numeric instruction, named as in the text format, e.g. `i32.add`.
+    Numeric {
+        name: &'static str,
+        arity: usize,
+    },
+    /// Takes the value picked when the condition holds, the value picked
+    /// otherwise, and the condition.
+    Select,
+    /// A load, named as in the text format, e.g. `i32.load8_u`, taking the
+    /// address.
+    Load {
+        name: &'static str,
+        offset: u64,
+    },
+    /// A store, named as in the text format, taking the address and the
+    /// value to store.
+    Store {
+        name: &'static str,
+        offset: u64,
+    },
+    MemorySize,
+    MemoryGrow,
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Call {
+        function: u32,
+        params: usize,
+        results: usize,
+    },
+    /// Takes the arguments followed by the index of the function in the
+    /// table.
+    CallIndirect {
+        ty: u32,
+        params: usize,
+        results: usize,
+    },
+    /// Traps.
+    Unreachable,
+}
+
+impl Sig for WasmOp {
+    fn sig(&self) -> SigS {
+        let (val_ins, val_outs, stateful) = match self {
+            WasmOp::I32Const(..)
+            | WasmOp::I64Const(..)
+            | WasmOp::F32Const(..)
+            | WasmOp::F64Const(..) => (0, 1, false),
+            WasmOp::Numeric { arity, .. } => (*arity, 1, false),
+            WasmOp::Select => (3, 1, false),
+            WasmOp::Load { .. } => (1, 1, true),
+            WasmOp::Store { .. } => (2, 0, true),
+            WasmOp::MemorySize => (0, 1, true),
+            WasmOp::MemoryGrow => (1, 1, true),
+            WasmOp::GlobalGet(..) => (0, 1, true),
+            WasmOp::GlobalSet(..) => (1, 0, true),
+            WasmOp::Call {
+                params, results, ..
+            } => (*params, *results, true),
+            WasmOp::CallIndirect {
+                params, results, ..
+            } => (*params + 1, *results, true),
+            WasmOp::Unreachable => (0, 0, true),
+        };
+        let st = if stateful { 1 } else { 0 };
+        SigS {
+            val_ins,
+            st_ins: st,
+            val_outs,
+            st_outs: st,
+        }
+    }
+
+    fn properties(&self) -> &dyn OpProperties {
+        self
+    }
+}
+
+/// Integer arithmetic wraps around, so it regroups freely. Comparisons for
+/// equality commute, but they give booleans rather than their operands'
+/// type, so they can't be regrouped.
+impl OpProperties for WasmOp {
+    fn is_commutative(&self) -> bool {
+        match self {
+            WasmOp::Numeric { name, arity: 2 } => matches!(
+                &name[..],
+                "i32.add"
+                    | "i32.mul"
+                    | "i32.and"
+                    | "i32.or"
+                    | "i32.xor"
+                    | "i32.eq"
+                    | "i32.ne"
+                    | "i64.add"
+                    | "i64.mul"
+                    | "i64.and"
+                    | "i64.or"
+                    | "i64.xor"
+                    | "i64.eq"
+                    | "i64.ne"
+            ),
+            _ => false,
+        }
+    }
+
+    fn is_associative(&self) -> bool {
+        match self {
+            WasmOp::Numeric { name, arity: 2 } => matches!(
+                &name[..],
+                "i32.add"
+                    | "i32.mul"
+                    | "i32.and"
+                    | "i32.or"
+                    | "i32.xor"
+                    | "i64.add"
+                    | "i64.mul"
+                    | "i64.and"
+                    | "i64.or"
+                    | "i64.xor"
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Why a WebAssembly module couldn't be read.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WasmError {
+    /// The offset in the module the error was found at.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl From<BinaryReaderError> for WasmError {
+    fn from(error: BinaryReaderError) -> WasmError {
+        WasmError {
+            offset: error.offset(),
+            message: error.message().to_owned(),
+        }
+    }
+}
+
+impl NodeCtxt<WasmOp> {
+    /// Reads the functions a binary WebAssembly module defines, after
+    /// validating it. Lambdas are named after the names their functions
+    /// are exported with, or `func<index>` otherwise.
+    ///
+    /// The graph is verified before it's returned.
+    pub fn parse_wasm(bytes: &[u8]) -> Result<NodeCtxt<WasmOp>, WasmError> {
+        Validator::new().validate_all(bytes)?;
+        let (module, bodies) = Module::read(bytes)?;
+
+        // Blocks write zeros to their results up front, which most of them
+        // overwrite, so the nodes left unused are removed as regions are
+        // finished.
+        let ncx = NodeCtxt::with_config(NodeCtxtConfig {
+            opt_region_cleanup: true,
+            ..NodeCtxtConfig::default()
+        });
+        for (index, body) in bodies.iter().enumerate() {
+            let function = module.num_imported_functions + index as u32;
+            import_function(&ncx, &module, function, body)?;
+        }
+
+        ncx.verify().map_err(|violations| WasmError {
+            offset: bytes.len(),
+            message: format!("malformed graph: {:?}", violations),
+        })?;
+        Ok(ncx)
+    }
+}
+
+#[derive(Clone, Default)]
+struct FuncType {
+    params: Vec<WasmType>,
+    results: Vec<WasmType>,
+}
+
+/// What the functions of a module need to know about it.
+#[derive(Default)]
+struct Module {
+    types: Vec<FuncType>,
+    /// The types of the functions, imported ones first.
+    functions: Vec<u32>,
+    num_imported_functions: u32,
+    globals: Vec<WasmType>,
+    /// The names the functions are exported with.
+    names: HashMap<u32, String>,
+}
+
+impl Module {
+    /// Reads what's needed of `bytes`, along with the bodies of the
+    /// functions it defines.
+    fn read(bytes: &[u8]) -> Result<(Module, Vec<FunctionBody<'_>>), WasmError> {
+        let mut module = Module::default();
+        let mut bodies = vec![];
+        for payload in Parser::new(0).parse_all(bytes) {
+            match payload? {
+                Payload::TypeSection(reader) => {
+                    let offset = reader.range().start;
+                    for func_type in reader.into_iter_err_on_gc_types() {
+                        let func_type = func_type?;
+                        module.types.push(FuncType {
+                            params: wasm_types(func_type.params(), offset)?,
+                            results: wasm_types(func_type.results(), offset)?,
+                        });
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader.into_imports_with_offsets() {
+                        let (offset, import) = import?;
+                        match import.ty {
+                            TypeRef::Func(ty) => {
+                                module.functions.push(ty);
+                                module.num_imported_functions += 1;
+                            }
+                            TypeRef::Global(global) => {
+                                module.globals.push(wasm_type(global.content_type, offset)?);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        module.functions.push(ty?);
+                    }
+                }
+                Payload::GlobalSection(reader) => {
+                    for global in reader.into_iter_with_offsets() {
+                        let (offset, global) = global?;
+                        module
+                            .globals
+                            .push(wasm_type(global.ty.content_type, offset)?);
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == ExternalKind::Func {
+                            module.names.insert(export.index, export.name.to_owned());
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => bodies.push(body),
+                _ => {}
+            }
+        }
+        Ok((module, bodies))
+    }
+
+    /// The name of the lambda made of `function`.
+    fn function_name(&self, function: u32) -> String {
+        match self.names.get(&function) {
+            Some(name) => name.clone(),
+            None => format!("func{}", function),
+        }
+    }
+
+    fn function_type(&self, function: u32) -> &FuncType {
+        &self.types[self.functions[function as usize] as usize]
+    }
+
+    fn block_type(&self, ty: BlockType, offset: usize) -> Result<Vec<WasmType>, WasmError> {
+        match ty {
+            BlockType::Empty => Ok(vec![]),
+            BlockType::Type(ty) => Ok(vec![wasm_type(ty, offset)?]),
+            BlockType::FuncType(index) => {
+                let func_type = &self.types[index as usize];
+                if !func_type.params.is_empty() {
+                    return Err(unsupported("blocks taking parameters", offset));
+                }
+                Ok(func_type.results.clone())
+            }
+        }
+    }
+}
+
+fn unsupported(what: &str, offset: usize) -> WasmError {
+    WasmError {
+        offset,
+        message: format!("{} aren't supported", what),
+    }
+}
+
+fn wasm_type(ty: ValType, offset: usize) -> Result<WasmType, WasmError> {
+    match ty {
+        ValType::I32 => Ok(WasmType::I32),
+        ValType::I64 => Ok(WasmType::I64),
+        ValType::F32 => Ok(WasmType::F32),
+        ValType::F64 => Ok(WasmType::F64),
+        ValType::V128 => Err(unsupported("vectors", offset)),
+        ValType::Ref(..) => Err(unsupported("references", offset)),
+    }
+}
+
+fn wasm_types(types: &[ValType], offset: usize) -> Result<Vec<WasmType>, WasmError> {
+    types.iter().map(|&ty| wasm_type(ty, offset)).collect()
+}
+
+/// An instruction of a function body, with the instructions of blocks
+/// nested in it.
+enum Instr<'a> {
+    Op(usize, Operator<'a>),
+    Block {
+        offset: usize,
+        ty: BlockType,
+        body: Vec<Instr<'a>>,
+    },
+    Loop {
+        offset: usize,
+        ty: BlockType,
+        body: Vec<Instr<'a>>,
+    },
+    If {
+        offset: usize,
+        ty: BlockType,
+        then: Vec<Instr<'a>>,
+        otherwise: Vec<Instr<'a>>,
+    },
+}
+
+/// Reads instructions up to the `end` or `else` closing the block they're
+/// in, returning which of the two it was.
+fn read_instrs<'a>(
+    reader: &mut OperatorsReader<'a>,
+) -> Result<(Vec<Instr<'a>>, Operator<'a>), WasmError> {
+    let mut instrs = vec![];
+    loop {
+        let offset = reader.original_position();
+        let instr = match reader.read()? {
+            Operator::Block { blockty } => Instr::Block {
+                offset,
+                ty: blockty,
+                body: read_instrs(reader)?.0,
+            },
+            Operator::Loop { blockty } => Instr::Loop {
+                offset,
+                ty: blockty,
+                body: read_instrs(reader)?.0,
+            },
+            Operator::If { blockty } => {
+                let (then, end) = read_instrs(reader)?;
+                let otherwise = match end {
+                    Operator::Else => read_instrs(reader)?.0,
+                    _ => vec![],
+                };
+                Instr::If {
+                    offset,
+                    ty: blockty,
+                    then,
+                    otherwise,
+                }
+            }
+            end @ (Operator::End | Operator::Else) => return Ok((instrs, end)),
+            op => Instr::Op(offset, op),
+        };
+        instrs.push(instr);
+    }
+}
+
+/// Whether `body` branches to the block it's in, and whether it branches
+/// out of it, given how many blocks deep in that block it is.
+fn branches(body: &[Instr], depth: u32) -> (bool, bool) {
+    let mut to_block = false;
+    let mut out = false;
+    for instr in body {
+        let mut relative_depths = vec![];
+        match instr {
+            Instr::Op(_, Operator::Br { relative_depth })
+            | Instr::Op(_, Operator::BrIf { relative_depth }) => {
+                relative_depths.push(*relative_depth)
+            }
+            Instr::Op(_, Operator::BrTable { targets }) => {
+                relative_depths.push(targets.default());
+                relative_depths.extend(targets.targets().flatten());
+            }
+            Instr::Op(_, Operator::Return) => out = true,
+            Instr::Op(..) => {}
+            Instr::Block { body, .. } | Instr::Loop { body, .. } => {
+                let (nested_to_block, nested_out) = branches(body, depth + 1);
+                to_block |= nested_to_block;
+                out |= nested_out;
+            }
+            Instr::If {
+                then, otherwise, ..
+            } => {
+                for body in [then, otherwise].iter() {
+                    let (nested_to_block, nested_out) = branches(body, depth + 1);
+                    to_block |= nested_to_block;
+                    out |= nested_out;
+                }
+            }
+        }
+        for relative_depth in relative_depths {
+            to_block |= relative_depth == depth;
+            out |= relative_depth > depth;
+        }
+    }
+    (to_block, out)
+}
+
+/// What the SSA builder keeps track of while a function is imported.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Slot {
+    Local(u32),
+    /// The value at the given height of the operand stack.
+    Stack(usize),
+    /// The label being branched to, or 0 when not branching.
+    Target,
+    State,
+}
+
+/// A block that can be branched to.
+struct Label {
+    id: i32,
+    /// Branching to a loop repeats it, without passing it values.
+    is_loop: bool,
+    /// The height of the operand stack at the start of the block.
+    base: usize,
+    results: Vec<WasmType>,
+}
+
+fn import_function(
+    ncx: &NodeCtxt<WasmOp>,
+    module: &Module,
+    function: u32,
+    body: &FunctionBody,
+) -> Result<(), WasmError> {
+    let func_type = module.function_type(function);
+    let mut kinds = vec![PortKind::Val; func_type.params.len()];
+    kinds.push(PortKind::St);
+    let lambda = ncx.lambda_builder(ncx.root_region(), &kinds);
+    let mut importer = Importer {
+        ncx,
+        ssa: SsaBuilder::new(ncx, lambda.body()),
+        module,
+        locals: func_type.params.clone(),
+        labels: vec![],
+        stack: vec![],
+        num_labels: 0,
+    };
+
+    for (index, _) in func_type.params.iter().enumerate() {
+        importer
+            .ssa
+            .write_val(Slot::Local(index as u32), lambda.val_param(index));
+    }
+    for local in body.get_locals_reader()? {
+        let (count, ty) = local?;
+        let ty = wasm_type(ty, body.range().start)?;
+        for _ in 0..count {
+            let index = importer.locals.len() as u32;
+            let zero = importer.zero(ty);
+            importer.ssa.write_val(Slot::Local(index), zero);
+            importer.locals.push(ty);
+        }
+    }
+    importer.ssa.write_state(Slot::State, lambda.st_param(0));
+    let none = importer.i32_const(0);
+    importer.ssa.write_val(Slot::Target, none);
+
+    // The body of the function is a block, which returning branches out of.
+    let (instrs, _) = read_instrs(&mut body.get_operators_reader()?)?;
+    importer.block(&func_type.results, false, |importer| {
+        importer.import_seq(&instrs)
+    })?;
+    let results: Vec<_> = (0..func_type.results.len())
+        .map(|height| importer.pop_slot(height))
+        .collect();
+    let state = importer.ssa.read_state(&Slot::State).unwrap();
+    let node = lambda.finish(&results, &[state]);
+    node.set_name(module.function_name(function));
+    Ok(())
+}
+
+struct Importer<'m, 'g> {
+    ncx: &'g NodeCtxt<WasmOp>,
+    ssa: SsaBuilder<'g, WasmOp, Slot>,
+    module: &'m Module,
+    locals: Vec<WasmType>,
+    /// The blocks being imported, innermost last.
+    labels: Vec<Label>,
+    /// The types of the values on the operand stack.
+    stack: Vec<WasmType>,
+    num_labels: i32,
+}
+
+impl<'m, 'g> Importer<'m, 'g> {
+    fn node(&mut self, op: WasmOp, operands: &[ValOrigin<'g, WasmOp>]) -> ValOrigin<'g, WasmOp> {
+        let mut builder = self.ncx.node_builder_in(self.ssa.region(), op);
+        for operand in operands {
+            builder = builder.operand(operand.clone());
+        }
+        builder.finish().val_out(0)
+    }
+
+    fn i32_const(&mut self, value: i32) -> ValOrigin<'g, WasmOp> {
+        self.node(WasmOp::I32Const(value), &[])
+    }
+
+    fn numeric(
+        &mut self,
+        name: &'static str,
+        operands: &[ValOrigin<'g, WasmOp>],
+    ) -> ValOrigin<'g, WasmOp> {
+        let arity = operands.len();
+        self.node(WasmOp::Numeric { name, arity }, operands)
+    }
+
+    fn zero(&mut self, ty: WasmType) -> ValOrigin<'g, WasmOp> {
+        let op = match ty {
+            WasmType::I32 => WasmOp::I32Const(0),
+            WasmType::I64 => WasmOp::I64Const(0),
+            WasmType::F32 => WasmOp::F32Const(0),
+            WasmType::F64 => WasmOp::F64Const(0),
+        };
+        self.node(op, &[])
+    }
+
+    /// Reads the value at `height` of the operand stack.
+    fn pop_slot(&mut self, height: usize) -> ValOrigin<'g, WasmOp> {
+        self.ssa.read_val(&Slot::Stack(height)).unwrap()
+    }
+
+    fn push(&mut self, ty: WasmType, value: ValOrigin<'g, WasmOp>) {
+        self.ssa.write_val(Slot::Stack(self.stack.len()), value);
+        self.stack.push(ty);
+    }
+
+    fn pop(&mut self) -> ValOrigin<'g, WasmOp> {
+        self.stack.pop();
+        self.pop_slot(self.stack.len())
+    }
+
+    /// Pops `count` values, the deepest first.
+    fn pop_n(&mut self, count: usize) -> Vec<ValOrigin<'g, WasmOp>> {
+        let mut values: Vec<_> = (0..count).map(|_| self.pop()).collect();
+        values.reverse();
+        values
+    }
+
+    /// Runs `op` on the state.
+    fn stateful(
+        &mut self,
+        op: WasmOp,
+        operands: &[ValOrigin<'g, WasmOp>],
+    ) -> Vec<ValOrigin<'g, WasmOp>> {
+        let num_results = op.sig().val_outs;
+        let mut builder = self.ncx.node_builder_in(self.ssa.region(), op);
+        for operand in operands {
+            builder = builder.operand(operand.clone());
+        }
+        let state = self.ssa.read_state(&Slot::State).unwrap();
+        let node = builder.state(state).finish();
+        self.ssa.write_state(Slot::State, node.st_out(0));
+        (0..num_results).map(|port| node.val_out(port)).collect()
+    }
+
+    /// Imports a block producing `results`, whose body `import_body`
+    /// imports.
+    fn block<F>(
+        &mut self,
+        results: &[WasmType],
+        is_loop: bool,
+        import_body: F,
+    ) -> Result<(), WasmError>
+    where
+        F: FnOnce(&mut Importer<'m, 'g>) -> Result<(), WasmError>,
+    {
+        // Results are defined up front, so that they're defined however the
+        // block is left.
+        let base = self.stack.len();
+        for (index, &ty) in results.iter().enumerate() {
+            let zero = self.zero(ty);
+            self.ssa.write_val(Slot::Stack(base + index), zero);
+        }
+        self.num_labels += 1;
+        self.labels.push(Label {
+            id: self.num_labels,
+            is_loop,
+            base,
+            results: results.to_vec(),
+        });
+        import_body(self)?;
+        self.labels.pop();
+        self.stack.truncate(base);
+        self.stack.extend_from_slice(results);
+        Ok(())
+    }
+
+    /// Stops branching once the block with label `id` is branched to.
+    fn land(&mut self, id: i32) {
+        let target = self.ssa.read_val(&Slot::Target).unwrap();
+        let label = self.i32_const(id);
+        let landed = self.numeric("i32.eq", &[target.clone(), label]);
+        let none = self.i32_const(0);
+        let target = self.node(WasmOp::Select, &[none, target, landed]);
+        self.ssa.write_val(Slot::Target, target);
+    }
+
+    /// Branches to the label `relative_depth` blocks out, passing it the
+    /// values on top of the operand stack.
+    fn branch(&mut self, relative_depth: u32) {
+        let label = &self.labels[self.labels.len() - 1 - relative_depth as usize];
+        let (id, base) = (label.id, label.base);
+        let arity = if label.is_loop {
+            0
+        } else {
+            label.results.len()
+        };
+        let top = self.stack.len();
+        let values: Vec<_> = (top - arity..top)
+            .map(|height| self.pop_slot(height))
+            .collect();
+        for (index, value) in values.into_iter().enumerate() {
+            self.ssa.write_val(Slot::Stack(base + index), value);
+        }
+        let target = self.i32_const(id);
+        self.ssa.write_val(Slot::Target, target);
+    }
+
+    /// Imports `instrs` from the first, up to the end of the block they're
+    /// in.
+    fn import_seq(&mut self, instrs: &[Instr]) -> Result<(), WasmError> {
+        for (index, instr) in instrs.iter().enumerate() {
+            let rest = &instrs[index + 1..];
+            let (offset, op) = match instr {
+                Instr::Op(offset, op) => (*offset, op),
+                Instr::Block { offset, ty, body } => {
+                    let results = self.module.block_type(*ty, *offset)?;
+                    let id = self.num_labels + 1;
+                    self.block(&results, false, |importer| importer.import_seq(body))?;
+                    let (to_block, out) = branches(body, 0);
+                    if to_block {
+                        self.land(id);
+                    }
+                    if out {
+                        return self.unless_branching(rest);
+                    }
+                    continue;
+                }
+                Instr::Loop { offset, ty, body } => {
+                    let results = self.module.block_type(*ty, *offset)?;
+                    let id = self.num_labels + 1;
+                    self.block(&results, true, |importer| {
+                        // Branching back to the loop is what repeats it.
+                        importer.ssa.begin_theta();
+                        let none = importer.i32_const(0);
+                        importer.ssa.write_val(Slot::Target, none);
+                        importer.import_seq(body)?;
+                        let target = importer.ssa.read_val(&Slot::Target).unwrap();
+                        let label = importer.i32_const(id);
+                        let repeat = importer.numeric("i32.eq", &[target, label]);
+                        importer.ssa.end_theta(repeat);
+                        Ok(())
+                    })?;
+                    if branches(body, 0).1 {
+                        return self.unless_branching(rest);
+                    }
+                    continue;
+                }
+                Instr::If {
+                    offset,
+                    ty,
+                    then,
+                    otherwise,
+                } => {
+                    let results = self.module.block_type(*ty, *offset)?;
+                    let condition = self.pop();
+                    let id = self.num_labels + 1;
+                    self.block(&results, false, |importer| {
+                        let predicate = importer.numeric("i32.eqz", &[condition]);
+                        importer.ssa.begin_gamma(predicate, 2);
+                        for (branch, body) in [then, otherwise].iter().enumerate() {
+                            if branch > 0 {
+                                importer.ssa.next_branch();
+                            }
+                            let stack = importer.stack.clone();
+                            importer.import_seq(body)?;
+                            importer.stack = stack;
+                        }
+                        importer.ssa.end_gamma();
+                        Ok(())
+                    })?;
+                    let (then_to_block, then_out) = branches(then, 0);
+                    let (otherwise_to_block, otherwise_out) = branches(otherwise, 0);
+                    if then_to_block || otherwise_to_block {
+                        self.land(id);
+                    }
+                    if then_out || otherwise_out {
+                        return self.unless_branching(rest);
+                    }
+                    continue;
+                }
+            };
+
+            match op {
+                Operator::Br { relative_depth } => {
+                    self.branch(*relative_depth);
+                    return Ok(());
+                }
+                Operator::Return => {
+                    self.branch(self.labels.len() as u32 - 1);
+                    return Ok(());
+                }
+                Operator::BrIf { relative_depth } => {
+                    let condition = self.pop();
+                    let predicate = self.numeric("i32.eqz", &[condition]);
+                    self.ssa.begin_gamma(predicate, 2);
+                    let stack = self.stack.clone();
+                    self.branch(*relative_depth);
+                    self.ssa.next_branch();
+                    self.import_seq(rest)?;
+                    self.stack = stack;
+                    self.ssa.end_gamma();
+                    return Ok(());
+                }
+                Operator::BrTable { targets } => {
+                    let index = self.pop();
+                    let mut relative_depths: Vec<u32> =
+                        targets.targets().collect::<Result<_, _>>()?;
+                    // Indices past the targets take the default one.
+                    let num_targets = self.i32_const(relative_depths.len() as i32);
+                    let in_range = self.numeric("i32.lt_u", &[index.clone(), num_targets.clone()]);
+                    let predicate = self.node(WasmOp::Select, &[index, num_targets, in_range]);
+                    relative_depths.push(targets.default());
+                    self.ssa.begin_gamma(predicate, relative_depths.len());
+                    for (branch, &relative_depth) in relative_depths.iter().enumerate() {
+                        if branch > 0 {
+                            self.ssa.next_branch();
+                        }
+                        self.branch(relative_depth);
+                    }
+                    self.ssa.end_gamma();
+                    return Ok(());
+                }
+                Operator::Unreachable => {
+                    self.stateful(WasmOp::Unreachable, &[]);
+                    return Ok(());
+                }
+                _ => self.import_op(offset, op)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports `rest` only if the block before it wasn't branched out of.
+    fn unless_branching(&mut self, rest: &[Instr]) -> Result<(), WasmError> {
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let target = self.ssa.read_val(&Slot::Target).unwrap();
+        let none = self.i32_const(0);
+        let branching = self.numeric("i32.ne", &[target, none]);
+        self.ssa.begin_gamma(branching, 2);
+        let stack = self.stack.clone();
+        self.import_seq(rest)?;
+        self.stack = stack;
+        self.ssa.next_branch();
+        self.ssa.end_gamma();
+        Ok(())
+    }
+
+    /// Imports an instruction that doesn't affect control flow.
+    fn import_op(&mut self, offset: usize, op: &Operator) -> Result<(), WasmError> {
+        if let Some((name, arity, ty)) = numeric_op(op) {
+            let operands = self.pop_n(arity);
+            let result = self.numeric(name, &operands);
+            self.push(ty, result);
+            return Ok(());
+        }
+        if let Some((name, ty, memarg)) = load_op(op) {
+            if memarg.memory != 0 {
+                return Err(unsupported("multiple memories", offset));
+            }
+            let address = self.pop();
+            let load = WasmOp::Load {
+                name,
+                offset: memarg.offset,
+            };
+            let result = self.stateful(load, &[address]).remove(0);
+            self.push(ty, result);
+            return Ok(());
+        }
+        if let Some((name, memarg)) = store_op(op) {
+            if memarg.memory != 0 {
+                return Err(unsupported("multiple memories", offset));
+            }
+            let operands = self.pop_n(2);
+            let store = WasmOp::Store {
+                name,
+                offset: memarg.offset,
+            };
+            self.stateful(store, &operands);
+            return Ok(());
+        }
+
+        match *op {
+            Operator::Nop => {}
+            Operator::Drop => {
+                self.pop();
+            }
+            Operator::I32Const { value } => {
+                let result = self.node(WasmOp::I32Const(value), &[]);
+                self.push(WasmType::I32, result);
+            }
+            Operator::I64Const { value } => {
+                let result = self.node(WasmOp::I64Const(value), &[]);
+                self.push(WasmType::I64, result);
+            }
+            Operator::F32Const { value } => {
+                let result = self.node(WasmOp::F32Const(value.bits()), &[]);
+                self.push(WasmType::F32, result);
+            }
+            Operator::F64Const { value } => {
+                let result = self.node(WasmOp::F64Const(value.bits()), &[]);
+                self.push(WasmType::F64, result);
+            }
+            Operator::Select | Operator::TypedSelect { .. } => {
+                let ty = self.stack[self.stack.len() - 3];
+                let operands = self.pop_n(3);
+                let result = self.node(WasmOp::Select, &operands);
+                self.push(ty, result);
+            }
+            Operator::LocalGet { local_index } => {
+                let value = self.ssa.read_val(&Slot::Local(local_index)).unwrap();
+                self.push(self.locals[local_index as usize], value);
+            }
+            Operator::LocalSet { local_index } => {
+                let value = self.pop();
+                self.ssa.write_val(Slot::Local(local_index), value);
+            }
+            Operator::LocalTee { local_index } => {
+                let value = self.pop_slot(self.stack.len() - 1);
+                self.ssa.write_val(Slot::Local(local_index), value);
+            }
+            Operator::GlobalGet { global_index } => {
+                let result = self
+                    .stateful(WasmOp::GlobalGet(global_index), &[])
+                    .remove(0);
+                self.push(self.module.globals[global_index as usize], result);
+            }
+            Operator::GlobalSet { global_index } => {
+                let value = self.pop();
+                self.stateful(WasmOp::GlobalSet(global_index), &[value]);
+            }
+            Operator::MemorySize { mem: 0 } => {
+                let result = self.stateful(WasmOp::MemorySize, &[]).remove(0);
+                self.push(WasmType::I32, result);
+            }
+            Operator::MemoryGrow { mem: 0 } => {
+                let delta = self.pop();
+                let result = self.stateful(WasmOp::MemoryGrow, &[delta]).remove(0);
+                self.push(WasmType::I32, result);
+            }
+            Operator::MemorySize { .. } | Operator::MemoryGrow { .. } => {
+                return Err(unsupported("multiple memories", offset));
+            }
+            Operator::Call { function_index } => {
+                let func_type = self.module.function_type(function_index);
+                let call = WasmOp::Call {
+                    function: function_index,
+                    params: func_type.params.len(),
+                    results: func_type.results.len(),
+                };
+                let args = self.pop_n(func_type.params.len());
+                let results = self.stateful(call, &args);
+                for (&ty, result) in func_type.results.iter().zip(results) {
+                    self.push(ty, result);
+                }
+            }
+            Operator::CallIndirect { table_index, .. } if table_index != 0 => {
+                return Err(unsupported("multiple tables", offset));
+            }
+            Operator::CallIndirect { type_index, .. } => {
+                let func_type = &self.module.types[type_index as usize];
+                let call = WasmOp::CallIndirect {
+                    ty: type_index,
+                    params: func_type.params.len(),
+                    results: func_type.results.len(),
+                };
+                let operands = self.pop_n(func_type.params.len() + 1);
+                let results = self.stateful(call, &operands);
+                for (&ty, result) in func_type.results.iter().zip(results) {
+                    self.push(ty, result);
+                }
+            }
+            _ => {
+                let name = format!("{:?}", op);
+                let name = name.split(' ').next().unwrap();
+                return Err(WasmError {
+                    offset,
+                    message: format!("unsupported instruction `{}`", name),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The name, arity and result type of a numeric instruction.
+fn numeric_op(op: &Operator) -> Option<(&'static str, usize, WasmType)> {
+    let numeric = match op {
+        Operator::I32Eqz => ("i32.eqz", 1, WasmType::I32),
+        Operator::I32Eq => ("i32.eq", 2, WasmType::I32),
+        Operator::I32Ne => ("i32.ne", 2, WasmType::I32),
+        Operator::I32LtS => ("i32.lt_s", 2, WasmType::I32),
+        Operator::I32LtU => ("i32.lt_u", 2, WasmType::I32),
+        Operator::I32GtS => ("i32.gt_s", 2, WasmType::I32),
+        Operator::I32GtU => ("i32.gt_u", 2, WasmType::I32),
+        Operator::I32LeS => ("i32.le_s", 2, WasmType::I32),
+        Operator::I32LeU => ("i32.le_u", 2, WasmType::I32),
+        Operator::I32GeS => ("i32.ge_s", 2, WasmType::I32),
+        Operator::I32GeU => ("i32.ge_u", 2, WasmType::I32),
+        Operator::I64Eqz => ("i64.eqz", 1, WasmType::I32),
+        Operator::I64Eq => ("i64.eq", 2, WasmType::I32),
+        Operator::I64Ne => ("i64.ne", 2, WasmType::I32),
+        Operator::I64LtS => ("i64.lt_s", 2, WasmType::I32),
+        Operator::I64LtU => ("i64.lt_u", 2, WasmType::I32),
+        Operator::I64GtS => ("i64.gt_s", 2, WasmType::I32),
+        Operator::I64GtU => ("i64.gt_u", 2, WasmType::I32),
+        Operator::I64LeS => ("i64.le_s", 2, WasmType::I32),
+        Operator::I64LeU => ("i64.le_u", 2, WasmType::I32),
+        Operator::I64GeS => ("i64.ge_s", 2, WasmType::I32),
+        Operator::I64GeU => ("i64.ge_u", 2, WasmType::I32),
+        Operator::F32Eq => ("f32.eq", 2, WasmType::I32),
+        Operator::F32Ne => ("f32.ne", 2, WasmType::I32),
+        Operator::F32Lt => ("f32.lt", 2, WasmType::I32),
+        Operator::F32Gt => ("f32.gt", 2, WasmType::I32),
+        Operator::F32Le => ("f32.le", 2, WasmType::I32),
+        Operator::F32Ge => ("f32.ge", 2, WasmType::I32),
+        Operator::F64Eq => ("f64.eq", 2, WasmType::I32),
+        Operator::F64Ne => ("f64.ne", 2, WasmType::I32),
+        Operator::F64Lt => ("f64.lt", 2, WasmType::I32),
+        Operator::F64Gt => ("f64.gt", 2, WasmType::I32),
+        Operator::F64Le => ("f64.le", 2, WasmType::I32),
+        Operator::F64Ge => ("f64.ge", 2, WasmType::I32),
+        Operator::I32Clz => ("i32.clz", 1, WasmType::I32),
+        Operator::I32Ctz => ("i32.ctz", 1, WasmType::I32),
+        Operator::I32Popcnt => ("i32.popcnt", 1, WasmType::I32),
+        Operator::I32Add => ("i32.add", 2, WasmType::I32),
+        Operator::I32Sub => ("i32.sub", 2, WasmType::I32),
+        Operator::I32Mul => ("i32.mul", 2, WasmType::I32),
+        Operator::I32DivS => ("i32.div_s", 2, WasmType::I32),
+        Operator::I32DivU => ("i32.div_u", 2, WasmType::I32),
+        Operator::I32RemS => ("i32.rem_s", 2, WasmType::I32),
+        Operator::I32RemU => ("i32.rem_u", 2, WasmType::I32),
+        Operator::I32And => ("i32.and", 2, WasmType::I32),
+        Operator::I32Or => ("i32.or", 2, WasmType::I32),
+        Operator::I32Xor => ("i32.xor", 2, WasmType::I32),
+        Operator::I32Shl => ("i32.shl", 2, WasmType::I32),
+        Operator::I32ShrS => ("i32.shr_s", 2, WasmType::I32),
+        Operator::I32ShrU => ("i32.shr_u", 2, WasmType::I32),
+        Operator::I32Rotl => ("i32.rotl", 2, WasmType::I32),
+        Operator::I32Rotr => ("i32.rotr", 2, WasmType::I32),
+        Operator::I64Clz => ("i64.clz", 1, WasmType::I64),
+        Operator::I64Ctz => ("i64.ctz", 1, WasmType::I64),
+        Operator::I64Popcnt => ("i64.popcnt", 1, WasmType::I64),
+        Operator::I64Add => ("i64.add", 2, WasmType::I64),
+        Operator::I64Sub => ("i64.sub", 2, WasmType::I64),
+        Operator::I64Mul => ("i64.mul", 2, WasmType::I64),
+        Operator::I64DivS => ("i64.div_s", 2, WasmType::I64),
+        Operator::I64DivU => ("i64.div_u", 2, WasmType::I64),
+        Operator::I64RemS => ("i64.rem_s", 2, WasmType::I64),
+        Operator::I64RemU => ("i64.rem_u", 2, WasmType::I64),
+        Operator::I64And => ("i64.and", 2, WasmType::I64),
+        Operator::I64Or => ("i64.or", 2, WasmType::I64),
+        Operator::I64Xor => ("i64.xor", 2, WasmType::I64),
+        Operator::I64Shl => ("i64.shl", 2, WasmType::I64),
+        Operator::I64ShrS => ("i64.shr_s", 2, WasmType::I64),
+        Operator::I64ShrU => ("i64.shr_u", 2, WasmType::I64),
+        Operator::I64Rotl => ("i64.rotl", 2, WasmType::I64),
+        Operator::I64Rotr => ("i64.rotr", 2, WasmType::I64),
+        Operator::F32Abs => ("f32.abs", 1, WasmType::F32),
+        Operator::F32Neg => ("f32.neg", 1, WasmType::F32),
+        Operator::F32Ceil => ("f32.ceil", 1, WasmType::F32),
+        Operator::F32Floor => ("f32.floor", 1, WasmType::F32),
+        Operator::F32Trunc => ("f32.trunc", 1, WasmType::F32),
+        Operator::F32Nearest => ("f32.nearest", 1, WasmType::F32),
+        Operator::F32Sqrt => ("f32.sqrt", 1, WasmType::F32),
+        Operator::F32Add => ("f32.add", 2, WasmType::F32),
+        Operator::F32Sub => ("f32.sub", 2, WasmType::F32),
+        Operator::F32Mul => ("f32.mul", 2, WasmType::F32),
+        Operator::F32Div => ("f32.div", 2, WasmType::F32),
+        Operator::F32Min => ("f32.min", 2, WasmType::F32),
+        Operator::F32Max => ("f32.max", 2, WasmType::F32),
+        Operator::F32Copysign => ("f32.copysign", 2, WasmType::F32),
+        Operator::F64Abs => ("f64.abs", 1, WasmType::F64),
+        Operator::F64Neg => ("f64.neg", 1, WasmType::F64),
+        Operator::F64Ceil => ("f64.ceil", 1, WasmType::F64),
+        Operator::F64Floor => ("f64.floor", 1, WasmType::F64),
+        Operator::F64Trunc => ("f64.trunc", 1, WasmType::F64),
+        Operator::F64Nearest => ("f64.nearest", 1, WasmType::F64),
+        Operator::F64Sqrt => ("f64.sqrt", 1, WasmType::F64),
+        Operator::F64Add => ("f64.add", 2, WasmType::F64),
+        Operator::F64Sub => ("f64.sub", 2, WasmType::F64),
+        Operator::F64Mul => ("f64.mul", 2, WasmType::F64),
+        Operator::F64Div => ("f64.div", 2, WasmType::F64),
+        Operator::F64Min => ("f64.min", 2, WasmType::F64),
+        Operator::F64Max => ("f64.max", 2, WasmType::F64),
+        Operator::F64Copysign => ("f64.copysign", 2, WasmType::F64),
+        Operator::I32WrapI64 => ("i32.wrap_i64", 1, WasmType::I32),
+        Operator::I32TruncF32S => ("i32.trunc_f32_s", 1, WasmType::I32),
+        Operator::I32TruncF32U => ("i32.trunc_f32_u", 1, WasmType::I32),
+        Operator::I32TruncF64S => ("i32.trunc_f64_s", 1, WasmType::I32),
+        Operator::I32TruncF64U => ("i32.trunc_f64_u", 1, WasmType::I32),
+        Operator::I64ExtendI32S => ("i64.extend_i32_s", 1, WasmType::I64),
+        Operator::I64ExtendI32U => ("i64.extend_i32_u", 1, WasmType::I64),
+        Operator::I64TruncF32S => ("i64.trunc_f32_s", 1, WasmType::I64),
+        Operator::I64TruncF32U => ("i64.trunc_f32_u", 1, WasmType::I64),
+        Operator::I64TruncF64S => ("i64.trunc_f64_s", 1, WasmType::I64),
+        Operator::I64TruncF64U => ("i64.trunc_f64_u", 1, WasmType::I64),
+        Operator::F32ConvertI32S => ("f32.convert_i32_s", 1, WasmType::F32),
+        Operator::F32ConvertI32U => ("f32.convert_i32_u", 1, WasmType::F32),
+        Operator::F32ConvertI64S => ("f32.convert_i64_s", 1, WasmType::F32),
+        Operator::F32ConvertI64U => ("f32.convert_i64_u", 1, WasmType::F32),
+        Operator::F32DemoteF64 => ("f32.demote_f64", 1, WasmType::F32),
+        Operator::F64ConvertI32S => ("f64.convert_i32_s", 1, WasmType::F64),
+        Operator::F64ConvertI32U => ("f64.convert_i32_u", 1, WasmType::F64),
+        Operator::F64ConvertI64S => ("f64.convert_i64_s", 1, WasmType::F64),
+        Operator::F64ConvertI64U => ("f64.convert_i64_u", 1, WasmType::F64),
+        Operator::F64PromoteF32 => ("f64.promote_f32", 1, WasmType::F64),
+        Operator::I32ReinterpretF32 => ("i32.reinterpret_f32", 1, WasmType::I32),
+        Operator::I64ReinterpretF64 => ("i64.reinterpret_f64", 1, WasmType::I64),
+        Operator::F32ReinterpretI32 => ("f32.reinterpret_i32", 1, WasmType::F32),
+        Operator::F64ReinterpretI64 => ("f64.reinterpret_i64", 1, WasmType::F64),
+        Operator::I32Extend8S => ("i32.extend8_s", 1, WasmType::I32),
+        Operator::I32Extend16S => ("i32.extend16_s", 1, WasmType::I32),
+        Operator::I64Extend8S => ("i64.extend8_s", 1, WasmType::I64),
+        Operator::I64Extend16S => ("i64.extend16_s", 1, WasmType::I64),
+        Operator::I64Extend32S => ("i64.extend32_s", 1, WasmType::I64),
+        Operator::I32TruncSatF32S => ("i32.trunc_sat_f32_s", 1, WasmType::I32),
+        Operator::I32TruncSatF32U => ("i32.trunc_sat_f32_u", 1, WasmType::I32),
+        Operator::I32TruncSatF64S => ("i32.trunc_sat_f64_s", 1, WasmType::I32),
+        Operator::I32TruncSatF64U => ("i32.trunc_sat_f64_u", 1, WasmType::I32),
+        Operator::I64TruncSatF32S => ("i64.trunc_sat_f32_s", 1, WasmType::I64),
+        Operator::I64TruncSatF32U => ("i64.trunc_sat_f32_u", 1, WasmType::I64),
+        Operator::I64TruncSatF64S => ("i64.trunc_sat_f64_s", 1, WasmType::I64),
+        Operator::I64TruncSatF64U => ("i64.trunc_sat_f64_u", 1, WasmType::I64),
+        _ => return None,
+    };
+    Some(numeric)
+}
+
+/// The name, result type and immediate of a load.
+fn load_op(op: &Operator) -> Option<(&'static str, WasmType, MemArg)> {
+    let load = match *op {
+        Operator::I32Load { memarg } => ("i32.load", WasmType::I32, memarg),
+        Operator::I64Load { memarg } => ("i64.load", WasmType::I64, memarg),
+        Operator::F32Load { memarg } => ("f32.load", WasmType::F32, memarg),
+        Operator::F64Load { memarg } => ("f64.load", WasmType::F64, memarg),
+        Operator::I32Load8S { memarg } => ("i32.load8_s", WasmType::I32, memarg),
+        Operator::I32Load8U { memarg } => ("i32.load8_u", WasmType::I32, memarg),
+        Operator::I32Load16S { memarg } => ("i32.load16_s", WasmType::I32, memarg),
+        Operator::I32Load16U { memarg } => ("i32.load16_u", WasmType::I32, memarg),
+        Operator::I64Load8S { memarg } => ("i64.load8_s", WasmType::I64, memarg),
+        Operator::I64Load8U { memarg } => ("i64.load8_u", WasmType::I64, memarg),
+        Operator::I64Load16S { memarg } => ("i64.load16_s", WasmType::I64, memarg),
+        Operator::I64Load16U { memarg } => ("i64.load16_u", WasmType::I64, memarg),
+        Operator::I64Load32S { memarg } => ("i64.load32_s", WasmType::I64, memarg),
+        Operator::I64Load32U { memarg } => ("i64.load32_u", WasmType::I64, memarg),
+        _ => return None,
+    };
+    Some(load)
+}
+
+/// The name and immediate of a store.
+fn store_op(op: &Operator) -> Option<(&'static str, MemArg)> {
+    let store = match *op {
+        Operator::I32Store { memarg } => ("i32.store", memarg),
+        Operator::I64Store { memarg } => ("i64.store", memarg),
+        Operator::F32Store { memarg } => ("f32.store", memarg),
+        Operator::F64Store { memarg } => ("f64.store", memarg),
+        Operator::I32Store8 { memarg } => ("i32.store8", memarg),
+        Operator::I32Store16 { memarg } => ("i32.store16", memarg),
+        Operator::I64Store8 { memarg } => ("i64.store8", memarg),
+        Operator::I64Store16 { memarg } => ("i64.store16", memarg),
+        Operator::I64Store32 { memarg } => ("i64.store32", memarg),
+        _ => return None,
+    };
+    Some(store)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rvsdg::{Inst, NodeKind, Terminator};
+
+    pub(super) fn parse(text: &str) -> NodeCtxt<WasmOp> {
+        let bytes = wat::parse_str(text).unwrap();
+        NodeCtxt::parse_wasm(&bytes).unwrap()
+    }
+
+    fn lambda_body(ncx: &NodeCtxt<WasmOp>, name: &str) -> crate::rvsdg::RegionId {
+        let lambda = ncx
+            .region_ref(ncx.root_region())
+            .nodes()
+            .find(|node| node.name().as_deref() == Some(name))
+            .unwrap();
+        ncx.inner_regions(lambda.id())[0]
+    }
+
+    /// Runs the function named `name` on `args`, understanding just enough
+    /// instructions for the tests.
+    pub(super) fn run(ncx: &NodeCtxt<WasmOp>, name: &str, args: &[i32]) -> Vec<i32> {
+        let cfg = ncx.to_cfg(lambda_body(ncx, name)).unwrap();
+        let mut vars = vec![0; cfg.num_vars];
+        let mut memory = HashMap::new();
+        let mut block = &cfg.blocks[0];
+        for (param, &arg) in block.params.iter().zip(args) {
+            vars[param.0] = arg;
+        }
+        loop {
+            for inst in &block.insts {
+                let (op, args, results) = match inst {
+                    Inst::Op { op, args, results } => (op, args, results),
+                    Inst::Call { .. } => unreachable!(),
+                };
+                let args: Vec<i32> = args.iter().map(|arg| vars[arg.0]).collect();
+                let result = match *op {
+                    WasmOp::I32Const(value) => value,
+                    WasmOp::Select => {
+                        if args[2] != 0 {
+                            args[0]
+                        } else {
+                            args[1]
+                        }
+                    }
+                    WasmOp::Load { offset, .. } => memory[&(args[0] as u64 + offset)],
+                    WasmOp::Store { offset, .. } => {
+                        memory.insert(args[0] as u64 + offset, args[1]);
+                        continue;
+                    }
+                    WasmOp::Numeric { name, .. } => match name {
+                        "i32.add" => args[0].wrapping_add(args[1]),
+                        "i32.sub" => args[0].wrapping_sub(args[1]),
+                        "i32.eqz" => (args[0] == 0) as i32,
+                        "i32.eq" => (args[0] == args[1]) as i32,
+                        "i32.ne" => (args[0] != args[1]) as i32,
+                        "i32.lt_u" => ((args[0] as u32) < args[1] as u32) as i32,
+                        "i32.gt_s" => (args[0] > args[1]) as i32,
+                        _ => panic!("can't run `{}`", name),
+                    },
+                    ref op => panic!("can't run {:?}", op),
+                };
+                vars[results[0].0] = result;
+            }
+            let jump = match &block.terminator {
+                Terminator::Return(results) => {
+                    return results.iter().map(|result| vars[result.0]).collect()
+                }
+                Terminator::Jump(jump) => jump,
+                Terminator::Switch { on, jumps } => &jumps[vars[on.0] as usize],
+            };
+            let args: Vec<i32> = jump.args.iter().map(|arg| vars[arg.0]).collect();
+            block = &cfg.blocks[jump.block.0];
+            for (param, arg) in block.params.iter().zip(args) {
+                vars[param.0] = arg;
+            }
+        }
+    }
+
+    #[test]
+    fn ifs_become_gammas() {
+        let ncx = parse(
+            r#"
+            (module
+              (func (export "max") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.gt_s
+                if (result i32)
+                  local.get 0
+                else
+                  local.get 1
+                end))
+            "#,
+        );
+
+        let body = lambda_body(&ncx, "max");
+        let result = ncx.region_ref(body).val_res(0).origin().producer();
+        assert!(matches!(*result.kind(), NodeKind::Gamma { .. }));
+        assert_eq!(vec![5], run(&ncx, "max", &[3, 5]));
+        assert_eq!(vec![7], run(&ncx, "max", &[7, 2]));
+    }
+
+    #[test]
+    fn loops_become_thetas() {
+        let ncx = parse(
+            r#"
+            (module
+              (func (export "sum") (param $n i32) (result i32) (local $acc i32)
+                block $exit
+                  loop $again
+                    local.get $n
+                    i32.eqz
+                    br_if $exit
+                    local.get $acc
+                    local.get $n
+                    i32.add
+                    local.set $acc
+                    local.get $n
+                    i32.const 1
+                    i32.sub
+                    local.set $n
+                    br $again
+                  end
+                end
+                local.get $acc))
+            "#,
+        );
+
+        let body = lambda_body(&ncx, "sum");
+        let has_theta = ncx
+            .region_ref(body)
+            .nodes()
+            .any(|node| matches!(*node.kind(), NodeKind::Theta { .. }));
+        assert!(has_theta);
+        assert_eq!(vec![0], run(&ncx, "sum", &[0]));
+        assert_eq!(vec![10], run(&ncx, "sum", &[4]));
+    }
+
+    #[test]
+    fn branches_out_of_blocks() {
+        let ncx = parse(
+            r#"
+            (module
+              (memory 1)
+              (func (export "pick") (param i32) (result i32)
+                i32.const 8
+                i32.const 40
+                i32.store offset=4
+                block $two
+                  block $one
+                    block $zero
+                      local.get 0
+                      br_table $zero $one $two
+                    end
+                    i32.const 10
+                    return
+                  end
+                  i32.const 12
+                  i32.load
+                  return
+                end
+                i32.const 30))
+            "#,
+        );
+
+        assert_eq!(vec![10], run(&ncx, "pick", &[0]));
+        assert_eq!(vec![40], run(&ncx, "pick", &[1]));
+        assert_eq!(vec![30], run(&ncx, "pick", &[2]));
+        assert_eq!(vec![30], run(&ncx, "pick", &[9]));
+
+        // The load comes after the store on the state.
+        let region = ncx.region_ref(lambda_body(&ncx, "pick"));
+        let store = region
+            .nodes()
+            .find(|node| matches!(*node.kind(), NodeKind::Op(WasmOp::Store { .. })))
+            .unwrap();
+        assert_eq!(region.st_arg(0), store.st_in(0).origin());
+    }
+
+    #[test]
+    fn unsupported_code() {
+        let error = |text: &str| match NodeCtxt::parse_wasm(&wat::parse_str(text).unwrap()) {
+            Ok(..) => panic!("parsed unsupported code"),
+            Err(error) => error.message,
+        };
+
+        assert_eq!(
+            "unsupported instruction `TableSize`",
+            error("(module (table 1 funcref) (func (result i32) table.size 0))")
+        );
+        assert_eq!(
+            "vectors aren't supported",
+            error("(module (func (param v128)))")
+        );
+        assert!(NodeCtxt::parse_wasm(b"\0asm").is_err());
+    }
+}
@@ -0,0 +1,720 @@
+use super::{Module, WasmError, WasmOp, WasmType};
+use crate::rvsdg::{Node, NodeCtxt, NodeId, NodeKind, RegionId, ValOrigin};
+use std::{collections::HashMap, fmt, mem};
+use wasm_encoder::{
+    BlockType, CodeSection, Function, Ieee32, Ieee64, Instruction, MemArg, RawSection, ValType,
+};
+use wasmparser::{Parser, Payload};
+
+/// Why a graph couldn't be written as a WebAssembly module.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WasmEmitError {
+    /// The module being written back out couldn't be read.
+    Module(WasmError),
+    /// No lambda is named after a function the module defines.
+    MissingFunction(u32),
+    /// The lambda of a function doesn't take its parameters or produce its
+    /// results.
+    Signature(NodeId),
+    /// The node has no counterpart in WebAssembly. These are apply, lambda
+    /// and omega nodes in the body of a lambda, lambdas with context
+    /// variables, and ops `parse_wasm` doesn't make.
+    Unsupported(NodeId),
+}
+
+impl fmt::Display for WasmEmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WasmEmitError::Module(error) => write!(f, "{}", error),
+            WasmEmitError::MissingFunction(function) => {
+                write!(f, "no lambda is named after function {}", function)
+            }
+            WasmEmitError::Signature(lambda) => {
+                write!(f, "{:?} doesn't match the type of its function", lambda)
+            }
+            WasmEmitError::Unsupported(node) => {
+                write!(f, "{:?} can't be written as WebAssembly", node)
+            }
+        }
+    }
+}
+
+impl From<WasmError> for WasmEmitError {
+    fn from(error: WasmError) -> WasmEmitError {
+        WasmEmitError::Module(error)
+    }
+}
+
+impl NodeCtxt<WasmOp> {
+    /// Writes `module` back out with the code of each function it defines
+    /// taken from the lambda named after it, as `parse_wasm` names them.
+    ///
+    /// Values are held in locals. Gammas become an `if`, or a `br_table`
+    /// into nested blocks when they have more than two branches, and thetas
+    /// become loops, with the nodes of each region in the order
+    /// `NodeCtxt::schedule` puts them in. The other sections are copied as
+    /// they are, except for the name section, as the locals it names are
+    /// gone.
+    pub fn to_wasm(&self, module: &[u8]) -> Result<Vec<u8>, WasmEmitError> {
+        let (info, bodies) = Module::read(module)?;
+        let lambdas: HashMap<String, NodeId> = self
+            .region_ref(self.root_region())
+            .nodes()
+            .filter(|node| matches!(*node.kind(), NodeKind::Lambda { .. }))
+            .filter_map(|node| Some((node.name()?, node.id())))
+            .collect();
+
+        let mut code = CodeSection::new();
+        for index in 0..bodies.len() as u32 {
+            let function = info.num_imported_functions + index;
+            let lambda = match lambdas.get(&info.function_name(function)) {
+                Some(&lambda) => lambda,
+                None => return Err(WasmEmitError::MissingFunction(function)),
+            };
+            code.function(&FunctionEmitter::new(self, &info, function, lambda)?.emit()?);
+        }
+
+        let mut out = wasm_encoder::Module::new();
+        for payload in Parser::new(0).parse_all(module) {
+            let payload = payload.map_err(WasmError::from)?;
+            match payload {
+                Payload::CodeSectionStart { .. } => {
+                    out.section(&code);
+                }
+                Payload::CustomSection(reader) if reader.name() == "name" => {}
+                _ => {
+                    if let Some((id, range)) = payload.as_section() {
+                        out.section(&RawSection {
+                            id,
+                            data: &module[range],
+                        });
+                    }
+                }
+            }
+        }
+        Ok(out.finish())
+    }
+}
+
+struct FunctionEmitter<'a, 'g> {
+    ncx: &'g NodeCtxt<WasmOp>,
+    module: &'a Module,
+    lambda: NodeId,
+    num_params: usize,
+    /// The locals holding the values of origins.
+    locals: HashMap<ValOrigin<'g, WasmOp>, u32>,
+    /// The types of the locals, starting with the parameters.
+    local_types: Vec<ValType>,
+    code: Vec<Instruction<'static>>,
+}
+
+impl<'a, 'g> FunctionEmitter<'a, 'g> {
+    fn new(
+        ncx: &'g NodeCtxt<WasmOp>,
+        module: &'a Module,
+        function: u32,
+        lambda: NodeId,
+    ) -> Result<FunctionEmitter<'a, 'g>, WasmEmitError> {
+        if !matches!(
+            *ncx.node_ref(lambda).kind(),
+            NodeKind::Lambda { ctx_vars: 0, .. }
+        ) {
+            return Err(WasmEmitError::Unsupported(lambda));
+        }
+        let func_type = module.function_type(function);
+        let sig = ncx.lambda_sig(lambda);
+        if sig.val_ins != func_type.params.len() || sig.val_outs != func_type.results.len() {
+            return Err(WasmEmitError::Signature(lambda));
+        }
+
+        let mut emitter = FunctionEmitter {
+            ncx,
+            module,
+            lambda,
+            num_params: func_type.params.len(),
+            locals: HashMap::new(),
+            local_types: vec![],
+            code: vec![],
+        };
+        let body = ncx.region_ref(ncx.inner_regions(lambda)[0]);
+        for (port, &ty) in func_type.params.iter().enumerate() {
+            let local = emitter.new_local(val_type(ty));
+            emitter.locals.insert(body.val_arg(port), local);
+        }
+        Ok(emitter)
+    }
+
+    fn new_local(&mut self, ty: ValType) -> u32 {
+        self.local_types.push(ty);
+        self.local_types.len() as u32 - 1
+    }
+
+    fn local(&self, origin: ValOrigin<'g, WasmOp>) -> u32 {
+        self.locals[&origin]
+    }
+
+    fn emit(mut self) -> Result<Function, WasmEmitError> {
+        let body = self.ncx.inner_regions(self.lambda)[0];
+        self.emit_region(body)?;
+        let body = self.ncx.region_ref(body);
+        for port in 0..self.ncx.lambda_sig(self.lambda).val_outs {
+            let result = self.local(body.val_res(port).origin());
+            self.code.push(Instruction::LocalGet(result));
+        }
+        self.code.push(Instruction::End);
+
+        // Locals are renumbered in the order they're first used in, which
+        // drops those only forwarded values were held in.
+        let code = forward_locals(self.code);
+        let mut renumbered: HashMap<u32, u32> = (0..self.num_params as u32)
+            .map(|param| (param, param))
+            .collect();
+        let mut locals: Vec<(u32, ValType)> = vec![];
+        for instruction in &code {
+            if let Instruction::LocalGet(local) | Instruction::LocalSet(local) = *instruction {
+                if renumbered.contains_key(&local) {
+                    continue;
+                }
+                renumbered.insert(local, renumbered.len() as u32);
+                let ty = self.local_types[local as usize];
+                match locals.last_mut() {
+                    Some((count, last)) if *last == ty => *count += 1,
+                    _ => locals.push((1, ty)),
+                }
+            }
+        }
+
+        let mut function = Function::new(locals);
+        for instruction in code {
+            let instruction = match instruction {
+                Instruction::LocalGet(local) => Instruction::LocalGet(renumbered[&local]),
+                Instruction::LocalSet(local) => Instruction::LocalSet(renumbered[&local]),
+                instruction => instruction,
+            };
+            function.instruction(&instruction);
+        }
+        Ok(function)
+    }
+
+    fn emit_region(&mut self, region_id: RegionId) -> Result<(), WasmEmitError> {
+        for node_id in self.ncx.schedule(region_id) {
+            let node = self.ncx.node_ref(node_id);
+            let kind = node.kind().clone();
+            match kind {
+                NodeKind::Op(op) => self.emit_op(node, op)?,
+                NodeKind::Gamma { .. } => self.emit_gamma(node)?,
+                NodeKind::Theta { .. } => self.emit_theta(node)?,
+                _ => return Err(WasmEmitError::Unsupported(node_id)),
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_op(&mut self, node: Node<'g, WasmOp>, op: WasmOp) -> Result<(), WasmEmitError> {
+        let mut args = vec![];
+        for user in node.val_ins() {
+            let local = self.local(user.origin());
+            args.push(self.local_types[local as usize]);
+            self.code.push(Instruction::LocalGet(local));
+        }
+        let memarg = |offset, align| MemArg {
+            offset,
+            align,
+            memory_index: 0,
+        };
+        let (instruction, results) = match op {
+            WasmOp::I32Const(value) => (Instruction::I32Const(value), vec![ValType::I32]),
+            WasmOp::I64Const(value) => (Instruction::I64Const(value), vec![ValType::I64]),
+            WasmOp::F32Const(bits) => {
+                (Instruction::F32Const(Ieee32::new(bits)), vec![ValType::F32])
+            }
+            WasmOp::F64Const(bits) => {
+                (Instruction::F64Const(Ieee64::new(bits)), vec![ValType::F64])
+            }
+            WasmOp::Numeric { name, .. } => match numeric_instruction(name) {
+                Some((instruction, ty)) => (instruction, vec![val_type(ty)]),
+                None => return Err(WasmEmitError::Unsupported(node.id())),
+            },
+            WasmOp::Select => (Instruction::Select, vec![args[0]]),
+            WasmOp::Load { name, offset } => {
+                let load = match name {
+                    "i32.load" => (Instruction::I32Load(memarg(offset, 2)), WasmType::I32),
+                    "i64.load" => (Instruction::I64Load(memarg(offset, 3)), WasmType::I64),
+                    "f32.load" => (Instruction::F32Load(memarg(offset, 2)), WasmType::F32),
+                    "f64.load" => (Instruction::F64Load(memarg(offset, 3)), WasmType::F64),
+                    "i32.load8_s" => (Instruction::I32Load8S(memarg(offset, 0)), WasmType::I32),
+                    "i32.load8_u" => (Instruction::I32Load8U(memarg(offset, 0)), WasmType::I32),
+                    "i32.load16_s" => (Instruction::I32Load16S(memarg(offset, 1)), WasmType::I32),
+                    "i32.load16_u" => (Instruction::I32Load16U(memarg(offset, 1)), WasmType::I32),
+                    "i64.load8_s" => (Instruction::I64Load8S(memarg(offset, 0)), WasmType::I64),
+                    "i64.load8_u" => (Instruction::I64Load8U(memarg(offset, 0)), WasmType::I64),
+                    "i64.load16_s" => (Instruction::I64Load16S(memarg(offset, 1)), WasmType::I64),
+                    "i64.load16_u" => (Instruction::I64Load16U(memarg(offset, 1)), WasmType::I64),
+                    "i64.load32_s" => (Instruction::I64Load32S(memarg(offset, 2)), WasmType::I64),
+                    "i64.load32_u" => (Instruction::I64Load32U(memarg(offset, 2)), WasmType::I64),
+                    _ => return Err(WasmEmitError::Unsupported(node.id())),
+                };
+                (load.0, vec![val_type(load.1)])
+            }
+            WasmOp::Store { name, offset } => {
+                let store = match name {
+                    "i32.store" => Instruction::I32Store(memarg(offset, 2)),
+                    "i64.store" => Instruction::I64Store(memarg(offset, 3)),
+                    "f32.store" => Instruction::F32Store(memarg(offset, 2)),
+                    "f64.store" => Instruction::F64Store(memarg(offset, 3)),
+                    "i32.store8" => Instruction::I32Store8(memarg(offset, 0)),
+                    "i32.store16" => Instruction::I32Store16(memarg(offset, 1)),
+                    "i64.store8" => Instruction::I64Store8(memarg(offset, 0)),
+                    "i64.store16" => Instruction::I64Store16(memarg(offset, 1)),
+                    "i64.store32" => Instruction::I64Store32(memarg(offset, 2)),
+                    _ => return Err(WasmEmitError::Unsupported(node.id())),
+                };
+                (store, vec![])
+            }
+            WasmOp::MemorySize => (Instruction::MemorySize(0), vec![ValType::I32]),
+            WasmOp::MemoryGrow => (Instruction::MemoryGrow(0), vec![ValType::I32]),
+            WasmOp::GlobalGet(global) => {
+                let ty = self.module.globals[global as usize];
+                (Instruction::GlobalGet(global), vec![val_type(ty)])
+            }
+            WasmOp::GlobalSet(global) => (Instruction::GlobalSet(global), vec![]),
+            WasmOp::Call { function, .. } => {
+                let results = &self.module.function_type(function).results;
+                (
+                    Instruction::Call(function),
+                    results.iter().copied().map(val_type).collect(),
+                )
+            }
+            WasmOp::CallIndirect { ty, .. } => {
+                let results = &self.module.types[ty as usize].results;
+                let call = Instruction::CallIndirect {
+                    type_index: ty,
+                    table_index: 0,
+                };
+                (call, results.iter().copied().map(val_type).collect())
+            }
+            WasmOp::Unreachable => (Instruction::Unreachable, vec![]),
+        };
+        self.code.push(instruction);
+
+        let outputs: Vec<u32> = results.into_iter().map(|ty| self.new_local(ty)).collect();
+        for (port, &local) in outputs.iter().enumerate() {
+            self.locals.insert(node.val_out(port), local);
+        }
+        for &local in outputs.iter().rev() {
+            self.code.push(Instruction::LocalSet(local));
+        }
+        Ok(())
+    }
+
+    fn emit_gamma(&mut self, node: Node<'g, WasmOp>) -> Result<(), WasmEmitError> {
+        let predicate = self.local(node.val_in(0).origin());
+        let num_ins = node.val_ins().count();
+        let num_outs = node.val_outs().count();
+
+        let mut branches = vec![];
+        let mut results = vec![];
+        for branch in self.ncx.inner_regions(node.id()) {
+            // Branches see the same values as the gamma, so their arguments
+            // are held in the locals of its inputs.
+            let region = self.ncx.region_ref(branch);
+            for port in 1..num_ins {
+                let local = self.local(node.val_in(port).origin());
+                self.locals.insert(region.val_arg(port - 1), local);
+            }
+            let outer = mem::take(&mut self.code);
+            self.emit_region(branch)?;
+            branches.push(mem::replace(&mut self.code, outer));
+            let locals: Vec<u32> = (0..num_outs)
+                .map(|port| self.local(region.val_res(port).origin()))
+                .collect();
+            results.push(locals);
+        }
+
+        for port in 0..num_outs {
+            // Values passed through every branch are held where they were.
+            let first = results[0][port];
+            if results.iter().all(|results| results[port] == first) {
+                self.locals.insert(node.val_out(port), first);
+                continue;
+            }
+            let output = self.new_local(self.local_types[first as usize]);
+            self.locals.insert(node.val_out(port), output);
+            for (code, results) in branches.iter_mut().zip(&results) {
+                code.push(Instruction::LocalGet(results[port]));
+                code.push(Instruction::LocalSet(output));
+            }
+        }
+
+        let num_branches = branches.len();
+        if num_branches == 2 {
+            let otherwise = branches.remove(0);
+            self.code.push(Instruction::LocalGet(predicate));
+            self.code.push(Instruction::If(BlockType::Empty));
+            self.code.extend(branches.remove(0));
+            self.code.push(Instruction::Else);
+            self.code.extend(otherwise);
+            self.code.push(Instruction::End);
+            return Ok(());
+        }
+
+        // Each branch follows the end of a block the `br_table` branches
+        // out of, and branches out of the block around them all when done.
+        for _ in 0..=num_branches {
+            self.code.push(Instruction::Block(BlockType::Empty));
+        }
+        let last = num_branches as u32 - 1;
+        self.code.push(Instruction::LocalGet(predicate));
+        self.code
+            .push(Instruction::BrTable((0..last).collect(), last));
+        self.code.push(Instruction::End);
+        for (index, code) in branches.into_iter().enumerate() {
+            self.code.extend(code);
+            if index as u32 != last {
+                self.code.push(Instruction::Br(last - index as u32));
+            }
+            self.code.push(Instruction::End);
+        }
+        Ok(())
+    }
+
+    fn emit_theta(&mut self, node: Node<'g, WasmOp>) -> Result<(), WasmEmitError> {
+        let body = self.ncx.region_ref(self.ncx.inner_regions(node.id())[0]);
+        let num_loop_vars = node.val_outs().count();
+
+        // The loop variables are held in the same locals throughout the loop
+        // and after it.
+        let mut loop_vars = vec![];
+        for port in 0..num_loop_vars {
+            let init = self.local(node.val_in(port).origin());
+            let local = self.new_local(self.local_types[init as usize]);
+            self.code.push(Instruction::LocalGet(init));
+            self.code.push(Instruction::LocalSet(local));
+            self.locals.insert(body.val_arg(port), local);
+            self.locals.insert(node.val_out(port), local);
+            loop_vars.push(local);
+        }
+
+        self.code.push(Instruction::Loop(BlockType::Empty));
+        self.emit_region(body.id())?;
+        // The predicate and the values for the next iteration are read before
+        // any of the loop variables is overwritten.
+        let predicate = self.local(body.val_res(0).origin());
+        self.code.push(Instruction::LocalGet(predicate));
+        let mut changed = vec![];
+        for (port, &local) in loop_vars.iter().enumerate() {
+            let result = self.local(body.val_res(port + 1).origin());
+            if result != local {
+                self.code.push(Instruction::LocalGet(result));
+                changed.push(local);
+            }
+        }
+        for &local in changed.iter().rev() {
+            self.code.push(Instruction::LocalSet(local));
+        }
+        self.code.push(Instruction::BrIf(0));
+        self.code.push(Instruction::End);
+        Ok(())
+    }
+}
+
+/// Leaves values on the stack for the instruction right after the one
+/// producing them, when that's their only user.
+fn forward_locals(code: Vec<Instruction<'static>>) -> Vec<Instruction<'static>> {
+    let mut gets = HashMap::new();
+    let mut sets = HashMap::new();
+    for instruction in &code {
+        match *instruction {
+            Instruction::LocalGet(local) => *gets.entry(local).or_insert(0) += 1,
+            Instruction::LocalSet(local) => *sets.entry(local).or_insert(0) += 1,
+            _ => {}
+        }
+    }
+
+    let mut forwarded = Vec::with_capacity(code.len());
+    let mut code = code.into_iter().peekable();
+    while let Some(instruction) = code.next() {
+        if let (Instruction::LocalSet(set), Some(Instruction::LocalGet(get))) =
+            (&instruction, code.peek())
+        {
+            if set == get && gets[get] == 1 && sets[set] == 1 {
+                code.next();
+                continue;
+            }
+        }
+        forwarded.push(instruction);
+    }
+    forwarded
+}
+
+fn val_type(ty: WasmType) -> ValType {
+    match ty {
+        WasmType::I32 => ValType::I32,
+        WasmType::I64 => ValType::I64,
+        WasmType::F32 => ValType::F32,
+        WasmType::F64 => ValType::F64,
+    }
+}
+
+/// The instruction of a numeric op, and its result type.
+fn numeric_instruction(name: &str) -> Option<(Instruction<'static>, WasmType)> {
+    let numeric = match name {
+        "i32.eqz" => (Instruction::I32Eqz, WasmType::I32),
+        "i32.eq" => (Instruction::I32Eq, WasmType::I32),
+        "i32.ne" => (Instruction::I32Ne, WasmType::I32),
+        "i32.lt_s" => (Instruction::I32LtS, WasmType::I32),
+        "i32.lt_u" => (Instruction::I32LtU, WasmType::I32),
+        "i32.gt_s" => (Instruction::I32GtS, WasmType::I32),
+        "i32.gt_u" => (Instruction::I32GtU, WasmType::I32),
+        "i32.le_s" => (Instruction::I32LeS, WasmType::I32),
+        "i32.le_u" => (Instruction::I32LeU, WasmType::I32),
+        "i32.ge_s" => (Instruction::I32GeS, WasmType::I32),
+        "i32.ge_u" => (Instruction::I32GeU, WasmType::I32),
+        "i64.eqz" => (Instruction::I64Eqz, WasmType::I32),
+        "i64.eq" => (Instruction::I64Eq, WasmType::I32),
+        "i64.ne" => (Instruction::I64Ne, WasmType::I32),
+        "i64.lt_s" => (Instruction::I64LtS, WasmType::I32),
+        "i64.lt_u" => (Instruction::I64LtU, WasmType::I32),
+        "i64.gt_s" => (Instruction::I64GtS, WasmType::I32),
+        "i64.gt_u" => (Instruction::I64GtU, WasmType::I32),
+        "i64.le_s" => (Instruction::I64LeS, WasmType::I32),
+        "i64.le_u" => (Instruction::I64LeU, WasmType::I32),
+        "i64.ge_s" => (Instruction::I64GeS, WasmType::I32),
+        "i64.ge_u" => (Instruction::I64GeU, WasmType::I32),
+        "f32.eq" => (Instruction::F32Eq, WasmType::I32),
+        "f32.ne" => (Instruction::F32Ne, WasmType::I32),
+        "f32.lt" => (Instruction::F32Lt, WasmType::I32),
+        "f32.gt" => (Instruction::F32Gt, WasmType::I32),
+        "f32.le" => (Instruction::F32Le, WasmType::I32),
+        "f32.ge" => (Instruction::F32Ge, WasmType::I32),
+        "f64.eq" => (Instruction::F64Eq, WasmType::I32),
+        "f64.ne" => (Instruction::F64Ne, WasmType::I32),
+        "f64.lt" => (Instruction::F64Lt, WasmType::I32),
+        "f64.gt" => (Instruction::F64Gt, WasmType::I32),
+        "f64.le" => (Instruction::F64Le, WasmType::I32),
+        "f64.ge" => (Instruction::F64Ge, WasmType::I32),
+        "i32.clz" => (Instruction::I32Clz, WasmType::I32),
+        "i32.ctz" => (Instruction::I32Ctz, WasmType::I32),
+        "i32.popcnt" => (Instruction::I32Popcnt, WasmType::I32),
+        "i32.add" => (Instruction::I32Add, WasmType::I32),
+        "i32.sub" => (Instruction::I32Sub, WasmType::I32),
+        "i32.mul" => (Instruction::I32Mul, WasmType::I32),
+        "i32.div_s" => (Instruction::I32DivS, WasmType::I32),
+        "i32.div_u" => (Instruction::I32DivU, WasmType::I32),
+        "i32.rem_s" => (Instruction::I32RemS, WasmType::I32),
+        "i32.rem_u" => (Instruction::I32RemU, WasmType::I32),
+        "i32.and" => (Instruction::I32And, WasmType::I32),
+        "i32.or" => (Instruction::I32Or, WasmType::I32),
+        "i32.xor" => (Instruction::I32Xor, WasmType::I32),
+        "i32.shl" => (Instruction::I32Shl, WasmType::I32),
+        "i32.shr_s" => (Instruction::I32ShrS, WasmType::I32),
+        "i32.shr_u" => (Instruction::I32ShrU, WasmType::I32),
+        "i32.rotl" => (Instruction::I32Rotl, WasmType::I32),
+        "i32.rotr" => (Instruction::I32Rotr, WasmType::I32),
+        "i64.clz" => (Instruction::I64Clz, WasmType::I64),
+        "i64.ctz" => (Instruction::I64Ctz, WasmType::I64),
+        "i64.popcnt" => (Instruction::I64Popcnt, WasmType::I64),
+        "i64.add" => (Instruction::I64Add, WasmType::I64),
+        "i64.sub" => (Instruction::I64Sub, WasmType::I64),
+        "i64.mul" => (Instruction::I64Mul, WasmType::I64),
+        "i64.div_s" => (Instruction::I64DivS, WasmType::I64),
+        "i64.div_u" => (Instruction::I64DivU, WasmType::I64),
+        "i64.rem_s" => (Instruction::I64RemS, WasmType::I64),
+        "i64.rem_u" => (Instruction::I64RemU, WasmType::I64),
+        "i64.and" => (Instruction::I64And, WasmType::I64),
+        "i64.or" => (Instruction::I64Or, WasmType::I64),
+        "i64.xor" => (Instruction::I64Xor, WasmType::I64),
+        "i64.shl" => (Instruction::I64Shl, WasmType::I64),
+        "i64.shr_s" => (Instruction::I64ShrS, WasmType::I64),
+        "i64.shr_u" => (Instruction::I64ShrU, WasmType::I64),
+        "i64.rotl" => (Instruction::I64Rotl, WasmType::I64),
+        "i64.rotr" => (Instruction::I64Rotr, WasmType::I64),
+        "f32.abs" => (Instruction::F32Abs, WasmType::F32),
+        "f32.neg" => (Instruction::F32Neg, WasmType::F32),
+        "f32.ceil" => (Instruction::F32Ceil, WasmType::F32),
+        "f32.floor" => (Instruction::F32Floor, WasmType::F32),
+        "f32.trunc" => (Instruction::F32Trunc, WasmType::F32),
+        "f32.nearest" => (Instruction::F32Nearest, WasmType::F32),
+        "f32.sqrt" => (Instruction::F32Sqrt, WasmType::F32),
+        "f32.add" => (Instruction::F32Add, WasmType::F32),
+        "f32.sub" => (Instruction::F32Sub, WasmType::F32),
+        "f32.mul" => (Instruction::F32Mul, WasmType::F32),
+        "f32.div" => (Instruction::F32Div, WasmType::F32),
+        "f32.min" => (Instruction::F32Min, WasmType::F32),
+        "f32.max" => (Instruction::F32Max, WasmType::F32),
+        "f32.copysign" => (Instruction::F32Copysign, WasmType::F32),
+        "f64.abs" => (Instruction::F64Abs, WasmType::F64),
+        "f64.neg" => (Instruction::F64Neg, WasmType::F64),
+        "f64.ceil" => (Instruction::F64Ceil, WasmType::F64),
+        "f64.floor" => (Instruction::F64Floor, WasmType::F64),
+        "f64.trunc" => (Instruction::F64Trunc, WasmType::F64),
+        "f64.nearest" => (Instruction::F64Nearest, WasmType::F64),
+        "f64.sqrt" => (Instruction::F64Sqrt, WasmType::F64),
+        "f64.add" => (Instruction::F64Add, WasmType::F64),
+        "f64.sub" => (Instruction::F64Sub, WasmType::F64),
+        "f64.mul" => (Instruction::F64Mul, WasmType::F64),
+        "f64.div" => (Instruction::F64Div, WasmType::F64),
+        "f64.min" => (Instruction::F64Min, WasmType::F64),
+        "f64.max" => (Instruction::F64Max, WasmType::F64),
+        "f64.copysign" => (Instruction::F64Copysign, WasmType::F64),
+        "i32.wrap_i64" => (Instruction::I32WrapI64, WasmType::I32),
+        "i32.trunc_f32_s" => (Instruction::I32TruncF32S, WasmType::I32),
+        "i32.trunc_f32_u" => (Instruction::I32TruncF32U, WasmType::I32),
+        "i32.trunc_f64_s" => (Instruction::I32TruncF64S, WasmType::I32),
+        "i32.trunc_f64_u" => (Instruction::I32TruncF64U, WasmType::I32),
+        "i64.extend_i32_s" => (Instruction::I64ExtendI32S, WasmType::I64),
+        "i64.extend_i32_u" => (Instruction::I64ExtendI32U, WasmType::I64),
+        "i64.trunc_f32_s" => (Instruction::I64TruncF32S, WasmType::I64),
+        "i64.trunc_f32_u" => (Instruction::I64TruncF32U, WasmType::I64),
+        "i64.trunc_f64_s" => (Instruction::I64TruncF64S, WasmType::I64),
+        "i64.trunc_f64_u" => (Instruction::I64TruncF64U, WasmType::I64),
+        "f32.convert_i32_s" => (Instruction::F32ConvertI32S, WasmType::F32),
+        "f32.convert_i32_u" => (Instruction::F32ConvertI32U, WasmType::F32),
+        "f32.convert_i64_s" => (Instruction::F32ConvertI64S, WasmType::F32),
+        "f32.convert_i64_u" => (Instruction::F32ConvertI64U, WasmType::F32),
+        "f32.demote_f64" => (Instruction::F32DemoteF64, WasmType::F32),
+        "f64.convert_i32_s" => (Instruction::F64ConvertI32S, WasmType::F64),
+        "f64.convert_i32_u" => (Instruction::F64ConvertI32U, WasmType::F64),
+        "f64.convert_i64_s" => (Instruction::F64ConvertI64S, WasmType::F64),
+        "f64.convert_i64_u" => (Instruction::F64ConvertI64U, WasmType::F64),
+        "f64.promote_f32" => (Instruction::F64PromoteF32, WasmType::F64),
+        "i32.reinterpret_f32" => (Instruction::I32ReinterpretF32, WasmType::I32),
+        "i64.reinterpret_f64" => (Instruction::I64ReinterpretF64, WasmType::I64),
+        "f32.reinterpret_i32" => (Instruction::F32ReinterpretI32, WasmType::F32),
+        "f64.reinterpret_i64" => (Instruction::F64ReinterpretI64, WasmType::F64),
+        "i32.extend8_s" => (Instruction::I32Extend8S, WasmType::I32),
+        "i32.extend16_s" => (Instruction::I32Extend16S, WasmType::I32),
+        "i64.extend8_s" => (Instruction::I64Extend8S, WasmType::I64),
+        "i64.extend16_s" => (Instruction::I64Extend16S, WasmType::I64),
+        "i64.extend32_s" => (Instruction::I64Extend32S, WasmType::I64),
+        "i32.trunc_sat_f32_s" => (Instruction::I32TruncSatF32S, WasmType::I32),
+        "i32.trunc_sat_f32_u" => (Instruction::I32TruncSatF32U, WasmType::I32),
+        "i32.trunc_sat_f64_s" => (Instruction::I32TruncSatF64S, WasmType::I32),
+        "i32.trunc_sat_f64_u" => (Instruction::I32TruncSatF64U, WasmType::I32),
+        "i64.trunc_sat_f32_s" => (Instruction::I64TruncSatF32S, WasmType::I64),
+        "i64.trunc_sat_f32_u" => (Instruction::I64TruncSatF32U, WasmType::I64),
+        "i64.trunc_sat_f64_s" => (Instruction::I64TruncSatF64S, WasmType::I64),
+        "i64.trunc_sat_f64_u" => (Instruction::I64TruncSatF64U, WasmType::I64),
+        _ => return None,
+    };
+    Some(numeric)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::test::{parse, run},
+        WasmEmitError,
+    };
+    use crate::rvsdg::NodeCtxt;
+    use wasmparser::Validator;
+
+    #[test]
+    fn straight_line_code() {
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (func (export "sub") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.sub))
+            "#,
+        )
+        .unwrap();
+
+        // Values used right away are left on the stack.
+        let ncx = NodeCtxt::parse_wasm(&bytes).unwrap();
+        assert_eq!(bytes, ncx.to_wasm(&bytes).unwrap());
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = r#"
+            (module
+              (import "env" "log" (func $log (param i32)))
+              (memory 1)
+              (global $calls (mut i32) (i32.const 0))
+              (func (export "max") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.gt_s
+                if (result i32)
+                  local.get 0
+                else
+                  local.get 1
+                end)
+              (func (export "sum") (param $n i32) (result i32) (local $acc i32)
+                block $exit
+                  loop $again
+                    local.get $n
+                    i32.eqz
+                    br_if $exit
+                    local.get $acc
+                    local.get $n
+                    i32.add
+                    local.set $acc
+                    local.get $n
+                    i32.const 1
+                    i32.sub
+                    local.set $n
+                    br $again
+                  end
+                end
+                local.get $acc)
+              (func (export "pick") (param i32) (result i32)
+                i32.const 8
+                i32.const 40
+                i32.store offset=4
+                block $two
+                  block $one
+                    block $zero
+                      local.get 0
+                      br_table $zero $one $two
+                    end
+                    i32.const 10
+                    return
+                  end
+                  i32.const 12
+                  i32.load
+                  return
+                end
+                i32.const 30)
+              (func $count (param i32)
+                global.get $calls
+                i32.const 1
+                i32.add
+                global.set $calls
+                local.get 0
+                call $log))
+            "#;
+        let bytes = wat::parse_str(text).unwrap();
+        let ncx = parse(text);
+        let written = ncx.to_wasm(&bytes).unwrap();
+        Validator::new().validate_all(&written).unwrap();
+
+        let reread = NodeCtxt::parse_wasm(&written).unwrap();
+        for args in &[[3, 5], [7, 2], [-1, -1]] {
+            assert_eq!(run(&ncx, "max", args), run(&reread, "max", args));
+        }
+        for n in 0..5 {
+            assert_eq!(run(&ncx, "sum", &[n]), run(&reread, "sum", &[n]));
+            assert_eq!(run(&ncx, "pick", &[n]), run(&reread, "pick", &[n]));
+        }
+    }
+
+    #[test]
+    fn missing_functions() {
+        let ncx = parse(r#"(module (func (export "f")))"#);
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (import "env" "g" (func))
+              (func (export "f"))
+              (func))
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Err(WasmEmitError::MissingFunction(2)), ncx.to_wasm(&bytes));
+    }
+}
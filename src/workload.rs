@@ -0,0 +1,190 @@
+use crate::rvsdg::{NodeCtxt, RegionId, Sig, SigS, StOrigin, ValOrigin};
+
+/// Ops of the synthetic workloads.
+///
+/// Literals carry an index so that workloads can control how much of a graph
+/// gets shared by interning.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum WorkOp {
+    Lit(u32),
+    Neg,
+    Add,
+    Entry,
+    Store,
+}
+
+impl Sig for WorkOp {
+    fn sig(&self) -> SigS {
+        match self {
+            WorkOp::Lit(..) => SigS {
+                val_outs: 1,
+                ..SigS::default()
+            },
+            WorkOp::Neg => SigS {
+                val_ins: 1,
+                val_outs: 1,
+                ..SigS::default()
+            },
+            WorkOp::Add => SigS {
+                val_ins: 2,
+                val_outs: 1,
+                ..SigS::default()
+            },
+            WorkOp::Entry => SigS {
+                st_outs: 1,
+                ..SigS::default()
+            },
+            WorkOp::Store => SigS {
+                val_ins: 2,
+                st_ins: 1,
+                st_outs: 1,
+                ..SigS::default()
+            },
+        }
+    }
+}
+
+/// A chain of `depth` additions, each adding a fresh literal to the result of
+/// the previous one.
+pub fn deep_expression_chain(
+    ncx: &NodeCtxt<WorkOp>,
+    region: RegionId,
+    depth: u32,
+) -> ValOrigin<'_, WorkOp> {
+    let mut acc = ncx
+        .node_builder_in(region, WorkOp::Lit(0))
+        .finish()
+        .val_out(0);
+
+    for index in 1..=depth {
+        let lit = ncx.node_builder_in(region, WorkOp::Lit(index)).finish();
+        acc = ncx
+            .node_builder_in(region, WorkOp::Add)
+            .operand(acc)
+            .operand(lit.val_out(0))
+            .finish()
+            .val_out(0);
+    }
+
+    acc
+}
+
+/// A single literal used by `width` distinct additions.
+pub fn wide_fan_out(
+    ncx: &NodeCtxt<WorkOp>,
+    region: RegionId,
+    width: u32,
+) -> Vec<ValOrigin<'_, WorkOp>> {
+    let shared = ncx.node_builder_in(region, WorkOp::Lit(0)).finish();
+
+    (1..=width)
+        .map(|index| {
+            let lit = ncx.node_builder_in(region, WorkOp::Lit(index)).finish();
+            ncx.node_builder_in(region, WorkOp::Add)
+                .operand(shared.val_out(0))
+                .operand(lit.val_out(0))
+                .finish()
+                .val_out(0)
+        })
+        .collect()
+}
+
+/// A chain of `length` stores threading a single state, all storing to the
+/// same address.
+pub fn heavy_state_chain(
+    ncx: &NodeCtxt<WorkOp>,
+    region: RegionId,
+    length: u32,
+) -> StOrigin<'_, WorkOp> {
+    let address = ncx.node_builder_in(region, WorkOp::Lit(0)).finish();
+    let mut st = ncx
+        .node_builder_in(region, WorkOp::Entry)
+        .finish()
+        .st_out(0);
+
+    for index in 1..=length {
+        let value = ncx.node_builder_in(region, WorkOp::Lit(index)).finish();
+        st = ncx
+            .node_builder_in(region, WorkOp::Store)
+            .operand(address.val_out(0))
+            .operand(value.val_out(0))
+            .state(st)
+            .finish()
+            .st_out(0);
+    }
+
+    st
+}
+
+/// `depth` gammas nested in their first branch, each passing a value through
+/// every branch and negating it in the first one.
+pub fn deeply_nested_gammas(
+    ncx: &NodeCtxt<WorkOp>,
+    region: RegionId,
+    depth: u32,
+) -> ValOrigin<'_, WorkOp> {
+    let x = ncx.node_builder_in(region, WorkOp::Lit(1)).finish();
+    nested_gamma(ncx, region, x.val_out(0), depth)
+}
+
+fn nested_gamma<'g>(
+    ncx: &'g NodeCtxt<WorkOp>,
+    region: RegionId,
+    x: ValOrigin<'g, WorkOp>,
+    depth: u32,
+) -> ValOrigin<'g, WorkOp> {
+    if depth == 0 {
+        return ncx
+            .node_builder_in(region, WorkOp::Neg)
+            .operand(x)
+            .finish()
+            .val_out(0);
+    }
+
+    let gamma = ncx.gamma_builder(x, 2);
+    let x_args = gamma.entry_var(x);
+    let inner = nested_gamma(ncx, gamma.branch(0), x_args[0], depth - 1);
+    let output = gamma.exit_var(&[inner, x_args[1]]);
+    gamma.finish();
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_shapes() {
+        let ncx = NodeCtxt::new();
+        let root = ncx.root_region();
+
+        deep_expression_chain(&ncx, root, 100);
+        assert_eq!(201, ncx.num_nodes());
+
+        let outs = wide_fan_out(&ncx, root, 50);
+        assert_eq!(50, outs.len());
+        // The literals and the first addition are shared with the expression
+        // chain.
+        assert_eq!(250, ncx.num_nodes());
+
+        heavy_state_chain(&ncx, root, 100);
+        assert_eq!(351, ncx.num_nodes());
+
+        deeply_nested_gammas(&ncx, root, 10);
+        assert_eq!(362, ncx.num_nodes());
+    }
+
+    #[test]
+    fn generators_are_reproducible() {
+        let build = || {
+            let ncx = NodeCtxt::new();
+            let root = ncx.root_region();
+            deep_expression_chain(&ncx, root, 20);
+            heavy_state_chain(&ncx, root, 20);
+            deeply_nested_gammas(&ncx, root, 5);
+            ncx.freeze_region(root)
+        };
+
+        assert_eq!(build(), build());
+    }
+}